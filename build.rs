@@ -0,0 +1,26 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(feature = "server")]
+    {
+        // protox compiles the schema in-process, so building doesn't need protoc installed
+        let descriptors = protox::compile(["proto/kopper.proto"], ["proto"])?;
+        // The client is only used by the tests of the service
+        tonic_build::configure()
+            .compile_fds(descriptors)?;
+    }
+
+    #[cfg(feature = "kopper-ffi")]
+    cbindgen::Builder::new()
+        .with_config(cbindgen::Config {
+            language: cbindgen::Language::C,
+            include_guard: Some("KOPPER_H".to_string()),
+            usize_is_size_t: true,
+            ..Default::default()
+        })
+        .with_src("src/ffi.rs")
+        .generate()?
+        .write_to_file("include/kopper.h");
+
+    println!("cargo:rerun-if-changed=proto");
+    println!("cargo:rerun-if-changed=src/ffi.rs");
+    Ok(())
+}
@@ -0,0 +1,22 @@
+//! Records come back whole and in order, each starting where the previous one ended
+
+#![no_main]
+
+use kopperdb::kopper::KeyValueIterator;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut end = 0;
+    for (key, record, offset) in KeyValueIterator::from(data) {
+        assert_eq!(&data[end..end + record.len()], record);
+        assert_eq!(&record[..key.len()], key.as_bytes());
+        assert_eq!(offset, end + key.len() + 1);
+
+        // Separators after the key and the value, none in between
+        assert_eq!(record.iter().filter(|&&byte| byte == b'\0').count(), 2);
+        assert_eq!(record[key.len()], b'\0');
+        assert_eq!(record[record.len() - 1], b'\0');
+        end += record.len();
+    }
+    assert!(end <= data.len());
+});
@@ -0,0 +1,31 @@
+//! Recovery of a segment indexes exactly what replaying its records does, and
+//! never any further than the last complete one
+
+#![no_main]
+
+use std::collections::HashMap;
+
+use kopperdb::kopper::{index_segment, KeyValueIterator};
+use kopperdb::store::{MemoryStore, SegmentStore};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let store = MemoryStore::new();
+    let file = store.create("0_0").unwrap();
+    file.append(data).unwrap();
+    let index = index_segment(&*file).unwrap();
+
+    let mut values = HashMap::new();
+    let mut end = 0;
+    for (key, record, offset) in KeyValueIterator::from(data) {
+        let len = record.len() - key.len() - 2;
+        match &data[offset..offset + len] {
+            [0xFF] => values.remove(key),
+            _ => values.insert(key.to_owned(), (offset, len)),
+        };
+        end = offset + len + 1;
+    }
+
+    assert_eq!(index.end_of_records, end);
+    assert_eq!(index.values, values);
+});
@@ -0,0 +1,78 @@
+#ifndef KOPPER_H
+#define KOPPER_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Result of every call taking a database. Variants are named the way C code spells them.
+ */
+typedef enum KopperStatus {
+  KOPPER_OK = 0,
+  KOPPER_NOT_FOUND = 1,
+  /**
+   * Null pointer, or a string that isn't valid UTF-8
+   */
+  KOPPER_INVALID_ARGUMENT = 2,
+  KOPPER_ERROR = 3,
+} KopperStatus;
+
+/**
+ * Opaque handle of an open database
+ */
+typedef struct Kopper Kopper;
+
+/**
+ * Opens the database at `path`, creating it if needed. Returns null on failure.
+ *
+ * # Safety
+ * `path` must be null or point to a NUL-terminated string.
+ */
+struct Kopper *kopper_open(const char *path, size_t segment_size);
+
+/**
+ * Reads `key`. On success `*value` points to a copy of the value, to be freed
+ * with [`kopper_free_string`].
+ *
+ * # Safety
+ * `db` must come from [`kopper_open`], `key` must be null or NUL-terminated,
+ * `value` must be null or valid for writes.
+ */
+enum KopperStatus kopper_get(const struct Kopper *db, const char *key, char **value);
+
+/**
+ * Writes `value` under `key`
+ *
+ * # Safety
+ * `db` must come from [`kopper_open`], `key` and `value` must be null or NUL-terminated.
+ */
+enum KopperStatus kopper_put(const struct Kopper *db, const char *key, const char *value);
+
+/**
+ * Removes `key`
+ *
+ * # Safety
+ * `db` must come from [`kopper_open`], `key` must be null or NUL-terminated.
+ */
+enum KopperStatus kopper_delete(const struct Kopper *db, const char *key);
+
+/**
+ * Flushes and closes the database, freeing the handle. Null is ignored.
+ *
+ * # Safety
+ * `db` must be null or come from [`kopper_open`], and not be used afterwards.
+ */
+enum KopperStatus kopper_close(struct Kopper *db);
+
+/**
+ * Frees a value returned by [`kopper_get`]. Null is ignored.
+ *
+ * # Safety
+ * `string` must be null or come from [`kopper_get`], and not be used afterwards.
+ */
+void kopper_free_string(char *string);
+
+#endif  /* KOPPER_H */
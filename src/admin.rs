@@ -0,0 +1,330 @@
+use std::{fs, io::{self, Write}, path::PathBuf, time::{SystemTime, UNIX_EPOCH}};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use rocket::{State, Request, Response};
+use rocket::futures::stream;
+use rocket::http::{ContentType, Status};
+use rocket::response::{self, Responder, stream::ByteStream};
+use rocket::serde::json::Json;
+use rocket::tokio::sync::mpsc;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use kopperdb::kopper::Kopper;
+use kopperdb::pattern::KeyPattern;
+
+/// Tarballs are sent to the client in chunks of roughly this size
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Keys [`search`] returns at once unless asked for another number
+const DEFAULT_SEARCH_LIMIT: usize = 100;
+
+/// Directory holding one subdirectory per backup, named after the backup ID.
+/// Configured with `backup_dir` in Rocket's config.
+pub struct Backups {
+    pub dir: PathBuf,
+    /// Tells apart backups started within the same millisecond
+    taken: AtomicU64
+}
+
+impl Backups {
+    pub fn new(dir: PathBuf) -> Self {
+        Backups { dir, taken: AtomicU64::new(0) }
+    }
+
+    /// Creates the directory of a new backup, failing rather than mixing two backups in one directory
+    fn claim(&self) -> io::Result<(String, PathBuf)> {
+        let millis = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        let id = format!("backup-{millis}-{}", self.taken.fetch_add(1, Ordering::Relaxed));
+        let path = self.dir.join(&id);
+        fs::create_dir_all(&self.dir)?;
+        fs::create_dir(&path)?;
+        Ok((id, path))
+    }
+
+    /// Backup IDs end up in paths - only allow what [`create_backup`] generates
+    fn path(&self, id: &str) -> Option<PathBuf> {
+        let valid = !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+        valid.then(|| self.dir.join(id))
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct BackupResponse {
+    /// ID to pass to `GET /admin/backup/{id}`, empty if the backup failed
+    id: String,
+    /// "OK" on success, description of the problem otherwise
+    error: String
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/backup",
+    tag = "admin",
+    responses((status = 200, description = "Result of the backup", body = BackupResponse))
+)]
+#[post("/admin/backup")]
+pub async fn create_backup(db: &State<Kopper>, backups: &State<Backups>) -> Json<BackupResponse> {
+    let (id, path) = match backups.claim() {
+        Ok(claimed) => claimed,
+        Err(err) => return Json(BackupResponse { id: String::new(), error: format!("Backup failed: {err}") }),
+    };
+
+    let db = db.inner().clone();
+    // Backups usually share the filesystem with the database, so most segments are just linked
+    let result = rocket::tokio::task::spawn_blocking(move || db.snapshot_to(&path.to_string_lossy())).await;
+
+    Json(match result {
+        Ok(Ok(_)) => BackupResponse { id, error: "OK".to_string() },
+        Ok(Err(err)) => BackupResponse { id: String::new(), error: format!("Backup failed: {err}") },
+        Err(err) => BackupResponse { id: String::new(), error: format!("Backup failed: {err}") },
+    })
+}
+
+/// [`Write`] sending everything written to it over a channel, in chunks
+struct ChunkWriter {
+    sender: mpsc::Sender<Vec<u8>>,
+    buffer: Vec<u8>
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        self.sender.blocking_send(std::mem::take(&mut self.buffer))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Client disconnected"))
+    }
+}
+
+/// Tarball streamed from a [`ChunkWriter`], offered to the client as `<name>.tar`
+pub struct Tarball {
+    name: String,
+    receiver: mpsc::Receiver<Vec<u8>>
+}
+
+impl<'r> Responder<'r, 'r> for Tarball {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'r> {
+        let chunks = stream::unfold(self.receiver, |mut receiver| async move {
+            receiver.recv().await.map(|chunk| (chunk, receiver))
+        });
+
+        Response::build_from(ByteStream::from(chunks).respond_to(req)?)
+            .header(ContentType::new("application", "x-tar"))
+            .raw_header("Content-Disposition", format!("attachment; filename=\"{}.tar\"", self.name))
+            .ok()
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/backup/{id}",
+    tag = "admin",
+    params(("id" = String, Path, description = "ID returned by `POST /admin/backup`")),
+    responses(
+        (status = 200, description = "Tarball with the segment files of the backup", content_type = "application/x-tar"),
+        (status = 404, description = "No such backup")
+    )
+)]
+#[get("/admin/backup/<id>")]
+pub fn download_backup(id: &str, backups: &State<Backups>) -> Option<Tarball> {
+    let path = backups.path(id).filter(|path| path.is_dir())?;
+    Some(Tarball::of_dir(id, path, || {}))
+}
+
+impl Tarball {
+    /// Streams the files in `path` under a `name` directory, calling `done` once
+    /// they're all sent - or the client is gone
+    pub fn of_dir(name: &str, path: PathBuf, done: impl FnOnce() + Send + 'static) -> Self {
+        let (sender, receiver) = mpsc::channel(4);
+        let dir = name.to_owned();
+        std::thread::spawn(move || {
+            let mut tar = tar::Builder::new(ChunkWriter { sender, buffer: Vec::new() });
+            let result = tar.append_dir_all(&dir, &path)
+                .and_then(|_| tar.into_inner())
+                .and_then(|mut writer| writer.flush());
+
+            // Status is already sent, the best we can do is to cut the stream short
+            if let Err(err) = result {
+                tracing::error!("Sending {dir} failed: {err}");
+            }
+            done();
+        });
+
+        Tarball { name: name.to_owned(), receiver }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CompactResponse {
+    /// Number of segments queued for compaction
+    queued: usize,
+    /// "OK" on success, description of the problem otherwise
+    error: String
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/compact",
+    tag = "admin",
+    responses((status = 200, description = "Compactions queued, they finish in the background", body = CompactResponse))
+)]
+#[post("/admin/compact")]
+pub fn compact(db: &State<Kopper>) -> Json<CompactResponse> {
+    Json(match db.compact() {
+        Ok(queued) => CompactResponse { queued, error: "OK".to_string() },
+        Err(err) => CompactResponse { queued: 0, error: format!("Compaction failed: {err}") },
+    })
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RollResponse {
+    /// Active segment from now on, empty on failure
+    segment: String,
+    /// "OK" on success, description of the problem otherwise
+    error: String
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/roll",
+    tag = "admin",
+    responses((status = 200, description = "Active segment sealed, writes go to a new one", body = RollResponse))
+)]
+#[post("/admin/roll")]
+pub fn roll(db: &State<Kopper>) -> Json<RollResponse> {
+    Json(match db.roll_segment() {
+        Ok(segment) => RollResponse { segment, error: "OK".to_string() },
+        Err(err) => RollResponse { segment: String::new(), error: format!("Rolling the segment failed: {err}") },
+    })
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct DoctorCheck {
+    /// permissions, lock, manifest, descriptors, compactor or fragmentation
+    name: String,
+    /// "ok", "warning" or "failing"
+    health: String,
+    /// What was found
+    detail: String,
+    /// What to do about it, missing if it's healthy
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remedy: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct DoctorResponse {
+    /// Worst of the checks
+    health: String,
+    checks: Vec<DoctorCheck>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/doctor",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Outcome of every check, with suggested remedies", body = DoctorResponse),
+        (status = 503, description = "Database is closed")
+    )
+)]
+#[get("/admin/doctor")]
+pub fn doctor(db: &State<Kopper>) -> Result<Json<DoctorResponse>, Status> {
+    let report = db.doctor().map_err(|_| Status::ServiceUnavailable)?;
+    Ok(Json(DoctorResponse {
+        health: report.health().to_string(),
+        checks: report.checks.into_iter()
+            .map(|check| DoctorCheck { name: check.name, health: check.health.to_string(), detail: check.detail, remedy: check.remedy })
+            .collect(),
+    }))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SearchResponse {
+    /// Matching keys in order
+    keys: Vec<String>,
+    /// Pass as `after` for the next page, none if this is the last one
+    next: Option<String>,
+    /// "OK" on success, description of the problem otherwise
+    error: String
+}
+
+#[utoipa::path(
+    get,
+    path = "/search",
+    tag = "admin",
+    params(
+        ("pattern" = String, Query, description = "Glob - `*` matches anything, `?` one character - e.g. `user:*:settings`"),
+        ("regex" = Option<bool>, Query, description = "Take the pattern for a regular expression instead, false by default"),
+        ("after" = Option<String>, Query, description = "Only return keys after this one, e.g. the `next` of the previous page"),
+        ("limit" = Option<usize>, Query, description = "Most keys to return, 100 by default")
+    ),
+    responses((status = 200, description = "A page of keys the pattern matches", body = SearchResponse))
+)]
+#[get("/search?<pattern>&<regex>&<after>&<limit>")]
+pub fn search(pattern: &str, regex: Option<bool>, after: Option<&str>, limit: Option<usize>, db: &State<Kopper>) -> Json<SearchResponse> {
+    let failed = |error: String| Json(SearchResponse { keys: Vec::new(), next: None, error });
+    let pattern = match regex.unwrap_or(false) {
+        true => match KeyPattern::regex(pattern) {
+            Ok(pattern) => pattern,
+            Err(err) => return failed(format!("Invalid pattern! : {err}")),
+        },
+        false => KeyPattern::Glob(pattern.to_owned()),
+    };
+
+    // One extra key tells whether there's another page
+    let limit = limit.unwrap_or(DEFAULT_SEARCH_LIMIT).max(1);
+    match db.scan_match(&pattern, after, limit.saturating_add(1)) {
+        Ok(mut keys) => {
+            let next = (keys.len() > limit).then(|| {
+                keys.truncate(limit);
+                keys[limit - 1].clone()
+            });
+            Json(SearchResponse { keys, next, error: "OK".to_string() })
+        },
+        Err(err) => failed(format!("Error while searching! : {err}")),
+    }
+}
+
+/// TESTS
+
+#[test]
+fn test_backups_are_taken_and_downloaded_as_tarballs() {
+    use rocket::local::blocking::Client;
+
+    let db = kopperdb::testing::TempDb::new();
+    let kopper = db.kopper(4096).unwrap();
+    kopper.write("a", "1").unwrap();
+    let backups = Backups::new(PathBuf::from(db.join("backups")));
+    let client = Client::tracked(rocket::build().manage(kopper.clone()).manage(backups)
+        .mount("/", routes![create_backup, download_backup])).unwrap();
+
+    let response: serde_json::Value = client.post("/admin/backup").dispatch().into_json().unwrap();
+    assert_eq!(response["error"], "OK");
+    let id = response["id"].as_str().unwrap().to_owned();
+    // Written after the backup, so not in it
+    kopper.write("b", "2").unwrap();
+
+    let response = client.get(format!("/admin/backup/{id}")).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::new("application", "x-tar")));
+    assert_eq!(response.headers().get_one("Content-Disposition"), Some(format!("attachment; filename=\"{id}.tar\"").as_str()));
+
+    // Unpacked, it opens as the database was
+    let restored = db.join("restored");
+    tar::Archive::new(&response.into_bytes().unwrap()[..]).unpack(&restored).unwrap();
+    let copy = Kopper::create(&format!("{restored}/{id}"), 4096).unwrap();
+    assert_eq!(copy.read("a").unwrap(), "1");
+    assert!(copy.read("b").is_err());
+
+    assert_eq!(client.get("/admin/backup/missing").dispatch().status(), Status::NotFound);
+    assert_eq!(client.get("/admin/backup/..").dispatch().status(), Status::NotFound);
+}
@@ -1,36 +1,158 @@
 #![allow(unused)]
 
-use std::time::Instant;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use rocket::State;
+use rocket::{State, Request, Response};
+use rocket::http::{ContentType, Status};
+use rocket::response::{self, Responder};
 use rocket::serde::json::Json;
-use rocket::fs::NamedFile;
+use rocket::response::content::RawHtml;
+use rocket::response::stream::{Event, EventStream};
+use rocket::Shutdown;
 use serde::Serialize;
+use utoipa::{OpenApi, ToSchema};
 
-use czkawka::kopper::*;
-use czkawka::brass::*;
-use czkawka::stats::{Stats, self, Stat};
+use kopperdb::kopper::*;
+use kopperdb::brass::*;
+use kopperdb::engine::{Durability, KvEngine, Shadowed};
+use kopperdb::compression::Compression;
+use kopperdb::dedupe;
+use kopperdb::packed::Packing;
+use kopperdb::encryption::{EncryptedStore, EncryptionKey};
+use kopperdb::store::{LocalStore, Placement, SegmentStore, SpreadStore, Tier, TieredStore};
+use kopperdb::lsm::{self, Lsm};
+use kopperdb::stats::{Stats, self, Stat, Operation, Unit, ChartFormat};
+use kopperdb::metrics::{MetricsSink, Fanout, StatsdSink};
+use kopperdb::schema::Schemas;
 
-#[derive(Serialize)]
+use crate::audit::{AuditLog, Auditor};
+use crate::logging::{RequestId, RequestLogger};
+use crate::registry::{Registry, DatabaseInfo, DatabaseUsage, Readiness, RecoveringDatabase};
+use crate::admin::Backups;
+use crate::reload::{Reloader, Tunables};
+use crate::version::{self, ApiVersion};
+
+#[derive(Serialize, ToSchema)]
 pub struct ReadResponse {
+    /// Value stored under the key, empty if the read failed
     value: String,
+    /// "OK" on success, description of the problem otherwise
     error: String
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct WriteResponse {
-    error: String
+    /// "OK" on success, description of the problem otherwise
+    pub error: String
+}
+
+/// Engine behind the key-value routes, picked when the server is mounted
+pub type Engine = Arc<dyn KvEngine>;
+
+/// How long writes waiting for a replica wait unless told otherwise
+const REPLICATION_TIMEOUT_MS: u64 = 5000;
+
+/// What a write asks to wait for before it's acknowledged
+#[derive(FromForm)]
+pub struct Wait<'r> {
+    wait: Option<&'r str>,
+    timeout_ms: Option<u64>,
+}
+
+impl Wait<'_> {
+    /// Fails for unknown values - silently acknowledging sooner than asked would be worse than failing
+    fn durability(&self) -> Result<Option<Durability>, Status> {
+        match self.wait {
+            None => Ok(None),
+            Some("flush") => Ok(Some(Durability::Flush)),
+            Some("replica") => Ok(Some(Durability::Replica(Duration::from_millis(self.timeout_ms.unwrap_or(REPLICATION_TIMEOUT_MS))))),
+            Some(_) => Err(Status::BadRequest),
+        }
+    }
+}
+
+/// How long clients are asked to wait before retrying requests that timed out
+const RETRY_AFTER_SECS: u64 = 1;
+
+/// Budgets of the key-value read and write routes, from `read_timeout_ms` and
+/// `write_timeout_ms`. Without one, requests wait for the engine however long it takes.
+#[derive(Clone, Copy, Default)]
+pub struct Timeouts {
+    pub read: Option<Duration>,
+    pub write: Option<Duration>,
+}
+
+impl Timeouts {
+    pub fn from_config(figment: &rocket::figment::Figment) -> Self {
+        let budget = |name| figment.extract_inner::<u64>(name).ok().map(Duration::from_millis);
+        Timeouts { read: budget("read_timeout_ms"), write: budget("write_timeout_ms") }
+    }
 }
 
-pub trait Database {
-    fn read(&self, key: &str) -> Result<String, KopperError>;
-    fn write(&self, key: &str, value: &str) -> Result<usize, KopperError>;
+/// Answer to a request that ran out of its budget, see [`Timeouts`]: 503, with a
+/// `Retry-After` hint
+pub struct TimedOut;
+
+impl<'r> Responder<'r, 'static> for TimedOut {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        Response::build_from("Timed out waiting for the database, try again later".respond_to(req)?)
+            .status(Status::ServiceUnavailable)
+            .raw_header("Retry-After", RETRY_AFTER_SECS.to_string())
+            .ok()
+    }
+}
+
+/// Runs `operation` on a blocking thread with a deadline `budget` from now, answering
+/// with [`TimedOut`] once it passes - even if the engine is stuck on the disk, so
+/// one request can't tie up a worker. The engine gives up by itself at its next
+/// cancellation point, see [`KvEngine::read_until`]. Without a budget, `operation`
+/// runs right here with no deadline. Only for reads, see [`writing_within`].
+async fn within<T: Send + 'static>(budget: Option<Duration>, operation: impl FnOnce(Option<Instant>) -> Result<T, TimedOut> + Send + 'static) -> Result<T, TimedOut> {
+    let Some(budget) = budget else {
+        return operation(None);
+    };
+    let deadline = Instant::now() + budget;
+    let running = rocket::tokio::task::spawn_blocking(move || operation(Some(deadline)));
+    match rocket::tokio::time::timeout(budget, running).await {
+        Ok(Ok(done)) => done,
+        Ok(Err(panicked)) => std::panic::resume_unwind(panicked.into_panic()),
+        Err(_) => Err(TimedOut),
+    }
+}
+
+/// Like [`within`] for writes, which aren't abandoned once they start. The engine
+/// fails with [`KopperError::TimedOut`] itself if the write can't start by the
+/// deadline, see [`KvEngine::write_until`], and a write that did is waited for
+/// however late - so a 503 always means nothing was written, and retrying is safe.
+async fn writing_within<T: Send + 'static>(budget: Option<Duration>, operation: impl FnOnce(Option<Instant>) -> Result<T, TimedOut> + Send + 'static) -> Result<T, TimedOut> {
+    let Some(budget) = budget else {
+        return operation(None);
+    };
+    let deadline = Instant::now() + budget;
+    blocking(move || operation(Some(deadline))).await
+}
+
+/// Runs `operation` on a blocking thread, for listeners calling the engine from
+/// their async tasks - the engine waits on its lock and the disk
+pub async fn blocking<T: Send + 'static>(operation: impl FnOnce() -> T + Send + 'static) -> T {
+    match rocket::tokio::task::spawn_blocking(operation).await {
+        Ok(done) => done,
+        Err(panicked) => std::panic::resume_unwind(panicked.into_panic()),
+    }
 }
 
-pub fn read(key: &str, db: &impl Database, stats: &State<Stats>) -> Json<ReadResponse> {
+/// Reads through `db`, giving up at `deadline` if there's one
+pub fn read(key: &str, db: &(impl KvEngine + ?Sized), deadline: Option<Instant>, metrics: &Metrics, id: &RequestId) -> Result<Json<ReadResponse>, TimedOut> {
     let timer = Instant::now();
+    // A missing key is a valid answer, only internal errors count as failures
+    let mut failed = false;
     
-    let response = match db.read(key) {
+    let read = id.span(key).in_scope(|| match deadline {
+        Some(deadline) => db.read_until(key, deadline),
+        None => db.read(key),
+    });
+    let response = match read {
 
         // Database operation successful
         Ok(value) => {
@@ -49,107 +171,990 @@ pub fn read(key: &str, db: &impl Database, stats: &State<Stats>) -> Json<ReadRes
             }
         },
 
+        Err(KopperError::TimedOut) => {
+            metrics.record(Stat::Completed(Operation::Read, true));
+            return Err(TimedOut);
+        },
+
         Err(other) => {
-            println!("{other}");
+            tracing::error!(request_id = %id, "{other}");
+            failed = true;
 
             ReadResponse { 
-                value: format!(""),
-                error: format!("Internal Error")
+                value: String::new(),
+                error: "Internal Error".to_string()
             }
         }
     };
     
-    stats.send(Stat::ReadTime(timer.elapsed().as_nanos()));
-    Json(response)
+    metrics.record(Stat::ReadTime(timer.elapsed().as_nanos()));
+    metrics.record(Stat::Completed(Operation::Read, failed));
+    Ok(Json(response))
 }
 
-pub fn write(key: &str, value: &str, db: &impl Database, stats: &State<Stats>) -> Json<WriteResponse> {
+/// Writes through `db`, acknowledging once the write is as durable as asked for.
+/// Gives up at `deadline` if there's one, unless it waits for durability.
+pub fn write(key: &str, value: &str, durability: Option<Durability>, db: &(impl KvEngine + ?Sized), deadline: Option<Instant>, metrics: &Metrics, id: &RequestId) -> Result<Json<WriteResponse>, TimedOut> {
     let timer = Instant::now();
+    let mut failed = false;
 
-    let response = match db.write(key, value) {
+    let written = id.span(key).in_scope(|| match (durability, deadline) {
+        (Some(durability), _) => db.write_durable(key, value, durability),
+        (None, Some(deadline)) => db.write_until(key, value, deadline),
+        (None, None) => db.write(key, value),
+    });
+    let response = match written {
 
         // Database opration successful = write successful
         Ok(size) => {
-            stats.send(Stat::Size(size as u128));
+            metrics.record(Stat::Size(size as u128));
             WriteResponse { error: "OK".to_string() }  
         },
 
+        Err(KopperError::TimedOut) => {
+            metrics.record(Stat::Completed(Operation::Write, true));
+            return Err(TimedOut);
+        },
+
         Err(err) => {
+            failed = true;
             WriteResponse { error: format!("Error while writing! : {}", err) }
         }
     };
 
-    stats.send(Stat::WriteTime(timer.elapsed().as_nanos()));
-    Json(response)
+    metrics.record(Stat::WriteTime(timer.elapsed().as_nanos()));
+    metrics.record(Stat::Completed(Operation::Write, failed));
+    Ok(Json(response))
 }
 
+#[utoipa::path(
+    get,
+    path = "/read/{key}",
+    tag = "kopper",
+    params(("key" = String, Path, description = "Key to read")),
+    responses(
+        (status = 200, description = "Result of the read", body = ReadResponse),
+        (status = 503, description = "Timed out waiting for the database, retry after Retry-After seconds")
+    )
+)]
 #[get("/read/<key>")]
-pub fn read_kopper(key: &str, db: &State<Kopper>, stats: &State<Stats>) -> Json<ReadResponse> {
-    read(key, db.inner(), stats)
+pub async fn read_kopper(key: &str, db: &State<Engine>, timeouts: &State<Timeouts>, metrics: &State<Metrics>, id: RequestId) -> Result<Json<ReadResponse>, TimedOut> {
+    let (key, db, metrics) = (key.to_owned(), db.inner().clone(), metrics.inner().clone());
+    within(timeouts.read, move |deadline| read(&key, db.as_ref(), deadline, &metrics, &id)).await
 }
 
-#[get("/write/<key>/<value>")]
-pub fn write_kopper(key: &str, value: &str, db: &State<Kopper>, stats: &State<Stats>) -> Json<WriteResponse> {
-    write(key, value, db.inner(), stats)
+#[utoipa::path(
+    get,
+    path = "/write/{key}/{value}",
+    tag = "kopper",
+    params(
+        ("key" = String, Path, description = "Key to write"),
+        ("value" = String, Path, description = "Value to store under the key"),
+        ("wait" = Option<String>, Query, description = "flush to acknowledge once the write is fsynced, \
+            replica once a replica applied it"),
+        ("timeout_ms" = Option<u64>, Query, description = "How long to wait for a replica, 5000 by default. \
+            The write is kept even if none confirms it in time")
+    ),
+    responses(
+        (status = 200, description = "Result of the write", body = WriteResponse),
+        (status = 400, description = "Unknown wait"),
+        (status = 503, description = "Timed out waiting for the database, retry after Retry-After seconds")
+    )
+)]
+#[get("/write/<key>/<value>?<wait..>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn write_kopper(key: &str, value: &str, wait: Wait<'_>, db: &State<Engine>, schemas: &State<Arc<Schemas>>, timeouts: &State<Timeouts>, metrics: &State<Metrics>, id: RequestId) -> Result<Result<Json<WriteResponse>, TimedOut>, Status> {
+    if let Err(err) = schemas.check(key, value) {
+        metrics.record(Stat::Completed(Operation::Write, true));
+        return Ok(Ok(Json(WriteResponse { error: format!("Error while writing! : {err}") })));
+    }
+    match wait.durability()? {
+        None => {
+            let (key, value, db, metrics) = (key.to_owned(), value.to_owned(), db.inner().clone(), metrics.inner().clone());
+            Ok(writing_within(timeouts.write, move |deadline| write(&key, &value, None, db.as_ref(), deadline, &metrics, &id)).await)
+        },
+        // Fsyncs and replicas take a while, don't hold up other requests on this worker.
+        // Waiting for a replica is bounded by timeout_ms instead of the budget
+        durability => Ok(rocket::tokio::task::block_in_place(|| write(key, value, durability, db.as_ref(), None, metrics, &id))),
+    }
 }
 
+#[utoipa::path(
+    delete,
+    path = "/delete/{key}",
+    tag = "kopper",
+    params(("key" = String, Path, description = "Key to delete")),
+    responses((status = 200, description = "Result of the delete", body = WriteResponse))
+)]
+#[delete("/delete/<key>")]
+pub fn delete_kopper(key: &str, db: &State<Engine>, metrics: &State<Metrics>, id: RequestId) -> Json<WriteResponse> {
+    let timer = Instant::now();
+    let mut failed = false;
+
+    let response = match id.span(key).in_scope(|| db.delete(key)) {
+        Ok(size) => {
+            metrics.record(Stat::Size(size as u128));
+            WriteResponse { error: "OK".to_string() }
+        },
+        Err(KopperError::KeyDoesNotExist(_)) => WriteResponse { error: format!("{key} does not exist!") },
+        Err(err) => {
+            failed = true;
+            WriteResponse { error: format!("Error while deleting! : {}", err) }
+        }
+    };
+
+    // Deletes append a tombstone, so they're measured as writes
+    metrics.record(Stat::WriteTime(timer.elapsed().as_nanos()));
+    metrics.record(Stat::Completed(Operation::Write, failed));
+    Json(response)
+}
+
+#[utoipa::path(
+    post,
+    path = "/rename/{key}/{to}",
+    tag = "kopper",
+    params(
+        ("key" = String, Path, description = "Key to rename"),
+        ("to" = String, Path, description = "New name of the key, replaced if it exists")
+    ),
+    responses((status = 200, description = "Result of the rename", body = WriteResponse))
+)]
+#[post("/rename/<key>/<to>")]
+pub fn rename_kopper(key: &str, to: &str, db: &State<Kopper>, metrics: &State<Metrics>, id: RequestId) -> Json<WriteResponse> {
+    relocate(key, metrics, &id, || db.rename(key, to))
+}
+
+#[utoipa::path(
+    post,
+    path = "/copy/{key}/{to}",
+    tag = "kopper",
+    params(
+        ("key" = String, Path, description = "Key to copy"),
+        ("to" = String, Path, description = "Key to copy it to, replaced if it exists")
+    ),
+    responses((status = 200, description = "Result of the copy", body = WriteResponse))
+)]
+#[post("/copy/<key>/<to>")]
+pub fn copy_kopper(key: &str, to: &str, db: &State<Kopper>, metrics: &State<Metrics>, id: RequestId) -> Json<WriteResponse> {
+    relocate(key, metrics, &id, || db.copy(key, to))
+}
+
+#[utoipa::path(
+    post,
+    path = "/undelete/{key}",
+    tag = "kopper",
+    params(("key" = String, Path, description = "Key to restore the deleted value of - see `undelete_window_ms` in Rocket.toml")),
+    responses((status = 200, description = "Result of the undelete", body = WriteResponse))
+)]
+#[post("/undelete/<key>")]
+pub fn undelete_kopper(key: &str, db: &State<Kopper>, metrics: &State<Metrics>, id: RequestId) -> Json<WriteResponse> {
+    relocate(key, metrics, &id, || db.undelete(key))
+}
+
+/// Renames, copies or undeletes `key` with `relocate`, measured like a write
+fn relocate(key: &str, metrics: &Metrics, id: &RequestId, relocate: impl FnOnce() -> Result<Commit, KopperError>) -> Json<WriteResponse> {
+    let timer = Instant::now();
+    let mut failed = false;
+
+    let response = match id.span(key).in_scope(relocate) {
+        Ok(commit) => {
+            metrics.record(Stat::Size(commit.size as u128));
+            WriteResponse { error: "OK".to_string() }
+        },
+        Err(KopperError::KeyDoesNotExist(_)) => WriteResponse { error: format!("{key} does not exist!") },
+        Err(err) => {
+            failed = true;
+            WriteResponse { error: format!("Error while writing! : {}", err) }
+        }
+    };
+
+    metrics.record(Stat::WriteTime(timer.elapsed().as_nanos()));
+    metrics.record(Stat::Completed(Operation::Write, failed));
+    Json(response)
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct VersionsResponse {
+    /// The value of the key and the ones it had before, newest first
+    versions: Vec<String>,
+    /// "OK" on success, description of the problem otherwise
+    error: String
+}
+
+#[utoipa::path(
+    get,
+    path = "/versions/{key}",
+    tag = "kopper",
+    params(("key" = String, Path, description = "Key to read, with the previous values kept of it - see `versions` in Rocket.toml")),
+    responses((status = 200, description = "Result of the read", body = VersionsResponse))
+)]
+#[get("/versions/<key>")]
+pub fn read_versions(key: &str, db: &State<Kopper>) -> Json<VersionsResponse> {
+    Json(match db.read_versions(key) {
+        Ok(versions) => VersionsResponse { versions, error: "OK".to_string() },
+        Err(KopperError::KeyDoesNotExist(_)) => VersionsResponse { versions: Vec::new(), error: format!("{key} does not exist!") },
+        Err(err) => VersionsResponse { versions: Vec::new(), error: format!("Error while reading! : {err}") },
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/read/b/{key}",
+    tag = "brass",
+    params(("key" = String, Path, description = "Key to read")),
+    responses(
+        (status = 200, description = "Result of the read", body = ReadResponse),
+        (status = 503, description = "Timed out waiting for the database, retry after Retry-After seconds")
+    )
+)]
 #[get("/read/b/<key>")]
-pub fn read_brass(key: &str, db: &State<Brass>, stats: &State<Stats>) -> Json<ReadResponse> {
-    read(key, db.inner(), stats)
+pub async fn read_brass(key: &str, db: &State<Brass>, timeouts: &State<Timeouts>, metrics: &State<Metrics>, id: RequestId) -> Result<Json<ReadResponse>, TimedOut> {
+    let (key, db, metrics) = (key.to_owned(), db.inner().clone(), metrics.inner().clone());
+    within(timeouts.read, move |deadline| read(&key, &db, deadline, &metrics, &id)).await
 }
 
+#[utoipa::path(
+    get,
+    path = "/write/b/{key}/{value}",
+    tag = "brass",
+    params(
+        ("key" = String, Path, description = "Key to write"),
+        ("value" = String, Path, description = "Value to store under the key")
+    ),
+    responses(
+        (status = 200, description = "Result of the write", body = WriteResponse),
+        (status = 503, description = "Timed out waiting for the database, retry after Retry-After seconds")
+    )
+)]
 #[get("/write/b/<key>/<value>")]
-pub fn write_brass(key: &str, value: &str, db: &State<Brass>, stats: &State<Stats>) -> Json<WriteResponse> {
-    write(key, value, db.inner(), stats)
+pub async fn write_brass(key: &str, value: &str, db: &State<Brass>, timeouts: &State<Timeouts>, metrics: &State<Metrics>, id: RequestId) -> Result<Json<WriteResponse>, TimedOut> {
+    let (key, value, db, metrics) = (key.to_owned(), value.to_owned(), db.inner().clone(), metrics.inner().clone());
+    writing_within(timeouts.write, move |deadline| write(&key, &value, None, &db, deadline, &metrics, &id)).await
 }
 
-#[get("/stats/<read_or_write>")]
-pub async fn get_stats(read_or_write: String, stats: &State<Stats>) -> Option<NamedFile> {
-    
-    match read_or_write.as_str() {
-        "read" => {
-            let read_counter = stats.counters.read_counter.lock().unwrap();
-            stats::draw(&*read_counter, "Reads", "us").expect("Drawing");
+#[derive(Serialize, ToSchema)]
+pub struct ChangeResponse {
+    key: String,
+    /// Missing for deletes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>
+}
+
+impl ChangeResponse {
+    /// Splits an engine event into its SSE event name and payload
+    pub fn from_event(event: ChangeEvent) -> (&'static str, Self) {
+        match event {
+            ChangeEvent::Write { key, value } => ("write", ChangeResponse { key, value: Some(value) }),
+            ChangeEvent::Delete { key } => ("delete", ChangeResponse { key, value: None }),
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/watch",
+    tag = "kopper",
+    params(("prefix" = Option<String>, Query, description = "Only report keys starting with this prefix")),
+    responses((status = 200, description = "Stream of `write` and `delete` events carrying a ChangeResponse", content_type = "text/event-stream"))
+)]
+#[get("/watch?<prefix>")]
+pub fn watch(prefix: Option<&str>, db: &State<Kopper>, mut shutdown: Shutdown) -> EventStream![] {
+    // Ends when the database closes, or once the stream is dropped after the client disconnects
+    let (sender, mut receiver) = rocket::tokio::sync::mpsc::unbounded_channel();
+    let subscription = db.watch_into(prefix.unwrap_or(""), move |event| sender.send(event).is_ok());
+
+    EventStream! {
+        let _subscription = subscription;
+        loop {
+            let event = rocket::tokio::select! {
+                event = receiver.recv() => match event {
+                    Some(event) => event,
+                    None => break,
+                },
+                _ = &mut shutdown => break,
+            };
+
+            let (name, change) = ChangeResponse::from_event(event);
+            yield Event::json(&change).event(name);
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/db",
+    tag = "databases",
+    responses((status = 200, description = "All configured databases", body = [DatabaseInfo]))
+)]
+#[get("/db")]
+pub fn list_databases(registry: &State<Arc<Registry>>) -> Json<Vec<DatabaseInfo>> {
+    Json(registry.list())
+}
+
+#[utoipa::path(
+    get,
+    path = "/ready",
+    tag = "databases",
+    responses(
+        (status = 200, description = "Every database opened so far is serving", body = Readiness),
+        (status = 503, description = "Databases are still being recovered", body = Readiness)
+    )
+)]
+#[get("/ready")]
+pub fn ready(registry: &State<Arc<Registry>>, kopper: &State<Kopper>) -> (Status, Json<Readiness>) {
+    let readiness = registry.readiness(kopper);
+    let status = match readiness.ready {
+        true => Status::Ok,
+        false => Status::ServiceUnavailable,
+    };
+    (status, Json(readiness))
+}
+
+#[utoipa::path(
+    get,
+    path = "/db/{name}/read/{key}",
+    tag = "databases",
+    params(
+        ("name" = String, Path, description = "Name of the database"),
+        ("key" = String, Path, description = "Key to read")
+    ),
+    responses(
+        (status = 200, description = "Result of the read", body = ReadResponse),
+        (status = 404, description = "No such database"),
+        (status = 503, description = "Timed out waiting for the database, retry after Retry-After seconds")
+    )
+)]
+#[get("/db/<name>/read/<key>")]
+pub async fn read_named(name: &str, key: &str, registry: &State<Arc<Registry>>, timeouts: &State<Timeouts>, metrics: &State<Metrics>, id: RequestId) -> Option<Result<Json<ReadResponse>, TimedOut>> {
+    match registry.get(name)? {
+        Ok(db) => {
+            let (key, metrics) = (key.to_owned(), metrics.inner().clone());
+            Some(within(timeouts.read, move |deadline| read(&key, &db, deadline, &metrics, &id)).await)
+        },
+        Err(err) => {
+            tracing::error!(request_id = %id, "Can't open database {name}: {err}");
+            Some(Ok(Json(ReadResponse { value: String::new(), error: "Internal Error".to_string() })))
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/db/{name}/write/{key}/{value}",
+    tag = "databases",
+    params(
+        ("name" = String, Path, description = "Name of the database"),
+        ("key" = String, Path, description = "Key to write"),
+        ("value" = String, Path, description = "Value to store under the key")
+    ),
+    responses(
+        (status = 200, description = "Result of the write", body = WriteResponse),
+        (status = 404, description = "No such database"),
+        (status = 503, description = "Timed out waiting for the database, retry after Retry-After seconds")
+    )
+)]
+#[get("/db/<name>/write/<key>/<value>")]
+pub async fn write_named(name: &str, key: &str, value: &str, registry: &State<Arc<Registry>>, timeouts: &State<Timeouts>, metrics: &State<Metrics>, id: RequestId) -> Option<Result<Json<WriteResponse>, TimedOut>> {
+    match registry.get(name)? {
+        Ok(db) => {
+            let durability = registry.durability(name);
+            // Writes waiting for durability aren't cut short, see write_kopper
+            let budget = timeouts.write.filter(|_| durability.is_none());
+            let (key, value, metrics) = (key.to_owned(), value.to_owned(), metrics.inner().clone());
+            Some(writing_within(budget, move |deadline| write(&key, &value, durability, &db, deadline, &metrics, &id)).await)
         },
-        "write" => {
-            let write_counter = stats.counters.write_counter.lock().unwrap();
-            stats::draw(&*write_counter, "Writes", "us").expect("Drawing");
+        Err(err) => Some(Ok(Json(WriteResponse { error: format!("Error while writing! : {}", err) })))
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/stats/{read_or_write}",
+    tag = "stats",
+    params(
+        ("read_or_write" = String, Path, description = "One of: read, write, size, request, throughput, errors, compaction, keys, shadow, compression, discrepancies, saturation"),
+        ("route" = Option<String>, Query, description = "For `request`: only requests handled by this route, see /stats/labels"),
+        ("status" = Option<u16>, Query, description = "For `request`: only requests answered with this status"),
+        ("op" = Option<String>, Query, description = "For `throughput` and `errors`: read or write, both by default"),
+        ("of" = Option<String>, Query, description = "For `compaction`: reclaimed (default), duration or segments. For `shadow`: shadow (default) or primary"),
+        ("window" = Option<String>, Query, description = "Time span to chart, like 30s, 15m or 2h. Everything still retained by default"),
+        ("format" = Option<String>, Query, description = "png (default) or svg")
+    ),
+    responses(
+        (status = 200, description = "Chart of the chosen metric over time", content_type = "image/png"),
+        (status = 400, description = "Malformed window or unknown format"),
+        (status = 404, description = "Unknown metric, or nothing recorded for it yet")
+    )
+)]
+#[get("/stats/<read_or_write>?<window>&<format>&<filter..>")]
+pub async fn get_stats(read_or_write: String, filter: Filter<'_>, window: Option<&str>, format: Option<&str>, stats: &State<Stats>) -> Result<(ContentType, Vec<u8>), Status> {
+    let window = window.map(|window| parse_window(window).ok_or(Status::BadRequest)).transpose()?;
+    let (format, content_type) = match format {
+        None | Some("png") => (ChartFormat::Png, ContentType::PNG),
+        Some("svg") => (ChartFormat::Svg, ContentType::SVG),
+        Some(_) => return Err(Status::BadRequest),
+    };
+    let (series, label, unit) = metric(&read_or_write, &filter, stats).ok_or(Status::NotFound)?;
+
+    // Nothing to chart in an empty series
+    if series.samples().is_empty() {
+        return Err(Status::NotFound);
+    }
+
+    // Rendering is CPU-bound - keep it off the async workers
+    let now = stats.now_millis();
+    let chart = rocket::tokio::task::spawn_blocking(move || {
+        stats::render(&series, &label, unit, window, now, format).map_err(|err| err.to_string())
+    }).await;
+
+    match chart {
+        Ok(Ok(chart)) => Ok((content_type, chart)),
+        Ok(Err(err)) => {
+            tracing::error!("Can't render {read_or_write} chart: {err}");
+            Err(Status::InternalServerError)
         },
-        "size" => {
-            let size_metric = stats.counters.size.lock().unwrap();
-            stats::draw(&*size_metric, "Size", "KB").expect("Drawing");
+        Err(err) => {
+            tracing::error!("Can't render {read_or_write} chart: {err}");
+            Err(Status::InternalServerError)
+        },
+    }
+}
+
+/// Parses durations like `90s`, `15m`, `2h` or `1d`
+fn parse_window(window: &str) -> Option<Duration> {
+    let split = window.find(|c: char| !c.is_ascii_digit())?;
+    let (count, unit) = window.split_at(split);
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    Some(Duration::from_secs(count.parse::<u64>().ok()?.checked_mul(seconds)?))
+}
+
+/// Narrows down the series of a metric, each field only applies to some metrics
+#[derive(FromForm)]
+pub struct Filter<'r> {
+    route: Option<&'r str>,
+    status: Option<u16>,
+    op: Option<&'r str>,
+    of: Option<&'r str>,
+}
+
+/// Copy of the series behind a metric name, with its chart label and display unit
+fn metric(name: &str, filter: &Filter<'_>, stats: &Stats) -> Option<(stats::Series, String, Unit)> {
+    let counters = &stats.counters;
+    Some(match name {
+        "read" => (counters.read_counter.lock().unwrap().clone(), "Reads".to_string(), Unit::MICROS),
+        "write" => (counters.write_counter.lock().unwrap().clone(), "Writes".to_string(), Unit::MICROS),
+        "size" => (counters.size.lock().unwrap().clone(), "Size".to_string(), Unit::KB),
+        "request" => {
+            let Filter { route, status, .. } = *filter;
+            let label = format!("{} {}", route.unwrap_or("All routes"), status.map(|s| s.to_string()).unwrap_or_default());
+            (counters.requests_matching(route, status), label.trim_end().to_string(), Unit::MICROS)
+        },
+        "compaction" => match filter.of {
+            None | Some("reclaimed") => (counters.compaction_reclaimed.lock().unwrap().clone(), "Reclaimed per compaction".to_string(), Unit::KB),
+            Some("duration") => (counters.compaction_duration.lock().unwrap().clone(), "Compaction duration".to_string(), Unit::MILLIS),
+            Some("segments") => (counters.segments.lock().unwrap().clone(), "Segments after compaction".to_string(), Unit::COUNT),
+            Some(_) => return None,
+        },
+        "keys" => (counters.keys.lock().unwrap().clone(), "Keys".to_string(), Unit::COUNT),
+        "compression" => (counters.compression.lock().unwrap().clone(), "Compressed size of values".to_string(), Unit::PERCENT),
+        "discrepancies" => (counters.discrepancies.lock().unwrap().clone(), "Corrupt or mismatched keys found".to_string(), Unit::COUNT),
+        "saturation" => match filter.of {
+            None | Some("open_segments") => (counters.open_segments.lock().unwrap().clone(), "Open segments".to_string(), Unit::COUNT),
+            Some("compactor_queue") => (counters.compactor_queue.lock().unwrap().clone(), "Compactions queued".to_string(), Unit::COUNT),
+            Some("lock_wait") => (counters.lock_wait.lock().unwrap().clone(), "Time waiting for the lock".to_string(), Unit::MICROS),
+            Some(_) => return None,
+        },
+        "shadow" => match filter.of {
+            None | Some("shadow") => (counters.shadow_secondary.lock().unwrap().clone(), "Shadow engine writes".to_string(), Unit::MICROS),
+            Some("primary") => (counters.shadow_primary.lock().unwrap().clone(), "Primary engine writes".to_string(), Unit::MICROS),
+            Some(_) => return None,
+        },
+        "throughput" | "errors" => {
+            let (rates, op) = match filter.op {
+                Some("read") => (counters.read_rates.lock().unwrap().clone(), "Read"),
+                Some("write") => (counters.write_rates.lock().unwrap().clone(), "Write"),
+                None => (counters.read_rates.lock().unwrap().merged(&counters.write_rates.lock().unwrap()), "Read and write"),
+                Some(_) => return None,
+            };
+            match name {
+                "throughput" => (rates.throughput(stats.now_millis()), format!("{op} throughput"), Unit::OPS),
+                _ => (rates.error_rate(), format!("{op} error rate"), Unit::PERCENT),
+            }
         },
         _ => return None
+    })
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SeriesStats {
+    /// Unit of the percentiles: us for latencies, KB for size, ops/s for throughput, % for errors
+    unit: &'static str,
+    /// Number of samples recorded since startup
+    count: u64,
+    p50: u64,
+    p90: u64,
+    p99: u64,
+    p999: u64,
+}
+
+impl SeriesStats {
+    fn of(series: &stats::Series, unit: Unit) -> Self {
+        let percentiles = series.percentiles();
+        let divisor = unit.divisor as u64;
+        SeriesStats {
+            unit: unit.name,
+            count: percentiles.count,
+            p50: percentiles.p50 / divisor,
+            p90: percentiles.p90 / divisor,
+            p99: percentiles.p99 / divisor,
+            p999: percentiles.p999 / divisor,
+        }
     }
+}
 
-    return NamedFile::open(std::path::Path::new("stats.png")).await.ok()
+#[utoipa::path(
+    get,
+    path = "/stats/{metric}/percentiles",
+    tag = "stats",
+    params(
+        ("metric" = String, Path, description = "One of: read, write, size, request, throughput, errors, compaction, keys, shadow, compression, discrepancies, saturation"),
+        ("route" = Option<String>, Query, description = "For `request`: only requests handled by this route, see /stats/labels"),
+        ("status" = Option<u16>, Query, description = "For `request`: only requests answered with this status"),
+        ("op" = Option<String>, Query, description = "For `throughput` and `errors`: read or write, both by default"),
+        ("of" = Option<String>, Query, description = "For `compaction`: reclaimed (default), duration or segments. For `shadow`: shadow (default) or primary")
+    ),
+    responses(
+        (status = 200, description = "Percentiles of the chosen metric", body = SeriesStats),
+        (status = 404, description = "Unknown metric")
+    )
+)]
+#[get("/stats/<metric>/percentiles?<filter..>")]
+pub fn stats_percentiles(metric: &str, filter: Filter<'_>, stats: &State<Stats>) -> Option<Json<SeriesStats>> {
+    let (series, _, unit) = self::metric(metric, &filter, stats)?;
+    Some(Json(SeriesStats::of(&series, unit)))
 }
 
+#[utoipa::path(
+    get,
+    path = "/stats/{metric}/export",
+    tag = "stats",
+    params(
+        ("metric" = String, Path, description = "One of: read, write, size, request, throughput, errors, compaction, keys, shadow, compression, discrepancies, saturation"),
+        ("route" = Option<String>, Query, description = "For `request`: only requests handled by this route, see /stats/labels"),
+        ("status" = Option<u16>, Query, description = "For `request`: only requests answered with this status"),
+        ("op" = Option<String>, Query, description = "For `throughput` and `errors`: read or write, both by default"),
+        ("of" = Option<String>, Query, description = "For `compaction`: reclaimed (default), duration or segments. For `shadow`: shadow (default) or primary"),
+        ("window" = Option<String>, Query, description = "Time span to export, like 30s, 15m or 2h. Everything still retained by default")
+    ),
+    responses(
+        (status = 200, description = "Retained samples as `timestamp,value` CSV. Timestamps are Unix milliseconds, values are as recorded: \
+            ns for latencies, bytes for sizes, basis points for error rates", content_type = "text/csv"),
+        (status = 400, description = "Malformed window"),
+        (status = 404, description = "Unknown metric")
+    )
+)]
+#[get("/stats/<metric>/export?<window>&<filter..>")]
+pub fn stats_export(metric: &str, filter: Filter<'_>, window: Option<&str>, stats: &State<Stats>) -> Result<(ContentType, String), Status> {
+    let window = window.map(|window| parse_window(window).ok_or(Status::BadRequest)).transpose()?;
+    let (series, _, _) = self::metric(metric, &filter, stats).ok_or(Status::NotFound)?;
 
-// TODO: Move the Database trait to another file and implement it in kopper/brass respectively
-impl Database for Kopper {
-    fn read(&self, key: &str) -> Result<String, KopperError> {
-        self.read(key)
+    let start = window.map_or(0, |window| stats.now_millis().saturating_sub(window.as_millis() as u64));
+    let mut csv = String::from("timestamp,value\n");
+    for sample in series.samples().since(start) {
+        csv.push_str(&format!("{},{}\n", sample.time, sample.value));
     }
+    Ok((ContentType::CSV, csv))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct LabelStats {
+    /// Name of the route, `unmatched` for requests no route matched
+    route: String,
+    status: u16,
+    latency: SeriesStats,
+}
+
+#[utoipa::path(
+    get,
+    path = "/stats/labels",
+    tag = "stats",
+    responses((status = 200, description = "Request latency summary per route and status", body = [LabelStats]))
+)]
+#[get("/stats/labels")]
+pub fn stats_labels(stats: &State<Stats>) -> Json<Vec<LabelStats>> {
+    let requests = stats.counters.requests.lock().unwrap();
+    Json(requests.iter()
+        .map(|(label, series)| LabelStats {
+            route: label.route.clone(),
+            status: label.status,
+            latency: SeriesStats::of(series, Unit::MICROS),
+        })
+        .collect())
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SegmentInfo {
+    name: String,
+    /// Of its records, however it's stored
+    bytes: usize,
+    /// On disk, fewer if the segment is packed
+    stored_bytes: usize,
+    /// Of the current values of keys, kept by compaction
+    live_bytes: usize,
+    /// Of overwritten values and tombstones, dropped by compaction
+    dead_bytes: usize,
+    /// Keys whose current value it holds
+    entries: usize,
+    /// Records compaction drops - it picks the segment with the most
+    dead_entries: usize,
+    /// Segments cut off since this one, 0 for the active one
+    age: u32,
+    /// "hot" or "cold"
+    tier: String,
+}
+
+#[utoipa::path(
+    get,
+    path = "/stats/segments",
+    tag = "stats",
+    responses(
+        (status = 200, description = "Where the space of every segment goes, oldest first", body = [SegmentInfo]),
+        (status = 503, description = "Database is closed")
+    )
+)]
+#[get("/stats/segments")]
+pub fn stats_segments(db: &State<Kopper>) -> Result<Json<Vec<SegmentInfo>>, Status> {
+    let segments = db.segment_stats().map_err(|_| Status::ServiceUnavailable)?;
+    Ok(Json(segments.into_iter()
+        .map(|segment| SegmentInfo {
+            name: segment.name,
+            bytes: segment.bytes,
+            stored_bytes: segment.stored_bytes,
+            live_bytes: segment.live_bytes,
+            dead_bytes: segment.dead_bytes,
+            entries: segment.entries,
+            dead_entries: segment.dead_entries,
+            age: segment.age,
+            tier: match segment.tier {
+                Tier::Hot => "hot".to_owned(),
+                Tier::Cold => "cold".to_owned(),
+            },
+        })
+        .collect()))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SaturationInfo {
+    /// One file handle each
+    open_segments: usize,
+    /// Compaction requests the compactor hasn't taken up yet
+    compactor_queue: usize,
+    /// Whether the compactor thread runs, null for followers which don't run one
+    compactor_alive: Option<bool>,
+    /// Times a request waited for the lock of the database, since startup
+    lock_waits: u64,
+    /// Time spent waiting in all of them
+    lock_wait_ms: u64,
+}
+
+#[utoipa::path(
+    get,
+    path = "/stats/saturation",
+    tag = "stats",
+    responses(
+        (status = 200, description = "How close the database is to running out of file handles, compaction throughput or lock time", body = SaturationInfo),
+        (status = 503, description = "Database is closed")
+    )
+)]
+#[get("/stats/saturation")]
+pub fn stats_saturation(db: &State<Kopper>) -> Result<Json<SaturationInfo>, Status> {
+    let saturation = db.saturation().map_err(|_| Status::ServiceUnavailable)?;
+    Ok(Json(SaturationInfo {
+        open_segments: saturation.open_segments,
+        compactor_queue: saturation.compactor_queue,
+        compactor_alive: saturation.compactor_alive,
+        lock_waits: saturation.lock_waits,
+        lock_wait_ms: saturation.lock_wait.as_millis() as u64,
+    }))
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "KopperDB", description = "Fast, persistent key-value store", version = "1"),
+    servers((url = "/v1", description = "Version 1 of the API, also served without the prefix")),
+    paths(read_kopper, write_kopper, delete_kopper, rename_kopper, copy_kopper, undelete_kopper, read_versions, crate::blobs::put_blob, crate::blobs::get_blob, crate::json::patch_json, read_brass, write_brass, watch, crate::bulk::export, crate::bulk::import,
+        crate::lists::lpush, crate::lists::rpush, crate::lists::lpop, crate::lists::rpop, crate::lists::lrange,
+        crate::queues::push, crate::queues::pop, crate::queues::consume, crate::queues::commit,
+        crate::locks::acquire, crate::locks::release,
+        crate::channels::publish, crate::channels::listen,
+        crate::sets::sadd, crate::sets::srem, crate::sets::smembers, crate::sets::sismember,
+        crate::hashes::hset, crate::hashes::hget, crate::hashes::hdel, crate::hashes::hgetall, crate::counters::incr, crate::counters::counted,
+        crate::admin::create_backup, crate::admin::download_backup, crate::admin::compact, crate::admin::roll, crate::admin::doctor, crate::admin::search, crate::reload::reload, crate::audit::recent,
+        crate::replication::changes, crate::replication::segments, crate::replication::snapshot, crate::replication::status, list_databases, ready, read_named, write_named, get_stats, stats_percentiles, stats_export, stats_labels, stats_segments, stats_saturation),
+    components(schemas(ReadResponse, WriteResponse, VersionsResponse, ChangeResponse, crate::json::PatchResponse, DatabaseInfo, DatabaseUsage, Readiness, RecoveringDatabase, crate::bulk::ImportResponse, crate::bulk::LineError,
+        crate::lists::PushResponse, crate::lists::PopResponse, crate::lists::RangeResponse,
+        crate::queues::QueuedResponse, crate::queues::QueuePopResponse, crate::queues::QueuedItem, crate::queues::ConsumeResponse, crate::queues::OffsetResponse,
+        crate::locks::LockResponse, crate::locks::ReleaseResponse,
+        crate::channels::PublishResponse,
+        crate::sets::ChangeCountResponse, crate::sets::MembersResponse, crate::sets::IsMemberResponse,
+        crate::hashes::FieldCountResponse, crate::hashes::FieldResponse, crate::hashes::FieldsResponse, crate::counters::CountResponse,
+        crate::admin::BackupResponse, crate::admin::CompactResponse, crate::admin::RollResponse, crate::admin::DoctorResponse, crate::admin::DoctorCheck, crate::admin::SearchResponse, crate::reload::ReloadResponse, crate::audit::AuditEntry, crate::replication::ReplicatedChange,
+        crate::replication::ChangesResponse, crate::replication::ReplicationStatus, SeriesStats, LabelStats, SegmentInfo, SaturationInfo))
+)]
+pub struct ApiDoc;
+
+#[get("/openapi.json")]
+pub fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+/// Swagger UI is loaded from a CDN and pointed at [`openapi`], so it doesn't
+/// need to be bundled with the server.
+#[get("/swagger")]
+pub fn swagger() -> RawHtml<&'static str> {
+    RawHtml(SWAGGER_PAGE)
+}
+
+const SWAGGER_PAGE: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+    <title>KopperDB API</title>
+    <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css" />
+</head>
+<body>
+    <div id="swagger-ui"></div>
+    <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+    <script>
+        window.onload = () => {
+            window.ui = SwaggerUIBundle({ url: "/v1/openapi.json", dom_id: "#swagger-ui" });
+        };
+    </script>
+</body>
+</html>
+"##;
+
+/// Live monitoring page. It re-renders the stats charts every few seconds through
+/// [`get_stats`] and shows headline numbers from [`stats_percentiles`].
+#[get("/dashboard")]
+pub fn dashboard() -> RawHtml<&'static str> {
+    RawHtml(DASHBOARD_PAGE)
+}
+
+const DASHBOARD_PAGE: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+    <title>KopperDB dashboard</title>
+    <style>
+        body { font-family: sans-serif; margin: 1em; background: #f4f4f4; }
+        header { display: flex; gap: 1em; align-items: baseline; }
+        main { display: grid; grid-template-columns: repeat(auto-fit, minmax(480px, 1fr)); gap: 1em; }
+        section { background: white; padding: 0.5em; border-radius: 4px; }
+        h2 { font-size: 1em; margin: 0 0 0.3em; }
+        .summary { color: #555; font-size: 0.9em; }
+        img { width: 100%; }
+    </style>
+</head>
+<body>
+    <header>
+        <h1>KopperDB</h1>
+        <label>Window
+            <select id="window">
+                <option>1m</option><option selected>15m</option><option>1h</option><option>1d</option>
+            </select>
+        </label>
+    </header>
+    <main id="panels"></main>
+    <script>
+        const panels = [
+            { title: "Read latency", metric: "read" },
+            { title: "Write latency", metric: "write" },
+            { title: "Throughput", metric: "throughput" },
+            { title: "Error rate", metric: "errors" },
+            { title: "Size", metric: "size" },
+            { title: "Keys", metric: "keys" },
+            { title: "Compressed size of values", metric: "compression" },
+            { title: "Corrupt or mismatched keys found", metric: "discrepancies" },
+            { title: "Open segments", metric: "saturation" },
+            { title: "Compactions queued", metric: "saturation", query: "of=compactor_queue" },
+            { title: "Time waiting for the lock", metric: "saturation", query: "of=lock_wait" },
+            { title: "Reclaimed by compaction", metric: "compaction" },
+            { title: "Compaction duration", metric: "compaction", query: "of=duration" },
+            { title: "Primary engine writes", metric: "shadow", query: "of=primary" },
+            { title: "Shadow engine writes", metric: "shadow" },
+        ];
+
+        const root = document.getElementById("panels");
+        for (const panel of panels) {
+            panel.element = document.createElement("section");
+            panel.element.innerHTML = `<h2>${panel.title}</h2><div class="summary"></div><img alt="No data yet">`;
+            root.appendChild(panel.element);
+        }
+
+        async function refresh() {
+            const window = document.getElementById("window").value;
+            for (const panel of panels) {
+                const query = panel.query ? `&${panel.query}` : "";
+                panel.element.querySelector("img").src = `/v1/stats/${panel.metric}?format=svg&window=${window}${query}&t=${Date.now()}`;
+
+                const response = await fetch(`/v1/stats/${panel.metric}/percentiles?${panel.query || ""}`);
+                if (response.ok) {
+                    const s = await response.json();
+                    panel.element.querySelector(".summary").textContent =
+                        `${s.count} samples, p50 ${s.p50}${s.unit}, p99 ${s.p99}${s.unit}, p999 ${s.p999}${s.unit}`;
+                }
+            }
+        }
+
+        document.getElementById("window").onchange = refresh;
+        refresh();
+        setInterval(refresh, 5000);
+    </script>
+</body>
+</html>
+"##;
+
+/// Directories a database keeps segments in besides its main one
+#[derive(Default)]
+pub struct DataDirs<'a> {
+    /// For segments on the cold tier, see [`TieredStore`]
+    pub cold: Option<&'a str>,
+    /// New segments are spread across these and the main one, see [`SpreadStore`]
+    pub spread: &'a [String],
+    pub placement: Placement,
+}
 
-    fn write(&self, key: &str, value: &str) -> Result<usize, KopperError> {
-        self.write(key, value)
+/// Creates a [`Kopper`] instance that can be mounted as a state by Rocket,
+/// encrypted at rest if there's a `key`, reporting recovery to `progress`.
+/// With `eager` set, only that many segments are recovered before it's returned,
+/// see [`Kopper::create_lazily`]. Segments get `mode`, see [`LocalStore::with_mode`],
+/// and may be kept in other `dirs` than `path`.
+pub fn create_kopper(path: &str, segment_size: usize, key: Option<&EncryptionKey>, mode: Option<u32>, dirs: &DataDirs, eager: Option<usize>, progress: impl FnMut(RecoveryProgress)) -> Result<Kopper, KopperError> {
+    let local = |path: &str| -> Result<Arc<dyn SegmentStore>, KopperError> {
+        let _ = std::fs::create_dir_all(path);
+        let mut store: Arc<dyn SegmentStore> = match mode {
+            Some(mode) => Arc::new(LocalStore::with_mode(path, mode)?),
+            None => Arc::new(LocalStore::new(path)),
+        };
+        if let Some(key) = key {
+            store = Arc::new(EncryptedStore::new(store, key));
+        }
+        Ok(store)
+    };
+    let mut store = local(path)?;
+    if !dirs.spread.is_empty() {
+        let mut stores = vec![store];
+        for dir in dirs.spread {
+            stores.push(local(dir)?);
+        }
+        store = Arc::new(SpreadStore::new(stores, dirs.placement));
+    }
+    if let Some(cold) = dirs.cold {
+        store = Arc::new(TieredStore::new(store, local(cold)?));
+    }
+    match eager {
+        Some(eager) => Kopper::with_store_lazily(store, segment_size, eager),
+        None => Kopper::with_store_and_progress(store, segment_size, progress),
+    }
+}
+
+/// Logs how recovering the database `name` goes, at most once a second
+pub fn log_recovery(name: &str) -> impl FnMut(RecoveryProgress) + '_ {
+    let mut logged = Instant::now();
+    move |progress| {
+        if progress.recovered < progress.segments && logged.elapsed() >= Duration::from_secs(1) {
+            logged = Instant::now();
+            tracing::info!(
+                database = name,
+                recovered = progress.recovered,
+                segments = progress.segments,
+                bytes_scanned = progress.bytes_scanned,
+                entries = progress.entries,
+                "recovering"
+            );
+        }
     }
 }
 
-impl Database for Brass {
-    fn read(&self, key: &str) -> Result<String, KopperError> {
-        self.read(key)
+/// Codec named by `compression` in the config, compressing values of at least
+/// `compression_threshold` bytes. `None` stores values as they are.
+pub fn compression(figment: &rocket::figment::Figment) -> Option<Compression> {
+    let codec = figment.extract_inner::<String>("compression").ok()?;
+    let mut compression = Compression::new(codec.parse().expect("Invalid compression"));
+    if let Ok(threshold) = figment.extract_inner("compression_threshold") {
+        compression.threshold = threshold;
     }
+    Some(compression)
+}
 
-    fn write(&self, key: &str, value: &str) -> Result<usize, KopperError> {
-        self.write(key, value)
+/// Codec named by `pack_segments` in the config, packing segments `pack_after`
+/// segments behind the active one. `None` leaves segments unpacked.
+pub fn packing(figment: &rocket::figment::Figment) -> Option<Packing> {
+    let codec = figment.extract_inner::<String>("pack_segments").ok()?;
+    let mut packing = Packing::new(codec.parse().expect("Invalid pack_segments"));
+    if let Ok(cold_after) = figment.extract_inner("pack_after") {
+        packing.cold_after = cold_after;
     }
+    Some(packing)
+}
+
+/// Length from `dedupe_min_len` in the config if `dedupe` is on, storing values
+/// at least that long once for all keys. `None` stores every value written.
+pub fn dedupe(figment: &rocket::figment::Figment) -> Option<usize> {
+    figment.extract_inner("dedupe").unwrap_or(false)
+        .then(|| figment.extract_inner("dedupe_min_len").unwrap_or(dedupe::DEFAULT_MIN_LEN))
+}
+
+/// Limits of `max_db_size` and `max_keys` in the config if `eviction` is `"lru"`,
+/// evicting the least recently used keys past them. `None` never evicts.
+pub fn eviction(figment: &rocket::figment::Figment) -> Option<Eviction> {
+    let policy = figment.extract_inner::<String>("eviction").ok()?;
+    assert!(policy == "lru", "Invalid eviction, only \"lru\" is supported");
+    Some(Eviction {
+        max_bytes: figment.extract_inner("max_db_size").ok(),
+        max_keys: figment.extract_inner("max_keys").ok(),
+    })
+}
+
+/// Dead ratios from `write_stall_soft` and `write_stall_hard` in the config,
+/// delaying writes by `write_stall_delay_ms` past the soft one. `None` without
+/// a hard one, never holding writes back.
+pub fn backpressure(figment: &rocket::figment::Figment) -> Option<Backpressure> {
+    let hard = figment.extract_inner("write_stall_hard").ok()?;
+    Some(Backpressure {
+        soft: figment.extract_inner("write_stall_soft").unwrap_or(hard),
+        hard,
+        delay: figment.extract_inner("write_stall_delay_ms").map_or(DEFAULT_WRITE_STALL, Duration::from_millis),
+    })
 }
 
-/// Creates a [`Kopper`] instance that can be mounted as a state by Rocket 
-pub fn create_kopper(path: &str, segment_size: usize) -> Result<Kopper, KopperError> {
-    Kopper::create(path, segment_size)
+/// Schemas from the files in the `schemas` table of the config, by key prefix.
+/// Writes through HTTP are checked against them, and with `enforce_schemas` on
+/// every write to the database is, see [`enforce_schemas`].
+pub fn schemas(figment: &rocket::figment::Figment) -> Schemas {
+    let files = figment.extract_inner("schemas").unwrap_or_default();
+    Schemas::load(&files).expect("Can't load schemas")
+}
+
+/// Has every write to `kopper` checked against `schemas`, whichever way it comes
+pub fn enforce_schemas(kopper: &Kopper, schemas: Arc<Schemas>) {
+    if !schemas.is_empty() {
+        kopper.on_write(move |key, value| schemas.check(key, &value).map(|_| value));
+    }
+}
+
+/// Key to encrypt the database with, from the file named by `key_file` in the
+/// config, or else by `KOPPER_KEY_FILE`. `None` keeps the database unencrypted.
+pub fn encryption_key(figment: &rocket::figment::Figment) -> Option<EncryptionKey> {
+    match figment.extract_inner::<String>("key_file") {
+        Ok(path) => Some(EncryptionKey::from_file(&path).expect("Can't read the encryption key")),
+        Err(_) => EncryptionKey::from_env().expect("Can't read the encryption key"),
+    }
 }
 
 /// Creates a [`Brass`] instance that can be mounted as a state by Rocket 
@@ -159,11 +1164,12 @@ pub fn create_brass(path: &str, segment_size: usize) -> Result<Brass, KopperErro
 
 /// Creates a [`Stats`] instance that can be mounted as a state by Rocket,
 /// as well as starting a [`stats::StatsAggregator`] on a separate thread.
+/// Every series keeps only its last `retention` samples.
 /// 
 /// The aggregator thread lifetime is linked to stats. When Stats are destroyed, 
 /// so is the aggregator.
-pub fn create_stats() -> Stats {
-    let (stats, mut aggregator) = Stats::create();
+pub fn create_stats(retention: usize) -> Stats {
+    let (stats, mut aggregator) = Stats::with_retention(retention);
 
     std::thread::spawn(move || {
         aggregator.run();
@@ -172,14 +1178,313 @@ pub fn create_stats() -> Stats {
     stats
 }
 
-fn rocket() -> rocket::Rocket<rocket::Build> {
+/// How often the number of keys is sampled into the metrics
+const KEYS_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+const SATURATION_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Sink everything measured by the server goes to, shared with the engine
+pub type Metrics = Arc<dyn MetricsSink>;
+
+/// Builds the sink for the server from its config: the in-process `stats` behind
+/// the `/stats` endpoints, plus a statsd exporter if `statsd` holds an address.
+pub fn create_metrics(stats: &Stats, figment: &rocket::figment::Figment) -> Metrics {
+    let mut sinks: Vec<Arc<dyn MetricsSink>> = vec![Arc::new(stats.clone())];
+
+    if let Ok(address) = figment.extract_inner::<String>("statsd") {
+        match StatsdSink::new(&address, "kopperdb") {
+            Ok(statsd) => sinks.push(Arc::new(statsd)),
+            Err(err) => tracing::error!("Can't export metrics to statsd at {address}: {err}"),
+        }
+    }
+
+    Arc::new(Fanout(sinks))
+}
+
+/// Samples the number of keys in `db` into `metrics` every [`KEYS_SAMPLE_INTERVAL`]
+/// on a separate thread. The thread ends when `db` is closed.
+pub fn report_engine_metrics(db: Engine, metrics: Metrics) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(KEYS_SAMPLE_INTERVAL);
+        match db.stats() {
+            Ok(stats) => metrics.record(Stat::Keys(stats.keys as u128)),
+            Err(_) => break,
+        }
+    });
+}
+
+/// Samples [`Kopper::saturation`] into `metrics` every [`SATURATION_SAMPLE_INTERVAL`]
+/// on a separate thread, with the lock waits since the previous sample. The thread
+/// ends when `db` is closed.
+pub fn report_saturation(db: Kopper, metrics: Metrics) {
+    std::thread::spawn(move || {
+        let (mut waits, mut waited) = (0, Duration::ZERO);
+        loop {
+            std::thread::sleep(SATURATION_SAMPLE_INTERVAL);
+            let Ok(saturation) = db.saturation() else {
+                break;
+            };
+            metrics.record(Stat::Saturation {
+                open_segments: saturation.open_segments as u128,
+                compactor_queue: saturation.compactor_queue as u128,
+                // Followers don't run one, there's nothing to miss
+                compactor_alive: saturation.compactor_alive.unwrap_or(true),
+                lock_waits: (saturation.lock_waits - waits) as u128,
+                lock_wait: (saturation.lock_wait - waited).as_nanos(),
+            });
+            (waits, waited) = (saturation.lock_waits, saturation.lock_wait);
+        }
+    });
+}
+
+/// Engine behind the key-value routes from the config, `engine`: `kopper` (default),
+/// `brass` or `lsm`
+pub fn engine_name(figment: &rocket::figment::Figment) -> String {
+    figment.extract_inner::<String>("engine").unwrap_or_else(|_| "kopper".to_owned())
+}
+
+/// Picks the engine behind the key-value routes and /export and /import, see
+/// [`engine_name`]. If `shadow` names another one, writes are mirrored to it to
+/// compare the two, see [`Shadowed`]. LSM is only opened if it's picked.
+///
+/// Only Kopper has lists, queues, watches and the like - their routes and the
+/// other protocols aren't served by another engine, see [`rocket`]. Kopper keeps
+/// being replicated and backed up.
+pub fn select_engine(figment: &rocket::figment::Figment, kopper: &Kopper, brass: &Brass, metrics: &Metrics) -> Engine {
+    const LSMDB_FOLDER: &str = "lsm_database";
+
+    let open = |name: &str| -> Engine {
+        match name {
+            "kopper" => Arc::new(kopper.clone()),
+            "brass" => Arc::new(brass.clone()),
+            "lsm" => Arc::new(Lsm::create(LSMDB_FOLDER, lsm::DEFAULT_MEMTABLE_SIZE).expect("Can't create LSM")),
+            _ => panic!("Unknown engine {name}, expected kopper, brass or lsm"),
+        }
+    };
+
+    let name = engine_name(figment);
+    let engine = open(&name);
+    match figment.extract_inner::<String>("shadow") {
+        Ok(shadow) if shadow == name => panic!("Engine {name} can't shadow itself"),
+        Ok(shadow) => {
+            tracing::info!("Serving from {name}, mirroring writes to {shadow}");
+            Arc::new(Shadowed::new(engine, open(&shadow), metrics.clone()))
+        },
+        Err(_) => engine,
+    }
+}
+
+/// How often persisted stats are saved, see [`persist_stats`]
+const STATS_PERSIST_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Where stats are persisted, if persistence is on
+pub struct StatsFile(pub std::path::PathBuf);
+
+/// Restores stats saved by a previous run from `file`, then keeps saving them
+/// there every [`STATS_PERSIST_INTERVAL`] on a separate thread.
+pub fn persist_stats(stats: &Stats, file: &StatsFile) {
+    match stats.counters.load(&file.0) {
+        Ok(()) => tracing::info!("Stats restored from {}", file.0.display()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {},
+        Err(err) => tracing::error!("Can't restore stats from {}: {err}", file.0.display()),
+    }
+
+    let counters = stats.counters.clone();
+    let path = file.0.clone();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(STATS_PERSIST_INTERVAL);
+        if let Err(err) = counters.save(&path) {
+            tracing::error!("Can't save stats to {}: {err}", path.display());
+        }
+    });
+}
+
+/// Flushes and closes the databases managed by `rocket`. Meant to be called after
+/// the server has shut down, so no request can observe a closed database.
+pub fn close_databases<P: rocket::Phase>(rocket: &rocket::Rocket<P>) {
+    if let Some(engine) = rocket.state::<Engine>() {
+        match engine.close() {
+            Ok(()) => tracing::info!("Engine {} closed", engine.name()),
+            Err(err) => tracing::error!("Failed to close engine {}: {err}", engine.name()),
+        }
+    }
+
+    // Unless it's the engine closed above, Kopper still serves replication and the like
+    if let Some(kopper) = rocket.state::<Kopper>().filter(|kopper| !kopper.is_closed()) {
+        match kopper.close() {
+            Ok(()) => tracing::info!("Kopper closed"),
+            Err(err) => tracing::error!("Failed to close Kopper: {err}"),
+        }
+    }
+
+    if let Some(registry) = rocket.state::<Arc<Registry>>() {
+        registry.close_all();
+    }
+
+    // Whatever was recorded since the last periodic save
+    if let (Some(stats), Some(file)) = (rocket.state::<Stats>(), rocket.state::<StatsFile>()) {
+        if let Err(err) = stats.counters.save(&file.0) {
+            tracing::error!("Can't save stats to {}: {err}", file.0.display());
+        }
+    }
+}
+
+pub fn rocket() -> rocket::Rocket<rocket::Build> {
     const KOPPERDB_FOLDER: &str = "kopper_database";
     const BRASSDB_FOLDER: &str = "brass_database";
+    const BACKUP_FOLDER: &str = "kopper_backups";
     const SEGMENT_SIZE: usize = 4096; 
+    const FOLLOW_INTERVAL_MS: u64 = 1000;
+    const TTL_SWEEP_LIMIT: usize = 1000;
+
+    let rocket = rocket::build();
 
-    rocket::build()
-        .mount("/", routes![read_kopper, read_brass, write_kopper, write_brass, get_stats])
-        .manage(create_stats())
-        .manage(create_brass(BRASSDB_FOLDER, SEGMENT_SIZE).expect("Can't create Brass"))
-        .manage(create_kopper(KOPPERDB_FOLDER, SEGMENT_SIZE).expect("Can't create Kopper")) // Shared state accessible by ref in all endpoints. Must be Send + Sync
-}
\ No newline at end of file
+    // Named databases are optional - no `databases` table means an empty registry
+    let databases = rocket.figment().extract_inner("databases").unwrap_or_default();
+    let named_backpressure = backpressure(rocket.figment());
+    let backup_dir: String = rocket.figment().extract_inner("backup_dir").unwrap_or_else(|_| BACKUP_FOLDER.to_owned());
+    let stats_retention = rocket.figment().extract_inner("stats_retention").unwrap_or(stats::DEFAULT_RETENTION);
+
+    let stats = create_stats(stats_retention);
+    let metrics = create_metrics(&stats, rocket.figment());
+    let follow = rocket.figment().extract_inner("follow").unwrap_or(false);
+    let key = encryption_key(rocket.figment());
+    let cold_dir: Option<String> = rocket.figment().extract_inner("cold_dir").ok();
+    let data_dirs: Vec<String> = rocket.figment().extract_inner("data_dirs").unwrap_or_default();
+    let kopper = match follow {
+        true => {
+            assert!(key.is_none(), "Followers can't read encrypted databases");
+            assert!(data_dirs.is_empty(), "Followers can't read segments spread across data_dirs");
+            let interval = rocket.figment().extract_inner("follow_interval_ms").unwrap_or(FOLLOW_INTERVAL_MS);
+            Kopper::follow(KOPPERDB_FOLDER, Duration::from_millis(interval)).expect("Can't follow Kopper")
+        },
+        false => {
+            let mode = rocket.figment().extract_inner("file_mode").ok();
+            let eager = rocket.figment().extract_inner("lazy_recovery").ok();
+            let dirs = DataDirs {
+                cold: cold_dir.as_deref(),
+                spread: &data_dirs,
+                placement: rocket.figment().extract_inner::<String>("data_dir_placement")
+                    .map_or(Placement::default(), |placement| placement.parse().expect("Invalid data_dir_placement")),
+            };
+            create_kopper(KOPPERDB_FOLDER, SEGMENT_SIZE, key.as_ref(), mode, &dirs, eager, log_recovery(KOPPERDB_FOLDER)).expect("Can't create Kopper")
+        },
+    };
+    if let Ok(capacity) = rocket.figment().extract_inner("replication_log_size") {
+        kopper.set_change_log_capacity(capacity);
+    }
+    // Can be changed later on, see crate::reload
+    let tunables = Tunables::from_config(rocket.figment(), follow, cold_dir.is_some());
+    tunables.apply(&kopper, &Tunables::default());
+    if let (Ok(interval), false) = (rocket.figment().extract_inner("ttl_sweep_interval_ms"), follow) {
+        let limit = rocket.figment().extract_inner("ttl_sweep_limit").unwrap_or(TTL_SWEEP_LIMIT);
+        kopper.start_sweeper(Duration::from_millis(interval), limit);
+    }
+    // Enforced by the engine, HTTP writes don't need to be checked on their own
+    let schemas = Arc::new(schemas(rocket.figment()));
+    let schemas = match rocket.figment().extract_inner("enforce_schemas").unwrap_or(false) {
+        true => {
+            enforce_schemas(&kopper, schemas);
+            Arc::new(Schemas::default())
+        },
+        false => schemas,
+    };
+    // Compactions are reported by Kopper itself
+    kopper.set_metrics_sink(metrics.clone());
+    let brass = create_brass(BRASSDB_FOLDER, SEGMENT_SIZE).expect("Can't create Brass");
+    let engine = select_engine(rocket.figment(), &kopper, &brass, &metrics);
+    report_engine_metrics(engine.clone(), metrics.clone());
+    report_saturation(kopper.clone(), metrics.clone());
+
+    // Hidden file - the database skips those when recovering
+    // Followers don't write to the directory they share with its owner
+    let persist = rocket.figment().extract_inner("persist_stats").unwrap_or(false) && !follow;
+    let stats_file = persist.then(|| StatsFile(std::path::Path::new(KOPPERDB_FOLDER).join(".stats.json")));
+    if let Some(file) = &stats_file {
+        persist_stats(&stats, file);
+    }
+
+    let registry = Arc::new(Registry::new(databases, named_backpressure));
+    let timeouts = Timeouts::from_config(rocket.figment());
+    let reloader = Arc::new(Reloader::new(kopper.clone(), registry.clone(), follow, cold_dir.is_some(), tunables, rocket.figment()));
+
+    // Unversioned paths are routed here by ApiVersion
+    let v1 = version::base(1);
+
+    // Data only Kopper holds, which would be out of step with what the
+    // key-value routes serve from another engine
+    let rocket = match engine_name(rocket.figment()).as_str() {
+        "kopper" => rocket
+            .attach(crate::resp::listener())
+            .attach(crate::memcached::listener())
+            .attach(crate::grpc::listener())
+            .attach(crate::binary::listener())
+            .mount(&v1, routes![rename_kopper, copy_kopper, undelete_kopper, read_versions, watch, crate::ws::ws, crate::admin::search])
+            .mount(&v1, routes![crate::lists::lpush, crate::lists::rpush, crate::lists::lpop, crate::lists::rpop, crate::lists::lrange])
+            .mount(&v1, routes![crate::queues::push, crate::queues::pop, crate::queues::consume, crate::queues::commit])
+            .mount(&v1, routes![crate::locks::acquire, crate::locks::release])
+            .mount(&v1, routes![crate::sets::sadd, crate::sets::srem, crate::sets::smembers, crate::sets::sismember])
+            .mount(&v1, routes![crate::hashes::hset, crate::hashes::hget, crate::hashes::hdel, crate::hashes::hgetall])
+            .mount(&v1, routes![crate::counters::incr, crate::counters::counted])
+            .mount(&v1, routes![crate::blobs::put_blob, crate::blobs::get_blob, crate::json::patch_json]),
+        _ => rocket,
+    };
+
+    let rocket = rocket
+        .attach(ApiVersion)
+        .attach(RequestLogger)
+        .attach(Auditor)
+        .attach(crate::replication::replica())
+        .attach(crate::verifier::verifier())
+        .attach(crate::reload::on_sighup())
+        .mount(&v1, routes![read_kopper, read_brass, write_kopper, delete_kopper, write_brass, get_stats, stats_percentiles, stats_export, stats_labels, stats_segments, stats_saturation, openapi, swagger, dashboard])
+        .mount(&v1, routes![list_databases, ready, read_named, write_named])
+        .mount(&v1, routes![crate::bulk::export, crate::bulk::import])
+        .mount(&v1, routes![crate::channels::publish, crate::channels::listen])
+        .mount(&v1, routes![crate::admin::create_backup, crate::admin::download_backup, crate::admin::compact, crate::admin::roll, crate::admin::doctor, crate::reload::reload, crate::audit::recent])
+        .mount(&v1, routes![crate::replication::changes, crate::replication::segments, crate::replication::snapshot, crate::replication::status])
+        .manage(Backups::new(backup_dir.into()))
+        .manage(registry)
+        .manage(timeouts)
+        .manage(reloader)
+        .manage(schemas)
+        .manage(stats)
+        .manage(metrics)
+        .manage(brass)
+        .manage(engine)
+        .manage(kopper); // Shared state accessible by ref in all endpoints. Must be Send + Sync
+
+    // Managed either way, so the audit endpoint can tell there's none
+    let audit = AuditLog::open(rocket.figment());
+    let rocket = rocket.manage(audit);
+    match stats_file {
+        Some(file) => rocket.manage(file),
+        None => rocket,
+    }
+}
+/// TESTS
+
+#[test]
+fn test_watch_streams_changes_as_server_sent_events() {
+    use rocket::local::blocking::Client;
+
+    let db = kopperdb::testing::TempDb::new();
+    let kopper = db.kopper(4096).unwrap();
+    let client = Client::tracked(rocket::build().manage(kopper.clone()).mount("/", routes![watch])).unwrap();
+
+    let response = client.get("/watch?prefix=user:").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(rocket::http::ContentType::EventStream));
+
+    kopper.write("user:1", "ann").unwrap();
+    kopper.write("other", "skipped").unwrap();
+    kopper.delete("user:1").unwrap();
+    // Closing the database ends the stream
+    kopper.close().unwrap();
+    let body = response.into_string().unwrap();
+    // Without the heartbeats, comments of their own
+    let lines: Vec<_> = body.lines().filter(|line| !line.starts_with(':')).collect();
+    assert_eq!(lines, [
+        "event:write", r#"data:{"key":"user:1","value":"ann"}"#, "",
+        "event:delete", r#"data:{"key":"user:1"}"#, "",
+    ]);
+}
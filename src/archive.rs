@@ -0,0 +1,273 @@
+//! Portable dump format, independent of how segments are laid out on disk, so
+//! it stays readable across machines and versions of the engine.
+//!
+//! ```text
+//! header:  magic "KOPPERDB" | version u16 | created at u64 (Unix ms)
+//! entry:   1u8 | key len u32 | value len u32 | timestamp u64 | expires at u64 | key | value
+//! trailer: 0u8 | entry count u64 | CRC32 of everything before the trailer's checksum
+//! ```
+//!
+//! Integers are big endian. Timestamps and expiry times are Unix milliseconds,
+//! 0 where unknown or not set.
+
+use std::io::{self, Read, Write};
+
+use crc32fast::Hasher;
+
+pub const MAGIC: &[u8; 8] = b"KOPPERDB";
+
+/// Version written by [`ArchiveWriter`]. Readers reject anything newer.
+pub const VERSION: u16 = 1;
+
+const ENTRY_TAG: u8 = 1;
+const TRAILER_TAG: u8 = 0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub key: String,
+    pub value: String,
+    /// When the value was written, 0 if unknown
+    pub timestamp: u64,
+    /// When the value expires, 0 if never
+    pub expires_at: u64,
+}
+
+impl Entry {
+    /// Entry with no timestamp or expiry
+    pub fn new(key: String, value: String) -> Self {
+        Entry { key, value, timestamp: 0, expires_at: 0 }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ArchiveError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("Not a Kopper archive")]
+    NotAnArchive,
+
+    #[error("Archive version {0} is newer than supported version {VERSION}")]
+    UnsupportedVersion(u16),
+
+    #[error("Archive is corrupted: {0}")]
+    Corrupted(String),
+}
+
+/// Reader or writer hashing everything passing through it
+struct Hashing<W> {
+    inner: W,
+    hasher: Hasher,
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+pub struct ArchiveWriter<W: Write> {
+    writer: Hashing<W>,
+    count: u64,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    /// Starts an archive created at `created_at` (Unix ms)
+    pub fn new(writer: W, created_at: u64) -> io::Result<Self> {
+        let mut writer = Hashing { inner: writer, hasher: Hasher::new() };
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_be_bytes())?;
+        writer.write_all(&created_at.to_be_bytes())?;
+        Ok(ArchiveWriter { writer, count: 0 })
+    }
+
+    pub fn write(&mut self, entry: &Entry) -> io::Result<()> {
+        let writer = &mut self.writer;
+        writer.write_all(&[ENTRY_TAG])?;
+        writer.write_all(&(entry.key.len() as u32).to_be_bytes())?;
+        writer.write_all(&(entry.value.len() as u32).to_be_bytes())?;
+        writer.write_all(&entry.timestamp.to_be_bytes())?;
+        writer.write_all(&entry.expires_at.to_be_bytes())?;
+        writer.write_all(entry.key.as_bytes())?;
+        writer.write_all(entry.value.as_bytes())?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Writes the trailer, returning the underlying writer. An archive without
+    /// a trailer is rejected as truncated.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.write_all(&[TRAILER_TAG])?;
+        self.writer.write_all(&self.count.to_be_bytes())?;
+
+        let Hashing { mut inner, hasher } = self.writer;
+        inner.write_all(&hasher.finalize().to_be_bytes())?;
+        inner.flush()?;
+        Ok(inner)
+    }
+}
+
+/// Iterates over the entries of an archive. The checksum covers the whole archive,
+/// so whether it's intact is only known once the iterator is exhausted.
+/// Read everything before acting on any of it, when that matters.
+pub struct ArchiveReader<R: Read> {
+    reader: Hashing<R>,
+    created_at: u64,
+    count: u64,
+    done: bool,
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn read_string(reader: &mut impl Read, len: u32) -> Result<String, ArchiveError> {
+    let mut bytes = Vec::new();
+    reader.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len as usize {
+        return Err(ArchiveError::Corrupted("truncated".to_string()));
+    }
+    String::from_utf8(bytes).map_err(|_| ArchiveError::Corrupted("string isn't valid UTF-8".to_string()))
+}
+
+impl<R: Read> ArchiveReader<R> {
+    pub fn new(reader: R) -> Result<Self, ArchiveError> {
+        let mut reader = Hashing { inner: reader, hasher: Hasher::new() };
+
+        let mut magic = [0; MAGIC.len()];
+        if reader.read_exact(&mut magic).is_err() || &magic != MAGIC {
+            return Err(ArchiveError::NotAnArchive);
+        }
+
+        let mut version = [0; 2];
+        reader.read_exact(&mut version)?;
+        let version = u16::from_be_bytes(version);
+        if version > VERSION {
+            return Err(ArchiveError::UnsupportedVersion(version));
+        }
+
+        let created_at = read_u64(&mut reader)?;
+        Ok(ArchiveReader { reader, created_at, count: 0, done: false })
+    }
+
+    /// When the archive was created, Unix ms
+    pub fn created_at(&self) -> u64 {
+        self.created_at
+    }
+
+    fn next_entry(&mut self) -> Result<Option<Entry>, ArchiveError> {
+        let eof = |err: io::Error| match err.kind() {
+            io::ErrorKind::UnexpectedEof => ArchiveError::Corrupted("truncated".to_string()),
+            _ => ArchiveError::Io(err),
+        };
+
+        let mut tag = [0; 1];
+        self.reader.read_exact(&mut tag).map_err(eof)?;
+
+        match tag[0] {
+            ENTRY_TAG => {
+                let key_len = read_u32(&mut self.reader).map_err(eof)?;
+                let value_len = read_u32(&mut self.reader).map_err(eof)?;
+                let timestamp = read_u64(&mut self.reader).map_err(eof)?;
+                let expires_at = read_u64(&mut self.reader).map_err(eof)?;
+                let key = read_string(&mut self.reader, key_len)?;
+                let value = read_string(&mut self.reader, value_len)?;
+
+                self.count += 1;
+                Ok(Some(Entry { key, value, timestamp, expires_at }))
+            },
+            TRAILER_TAG => {
+                let count = read_u64(&mut self.reader).map_err(eof)?;
+                let expected = self.reader.hasher.clone().finalize();
+                let checksum = read_u32(&mut self.reader.inner).map_err(eof)?;
+
+                if checksum != expected {
+                    return Err(ArchiveError::Corrupted("checksum mismatch".to_string()));
+                }
+                if count != self.count {
+                    return Err(ArchiveError::Corrupted(format!("expected {count} entries, found {}", self.count)));
+                }
+                Ok(None)
+            },
+            tag => Err(ArchiveError::Corrupted(format!("unknown record {tag}"))),
+        }
+    }
+}
+
+impl<R: Read> Iterator for ArchiveReader<R> {
+    type Item = Result<Entry, ArchiveError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let entry = self.next_entry().transpose();
+        // Stop after the trailer or the first error - nothing after either can be trusted
+        if !matches!(entry, Some(Ok(_))) {
+            self.done = true;
+        }
+        entry
+    }
+}
+
+/// TESTS
+
+#[test]
+fn test_archive_roundtrip() {
+    let archive = sample_archive();
+    let reader = ArchiveReader::new(archive.as_slice()).unwrap();
+    assert_eq!(reader.created_at(), 1234);
+
+    let entries: Vec<Entry> = reader.collect::<Result<_, _>>().unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].value, "zażółć");
+    assert_eq!((entries[1].timestamp, entries[1].expires_at), (5, 6));
+}
+
+#[test]
+fn test_archive_damage_is_detected() {
+    let mut archive = sample_archive();
+
+    // Flipped bit in a value
+    archive[44] ^= 1;
+    let result: Result<Vec<Entry>, _> = ArchiveReader::new(archive.as_slice()).unwrap().collect();
+    assert!(matches!(result, Err(ArchiveError::Corrupted(_))));
+
+    // Missing trailer
+    let archive = sample_archive();
+    let result: Result<Vec<Entry>, _> = ArchiveReader::new(&archive[..archive.len() - 13]).unwrap().collect();
+    assert!(matches!(result, Err(ArchiveError::Corrupted(_))));
+
+    assert!(matches!(ArchiveReader::new(&b"not an archive"[..]), Err(ArchiveError::NotAnArchive)));
+}
+
+#[cfg(test)]
+fn sample_archive() -> Vec<u8> {
+    let mut writer = ArchiveWriter::new(Vec::new(), 1234).unwrap();
+    writer.write(&Entry::new("a".to_string(), "zażółć".to_string())).unwrap();
+    writer.write(&Entry { key: "b".to_string(), value: String::new(), timestamp: 5, expires_at: 6 }).unwrap();
+    writer.finish().unwrap()
+}
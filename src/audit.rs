@@ -0,0 +1,262 @@
+//! Audit log of mutations, for deployments that have to account for every change.
+//! With `audit_log` in the config, every request to a route writing or deleting
+//! keys is appended to that file as a line of JSON - when, by whom, to what and
+//! how it ended. The file is rotated once it grows past `audit_log_max_bytes`,
+//! keeping `audit_log_files` of the previous ones. The newest entries are also
+//! kept in memory, served under `/admin/audit`. Only plain HTTP requests are
+//! audited - not the WebSocket or the other listeners.
+
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rocket::{Request, Response, State};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::figment::Figment;
+use rocket::serde::json::Json;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::logging::{route_param, RequestId};
+
+/// Taken as the client's identity if present, e.g. set by an authenticating proxy
+const CLIENT_ID_HEADER: &str = "X-Client-Id";
+
+/// Routes that write or delete keys
+const MUTATIONS: &[&str] = &["write_kopper", "delete_kopper", "rename_kopper", "copy_kopper", "write_brass", "write_named", "import",
+    "lpush", "rpush", "lpop", "rpop", "sadd", "srem", "hset", "hdel", "incr"];
+
+const DEFAULT_MAX_BYTES: u64 = 16 * 1024 * 1024;
+const DEFAULT_FILES: usize = 4;
+/// Entries served by `/admin/audit`
+const RECENT: usize = 1000;
+
+#[derive(Serialize, ToSchema, Clone)]
+pub struct AuditEntry {
+    /// Milliseconds since the Unix epoch
+    timestamp_ms: u64,
+    request_id: String,
+    /// `X-Client-Id` header, or else the client's address
+    client: String,
+    route: String,
+    /// Named database written to, missing for the default one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    database: Option<String>,
+    /// Missing for imports, which write many
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    /// Of the value written, or the body imported. 0 for deletes.
+    size: usize,
+    status: u16,
+}
+
+struct AuditFile {
+    file: File,
+    len: u64,
+}
+
+/// Destination of the audit log, managed by Rocket as `Some` if `audit_log` is configured
+pub struct AuditLog {
+    path: PathBuf,
+    max_bytes: u64,
+    /// Rotated files kept, `audit.log.1` being the newest of them
+    files: usize,
+    current: Mutex<AuditFile>,
+    recent: Mutex<VecDeque<AuditEntry>>,
+}
+
+impl AuditLog {
+    /// Audit log configured in `figment`, `None` if there's no `audit_log`
+    pub fn open(figment: &Figment) -> Option<AuditLog> {
+        let path: PathBuf = figment.extract_inner::<String>("audit_log").ok()?.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path).expect("Can't open the audit log");
+        let len = file.metadata().expect("Can't open the audit log").len();
+        Some(AuditLog {
+            path,
+            max_bytes: figment.extract_inner("audit_log_max_bytes").unwrap_or(DEFAULT_MAX_BYTES),
+            files: figment.extract_inner("audit_log_files").unwrap_or(DEFAULT_FILES),
+            current: Mutex::new(AuditFile { file, len }),
+            recent: Mutex::new(VecDeque::with_capacity(RECENT)),
+        })
+    }
+
+    fn record(&self, entry: AuditEntry) {
+        let mut line = serde_json::to_vec(&entry).expect("Audit entries serialize");
+        line.push(b'\n');
+
+        let mut current = self.current.lock().unwrap();
+        if current.len > 0 && current.len + line.len() as u64 > self.max_bytes {
+            if let Err(err) = self.rotate(&mut current) {
+                tracing::error!("Can't rotate the audit log: {err}");
+            }
+        }
+        match current.file.write_all(&line) {
+            Ok(()) => current.len += line.len() as u64,
+            Err(err) => tracing::error!(request_id = %entry.request_id, "Can't write to the audit log: {err}"),
+        }
+        drop(current);
+
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == RECENT {
+            recent.pop_front();
+        }
+        recent.push_back(entry);
+    }
+
+    /// Shifts every file one up, dropping the oldest, and starts a new one
+    fn rotate(&self, current: &mut AuditFile) -> std::io::Result<()> {
+        let rotated = |n: usize| PathBuf::from(format!("{}.{n}", self.path.display()));
+        for n in (1..self.files).rev() {
+            if rotated(n).exists() {
+                fs::rename(rotated(n), rotated(n + 1))?;
+            }
+        }
+        match self.files {
+            0 => fs::remove_file(&self.path)?,
+            _ => fs::rename(&self.path, rotated(1))?,
+        }
+        *current = AuditFile { file: OpenOptions::new().create(true).append(true).open(&self.path)?, len: 0 };
+        Ok(())
+    }
+}
+
+/// Fairing recording the requests to [`MUTATIONS`] in the [`AuditLog`], if there's one
+pub struct Auditor;
+
+#[rocket::async_trait]
+impl Fairing for Auditor {
+    fn info(&self) -> Info {
+        Info { name: "Audit log", kind: Kind::Response }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let Some(audit) = req.rocket().state::<Option<AuditLog>>().and_then(Option::as_ref) else {
+            return;
+        };
+        let Some(route) = req.route().and_then(|route| route.name.as_deref()).filter(|name| MUTATIONS.contains(name)) else {
+            return;
+        };
+
+        let client = match req.headers().get_one(CLIENT_ID_HEADER) {
+            Some(client) => client.to_owned(),
+            None => req.client_ip().map_or_else(|| "unknown".to_owned(), |ip| ip.to_string()),
+        };
+        let size = match route_param(req, "<value>") {
+            Some(value) => value.len(),
+            None => req.headers().get_one("Content-Length").and_then(|len| len.parse().ok()).unwrap_or(0),
+        };
+        audit.record(AuditEntry {
+            timestamp_ms: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64,
+            request_id: RequestId::of(req).to_string(),
+            client,
+            route: route.to_owned(),
+            database: route_param(req, "<name>").map(str::to_owned),
+            key: route_param(req, "<key>").map(str::to_owned),
+            size,
+            status: res.status().code,
+        });
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/audit",
+    tag = "admin",
+    params(
+        ("limit" = Option<usize>, Query, description = "Most entries to return, 100 by default"),
+        ("key" = Option<String>, Query, description = "Only entries for this key")
+    ),
+    responses(
+        (status = 200, description = "Newest mutations first, out of the last 1000", body = [AuditEntry]),
+        (status = 404, description = "There's no audit log")
+    )
+)]
+#[get("/admin/audit?<limit>&<key>")]
+pub fn recent(limit: Option<usize>, key: Option<&str>, audit: &State<Option<AuditLog>>) -> Option<Json<Vec<AuditEntry>>> {
+    let recent = audit.as_ref()?.recent.lock().unwrap();
+    Some(Json(recent.iter().rev()
+        .filter(|entry| key.is_none() || entry.key.as_deref() == key)
+        .take(limit.unwrap_or(100))
+        .cloned()
+        .collect()))
+}
+
+/// TESTS
+
+#[test]
+fn test_log_is_rotated_past_its_size_keeping_as_many_files() {
+    let db = kopperdb::testing::TempDb::new();
+    let path = db.join("audit.log");
+    let line_len = serde_json::to_vec(&entry("key0")).unwrap().len() as u64 + 1;
+    let audit = AuditLog::open(&Figment::new()
+        .merge(("audit_log", &path))
+        .merge(("audit_log_max_bytes", 3 * line_len))
+        .merge(("audit_log_files", 2))).unwrap();
+
+    for n in 0..10 {
+        audit.record(entry(&format!("key{n}")));
+    }
+
+    // Three lines a file, the oldest ones dropped with the third file
+    let keys = |path: &str| -> Vec<String> {
+        fs::read_to_string(path).unwrap().lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["key"].as_str().unwrap().to_owned())
+            .collect()
+    };
+    assert_eq!(keys(&path), ["key9"]);
+    assert_eq!(keys(&format!("{path}.1")), ["key6", "key7", "key8"]);
+    assert_eq!(keys(&format!("{path}.2")), ["key3", "key4", "key5"]);
+    assert!(!PathBuf::from(format!("{path}.3")).exists());
+
+    // Picks up where it left off
+    drop(audit);
+    let audit = AuditLog::open(&Figment::new().merge(("audit_log", &path)).merge(("audit_log_max_bytes", 3 * line_len))).unwrap();
+    audit.record(entry("keyA"));
+    audit.record(entry("keyB"));
+    audit.record(entry("keyC"));
+    assert_eq!(keys(&path), ["keyC"]);
+    assert_eq!(keys(&format!("{path}.1")), ["key9", "keyA", "keyB"]);
+}
+
+#[test]
+fn test_recent_entries_are_filtered_by_key_newest_first() {
+    use rocket::local::blocking::Client;
+
+    let db = kopperdb::testing::TempDb::new();
+    let audit = AuditLog::open(&Figment::new().merge(("audit_log", db.join("audit.log")))).unwrap();
+    for (size, key) in ["a", "b", "a", "c", "a"].into_iter().enumerate() {
+        audit.record(AuditEntry { size, ..entry(key) });
+    }
+    let client = Client::tracked(rocket::build().manage(Some(audit)).mount("/", routes![recent])).unwrap();
+    let sizes = |uri: &str| -> Vec<u64> {
+        let entries: Vec<serde_json::Value> = client.get(uri.to_owned()).dispatch().into_json().unwrap();
+        entries.iter().map(|entry| entry["size"].as_u64().unwrap()).collect()
+    };
+
+    assert_eq!(sizes("/admin/audit"), [4, 3, 2, 1, 0]);
+    assert_eq!(sizes("/admin/audit?key=a"), [4, 2, 0]);
+    assert_eq!(sizes("/admin/audit?key=a&limit=2"), [4, 2]);
+    assert_eq!(sizes("/admin/audit?limit=1"), [4]);
+    assert!(sizes("/admin/audit?key=missing").is_empty());
+
+    // Without an audit log, there's nothing to query
+    let client = Client::tracked(rocket::build().manage(None::<AuditLog>).mount("/", routes![recent])).unwrap();
+    assert_eq!(client.get("/admin/audit").dispatch().status(), rocket::http::Status::NotFound);
+}
+
+#[cfg(test)]
+fn entry(key: &str) -> AuditEntry {
+    AuditEntry {
+        timestamp_ms: 0,
+        request_id: "request".to_owned(),
+        client: "127.0.0.1".to_owned(),
+        route: "write_kopper".to_owned(),
+        database: None,
+        key: Some(key.to_owned()),
+        size: 1,
+        status: 200,
+    }
+}
@@ -0,0 +1,213 @@
+//! Readers turning files of other tools into writes
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use clap::ValueEnum;
+
+use crate::{CliResult, Target, BATCH_SIZE};
+
+/// Entries between progress reports
+const PROGRESS_EVERY: usize = 10_000;
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Format {
+    /// Key in the first column, value in the second
+    Csv,
+    /// Redis append only file, with the RDB preamble disabled
+    RedisAppendonly,
+}
+
+/// Change read from an imported file
+enum Op {
+    Put { key: String, value: String, expires: bool },
+    Delete(String),
+    /// Something that isn't imported, like a command without a counterpart
+    Skip(String),
+}
+
+fn csv(file: File, header: bool) -> impl Iterator<Item = CliResult<Op>> {
+    let reader = csv::ReaderBuilder::new().has_headers(header).from_reader(BufReader::new(file));
+
+    reader.into_records().map(|record| {
+        let record = record?;
+        match (record.get(0), record.get(1), record.len()) {
+            (Some(key), Some(value), 2) => Ok(Op::Put { key: key.to_string(), value: value.to_string(), expires: false }),
+            _ => Err(format!("Line {}: expected 2 columns, found {}", record.position().map_or(0, |position| position.line()), record.len()).into()),
+        }
+    })
+}
+
+/// Commands of a Redis append only file, read one at a time
+struct Appendonly<R> {
+    reader: R,
+}
+
+impl<R: BufRead> Appendonly<R> {
+    fn line(&mut self) -> CliResult<Option<String>> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        match line.strip_suffix("\r\n") {
+            Some(line) => Ok(Some(line.to_string())),
+            None => Err("Truncated command".into()),
+        }
+    }
+
+    fn command(&mut self) -> CliResult<Option<Vec<Vec<u8>>>> {
+        let Some(header) = self.line()? else {
+            return Ok(None);
+        };
+        let count: usize = header.strip_prefix('*').and_then(|count| count.parse().ok())
+            .ok_or_else(|| format!("Expected a command, found {header:?}"))?;
+
+        let mut args = Vec::with_capacity(count);
+        for _ in 0..count {
+            let header = self.line()?.ok_or("Truncated command")?;
+            let len: usize = header.strip_prefix('$').and_then(|len| len.parse().ok())
+                .ok_or_else(|| format!("Expected an argument, found {header:?}"))?;
+
+            let mut arg = vec![0; len + 2];
+            if self.reader.read_exact(&mut arg).is_err() {
+                return Err("Truncated command".into());
+            }
+            if !arg.ends_with(b"\r\n") {
+                return Err("Argument longer than declared".into());
+            }
+            arg.truncate(len);
+            args.push(arg);
+        }
+        Ok(Some(args))
+    }
+
+    fn op(args: Vec<Vec<u8>>) -> CliResult<Vec<Op>> {
+        let args = args.into_iter()
+            .map(String::from_utf8)
+            .collect::<Result<Vec<_>, _>>();
+        // Binary keys and values can't be stored
+        let Ok(args) = args else {
+            return Ok(vec![Op::Skip("binary data".to_string())]);
+        };
+
+        let Some(command) = args.first().map(|command| command.to_uppercase()) else {
+            return Err("Empty command".into());
+        };
+        let ops = match (command.as_str(), &args[1..]) {
+            // Only successful writes are logged, so NX and XX don't matter
+            ("SET", [key, value, options @ ..]) => {
+                let expires = options.iter().any(|option| {
+                    matches!(option.to_uppercase().as_str(), "EX" | "PX" | "EXAT" | "PXAT")
+                });
+                vec![Op::Put { key: key.clone(), value: value.clone(), expires }]
+            },
+            ("SETEX" | "PSETEX", [key, _, value]) => vec![Op::Put { key: key.clone(), value: value.clone(), expires: true }],
+            ("MSET", pairs) if pairs.len() % 2 == 0 => pairs.chunks(2)
+                .map(|pair| Op::Put { key: pair[0].clone(), value: pair[1].clone(), expires: false })
+                .collect(),
+            ("DEL" | "UNLINK", keys) => keys.iter().cloned().map(Op::Delete).collect(),
+            // Transactions are replayed command by command anyway
+            ("MULTI" | "EXEC" | "SELECT", _) => Vec::new(),
+            _ => vec![Op::Skip(command)],
+        };
+        Ok(ops)
+    }
+
+    fn into_ops(mut self) -> impl Iterator<Item = CliResult<Op>> {
+        let mut failed = false;
+        std::iter::from_fn(move || {
+            if failed {
+                return None;
+            }
+            let ops = match self.command() {
+                Ok(Some(args)) => Self::op(args),
+                Ok(None) => return None,
+                Err(err) => Err(err),
+            };
+            failed = ops.is_err();
+            Some(ops)
+        })
+        .flat_map(|ops| match ops {
+            Ok(ops) => ops.into_iter().map(Ok).collect::<Vec<_>>(),
+            Err(err) => vec![Err(err)],
+        })
+    }
+}
+
+fn appendonly(file: File) -> CliResult<impl Iterator<Item = CliResult<Op>>> {
+    let mut reader = BufReader::new(file);
+    if reader.fill_buf()?.starts_with(b"REDIS") {
+        return Err("Append only files with an RDB preamble aren't supported, rewrite it with aof-use-rdb-preamble no".into());
+    }
+    Ok(Appendonly { reader }.into_ops())
+}
+
+/// What an import did, or would do in a dry run
+#[derive(Default)]
+struct Summary {
+    written: usize,
+    deleted: usize,
+    expiring: usize,
+    skipped: BTreeMap<String, usize>,
+}
+
+/// Streams the entries of `file` through batched writes, printing progress to
+/// stderr. Deletes flush the pending batch first, keeping the order of the file.
+/// `dry_run` reads the whole file, writing nothing.
+pub fn import(target: &Target, file: &str, format: Format, header: bool, dry_run: bool) -> CliResult<()> {
+    let file = File::open(file)?;
+    let ops: Box<dyn Iterator<Item = CliResult<Op>>> = match format {
+        Format::Csv => Box::new(csv(file, header)),
+        Format::RedisAppendonly => Box::new(appendonly(file)?),
+    };
+
+    let mut summary = Summary::default();
+    let mut batch: Vec<(String, String)> = Vec::with_capacity(BATCH_SIZE);
+    let flush = |batch: &mut Vec<(String, String)>| -> CliResult<()> {
+        if !dry_run && !batch.is_empty() {
+            let pairs: Vec<(&str, &str)> = batch.iter().map(|(key, value)| (key.as_str(), value.as_str())).collect();
+            target.write_batch(&pairs)?;
+        }
+        batch.clear();
+        Ok(())
+    };
+
+    for (done, op) in ops.enumerate() {
+        match op? {
+            Op::Put { key, value, .. } if key.is_empty() || key.contains('\0') || value.contains('\0') => {
+                *summary.skipped.entry("unstorable entry".to_string()).or_default() += 1;
+            },
+            Op::Put { key, value, expires } => {
+                summary.written += 1;
+                summary.expiring += expires as usize;
+                batch.push((key, value));
+                if batch.len() == BATCH_SIZE {
+                    flush(&mut batch)?;
+                }
+            },
+            Op::Delete(key) => {
+                flush(&mut batch)?;
+                if dry_run || target.delete(&key)? {
+                    summary.deleted += 1;
+                }
+            },
+            Op::Skip(reason) => *summary.skipped.entry(reason).or_default() += 1,
+        }
+
+        if (done + 1) % PROGRESS_EVERY == 0 {
+            eprintln!("{} entries read...", done + 1);
+        }
+    }
+    flush(&mut batch)?;
+
+    let verb = if dry_run { "Would write" } else { "Wrote" };
+    println!("{verb} {} entries, deleted {}", summary.written, summary.deleted);
+    if summary.expiring > 0 {
+        println!("{} of them had an expiry time, which isn't supported - they're written without one", summary.expiring);
+    }
+    for (reason, count) in summary.skipped {
+        println!("Skipped {count}: {reason}");
+    }
+    Ok(())
+}
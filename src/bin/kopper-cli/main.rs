@@ -0,0 +1,372 @@
+use std::{error::Error, fs::{self, File}, io::{self, BufReader, BufWriter}, path::Path, process::ExitCode};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::{Parser, Subcommand};
+
+use kopperdb::archive::{ArchiveReader, ArchiveWriter, Entry};
+use kopperdb::client::{ClientError, KopperClient};
+use kopperdb::doctor::Health;
+use kopperdb::kopper::{Kopper, KopperError};
+
+mod import;
+mod shell;
+
+type CliResult<T> = Result<T, Box<dyn Error>>;
+
+/// Entries written with a single batch by restore and import
+const BATCH_SIZE: usize = 1024;
+
+/// Day-to-day operations on a KopperDB database.
+///
+/// Every command takes the database first - either a directory, opened directly,
+/// or the URL of a running server, like http://localhost:8000. Don't open a
+/// directory a server is using, go through the server instead.
+#[derive(Parser)]
+#[command(name = "kopper-cli", version)]
+struct Cli {
+    /// Segment size to use when opening a database directory
+    #[arg(long, global = true, default_value_t = 4096)]
+    segment_size: usize,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the value of a key
+    Get { db: String, key: String },
+    /// Store a value under a key
+    Set { db: String, key: String, value: String },
+    /// Delete a key
+    Del { db: String, key: String },
+    /// Print all entries with keys starting with a prefix, tab separated
+    Scan {
+        db: String,
+        #[arg(default_value = "")]
+        prefix: String,
+    },
+    /// Print size and latency stats
+    Stats { db: String },
+    /// Compact every sealed segment
+    Compact { db: String },
+    /// Seal the active segment and start writing a new one
+    Roll { db: String },
+    /// Check the database is healthy, suggesting what to do if it's not. Fails
+    /// if a check does.
+    Doctor { db: String },
+    /// Back the database up. Directories are copied to `target`, servers create
+    /// a backup on their side, downloaded as a tarball to `target` if it's given.
+    Backup { db: String, target: Option<String> },
+    /// Open an interactive shell
+    Shell { db: String },
+    /// Write every entry to a checksummed archive file
+    Dump { db: String, file: String },
+    /// Write every entry of an archive file to a database, created if needed.
+    /// The whole archive is verified before anything is written.
+    Restore { file: String, db: String },
+    /// Write the entries of a file from another tool. Keys in a Redis append
+    /// only file are imported from every database.
+    Import {
+        db: String,
+        file: String,
+        #[arg(long, value_enum)]
+        format: import::Format,
+        /// Skip the first row of a CSV file
+        #[arg(long)]
+        header: bool,
+        /// Read the whole file and report what would be written, writing nothing
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+impl Command {
+    fn db(&self) -> &str {
+        match self {
+            Command::Get { db, .. } | Command::Set { db, .. } | Command::Del { db, .. } |
+            Command::Scan { db, .. } | Command::Stats { db } | Command::Compact { db } | Command::Roll { db } | Command::Doctor { db } |
+            Command::Backup { db, .. } | Command::Shell { db } | Command::Dump { db, .. } |
+            Command::Restore { db, .. } | Command::Import { db, .. } => db,
+        }
+    }
+}
+
+/// Database a command runs against
+enum Target {
+    Embedded(Kopper),
+    Remote(KopperClient),
+}
+
+impl Target {
+    fn is_remote(db: &str) -> bool {
+        db.starts_with("http://") || db.starts_with("https://")
+    }
+
+    fn open(db: &str, segment_size: usize) -> CliResult<Self> {
+        if Target::is_remote(db) {
+            return Ok(Target::Remote(KopperClient::connect(db)?));
+        }
+
+        // Opening creates missing databases - a typo shouldn't
+        if !Path::new(db).is_dir() {
+            return Err(format!("No database directory at {db}").into());
+        }
+        Ok(Target::Embedded(Kopper::create(db, segment_size)?))
+    }
+
+    fn scan(&self) -> CliResult<Vec<(String, String)>> {
+        match self {
+            Target::Embedded(db) => Ok(db.scan()?.collect::<Result<_, _>>()?),
+            Target::Remote(client) => Ok(client.scan("")?),
+        }
+    }
+
+    fn write_batch(&self, entries: &[(&str, &str)]) -> CliResult<()> {
+        match self {
+            Target::Embedded(db) => db.write_batch(entries).map(|_| ())?,
+            Target::Remote(client) => client.batch(entries).map(|_| ())?,
+        }
+        Ok(())
+    }
+
+    /// Deletes `key`, false if it didn't exist
+    fn delete(&self, key: &str) -> CliResult<bool> {
+        match self {
+            Target::Embedded(db) => match db.delete(key) {
+                Ok(_) => Ok(true),
+                Err(KopperError::KeyDoesNotExist(_)) => Ok(false),
+                Err(err) => Err(err.into()),
+            },
+            Target::Remote(client) => Ok(client.delete(key)?),
+        }
+    }
+
+    fn close(self) -> CliResult<()> {
+        if let Target::Embedded(db) = self {
+            db.close()?;
+        }
+        Ok(())
+    }
+}
+
+/// How values are printed
+#[derive(Clone, Copy)]
+enum Output {
+    /// As stored, for scripts
+    Raw,
+    /// JSON values indented, for people
+    Pretty,
+}
+
+impl Output {
+    fn print(self, value: &str) {
+        let json = match self {
+            Output::Raw => None,
+            Output::Pretty => serde_json::from_str::<serde_json::Value>(value).ok()
+                .filter(|json| json.is_object() || json.is_array()),
+        };
+
+        match json {
+            // Serializing a parsed value can't fail
+            Some(json) => println!("{}", serde_json::to_string_pretty(&json).unwrap()),
+            None => println!("{value}"),
+        }
+    }
+}
+
+fn run(target: &Target, command: Command, output: Output) -> CliResult<()> {
+    match (target, command) {
+        (_, Command::Shell { db }) => shell::shell(target, &db)?,
+
+        (Target::Embedded(db), Command::Get { key, .. }) => match db.read(&key) {
+            Ok(value) => output.print(&value),
+            Err(KopperError::KeyDoesNotExist(_)) => return Err(format!("{key} does not exist").into()),
+            Err(err) => return Err(err.into()),
+        },
+        (Target::Remote(client), Command::Get { key, .. }) => match client.get(&key)? {
+            Some(value) => output.print(&value),
+            None => return Err(format!("{key} does not exist").into()),
+        },
+
+        (Target::Embedded(db), Command::Set { key, value, .. }) => {
+            if key.is_empty() || key.contains('\0') || value.contains('\0') {
+                return Err("Keys can't be empty, keys and values can't contain NUL".into());
+            }
+            db.write(&key, &value)?;
+        },
+        (Target::Remote(client), Command::Set { key, value, .. }) => client.put(&key, &value)?,
+
+        (Target::Embedded(db), Command::Del { key, .. }) => match db.delete(&key) {
+            Ok(_) => {},
+            Err(KopperError::KeyDoesNotExist(_)) => return Err(format!("{key} does not exist").into()),
+            Err(err) => return Err(err.into()),
+        },
+        (Target::Remote(client), Command::Del { key, .. }) => {
+            if !client.delete(&key)? {
+                return Err(format!("{key} does not exist").into());
+            }
+        },
+
+        (Target::Embedded(db), Command::Scan { prefix, .. }) => {
+            for entry in db.scan()? {
+                let (key, value) = entry?;
+                if key.starts_with(&prefix) {
+                    println!("{key}\t{value}");
+                }
+            }
+        },
+        (Target::Remote(client), Command::Scan { prefix, .. }) => {
+            for (key, value) in client.scan(&prefix)? {
+                println!("{key}\t{value}");
+            }
+        },
+
+        (Target::Embedded(db), Command::Stats { .. }) => {
+            println!("keys: {}", db.len());
+            println!("size: {} bytes", db.size());
+        },
+        (Target::Remote(client), Command::Stats { .. }) => {
+            for metric in ["read", "write", "size", "throughput", "errors"] {
+                match client.percentiles(metric) {
+                    Ok(stats) => println!("{metric}: p50 {} p90 {} p99 {} p999 {} {} ({} samples)",
+                        stats.p50, stats.p90, stats.p99, stats.p999, stats.unit, stats.count),
+                    // Metrics without samples aren't served
+                    Err(ClientError::Http(err)) if err.status().is_some_and(|status| status.as_u16() == 404) =>
+                        println!("{metric}: no samples"),
+                    Err(err) => return Err(err.into()),
+                }
+            }
+        },
+
+        (Target::Embedded(db), Command::Compact { .. }) => {
+            let reports = db.compactions();
+            let queued = db.compact()?;
+
+            db.wait_for_compactions()?;
+            let reclaimed: usize = reports.try_iter().map(|report| report.reclaimed_bytes).sum();
+            println!("Compacted {queued} segments, reclaimed {reclaimed} bytes");
+        },
+        (Target::Remote(client), Command::Compact { .. }) => {
+            let queued = client.compact()?;
+            println!("Queued compaction of {queued} segments");
+        },
+
+        (_, Command::Roll { .. }) => {
+            let segment = match target {
+                Target::Embedded(db) => db.roll_segment()?,
+                Target::Remote(client) => client.roll_segment()?,
+            };
+            println!("Writing to segment {segment}");
+        },
+
+        (_, Command::Doctor { .. }) => {
+            let report = match target {
+                Target::Embedded(db) => db.doctor()?,
+                Target::Remote(client) => client.doctor()?,
+            };
+            for check in &report.checks {
+                println!("[{}] {}: {}", check.health, check.name, check.detail);
+                if let Some(remedy) = &check.remedy {
+                    println!("    {remedy}");
+                }
+            }
+            if report.health() == Health::Failing {
+                return Err("Some checks failed".into());
+            }
+        },
+
+        (Target::Embedded(db), Command::Backup { target, .. }) => {
+            let target = target.ok_or("Backing up a directory needs a target directory")?;
+            let sequence = db.snapshot_to(&target)?;
+            println!("Backed up to {target} as of change {sequence}");
+        },
+        (Target::Remote(client), Command::Backup { target, .. }) => {
+            let id = client.backup()?;
+            let url = client.backup_url(&id);
+            match target {
+                Some(target) => {
+                    let mut tarball = reqwest::blocking::get(url)?.error_for_status()?;
+                    io::copy(&mut tarball, &mut File::create(&target)?)?;
+                    println!("Backup {id} saved to {target}");
+                },
+                None => println!("Backup {id} available at {url}"),
+            }
+        },
+
+        (_, Command::Dump { file, .. }) => {
+            let created_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+            let mut archive = ArchiveWriter::new(BufWriter::new(File::create(&file)?), created_at)?;
+
+            // Write times aren't tracked, so entries carry no timestamp
+            let entries = target.scan()?;
+            for (key, value) in &entries {
+                archive.write(&Entry::new(key.clone(), value.clone()))?;
+            }
+            archive.finish()?;
+            println!("Dumped {} entries to {file}", entries.len());
+        },
+        (_, Command::Restore { file, .. }) => {
+            // Verify the checksum before touching the database, so a damaged
+            // archive can't leave it half restored
+            let mut expiring = 0;
+            for entry in ArchiveReader::new(BufReader::new(File::open(&file)?))? {
+                let entry = entry?;
+                if entry.key.is_empty() || entry.key.contains('\0') || entry.value.contains('\0') {
+                    return Err(format!("Archive holds an entry that can't be stored: {:?}", entry.key).into());
+                }
+                if entry.expires_at != 0 {
+                    expiring += 1;
+                }
+            }
+
+            let mut restored = 0;
+            let mut batch = Vec::with_capacity(BATCH_SIZE);
+            let mut entries = ArchiveReader::new(BufReader::new(File::open(&file)?))?.peekable();
+            while let Some(entry) = entries.next() {
+                batch.push(entry?);
+                if batch.len() == BATCH_SIZE || entries.peek().is_none() {
+                    let pairs: Vec<(&str, &str)> = batch.iter().map(|entry| (entry.key.as_str(), entry.value.as_str())).collect();
+                    target.write_batch(&pairs)?;
+                    restored += batch.len();
+                    batch.clear();
+                }
+            }
+
+            println!("Restored {restored} entries from {file}");
+            if expiring > 0 {
+                println!("{expiring} of them had an expiry time, which isn't supported - they're restored without one");
+            }
+        },
+        (_, Command::Import { file, format, header, dry_run, .. }) => import::import(target, &file, format, header, dry_run)?,
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    // Restoring is how new databases get created
+    if let Command::Restore { db, .. } = &cli.command {
+        if !Target::is_remote(db) {
+            if let Err(err) = fs::create_dir_all(db) {
+                eprintln!("error: Can't create {db}: {err}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    let result = Target::open(cli.command.db(), cli.segment_size).and_then(|target| {
+        let result = run(&target, cli.command, Output::Raw);
+        let closed = target.close();
+        result.and(closed)
+    });
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
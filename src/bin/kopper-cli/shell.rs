@@ -0,0 +1,117 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::{Context, Editor, Helper, Highlighter, Hinter, Validator};
+
+use crate::{run, Cli, CliResult, Command, Output, Target};
+
+/// Commands of the shell, completed with tab
+const COMMANDS: &[&str] = &["get", "set", "del", "scan", "stats", "compact", "backup", "dump", "restore", "import", "help", "exit"];
+
+const HELP: &str = "\
+get <key>              print the value of a key
+set <key> <value>      store a value under a key
+del <key>              delete a key
+scan [prefix]          print entries with keys starting with prefix
+stats                  print size and latency stats
+compact                compact every sealed segment
+backup [target]        back the database up
+dump <file>            write every entry to an archive file
+restore <file>         write every entry of an archive file
+import <file> --format csv|redis-appendonly [--header] [--dry-run]
+                       write the entries of a file from another tool
+help                   print this message
+exit                   leave the shell
+
+Arguments with spaces can be quoted: set greeting \"hello world\"";
+
+#[derive(Helper, Hinter, Highlighter, Validator)]
+struct ShellHelper;
+
+impl Completer for ShellHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, _: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        // Only the command is completed - keys would need a scan per keystroke
+        let typed = &line[..pos];
+        if typed.contains(char::is_whitespace) {
+            return Ok((pos, Vec::new()));
+        }
+
+        let candidates = COMMANDS.iter()
+            .filter(|command| command.starts_with(typed))
+            .map(|command| Pair { display: command.to_string(), replacement: format!("{command} ") })
+            .collect();
+        Ok((0, candidates))
+    }
+}
+
+/// History is kept across sessions in the user's home directory
+fn history_file() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".kopper_history"))
+}
+
+/// Interactive prompt running commands against `target` until `exit` or Ctrl-D
+pub fn shell(target: &Target, db: &str) -> CliResult<()> {
+    let mut editor = Editor::new()?;
+    editor.set_helper(Some(ShellHelper));
+
+    let history = history_file();
+    if let Some(history) = &history {
+        // There's none on the first run
+        let _ = editor.load_history(history);
+    }
+
+    let prompt = format!("{db}> ");
+    loop {
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(err.into()),
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line.as_str())?;
+
+        let Some(mut args) = shlex::split(&line) else {
+            eprintln!("error: unbalanced quotes");
+            continue;
+        };
+
+        match args[0].as_str() {
+            "exit" | "quit" => break,
+            "help" => {
+                println!("{HELP}");
+                continue;
+            },
+            _ => {},
+        }
+
+        // Same commands as on the command line, with the database filled in
+        // in the place it takes there - last for restore, first for the rest
+        if args[0] == "restore" {
+            args.push(db.to_string());
+        } else {
+            args.insert(1, db.to_string());
+        }
+        args.insert(0, "kopper-cli".to_string());
+        match Cli::try_parse_from(args) {
+            Ok(Cli { command: Command::Shell { .. }, .. }) => eprintln!("error: already in a shell"),
+            Ok(cli) => {
+                if let Err(err) = run(target, cli.command, Output::Pretty) {
+                    eprintln!("error: {err}");
+                }
+            },
+            Err(err) => eprintln!("{}", err.render()),
+        }
+    }
+
+    if let Some(history) = &history {
+        editor.save_history(history)?;
+    }
+    Ok(())
+}
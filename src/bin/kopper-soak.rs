@@ -0,0 +1,366 @@
+//! Hammers a database from many threads for as long as asked, stopping every
+//! now and then to check it still holds exactly what was written. Meant to run
+//! for hours before a release - see `kopper-soak --help`.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write as _;
+use std::fs;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use clap::Parser;
+
+use kopperdb::kopper::{Kopper, KopperError};
+
+/// Stress test of a KopperDB database with invariant checks.
+///
+/// Every worker thread owns its own keys and remembers what it wrote, so it
+/// can tell whether it reads back its own writes. At every check the workers
+/// are paused, segments are compacted, and every key is compared, the size is
+/// held against what the live keys can take up, and open files are counted.
+/// The first failure is reported with diagnostics and the exit code is 1.
+#[derive(Parser)]
+#[command(name = "kopper-soak", version)]
+struct Options {
+    /// Database directory, created if needed. Should be empty - existing
+    /// entries aren't known to the workers.
+    path: String,
+
+    #[arg(long, default_value_t = 8)]
+    threads: usize,
+
+    /// How long to run for, in seconds
+    #[arg(long, default_value_t = 60)]
+    seconds: u64,
+
+    /// Seconds between checks
+    #[arg(long, default_value_t = 5)]
+    check_every: u64,
+
+    /// Keys every thread writes to
+    #[arg(long, default_value_t = 1000)]
+    keys: usize,
+
+    /// Longest value written
+    #[arg(long, default_value_t = 100)]
+    max_value: usize,
+
+    #[arg(long, default_value_t = 4096)]
+    segment_size: usize,
+
+    /// How many times the space the live keys and their tombstones take up the
+    /// compacted database may grow to
+    #[arg(long, default_value_t = 4)]
+    max_growth: usize,
+
+    /// Close and reopen the database at every check, to verify recovery too
+    #[arg(long)]
+    restart: bool,
+
+    /// Seed of the workload, random if not given
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+/// Operations remembered per thread, printed when something goes wrong
+const HISTORY: usize = 32;
+
+/// xorshift64* - good enough to pick operations, and reproducible from a seed
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+}
+
+/// What a worker wrote, and the last few things it did
+#[derive(Default)]
+struct Model {
+    entries: BTreeMap<String, String>,
+    history: VecDeque<String>,
+}
+
+impl Model {
+    fn record(&mut self, op: String) {
+        if self.history.len() == HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(op);
+    }
+}
+
+struct Soak {
+    options: Options,
+    /// Workers hold it for reading during every operation, checks take it for
+    /// writing to pause them - and to swap the database when restarting
+    db: RwLock<Kopper>,
+    models: Vec<Mutex<Model>>,
+    failure: Mutex<Option<String>>,
+    stop: AtomicBool,
+    ops: AtomicU64,
+}
+
+impl Soak {
+    fn key(thread: usize, key: usize) -> String {
+        format!("t{thread}:{key:08}")
+    }
+
+    fn fail(&self, message: String) {
+        let mut failure = self.failure.lock().unwrap();
+        if failure.is_none() {
+            *failure = Some(message);
+        }
+        self.stop.store(true, Ordering::SeqCst);
+    }
+
+    fn work(&self, thread: usize, mut rng: Rng) {
+        while !self.stop.load(Ordering::SeqCst) {
+            let db = self.db.read().unwrap();
+            let mut model = self.models[thread].lock().unwrap();
+            if let Err(message) = self.step(&db, &mut model, thread, &mut rng) {
+                let history = model.history.iter().cloned().collect::<Vec<_>>().join("\n  ");
+                self.fail(format!("Thread {thread}: {message}\nIts last operations:\n  {history}"));
+            }
+            self.ops.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// One random operation, checked against what the thread wrote before
+    fn step(&self, db: &Kopper, model: &mut Model, thread: usize, rng: &mut Rng) -> Result<(), String> {
+        let key = Soak::key(thread, rng.below(self.options.keys));
+        let value = |rng: &mut Rng| "v".repeat(rng.below(self.options.max_value + 1));
+
+        match rng.below(100) {
+            0..=44 => {
+                let value = value(rng);
+                model.record(format!("write {key} ({} bytes)", value.len()));
+                db.write(&key, &value).map_err(|err| format!("Write of {key} failed: {err}"))?;
+                model.entries.insert(key, value);
+            },
+            45..=54 => {
+                let batch: Vec<(String, String)> = (0..1 + rng.below(16))
+                    .map(|_| (Soak::key(thread, rng.below(self.options.keys)), value(rng)))
+                    .collect();
+                model.record(format!("batch of {} from {}", batch.len(), batch[0].0));
+                let entries: Vec<(&str, &str)> = batch.iter().map(|(key, value)| (key.as_str(), value.as_str())).collect();
+                db.write_batch(&entries).map_err(|err| format!("Batch from {} failed: {err}", batch[0].0))?;
+                model.entries.extend(batch);
+            },
+            55..=64 => {
+                model.record(format!("delete {key}"));
+                match (db.delete(&key), model.entries.remove(&key)) {
+                    (Ok(_), Some(_)) | (Err(KopperError::KeyDoesNotExist(_)), None) => {},
+                    (Ok(_), None) => return Err(format!("Deleted {key}, which was never written or deleted already")),
+                    (Err(err), _) => return Err(format!("Delete of {key} failed: {err}")),
+                }
+            },
+            65..=98 => {
+                model.record(format!("read {key}"));
+                Soak::check_read(db, &key, model.entries.get(&key))?;
+            },
+            _ => {
+                // Point-in-time, so exactly the thread's own writes up to now
+                model.record("scan".to_owned());
+                let prefix = format!("t{thread}:");
+                let mut scanned = BTreeMap::new();
+                for entry in db.scan().map_err(|err| format!("Scan failed: {err}"))? {
+                    let (key, value) = entry.map_err(|err| format!("Scan failed: {err}"))?;
+                    if key.starts_with(&prefix) {
+                        scanned.insert(key, value);
+                    }
+                }
+                if scanned != model.entries {
+                    return Err(format!("Scan found {} of its keys, {} were written", scanned.len(), model.entries.len()));
+                }
+            },
+        }
+        Ok(())
+    }
+
+    fn check_read(db: &Kopper, key: &str, expected: Option<&String>) -> Result<(), String> {
+        match (db.read(key), expected) {
+            (Ok(value), Some(expected)) if &value == expected => Ok(()),
+            (Err(KopperError::KeyDoesNotExist(_)), None) => Ok(()),
+            (Ok(value), expected) => Err(format!("Read {key} as {} bytes, expected {:?}", value.len(), expected.map(String::len))),
+            (Err(err), expected) => Err(format!("Read of {key} failed: {err}, expected {:?} bytes", expected.map(String::len))),
+        }
+    }
+
+    /// Runs with every worker paused
+    fn check(&self, db: &mut Kopper) -> Result<(), String> {
+        if self.options.restart {
+            db.close().map_err(|err| format!("Close failed: {err}"))?;
+            *db = Kopper::create(&self.options.path, self.options.segment_size)
+                .map_err(|err| format!("Reopening failed: {err}"))?;
+        }
+        db.compact().and_then(|_| db.wait_for_compactions()).map_err(|err| format!("Compaction failed: {err}"))?;
+
+        let mut keys = 0;
+        let mut live = 0;
+        for (thread, model) in self.models.iter().enumerate() {
+            let model = model.lock().unwrap();
+            for key in (0..self.options.keys).map(|key| Soak::key(thread, key)) {
+                Soak::check_read(db, &key, model.entries.get(&key)).map_err(|err| format!("Thread {thread}: {err}"))?;
+            }
+            keys += model.entries.len();
+            live += model.entries.iter().map(|(key, value)| key.len() + value.len() + 2).sum::<usize>();
+        }
+        if db.len() != keys {
+            return Err(format!("{} keys in the database, {keys} were written", db.len()));
+        }
+
+        // Every key may leave a tombstone behind, and the active segment isn't compacted
+        let key_len = Soak::key(0, 0).len();
+        let tombstones = self.options.threads * self.options.keys * (key_len + 3);
+        let bound = self.options.max_growth * (live + tombstones) + 2 * self.options.segment_size;
+        if db.size() > bound {
+            return Err(format!("Database takes up {} bytes after compacting, more than {bound}", db.size()));
+        }
+
+        // Descriptors of removed segments stay open only while something still reads them
+        if let Some(open) = open_files(&self.options.path) {
+            let segments = segments(&self.options.path).len();
+            let deleted = open.iter().filter(|file| file.ends_with(" (deleted)")).count();
+            if deleted > 0 || open.len() > segments {
+                return Err(format!("{} files of the database open, {deleted} of them removed, {segments} segments", open.len()));
+            }
+        }
+        Ok(())
+    }
+
+    fn diagnostics(&self, db: &Kopper) -> String {
+        let mut report = String::new();
+        let _ = writeln!(report, "Operations: {}, keys: {}, size: {}, sequence: {}",
+            self.ops.load(Ordering::Relaxed), db.len(), db.size(), db.sequence());
+        let mut segments = segments(&self.options.path);
+        let total: u64 = segments.iter().map(|(_, len)| len).sum();
+        let _ = writeln!(report, "Segments: {}, {total} bytes, largest:", segments.len());
+        segments.sort_by_key(|(_, len)| std::cmp::Reverse(*len));
+        for (name, len) in segments.iter().take(10) {
+            let _ = writeln!(report, "  {name}: {len} bytes");
+        }
+        if let Some(open) = open_files(&self.options.path) {
+            let _ = writeln!(report, "Open files of the database:");
+            for file in open {
+                let _ = writeln!(report, "  {file}");
+            }
+        }
+        report
+    }
+}
+
+/// Segment files of the database with their sizes, by name
+fn segments(path: &str) -> Vec<(String, u64)> {
+    let mut segments: Vec<(String, u64)> = fs::read_dir(path).into_iter().flatten().flatten()
+        .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.') && entry.file_name() != "MANIFEST")
+        .map(|entry| (entry.file_name().to_string_lossy().into_owned(), entry.metadata().map_or(0, |metadata| metadata.len())))
+        .collect();
+    segments.sort();
+    segments
+}
+
+/// Files in `path` this process has open, `None` where that can't be told
+fn open_files(path: &str) -> Option<Vec<String>> {
+    let dir = fs::canonicalize(path).ok()?;
+    let descriptors = fs::read_dir("/proc/self/fd").ok()?;
+    Some(descriptors.flatten()
+        .filter_map(|descriptor| fs::read_link(descriptor.path()).ok())
+        .filter(|target| target.parent() == Some(&dir))
+        .map(|target| target.display().to_string())
+        .collect())
+}
+
+fn main() -> ExitCode {
+    let options = Options::parse();
+    let seed = options.seed.unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64) | 1;
+    println!("Soaking {} with {} threads for {}s, seed {seed}", options.path, options.threads, options.seconds);
+
+    let db = match Kopper::create(&options.path, options.segment_size) {
+        Ok(db) => db,
+        Err(err) => {
+            eprintln!("Can't open {}: {err}", options.path);
+            return ExitCode::FAILURE;
+        }
+    };
+    if !db.is_empty() {
+        eprintln!("{} isn't empty", options.path);
+        return ExitCode::FAILURE;
+    }
+
+    let soak = Arc::new(Soak {
+        models: (0..options.threads).map(|_| Mutex::default()).collect(),
+        options,
+        db: RwLock::new(db),
+        failure: Mutex::new(None),
+        stop: AtomicBool::new(false),
+        ops: AtomicU64::new(0),
+    });
+
+    let mut seeds = Rng(seed);
+    let workers: Vec<_> = (0..soak.options.threads).map(|thread| {
+        let soak = soak.clone();
+        let rng = Rng(seeds.next() | 1);
+        std::thread::spawn(move || soak.work(thread, rng))
+    }).collect();
+
+    let started = Instant::now();
+    let deadline = started + Duration::from_secs(soak.options.seconds);
+    let mut checks = 0;
+    while !soak.stop.load(Ordering::SeqCst) {
+        let next = (Instant::now() + Duration::from_secs(soak.options.check_every)).min(deadline);
+        while Instant::now() < next && !soak.stop.load(Ordering::SeqCst) {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        if Instant::now() >= deadline {
+            soak.stop.store(true, Ordering::SeqCst);
+        }
+
+        let mut db = soak.db.write().unwrap();
+        if soak.failure.lock().unwrap().is_some() {
+            break;
+        }
+        match soak.check(&mut db) {
+            Ok(()) => {
+                checks += 1;
+                println!("{:>6}s  {} operations, {} keys, {} bytes, {} segments - ok",
+                    started.elapsed().as_secs(), soak.ops.load(Ordering::Relaxed), db.len(), db.size(), segments(&soak.options.path).len());
+            },
+            Err(message) => soak.fail(format!("Check {}: {message}", checks + 1)),
+        }
+    }
+
+    for worker in workers {
+        if worker.join().is_err() {
+            soak.fail("A worker panicked".to_owned());
+        }
+    }
+
+    let db = soak.db.read().unwrap();
+    let failure = soak.failure.lock().unwrap().take();
+    match failure {
+        None => match db.close() {
+            Ok(()) => {
+                println!("Passed {checks} checks, {} operations", soak.ops.load(Ordering::Relaxed));
+                ExitCode::SUCCESS
+            },
+            Err(err) => {
+                eprintln!("Close failed: {err}");
+                ExitCode::FAILURE
+            },
+        },
+        Some(failure) => {
+            eprintln!("FAILED: {failure}\n{}Rerun with --seed {seed} to get the same workload", soak.diagnostics(&db));
+            ExitCode::FAILURE
+        },
+    }
+}
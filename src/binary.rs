@@ -0,0 +1,174 @@
+use std::io;
+
+use rocket::{Rocket, Orbit, Shutdown};
+use rocket::fairing::AdHoc;
+use rocket::tokio::{self, io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter}, net::{TcpListener, TcpStream}};
+
+use kopperdb::kopper::{Kopper, KopperError};
+use kopperdb::protocol::{self, Frame, Header, ProtocolError, Reply, Request, Response};
+
+/// Fairing starting a listener for the binary protocol from [`kopperdb::protocol`]
+/// at `binary_address` from Rocket's config, and another on the Unix socket at
+/// `binary_socket`, for each that's set. Every connection is served by its own
+/// task, so they're spread over all of the runtime's threads.
+pub fn listener() -> AdHoc {
+    AdHoc::on_liftoff("binary protocol listener", |rocket| Box::pin(async move {
+        if let Ok(address) = rocket.figment().extract_inner::<String>("binary_address") {
+            match TcpListener::bind(&address).await {
+                Ok(listener) => {
+                    tracing::info!("Binary protocol listener on {address}");
+                    tokio::spawn(accept(listener, db(rocket), rocket.shutdown()));
+                },
+                Err(err) => tracing::error!("Can't start binary protocol listener on {address}: {err}"),
+            }
+        }
+
+        if let Ok(path) = rocket.figment().extract_inner::<String>("binary_socket") {
+            #[cfg(unix)]
+            match unix::bind(&path) {
+                Ok(listener) => {
+                    tracing::info!("Binary protocol listener on {path}");
+                    let (db, shutdown) = (db(rocket), rocket.shutdown());
+                    tokio::spawn(async move {
+                        accept(listener, db, shutdown).await;
+                        let _ = std::fs::remove_file(&path);
+                    });
+                },
+                Err(err) => tracing::error!("Can't start binary protocol listener on {path}: {err}"),
+            }
+            #[cfg(not(unix))]
+            tracing::error!("Can't listen on {path}, Unix sockets aren't supported on this platform");
+        }
+    }))
+}
+
+/// Listener serving the protocol, TCP or a Unix socket
+trait Listener: Send + 'static {
+    type Stream: AsyncRead + AsyncWrite + Send + 'static;
+
+    fn accept(&self) -> impl std::future::Future<Output = io::Result<Self::Stream>> + Send;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    async fn accept(&self) -> io::Result<TcpStream> {
+        let (stream, _) = TcpListener::accept(self).await?;
+        // Responses are batched by the BufWriter, Nagle would only add latency
+        let _ = stream.set_nodelay(true);
+        Ok(stream)
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::io;
+    use std::os::unix::fs::FileTypeExt;
+
+    use rocket::tokio::net::{UnixListener, UnixStream};
+
+    /// Binds `path`, replacing the socket left behind by a previous run. Anything
+    /// else already there is left alone.
+    pub fn bind(path: &str) -> io::Result<UnixListener> {
+        if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            std::fs::remove_file(path)?;
+        }
+        UnixListener::bind(path)
+    }
+
+    impl super::Listener for UnixListener {
+        type Stream = UnixStream;
+
+        async fn accept(&self) -> io::Result<UnixStream> {
+            let (stream, _) = UnixListener::accept(self).await?;
+            Ok(stream)
+        }
+    }
+}
+
+fn db(rocket: &Rocket<Orbit>) -> Kopper {
+    rocket.state::<Kopper>().expect("Kopper is managed").clone()
+}
+
+async fn accept(listener: impl Listener, db: Kopper, mut shutdown: Shutdown) {
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(stream) => stream,
+                Err(err) => {
+                    tracing::error!("Binary protocol accept failed: {err}");
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+
+        let db = db.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            if let Err(err) = serve(stream, db, shutdown).await {
+                tracing::debug!("Binary protocol connection closed: {err}");
+            }
+        });
+    }
+}
+
+async fn serve(stream: impl AsyncRead + AsyncWrite, db: Kopper, mut shutdown: Shutdown) -> Result<(), ProtocolError> {
+    let (reader, writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+
+    loop {
+        let mut header = [0; protocol::HEADER_LEN];
+        tokio::select! {
+            read = reader.read_exact(&mut header) => match read {
+                Ok(_) => {},
+                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()), // Client hung up
+                Err(err) => return Err(err.into()),
+            },
+            _ = &mut shutdown => return Ok(()),
+        }
+
+        // A frame can't be skipped without a sane header - drop the connection
+        let header = Header::parse(&header)?;
+        let mut body = vec![0; header.body_len()];
+        reader.read_exact(&mut body).await?;
+
+        let response = match Frame::from_parts(header, body).and_then(Request::try_from) {
+            Ok(request) => execute(&db, request),
+            Err(err) => Response { reply: Reply::Invalid, value: err.to_string() },
+        };
+        writer.write_all(&Frame::from(response).encode()).await?;
+
+        // Keep pipelined responses together, but don't sit on them once the client waits
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+    }
+}
+
+fn execute(db: &Kopper, request: Request) -> Response {
+    let result = match request {
+        Request::Ping => return Response::ok(String::new()),
+        Request::Get { key } => db.read(&key),
+        Request::Put { key, value } => {
+            if key.is_empty() || key.contains('\0') || value.contains('\0') {
+                return Response {
+                    reply: Reply::Invalid,
+                    value: "Keys can't be empty, keys and values can't contain NUL".to_string()
+                };
+            }
+            db.write(&key, &value).map(|_| String::new())
+        },
+        Request::Delete { key } => db.delete(&key).map(|_| String::new()),
+    };
+
+    match result {
+        Ok(value) => Response::ok(value),
+        Err(KopperError::KeyDoesNotExist(_)) => Response { reply: Reply::NotFound, value: String::new() },
+        Err(err) => {
+            tracing::error!("{err}");
+            Response { reply: Reply::Error, value: "Internal error".to_string() }
+        }
+    }
+}
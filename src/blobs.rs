@@ -0,0 +1,73 @@
+//! Routes for values served as they are, with their own content type - e.g. SVGs
+//! or HTML pages - see [`Kopper::write_with_meta`]. The content type a value is
+//! written with is stored as its metadata, and read back with it.
+
+use rocket::{State, Request, Response};
+use rocket::http::{ContentType, Status};
+use rocket::response::{self, Responder};
+use rocket::serde::json::Json;
+
+use kopperdb::kopper::{Kopper, KopperError};
+use kopperdb::meta::Meta;
+
+use crate::api::WriteResponse;
+
+/// Value with the content type it was written with, plain text if none
+pub struct Blob {
+    value: String,
+    meta: Meta,
+}
+
+impl<'r> Responder<'r, 'static> for Blob {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let content_type = self.meta.content_type.as_deref().and_then(ContentType::parse_flexible).unwrap_or(ContentType::Plain);
+        let mut response = Response::build_from(self.value.respond_to(req)?);
+        response.header(content_type);
+        if self.meta.flags != 0 {
+            response.raw_header("X-Kopper-Flags", self.meta.flags.to_string());
+        }
+        response.ok()
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/blob/{key}",
+    tag = "kopper",
+    params(
+        ("key" = String, Path, description = "Key to write"),
+        ("flags" = Option<u8>, Query, description = "Flags of the key, up to the user. Returned in X-Kopper-Flags")
+    ),
+    request_body(content = String, description = "Value to store under the key, served with the Content-Type it's sent with", content_type = "*/*"),
+    responses((status = 200, description = "Result of the write", body = WriteResponse))
+)]
+#[put("/blob/<key>?<flags>", data = "<value>")]
+pub fn put_blob(key: &str, flags: Option<u8>, value: String, content_type: Option<&ContentType>, db: &State<Kopper>) -> Json<WriteResponse> {
+    let meta = Meta { content_type: content_type.map(ContentType::to_string), flags: flags.unwrap_or(0) };
+    Json(match db.write_with_meta(key, &value, &meta) {
+        Ok(_) => WriteResponse { error: "OK".to_string() },
+        Err(err) => WriteResponse { error: format!("Error while writing! : {err}") },
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/blob/{key}",
+    tag = "kopper",
+    params(("key" = String, Path, description = "Key to read")),
+    responses(
+        (status = 200, description = "Value stored under the key, with the Content-Type it was written with", content_type = "*/*"),
+        (status = 404, description = "No such key")
+    )
+)]
+#[get("/blob/<key>")]
+pub fn get_blob(key: &str, db: &State<Kopper>) -> Result<Option<Blob>, Status> {
+    match db.read_with_meta(key) {
+        Ok((value, meta)) => Ok(Some(Blob { value, meta })),
+        Err(KopperError::KeyDoesNotExist(_)) => Ok(None),
+        Err(err) => {
+            tracing::error!("Can't read {key}: {err}");
+            Err(Status::InternalServerError)
+        },
+    }
+}
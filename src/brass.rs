@@ -1,182 +1,523 @@
-use std::{fs::{File, self, OpenOptions}, sync::{Mutex, Arc}, io::{self, Read, Seek, Write}};
-
+//! B+ tree engine. Every node and leaf of the tree is a segment of exactly
+//! `segment_size` bytes in its own file, named by its id. Segment `0` is always
+//! the root - a leaf at first, a node once it had to be split.
+//!
+//! ```text
+//! 0:  [node]  ""->3  "m"->4
+//! 3:  [leaf]  "a"=1  "f"=2          (keys before "m")
+//! 4:  [leaf]  "m"=3  "x"=4          (keys from "m" on)
+//! ```
+//!
+//! Unlike Kopper, Brass updates segments in place rather than appending, so there's
+//! no log to replay and no garbage from overwrites - only deletes free space, which
+//! [`Brass::compact`] gives back by merging neighbouring segments. A segment is
+//! replaced by writing a fsynced temporary file and renaming it over the old one,
+//! and the segments of a split or merge are written in an order that keeps the tree
+//! valid after each step. A crash can leave a segment nothing points to, or entries
+//! no lookup reaches - [`Brass::create`] removes both.
+//!
+//! What it trades away: entries have to fit half a segment, so they can't be
+//! as big as Kopper's; every write rewrites a whole segment; there are no change
+//! events, replication or backups.
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::engine::{Durability, EngineScan, EngineStats, KvEngine};
 use crate::kopper::KopperError;
 
-const ROOT_NAME: &str = "0";
+const ROOT: u64 = 0;
+
+/// First byte of a segment, telling leaves from nodes
+const LEAF: u8 = 0;
+const NODE: u8 = 1;
+
+/// Widest child id a node can hold, in digits
+const MAX_CHILD_DIGITS: usize = 20;
 
+#[derive(Clone)]
 pub struct Brass {
     state: Arc<Mutex<SharedState>>,
-    _path: String,
+    path: String,
     segment_size: usize,
 }
 
 struct SharedState {
-    root_file: File,
+    /// Id of the next new segment
+    next_id: u64,
+    /// Number of segments in the tree
+    segments: usize,
+}
+
+impl SharedState {
+    fn allocate(&mut self) -> u64 {
+        self.segments += 1;
+        self.next_id += 1;
+        self.next_id - 1
+    }
 }
 
+/// Segment along the path from the root to a leaf
+type Visited = (u64, Segment);
+
 impl Brass {
+    /// Opens the database in `path`, creating it if needed. Segments left behind
+    /// by a crash are removed, see the [module docs](self).
     pub fn create(path: &str, segment_size: usize) -> Result<Self, KopperError> {
+        fs::create_dir_all(path)?;
+
+        let brass = Brass {
+            segment_size,
+            path: path.to_owned(),
+            state: Arc::new(Mutex::new(SharedState { next_id: ROOT + 1, segments: 1 }))
+        };
+
+        if !Path::new(&brass.segment_path(ROOT)).exists() {
+            // This is a new database, its root is an empty leaf
+            brass.store(ROOT, &Segment::build(LEAF, &[], segment_size).expect("Empty segment fits"))?;
+            File::open(path)?.sync_all()?;
+            return Ok(brass);
+        }
+
+        brass.recover()?;
+        Ok(brass)
+    }
 
-        // Create the DB directory if it doesn't exist
-        match fs::create_dir_all(path) { _ => () };
+    fn segment_path(&self, id: u64) -> String {
+        format!("{}/{id}", self.path)
+    }
 
-        // If file exists - return it. If doesn't - create it.
-        let mut file = 
-        OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(path.to_owned() + "/" + ROOT_NAME)?;
+    /// Walks the tree to find the segments in use, dropping entries no lookup reaches,
+    /// then removes every other segment and unfinished temporary files
+    fn recover(&self) -> Result<(), KopperError> {
+        let mut reachable = HashSet::new();
+        self.recover_segment(ROOT, "", None, &mut reachable)?;
+
+        let mut highest = ROOT;
+        for file in fs::read_dir(&self.path)? {
+            let file = file?;
+            let name = file.file_name().to_string_lossy().into_owned();
+            match name.parse::<u64>() {
+                Ok(id) if reachable.contains(&id) => highest = highest.max(id),
+                // Written for a split, or left after a merge, but not in the tree
+                Ok(id) => {
+                    tracing::debug!("Removing unused segment {id} of {}", self.path);
+                    fs::remove_file(file.path())?;
+                },
+                Err(_) if name.starts_with('.') && name.ends_with(".tmp") => fs::remove_file(file.path())?,
+                Err(_) => {},
+            }
+        }
+        File::open(&self.path)?.sync_all()?;
+
+        let mut state = self.state.lock().unwrap();
+        state.next_id = highest + 1;
+        state.segments = reachable.len();
+        Ok(())
+    }
 
-        if file.metadata().unwrap().len() == 0 {
-            // This is a new database, create a full empty segment
-            file.write(&vec![0; segment_size]).unwrap();
+    /// Recovers the segment `id`, responsible for keys from `low` up to `high`
+    fn recover_segment(&self, id: u64, low: &str, high: Option<&str>, reachable: &mut HashSet<u64>) -> Result<(), KopperError> {
+        if !reachable.insert(id) {
+            return Err(KopperError::InternalError(anyhow::anyhow!("Segment {id} of {} is in the tree twice", self.path)));
+        }
 
-            // Mark the second byte of the segment with a tombstone - end of file symbol
-            file.seek(io::SeekFrom::Start(1)).unwrap();
-            file.write(&[b'\n']).unwrap();
+        let segment = self.load(id)?;
+        let entries = segment.entries();
+        let kind = segment.kind();
+
+        // Entries past `high` are the ones a crash didn't get to remove after copying them elsewhere
+        let kept: Vec<_> = entries.iter()
+            .filter(|(key, _)| high.is_none_or(|high| key.as_str() < high))
+            .filter(|(key, _)| kind == NODE || key.as_str() >= low)
+            .cloned()
+            .collect();
+        if kept.len() != entries.len() {
+            tracing::info!("Dropping {} stale entries of segment {id} of {}", entries.len() - kept.len(), self.path);
+            self.store(id, &Segment::build(kind, &kept, self.segment_size).expect("Fewer entries fit"))?;
         }
 
-        Ok(Brass{ 
-            segment_size, 
-            _path: path.to_owned(), 
-            state: Arc::new(Mutex::new(SharedState { root_file: file }))
-        })
+        if kind == NODE {
+            for (index, (separator, child)) in kept.iter().enumerate() {
+                let child = child.parse()?;
+                let low = separator.as_str().max(low);
+                let high = kept.get(index + 1).map(|(next, _)| next.as_str()).or(high);
+                self.recover_segment(child, low, high, reachable)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn load(&self, id: u64) -> Result<Segment, KopperError> {
+        let buffer = fs::read(self.segment_path(id))?;
+        if buffer.len() != self.segment_size || ![LEAF, NODE].contains(&buffer[0]) {
+            return Err(KopperError::InternalError(anyhow::anyhow!(
+                "Segment {id} of {} is corrupted, {} bytes of {}", self.path, buffer.len(), self.segment_size
+            )));
+        }
+        Ok(Segment { buffer })
+    }
+
+    /// Replaces the segment `id` in one step - readers see either the old or the new one
+    fn store(&self, id: u64, segment: &Segment) -> Result<(), KopperError> {
+        let temporary = format!("{}/.{id}.tmp", self.path);
+        let mut file = File::create(&temporary)?;
+        file.write_all(&segment.buffer)?;
+        file.sync_data()?;
+        fs::rename(temporary, self.segment_path(id))?;
+        Ok(())
+    }
+
+    /// Segments from the root down to the leaf that holds, or would hold, `key`
+    fn path_to(&self, key: &str) -> Result<Vec<Visited>, KopperError> {
+        let mut path = vec![(ROOT, self.load(ROOT)?)];
+        loop {
+            let (_, segment) = path.last().unwrap();
+            let child = match segment.iter() {
+                SegmentIter::Leaf(_) => return Ok(path),
+                SegmentIter::Node(children) => children
+                    .take_while(|(separator, _, _)| separator.as_str() <= key)
+                    .last()
+                    .map(|(_, child, _)| child)
+                    .ok_or_else(|| KopperError::InternalError(anyhow::anyhow!("Node of {} has no children", self.path)))?,
+            };
+            path.push((child, self.load(child)?));
+        }
     }
 
     pub fn read(&self, key: &str) -> Result<String, KopperError> {
+        let _state = self.state.lock().unwrap();
+        let (_, leaf) = self.path_to(key)?.pop().unwrap();
+
+        match leaf.iter() {
+            SegmentIter::Leaf(mut iter) => iter
+                .find(|(k, _, _)| *k == key)
+                .map(|(_, value, _)| value.to_owned())
+                .ok_or_else(|| KopperError::KeyDoesNotExist(key.to_owned())),
+            SegmentIter::Node(_) => unreachable!("Paths end with a leaf"),
+        }
+    }
+
+    /// Stores `value` under `key`, splitting segments that get too full.
+    /// Returns the size of the entry.
+    pub fn write(&self, key: &str, value: &str) -> Result<usize, KopperError> {
+        // Keys are NUL terminated, and a newline where a key starts marks the end of data
+        if key.contains('\0') || value.contains('\0') || key.starts_with('\n') {
+            return Err(KopperError::InternalError(anyhow::anyhow!("Keys and values can't contain NUL, keys can't start with a newline")));
+        }
+        // Any full segment has to split in two, including nodes with the key as a separator
+        if key.len() + value.len().max(MAX_CHILD_DIGITS) + 2 > self.capacity() / 2 {
+            return Err(KopperError::InternalError(anyhow::anyhow!(
+                "Entry of {} bytes doesn't fit half a segment of {}", key.len() + value.len(), self.segment_size
+            )));
+        }
+
         let mut state = self.state.lock().unwrap();
-        let root = Segment::load(&mut state.root_file, self.segment_size);
+        let mut path = self.path_to(key)?;
+        let (id, leaf) = path.pop().unwrap();
 
-        match root.iter() {
-            SegmentIter::Leaf(iter) => {
-                for (k, value, _) in iter {
-                    if k == key {
-                        return Ok(value.to_owned());
-                    }
+        let mut entries = leaf.entries();
+        match entries.binary_search_by(|(k, _)| k.as_str().cmp(key)) {
+            Ok(index) => entries[index].1 = value.to_owned(),
+            Err(index) => entries.insert(index, (key.to_owned(), value.to_owned())),
+        }
+        self.store_entries(&mut state, path, id, LEAF, entries)?;
+        Ok(key.len() + value.len())
+    }
+
+    /// Bytes of entries a segment can hold
+    fn capacity(&self) -> usize {
+        // Kind in front, end of data after them
+        self.segment_size - 2
+    }
+
+    /// Stores `entries` in the segment `id`, splitting it in two if they don't fit.
+    /// `parents` lead from the root to it.
+    fn store_entries(&self, state: &mut SharedState, mut parents: Vec<Visited>, id: u64, kind: u8, mut entries: Vec<(String, String)>) -> Result<(), KopperError> {
+        if let Some(segment) = Segment::build(kind, &entries, self.segment_size) {
+            return self.store(id, &segment);
+        }
+
+        let split = split_point(&entries, self.capacity())
+            .ok_or_else(|| KopperError::InternalError(anyhow::anyhow!("Segment {id} of {} can't be split", self.path)))?;
+        let right = entries.split_off(split);
+        let left = entries;
+        let separator = right[0].0.clone();
+        let build = |entries: &[(String, String)]| Segment::build(kind, entries, self.segment_size).expect("Halves of a split fit");
+
+        if id == ROOT {
+            // Root stays where it is - its halves move to two new segments below it
+            let (left_id, right_id) = (state.allocate(), state.allocate());
+            self.store(left_id, &build(&left))?;
+            self.store(right_id, &build(&right))?;
+            let root = [(String::new(), left_id.to_string()), (separator, right_id.to_string())];
+            return self.store(ROOT, &Segment::build(NODE, &root, self.segment_size).expect("Two children fit"));
+        }
+
+        // Right half first, it's unreachable until the parent points to it. Then the parent,
+        // from then on lookups of the right half's keys go to it. Then the left half without them.
+        let right_id = state.allocate();
+        self.store(right_id, &build(&right))?;
+
+        let (parent_id, parent) = parents.pop().expect("Only the root has no parent");
+        let mut children = parent.entries();
+        let position = children.iter().position(|(_, child)| *child == id.to_string()).expect("Parent points to its child");
+        children.insert(position + 1, (separator, right_id.to_string()));
+        self.store_entries(state, parents, parent_id, NODE, children)?;
+
+        self.store(id, &build(&left))
+    }
+
+    /// Removes `key`, returning the number of bytes freed in its segment
+    pub fn delete(&self, key: &str) -> Result<usize, KopperError> {
+        let _state = self.state.lock().unwrap();
+        let (id, mut leaf) = self.path_to(key)?.pop().unwrap();
+
+        let freed = leaf.remove(key).ok_or_else(|| KopperError::KeyDoesNotExist(key.to_owned()))?;
+        self.store(id, &leaf)?;
+        Ok(freed)
+    }
+
+    /// Every entry in key order, as of the call
+    pub fn scan(&self) -> Result<Vec<(String, String)>, KopperError> {
+        let _state = self.state.lock().unwrap();
+        let mut entries = Vec::new();
+        self.collect(ROOT, &mut entries)?;
+        Ok(entries)
+    }
+
+    fn collect(&self, id: u64, entries: &mut Vec<(String, String)>) -> Result<(), KopperError> {
+        let segment = self.load(id)?;
+        match segment.iter() {
+            SegmentIter::Leaf(iter) => entries.extend(iter.map(|(key, value, _)| (key.to_owned(), value.to_owned()))),
+            SegmentIter::Node(children) => {
+                for (_, child, _) in children {
+                    self.collect(child, entries)?;
                 }
-                return Err(KopperError::KeyDoesNotExist(key.to_owned()))
             },
-            SegmentIter::Node(_) => {
-                todo!()
-            }
         }
+        Ok(())
     }
-    pub fn write(&self, key: &str, value: &str) -> Result<usize, KopperError> {
-        
-        // Load root into memory
+
+    /// Merges neighbouring segments whose entries fit in one, and shrinks the tree
+    /// while its root has a single child. Returns the number of segments freed.
+    pub fn compact(&self) -> Result<usize, KopperError> {
         let mut state = self.state.lock().unwrap();
-        let mut root = Segment::load(&mut state.root_file, self.segment_size);
-        
-        match root.iter() {
-            SegmentIter::Leaf(_iter) => {
-                if root.try_insert(&key, &value) {
-                    state.root_file.rewind()?;
-                    state.root_file.write(&root.buffer)?;
-                    return Ok(key.len() + value.len());
-                }
+        let mut freed = 0;
+
+        // Segments that had different parents can become neighbours once theirs merge
+        loop {
+            let mut merged = self.compact_node(&mut state, ROOT)?;
+
+            let root = self.load(ROOT)?;
+            let children = root.entries();
+            if root.kind() == NODE && children.len() == 1 {
+                // Root takes over the contents of its only child
+                let child = children[0].1.parse()?;
+                self.store(ROOT, &self.load(child)?)?;
+                fs::remove_file(self.segment_path(child))?;
+                state.segments -= 1;
+                merged += 1;
+            }
 
-                todo!("Can't insert into the leaf, implement spliting!")
-            },
-            SegmentIter::Node(_) => {
-                todo!()
+            if merged == 0 {
+                break;
             }
+            freed += merged;
         }
+
+        File::open(&self.path)?.sync_all()?;
+        Ok(freed)
+    }
+
+    /// Compacts the children of the node `id` bottom up, then merges them pairwise
+    fn compact_node(&self, state: &mut SharedState, id: u64) -> Result<usize, KopperError> {
+        let node = self.load(id)?;
+        if node.kind() != NODE {
+            return Ok(0);
+        }
+
+        let mut freed = 0;
+        let mut children = node.entries();
+        for (_, child) in &children {
+            freed += self.compact_node(state, child.parse()?)?;
+        }
+
+        let mut index = 0;
+        while index + 1 < children.len() {
+            let (left_id, right_id): (u64, u64) = (children[index].1.parse()?, children[index + 1].1.parse()?);
+            let (left, right) = (self.load(left_id)?, self.load(right_id)?);
+            let mut merged = left.entries();
+            merged.extend(right.entries());
+
+            let Some(segment) = Segment::build(left.kind(), &merged, self.segment_size) else {
+                index += 1;
+                continue;
+            };
+
+            // Left gets the right's entries first, lookups still go to the right until
+            // the parent stops pointing to it. Only then it's removed.
+            self.store(left_id, &segment)?;
+            children.remove(index + 1);
+            self.store(id, &Segment::build(NODE, &children, self.segment_size).expect("Fewer children fit"))?;
+            fs::remove_file(self.segment_path(right_id))?;
+            state.segments -= 1;
+            freed += 1;
+        }
+        Ok(freed)
+    }
+
+    /// Flushes everything written so far to disk
+    pub fn sync(&self) -> Result<(), KopperError> {
+        // Segments are fsynced as they're written, only the renames can still be in flight
+        let _state = self.state.lock().unwrap();
+        File::open(&self.path)?.sync_all()?;
+        Ok(())
+    }
+
+    /// Size of the database on disk
+    pub fn size(&self) -> usize {
+        self.state.lock().unwrap().segments * self.segment_size
     }
 }
 
-struct Segment {
-    buffer: Vec<u8>
+/// Index splitting `entries` into two halves of as close a size as possible, both
+/// within `capacity`. `None` if there's no such split.
+fn split_point(entries: &[(String, String)], capacity: usize) -> Option<usize> {
+    let sizes: Vec<usize> = entries.iter().map(|(key, value)| key.len() + value.len() + 2).collect();
+    let total: usize = sizes.iter().sum();
+
+    let mut left = 0;
+    let mut best: Option<(usize, usize)> = None;
+    for (index, size) in sizes.iter().enumerate().take(sizes.len().saturating_sub(1)) {
+        left += size;
+        let right = total - left;
+        if left <= capacity && right <= capacity && best.is_none_or(|(_, imbalance)| left.abs_diff(right) < imbalance) {
+            best = Some((index + 1, left.abs_diff(right)));
+        }
+    }
+    best.map(|(index, _)| index)
 }
 
-impl Segment {
-    fn iter(&self) -> SegmentIter {
-        SegmentIter::new(&self.buffer)
+impl KvEngine for Brass {
+    fn name(&self) -> &'static str {
+        "brass"
     }
 
-    fn buf(&self) -> &[u8] {
-        &self.buffer[1..]
+    fn read(&self, key: &str) -> Result<String, KopperError> {
+        Brass::read(self, key)
     }
 
-    fn buf_mut(&mut self) -> &mut [u8] {
-        &mut self.buffer[1..]
+    fn write(&self, key: &str, value: &str) -> Result<usize, KopperError> {
+        Brass::write(self, key, value)?;
+        Ok(self.size())
     }
 
-    fn try_insert(&mut self, key: &str, value: &str) -> bool {
+    fn write_durable(&self, key: &str, value: &str, durability: Durability) -> Result<usize, KopperError> {
+        // Checked up front - a write that can't be as durable as asked isn't made at all
+        if let Durability::Replica(_) = durability {
+            return Err(KopperError::Unsupported("replication"));
+        }
+        Brass::write(self, key, value)?;
+        self.sync()?;
+        Ok(self.size())
+    }
 
-        let mut offset;
-        let end_offset;
+    fn delete(&self, key: &str) -> Result<usize, KopperError> {
+        Brass::delete(self, key)?;
+        Ok(self.size())
+    }
 
-        match self.iter() {
-            
-            SegmentIter::Leaf(iter) => {
-
-                // Find the end of segment
-                let mut tomb_index = None;
-                for (index, byte) in self.buf().iter().enumerate() {
-                    if byte == &b'\n' {
-                        tomb_index = Some(index);
-                        break;
-                    }
-                };
-    
-                // Segment is full to the brim
-                if tomb_index.is_none() {
-                    return false;
-                }
+    fn scan(&self) -> Result<EngineScan, KopperError> {
+        Ok(Box::new(Brass::scan(self)?.into_iter().map(Ok)))
+    }
 
-                end_offset = tomb_index.unwrap();
+    fn stats(&self) -> Result<EngineStats, KopperError> {
+        Ok(EngineStats { keys: Brass::scan(self)?.len(), size: self.size() })
+    }
 
-                // Each entry is "key\0value\0", hence + 2
-                let entry_size = key.len() + value.len() + 2;
+    /// Brass has no threads of its own, only the renames are left to flush
+    fn close(&self) -> Result<(), KopperError> {
+        self.sync()
+    }
+}
 
-                // Check if key and value would fit the buffer
-                if entry_size + end_offset > self.buf().len() {
-                    return false;
-                }
+struct Segment {
+    buffer: Vec<u8>
+}
 
-                // Initialize offset to the last position in case the new key-value
-                // should be put at the end
-                offset = end_offset;
-
-                // We can fit them. Go over existing entries to figure out where
-                // - keys should be ordered alphabetically.
-                for (k, _, o) in iter {
-                    
-                    if key < k {
-                        // New key should be placed before the key 
-                        // we are currently iterating over
-                        offset = o;
-                        break;
-                    }
-                }
-            },
-            
-            SegmentIter::Node(_) => todo!(),
+impl Segment {
+    /// Segment of `kind` holding `entries`, `None` if they don't fit
+    fn build(kind: u8, entries: &[(String, String)], segment_size: usize) -> Option<Self> {
+        let mut buffer = Vec::with_capacity(segment_size);
+        buffer.push(kind);
+        for (key, value) in entries {
+            buffer.extend_from_slice(key.as_bytes());
+            buffer.push(b'\0');
+            buffer.extend_from_slice(value.as_bytes());
+            buffer.push(b'\0');
         }
+        buffer.push(b'\n');
 
-        // TODO: tmp code
-        if offset == end_offset {
-            // Key
-            self.buf_mut()[offset..(offset + key.len())].clone_from_slice(key.as_bytes());
-            // Separator
-            self.buf_mut()[offset + key.len()] = b'\0';
-            // Value
-            self.buf_mut()[(offset + key.len() + 1)..(offset + key.len() + 1 + value.len())].clone_from_slice(value.as_bytes());
-            // Separator
-            self.buf_mut()[offset + key.len() + 2 + value.len()] = b'\0';
-            // Tombstone
-            self.buf_mut()[offset + key.len() + 3 + value.len()] = b'\n';
-            return true;
+        if buffer.len() > segment_size {
+            return None;
         }
+        buffer.resize(segment_size, 0);
+        Some(Segment { buffer })
+    }
 
-        false
+    fn kind(&self) -> u8 {
+        self.buffer[0]
+    }
+
+    fn iter(&self) -> SegmentIter<'_> {
+        SegmentIter::new(&self.buffer)
+    }
+
+    /// Entries of a leaf, or separators and child ids of a node
+    fn entries(&self) -> Vec<(String, String)> {
+        LeafIterator { offset: 0, buffer: self.buf() }
+            .map(|(key, value, _)| (key.to_owned(), value.to_owned()))
+            .collect()
+    }
+
+    fn buf(&self) -> &[u8] {
+        &self.buffer[1..]
+    }
+
+    fn buf_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer[1..]
+    }
+
+    /// Removes `key` from a leaf, moving the entries after it to close the gap.
+    /// Returns the size of the removed entry, `None` if the key isn't there.
+    fn remove(&mut self, key: &str) -> Option<usize> {
+        let (offset, entry_size) = match self.iter() {
+            SegmentIter::Leaf(mut iter) => {
+                let (k, v, offset) = iter.find(|(k, _, _)| *k == key)?;
+                (offset, k.len() + v.len() + 2)
+            },
+            SegmentIter::Node(_) => return None,
+        };
+
+        // Everything up to and including the end of data moves back, the rest is zeroed
+        let buf = self.buf_mut();
+        let end = buf.iter().position(|byte| byte == &b'\n').map_or(buf.len(), |tomb| tomb + 1);
+        buf.copy_within((offset + entry_size)..end, offset);
+        buf[(end - entry_size)..end].fill(0);
+        if end == buf.len() {
+            buf[end - entry_size] = b'\n';
+        }
+        Some(entry_size)
     }
 }
 
 enum SegmentIter<'a> {
     Leaf(LeafIterator<'a>),
-    Node(NodeIterator)
+    Node(NodeIterator<'a>)
 }
 
 struct LeafIterator<'a> {
@@ -184,7 +525,17 @@ struct LeafIterator<'a> {
     buffer: &'a [u8],
 }
 
-struct NodeIterator {
+/// Iterator over the children of a node, as `(Separator, Child, Index)`.
+/// Entries are laid out like in leaves, with the child's id as the value.
+struct NodeIterator<'a>(LeafIterator<'a>);
+
+impl Iterator for NodeIterator<'_> {
+    type Item = (String, u64, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (separator, child, offset) = self.0.next()?;
+        Some((separator.to_owned(), child.parse().ok()?, offset))
+    }
 }
 
 /// Iterator over key-value pairs stored in the segment.
@@ -227,7 +578,7 @@ impl<'a> Iterator for LeafIterator<'a> {
                     Some(offset) => {
                         let value = std::str::from_utf8(&self.buffer[offset..byte_index]).unwrap();
                         let ret = Some((key, value, self.offset));
-                        self.offset += byte_index + 1;
+                        self.offset = byte_index + 1;
                         return ret;
                     }
                 }
@@ -240,30 +591,11 @@ impl<'a> Iterator for LeafIterator<'a> {
 
 impl<'a> SegmentIter<'a> {
     fn new(buf: &'a [u8]) -> Self {
-        if buf[0] == 0b0 {
-            return SegmentIter::Leaf(
-                LeafIterator { 
-                    offset: 0, 
-                    buffer: &buf[1..]
-                });
+        let entries = LeafIterator { offset: 0, buffer: &buf[1..] };
+        match buf[0] {
+            LEAF => SegmentIter::Leaf(entries),
+            _ => SegmentIter::Node(NodeIterator(entries)),
         }
-
-        SegmentIter::Node(NodeIterator {})
-    }
-}
-
-impl Segment {
-    fn load(file: &mut File, segment_size: usize) -> Self {
-
-        file.rewind().expect("Can't rewind file");
-
-        let mut buffer = vec![];
-        let bytes_read = file
-            .read_to_end(&mut buffer)
-            .expect("Can't read file");
-
-        assert_eq!(bytes_read, segment_size);
-        Segment { buffer }
     }
 }
 
@@ -307,4 +639,46 @@ fn test_leaf_iterator_no_tombstone() {
         },
         _ => { panic!() },
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_leaf_iterator_two_values() {
+
+    let segment = Segment { buffer: b"\0AB\0CD\0EF\0GH\0\n".to_vec() };
+    match segment.iter() {
+        SegmentIter::Leaf(mut iter) => {
+            assert_eq!(iter.next(), Some(("AB", "CD", 0)));
+            assert_eq!(iter.next(), Some(("EF", "GH", 6)));
+            assert_eq!(iter.next(), None);
+        },
+        _ => { panic!() },
+    }
+}
+
+#[test]
+fn test_leaf_remove() {
+
+    let mut segment = Segment { buffer: b"\0AB\0CD\0EF\0GH\0\n\0".to_vec() };
+    assert_eq!(segment.remove("XY"), None);
+    assert_eq!(segment.remove("AB"), Some(6));
+    assert_eq!(segment.buffer, b"\0EF\0GH\0\n\0\0\0\0\0\0\0".to_vec());
+
+    // Without room for the end of data, it's put back once there is
+    let mut segment = Segment { buffer: b"\0AB\0CD\0".to_vec() };
+    assert_eq!(segment.remove("AB"), Some(6));
+    assert_eq!(segment.buffer, b"\0\n\0\0\0\0\0".to_vec());
+}
+
+#[test]
+fn test_split_point() {
+    let entries = |sizes: &[usize]| -> Vec<(String, String)> {
+        sizes.iter().map(|size| ("k".repeat(size - 2), String::new())).collect()
+    };
+
+    // As even as possible
+    assert_eq!(split_point(&entries(&[10, 10, 10, 10]), 30), Some(2));
+    assert_eq!(split_point(&entries(&[30, 5, 5, 5]), 30), Some(1));
+    // Both halves have to fit
+    assert_eq!(split_point(&entries(&[20, 15, 20]), 30), None);
+    assert_eq!(split_point(&entries(&[10]), 30), None);
+}
@@ -0,0 +1,427 @@
+use std::borrow::Cow;
+
+use async_compression::tokio::bufread::GzipDecoder;
+use rocket::{State, Request, Data};
+use rocket::data::{Limits, ToByteUnit};
+use rocket::http::{ContentType, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::stream::TextStream;
+use rocket::serde::json::{Json, serde_json};
+use rocket::tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader};
+use rocket::tokio::sync::mpsc;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use kopperdb::kopper::{KopperError, Scan};
+
+use crate::api::Engine;
+
+/// Lines are sent to the client in chunks of roughly this size
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// How many chunks can wait for a slow client before the export thread blocks
+const CHUNKS_IN_FLIGHT: usize = 4;
+
+#[derive(Clone, Copy)]
+enum Format {
+    Ndjson,
+    Csv
+}
+
+impl Format {
+    fn parse(format: Option<&str>) -> Option<Self> {
+        match format {
+            None | Some("ndjson") => Some(Format::Ndjson),
+            Some("csv") => Some(Format::Csv),
+            Some(_) => None
+        }
+    }
+
+    fn content_type(self) -> ContentType {
+        match self {
+            Format::Ndjson => ContentType::new("application", "x-ndjson"),
+            Format::Csv => ContentType::CSV,
+        }
+    }
+
+    fn header(self) -> &'static str {
+        match self {
+            Format::Ndjson => "",
+            Format::Csv => "key,value\n",
+        }
+    }
+
+    fn line(self, key: &str, value: &str) -> String {
+        match self {
+            Format::Ndjson => {
+                #[derive(Serialize)]
+                struct Line<'a> { key: &'a str, value: &'a str }
+
+                // Serializing a struct of strings can't fail
+                serde_json::to_string(&Line { key, value }).unwrap() + "\n"
+            },
+            Format::Csv => format!("{},{}\n", csv_field(key), csv_field(value)),
+        }
+    }
+}
+
+/// Entries are written to the database in batches this big
+const IMPORT_BATCH_SIZE: usize = 1024;
+
+/// Only this many per-line errors are reported back, the rest are just counted
+const MAX_REPORTED_ERRORS: usize = 100;
+
+/// Parses a `key,value` CSV record, following RFC 4180 quoting.
+/// `None` if the record isn't complete yet - a quoted field continues on the next line.
+fn parse_csv_record(record: &str) -> Option<Result<(String, String), String>> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut field_start = true;
+
+    let mut chars = record.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if field_start => in_quotes = true,
+            '"' if in_quotes => {
+                if chars.peek() == Some(&'"') {
+                    field.push('"');
+                    chars.next();
+                } else {
+                    in_quotes = false;
+                }
+            },
+            ',' if !in_quotes => {
+                fields.push(std::mem::take(&mut field));
+                field_start = true;
+                continue;
+            },
+            c => field.push(c),
+        }
+        field_start = false;
+    }
+
+    if in_quotes {
+        return None;
+    }
+    fields.push(field);
+
+    Some(match <[String; 2]>::try_from(fields) {
+        Ok([key, value]) => Ok((key, value)),
+        Err(fields) => Err(format!("Expected 2 fields, found {}", fields.len())),
+    })
+}
+
+/// Quotes `field` if it contains characters special to CSV
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/export",
+    tag = "kopper",
+    params(("format" = Option<String>, Query, description = "ndjson (default) or csv")),
+    responses(
+        (status = 200, description = "All key-value pairs, one per line, as of the start of the request", content_type = "application/x-ndjson"),
+        (status = 400, description = "Unknown format")
+    )
+)]
+#[get("/export?<format>")]
+pub fn export(format: Option<&str>, db: &State<Engine>) -> Result<(ContentType, TextStream![String]), Status> {
+    let format = Format::parse(format).ok_or(Status::BadRequest)?;
+    let scan = db.scan().map_err(|err| {
+        tracing::error!("Can't start export: {err}");
+        Status::InternalServerError
+    })?;
+
+    Ok((format.content_type(), stream(scan, format)))
+}
+
+/// `scan` as NDJSON lines, in the format of [`export`]
+pub fn ndjson(scan: Scan) -> (ContentType, TextStream![String]) {
+    (Format::Ndjson.content_type(), stream(scan, Format::Ndjson))
+}
+
+fn stream(scan: impl Iterator<Item = Result<(String, String), KopperError>> + Send + 'static, format: Format) -> TextStream![String] {
+    // Reading values is blocking IO - do it on a separate thread, bounded channel
+    // keeps memory in check when the client reads slower than the disk
+    let (sender, mut receiver) = mpsc::channel(CHUNKS_IN_FLIGHT);
+    std::thread::spawn(move || {
+        let mut chunk = String::from(format.header());
+
+        for entry in scan {
+            let (key, value) = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    // Status is already sent, the best we can do is to cut the stream short
+                    tracing::error!("Export failed: {err}");
+                    return;
+                }
+            };
+
+            chunk.push_str(&format.line(&key, &value));
+            if chunk.len() >= CHUNK_SIZE && sender.blocking_send(std::mem::take(&mut chunk)).is_err() {
+                return; // Client disconnected
+            }
+        }
+
+        if !chunk.is_empty() {
+            let _ = sender.blocking_send(chunk);
+        }
+    });
+
+    TextStream! {
+        while let Some(chunk) = receiver.recv().await {
+            yield chunk;
+        }
+    }
+}
+
+/// Whether the request body is gzip'd, based on the `Content-Encoding` header
+pub struct Gzipped(bool);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Gzipped {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Gzipped(req.headers().get_one("Content-Encoding") == Some("gzip")))
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ImportResponse {
+    /// Number of entries written
+    imported: usize,
+    /// Number of lines that couldn't be imported
+    failed: usize,
+    /// Details of the first failed lines
+    errors: Vec<LineError>,
+    /// "OK" if the whole body was processed, description of the problem otherwise
+    error: String
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct LineError {
+    /// 1-based line number, first line of the record for multi-line CSV records
+    line: usize,
+    error: String
+}
+
+impl ImportResponse {
+    fn fail_line(&mut self, line: usize, error: String) {
+        self.failed += 1;
+        if self.errors.len() < MAX_REPORTED_ERRORS {
+            self.errors.push(LineError { line, error });
+        }
+    }
+}
+
+fn parse_ndjson_line(line: &str) -> Result<(String, String), String> {
+    #[derive(Deserialize)]
+    struct Line { key: String, value: String }
+
+    serde_json::from_str::<Line>(line)
+        .map(|line| (line.key, line.value))
+        .map_err(|err| err.to_string())
+}
+
+/// Key and value are stored `\0`-terminated, so they can't contain it
+fn validate(key: &str, value: &str) -> Result<(), String> {
+    if key.is_empty() {
+        return Err("Key can't be empty".to_string());
+    }
+    if key.contains('\0') || value.contains('\0') {
+        return Err("Key and value can't contain NUL characters".to_string());
+    }
+    Ok(())
+}
+
+async fn write_batch(db: &Engine, batch: &mut Vec<(String, String)>) -> Result<usize, KopperError> {
+    let db = db.clone();
+    let entries = std::mem::take(batch);
+    let count = entries.len();
+
+    rocket::tokio::task::spawn_blocking(move || {
+        let entries: Vec<(&str, &str)> = entries.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        db.write_batch(&entries)
+    })
+    .await
+    .map_err(|err| KopperError::InternalError(err.into()))??;
+
+    Ok(count)
+}
+
+#[utoipa::path(
+    post,
+    path = "/import",
+    tag = "kopper",
+    params(("format" = Option<String>, Query, description = "ndjson (default) or csv, in the same shape as produced by /export")),
+    request_body(content = String, description = "Entries, one per line. Send `Content-Encoding: gzip` for compressed bodies", content_type = "application/x-ndjson"),
+    responses(
+        (status = 200, description = "Import summary", body = ImportResponse),
+        (status = 400, description = "Unknown format")
+    )
+)]
+#[post("/import?<format>", data = "<body>")]
+pub async fn import(format: Option<&str>, body: Data<'_>, gzipped: Gzipped, limits: &Limits, db: &State<Engine>) -> Result<Json<ImportResponse>, Status> {
+    let format = Format::parse(format).ok_or(Status::BadRequest)?;
+
+    let stream = BufReader::new(body.open(limits.get("import").unwrap_or(1.gibibytes())));
+    let reader: Box<dyn AsyncBufRead + Unpin + Send> = match gzipped {
+        Gzipped(true) => Box::new(BufReader::new(GzipDecoder::new(stream))),
+        Gzipped(false) => Box::new(stream),
+    };
+
+    let mut response = ImportResponse { imported: 0, failed: 0, errors: Vec::new(), error: "OK".to_string() };
+    let mut batch = Vec::with_capacity(IMPORT_BATCH_SIZE);
+
+    // CSV records can span lines - keep the unfinished part and where it started
+    let mut record = String::new();
+    let mut record_line = 0;
+
+    let mut lines = reader.lines();
+    let mut line_number = 0;
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(err) => {
+                response.error = format!("Can't read body: {err}");
+                break;
+            }
+        };
+        line_number += 1;
+
+        let parsed = match format {
+            _ if record.is_empty() && line.trim().is_empty() => continue,
+            Format::Ndjson => parse_ndjson_line(&line),
+            Format::Csv => {
+                if record.is_empty() {
+                    record_line = line_number;
+                    record = line;
+                } else {
+                    record.push('\n');
+                    record.push_str(&line);
+                }
+
+                match parse_csv_record(&record) {
+                    None => continue,
+                    Some(_) if record_line == 1 && record == "key,value" => {
+                        record.clear();
+                        continue;
+                    },
+                    Some(parsed) => {
+                        record.clear();
+                        parsed
+                    },
+                }
+            },
+        };
+
+        let line = if let Format::Csv = format { record_line } else { line_number };
+        match parsed.and_then(|(key, value)| validate(&key, &value).map(|_| (key, value))) {
+            Ok(entry) => batch.push(entry),
+            Err(err) => response.fail_line(line, err),
+        }
+
+        if batch.len() >= IMPORT_BATCH_SIZE {
+            match write_batch(db, &mut batch).await {
+                Ok(count) => response.imported += count,
+                Err(err) => {
+                    response.error = format!("Error while writing! : {err}");
+                    return Ok(Json(response));
+                }
+            }
+        }
+    }
+
+    if !record.is_empty() {
+        response.fail_line(record_line, "Unterminated quoted field".to_string());
+    }
+
+    match write_batch(db, &mut batch).await {
+        Ok(count) => response.imported += count,
+        Err(err) => response.error = format!("Error while writing! : {err}"),
+    }
+
+    Ok(Json(response))
+}
+
+/// TESTS
+
+#[test]
+fn test_export_streams_every_entry() {
+    use rocket::local::blocking::Client;
+
+    let db = kopperdb::testing::TempDb::new();
+    let (kopper, client) = client(&db);
+    let value = "v".repeat(100);
+    for i in 0..2000 {
+        kopper.write(&format!("key{i:04}"), &value).unwrap();
+    }
+    kopper.write("quoted", "a,\"b\"").unwrap();
+
+    // Several chunks long, sent as they're read
+    let response = client.get("/export").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(Format::Ndjson.content_type()));
+    assert_eq!(response.headers().get_one("Content-Length"), None);
+    let body = response.into_string().unwrap();
+    assert!(body.len() > 2 * CHUNK_SIZE);
+    let lines: Vec<_> = body.lines().collect();
+    assert_eq!(lines.len(), 2001);
+    assert_eq!(lines[0], format!(r#"{{"key":"key0000","value":"{value}"}}"#));
+    assert_eq!(lines[2000], r#"{"key":"quoted","value":"a,\"b\""}"#);
+
+    let csv = client.get("/export?format=csv").dispatch().into_string().unwrap();
+    assert!(csv.starts_with("key,value\nkey0000,v"));
+    assert!(csv.ends_with("quoted,\"a,\"\"b\"\"\"\n"));
+    assert_eq!(client.get("/export?format=xml").dispatch().status(), Status::BadRequest);
+
+    fn client(db: &kopperdb::testing::TempDb) -> (kopperdb::kopper::Kopper, Client) {
+        let kopper = db.kopper(4096).unwrap();
+        let engine: Engine = std::sync::Arc::new(kopper.clone());
+        (kopper, Client::tracked(rocket::build().manage(engine).mount("/", routes![export])).unwrap())
+    }
+}
+
+#[test]
+fn test_import_reports_counts_and_failed_lines() {
+    use rocket::local::blocking::Client;
+
+    let db = kopperdb::testing::TempDb::new();
+    let kopper = db.kopper(4096).unwrap();
+    let engine: Engine = std::sync::Arc::new(kopper.clone());
+    let client = Client::tracked(rocket::build().manage(engine).mount("/", routes![import])).unwrap();
+    let import = |format: &str, body: &str| -> serde_json::Value {
+        let response = client.post(format!("/import?format={format}")).body(body).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        response.into_json().unwrap()
+    };
+
+    let body = "{\"key\":\"a\",\"value\":\"1\"}\n\nnot json\n{\"key\":\"\",\"value\":\"2\"}\n{\"key\":\"b\",\"value\":\"2\"}\n";
+    let response = import("ndjson", body);
+    assert_eq!(response["imported"], 2);
+    assert_eq!(response["failed"], 2);
+    assert_eq!(response["errors"][0]["line"], 3);
+    assert_eq!(response["errors"][1]["line"], 4);
+    assert_eq!(response["errors"][1]["error"], "Key can't be empty");
+    assert_eq!(response["error"], "OK");
+    assert_eq!(kopper.read("b").unwrap(), "2");
+
+    // A quoted field goes on over lines, errors are reported at the record's first line
+    let response = import("csv", "key,value\nc,\"multi\nline\"\nd,1,extra\ne,\"unterminated\n");
+    assert_eq!(response["imported"], 1);
+    assert_eq!(response["failed"], 2);
+    assert_eq!(response["errors"][0]["line"], 4);
+    assert_eq!(response["errors"][1]["line"], 5);
+    assert_eq!(kopper.read("c").unwrap(), "multi\nline");
+
+    assert_eq!(client.post("/import?format=xml").body("").dispatch().status(), Status::BadRequest);
+}
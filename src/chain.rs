@@ -0,0 +1,178 @@
+//! Values changed a part at a time - [`set`](crate::set)s, [`hash`](crate::hash)es,
+//! [`counter`](crate::counter)s and [`list`](crate::list)s.
+//! Such a value is stored as a record of all of it, and every change after it
+//! as a record of only the part that changed, pointing back at the record before
+//! it. Changes only build on records in the same segment - the first one in a
+//! newer segment writes the whole value again, and so does the compactor - so
+//! reading a value never leaves the segment of its newest record.
+//!
+//! Layout: a flag byte telling the type, `F` for the whole value or `D` for a
+//! change, and for a change where the record before it starts, how long it is
+//! and how many changes lead up to it. Then the parts of the value, in a layout
+//! of its type. Numbers are in hex so a record never holds a NUL, and like the
+//! other flags these never start valid UTF-8.
+
+use crate::kopper::KopperError;
+
+const FULL: u8 = b'F';
+const DELTA: u8 = b'D';
+
+const OFFSET_LEN: usize = 16;
+const LEN_LEN: usize = 8;
+const DEPTH_LEN: usize = 4;
+const FULL_HEADER_LEN: usize = 2;
+const DELTA_HEADER_LEN: usize = FULL_HEADER_LEN + OFFSET_LEN + LEN_LEN + DEPTH_LEN;
+
+/// Changes written after a whole value before it's written whole again, so
+/// reading one takes no more than as many reads
+pub const MAX_DELTAS: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Set,
+    Hash,
+    Counter,
+    List,
+}
+
+impl Kind {
+    fn flag(self) -> u8 {
+        match self {
+            Kind::Set => 0xF9,
+            Kind::Hash => 0xF8,
+            Kind::Counter => 0xF7,
+            Kind::List => 0xF5,
+        }
+    }
+}
+
+/// Type of the value starting with `byte`, `None` if it isn't changed a part at a time
+pub fn kind(byte: u8) -> Option<Kind> {
+    [Kind::Set, Kind::Hash, Kind::Counter, Kind::List].into_iter().find(|kind| kind.flag() == byte)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Header {
+    Full,
+    /// Change to the record at `offset` of the same segment, `len` long, which
+    /// is `depth` changes after the whole value
+    Delta { offset: u64, len: usize, depth: usize },
+}
+
+impl Header {
+    /// Changes since the whole value, counting this one
+    pub fn depth(self) -> usize {
+        match self {
+            Header::Full => 0,
+            Header::Delta { depth, .. } => depth + 1,
+        }
+    }
+}
+
+/// Header of a record of a whole value, its parts go after it
+pub fn full(kind: Kind) -> Vec<u8> {
+    vec![kind.flag(), FULL]
+}
+
+/// Header of a record changing the value recorded at `offset` of the same
+/// segment - `len` long, with a header `previous`. The parts changed go after it.
+pub fn delta(kind: Kind, offset: u64, len: usize, previous: Header) -> Vec<u8> {
+    let mut record = vec![kind.flag(), DELTA];
+    record.extend_from_slice(format!("{offset:016x}{len:08x}{:04x}", previous.depth()).as_bytes());
+    record
+}
+
+/// Type and header of `record`, `None` if it isn't a value changed a part at a time
+pub fn header(record: &[u8]) -> Option<(Kind, Header)> {
+    let hex = |at: usize, len: usize| record.get(at..at + len)
+        .and_then(|hex| std::str::from_utf8(hex).ok())
+        .and_then(|hex| u64::from_str_radix(hex, 16).ok());
+    let header = match *record.get(1)? {
+        FULL => Header::Full,
+        DELTA => Header::Delta {
+            offset: hex(FULL_HEADER_LEN, OFFSET_LEN)?,
+            len: hex(FULL_HEADER_LEN + OFFSET_LEN, LEN_LEN)? as usize,
+            depth: hex(FULL_HEADER_LEN + OFFSET_LEN + LEN_LEN, DEPTH_LEN)? as usize,
+        },
+        _ => return None,
+    };
+    Some((kind(record[0])?, header))
+}
+
+pub fn damaged() -> KopperError {
+    KopperError::InternalError(anyhow::anyhow!("Record of a value changed a part at a time is damaged"))
+}
+
+/// Records of the value of `kind` whose newest record is `record`, oldest - the
+/// whole value - first. The ones before it are read with `read`, from the offset
+/// and as long as given.
+pub fn records(kind: Kind, record: Vec<u8>, mut read: impl FnMut(u64, usize) -> Result<Vec<u8>, KopperError>) -> Result<Vec<Vec<u8>>, KopperError> {
+    let mut records = vec![record];
+    loop {
+        match header(records.last().unwrap()) {
+            Some((found, _)) if found != kind => return Err(damaged()),
+            Some((_, Header::Full)) => break,
+            Some((_, Header::Delta { .. })) if records.len() > MAX_DELTAS => return Err(damaged()),
+            Some((_, Header::Delta { offset, len, .. })) => records.push(read(offset, len)?),
+            None => return Err(damaged()),
+        }
+    }
+    records.reverse();
+    Ok(records)
+}
+
+/// Parts of a record read by [`records`], after its header
+pub fn body(record: &[u8]) -> &[u8] {
+    match header(record) {
+        Some((_, Header::Delta { .. })) => &record[DELTA_HEADER_LEN..],
+        _ => &record[FULL_HEADER_LEN.min(record.len())..],
+    }
+}
+
+/// Appends `part` to a record, behind its length
+pub fn push(record: &mut Vec<u8>, part: &str) {
+    record.extend_from_slice(format!("{:08x}", part.len()).as_bytes());
+    record.extend_from_slice(part.as_bytes());
+}
+
+/// Takes a part [`push`]ed to a record off the start of `parts`
+pub fn take<'a>(parts: &mut &'a [u8]) -> Result<&'a str, KopperError> {
+    let len = parts.get(..LEN_LEN)
+        .and_then(|len| std::str::from_utf8(len).ok())
+        .and_then(|len| usize::from_str_radix(len, 16).ok())
+        .ok_or_else(damaged)?;
+    let part = parts.get(LEN_LEN..LEN_LEN + len)
+        .and_then(|part| std::str::from_utf8(part).ok())
+        .ok_or_else(damaged)?;
+    *parts = &parts[LEN_LEN + len..];
+    Ok(part)
+}
+
+/// TESTS
+
+#[test]
+fn test_changes_lead_back_to_the_whole_value() {
+    let mut whole = full(Kind::Hash);
+    push(&mut whole, "part");
+    let change = delta(Kind::Hash, 0, whole.len(), Header::Full);
+    let previous = header(&change).unwrap();
+    assert_eq!(previous, (Kind::Hash, Header::Delta { offset: 0, len: whole.len(), depth: 0 }));
+    let newest = delta(Kind::Hash, 1, change.len(), previous.1);
+    assert_eq!(header(&newest).unwrap().1.depth(), 2);
+    assert!([&whole, &change, &newest].iter().all(|record| !record.contains(&0)));
+
+    // Offsets stand in for where the records are
+    let segment = [whole.clone(), change.clone()];
+    let read = |offset: u64, len: usize| {
+        assert_eq!(segment[offset as usize].len(), len);
+        Ok(segment[offset as usize].clone())
+    };
+    let chain = records(Kind::Hash, newest.clone(), read).unwrap();
+    assert_eq!(chain, [whole, change, newest]);
+    let mut parts = body(&chain[0]);
+    assert_eq!(take(&mut parts).unwrap(), "part");
+    assert!(parts.is_empty());
+
+    assert_eq!(header(b"plain"), None);
+    assert!(records(Kind::Set, chain[2].clone(), read).is_err());
+}
@@ -0,0 +1,69 @@
+//! Routes for channels, see [`Kopper::publish`]. Messages go to the clients
+//! listening at the time, over SSE here or over the WebSocket, and aren't stored.
+
+use rocket::{State, Shutdown};
+use rocket::response::stream::{Event, EventStream};
+use rocket::serde::json::Json;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use kopperdb::kopper::Kopper;
+
+#[derive(Serialize, ToSchema)]
+pub struct PublishResponse {
+    /// Listeners the message went to
+    receivers: usize,
+    /// "OK" on success, description of the problem otherwise
+    error: String
+}
+
+#[utoipa::path(
+    post,
+    path = "/channel/{channel}/publish",
+    tag = "channels",
+    params(("channel" = String, Path, description = "Channel to publish to")),
+    request_body(content = String, description = "Message to send", content_type = "text/plain"),
+    responses((status = 200, description = "Result of the publish", body = PublishResponse))
+)]
+#[post("/channel/<channel>/publish", data = "<message>")]
+pub fn publish(channel: &str, message: String, db: &State<Kopper>) -> Json<PublishResponse> {
+    Json(match db.publish(channel, &message) {
+        Ok(receivers) => PublishResponse { receivers, error: "OK".to_string() },
+        Err(err) => PublishResponse { receivers: 0, error: format!("Error while publishing! : {err}") },
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/channel/{channel}",
+    tag = "channels",
+    params(("channel" = String, Path, description = "Channel to listen to")),
+    responses((status = 200, description = "Stream of `message` events, carrying the messages published from now on", content_type = "text/event-stream"))
+)]
+#[get("/channel/<channel>")]
+pub fn listen(channel: &str, db: &State<Kopper>, mut shutdown: Shutdown) -> EventStream![] {
+    let messages = db.subscribe(channel);
+
+    // Same bridge as in the watch endpoint
+    let (sender, mut receiver) = rocket::tokio::sync::mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        while let Ok(message) = messages.recv() {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    EventStream! {
+        loop {
+            let message = rocket::tokio::select! {
+                message = receiver.recv() => match message {
+                    Some(message) => message,
+                    None => break,
+                },
+                _ = &mut shutdown => break,
+            };
+            yield Event::data(message).event("message");
+        }
+    }
+}
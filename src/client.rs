@@ -0,0 +1,551 @@
+//! Typed clients for the HTTP API of a running server - [`KopperClient`] for
+//! blocking code, [`AsyncKopperClient`] for async code.
+//!
+//! ```no_run
+//! use kopperdb::client::KopperClient;
+//!
+//! let client = KopperClient::connect("http://localhost:8000")?;
+//! client.put("user:1", "Alice")?;
+//! assert_eq!(client.get("user:1")?, Some("Alice".to_string()));
+//! # Ok::<(), kopperdb::client::ClientError>(())
+//! ```
+
+use std::{future::Future, io::{BufRead, BufReader, Lines}, path::Path, sync::Arc, time::{Duration, Instant}};
+
+use reqwest::{blocking::{Client, Response}, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+
+use crate::doctor::Report;
+use crate::kopper::ChangeEvent;
+use crate::metrics::{MetricsSink, NoopSink};
+use crate::stats::{Operation, Stat};
+
+/// Version of the HTTP API the client speaks
+const API_VERSION: u32 = 1;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    #[error("Invalid server URL: {0}")]
+    Url(String),
+
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    /// Server processed the request but couldn't carry it out
+    #[error("Server error: {0}")]
+    Server(String),
+}
+
+#[derive(Deserialize)]
+struct ReadResponse {
+    value: String,
+    error: String,
+}
+
+#[derive(Deserialize)]
+struct WriteResponse {
+    error: String,
+}
+
+#[derive(Deserialize)]
+struct ImportResponse {
+    imported: usize,
+    errors: Vec<LineError>,
+    error: String,
+}
+
+#[derive(Deserialize)]
+struct LineError {
+    line: usize,
+    error: String,
+}
+
+/// Percentiles of one of the server's metrics, see [`KopperClient::percentiles`]
+#[derive(Debug, Deserialize)]
+pub struct SeriesStats {
+    /// Unit of the percentiles: us for latencies, KB for size, ops/s for throughput, % for errors
+    pub unit: String,
+    /// Number of samples recorded since the server started
+    pub count: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub p999: u64,
+}
+
+#[derive(Deserialize)]
+struct BackupResponse {
+    id: String,
+    error: String,
+}
+
+#[derive(Deserialize)]
+struct CompactResponse {
+    queued: usize,
+    error: String,
+}
+
+#[derive(Deserialize)]
+struct RollResponse {
+    segment: String,
+    error: String,
+}
+
+#[derive(Deserialize)]
+struct ExportLine {
+    key: String,
+    value: String,
+}
+
+/// Changes made on a server after some sequence, see [`KopperClient::changes`]
+#[derive(Debug, Deserialize)]
+pub struct Changes {
+    /// Change log the sequences belong to
+    pub log: u64,
+    /// Sequence of the server's newest change
+    pub sequence: u64,
+    pub changes: Vec<Change>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Change {
+    pub sequence: u64,
+    pub key: String,
+    /// Missing for deletes
+    pub value: Option<String>,
+}
+
+impl From<Change> for ChangeEvent {
+    fn from(change: Change) -> Self {
+        match change.value {
+            Some(value) => ChangeEvent::Write { key: change.key, value },
+            None => ChangeEvent::Delete { key: change.key },
+        }
+    }
+}
+
+/// Consistent copy of a server's entries, streamed in key order, with the
+/// position in its change log the copy was taken at. See [`KopperClient::snapshot`].
+pub struct Snapshot {
+    pub log: u64,
+    pub sequence: u64,
+    lines: Lines<BufReader<Response>>,
+}
+
+impl Iterator for Snapshot {
+    type Item = Result<(String, String), ClientError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let line = match self.lines.next()? {
+            Ok(line) => line,
+            Err(err) => return Some(Err(ClientError::Server(format!("Snapshot cut short: {err}")))),
+        };
+        Some(serde_json::from_str::<ExportLine>(&line)
+            .map(|line| (line.key, line.value))
+            .map_err(|err| ClientError::Server(format!("Malformed snapshot line: {err}"))))
+    }
+}
+
+/// Reads a numeric header of a snapshot response
+fn position_header(response: &Response, name: &str) -> Result<u64, ClientError> {
+    response.headers().get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| ClientError::Server(format!("Snapshot without a {name} header")))
+}
+
+/// Server reports missing keys in the `error` field, as "<key> does not exist!"
+fn is_missing(error: &str) -> bool {
+    error.ends_with(" does not exist!")
+}
+
+fn check(error: String) -> Result<(), ClientError> {
+    match error.as_str() {
+        "OK" => Ok(()),
+        _ => Err(ClientError::Server(error)),
+    }
+}
+
+impl ReadResponse {
+    fn into_value(self) -> Result<Option<String>, ClientError> {
+        match self.error {
+            error if is_missing(&error) => Ok(None),
+            error => check(error).map(|_| Some(self.value)),
+        }
+    }
+}
+
+impl WriteResponse {
+    /// Whether the key existed, for deletes
+    fn into_deleted(self) -> Result<bool, ClientError> {
+        match self.error {
+            error if is_missing(&error) => Ok(false),
+            error => check(error).map(|_| true),
+        }
+    }
+}
+
+impl ImportResponse {
+    /// Fails if any entry was rejected, though the valid ones are still written
+    fn into_imported(self) -> Result<usize, ClientError> {
+        check(self.error)?;
+        if let Some(LineError { line, error }) = self.errors.into_iter().next() {
+            return Err(ClientError::Server(format!("Entry {line}: {error}")));
+        }
+        Ok(self.imported)
+    }
+}
+
+fn parse_base(url: &str) -> Result<Url, ClientError> {
+    let base = Url::parse(url).map_err(|err| ClientError::Url(err.to_string()))?;
+    if base.cannot_be_a_base() {
+        return Err(ClientError::Url(format!("{url} can't be a base URL")));
+    }
+    Ok(base)
+}
+
+/// URL of `segments` under the API version's base path. Segments are
+/// percent-encoded, so keys may contain `/` or `?`.
+fn api_url(base: &Url, segments: &[&str]) -> Url {
+    let mut url = base.clone();
+    url.path_segments_mut()
+        .unwrap() // Checked in parse_base
+        .pop_if_empty()
+        .push(&format!("v{API_VERSION}"))
+        .extend(segments);
+    url
+}
+
+/// Body of a batch write, in the NDJSON format of `/import`
+fn batch_body(entries: &[(&str, &str)]) -> String {
+    #[derive(Serialize)]
+    struct Line<'a> { key: &'a str, value: &'a str }
+
+    let mut body = String::new();
+    for (key, value) in entries {
+        // Serializing a struct of strings can't fail
+        body += &serde_json::to_string(&Line { key, value }).unwrap();
+        body.push('\n');
+    }
+    body
+}
+
+/// Blocking client of a single server. Cheap to clone, clones share connections.
+#[derive(Clone)]
+pub struct KopperClient {
+    http: Client,
+    base: Url,
+}
+
+impl KopperClient {
+    /// Connects to the server at `url`, e.g. `http://localhost:8000`, checking
+    /// that it's reachable.
+    pub fn connect(url: &str) -> Result<Self, ClientError> {
+        let client = KopperClient { http: Client::new(), base: parse_base(url)? };
+        client.http.get(client.url(&["db"])).send()?.error_for_status()?;
+        Ok(client)
+    }
+
+    fn url(&self, segments: &[&str]) -> Url {
+        api_url(&self.base, segments)
+    }
+
+    /// Value of `key`, `None` if there's no such key
+    pub fn get(&self, key: &str) -> Result<Option<String>, ClientError> {
+        let response: ReadResponse = self.http.get(self.url(&["read", key])).send()?.error_for_status()?.json()?;
+        response.into_value()
+    }
+
+    pub fn put(&self, key: &str, value: &str) -> Result<(), ClientError> {
+        let response: WriteResponse = self.http.get(self.url(&["write", key, value])).send()?.error_for_status()?.json()?;
+        check(response.error)
+    }
+
+    /// Removes `key`, returning whether it existed
+    pub fn delete(&self, key: &str) -> Result<bool, ClientError> {
+        let response: WriteResponse = self.http.delete(self.url(&["delete", key])).send()?.error_for_status()?.json()?;
+        response.into_deleted()
+    }
+
+    /// Moves the value of `from` to `to`, replacing whatever `to` held. Returns
+    /// whether `from` existed.
+    pub fn rename(&self, from: &str, to: &str) -> Result<bool, ClientError> {
+        let response: WriteResponse = self.http.post(self.url(&["rename", from, to])).send()?.error_for_status()?.json()?;
+        response.into_deleted()
+    }
+
+    /// Copies the value of `from` to `to`, replacing whatever `to` held. Returns
+    /// whether `from` existed.
+    pub fn copy(&self, from: &str, to: &str) -> Result<bool, ClientError> {
+        let response: WriteResponse = self.http.post(self.url(&["copy", from, to])).send()?.error_for_status()?.json()?;
+        response.into_deleted()
+    }
+
+    /// Writes all `entries` in a single request, returning how many were written.
+    /// Fails if any entry was rejected, though the valid ones are still written.
+    pub fn batch(&self, entries: &[(&str, &str)]) -> Result<usize, ClientError> {
+        let response: ImportResponse = self.http.post(self.url(&["import"]))
+            .header("Content-Type", "application/x-ndjson")
+            .body(batch_body(entries))
+            .send()?
+            .error_for_status()?
+            .json()?;
+        response.into_imported()
+    }
+
+    /// All entries with keys starting with `prefix`, in key order
+    pub fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>, ClientError> {
+        let export = self.http.get(self.url(&["export"])).send()?.error_for_status()?.text()?;
+
+        let mut entries = Vec::new();
+        for line in export.lines() {
+            let line: ExportLine = serde_json::from_str(line)
+                .map_err(|err| ClientError::Server(format!("Malformed export line: {err}")))?;
+            if line.key.starts_with(prefix) {
+                entries.push((line.key, line.value));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Percentiles of `metric` - read, write, size, throughput, errors, compaction or keys
+    pub fn percentiles(&self, metric: &str) -> Result<SeriesStats, ClientError> {
+        Ok(self.http.get(self.url(&["stats", metric, "percentiles"])).send()?.error_for_status()?.json()?)
+    }
+
+    /// Queues compaction of every sealed segment, returning how many were queued
+    pub fn compact(&self) -> Result<usize, ClientError> {
+        let response: CompactResponse = self.http.post(self.url(&["admin", "compact"])).send()?.error_for_status()?.json()?;
+        check(response.error).map(|_| response.queued)
+    }
+
+    /// Seals the active segment on the server, returning the new one, see
+    /// [`Kopper::roll_segment`](crate::kopper::Kopper::roll_segment)
+    pub fn roll_segment(&self) -> Result<String, ClientError> {
+        let response: RollResponse = self.http.post(self.url(&["admin", "roll"])).send()?.error_for_status()?.json()?;
+        check(response.error).map(|_| response.segment)
+    }
+
+    /// Runs the checks of [`Kopper::doctor`](crate::kopper::Kopper::doctor) on the server's database
+    pub fn doctor(&self) -> Result<Report, ClientError> {
+        Ok(self.http.get(self.url(&["admin", "doctor"])).send()?.error_for_status()?.json()?)
+    }
+
+    /// Backs the database up on the server, returning the backup's ID. The backup
+    /// can be downloaded from [`KopperClient::backup_url`].
+    pub fn backup(&self) -> Result<String, ClientError> {
+        let response: BackupResponse = self.http.post(self.url(&["admin", "backup"])).send()?.error_for_status()?.json()?;
+        check(response.error).map(|_| response.id)
+    }
+
+    /// Where the tarball of backup `id` can be downloaded from
+    pub fn backup_url(&self, id: &str) -> Url {
+        self.url(&["admin", "backup", id])
+    }
+
+    /// Up to `limit` changes made after `since` in change log `log`. `None` if
+    /// they aren't available anymore, or `log` isn't the server's log now - take
+    /// a [`KopperClient::snapshot`] and continue from there.
+    pub fn changes(&self, log: u64, since: u64, limit: usize) -> Result<Option<Changes>, ClientError> {
+        let response = self.http.get(self.url(&["replication", "changes"]))
+            .query(&[("log", log), ("since", since), ("limit", limit as u64)])
+            .send()?;
+
+        if response.status() == StatusCode::GONE {
+            return Ok(None);
+        }
+        Ok(Some(response.error_for_status()?.json()?))
+    }
+
+    /// Starts streaming a snapshot of every entry on the server
+    pub fn snapshot(&self) -> Result<Snapshot, ClientError> {
+        let response = self.http.get(self.url(&["replication", "snapshot"])).send()?.error_for_status()?;
+        let log = position_header(&response, "Kopper-Log")?;
+        let sequence = position_header(&response, "Kopper-Sequence")?;
+        Ok(Snapshot { log, sequence, lines: BufReader::new(response).lines() })
+    }
+
+    /// Downloads the segment files of a snapshot of the server into `dir`, which
+    /// can then be opened or installed as a database. Cheaper than
+    /// [`KopperClient::snapshot`] for big databases - nothing is parsed on
+    /// either side. Returns the change log and sequence the snapshot was taken at.
+    pub fn segments(&self, dir: &Path) -> Result<(u64, u64), ClientError> {
+        let response = self.http.get(self.url(&["replication", "segments"])).send()?.error_for_status()?;
+        let log = position_header(&response, "Kopper-Log")?;
+        let sequence = position_header(&response, "Kopper-Sequence")?;
+
+        // Files sit in a directory named after the snapshot - flatten it, and
+        // never let a name point outside of `dir`
+        for entry in tar::Archive::new(response).entries()? {
+            let mut entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let path = entry.path()?.into_owned();
+            if let Some(name) = path.file_name() {
+                entry.unpack(dir.join(name))?;
+            }
+        }
+        Ok((log, sequence))
+    }
+}
+
+/// Reads are retried this many times by default
+const DEFAULT_RETRIES: u32 = 3;
+
+/// Wait before the first retry, doubled for every next one
+const DEFAULT_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Idle connections kept open per server
+const POOL_SIZE: usize = 32;
+
+/// Async client of a single server, for use inside tokio services. Cheap to clone,
+/// clones share the connection pool.
+///
+/// Reads are retried with exponential backoff when the server can't be reached
+/// or fails with a 5xx - writes aren't, as they may have been applied. Every
+/// operation's latency goes to the [`MetricsSink`] set with [`AsyncKopperClient::with_metrics`],
+/// as [`Stat::ReadTime`]/[`Stat::WriteTime`] plus a [`Stat::Completed`].
+#[derive(Clone)]
+pub struct AsyncKopperClient {
+    http: reqwest::Client,
+    base: Url,
+    retries: u32,
+    backoff: Duration,
+    metrics: Arc<dyn MetricsSink>,
+}
+
+impl AsyncKopperClient {
+    /// Connects to the server at `url`, e.g. `http://localhost:8000`, checking
+    /// that it's reachable.
+    pub async fn connect(url: &str) -> Result<Self, ClientError> {
+        let http = reqwest::Client::builder()
+            .pool_max_idle_per_host(POOL_SIZE)
+            .build()?;
+
+        let client = AsyncKopperClient {
+            http,
+            base: parse_base(url)?,
+            retries: DEFAULT_RETRIES,
+            backoff: DEFAULT_BACKOFF,
+            metrics: Arc::new(NoopSink),
+        };
+        client.http.get(client.url(&["db"])).send().await?.error_for_status()?;
+        Ok(client)
+    }
+
+    /// Retries failed reads up to `retries` times, waiting `backoff` before the first retry
+    pub fn with_retries(self, retries: u32, backoff: Duration) -> Self {
+        AsyncKopperClient { retries, backoff, ..self }
+    }
+
+    pub fn with_metrics(self, metrics: Arc<dyn MetricsSink>) -> Self {
+        AsyncKopperClient { metrics, ..self }
+    }
+
+    fn url(&self, segments: &[&str]) -> Url {
+        api_url(&self.base, segments)
+    }
+
+    /// Times `operation`, then reports it as `op`
+    async fn measured<T>(&self, op: Operation, operation: impl Future<Output = Result<T, ClientError>>) -> Result<T, ClientError> {
+        let timer = Instant::now();
+        let result = operation.await;
+
+        let time = timer.elapsed().as_nanos();
+        self.metrics.record(match op {
+            Operation::Read => Stat::ReadTime(time),
+            Operation::Write => Stat::WriteTime(time),
+        });
+        self.metrics.record(Stat::Completed(op, result.is_err()));
+        result
+    }
+
+    /// Value of `key`, `None` if there's no such key
+    pub async fn get(&self, key: &str) -> Result<Option<String>, ClientError> {
+        self.measured(Operation::Read, async {
+            let mut backoff = self.backoff;
+            let mut attempt = 0;
+            loop {
+                let result = async {
+                    let response = self.http.get(self.url(&["read", key])).send().await?.error_for_status()?;
+                    response.json::<ReadResponse>().await
+                }.await;
+
+                match result {
+                    Err(err) if attempt < self.retries && retryable(&err) => {
+                        tokio::time::sleep(backoff).await;
+                        backoff *= 2;
+                        attempt += 1;
+                    },
+                    result => return result?.into_value(),
+                }
+            }
+        }).await
+    }
+
+    pub async fn put(&self, key: &str, value: &str) -> Result<(), ClientError> {
+        self.measured(Operation::Write, async {
+            let response: WriteResponse = self.http.get(self.url(&["write", key, value])).send().await?.error_for_status()?.json().await?;
+            check(response.error)
+        }).await
+    }
+
+    /// Removes `key`, returning whether it existed
+    pub async fn delete(&self, key: &str) -> Result<bool, ClientError> {
+        self.measured(Operation::Write, async {
+            let response: WriteResponse = self.http.delete(self.url(&["delete", key])).send().await?.error_for_status()?.json().await?;
+            response.into_deleted()
+        }).await
+    }
+
+    /// Moves the value of `from` to `to`, replacing whatever `to` held. Returns
+    /// whether `from` existed.
+    pub async fn rename(&self, from: &str, to: &str) -> Result<bool, ClientError> {
+        self.measured(Operation::Write, async {
+            let response: WriteResponse = self.http.post(self.url(&["rename", from, to])).send().await?.error_for_status()?.json().await?;
+            response.into_deleted()
+        }).await
+    }
+
+    /// Copies the value of `from` to `to`, replacing whatever `to` held. Returns
+    /// whether `from` existed.
+    pub async fn copy(&self, from: &str, to: &str) -> Result<bool, ClientError> {
+        self.measured(Operation::Write, async {
+            let response: WriteResponse = self.http.post(self.url(&["copy", from, to])).send().await?.error_for_status()?.json().await?;
+            response.into_deleted()
+        }).await
+    }
+
+    /// Writes all `entries` in a single request, returning how many were written.
+    /// Fails if any entry was rejected, though the valid ones are still written.
+    pub async fn batch(&self, entries: &[(&str, &str)]) -> Result<usize, ClientError> {
+        self.measured(Operation::Write, async {
+            let response: ImportResponse = self.http.post(self.url(&["import"]))
+                .header("Content-Type", "application/x-ndjson")
+                .body(batch_body(entries))
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            response.into_imported()
+        }).await
+    }
+}
+
+/// Failures that may go away on their own - the server being unreachable, slow or overloaded
+fn retryable(err: &reqwest::Error) -> bool {
+    err.is_connect() || err.is_timeout() || err.status().is_some_and(|status| status.is_server_error())
+}
+
+/// TESTS
+
+#[test]
+fn test_urls_encode_keys() {
+    let base = parse_base("http://localhost:8000/kopper/").unwrap();
+    assert_eq!(api_url(&base, &["write", "a/b", "c d?"]).as_str(), "http://localhost:8000/kopper/v1/write/a%2Fb/c%20d%3F");
+}
@@ -0,0 +1,101 @@
+//! Where the engine and the stats take the time from, so tests can move it
+//! along with a [`MockClock`] instead of waiting for it to pass.
+
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub trait Clock: Send + Sync {
+    /// Wall-clock time
+    fn now(&self) -> SystemTime;
+
+    /// Blocks until `duration` has passed on this clock
+    fn sleep(&self, duration: Duration);
+
+    /// Milliseconds since the Unix epoch
+    fn now_millis(&self) -> u64 {
+        self.now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+    }
+
+    /// Time passed since `earlier`, zero if the clock went back meanwhile
+    fn since(&self, earlier: SystemTime) -> Duration {
+        self.now().duration_since(earlier).unwrap_or_default()
+    }
+}
+
+/// Time of the operating system
+#[derive(Clone, Copy, Default, Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+struct MockTime {
+    now: SystemTime,
+    /// Calls to [`Clock::sleep`] so far
+    sleeps: usize,
+}
+
+/// Clock that stands still until moved with [`MockClock::advance`]. Threads
+/// sleeping on it wake up once it's moved past the time they wait for.
+pub struct MockClock {
+    time: Mutex<MockTime>,
+    changed: Condvar,
+}
+
+impl MockClock {
+    pub fn new(now: SystemTime) -> Self {
+        MockClock { time: Mutex::new(MockTime { now, sleeps: 0 }), changed: Condvar::new() }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.time.lock().unwrap().now += duration;
+        self.changed.notify_all();
+    }
+
+    /// Blocks until [`Clock::sleep`] was called `count` times in total, e.g. to
+    /// know that a background thread is done with a round of work
+    pub fn wait_for_sleeps(&self, count: usize) {
+        let _time = self.changed.wait_while(self.time.lock().unwrap(), |time| time.sleeps < count).unwrap();
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        self.time.lock().unwrap().now
+    }
+
+    fn sleep(&self, duration: Duration) {
+        let mut time = self.time.lock().unwrap();
+        let until = time.now + duration;
+        time.sleeps += 1;
+        self.changed.notify_all();
+        let _time = self.changed.wait_while(time, |time| time.now < until).unwrap();
+    }
+}
+
+/// TESTS
+
+#[test]
+fn test_mock_clock_wakes_sleepers() {
+    use std::sync::Arc;
+
+    let clock = Arc::new(MockClock::new(UNIX_EPOCH + Duration::from_secs(1)));
+    let sleeper = {
+        let clock = clock.clone();
+        std::thread::spawn(move || clock.sleep(Duration::from_secs(10)))
+    };
+
+    clock.wait_for_sleeps(1);
+    clock.advance(Duration::from_secs(9));
+    assert!(!sleeper.is_finished());
+    clock.advance(Duration::from_secs(1));
+    sleeper.join().unwrap();
+    assert_eq!(clock.now_millis(), 11_000);
+}
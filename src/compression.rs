@@ -0,0 +1,225 @@
+//! Compression of single values. Records are NUL separated, so a compressed value
+//! is stored as a flag byte naming the codec, followed by the compressed bytes
+//! with every NUL encoded away ([COBS]). The flag bytes never start valid UTF-8,
+//! which every value written uncompressed is - databases written before, or with
+//! compression off, read the same as ever.
+//!
+//! [COBS]: https://en.wikipedia.org/wiki/Consistent_Overhead_Byte_Stuffing
+
+use std::borrow::Cow;
+use std::str::FromStr;
+
+use crate::kopper::KopperError;
+
+const LZ4: u8 = 0xFE;
+const ZSTD: u8 = 0xFD;
+
+/// Level zstd compresses at, its own default
+#[cfg(feature = "compression")]
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    /// Fast, compresses less
+    Lz4,
+    /// Slower, compresses more
+    Zstd,
+}
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "lz4" => Ok(Codec::Lz4),
+            "zstd" => Ok(Codec::Zstd),
+            _ => Err(format!("Unknown codec {name}, expected lz4 or zstd")),
+        }
+    }
+}
+
+/// How values are compressed, see [`Kopper::set_compression`](crate::kopper::Kopper::set_compression)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Compression {
+    pub codec: Codec,
+    /// Values shorter than this many bytes are stored as they are
+    pub threshold: usize,
+}
+
+/// Values compressed by a database since it was opened, see
+/// [`Kopper::compression_stats`](crate::kopper::Kopper::compression_stats)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompressionStats {
+    pub values: u64,
+    /// Of the compressed values, before compressing
+    pub plain_bytes: u64,
+    pub stored_bytes: u64,
+}
+
+impl CompressionStats {
+    /// Stored bytes per byte of the compressed values, `None` until one is compressed
+    pub fn ratio(&self) -> Option<f64> {
+        (self.plain_bytes > 0).then(|| self.stored_bytes as f64 / self.plain_bytes as f64)
+    }
+}
+
+/// Default [`Compression::threshold`] - shorter values rarely shrink by much
+pub const DEFAULT_THRESHOLD: usize = 256;
+
+impl Compression {
+    pub fn new(codec: Codec) -> Self {
+        Compression { codec, threshold: DEFAULT_THRESHOLD }
+    }
+
+    /// Bytes to store for `value`. Values that wouldn't get any shorter are kept as they are.
+    pub fn encode<'a>(&self, value: &'a [u8]) -> Cow<'a, [u8]> {
+        if value.len() < self.threshold {
+            return Cow::Borrowed(value);
+        }
+        let Some(compressed) = compress(self.codec, value) else {
+            return Cow::Borrowed(value);
+        };
+
+        let mut stored = Vec::with_capacity(compressed.len() + compressed.len() / 254 + 2);
+        stored.push(match self.codec {
+            Codec::Lz4 => LZ4,
+            Codec::Zstd => ZSTD,
+        });
+        cobs_encode(&compressed, &mut stored);
+
+        match stored.len() < value.len() {
+            true => Cow::Owned(stored),
+            false => Cow::Borrowed(value),
+        }
+    }
+}
+
+/// `value` compressed as it is, without a flag - also used for packed segments
+#[cfg(feature = "compression")]
+pub(crate) fn compress(codec: Codec, value: &[u8]) -> Option<Vec<u8>> {
+    match codec {
+        Codec::Lz4 => Some(lz4_flex::compress_prepend_size(value)),
+        Codec::Zstd => zstd::bulk::compress(value, ZSTD_LEVEL).ok(),
+    }
+}
+
+/// Built without codecs, nothing is compressed
+#[cfg(not(feature = "compression"))]
+pub(crate) fn compress(_: Codec, _: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+/// Value stored as `stored`, decompressed if it was compressed
+pub fn decode(stored: Vec<u8>) -> Result<Vec<u8>, KopperError> {
+    let codec = match stored.first() {
+        Some(&LZ4) => Codec::Lz4,
+        Some(&ZSTD) => Codec::Zstd,
+        _ => return Ok(stored),
+    };
+    let compressed = cobs_decode(&stored[1..])
+        .ok_or_else(|| KopperError::InternalError(anyhow::anyhow!("Compressed value is damaged")))?;
+    decompress(codec, &compressed)
+}
+
+#[cfg(feature = "compression")]
+pub(crate) fn decompress(codec: Codec, compressed: &[u8]) -> Result<Vec<u8>, KopperError> {
+    let decompressed = match codec {
+        Codec::Lz4 => lz4_flex::decompress_size_prepended(compressed).map_err(anyhow::Error::new),
+        Codec::Zstd => zstd::stream::decode_all(compressed).map_err(anyhow::Error::new),
+    };
+    decompressed.map_err(KopperError::InternalError)
+}
+
+#[cfg(not(feature = "compression"))]
+pub(crate) fn decompress(_: Codec, _: &[u8]) -> Result<Vec<u8>, KopperError> {
+    Err(KopperError::Unsupported("compressed values, build with the compression feature"))
+}
+
+/// Appends `data` to `out` without a single NUL. Every run of up to 254 bytes
+/// other than NUL is prefixed with its length plus one, a NUL ending it implied
+/// unless the run is 254 bytes long.
+fn cobs_encode(data: &[u8], out: &mut Vec<u8>) {
+    let mut code_at = out.len();
+    out.push(0);
+    let mut code = 1u8;
+    for &byte in data {
+        if byte != 0 {
+            out.push(byte);
+            code += 1;
+        }
+        if byte == 0 || code == 0xFF {
+            out[code_at] = code;
+            code_at = out.len();
+            out.push(0);
+            code = 1;
+        }
+    }
+    out[code_at] = code;
+}
+
+fn cobs_decode(encoded: &[u8]) -> Option<Vec<u8>> {
+    let mut data = Vec::with_capacity(encoded.len());
+    let mut at = 0;
+    while at < encoded.len() {
+        let code = encoded[at] as usize;
+        let run = encoded.get(at + 1..at + code)?;
+        if code == 0 || run.contains(&0) {
+            return None;
+        }
+        data.extend_from_slice(run);
+        at += code;
+        if code < 0xFF && at < encoded.len() {
+            data.push(0);
+        }
+    }
+    Some(data)
+}
+
+/// TESTS
+
+#[test]
+fn test_cobs_roundtrip() {
+    let cases: Vec<Vec<u8>> = vec![
+        vec![],
+        vec![0],
+        vec![0, 0],
+        vec![1, 2, 0, 3],
+        vec![7; 254],
+        vec![7; 255],
+        [vec![7; 254], vec![0], vec![7; 300]].concat(),
+        (0..=255).cycle().take(2000).collect(),
+    ];
+    for data in cases {
+        let mut encoded = Vec::new();
+        cobs_encode(&data, &mut encoded);
+        assert!(!encoded.contains(&0));
+        assert_eq!(cobs_decode(&encoded).unwrap(), data);
+    }
+    assert!(cobs_decode(&[3, 1]).is_none());
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn test_values_are_compressed_above_threshold() {
+    let value = "abcdefgh".repeat(100);
+    for codec in [Codec::Lz4, Codec::Zstd] {
+        let compression = Compression::new(codec);
+        let stored = compression.encode(value.as_bytes());
+        assert!(stored.len() < value.len() / 4);
+        assert!(!stored.contains(&0));
+        assert_eq!(decode(stored.into_owned()).unwrap(), value.as_bytes());
+
+        // Too short to bother
+        assert!(matches!(compression.encode(b"abcabcabc"), Cow::Borrowed(_)));
+    }
+
+    // Doesn't get any shorter
+    let mut state = 0x2545F4914F6CDD1Du64;
+    let random: Vec<u8> = (0..1000).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state as u8
+    }).collect();
+    assert!(matches!(Compression::new(Codec::Lz4).encode(&random), Cow::Borrowed(_)));
+}
@@ -0,0 +1,182 @@
+//! Counters - values summing up increments, see [`Kopper::incr`](crate::kopper::Kopper::incr).
+//! Every increment is recorded with when it happened, a record at a time, see
+//! [`chain`], so a counter can tell how much it counted over the last hour, say.
+//! Written whole, increments in buckets that are over are collapsed into one
+//! sum per bucket - a window then counts every bucket it overlaps in whole.
+//! Minute buckets older than [`MINUTE_BUCKETS_KEPT_MS`] are merged on into
+//! hourly ones, so a counter grows by a bucket an hour, not one a minute.
+//!
+//! A record lists `+ | at | by` for every increment, and for the whole counter
+//! `# | start | sum` for every minute bucket and `= | start | sum` for every
+//! hourly one before them - numbers in decimal, times in milliseconds since the
+//! Unix epoch. A counter reads as its total.
+
+use std::collections::BTreeMap;
+
+use crate::chain::{self, Header, Kind};
+use crate::kopper::KopperError;
+
+const INCREMENT: u8 = b'+';
+const BUCKET: u8 = b'#';
+const HOUR: u8 = b'=';
+
+/// How long a bucket old increments are collapsed into is
+pub const BUCKET_MS: u64 = 60_000;
+
+/// How long an hourly bucket old minute buckets are merged into is
+pub const HOUR_MS: u64 = 3_600_000;
+
+/// How long minute buckets are kept before they're merged into hourly ones
+pub const MINUTE_BUCKETS_KEPT_MS: u64 = 24 * HOUR_MS;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Counter {
+    /// Sums of increments by the start of the bucket they fell into
+    buckets: BTreeMap<u64, i64>,
+    /// Sums of minute buckets by the start of the hour they fell into
+    hours: BTreeMap<u64, i64>,
+    /// Increments not collapsed yet, as when they happened and by how much
+    increments: Vec<(u64, i64)>,
+}
+
+impl Counter {
+    pub fn add(&mut self, at: u64, by: i64) {
+        self.increments.push((at, by));
+    }
+
+    /// Sum of everything counted
+    pub fn total(&self) -> i64 {
+        self.hours.values().chain(self.buckets.values()).chain(self.increments.iter().map(|(_, by)| by))
+            .fold(0, |sum, by| sum.saturating_add(*by))
+    }
+
+    /// Sum of what was counted at `since` or later, and in buckets ending after it
+    pub fn sum_since(&self, since: u64) -> i64 {
+        let hours = self.hours.range(since.saturating_sub(HOUR_MS - 1)..).map(|(_, sum)| sum);
+        let buckets = self.buckets.range(since.saturating_sub(BUCKET_MS - 1)..).map(|(_, sum)| sum);
+        let increments = self.increments.iter().filter(|(at, _)| *at >= since).map(|(_, by)| by);
+        hours.chain(buckets).chain(increments).fold(0, |sum, by| sum.saturating_add(*by))
+    }
+
+    /// Collapses increments in buckets over by `now` into their sums, and minute
+    /// buckets older than [`MINUTE_BUCKETS_KEPT_MS`] into hourly ones
+    pub fn collapse(&mut self, now: u64) {
+        let (old, recent) = self.increments.iter().partition(|(at, _)| bucket(*at, BUCKET_MS) + BUCKET_MS <= now);
+        self.increments = recent;
+        for (at, by) in old {
+            let sum = self.buckets.entry(bucket(at, BUCKET_MS)).or_default();
+            *sum = sum.saturating_add(by);
+        }
+
+        // Only whole hours, so no hour has both minute buckets and an hourly one
+        let kept = bucket(now.saturating_sub(MINUTE_BUCKETS_KEPT_MS), HOUR_MS);
+        let recent = self.buckets.split_off(&kept);
+        for (start, by) in std::mem::replace(&mut self.buckets, recent) {
+            let sum = self.hours.entry(bucket(start, HOUR_MS)).or_default();
+            *sum = sum.saturating_add(by);
+        }
+    }
+}
+
+/// Start of the bucket `width` long `at` falls into
+fn bucket(at: u64, width: u64) -> u64 {
+    at - at % width
+}
+
+/// Record of the whole `counter`
+pub fn full(counter: &Counter) -> Vec<u8> {
+    let mut record = chain::full(Kind::Counter);
+    for (op, (at, by)) in counter.hours.iter().map(|(start, sum)| (HOUR, (*start, *sum)))
+        .chain(counter.buckets.iter().map(|(start, sum)| (BUCKET, (*start, *sum))))
+        .chain(counter.increments.iter().map(|increment| (INCREMENT, *increment))) {
+        record.push(op);
+        chain::push(&mut record, &at.to_string());
+        chain::push(&mut record, &by.to_string());
+    }
+    record
+}
+
+/// Record of counting `by` `at` a time, changing the counter recorded at `offset`
+/// of the same segment - `len` long, with a header `previous`
+pub fn delta(offset: u64, len: usize, previous: Header, at: u64, by: i64) -> Vec<u8> {
+    let mut record = chain::delta(Kind::Counter, offset, len, previous);
+    record.push(INCREMENT);
+    chain::push(&mut record, &at.to_string());
+    chain::push(&mut record, &by.to_string());
+    record
+}
+
+/// The counter whose newest record is `record`, see [`chain::records`]
+pub fn counter(record: Vec<u8>, read: impl FnMut(u64, usize) -> Result<Vec<u8>, KopperError>) -> Result<Counter, KopperError> {
+    let mut counter = Counter::default();
+    for record in chain::records(Kind::Counter, record, read)? {
+        let mut ops = chain::body(&record);
+        while let [op, rest @ ..] = ops {
+            ops = rest;
+            let at = chain::take(&mut ops)?.parse().map_err(|_| chain::damaged())?;
+            let by = chain::take(&mut ops)?.parse().map_err(|_| chain::damaged())?;
+            match *op {
+                INCREMENT => counter.add(at, by),
+                BUCKET => { counter.buckets.insert(at, by); },
+                HOUR => { counter.hours.insert(at, by); },
+                _ => return Err(chain::damaged()),
+            }
+        }
+    }
+    Ok(counter)
+}
+
+/// TESTS
+
+#[test]
+fn test_old_increments_are_collapsed_into_buckets() {
+    let mut whole = Counter::default();
+    whole.add(1_000, 1);
+    whole.add(59_000, 2);
+    whole.add(61_000, 4);
+    let mut segment = vec![full(&whole)];
+    segment.push(delta(0, segment[0].len(), Header::Full, 130_000, -8));
+
+    let read = |offset: u64, len: usize| {
+        assert_eq!(segment[offset as usize].len(), len);
+        Ok(segment[offset as usize].clone())
+    };
+    let mut counter = counter(segment[1].clone(), read).unwrap();
+    assert_eq!(counter.total(), -1);
+    assert_eq!(counter.sum_since(60_000), -4);
+
+    // The first bucket is over, the second one isn't - and a window reaching
+    // into a bucket counts all of it
+    counter.collapse(119_999);
+    assert_eq!(counter.buckets, BTreeMap::from([(0, 3)]));
+    assert_eq!(counter.total(), -1);
+    assert_eq!(counter.sum_since(30_000), -1);
+    assert_eq!(counter.sum_since(60_000), -4);
+    assert_eq!(self::counter(full(&counter), read).unwrap(), counter);
+}
+
+#[test]
+fn test_old_minute_buckets_are_merged_into_hours() {
+    let mut counter = Counter::default();
+    for minute in 0..120 {
+        counter.add(minute * BUCKET_MS, 1);
+    }
+    // A day after the first hour ended, its minutes go - the second hour's stay
+    counter.collapse(HOUR_MS + MINUTE_BUCKETS_KEPT_MS);
+    assert_eq!(counter.hours, BTreeMap::from([(0, 60)]));
+    assert_eq!(counter.buckets.len(), 60);
+    assert!(counter.buckets.keys().all(|start| *start >= HOUR_MS));
+
+    assert_eq!(counter.total(), 120);
+    assert_eq!(counter.sum_since(HOUR_MS + 30 * BUCKET_MS), 30);
+    // A window reaching into an hour counts all of it
+    assert_eq!(counter.sum_since(30 * BUCKET_MS), 120);
+
+    let record = full(&counter);
+    assert_eq!(self::counter(record.clone(), |_, _| Ok(record.clone())).unwrap(), counter);
+
+    counter.collapse(2 * HOUR_MS + MINUTE_BUCKETS_KEPT_MS);
+    assert!(counter.buckets.is_empty());
+    assert_eq!(counter.hours, BTreeMap::from([(0, 60), (HOUR_MS, 60)]));
+    assert_eq!(counter.total(), 120);
+}
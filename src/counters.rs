@@ -0,0 +1,55 @@
+//! Routes for counters, see [`Kopper::incr`]. Increments are recorded with when
+//! they happened, so a counter tells what it counted over a recent window too.
+
+use std::time::Duration;
+
+use rocket::State;
+use rocket::serde::json::Json;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use kopperdb::kopper::Kopper;
+
+#[derive(Serialize, ToSchema)]
+pub struct CountResponse {
+    /// Total of the counter after the increment, or sum over the window - 0 on failure
+    count: i64,
+    /// "OK" on success, description of the problem otherwise
+    error: String
+}
+
+#[utoipa::path(
+    post,
+    path = "/incr/{key}",
+    tag = "counters",
+    params(
+        ("key" = String, Path, description = "Key of the counter, created if there's none"),
+        ("by" = Option<i64>, Query, description = "What to count, 1 by default. Negative to count down.")
+    ),
+    responses((status = 200, description = "Total of the counter", body = CountResponse))
+)]
+#[post("/incr/<key>?<by>")]
+pub fn incr(key: &str, by: Option<i64>, db: &State<Kopper>) -> Json<CountResponse> {
+    Json(match db.incr(key, by.unwrap_or(1)) {
+        Ok(count) => CountResponse { count, error: "OK".to_string() },
+        Err(err) => CountResponse { count: 0, error: format!("Error while writing! : {err}") },
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/counted/{key}",
+    tag = "counters",
+    params(
+        ("key" = String, Path, description = "Key of the counter"),
+        ("window" = u64, Query, description = "Seconds to sum over, up to now. Old increments count by the minute.")
+    ),
+    responses((status = 200, description = "Sum over the window, 0 if there's no such counter", body = CountResponse))
+)]
+#[get("/counted/<key>?<window>")]
+pub fn counted(key: &str, window: u64, db: &State<Kopper>) -> Json<CountResponse> {
+    Json(match db.counted(key, Duration::from_secs(window)) {
+        Ok(count) => CountResponse { count, error: "OK".to_string() },
+        Err(err) => CountResponse { count: 0, error: format!("Error while reading! : {err}") },
+    })
+}
@@ -0,0 +1,95 @@
+//! Deduplication of identical values, see [`Kopper::set_dedupe`](crate::kopper::Kopper::set_dedupe).
+//! The first key written with a value stores it as a blob - the value behind a
+//! flag byte and its hash. Keys written with the same value later store only a
+//! reference, another flag byte and the hash. Neither flag starts valid UTF-8,
+//! so plain and compressed values are never mistaken for either.
+
+use crate::kopper::KopperError;
+
+const BLOB: u8 = 0xFB;
+const REFERENCE: u8 = 0xFC;
+
+/// Flag and hash, in hex so a record never holds a NUL
+const HASH_LEN: usize = 16;
+pub const HEADER_LEN: usize = 1 + HASH_LEN;
+
+/// Default [`Kopper::set_dedupe`](crate::kopper::Kopper::set_dedupe) length -
+/// shorter values would hardly be any longer than a reference
+pub const DEFAULT_MIN_LEN: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Record {
+    /// Value stored behind the header, for every key with the same hash
+    Blob(u64),
+    Reference(u64),
+}
+
+/// Hash of a value as stored. FNV-1a, so it stays the same across builds and
+/// platforms, unlike the standard library's.
+pub fn hash(stored: &[u8]) -> u64 {
+    stored.iter().fold(0xcbf29ce484222325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/// `stored` as the blob of the values hashing to `hash`
+pub fn blob(hash: u64, stored: &[u8]) -> Vec<u8> {
+    let mut record = header(BLOB, hash);
+    record.extend_from_slice(stored);
+    record
+}
+
+pub fn reference(hash: u64) -> Vec<u8> {
+    header(REFERENCE, hash)
+}
+
+fn header(flag: u8, hash: u64) -> Vec<u8> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.push(flag);
+    header.extend_from_slice(format!("{hash:016x}").as_bytes());
+    header
+}
+
+/// Whether a value starting with `byte` may be a blob or a reference
+pub fn flagged(byte: u8) -> bool {
+    byte == BLOB || byte == REFERENCE
+}
+
+/// What the value starting with `stored` is, `None` if it's not deduplicated
+pub fn parse(stored: &[u8]) -> Option<Record> {
+    let hash = stored.get(1..HEADER_LEN)
+        .and_then(|hash| std::str::from_utf8(hash).ok())
+        .and_then(|hash| u64::from_str_radix(hash, 16).ok());
+    match (stored.first(), hash) {
+        (Some(&BLOB), Some(hash)) => Some(Record::Blob(hash)),
+        (Some(&REFERENCE), Some(hash)) => Some(Record::Reference(hash)),
+        _ => None,
+    }
+}
+
+/// Value stored as `stored`, without the header if it's a blob. A reference
+/// has to be looked up first.
+pub fn strip(mut stored: Vec<u8>) -> Result<Vec<u8>, KopperError> {
+    match parse(&stored) {
+        Some(Record::Blob(_)) => {
+            stored.drain(..HEADER_LEN);
+            Ok(stored)
+        },
+        Some(Record::Reference(_)) => Err(KopperError::InternalError(anyhow::anyhow!("Value is a reference to a deduplicated one that isn't known"))),
+        None => Ok(stored),
+    }
+}
+
+/// TESTS
+
+#[test]
+fn test_records_parse_back() {
+    let hash = hash(b"value");
+    let blob = blob(hash, b"value");
+    assert_eq!(parse(&blob), Some(Record::Blob(hash)));
+    assert_eq!(strip(blob).unwrap(), b"value");
+    assert_eq!(parse(&reference(hash)), Some(Record::Reference(hash)));
+    assert!(strip(reference(hash)).is_err());
+
+    // Plain values are left as they are
+    assert_eq!(parse(b"\xFB not a hash at all"), None);
+    assert_eq!(strip(b"value".to_vec()).unwrap(), b"value");
+}
@@ -0,0 +1,251 @@
+//! Diagnostics of an open database, see [`Kopper::doctor`](crate::kopper::Kopper::doctor).
+//! Every check comes out healthy, with a warning or failing, and the ones that
+//! aren't healthy suggest what to do about it.
+
+use std::ffi::CString;
+use std::fmt::Display;
+use std::fs;
+
+use serde::{Deserialize, Serialize};
+
+use crate::kopper::SegmentStats;
+use crate::verify::VerifyStats;
+use crate::segment_log::{self, Segments};
+use crate::store::Tier;
+
+/// Share of the open files limit past which [`descriptors`] warns
+const DESCRIPTORS_WARNING: f64 = 0.8;
+/// Share of dead bytes in sealed segments past which [`fragmentation`] warns
+const FRAGMENTATION_WARNING: f64 = 0.5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Health {
+    Ok,
+    Warning,
+    Failing,
+}
+
+impl Display for Health {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Health::Ok => "ok",
+            Health::Warning => "warning",
+            Health::Failing => "failing",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Check {
+    pub name: String,
+    pub health: Health,
+    /// What was found
+    pub detail: String,
+    /// What to do about it, `None` if it's healthy
+    pub remedy: Option<String>,
+}
+
+impl Check {
+    pub(crate) fn ok(name: &str, detail: impl Into<String>) -> Check {
+        Check { name: name.to_owned(), health: Health::Ok, detail: detail.into(), remedy: None }
+    }
+
+    fn warning(name: &str, detail: impl Into<String>, remedy: impl Into<String>) -> Check {
+        Check { name: name.to_owned(), health: Health::Warning, detail: detail.into(), remedy: Some(remedy.into()) }
+    }
+
+    fn failing(name: &str, detail: impl Into<String>, remedy: impl Into<String>) -> Check {
+        Check { name: name.to_owned(), health: Health::Failing, detail: detail.into(), remedy: Some(remedy.into()) }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    /// Worst of the checks
+    pub fn health(&self) -> Health {
+        self.checks.iter().map(|check| check.health).max().unwrap_or(Health::Ok)
+    }
+}
+
+/// Whether this process may access `path` as `mode`, see `access(2)`
+fn accessible(path: &str, mode: libc::c_int) -> bool {
+    let Ok(path) = CString::new(path) else {
+        return false;
+    };
+    // Safe, only reads the path
+    unsafe { libc::access(path.as_ptr(), mode) == 0 }
+}
+
+/// Whether the database can use its directory `dir`, read its `segments` and
+/// append to the active one. Segments on the cold tier, or spread to other
+/// directories, are kept elsewhere.
+pub(crate) fn permissions(dir: Option<&str>, segments: &[SegmentStats]) -> Check {
+    const NAME: &str = "permissions";
+    let Some(dir) = dir else {
+        return Check::ok(NAME, "Segments aren't kept in a local directory");
+    };
+    if !accessible(dir, libc::R_OK | libc::W_OK | libc::X_OK) {
+        return Check::failing(NAME, format!("{dir} can't be listed and written to"),
+            format!("Give the user the database runs as read, write and search permission on {dir}"));
+    }
+
+    let mut denied = Vec::new();
+    for segment in segments.iter().filter(|segment| segment.tier == Tier::Hot) {
+        let path = format!("{dir}/{}", segment.name);
+        let mode = match segment.age {
+            0 => libc::R_OK | libc::W_OK,
+            _ => libc::R_OK,
+        };
+        if !accessible(&path, mode) && accessible(&path, libc::F_OK) {
+            denied.push(segment.name.as_str());
+        }
+    }
+    match denied.is_empty() {
+        true => Check::ok(NAME, format!("{dir} and its {} segments are accessible", segments.len())),
+        false => Check::failing(NAME, format!("Segments {} can't be read, or written to if active", denied.join(", ")),
+            format!("Give the user the database runs as read permission on every segment in {dir}, and write permission on the active one")),
+    }
+}
+
+/// Whether writes go through, and if not why
+pub(crate) fn writes(following: bool, read_only: bool, degraded: Option<&str>) -> Check {
+    const NAME: &str = "lock";
+    match (degraded, following, read_only) {
+        (Some(reason), ..) => Check::failing(NAME, format!("Writes are refused: {reason}"),
+            "Free up disk space, then resume writes with Kopper::resume"),
+        (None, true, _) => Check::ok(NAME, "Following another process, which writes the segments"),
+        (None, false, true) => Check::warning(NAME, "The database is read-only", "Make it writable again if that's not on purpose"),
+        (None, false, false) => Check::ok(NAME, "Writes are accepted"),
+    }
+}
+
+/// Whether the MANIFEST names the segments open, `None` if it's empty or
+/// damaged. `open` are the names of those segments.
+pub(crate) fn manifest(logged: Option<Segments>, open: &[String]) -> Check {
+    const NAME: &str = "manifest";
+    let Some(logged) = logged else {
+        return Check::warning(NAME, format!("The {} is empty or damaged, segments are only found by listing them", segment_log::NAME),
+            format!("Reopen the database, recovery writes the {} again", segment_log::NAME));
+    };
+
+    let unlisted: Vec<&str> = open.iter().filter(|name| !logged.live.contains(*name)).map(String::as_str).collect();
+    let missing: Vec<&str> = logged.live.iter().filter(|name| !open.contains(name)).map(String::as_str).collect();
+    if unlisted.is_empty() && missing.is_empty() {
+        return Check::ok(NAME, format!("Names the {} segments open", open.len()));
+    }
+
+    let mut detail = Vec::new();
+    if !unlisted.is_empty() {
+        detail.push(format!("segments {} aren't in it", unlisted.join(", ")));
+    }
+    if !missing.is_empty() {
+        detail.push(format!("it names segments {} that aren't open", missing.join(", ")));
+    }
+    Check::failing(NAME, format!("The {} disagrees with the segments open: {}", segment_log::NAME, detail.join(", ")),
+        format!("Reopen the database, recovery checks the segments and writes the {} again", segment_log::NAME))
+}
+
+/// How many files the process has open, out of how many it may. `segments` of
+/// them are the database's.
+pub(crate) fn descriptors(segments: usize) -> Check {
+    const NAME: &str = "descriptors";
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    // Safe, only writes the limit
+    let limited = unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } == 0;
+    let (Ok(open), true) = (fs::read_dir("/proc/self/fd"), limited) else {
+        return Check::ok(NAME, "Open files can't be counted on this platform");
+    };
+
+    let open = open.count();
+    let detail = format!("{open} files open, {segments} of them segments, out of {} allowed", limit.rlim_cur);
+    match open as f64 > limit.rlim_cur as f64 * DESCRIPTORS_WARNING {
+        true => Check::warning(NAME, detail, "Raise the limit of open files (ulimit -n), or compact to have fewer segments"),
+        false => Check::ok(NAME, detail),
+    }
+}
+
+/// Whether the compactor is still running, `None` if the database doesn't have one
+pub(crate) fn compactor(running: Option<bool>) -> Check {
+    const NAME: &str = "compactor";
+    match running {
+        None => Check::ok(NAME, "Followers don't compact, the process they follow does"),
+        Some(true) => Check::ok(NAME, "Running"),
+        Some(false) => Check::failing(NAME, "The compactor stopped, dead records aren't reclaimed anymore",
+            "Restart the server. The logs tell why the compactor stopped."),
+    }
+}
+
+/// How much of the sealed `segments` compaction would drop
+pub(crate) fn fragmentation(segments: &[SegmentStats]) -> Check {
+    const NAME: &str = "fragmentation";
+    let sealed: Vec<&SegmentStats> = segments.iter().filter(|segment| segment.age > 0).collect();
+    let bytes: usize = sealed.iter().map(|segment| segment.bytes).sum();
+    let dead: usize = sealed.iter().map(|segment| segment.dead_bytes).sum();
+    if bytes == 0 {
+        return Check::ok(NAME, "No sealed segments yet");
+    }
+
+    let ratio = dead as f64 / bytes as f64;
+    let detail = format!("{dead} of {bytes} bytes in {} sealed segments are dead ({:.0}%)", sealed.len(), ratio * 100.0);
+    match ratio > FRAGMENTATION_WARNING {
+        true => Check::warning(NAME, detail,
+            "Compact (POST /admin/compact or kopper-cli compact). If it keeps growing, writes outpace the compactor."),
+        false => Check::ok(NAME, detail),
+    }
+}
+
+/// What the verifier found, see [`Kopper::verify`](crate::kopper::Kopper::verify)
+pub(crate) fn verification(stats: &VerifyStats) -> Check {
+    const NAME: &str = "verification";
+    if stats.checked == 0 {
+        return Check::ok(NAME, "No keys verified yet");
+    }
+
+    let detail = format!("{} of {} keys verified were corrupt, {} differed on the replica", stats.corrupt, stats.checked, stats.mismatched);
+    if !stats.quarantined.is_empty() {
+        return Check::failing(NAME, format!("{detail}, segments {} are quarantined", stats.quarantined.join(", ")),
+            "Restore the keys in the quarantined segments from a backup or the replica, then restart the server");
+    }
+    match (stats.corrupt, stats.mismatched) {
+        (0, 0) => Check::ok(NAME, detail),
+        (0, _) => Check::warning(NAME, detail, "Compare the keys logged with the replica's, replication may have missed changes"),
+        _ => Check::failing(NAME, detail, "Restore the keys logged from a backup or the replica, and check the disk for errors"),
+    }
+}
+
+/// TESTS
+
+#[test]
+fn test_manifest_check_names_disagreeing_segments() {
+    use std::collections::BTreeSet;
+
+    let open = vec!["0_1".to_owned(), "1_0".to_owned()];
+    let logged = |live: &[&str]| Some(Segments { live: live.iter().map(|name| name.to_string()).collect::<BTreeSet<_>>(), ..Default::default() });
+    assert_eq!(manifest(logged(&["0_1", "1_0"]), &open).health, Health::Ok);
+    assert_eq!(manifest(None, &open).health, Health::Warning);
+
+    let check = manifest(logged(&["0_0", "1_0"]), &open);
+    assert_eq!(check.health, Health::Failing);
+    assert!(check.detail.contains("0_1 aren't in it"));
+    assert!(check.detail.contains("0_0 that aren't open"));
+    assert!(check.remedy.is_some());
+}
+
+#[test]
+fn test_fragmentation_counts_sealed_segments() {
+    let segment = |age: u32, bytes: usize, dead_bytes: usize| SegmentStats {
+        name: format!("{age}_0"), bytes, stored_bytes: bytes, live_bytes: bytes - dead_bytes, dead_bytes,
+        entries: 1, dead_entries: 1, age, tier: Tier::Hot,
+    };
+    assert_eq!(fragmentation(&[segment(0, 100, 90)]).health, Health::Ok);
+    assert_eq!(fragmentation(&[segment(2, 100, 40), segment(1, 100, 40), segment(0, 100, 100)]).health, Health::Ok);
+
+    let check = fragmentation(&[segment(2, 100, 90), segment(1, 100, 40), segment(0, 100, 0)]);
+    assert_eq!(check.health, Health::Warning);
+    assert!(check.detail.contains("130 of 200 bytes"));
+}
@@ -0,0 +1,415 @@
+//! Encryption at rest. An [`EncryptedStore`] wraps another [`SegmentStore`] and
+//! seals everything appended to its segments with AES-256-GCM, so the files are
+//! of no use to whoever else can read the disk. The engine above only ever sees
+//! plain segments - recovery, compaction and reads decrypt transparently, and
+//! backups stay encrypted with the same key.
+//!
+//! A segment starts with a header naming the format and a random id of the file.
+//! Appends follow as chunks of `length: u32 LE | nonce | ciphertext and tag`,
+//! each sealed under a fresh random nonce and bound to its file and position by
+//! the id and the chunk's offset in the plain segment. A chunk cut short by a
+//! crash is dropped once the segment is opened for appending, like a torn record.
+//!
+//! ```
+//! use std::sync::Arc;
+//! use kopperdb::encryption::{EncryptedStore, EncryptionKey};
+//! use kopperdb::kopper::Kopper;
+//! use kopperdb::store::MemoryStore;
+//!
+//! let key = EncryptionKey::generate();
+//! let store = Arc::new(EncryptedStore::new(Arc::new(MemoryStore::new()), &key));
+//! let kopper = Kopper::with_store(store, 1024).unwrap();
+//! kopper.write("key", "value").unwrap();
+//! assert_eq!(kopper.read("key").unwrap(), "value");
+//! ```
+
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+
+use crate::kopper::KopperError;
+use crate::store::{SegmentFile, SegmentStore};
+
+/// Variable naming the key file, see [`EncryptionKey::from_env`]
+pub const KEY_FILE_VAR: &str = "KOPPER_KEY_FILE";
+
+const MAGIC: &[u8; 8] = b"KOPENC1\0";
+const ID_LEN: usize = 16;
+const HEADER_LEN: usize = MAGIC.len() + ID_LEN;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const CHUNK_HEADER_LEN: usize = 4 + NONCE_LEN;
+
+/// Plain bytes in a chunk at most, so reading a record doesn't decrypt a whole
+/// compacted segment
+const MAX_CHUNK: usize = 4096;
+
+/// 256-bit AES key. Kept in files as 64 hex digits.
+#[derive(Clone)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    pub fn new(bytes: [u8; 32]) -> Self {
+        EncryptionKey(bytes)
+    }
+
+    /// Random key, e.g. to save with [`EncryptionKey::to_hex`] for a new database
+    pub fn generate() -> Self {
+        let mut bytes = [0; 32];
+        OsRng.fill_bytes(&mut bytes);
+        EncryptionKey(bytes)
+    }
+
+    /// Reads the key from a file holding its 64 hex digits, surrounding whitespace aside
+    pub fn from_file(path: &str) -> Result<Self, KopperError> {
+        let hex = std::fs::read_to_string(path)?;
+        EncryptionKey::from_hex(hex.trim())
+            .ok_or_else(|| KopperError::InternalError(anyhow::anyhow!("{path} doesn't hold a key of 64 hex digits")))
+    }
+
+    /// Reads the key from the file named by `KOPPER_KEY_FILE`, `None` if it isn't set
+    pub fn from_env() -> Result<Option<Self>, KopperError> {
+        match std::env::var(KEY_FILE_VAR) {
+            Ok(path) => EncryptionKey::from_file(&path).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        if hex.len() != 64 || !hex.is_ascii() {
+            return None;
+        }
+        let mut bytes = [0; 32];
+        for (byte, digits) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            *byte = u8::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()?;
+        }
+        Some(EncryptionKey(bytes))
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+}
+
+/// Never prints the key itself
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+/// Segments of `inner`, encrypted with one key. Segments written without it,
+/// or with another key, fail to open or to read.
+pub struct EncryptedStore {
+    inner: Arc<dyn SegmentStore>,
+    cipher: Aes256Gcm,
+}
+
+impl EncryptedStore {
+    pub fn new(inner: Arc<dyn SegmentStore>, key: &EncryptionKey) -> Self {
+        EncryptedStore { inner, cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key.0)) }
+    }
+}
+
+impl SegmentStore for EncryptedStore {
+    fn create(&self, name: &str) -> Result<Arc<dyn SegmentFile>, KopperError> {
+        Ok(Arc::new(EncryptedFile::load(self.inner.create(name)?, self.cipher.clone(), true)?))
+    }
+
+    fn open(&self, name: &str) -> Result<Arc<dyn SegmentFile>, KopperError> {
+        Ok(Arc::new(EncryptedFile::load(self.inner.open(name)?, self.cipher.clone(), false)?))
+    }
+
+    fn list(&self) -> Result<Vec<String>, KopperError> {
+        self.inner.list()
+    }
+
+    fn delete(&self, name: &str) -> Result<(), KopperError> {
+        self.inner.delete(name)
+    }
+
+    fn sync_dir(&self) -> Result<(), KopperError> {
+        self.inner.sync_dir()
+    }
+
+    fn path(&self) -> Option<&str> {
+        self.inner.path()
+    }
+
+    fn in_dir(&self, dir: &str) -> Arc<dyn SegmentStore> {
+        Arc::new(EncryptedStore { inner: self.inner.in_dir(dir), cipher: self.cipher.clone() })
+    }
+}
+
+/// Where a chunk is, in the plain segment and in the file
+struct Chunk {
+    plain: u64,
+    raw: u64,
+    /// Of the plain bytes
+    len: usize,
+}
+
+#[derive(Default)]
+struct Chunks {
+    chunks: Vec<Chunk>,
+    /// Of the plain segment
+    len: u64,
+    /// Where the next chunk goes in the file
+    raw_len: u64,
+    /// Last chunk decrypted, by its position in `chunks`
+    cached: Option<(usize, Vec<u8>)>,
+}
+
+struct EncryptedFile {
+    inner: Arc<dyn SegmentFile>,
+    cipher: Aes256Gcm,
+    id: [u8; ID_LEN],
+    chunks: Mutex<Chunks>,
+}
+
+fn invalid(message: String) -> KopperError {
+    io::Error::new(io::ErrorKind::InvalidData, message).into()
+}
+
+impl EncryptedFile {
+    /// Indexes the chunks of `inner`. Opened for appending, a torn chunk at the
+    /// end is cut off, and an empty segment gets its header.
+    fn load(inner: Arc<dyn SegmentFile>, cipher: Aes256Gcm, writable: bool) -> Result<Self, KopperError> {
+        let raw_len = inner.len()?;
+
+        let mut magic = vec![0; MAGIC.len().min(raw_len as usize)];
+        inner.read_at(&mut magic, 0)?;
+        if !MAGIC.starts_with(&magic) {
+            return Err(invalid("Segment isn't encrypted".to_owned()));
+        }
+
+        // A crash while writing the header left nothing worth keeping
+        if raw_len < HEADER_LEN as u64 {
+            let mut id = [0; ID_LEN];
+            OsRng.fill_bytes(&mut id);
+            let mut chunks = Chunks::default();
+            if writable {
+                inner.truncate(0)?;
+                inner.append(&[&MAGIC[..], &id].concat())?;
+                chunks.raw_len = HEADER_LEN as u64;
+            }
+            return Ok(EncryptedFile { inner, cipher, id, chunks: Mutex::new(chunks) });
+        }
+
+        let mut header = [0; HEADER_LEN];
+        inner.read_at(&mut header, 0)?;
+        let id = header[MAGIC.len()..].try_into().unwrap();
+
+        let mut chunks = Chunks { raw_len: HEADER_LEN as u64, ..Chunks::default() };
+        let mut chunk_header = [0; CHUNK_HEADER_LEN];
+        while chunks.raw_len + CHUNK_HEADER_LEN as u64 <= raw_len {
+            inner.read_at(&mut chunk_header, chunks.raw_len)?;
+            let sealed_len = u32::from_le_bytes(chunk_header[..4].try_into().unwrap()) as u64;
+            let end = chunks.raw_len + CHUNK_HEADER_LEN as u64 + sealed_len;
+            if sealed_len < TAG_LEN as u64 || end > raw_len {
+                break;
+            }
+            let len = sealed_len as usize - TAG_LEN;
+            chunks.chunks.push(Chunk { plain: chunks.len, raw: chunks.raw_len, len });
+            chunks.len += len as u64;
+            chunks.raw_len = end;
+        }
+
+        if writable && chunks.raw_len < raw_len {
+            inner.truncate(chunks.raw_len)?;
+        }
+        Ok(EncryptedFile { inner, cipher, id, chunks: Mutex::new(chunks) })
+    }
+
+    /// Ties a chunk to this file and its place in it
+    fn aad(&self, plain: u64) -> [u8; ID_LEN + 8] {
+        let mut aad = [0; ID_LEN + 8];
+        aad[..ID_LEN].copy_from_slice(&self.id);
+        aad[ID_LEN..].copy_from_slice(&plain.to_le_bytes());
+        aad
+    }
+
+    /// Plain bytes of the `index`th chunk, decrypted once for consecutive reads of it
+    fn decrypt<'a>(&self, chunks: &'a mut Chunks, index: usize) -> Result<&'a [u8], KopperError> {
+        if chunks.cached.as_ref().is_none_or(|(cached, _)| *cached != index) {
+            let chunk = &chunks.chunks[index];
+            let mut sealed = vec![0; CHUNK_HEADER_LEN + chunk.len + TAG_LEN];
+            self.inner.read_at(&mut sealed, chunk.raw)?;
+
+            let nonce = Nonce::from_slice(&sealed[4..CHUNK_HEADER_LEN]);
+            let payload = Payload { msg: &sealed[CHUNK_HEADER_LEN..], aad: &self.aad(chunk.plain) };
+            let plain = self.cipher.decrypt(nonce, payload)
+                .map_err(|_| invalid(format!("Can't decrypt the chunk at {} - wrong key, or the segment is damaged", chunk.plain)))?;
+            chunks.cached = Some((index, plain));
+        }
+        Ok(&chunks.cached.as_ref().unwrap().1)
+    }
+
+    fn append_chunks(&self, chunks: &mut Chunks, data: &[u8]) -> Result<(), KopperError> {
+        let mut sealed = Vec::with_capacity(data.len() + data.len().div_ceil(MAX_CHUNK) * (CHUNK_HEADER_LEN + TAG_LEN));
+        let mut added = Vec::new();
+        let mut plain = chunks.len;
+        for part in data.chunks(MAX_CHUNK) {
+            let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+            let ciphertext = self.cipher.encrypt(&nonce, Payload { msg: part, aad: &self.aad(plain) })
+                .map_err(|_| invalid("Can't encrypt".to_owned()))?;
+
+            added.push(Chunk { plain, raw: chunks.raw_len + sealed.len() as u64, len: part.len() });
+            sealed.extend_from_slice(&(ciphertext.len() as u32).to_le_bytes());
+            sealed.extend_from_slice(&nonce);
+            sealed.extend_from_slice(&ciphertext);
+            plain += part.len() as u64;
+        }
+
+        // Nothing is indexed if it fails - whatever made it is cut off by the next truncate
+        self.inner.append(&sealed)?;
+        chunks.chunks.extend(added);
+        chunks.len = plain;
+        chunks.raw_len += sealed.len() as u64;
+        Ok(())
+    }
+}
+
+impl SegmentFile for EncryptedFile {
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> Result<(), KopperError> {
+        let mut chunks = self.chunks.lock().unwrap();
+        if offset + buffer.len() as u64 > chunks.len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+
+        let mut index = chunks.chunks.partition_point(|chunk| chunk.plain + chunk.len as u64 <= offset);
+        let mut filled = 0;
+        while filled < buffer.len() {
+            let from = (offset + filled as u64 - chunks.chunks[index].plain) as usize;
+            let plain = self.decrypt(&mut chunks, index)?;
+            let part = (plain.len() - from).min(buffer.len() - filled);
+            buffer[filled..filled + part].copy_from_slice(&plain[from..from + part]);
+            filled += part;
+            index += 1;
+        }
+        Ok(())
+    }
+
+    fn append(&self, data: &[u8]) -> Result<(), KopperError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let mut chunks = self.chunks.lock().unwrap();
+        self.append_chunks(&mut chunks, data)
+    }
+
+    fn len(&self) -> Result<u64, KopperError> {
+        Ok(self.chunks.lock().unwrap().len)
+    }
+
+    /// A chunk cut in the middle is written again, under a new nonce
+    fn truncate(&self, len: u64) -> Result<(), KopperError> {
+        let mut chunks = self.chunks.lock().unwrap();
+        let index = chunks.chunks.partition_point(|chunk| chunk.plain + chunk.len as u64 <= len);
+
+        let kept = match chunks.chunks.get(index) {
+            Some(chunk) if chunk.plain < len => {
+                let kept_len = (len - chunk.plain) as usize;
+                self.decrypt(&mut chunks, index)?[..kept_len].to_vec()
+            },
+            _ => Vec::new(),
+        };
+
+        let raw_len = chunks.chunks.get(index).map_or(chunks.raw_len, |chunk| chunk.raw);
+        self.inner.truncate(raw_len)?;
+        chunks.chunks.truncate(index);
+        chunks.len = chunks.chunks.last().map_or(0, |chunk| chunk.plain + chunk.len as u64);
+        chunks.raw_len = raw_len;
+        chunks.cached = None;
+
+        if !kept.is_empty() {
+            self.append_chunks(&mut chunks, &kept)?;
+        }
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<(), KopperError> {
+        self.inner.sync()
+    }
+}
+
+/// TESTS
+
+#[test]
+fn test_segments_are_encrypted() {
+    use crate::store::MemoryStore;
+
+    let memory = Arc::new(MemoryStore::new());
+    let store = EncryptedStore::new(memory.clone(), &EncryptionKey::generate());
+
+    let file = store.create("0_0").unwrap();
+    file.append(b"secret\0value\0").unwrap();
+    file.append(&vec![b'x'; 3 * MAX_CHUNK]).unwrap();
+    assert_eq!(file.len().unwrap(), 13 + 3 * MAX_CHUNK as u64);
+
+    // Read across chunk boundaries, from the store and from a handle opened again
+    let mut buffer = vec![0; 10];
+    for file in [file.clone(), store.open("0_0").unwrap()] {
+        file.read_at(&mut buffer, 7).unwrap();
+        assert_eq!(&buffer, b"value\0xxxx");
+    }
+
+    let mut raw = vec![0; memory.open("0_0").unwrap().len().unwrap() as usize];
+    memory.open("0_0").unwrap().read_at(&mut raw, 0).unwrap();
+    assert!(!raw.windows(6).any(|window| window == b"secret"));
+}
+
+#[test]
+fn test_torn_chunks_are_cut_off() {
+    use crate::store::MemoryStore;
+
+    let memory = Arc::new(MemoryStore::new());
+    let store = EncryptedStore::new(memory.clone(), &EncryptionKey::generate());
+
+    let file = store.create("0_0").unwrap();
+    file.append(b"ab\0cd\0").unwrap();
+    file.append(b"ef\0gh\0").unwrap();
+    let raw = memory.open("0_0").unwrap();
+    raw.truncate(raw.len().unwrap() - 3).unwrap();
+
+    let file = store.create("0_0").unwrap();
+    assert_eq!(file.len().unwrap(), 6);
+    file.append(b"ij\0kl\0").unwrap();
+
+    // Cut in the middle of a chunk
+    file.truncate(9).unwrap();
+    let mut buffer = vec![0; 9];
+    store.open("0_0").unwrap().read_at(&mut buffer, 0).unwrap();
+    assert_eq!(&buffer, b"ab\0cd\0ij\0");
+}
+
+#[test]
+fn test_wrong_key_is_rejected() {
+    use crate::store::MemoryStore;
+
+    let memory = Arc::new(MemoryStore::new());
+    let store = EncryptedStore::new(memory.clone(), &EncryptionKey::generate());
+    store.create("0_0").unwrap().append(b"ab\0cd\0").unwrap();
+
+    let other = EncryptedStore::new(memory.clone(), &EncryptionKey::generate());
+    let mut buffer = vec![0; 2];
+    assert!(other.open("0_0").unwrap().read_at(&mut buffer, 0).is_err());
+
+    // Short enough to pass for a torn header, if it wasn't for what it starts with
+    memory.create("1_0").unwrap().append(b"ab\0cd\0").unwrap();
+    assert!(store.open("1_0").is_err());
+    assert!(store.create("1_0").is_err());
+}
+
+#[test]
+fn test_key_from_hex() {
+    let key = EncryptionKey::generate();
+    assert_eq!(EncryptionKey::from_hex(&key.to_hex()).unwrap().0, key.0);
+    assert!(EncryptionKey::from_hex("abc").is_none());
+    assert!(EncryptionKey::from_hex(&"g".repeat(64)).is_none());
+    assert_eq!(format!("{key:?}"), "EncryptionKey(..)");
+}
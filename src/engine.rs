@@ -0,0 +1,221 @@
+//! Operations every storage engine offers, so whatever serves them - the HTTP API,
+//! the stats - doesn't have to know which engine is behind it.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::time::{Duration, Instant};
+
+use crate::kopper::KopperError;
+use crate::metrics::{MetricsSink, Stat};
+
+/// Entries of an engine in key order, created by [`KvEngine::scan`]
+pub type EngineScan = Box<dyn Iterator<Item = Result<(String, String), KopperError>> + Send>;
+
+pub trait KvEngine: Send + Sync {
+    /// Short name of the engine, e.g. for logs
+    fn name(&self) -> &'static str;
+
+    fn read(&self, key: &str) -> Result<String, KopperError>;
+
+    /// Like [`KvEngine::read`], failing with [`KopperError::TimedOut`] rather than
+    /// waiting for the engine past `deadline`. A deadline passed already fails
+    /// without reading. Engines that can't tell how long they'd wait only check it then.
+    fn read_until(&self, key: &str, deadline: Instant) -> Result<String, KopperError> {
+        if Instant::now() >= deadline {
+            return Err(KopperError::TimedOut);
+        }
+        self.read(key)
+    }
+
+    /// Stores `value` under `key`, returning the size of the database on disk
+    fn write(&self, key: &str, value: &str) -> Result<usize, KopperError>;
+
+    /// Like [`KvEngine::write`], failing with [`KopperError::TimedOut`] if the write
+    /// can't start by `deadline`. Nothing is written then - once it starts, it's
+    /// finished however late.
+    fn write_until(&self, key: &str, value: &str, deadline: Instant) -> Result<usize, KopperError> {
+        if Instant::now() >= deadline {
+            return Err(KopperError::TimedOut);
+        }
+        self.write(key, value)
+    }
+
+    /// Stores all `entries`, later ones for the same key winning, returning the
+    /// size of the database on disk. Engines that can write them together do.
+    fn write_batch(&self, entries: &[(&str, &str)]) -> Result<usize, KopperError> {
+        let mut size = self.stats()?.size;
+        for (key, value) in entries {
+            size = self.write(key, value)?;
+        }
+        Ok(size)
+    }
+
+    /// Like [`KvEngine::write`], returning only once the write is as durable as asked for.
+    /// The write is kept even if waiting fails.
+    fn write_durable(&self, key: &str, value: &str, durability: Durability) -> Result<usize, KopperError>;
+
+    /// Removes `key`, returning the size of the database on disk.
+    /// Fails with [`KopperError::KeyDoesNotExist`] if it isn't there.
+    fn delete(&self, key: &str) -> Result<usize, KopperError>;
+
+    /// Every entry in key order
+    fn scan(&self) -> Result<EngineScan, KopperError>;
+
+    /// Fails with [`KopperError::Closed`] once the engine is closed
+    fn stats(&self) -> Result<EngineStats, KopperError>;
+
+    /// Flushes everything written so far to disk and stops the engine's own
+    /// threads, if it has any. Meant for shutdown, once nothing uses it anymore.
+    fn close(&self) -> Result<(), KopperError>;
+}
+
+/// What a write waits for before it's acknowledged, on top of landing in the OS page cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// Fsynced to disk
+    Flush,
+    /// Applied by a replica, failing with [`KopperError::NotReplicated`] if none does in time
+    Replica(Duration),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EngineStats {
+    /// Number of keys currently stored
+    pub keys: usize,
+    /// Bytes on disk
+    pub size: usize,
+}
+
+/// Mirrored writes waiting for the shadow before new ones are dropped
+const SHADOW_QUEUE_SIZE: usize = 1024;
+
+enum Mirror {
+    Write(String, String),
+    Delete(String),
+}
+
+/// Serves everything from `primary`, mirroring its successful writes and deletes
+/// to `shadow` on a separate thread, so two engines can be compared on the same
+/// traffic without the shadow slowing down or failing requests.
+///
+/// Latencies of both engines go to the metrics as [`Stat::ShadowWrite`]. Writes
+/// that wait for durability are mirrored, but not measured - waiting would skew the
+/// comparison. The shadow falls behind, rather than holding up the primary, once
+/// [`SHADOW_QUEUE_SIZE`] mirrored writes wait for it; if it panics, mirroring stops.
+pub struct Shadowed {
+    primary: Arc<dyn KvEngine>,
+    shadow: Arc<dyn KvEngine>,
+    mirror: SyncSender<(Mirror, Option<Duration>)>,
+}
+
+impl Shadowed {
+    pub fn new(primary: Arc<dyn KvEngine>, shadow: Arc<dyn KvEngine>, metrics: Arc<dyn MetricsSink>) -> Self {
+        let (mirror, receiver) = mpsc::sync_channel::<(Mirror, Option<Duration>)>(SHADOW_QUEUE_SIZE);
+        let (primary_name, shadow_name) = (primary.name(), shadow.name());
+
+        // Ends once the Shadowed is dropped
+        let mirrored_to = shadow.clone();
+        std::thread::spawn(move || {
+            let mirrored = panic::catch_unwind(AssertUnwindSafe(|| {
+                for (change, primary_time) in receiver {
+                    let timer = Instant::now();
+                    let result = match &change {
+                        Mirror::Write(key, value) => mirrored_to.write(key, value).map(drop),
+                        Mirror::Delete(key) => mirrored_to.delete(key).map(drop),
+                    };
+
+                    match result {
+                        Ok(()) => if let Some(primary) = primary_time {
+                            metrics.record(Stat::ShadowWrite { primary: primary.as_nanos(), shadow: timer.elapsed().as_nanos() });
+                        },
+                        Err(err) => tracing::warn!("Shadow {shadow_name} diverged from {primary_name}: {err}"),
+                    }
+                }
+            }));
+
+            if mirrored.is_err() {
+                tracing::error!("Shadow {shadow_name} panicked, no longer mirroring {primary_name}");
+            }
+        });
+
+        Shadowed { primary, shadow, mirror }
+    }
+
+    fn mirror(&self, change: Mirror, primary_time: Option<Duration>) {
+        match self.mirror.try_send((change, primary_time)) {
+            Ok(()) => {},
+            Err(TrySendError::Full(_)) => tracing::warn!("Shadow of {} can't keep up, dropped a write", self.primary.name()),
+            Err(TrySendError::Disconnected(_)) => {}, // Already reported by the mirroring thread
+        }
+    }
+}
+
+impl KvEngine for Shadowed {
+    fn name(&self) -> &'static str {
+        self.primary.name()
+    }
+
+    fn read(&self, key: &str) -> Result<String, KopperError> {
+        self.primary.read(key)
+    }
+
+    fn read_until(&self, key: &str, deadline: Instant) -> Result<String, KopperError> {
+        self.primary.read_until(key, deadline)
+    }
+
+    fn write(&self, key: &str, value: &str) -> Result<usize, KopperError> {
+        let timer = Instant::now();
+        let size = self.primary.write(key, value)?;
+        self.mirror(Mirror::Write(key.to_owned(), value.to_owned()), Some(timer.elapsed()));
+        Ok(size)
+    }
+
+    fn write_until(&self, key: &str, value: &str, deadline: Instant) -> Result<usize, KopperError> {
+        let timer = Instant::now();
+        let size = self.primary.write_until(key, value, deadline)?;
+        self.mirror(Mirror::Write(key.to_owned(), value.to_owned()), Some(timer.elapsed()));
+        Ok(size)
+    }
+
+    fn write_batch(&self, entries: &[(&str, &str)]) -> Result<usize, KopperError> {
+        let size = self.primary.write_batch(entries)?;
+        // Not measured, the time of a whole batch isn't the time of one write
+        for (key, value) in entries {
+            self.mirror(Mirror::Write((*key).to_owned(), (*value).to_owned()), None);
+        }
+        Ok(size)
+    }
+
+    fn write_durable(&self, key: &str, value: &str, durability: Durability) -> Result<usize, KopperError> {
+        let written = self.primary.write_durable(key, value, durability);
+        // Writes no replica confirmed in time are still kept
+        if matches!(written, Ok(_) | Err(KopperError::NotReplicated(_))) {
+            self.mirror(Mirror::Write(key.to_owned(), value.to_owned()), None);
+        }
+        written
+    }
+
+    fn delete(&self, key: &str) -> Result<usize, KopperError> {
+        let timer = Instant::now();
+        let size = self.primary.delete(key)?;
+        self.mirror(Mirror::Delete(key.to_owned()), Some(timer.elapsed()));
+        Ok(size)
+    }
+
+    fn scan(&self) -> Result<EngineScan, KopperError> {
+        self.primary.scan()
+    }
+
+    fn stats(&self) -> Result<EngineStats, KopperError> {
+        self.primary.stats()
+    }
+
+    /// Closes both engines. Writes still waiting to be mirrored then fail on the
+    /// shadow, which only diverges it.
+    fn close(&self) -> Result<(), KopperError> {
+        let primary = self.primary.close();
+        self.shadow.close()?;
+        primary
+    }
+}
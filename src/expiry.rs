@@ -0,0 +1,52 @@
+//! Values that expire, see [`Kopper::write_with_ttl`](crate::kopper::Kopper::write_with_ttl).
+//! Their records start with a header - a flag byte and when the value expires, in
+//! milliseconds since the Unix epoch - followed by the value as it's stored
+//! otherwise. Like the other flags, it never starts valid UTF-8.
+
+const EXPIRING: u8 = 0xFA;
+
+/// Flag and time, in hex so a record never holds a NUL
+pub const HEADER_LEN: usize = 1 + 16;
+
+/// `stored` behind the header of a value expiring at `expires_at`
+pub fn wrap(expires_at: u64, stored: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(HEADER_LEN + stored.len());
+    record.push(EXPIRING);
+    record.extend_from_slice(format!("{expires_at:016x}").as_bytes());
+    record.extend_from_slice(stored);
+    record
+}
+
+/// Whether a value starting with `byte` may expire
+pub fn flagged(byte: u8) -> bool {
+    byte == EXPIRING
+}
+
+/// When the value starting with `stored` expires, `None` if it doesn't
+pub fn parse(stored: &[u8]) -> Option<u64> {
+    if stored.first() != Some(&EXPIRING) {
+        return None;
+    }
+    let expires_at = std::str::from_utf8(stored.get(1..HEADER_LEN)?).ok()?;
+    u64::from_str_radix(expires_at, 16).ok()
+}
+
+/// Value stored as `stored`, without the header if it expires
+pub fn strip(mut stored: Vec<u8>) -> Vec<u8> {
+    if parse(&stored).is_some() {
+        stored.drain(..HEADER_LEN);
+    }
+    stored
+}
+
+/// TESTS
+
+#[test]
+fn test_expiry_parses_back() {
+    let record = wrap(1_700_000_000_000, b"value");
+    assert!(!record.contains(&0));
+    assert_eq!(parse(&record), Some(1_700_000_000_000));
+    assert_eq!(strip(record), b"value");
+    assert_eq!(parse(b"value"), None);
+    assert_eq!(strip(b"\xFAvalue".to_vec()), b"\xFAvalue");
+}
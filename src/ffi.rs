@@ -0,0 +1,186 @@
+//! C API of the engine, enabled with the `kopper-ffi` feature. Building with it
+//! regenerates `include/kopper.h`.
+//!
+//! ```c
+//! Kopper *db = kopper_open("data", 4096);
+//! kopper_put(db, "key", "value");
+//!
+//! char *value;
+//! if (kopper_get(db, "key", &value) == KOPPER_OK) {
+//!     puts(value);
+//!     kopper_free_string(value);
+//! }
+//! kopper_close(db);
+//! ```
+
+use std::ffi::{c_char, CStr, CString};
+
+use crate::kopper::{self, KopperError};
+
+/// Result of every call taking a database. Variants are named the way C code spells them.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, PartialEq)]
+pub enum KopperStatus {
+    KOPPER_OK = 0,
+    KOPPER_NOT_FOUND = 1,
+    /// Null pointer, or a string that isn't valid UTF-8
+    KOPPER_INVALID_ARGUMENT = 2,
+    KOPPER_ERROR = 3,
+}
+
+/// Opaque handle of an open database
+pub struct Kopper(kopper::Kopper);
+
+impl From<KopperError> for KopperStatus {
+    fn from(err: KopperError) -> Self {
+        match err {
+            KopperError::KeyDoesNotExist(_) => KopperStatus::KOPPER_NOT_FOUND,
+            _ => KopperStatus::KOPPER_ERROR,
+        }
+    }
+}
+
+/// Borrows a C string, `None` for null pointers or invalid UTF-8
+///
+/// # Safety
+/// `string` must be null or point to a NUL-terminated string.
+unsafe fn borrow<'a>(string: *const c_char) -> Option<&'a str> {
+    if string.is_null() {
+        return None;
+    }
+    CStr::from_ptr(string).to_str().ok()
+}
+
+/// Opens the database at `path`, creating it if needed. Returns null on failure.
+///
+/// # Safety
+/// `path` must be null or point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn kopper_open(path: *const c_char, segment_size: usize) -> *mut Kopper {
+    let Some(path) = borrow(path) else {
+        return std::ptr::null_mut();
+    };
+
+    match kopper::Kopper::create(path, segment_size) {
+        Ok(db) => Box::into_raw(Box::new(Kopper(db))),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Reads `key`. On success `*value` points to a copy of the value, to be freed
+/// with [`kopper_free_string`].
+///
+/// # Safety
+/// `db` must come from [`kopper_open`], `key` must be null or NUL-terminated,
+/// `value` must be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn kopper_get(db: *const Kopper, key: *const c_char, value: *mut *mut c_char) -> KopperStatus {
+    let (Some(db), Some(key)) = (db.as_ref(), borrow(key)) else {
+        return KopperStatus::KOPPER_INVALID_ARGUMENT;
+    };
+    if value.is_null() {
+        return KopperStatus::KOPPER_INVALID_ARGUMENT;
+    }
+
+    match db.0.read(key) {
+        // Values are stored NUL-terminated, so they can't contain one
+        Ok(read) => match CString::new(read) {
+            Ok(read) => {
+                *value = read.into_raw();
+                KopperStatus::KOPPER_OK
+            },
+            Err(_) => KopperStatus::KOPPER_ERROR,
+        },
+        Err(err) => err.into(),
+    }
+}
+
+/// Writes `value` under `key`
+///
+/// # Safety
+/// `db` must come from [`kopper_open`], `key` and `value` must be null or NUL-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn kopper_put(db: *const Kopper, key: *const c_char, value: *const c_char) -> KopperStatus {
+    let (Some(db), Some(key), Some(value)) = (db.as_ref(), borrow(key), borrow(value)) else {
+        return KopperStatus::KOPPER_INVALID_ARGUMENT;
+    };
+    if key.is_empty() {
+        return KopperStatus::KOPPER_INVALID_ARGUMENT;
+    }
+
+    match db.0.write(key, value) {
+        Ok(_) => KopperStatus::KOPPER_OK,
+        Err(err) => err.into(),
+    }
+}
+
+/// Removes `key`
+///
+/// # Safety
+/// `db` must come from [`kopper_open`], `key` must be null or NUL-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn kopper_delete(db: *const Kopper, key: *const c_char) -> KopperStatus {
+    let (Some(db), Some(key)) = (db.as_ref(), borrow(key)) else {
+        return KopperStatus::KOPPER_INVALID_ARGUMENT;
+    };
+
+    match db.0.delete(key) {
+        Ok(_) => KopperStatus::KOPPER_OK,
+        Err(err) => err.into(),
+    }
+}
+
+/// Flushes and closes the database, freeing the handle. Null is ignored.
+///
+/// # Safety
+/// `db` must be null or come from [`kopper_open`], and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn kopper_close(db: *mut Kopper) -> KopperStatus {
+    if db.is_null() {
+        return KopperStatus::KOPPER_OK;
+    }
+
+    match Box::from_raw(db).0.close() {
+        Ok(_) => KopperStatus::KOPPER_OK,
+        Err(err) => err.into(),
+    }
+}
+
+/// Frees a value returned by [`kopper_get`]. Null is ignored.
+///
+/// # Safety
+/// `string` must be null or come from [`kopper_get`], and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn kopper_free_string(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+/// TESTS
+
+#[test]
+fn test_ffi_roundtrip() {
+    let db = crate::testing::TempDb::new();
+    let path = CString::new(db.join("ffi")).unwrap();
+
+    unsafe {
+        let db = kopper_open(path.as_ptr(), 100);
+        assert!(!db.is_null());
+
+        let key = CString::new("key").unwrap();
+        let value = CString::new("value").unwrap();
+        assert_eq!(kopper_put(db, key.as_ptr(), value.as_ptr()), KopperStatus::KOPPER_OK);
+
+        let mut read = std::ptr::null_mut();
+        assert_eq!(kopper_get(db, key.as_ptr(), &mut read), KopperStatus::KOPPER_OK);
+        assert_eq!(CStr::from_ptr(read).to_str(), Ok("value"));
+        kopper_free_string(read);
+
+        assert_eq!(kopper_delete(db, key.as_ptr()), KopperStatus::KOPPER_OK);
+        assert_eq!(kopper_get(db, key.as_ptr(), &mut read), KopperStatus::KOPPER_NOT_FOUND);
+        assert_eq!(kopper_get(db, std::ptr::null(), &mut read), KopperStatus::KOPPER_INVALID_ARGUMENT);
+        assert_eq!(kopper_close(db), KopperStatus::KOPPER_OK);
+    }
+}
@@ -0,0 +1,239 @@
+// Every handler returns tonic's (big) Status as the error anyway
+#![allow(clippy::result_large_err)]
+
+use std::pin::Pin;
+
+use rocket::{Rocket, Orbit};
+use rocket::fairing::AdHoc;
+use rocket::futures::{Stream, stream};
+use rocket::tokio::{self, sync::mpsc};
+use tonic::{Request, Response, Status};
+
+use kopperdb::kopper::{self, Kopper, KopperError};
+
+mod proto {
+    tonic::include_proto!("kopper");
+}
+
+use proto::kopper_server::KopperServer;
+use proto::*;
+
+/// Entries read ahead of a slow Scan client
+const ENTRIES_IN_FLIGHT: usize = 64;
+
+/// Fairing starting the gRPC service at `grpc_address` from Rocket's config, if
+/// there's one. Shares the database with the HTTP API and stops with the server.
+pub fn listener() -> AdHoc {
+    AdHoc::on_liftoff("gRPC listener", |rocket| Box::pin(async move {
+        let Ok(address) = rocket.figment().extract_inner::<String>("grpc_address") else {
+            return;
+        };
+        let address = match address.parse() {
+            Ok(address) => address,
+            Err(err) => return tracing::error!("Bad grpc_address {address}: {err}"),
+        };
+
+        let service = KopperServer::new(Service { db: db(rocket) });
+        let shutdown = rocket.shutdown();
+        tokio::spawn(async move {
+            tracing::info!("gRPC listener on {address}");
+            let server = tonic::transport::Server::builder()
+                .add_service(service)
+                .serve_with_shutdown(address, shutdown);
+            if let Err(err) = server.await {
+                tracing::error!("gRPC listener on {address} failed: {err}");
+            }
+        });
+    }))
+}
+
+fn db(rocket: &Rocket<Orbit>) -> Kopper {
+    rocket.state::<Kopper>().expect("Kopper is managed").clone()
+}
+
+fn status(err: KopperError) -> Status {
+    match err {
+        KopperError::KeyDoesNotExist(key) => Status::not_found(format!("{key} does not exist")),
+        KopperError::Closed => Status::unavailable("Database is closed"),
+        KopperError::ReadOnly => Status::failed_precondition("Database is read-only"),
+        KopperError::ChangesUnavailable(sequence) => Status::out_of_range(format!("Changes after {sequence} aren't available")),
+        KopperError::NotReplicated(sequence) => Status::deadline_exceeded(format!("No replica confirmed change {sequence} in time")),
+        KopperError::InvalidBackup(message) => Status::failed_precondition(message),
+        KopperError::Unsupported(what) => Status::unimplemented(format!("{what} isn't supported")),
+        KopperError::DatabaseFull => Status::resource_exhausted("Out of disk space"),
+        KopperError::Degraded(reason) => Status::unavailable(format!("Database is degraded: {reason}")),
+        KopperError::Recovering => Status::unavailable("Database is still being recovered"),
+        KopperError::QuotaExceeded(limit) => Status::resource_exhausted(format!("Quota of {limit} exceeded")),
+        KopperError::Backpressure => Status::unavailable("Compaction is behind, try again later"),
+        KopperError::WrongType(key) => Status::failed_precondition(format!("{key} holds another type of value")),
+        KopperError::Rejected(reason) => Status::invalid_argument(format!("Rejected: {reason}")),
+        KopperError::Quarantined(segment) => Status::data_loss(format!("Segment {segment} is quarantined as corrupt")),
+        KopperError::TimedOut => Status::deadline_exceeded("Gave up waiting for the database"),
+        KopperError::InternalError(err) => {
+            tracing::error!("{err}");
+            Status::internal("Internal error")
+        }
+    }
+}
+
+/// Records are NUL separated, so neither keys nor values can contain one
+fn validate(key: &str, value: &str) -> Result<(), Status> {
+    if key.is_empty() || key.contains('\0') || value.contains('\0') {
+        return Err(Status::invalid_argument("Keys can't be empty, keys and values can't contain NUL"));
+    }
+    Ok(())
+}
+
+/// Runs blocking database work off the async runtime
+async fn blocking<T: Send + 'static>(work: impl FnOnce() -> Result<T, KopperError> + Send + 'static) -> Result<T, Status> {
+    tokio::task::spawn_blocking(work)
+        .await
+        .map_err(|err| status(KopperError::InternalError(err.into())))?
+        .map_err(status)
+}
+
+type ResponseStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+/// Turns a receiver fed by a blocking thread into a response stream
+fn into_stream<T: Send + 'static>(receiver: mpsc::Receiver<Result<T, Status>>) -> ResponseStream<T> {
+    Box::pin(stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|item| (item, receiver))
+    }))
+}
+
+struct Service {
+    db: Kopper,
+}
+
+#[tonic::async_trait]
+impl kopper_server::Kopper for Service {
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        let GetRequest { key } = request.into_inner();
+        let db = self.db.clone();
+        let value = blocking(move || db.read(&key)).await?;
+        Ok(Response::new(GetResponse { value }))
+    }
+
+    async fn put(&self, request: Request<PutRequest>) -> Result<Response<PutResponse>, Status> {
+        let PutRequest { key, value } = request.into_inner();
+        validate(&key, &value)?;
+
+        let db = self.db.clone();
+        let commit = blocking(move || db.write(&key, &value)).await?;
+        Ok(Response::new(PutResponse { size: commit.size as u64 }))
+    }
+
+    async fn delete(&self, request: Request<DeleteRequest>) -> Result<Response<DeleteResponse>, Status> {
+        let DeleteRequest { key } = request.into_inner();
+        let db = self.db.clone();
+        let commit = blocking(move || db.delete(&key)).await?;
+        Ok(Response::new(DeleteResponse { size: commit.size as u64 }))
+    }
+
+    async fn batch_put(&self, request: Request<BatchPutRequest>) -> Result<Response<PutResponse>, Status> {
+        let BatchPutRequest { entries } = request.into_inner();
+        for entry in &entries {
+            validate(&entry.key, &entry.value)?;
+        }
+
+        let db = self.db.clone();
+        let commit = blocking(move || {
+            let entries: Vec<(&str, &str)> = entries.iter().map(|e| (e.key.as_str(), e.value.as_str())).collect();
+            db.write_batch(&entries)
+        }).await?;
+        Ok(Response::new(PutResponse { size: commit.size as u64 }))
+    }
+
+    type ScanStream = ResponseStream<Entry>;
+
+    async fn scan(&self, request: Request<ScanRequest>) -> Result<Response<Self::ScanStream>, Status> {
+        let ScanRequest { prefix } = request.into_inner();
+        let scan = self.db.scan().map_err(status)?.filter_keys(|key| key.starts_with(&prefix));
+
+        // Reading values is blocking IO, same as in the HTTP export
+        let (sender, receiver) = mpsc::channel(ENTRIES_IN_FLIGHT);
+        std::thread::spawn(move || {
+            for entry in scan {
+                let entry = match entry {
+                    Ok((key, value)) => Ok(Entry { key, value }),
+                    Err(err) => Err(status(err)),
+                };
+                let failed = entry.is_err();
+                if sender.blocking_send(entry).is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(into_stream(receiver)))
+    }
+
+    type WatchStream = ResponseStream<ChangeEvent>;
+
+    async fn watch(&self, request: Request<WatchRequest>) -> Result<Response<Self::WatchStream>, Status> {
+        let WatchRequest { prefix } = request.into_inner();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let subscription = self.db.watch_into(&prefix, move |event| sender.send(event).is_ok());
+
+        // The stream owns the subscription, so it ends once the client is gone and tonic drops the stream
+        Ok(Response::new(Box::pin(stream::unfold((receiver, subscription), |(mut receiver, subscription)| async move {
+            let event = match receiver.recv().await? {
+                kopper::ChangeEvent::Write { key, value } => ChangeEvent { kind: change_event::Kind::Write.into(), key, value },
+                kopper::ChangeEvent::Delete { key } => ChangeEvent { kind: change_event::Kind::Delete.into(), key, value: String::new() },
+            };
+            Some((Ok(event), (receiver, subscription)))
+        }))))
+    }
+}
+
+/// TESTS
+
+#[rocket::async_test]
+async fn test_service_reads_writes_scans_and_unsubscribes_dropped_watches() {
+    use std::time::Duration;
+    use rocket::futures::StreamExt;
+    use tonic::Code;
+    use tonic::transport::server::TcpIncoming;
+    use proto::kopper_client::KopperClient;
+
+    let temp = kopperdb::testing::TempDb::new();
+    let db = temp.kopper(100).unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+    tokio::spawn(tonic::transport::Server::builder()
+        .add_service(KopperServer::new(Service { db: db.clone() }))
+        .serve_with_incoming(incoming));
+    let mut client = KopperClient::connect(format!("http://{address}")).await.unwrap();
+
+    let put = |key: &str, value: &str| PutRequest { key: key.to_owned(), value: value.to_owned() };
+    for (key, value) in [("a:1", "one"), ("a:2", "two"), ("b:1", "three")] {
+        client.put(put(key, value)).await.unwrap();
+    }
+    let get = client.get(GetRequest { key: "a:2".to_owned() }).await.unwrap();
+    assert_eq!(get.into_inner().value, "two");
+    let missing = client.get(GetRequest { key: "c:1".to_owned() }).await.unwrap_err();
+    assert_eq!(missing.code(), Code::NotFound);
+    assert_eq!(client.put(put("a:3", "with \0")).await.unwrap_err().code(), Code::InvalidArgument);
+
+    // Only keys with the prefix, in order
+    let scan = client.scan(ScanRequest { prefix: "a:".to_owned() }).await.unwrap().into_inner();
+    let entries: Vec<(String, String)> = scan.map(|entry| entry.map(|entry| (entry.key, entry.value)).unwrap()).collect().await;
+    assert_eq!(entries, [("a:1".to_owned(), "one".to_owned()), ("a:2".to_owned(), "two".to_owned())]);
+
+    let mut watch = client.watch(WatchRequest { prefix: "b:".to_owned() }).await.unwrap().into_inner();
+    client.put(put("a:1", "ignored")).await.unwrap();
+    client.put(put("b:2", "four")).await.unwrap();
+    let event = watch.message().await.unwrap().unwrap();
+    assert_eq!((event.kind(), event.key.as_str(), event.value.as_str()), (change_event::Kind::Write, "b:2", "four"));
+    assert_eq!(db.watchers(), 1);
+
+    // The subscription ends with the stream, without waiting for another change
+    drop(watch);
+    let unsubscribed = async {
+        while db.watchers() > 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), unsubscribed).await.expect("Watch wasn't unsubscribed");
+}
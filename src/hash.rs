@@ -0,0 +1,79 @@
+//! Hashes - values made of fields, see [`Kopper::hset`](crate::kopper::Kopper::hset).
+//! Changed a field at a time, see [`chain`] - a record lists `= | field | value`
+//! for every field set and `- | field` for every field deleted, or set for the
+//! whole hash. A hash reads as a JSON object of its fields, in order.
+
+use std::collections::BTreeMap;
+
+use crate::chain::{self, Header, Kind};
+use crate::kopper::KopperError;
+
+const SET: u8 = b'=';
+const DELETED: u8 = b'-';
+
+/// Record of the whole hash of `fields`
+pub fn full<'a>(fields: impl IntoIterator<Item = (&'a String, &'a String)>) -> Vec<u8> {
+    let mut record = chain::full(Kind::Hash);
+    for (field, value) in fields {
+        record.push(SET);
+        chain::push(&mut record, field);
+        chain::push(&mut record, value);
+    }
+    record
+}
+
+/// Record of fields `set` and `deleted`, changing the hash recorded at `offset`
+/// of the same segment - `len` long, with a header `previous`
+pub fn delta(offset: u64, len: usize, previous: Header, set: &[(&str, &str)], deleted: &[&str]) -> Vec<u8> {
+    let mut record = chain::delta(Kind::Hash, offset, len, previous);
+    for (field, value) in set {
+        record.push(SET);
+        chain::push(&mut record, field);
+        chain::push(&mut record, value);
+    }
+    for field in deleted {
+        record.push(DELETED);
+        chain::push(&mut record, field);
+    }
+    record
+}
+
+/// Fields of the hash whose newest record is `record`, see [`chain::records`]
+pub fn fields(record: Vec<u8>, read: impl FnMut(u64, usize) -> Result<Vec<u8>, KopperError>) -> Result<BTreeMap<String, String>, KopperError> {
+    let mut fields = BTreeMap::new();
+    for record in chain::records(Kind::Hash, record, read)? {
+        let mut ops = chain::body(&record);
+        while let [op, rest @ ..] = ops {
+            ops = rest;
+            let field = chain::take(&mut ops)?;
+            match *op {
+                SET => fields.insert(field.to_owned(), chain::take(&mut ops)?.to_owned()),
+                DELETED => fields.remove(field),
+                _ => return Err(chain::damaged()),
+            };
+        }
+    }
+    Ok(fields)
+}
+
+/// How a hash reads
+pub fn to_json(fields: &BTreeMap<String, String>) -> String {
+    // Serializing strings can't fail
+    serde_json::to_string(fields).unwrap()
+}
+
+/// TESTS
+
+#[test]
+fn test_fields_change_one_by_one() {
+    let whole = BTreeMap::from([("name".to_owned(), "kopper".to_owned()), ("stars".to_owned(), "1".to_owned())]);
+    let mut segment = vec![full(&whole)];
+    segment.push(delta(0, segment[0].len(), Header::Full, &[("stars", "2"), ("lang", "rust")], &["name"]));
+
+    let read = |offset: u64, len: usize| {
+        assert_eq!(segment[offset as usize].len(), len);
+        Ok(segment[offset as usize].clone())
+    };
+    let hash = fields(segment[1].clone(), read).unwrap();
+    assert_eq!(to_json(&hash), r#"{"lang":"rust","stars":"2"}"#);
+}
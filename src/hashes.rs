@@ -0,0 +1,107 @@
+//! Routes for hashes, see [`Kopper::hset`]. Fields are set, read and deleted one
+//! at a time, without rewriting the rest of the hash, or read back all at once.
+
+use std::collections::BTreeMap;
+
+use rocket::State;
+use rocket::serde::json::Json;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use kopperdb::kopper::{Kopper, KopperError};
+
+#[derive(Serialize, ToSchema)]
+pub struct FieldCountResponse {
+    /// Fields added or deleted, 0 if only a value changed, or on failure
+    changed: usize,
+    /// "OK" on success, description of the problem otherwise
+    error: String
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct FieldResponse {
+    /// Empty if there's no such field
+    value: String,
+    /// "OK" on success, description of the problem otherwise
+    error: String
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct FieldsResponse {
+    /// In order, none if there's no such hash
+    fields: BTreeMap<String, String>,
+    /// "OK" on success, description of the problem otherwise
+    error: String
+}
+
+fn changed(changed: Result<usize, KopperError>) -> Json<FieldCountResponse> {
+    Json(match changed {
+        Ok(changed) => FieldCountResponse { changed, error: "OK".to_string() },
+        Err(err) => FieldCountResponse { changed: 0, error: format!("Error while writing! : {err}") },
+    })
+}
+
+#[utoipa::path(
+    post,
+    path = "/hset/{key}/{field}/{value}",
+    tag = "hashes",
+    params(
+        ("key" = String, Path, description = "Key of the hash, created if there's none"),
+        ("field" = String, Path, description = "Field to set"),
+        ("value" = String, Path, description = "New value of the field")
+    ),
+    responses((status = 200, description = "Result of the write, 1 if the field is new", body = FieldCountResponse))
+)]
+#[post("/hset/<key>/<field>/<value>")]
+pub fn hset(key: &str, field: &str, value: &str, db: &State<Kopper>) -> Json<FieldCountResponse> {
+    changed(db.hset(key, &[(field, value)]))
+}
+
+#[utoipa::path(
+    get,
+    path = "/hget/{key}/{field}",
+    tag = "hashes",
+    params(
+        ("key" = String, Path, description = "Key of the hash"),
+        ("field" = String, Path, description = "Field to read")
+    ),
+    responses((status = 200, description = "Value of the field", body = FieldResponse))
+)]
+#[get("/hget/<key>/<field>")]
+pub fn hget(key: &str, field: &str, db: &State<Kopper>) -> Json<FieldResponse> {
+    Json(match db.hget(key, field) {
+        Ok(Some(value)) => FieldResponse { value, error: "OK".to_string() },
+        Ok(None) => FieldResponse { value: String::new(), error: format!("{key} has no field {field}!") },
+        Err(err) => FieldResponse { value: String::new(), error: format!("Error while reading! : {err}") },
+    })
+}
+
+#[utoipa::path(
+    post,
+    path = "/hdel/{key}/{field}",
+    tag = "hashes",
+    params(
+        ("key" = String, Path, description = "Key of the hash, deleted once it has no fields"),
+        ("field" = String, Path, description = "Field to delete")
+    ),
+    responses((status = 200, description = "Result of the deletion", body = FieldCountResponse))
+)]
+#[post("/hdel/<key>/<field>")]
+pub fn hdel(key: &str, field: &str, db: &State<Kopper>) -> Json<FieldCountResponse> {
+    changed(db.hdel(key, &[field]))
+}
+
+#[utoipa::path(
+    get,
+    path = "/hgetall/{key}",
+    tag = "hashes",
+    params(("key" = String, Path, description = "Key of the hash")),
+    responses((status = 200, description = "Fields of the hash", body = FieldsResponse))
+)]
+#[get("/hgetall/<key>")]
+pub fn hgetall(key: &str, db: &State<Kopper>) -> Json<FieldsResponse> {
+    Json(match db.hgetall(key) {
+        Ok(fields) => FieldsResponse { fields, error: "OK".to_string() },
+        Err(err) => FieldsResponse { fields: BTreeMap::new(), error: format!("Error while reading! : {err}") },
+    })
+}
@@ -0,0 +1,62 @@
+//! Hooks embedders plug into the engine, see [`Kopper::on_write`]. Each one may
+//! reject the operation with an error - e.g. [`KopperError::Rejected`] - and
+//! write and read hooks may change the value on the way, e.g. to encrypt values
+//! of some keys. Write and delete hooks run in the order they were added, read
+//! hooks in the opposite order, so a read hook added along with a write hook
+//! sees the value the way that write hook left it.
+//!
+//! Hooks run with the database locked, so they must not call into it.
+//!
+//! [`Kopper::on_write`]: crate::kopper::Kopper::on_write
+
+use std::sync::Arc;
+
+use crate::kopper::KopperError;
+
+/// Given a key and its value, returns the value to go on with
+pub type ValueHook = Arc<dyn Fn(&str, String) -> Result<String, KopperError> + Send + Sync>;
+/// Given a key about to be deleted
+pub type DeleteHook = Arc<dyn Fn(&str) -> Result<(), KopperError> + Send + Sync>;
+
+#[derive(Clone, Default)]
+pub struct Hooks {
+    pub write: Vec<ValueHook>,
+    pub read: Vec<ValueHook>,
+    pub delete: Vec<DeleteHook>,
+}
+
+impl Hooks {
+    /// Value to store for `value` being written under `key`
+    pub fn written(&self, key: &str, value: &str) -> Result<String, KopperError> {
+        self.write.iter().try_fold(value.to_owned(), |value, hook| hook(key, value))
+    }
+
+    /// Value to return for `value` read from `key`
+    pub fn read(&self, key: &str, value: String) -> Result<String, KopperError> {
+        self.read.iter().rev().try_fold(value, |value, hook| hook(key, value))
+    }
+
+    pub fn deleted(&self, key: &str) -> Result<(), KopperError> {
+        self.delete.iter().try_for_each(|hook| hook(key))
+    }
+}
+
+/// TESTS
+
+#[test]
+fn test_read_hooks_undo_write_hooks_in_reverse() {
+    let mut hooks = Hooks::default();
+    hooks.write.push(Arc::new(|_, value| Ok(format!("<{value}>"))));
+    hooks.write.push(Arc::new(|key, value| match value.len() > 10 {
+        true => Err(KopperError::Rejected(format!("{key} is too long"))),
+        false => Ok(value.to_uppercase()),
+    }));
+    hooks.read.push(Arc::new(|_, value| Ok(value[1..value.len() - 1].to_owned())));
+    hooks.read.push(Arc::new(|_, value| Ok(value.to_lowercase())));
+
+    let stored = hooks.written("key", "value").unwrap();
+    assert_eq!(stored, "<VALUE>");
+    assert_eq!(hooks.read("key", stored).unwrap(), "value");
+    assert!(matches!(hooks.written("key", "long value"), Err(KopperError::Rejected(_))));
+    assert!(hooks.deleted("key").is_ok());
+}
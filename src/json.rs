@@ -0,0 +1,67 @@
+//! Route patching JSON values in place, see [`kopperdb::merge_patch`]. Clients
+//! send the members that change rather than the whole document.
+
+use std::sync::Arc;
+
+use rocket::State;
+use rocket::serde::json::Json;
+use serde::Serialize;
+use serde_json::Value;
+use utoipa::ToSchema;
+
+use kopperdb::kopper::{Kopper, KopperError};
+use kopperdb::merge_patch;
+use kopperdb::schema::Schemas;
+
+/// Times a patch is reapplied when the value changes under it before giving up
+const PATCH_ATTEMPTS: usize = 16;
+
+#[derive(Serialize, ToSchema)]
+pub struct PatchResponse {
+    /// Document as patched, empty on failure
+    value: String,
+    /// "OK" on success, description of the problem otherwise
+    error: String
+}
+
+/// Document stored under `key`, patched with `patch`, or the problem
+fn patch_value(key: &str, patch: &Value, db: &Kopper, schemas: &Schemas) -> Result<String, String> {
+    for _ in 0..PATCH_ATTEMPTS {
+        let current = match db.read(key) {
+            Ok(current) => Some(current),
+            Err(KopperError::KeyDoesNotExist(_)) => None,
+            Err(err) => return Err(format!("Error while reading! : {err}")),
+        };
+        let mut document = match &current {
+            Some(current) => serde_json::from_str(current).map_err(|_| format!("{key} does not hold JSON!"))?,
+            None => Value::Null,
+        };
+        merge_patch::apply(&mut document, patch);
+
+        let patched = document.to_string();
+        schemas.check(key, &patched).map_err(|err| format!("Error while writing! : {err}"))?;
+        // Written only if nothing else changed the value meanwhile, read again otherwise
+        match db.compare_and_swap(key, current.as_deref(), &patched) {
+            Ok(true) => return Ok(patched),
+            Ok(false) => continue,
+            Err(err) => return Err(format!("Error while writing! : {err}")),
+        }
+    }
+    Err(format!("{key} kept changing, patch not applied!"))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/json/{key}",
+    tag = "kopper",
+    params(("key" = String, Path, description = "Key of the JSON document, created if there's none")),
+    request_body(content = Object, description = "Merge patch, RFC 7386", content_type = "application/merge-patch+json"),
+    responses((status = 200, description = "Result of the patch", body = PatchResponse))
+)]
+#[patch("/json/<key>", data = "<patch>")]
+pub fn patch_json(key: &str, patch: Json<Value>, db: &State<Kopper>, schemas: &State<Arc<Schemas>>) -> Json<PatchResponse> {
+    Json(match patch_value(key, &patch, db, schemas) {
+        Ok(value) => PatchResponse { value, error: "OK".to_string() },
+        Err(error) => PatchResponse { value: String::new(), error },
+    })
+}
@@ -0,0 +1,27 @@
+//! Keys the database keeps for itself, like deleted values kept for
+//! [`Kopper::undelete`](crate::kopper::Kopper::undelete). They start with
+//! [`RESERVED`], which user keys can't, so users can neither overwrite nor delete
+//! them. Reserved keys are stored, compacted and replicated like any other, but
+//! left out of scans, counts, quotas, eviction and watchers.
+
+/// First character of every reserved key
+pub const RESERVED: char = '\u{1}';
+
+/// Reserved key `kind` keeps for `key`
+pub fn reserved_key(kind: &str, key: &str) -> String {
+    format!("{RESERVED}{kind}{RESERVED}{key}")
+}
+
+pub fn is_reserved(key: &str) -> bool {
+    key.starts_with(RESERVED)
+}
+
+/// TESTS
+
+#[test]
+fn test_reserved_keys_are_told_apart_by_kind_and_key() {
+    assert!(is_reserved(&reserved_key("deleted", "a")));
+    assert!(!is_reserved("a"));
+    assert_ne!(reserved_key("deleted", "a"), reserved_key("deleted", "a:b"));
+    assert_ne!(reserved_key("deleted", "a"), reserved_key("versions", "a"));
+}
@@ -1,22 +1,73 @@
 use std::{
-    collections::{HashMap, BTreeMap}, 
-    sync::{Mutex, mpsc::channel}, 
-    sync::{Arc, mpsc::{Sender, Receiver}}, 
-    fs::{File, OpenOptions, self}, 
-    io::{Write, Read, self, Seek, SeekFrom},
+    collections::{HashMap, HashSet, BTreeMap, BTreeSet, VecDeque}, 
+    sync::{Mutex, PoisonError, mpsc::channel}, 
+    sync::{Arc, mpsc::{Sender, SendError, Receiver, RecvTimeoutError}}, 
+    sync::atomic::{AtomicUsize, Ordering}, 
+    thread::JoinHandle,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    hash::{Hash, Hasher},
+    fs::{File, self}, 
+    path::Path,
     fmt::Display, 
     str::FromStr, 
-    ops::Add
+    ops::{Add, RangeBounds}
 };
 
+use parking_lot::{Condvar, MutexGuard};
+
+use crate::engine::{Durability, EngineScan, EngineStats, KvEngine};
 use crate::from_error;
+use crate::manifest::{self, BackupManifest, SegmentRecord, MANIFEST_VERSION};
+use crate::clock::{Clock, SystemClock};
+use crate::compression::{self, Compression, CompressionStats};
+use crate::dedupe::{self, Record};
+use crate::doctor::{self, Check, Report};
+use crate::verify::{self, VerifyStats};
+use crate::saturation::{Contention, Saturation};
+use crate::expiry;
+use crate::meta::{self, Meta};
+use crate::chain;
+use crate::list;
+use crate::queue;
+use crate::lock;
+use crate::versions;
+use crate::trash;
+use crate::keyspace;
+use crate::packed::{self, Packing};
+use crate::segment_log::{self, SegmentLog, Segments};
+use crate::pubsub::Channels;
+use crate::set;
+use crate::hash;
+use crate::counter;
+use crate::pattern::KeyPattern;
+use crate::numeric_index::{Extractor, NumericIndex};
+use crate::hooks::Hooks;
+use crate::metrics::{MetricsSink, Stat};
+use crate::store::{LocalStore, SegmentFile, SegmentStore, Tier};
+#[cfg(feature = "encryption")]
+use crate::encryption::{EncryptedStore, EncryptionKey};
 
 #[derive(Clone)]
 pub struct Kopper {
-    state: Arc<Mutex<SharedState>>,
-    compactor: Sender<()>,
+    state: Arc<parking_lot::Mutex<SharedState>>,
+    /// Time spent waiting for `state`, see [`Kopper::saturation`]
+    contention: Arc<Contention>,
+    compactor: Sender<CompactorRequest>,
+    /// Requests sent to the compactor it hasn't taken up yet
+    compactor_queue: Arc<AtomicUsize>,
+    compactor_thread: Arc<Mutex<Option<JoinHandle<()>>>>,
+    /// Recovers older segments of databases opened with [`Kopper::create_lazily`]
+    recovery_thread: Arc<Mutex<Option<JoinHandle<()>>>>,
+    /// Signalled when the recovery thread is done, see [`Kopper::wait_for_recovery`]
+    recovered: Arc<Condvar>,
+    /// Held while fsyncing for [`Kopper::wait_for_flush`], so concurrent callers share one
+    flushing: Arc<Mutex<()>>,
+    /// Signalled when a replica confirms changes, see [`Kopper::confirm_replication`]
+    replicated: Arc<Condvar>,
+    /// See [`Kopper::publish`]
+    channels: Arc<Channels>,
     segment_size: usize,
-    path: String
+    store: Arc<dyn SegmentStore>
 }
 
 struct SharedState {
@@ -24,24 +75,412 @@ struct SharedState {
     files: BTreeMap<FileIndex, FileEntry>,
     offset: usize,
     current_file_index: FileIndex,
-    size: usize
+    size: usize,
+    closed: bool,
+    watchers: Vec<Watcher>,
+    /// Waiting for items pushed to the queue they watch, see [`Kopper::watch_pushes`]
+    poppers: Vec<Watcher>,
+    /// Given to the next watcher
+    next_watcher: u64,
+    compaction_listeners: Vec<Sender<CompactionReport>>,
+    metrics: Option<Arc<dyn MetricsSink>>,
+    clock: Arc<dyn Clock>,
+    compression: Option<Compression>,
+    compression_stats: CompressionStats,
+    packing: Option<Packing>,
+    /// Segments this many behind the active one are compacted onto the cold tier
+    cold_tier_after: Option<u32>,
+    /// Values at least this long are stored once for all keys, see [`Kopper::set_dedupe`]
+    dedupe: Option<usize>,
+    /// Deduplicated values, by hash
+    blobs: HashMap<u64, Blob>,
+    /// Keys whose newest record is a blob or a reference, with its hash
+    deduped: HashMap<String, u64>,
+    quota: Quota,
+    /// Of the keys in `table`, see [`Usage::bytes`]
+    live_bytes: usize,
+    /// Of the keys in `table`, how many are [`keyspace::is_reserved`]
+    reserved_keys: usize,
+    /// When the values written with a TTL expire, in milliseconds since the Unix epoch
+    expiries: HashMap<String, u64>,
+    /// The same, soonest first, for the sweeper
+    expiry_queue: BTreeSet<(u64, String)>,
+    /// Of values written without a TTL of their own, see [`Kopper::set_default_ttl`]
+    default_ttl: Option<Duration>,
+    /// How long deleted values can be restored, see [`Kopper::set_undelete_window`]
+    undelete_window: Option<Duration>,
+    eviction: Option<Eviction>,
+    backpressure: Option<Backpressure>,
+    /// Of the bytes in sealed segments, only counted with backpressure. Counted
+    /// again after every compaction, and by the first write after a segment is
+    /// cut off - the only times it moves much.
+    dead_ratio: f64,
+    /// Active segment when the dead ratio was counted
+    dead_ratio_of: FileIndex,
+    /// Writes fail until compaction brings the dead ratio below the hard limit
+    stalled: bool,
+    /// Ticks every time a key is written or read while evicting
+    uses: u64,
+    /// When every key was last used, by [`SharedState::uses`]
+    last_used: HashMap<String, u64>,
+    /// The same, least recently used first
+    lru: BTreeSet<(u64, String)>,
+    /// Never evicted or expired, see [`Kopper::pin`]
+    pinned: HashSet<String>,
+    /// Previous versions kept by key, see [`Kopper::keep_versions`]
+    versions: HashMap<String, usize>,
+    read_only: bool,
+    /// Why writes are refused until [`Kopper::resume`], e.g. the disk filled up
+    degraded: Option<String>,
+    /// Last key [`Kopper::verify`] checked, it goes on from the next one in key order
+    verify_cursor: Option<String>,
+    /// Without the segments quarantined, those are in `quarantined`
+    verify_stats: VerifyStats,
+    /// Whether segments with corrupt records are quarantined, see [`Kopper::set_quarantine`]
+    quarantine: bool,
+    quarantined: BTreeSet<FileIndex>,
+    /// Set while older segments are still being recovered in the background
+    recovering: Option<Recovering>,
+    /// Segments making up the database, `None` for followers - the owner keeps it
+    segment_log: Option<SegmentLog>,
+    /// Opened with [`Kopper::follow`], files belong to another process
+    following: bool,
+    /// Bumped whenever [`Kopper::install`] swaps the segments, so a compaction
+    /// that started before knows to abandon its work
+    generation: u64,
+    log_id: u64,
+    /// Sequence of the newest change
+    sequence: u64,
+    /// Every change up to this one is on disk
+    synced_sequence: u64,
+    /// Segments before this one had nothing written since they were last synced
+    synced_from: FileIndex,
+    /// Bumped for every segment created or deleted without syncing the directory
+    /// right away, see [`SegmentStore::sync_dir`]
+    dir_changes: u64,
+    /// Of `dir_changes` when the directory was last synced. Writes waiting for
+    /// [`Durability::Flush`] sync it again if it's behind.
+    synced_dir_changes: u64,
+    /// Newest change a replica confirmed
+    replicated_sequence: u64,
+    change_log: VecDeque<ChangeRecord>,
+    change_log_capacity: usize,
+    /// By name, see [`Kopper::create_numeric_index`]
+    numeric_indexes: BTreeMap<String, NumericIndex>,
+    hooks: Hooks,
+}
+
+struct Watcher {
+    id: u64,
+    prefix: String,
+    /// Hands an event over, `false` once the subscriber is gone
+    deliver: Box<dyn FnMut(ChangeEvent) -> bool + Send>
+}
+
+/// Subscription to changes or messages, see [`Kopper::watch_into`] and
+/// [`Kopper::subscribe_into`]. Dropping it unsubscribes.
+#[must_use = "dropping the subscription ends it right away"]
+pub struct Subscription {
+    unsubscribe: Option<Box<dyn FnOnce() + Send>>
+}
+
+impl Subscription {
+    pub(crate) fn new(unsubscribe: impl FnOnce() + Send + 'static) -> Self {
+        Subscription { unsubscribe: Some(Box::new(unsubscribe)) }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Some(unsubscribe) = self.unsubscribe.take() {
+            unsubscribe();
+        }
+    }
+}
+
+/// Change of a single key, delivered to subscribers of [`Kopper::watch`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChangeEvent {
+    Write { key: String, value: String },
+    Delete { key: String }
+}
+
+/// Change numbered in the order it was made, see [`Kopper::changes_since`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeRecord {
+    pub sequence: u64,
+    pub event: ChangeEvent
+}
+
+/// Outcome of a successful write or delete
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Commit {
+    /// Sequence of the change, to pass to [`Kopper::wait_for_flush`] or
+    /// [`Kopper::wait_for_replication`]. The newest one for batches.
+    pub sequence: u64,
+    /// Size of the database on disk afterwards
+    pub size: usize
+}
+
+/// Changes kept in memory for [`Kopper::changes_since`] unless configured otherwise
+pub const DEFAULT_CHANGE_LOG_CAPACITY: usize = 10_000;
+
+/// Segments behind the active one before they're moved to the cold tier, for
+/// servers configured with one - see [`Kopper::set_cold_tier_after`]
+pub const DEFAULT_COLD_TIER_AFTER: u32 = 8;
+
+/// Summary of a single finished compaction, delivered to subscribers of [`Kopper::compactions`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompactionReport {
+    /// Bytes of disk space freed by dropping stale records
+    pub reclaimed_bytes: usize,
+    pub duration: Duration,
+    /// Segment files left after the compaction
+    pub segments: usize
+}
+
+/// How far opening a database got, reported to the callback of
+/// [`Kopper::create_with_progress`] once the segments are found, and again after
+/// every segment recovered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RecoveryProgress {
+    /// Segments found to recover
+    pub segments: usize,
+    /// Of those, recovered so far
+    pub recovered: usize,
+    pub bytes_scanned: u64,
+    /// Keys indexed so far
+    pub entries: usize,
+}
+
+/// Where the space of a segment goes, see [`Kopper::segment_stats`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentStats {
+    pub name: String,
+    /// Of its records, however it's stored
+    pub bytes: usize,
+    /// Bytes on disk, fewer if the segment is packed
+    pub stored_bytes: usize,
+    /// Of records holding the current value of a key, or a blob referred to
+    pub live_bytes: usize,
+    /// Of everything else - overwritten values and tombstones - dropped by compaction
+    pub dead_bytes: usize,
+    /// Keys whose current value it holds
+    pub entries: usize,
+    /// Records dropped by compaction, which compacts the segment with the most first
+    pub dead_entries: usize,
+    /// Segments cut off since this one, 0 for the active one
+    pub age: u32,
+    pub tier: Tier,
+}
+
+/// Most a database may hold, see [`Kopper::set_quota`]. `None` is no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quota {
+    /// Of the keys and their values as stored, see [`Usage::bytes`]
+    pub max_bytes: Option<usize>,
+    pub max_keys: Option<usize>,
+}
+
+/// Limit of a [`Quota`] a write would go over
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaLimit {
+    Bytes(usize),
+    Keys(usize),
+}
+
+impl Display for QuotaLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuotaLimit::Bytes(max) => write!(f, "{max} bytes"),
+            QuotaLimit::Keys(max) => write!(f, "{max} keys"),
+        }
+    }
+}
+
+/// Most a database used as a cache holds, see [`Kopper::set_eviction`]. `None` is no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Eviction {
+    /// Of the keys and their values as stored, see [`Usage::bytes`]
+    pub max_bytes: Option<usize>,
+    pub max_keys: Option<usize>,
+}
+
+/// When writes are held back for the compactor to catch up, see
+/// [`Kopper::set_backpressure`]. Ratios are of the bytes in sealed segments that
+/// are dead - overwritten values and tombstones, which compaction drops.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backpressure {
+    /// Past this ratio every write waits for `delay` first
+    pub soft: f64,
+    /// Past this ratio writes fail with [`KopperError::Backpressure`]
+    pub hard: f64,
+    pub delay: Duration,
+}
+
+/// Default [`Backpressure::delay`]
+pub const DEFAULT_WRITE_STALL: Duration = Duration::from_millis(10);
+
+/// What a database holds, as counted against its [`Quota`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub keys: usize,
+    /// Of the keys and their values as stored - compressed, or a reference to a
+    /// deduplicated one. Unlike [`Kopper::size`], stale records don't count.
+    pub bytes: usize,
+}
+
+/// Recovery of the segments [`Kopper::create_lazily`] left for later
+struct Recovering {
+    progress: RecoveryProgress,
+    /// Keys deleted in the segments recovered upfront, or since - records of
+    /// them in older segments are stale
+    shadowed: HashSet<String>,
+    /// Why recovering the rest failed, if it did
+    failed: Option<String>,
+}
+
+/// Sends event produced by `event` to watchers interested in `key`, forgetting the ones that hung up.
+fn notify_watchers(state: &mut SharedState, key: &str, event: impl Fn() -> ChangeEvent) {
+    if keyspace::is_reserved(key) {
+        return;
+    }
+    state.watchers.retain_mut(|watcher| !key.starts_with(&watcher.prefix) || (watcher.deliver)(event()));
+}
+
+/// Numbers `event`, keeps it in the change log and sends it to watchers.
+fn publish(state: &mut SharedState, key: &str, event: ChangeEvent) {
+    state.sequence += 1;
+    let value = match &event {
+        ChangeEvent::Write { value, .. } => Some(value.as_str()),
+        ChangeEvent::Delete { .. } => None,
+    };
+    if !keyspace::is_reserved(key) {
+        for index in state.numeric_indexes.values_mut() {
+            index.update(key, value);
+        }
+    }
+    notify_watchers(state, key, || event.clone());
+
+    if state.change_log_capacity > 0 {
+        if state.change_log.len() >= state.change_log_capacity {
+            state.change_log.pop_front();
+        }
+        state.change_log.push_back(ChangeRecord { sequence: state.sequence, event });
+    }
+}
+
+/// Sequences start over with every instance and [`Kopper::install`] - tells their logs apart
+fn new_log_id() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64
+}
+
+/// Stands in for `key` in logs, which shouldn't hold user data. The same across
+/// runs, so the lines of one key can be followed.
+fn key_hash(key: &str) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Refuses keys users can't read or write, the ones the database keeps for itself
+fn check_key(key: &str) -> Result<(), KopperError> {
+    match keyspace::is_reserved(key) {
+        true => Err(KopperError::Rejected(format!("Keys starting with {:?} are reserved", keyspace::RESERVED))),
+        false => Ok(()),
+    }
+}
+
+/// `key` isn't in the table - unless it may be in a segment still being recovered
+fn missing(state: &SharedState, key: &str) -> KopperError {
+    match &state.recovering {
+        Some(recovering) if !recovering.shadowed.contains(key) => KopperError::Recovering,
+        _ => KopperError::KeyDoesNotExist(key.to_owned()),
+    }
 }
 
+/// Value of a record marking its key as deleted. Values are valid UTF-8,
+/// which never contains 0xFF, so it can't be mistaken for user data.
+const TOMBSTONE: &[u8] = &[0xFF];
+
+/// Most bytes of a segment [`Kopper::fold`] reads at once
+const FOLD_READ_LEN: usize = 1024 * 1024;
+
+enum CompactorRequest {
+    Compact,
+    /// Answered once every request queued before it is handled
+    Barrier(Sender<()>),
+    Stop
+}
+
+#[derive(Clone, Copy)]
 struct TableEntry {
     file_index: FileIndex,
     offset: usize,
     len: usize
 }
 
-#[derive(PartialEq, Eq, Ord, PartialOrd, Clone, Copy)]
+#[derive(PartialEq, Eq, Ord, PartialOrd, Clone, Copy, Hash)]
 struct FileIndex {
     base: u32,
     index: u32
 }
 
+/// Value stored once for every key written with it, see [`dedupe`]
+struct Blob {
+    /// Of the blob record, `None` while that's in a segment still being recovered
+    entry: Option<TableEntry>,
+    /// Keys in [`SharedState::deduped`] with its hash
+    refs: usize,
+}
+
+/// Newest record of a value changed a part at a time, see [`chain`]
+struct Chained {
+    entry: TableEntry,
+    kind: chain::Kind,
+    header: chain::Header,
+    record: Vec<u8>,
+}
+
+/// Blobs, references and values that expire recovery came across, before it's
+/// known which keys they're the newest records of
+#[derive(Default)]
+struct Found {
+    /// Hash of every blob and reference, by where its value starts
+    records: HashMap<(FileIndex, usize), u64>,
+    /// Newest blob of every hash
+    blobs: HashMap<u64, TableEntry>,
+    /// When every value that expires does, by where it starts
+    expiring: HashMap<(FileIndex, usize), u64>,
+}
+
+impl Found {
+    /// Takes the header at the start of the value at `entry`, if it's one of those
+    fn header(&mut self, entry: TableEntry, header: &[u8]) {
+        if let Some(record) = dedupe::parse(header) {
+            self.found(entry, record);
+        } else if let Some(expires_at) = expiry::parse(header) {
+            self.expiring.insert((entry.file_index, entry.offset), expires_at);
+        }
+    }
+
+
+    fn found(&mut self, entry: TableEntry, record: Record) {
+        let hash = match record {
+            Record::Blob(hash) => {
+                self.blobs.insert(hash, entry);
+                hash
+            },
+            Record::Reference(hash) => hash,
+        };
+        self.records.insert((entry.file_index, entry.offset), hash);
+    }
+}
+
 struct FileEntry {
-    file: File,
-    unused_count: usize
+    file: Arc<dyn SegmentFile>,
+    unused_count: usize,
+    tier: Tier
 }
 
 impl Add<u32> for FileIndex {
@@ -70,209 +509,3256 @@ impl FromStr for FileIndex {
 
 impl Kopper {
     pub fn create(path: &str, segment_size: usize) -> Result<Self, KopperError> {
+        Kopper::create_with_progress(path, segment_size, |_| {})
+    }
+
+    /// Like [`Kopper::create`], calling `progress` as the database is recovered -
+    /// opening a big one takes a while.
+    pub fn create_with_progress(path: &str, segment_size: usize, progress: impl FnMut(RecoveryProgress)) -> Result<Self, KopperError> {
+        // Create dir if doesn't exist yet
+        let _ = fs::create_dir_all(path);
+        Kopper::with_store_and_progress(Arc::new(LocalStore::new(path)), segment_size, progress)
+    }
+
+    /// Like [`Kopper::create`], with every segment encrypted with `key` - see
+    /// [`EncryptedStore`]. Opening it takes the same key again.
+    #[cfg(feature = "encryption")]
+    pub fn create_encrypted(path: &str, segment_size: usize, key: &EncryptionKey) -> Result<Self, KopperError> {
+        let _ = fs::create_dir_all(path);
+        Kopper::with_store(Arc::new(EncryptedStore::new(Arc::new(LocalStore::new(path)), key)), segment_size)
+    }
+
+    /// Opens the database kept in `store`, e.g. a [`MemoryStore`](crate::store::MemoryStore).
+    /// What needs segments to be files - [`Kopper::install`], [`Kopper::follow`] - only works 
+    /// with a [`LocalStore`], which is what [`Kopper::create`] uses.
+    pub fn with_store(store: Arc<dyn SegmentStore>, segment_size: usize) -> Result<Self, KopperError> {
+        Kopper::with_store_and_progress(store, segment_size, |_| {})
+    }
+
+    /// Like [`Kopper::with_store`], reporting progress like [`Kopper::create_with_progress`]
+    pub fn with_store_and_progress(store: Arc<dyn SegmentStore>, segment_size: usize, mut progress: impl FnMut(RecoveryProgress)) -> Result<Self, KopperError> {
+        Kopper::open(store, segment_size, &mut progress, None)
+    }
+
+    /// Like [`Kopper::create`], returning once the newest `eager` segments are
+    /// recovered - the older ones are recovered in the background. Meanwhile,
+    /// keys only those hold can't be read or deleted yet, which fails with
+    /// [`KopperError::Recovering`], and neither can the database be scanned or
+    /// compacted. Writes work from the start.
+    pub fn create_lazily(path: &str, segment_size: usize, eager: usize) -> Result<Self, KopperError> {
+        let _ = fs::create_dir_all(path);
+        Kopper::with_store_lazily(Arc::new(LocalStore::new(path)), segment_size, eager)
+    }
+
+    /// Like [`Kopper::with_store`], recovering like [`Kopper::create_lazily`]
+    pub fn with_store_lazily(store: Arc<dyn SegmentStore>, segment_size: usize, eager: usize) -> Result<Self, KopperError> {
+        Kopper::open(store, segment_size, &mut |_| {}, Some(eager))
+    }
+
+    fn open(store: Arc<dyn SegmentStore>, segment_size: usize, progress: &mut dyn FnMut(RecoveryProgress), eager: Option<usize>) -> Result<Self, KopperError> {
 
         // Recover
-        let shared_state = SharedState::create(path)?;
+        let (shared_state, pending) = SharedState::create(&*store, progress, eager)?;
 
         // Use channel to communicate with compactor to make sure every compaction request is handled
-        let (compactor_tx, compactor_rx) = channel::<()>();
+        let (compactor_tx, compactor_rx) = channel::<CompactorRequest>();
 
         let ret = Kopper { 
-            state: Arc::new(Mutex::new(shared_state)),
+            state: Arc::new(parking_lot::Mutex::new(shared_state)),
+            contention: Arc::default(),
             compactor: compactor_tx,
+            compactor_queue: Arc::default(),
+            compactor_thread: Arc::default(),
+            recovery_thread: Arc::default(),
+            recovered: Arc::default(),
+            flushing: Arc::default(),
+            replicated: Arc::default(),
+            channels: Arc::default(),
             segment_size,
-            path: path.to_owned(),
+            store,
         };
 
         // Start background thread compacting segments to reclaim memory
-        ret.run_compactor(compactor_rx);
+        let compactor_thread = ret.run_compactor(compactor_rx);
+        *ret.compactor_thread.lock().unwrap() = Some(compactor_thread);
+
+        if !pending.is_empty() {
+            let recovery_thread = ret.run_recovery(pending);
+            *ret.recovery_thread.lock().unwrap() = Some(recovery_thread);
+        }
+        Ok(ret)
+    }
+
+    /// Opens the database at `path` without taking it over, to read it while another
+    /// process owns it - e.g. on shared storage. Nothing in the directory is touched:
+    /// writes fail with [`KopperError::ReadOnly`], compaction is left to the owner,
+    /// and the owner's changes show up after [`Kopper::refresh`], which is called
+    /// every `refresh_interval` until the database is closed.
+    pub fn follow(path: &str, refresh_interval: Duration) -> Result<Self, KopperError> {
+        Kopper::follow_with_clock(path, refresh_interval, Arc::new(SystemClock))
+    }
+
+    /// Like [`Kopper::follow`], with `refresh_interval` measured on `clock`
+    pub fn follow_with_clock(path: &str, refresh_interval: Duration, clock: Arc<dyn Clock>) -> Result<Self, KopperError> {
+        let mut state = SharedState::empty();
+        state.read_only = true;
+        state.following = true;
+        state.clock = clock.clone();
+
+        // Compactor is never started, nothing is ever sent to it
+        let (compactor, _) = channel();
+        let ret = Kopper {
+            state: Arc::new(parking_lot::Mutex::new(state)),
+            contention: Arc::default(),
+            compactor,
+            compactor_queue: Arc::default(),
+            compactor_thread: Arc::default(),
+            recovery_thread: Arc::default(),
+            recovered: Arc::default(),
+            flushing: Arc::default(),
+            replicated: Arc::default(),
+            channels: Arc::default(),
+            segment_size: 0,
+            store: Arc::new(LocalStore::new(path)),
+        };
+        ret.refresh()?;
+
+        let follower = ret.clone();
+        std::thread::spawn(move || loop {
+            clock.sleep(refresh_interval);
+            if follower.is_closed() {
+                break;
+            }
+            if let Err(err) = follower.refresh() {
+                // Files can disappear mid-way when the owner compacts - next time it settles
+                tracing::warn!("Can't refresh {}: {err}", follower.path());
+            }
+        });
         Ok(ret)
     }
 
+    /// Catches up with changes made by the process owning a database opened with
+    /// [`Kopper::follow`]. Usually only the active segment grew, and only the
+    /// new records are read. If the owner compacted, everything is read again,
+    /// off the lock - reads keep being served from the previous state meanwhile.
+    /// Does nothing for databases opened with [`Kopper::create`].
+    pub fn refresh(&self) -> Result<(), KopperError> {
+        let on_disk = segment_indexes(&*self.store)?;
+
+        let mut state = self.contention.lock(&self.state);
+        if state.closed {
+            return Err(KopperError::Closed);
+        }
+        if !state.following {
+            return Ok(());
+        }
+
+        // Sealed segments never change, new ones are always newer than the rest
+        let newest = state.files.last_key_value().map(|(index, _)| *index);
+        let only_appended = state.files.keys().all(|index| on_disk.binary_search(index).is_ok())
+            && on_disk.iter().all(|index| state.files.contains_key(index) || Some(*index) > newest);
+
+        if only_appended {
+            for index in on_disk {
+                let from = match state.files.contains_key(&index) {
+                    true if Some(index) == newest => state.offset,
+                    true => continue,
+                    false => 0,
+                };
+                if from == 0 {
+                    let file = packed::open(self.store.open(&index.to_string())?)?;
+                    state.files.insert(index, FileEntry { file, unused_count: 0, tier: Tier::Hot });
+                }
+
+                let state = &mut *state;
+                state.offset = index_tail(&mut state.table, None, index, &*state.files[&index].file, from)?;
+                state.current_file_index = index;
+            }
+        } else {
+            drop(state);
+
+            let mut table = HashMap::new();
+            let mut files = BTreeMap::new();
+            let mut offset = 0;
+            for index in &on_disk {
+                let file = packed::open(self.store.open(&index.to_string())?)?;
+                offset = index_tail(&mut table, None, *index, &*file, 0)?;
+                files.insert(*index, FileEntry { file, unused_count: 0, tier: Tier::Hot });
+            }
+
+            state = self.contention.lock(&self.state);
+            state.table = table;
+            state.files = files;
+            state.offset = offset;
+            state.current_file_index = on_disk.last().copied().unwrap_or(FileIndex { base: 0, index: 0 });
+        }
+
+        let mut size = 0;
+        for entry in state.files.values() {
+            size += entry.file.stored_len()? as usize;
+        }
+        state.size = size;
+        state.count_live_bytes();
+        state.rebuild_numeric_indexes()
+    }
+
+    /// Flushes all segment files to disk.
+    pub fn sync(&self) -> Result<(), KopperError> {
+        let mut state = self.contention.lock(&self.state);
+        for entry in state.files.values() {
+            entry.file.sync()?;
+        }
+        if let Some(segment_log) = &state.segment_log {
+            segment_log.sync()?;
+        }
+        if state.synced_dir_changes < state.dir_changes {
+            self.store.sync_dir()?;
+            state.synced_dir_changes = state.dir_changes;
+        }
+        state.synced_sequence = state.sequence;
+        state.synced_from = state.current_file_index;
+        Ok(())
+    }
+
+    /// Returns once every change up to `sequence` is on disk, fsyncing the
+    /// segments written since the last time if needed. Callers waiting at the
+    /// same time share a single fsync. Sequences newer than [`Kopper::sequence`]
+    /// are only waited for as far as they exist.
+    pub fn wait_for_flush(&self, sequence: u64) -> Result<(), KopperError> {
+        let _flushing = self.flushing.lock().unwrap();
+
+        // Whoever held the lock before may have synced this far already
+        let (files, upto, current, dir_changes) = {
+            let state = self.contention.lock(&self.state);
+            if state.closed {
+                return Err(KopperError::Closed);
+            }
+            if state.synced_sequence >= sequence.min(state.sequence) {
+                return Ok(());
+            }
+
+            // Synced off the lock, so writes carry on meanwhile
+            let files = state.files.range(state.synced_from..)
+                .map(|(_, entry)| entry.file.clone())
+                .collect::<Vec<_>>();
+            let dir_changes = (state.synced_dir_changes < state.dir_changes).then_some(state.dir_changes);
+            (files, state.sequence, state.current_file_index, dir_changes)
+        };
+
+        for file in files {
+            file.sync()?;
+        }
+        // The new segments have to be found after a crash, not only what's in them
+        if dir_changes.is_some() {
+            self.store.sync_dir()?;
+        }
+
+        let mut state = self.contention.lock(&self.state);
+        state.synced_sequence = state.synced_sequence.max(upto);
+        state.synced_from = state.synced_from.max(current);
+        if let Some(dir_changes) = dir_changes {
+            state.synced_dir_changes = state.synced_dir_changes.max(dir_changes);
+        }
+        Ok(())
+    }
+
+    /// Records that a replica applied every change up to `sequence`, waking
+    /// [`Kopper::wait_for_replication`] callers waiting for it
+    pub fn confirm_replication(&self, sequence: u64) {
+        let mut state = self.contention.lock(&self.state);
+        if sequence > state.replicated_sequence {
+            state.replicated_sequence = sequence.min(state.sequence);
+            self.replicated.notify_all();
+        }
+    }
+
+    /// Newest change a replica confirmed with [`Kopper::confirm_replication`]
+    pub fn replicated_sequence(&self) -> u64 {
+        self.contention.lock(&self.state).replicated_sequence
+    }
+
+    /// Waits until a replica confirms every change up to `sequence`. Fails with
+    /// [`KopperError::NotReplicated`] if none does within `timeout` - the change
+    /// stays written locally either way.
+    pub fn wait_for_replication(&self, sequence: u64, timeout: Duration) -> Result<(), KopperError> {
+        let mut state = self.contention.lock(&self.state);
+        self.replicated.wait_while_for(&mut state, |state| !state.closed && state.replicated_sequence < sequence, timeout);
+
+        if state.replicated_sequence >= sequence {
+            Ok(())
+        } else if state.closed {
+            Err(KopperError::Closed)
+        } else {
+            Err(KopperError::NotReplicated(sequence))
+        }
+    }
+
+    /// Stops the compactor, waiting for queued compactions to finish, and flushes
+    /// all segment files to disk. Afterwards every handle to this database returns
+    /// [`KopperError::Closed`].
+    pub fn close(&self) -> Result<(), KopperError> {
+
+        // Reject new writes first, so nothing queues compaction behind the stop request.
+        // Dropping watchers disconnects their receivers.
+        let mut state = self.contention.lock(&self.state);
+        state.closed = true;
+        state.watchers.clear();
+        state.poppers.clear();
+        state.compaction_listeners.clear();
+        drop(state);
+        self.channels.close();
+        self.replicated.notify_all();
+        self.recovered.notify_all();
+
+        // Stops before the next segment
+        if let Some(recovery_thread) = self.recovery_thread.lock().unwrap().take() {
+            recovery_thread.join()
+                .map_err(|_| KopperError::InternalError(anyhow::anyhow!("Recovery thread panicked")))?;
+        }
+
+        if let Some(compactor_thread) = self.compactor_thread.lock().unwrap().take() {
+            // Ok to ignore - compactor only stops here, so it's still listening
+            let _ = self.send_to_compactor(CompactorRequest::Stop);
+            compactor_thread.join()
+                .map_err(|_| KopperError::InternalError(anyhow::anyhow!("Compactor thread panicked")))?;
+        }
+
+        self.sync()
+    }
+
     #[allow(dead_code)]
     pub fn size(&self) -> usize {
-        self.state.lock().unwrap().size
+        self.contention.lock(&self.state).size
     }
 
+    /// Directory of the database, empty if its segments aren't kept in files
     #[allow(dead_code)]
     pub fn path(&self) -> String {
-        self.path.clone()
+        self.store.path().unwrap_or_default().to_owned()
     }
 
-    pub fn read(&self, key: &str) -> Result<String, KopperError> {
-        let state = self.state.lock().unwrap();
+    /// Whether [`Kopper::close`] has been called on any handle to this database
+    pub fn is_closed(&self) -> bool {
+        self.contention.lock(&self.state).closed
+    }
 
-        let table_entry = match state.table.get(key) {
-            Some(table_entry) => table_entry,
-            None => return Err(KopperError::KeyDoesNotExist(key.to_owned())),
+    /// Number of keys currently stored
+    pub fn len(&self) -> usize {
+        self.contention.lock(&self.state).user_keys()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of subscriptions to changes, see [`Kopper::watch_into`]
+    pub fn watchers(&self) -> usize {
+        self.contention.lock(&self.state).watchers.len()
+    }
+
+    /// Reports engine-side metrics, like compactions, to `sink`
+    pub fn set_metrics_sink(&self, sink: Arc<dyn MetricsSink>) {
+        self.contention.lock(&self.state).metrics = Some(sink);
+    }
+
+    /// Takes the time from `clock` from now on, e.g. to time compactions and
+    /// date backups. [`SystemClock`] unless set.
+    pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+        self.contention.lock(&self.state).clock = clock;
+    }
+
+    /// Compresses values written from now on, `None` to stop. Values already
+    /// written stay the way they are, and are read either way.
+    pub fn set_compression(&self, compression: Option<Compression>) {
+        self.contention.lock(&self.state).compression = compression;
+    }
+
+    /// Has the compactor pack the segments it rewrites once they're cold, `None`
+    /// to stop. Packed segments are read block by block and take less space, at
+    /// the cost of decompressing a block for every read. Segments already packed
+    /// stay packed until compacted again.
+    pub fn set_packing(&self, packing: Option<Packing>) {
+        self.contention.lock(&self.state).packing = packing;
+    }
+
+    /// Has the compactor move the segments it rewrites to the cold tier of a
+    /// [`TieredStore`](crate::store::TieredStore) once they're `after` segments
+    /// behind the active one, `None` to stop. Old segments are the ones rarely
+    /// overwritten any more - recent data and the active segment stay hot.
+    /// Segments already moved stay cold.
+    pub fn set_cold_tier_after(&self, after: Option<u32>) {
+        self.contention.lock(&self.state).cold_tier_after = after;
+    }
+
+    /// Stores values at least `min_len` bytes long only once, however many keys
+    /// they're written under, `None` to stop - see [`dedupe`]. Keys written with
+    /// a value stored already only store a reference to it, and the compactor keeps
+    /// the value for as long as any key refers to it. Values already written stay
+    /// the way they are. Databases opened with [`Kopper::follow`] can't read references.
+    pub fn set_dedupe(&self, min_len: Option<usize>) {
+        self.contention.lock(&self.state).dedupe = min_len;
+    }
+
+    /// Values compressed since the database was opened
+    /// Has every value written without a TTL of its own expire once `ttl` passes,
+    /// like with [`Kopper::write_with_ttl`], `None` to stop - e.g. for sessions
+    /// or a cache, where everything ages out. Values already written keep the
+    /// TTL they had, and [`Kopper::apply`]'d ones don't get it.
+    pub fn set_default_ttl(&self, ttl: Option<Duration>) {
+        self.contention.lock(&self.state).default_ttl = ttl;
+    }
+
+    /// Keeps the values deleted from now on for `window`, see [`trash`], so they
+    /// can be restored with [`Kopper::undelete`] - `None` to delete them for good
+    /// right away. Sets, hashes, counters and lists are always deleted for good.
+    pub fn set_undelete_window(&self, window: Option<Duration>) {
+        self.contention.lock(&self.state).undelete_window = window;
+    }
+
+    /// Refuses writes that would take the database over `quota` with
+    /// [`KopperError::QuotaExceeded`]. Deletes, and writes that don't add to
+    /// what's over already, still go through - and so do changes [`Kopper::apply`]'d.
+    /// While [`Kopper::create_lazily`] still recovers older segments, only the
+    /// keys recovered so far count.
+    pub fn set_quota(&self, quota: Quota) {
+        self.contention.lock(&self.state).quota = quota;
+    }
+
+    /// Has the database act as a cache holding at most `eviction`, `None` to
+    /// stop. Every write that leaves it holding more evicts the keys least
+    /// recently read or written - deleting them, so the compactor reclaims their
+    /// space - until it's back within the limits, or only the key just written
+    /// is left. Keys already there count as used in no particular order, before
+    /// any written or read from now on. [`Kopper::apply`]'d changes don't evict,
+    /// the evictions of a primary are replicated as deletes.
+    pub fn set_eviction(&self, eviction: Option<Eviction>) {
+        let mut state = self.contention.lock(&self.state);
+        state.eviction = eviction;
+        state.last_used.clear();
+        state.lru.clear();
+        state.uses = 0;
+        if eviction.is_some() {
+            let keys: Vec<String> = state.table.keys().cloned().collect();
+            state.seed_lru(keys);
+        }
+    }
+
+    /// Keeps `key` from being evicted or expiring until [`Kopper::unpin`], e.g.
+    /// for configuration kept in a cache - whether it's there yet or not. A TTL
+    /// it has still counts down, and the value expires if it's past once it's
+    /// unpinned. Deleting the key doesn't unpin it. Pins last until the database
+    /// is closed.
+    pub fn pin(&self, key: &str) {
+        self.contention.lock(&self.state).pinned.insert(key.to_owned());
+    }
+
+    /// Lets `key` be evicted and expire again, returning whether it was pinned.
+    /// It's evicted by the next write that leaves the database over its limits.
+    pub fn unpin(&self, key: &str) -> bool {
+        self.contention.lock(&self.state).pinned.remove(key)
+    }
+
+    /// Keeps up to `versions` values `key` had before, see [`versions`], for
+    /// [`Kopper::read_versions`] - 0 stops keeping them, leaving the ones kept so
+    /// far. Fewer than before are cut at the next write. Sets, hashes, counters and
+    /// lists aren't versioned, nor are [reserved](keyspace) keys. Like pins, lasts
+    /// until the database is closed.
+    pub fn keep_versions(&self, key: &str, versions: usize) {
+        if keyspace::is_reserved(key) {
+            return;
+        }
+        let mut state = self.contention.lock(&self.state);
+        match versions {
+            0 => state.versions.remove(key),
+            versions => state.versions.insert(key.to_owned(), versions),
         };
+    }
 
-        let mut file = 
-        state.files
-            .get(&table_entry.file_index).unwrap() // Can't recover from this. Should panic.
-            .file.try_clone().unwrap();
+    /// The value of `key` followed by the ones it had before, newest first, see
+    /// [`Kopper::keep_versions`]. Starts with the previous one once it's deleted.
+    pub fn read_versions(&self, key: &str) -> Result<Vec<String>, KopperError> {
+        let _span = tracing::trace_span!("read_versions", key_hash = key_hash(key)).entered();
+        check_key(key)?;
+        let state = self.contention.lock(&self.state);
+        if state.closed {
+            return Err(KopperError::Closed);
+        }
 
-        // TODO: This is OK because files are never deleted. 
+        let mut versions = match Kopper::lookup(&state, key) {
+            Ok(value) => vec![state.hooks.read(key, value)?],
+            Err(KopperError::KeyDoesNotExist(_)) => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        for previous in Kopper::lookup_history(&state, key)? {
+            versions.push(state.hooks.read(key, previous)?);
+        }
+        if versions.is_empty() {
+            return Err(missing(&state, key));
+        }
+        Ok(versions)
+    }
 
-        let offset = table_entry.offset;
-        let mut buffer = vec![0; table_entry.len];
+    /// Pushes the value `key` has to its history if it keeps versions, before
+    /// it's replaced or removed
+    fn keep_version(&self, state: &mut MutexGuard<'_, SharedState>, key: &str) -> Result<(), KopperError> {
+        let Some(&keep) = state.versions.get(key) else {
+            return Ok(());
+        };
+        let previous = match Kopper::lookup(state, key) {
+            Ok(_) if Kopper::chained_kind(state, key)?.is_some() => return Ok(()),
+            Ok(previous) => previous,
+            Err(KopperError::KeyDoesNotExist(_)) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let mut history = Kopper::lookup_history(state, key)?;
+        history.push_front(previous);
+        history.truncate(keep);
+        // Without a TTL, the history outlives the value
+        self.put_batch(state, &[(&versions::history_key(key), &versions::encode(&history))], true, None)
+    }
 
-        file.seek(SeekFrom::Start(offset as u64))?;
-        file.read_exact(&mut buffer)?;
+    /// Previous versions of `key`, newest first
+    fn lookup_history(state: &SharedState, key: &str) -> Result<VecDeque<String>, KopperError> {
+        let history_key = versions::history_key(key);
+        match Kopper::lookup(state, &history_key) {
+            Ok(value) => versions::decode(&history_key, &value),
+            Err(KopperError::KeyDoesNotExist(_)) => Ok(VecDeque::new()),
+            Err(err) => Err(err),
+        }
+    }
 
-        Ok(String::from_utf8(buffer)?)
+    /// Holds writes back while compaction falls behind them, `None` to stop.
+    /// Once more of the sealed segments than `backpressure` allows is dead, every
+    /// write and batch is delayed, or past the hard limit fails with
+    /// [`KopperError::Backpressure`] - which is worth retrying - and every sealed
+    /// segment is queued for compaction. Deletes and [`Kopper::apply`] go through.
+    pub fn set_backpressure(&self, backpressure: Option<Backpressure>) {
+        let mut state = self.contention.lock(&self.state);
+        state.backpressure = backpressure;
+        state.refresh_dead_ratio();
     }
 
-    pub fn write(&self, key: &str, value: &str) -> Result<usize, KopperError> {
-        
-        let mut state = self.state.lock().unwrap();
+    pub fn usage(&self) -> Usage {
+        let state = self.contention.lock(&self.state);
+        Usage { keys: state.user_keys(), bytes: state.live_bytes }
+    }
 
-        let key_len = key.as_bytes().len();
-        let value_len = value.as_bytes().len();
+    /// Breaks down every segment, oldest first, into the bytes compaction would
+    /// keep and the ones it would drop
+    pub fn segment_stats(&self) -> Result<Vec<SegmentStats>, KopperError> {
+        let state = self.contention.lock(&self.state);
+        if state.closed {
+            return Err(KopperError::Closed);
+        }
+        state.segment_stats()
+    }
 
-        // 0. Segment file if next entry would exceed max size
-        if key_len + value_len + 2 + state.offset > self.segment_size {
-            self.cut_off_segment(&mut state);
+    /// How close the database is to running out of file handles, compaction
+    /// throughput or lock time, see [`Saturation`]. Only waits of calls into the
+    /// database count toward the lock, not the ones of its background threads.
+    pub fn saturation(&self) -> Result<Saturation, KopperError> {
+        let state = self.contention.lock(&self.state);
+        if state.closed {
+            return Err(KopperError::Closed);
+        }
+        let (lock_waits, lock_wait) = self.contention.waited();
+        Ok(Saturation {
+            open_segments: state.files.len(),
+            compactor_queue: self.compactor_queue.load(Ordering::Relaxed),
+            compactor_alive: self.compactor_alive(&state),
+            lock_waits,
+            lock_wait,
+        })
+    }
 
-            // Ok to unwrap because sender always exists until receiver exists
-            self.compactor.send(()).unwrap(); 
+    /// Runs a battery of checks on the database, see [`doctor`] - whether it can
+    /// use its directory and take writes, whether the MANIFEST agrees with the
+    /// segments open, how many files the process has open, whether the compactor
+    /// runs, how much of the segments it would drop and what [`Kopper::verify`]
+    /// found.
+    pub fn doctor(&self) -> Result<Report, KopperError> {
+        let state = self.contention.lock(&self.state);
+        if state.closed {
+            return Err(KopperError::Closed);
         }
 
-        // 1. Save in in-memory map
-        let entry = TableEntry {
-            file_index: state.current_file_index,
-            offset: state.offset + key.as_bytes().len() + 1,
-            len: value.as_bytes().len()
+        let segments = state.segment_stats()?;
+        let names: Vec<String> = segments.iter().map(|segment| segment.name.clone()).collect();
+        let manifest = match (&state.segment_log, &state.recovering) {
+            (Some(segment_log), None) => doctor::manifest(segment_log.segments()?, &names),
+            (None, _) => Check::ok("manifest", "Followers don't keep one, the process they follow does"),
+            (_, Some(_)) => Check::ok("manifest", "Not checked until every segment is recovered"),
         };
+        let compactor = self.compactor_alive(&state);
+
+        Ok(Report { checks: vec![
+            doctor::permissions(self.store.path(), &segments),
+            doctor::writes(state.following, state.read_only, state.degraded.as_deref()),
+            manifest,
+            doctor::descriptors(segments.len()),
+            doctor::compactor(compactor),
+            doctor::fragmentation(&segments),
+            doctor::verification(&state.verify_stats()),
+        ] })
+    }
 
-        let result = state.table.insert(key.to_string(), entry);
-        match result {
-            Some(entry) => {
-                println!("{}", &entry.file_index);
-                state.files.get_mut(&entry.file_index).unwrap().unused_count += 1;
+    /// Re-reads the records of up to `sample` keys, see [`verify`], going on from
+    /// where the last call left off, so calls one after another get around to
+    /// every key. Corrupt ones are logged and counted in [`Kopper::verify_stats`],
+    /// and with [`Kopper::set_quarantine`] their segments are quarantined. Returns
+    /// the keys found intact with their values, e.g. to [`Kopper::cross_check`].
+    pub fn verify(&self, sample: usize) -> Result<Vec<(String, String)>, KopperError> {
+        let (sampled, files) = {
+            let mut state = self.contention.lock(&self.state);
+            if state.closed {
+                return Err(KopperError::Closed);
             }
-            None => {},
+            // Values in segments not recovered yet can't be read
+            if state.recovering.is_some() || state.table.is_empty() {
+                return Ok(Vec::new());
+            }
+
+            // The keys after the last one verified, in key order, starting over from the first
+            let after = |key: &String| state.verify_cursor.as_ref().is_none_or(|cursor| key > cursor);
+            let mut keys = first_keys(state.table.keys().filter(|key| after(key)), sample);
+            keys.extend(first_keys(state.table.keys().filter(|key| !after(key)), sample - keys.len()));
+            let sampled: Vec<(String, TableEntry, Result<TableEntry, String>)> = keys.into_iter()
+                .map(|key| {
+                    let entry = state.table[key];
+                    (key.clone(), entry, state.value_entry(key, &entry).map_err(|err| err.to_string()))
+                })
+                .collect();
+            state.verify_cursor = sampled.last().map(|(key, ..)| key.clone());
+
+            // Read off the lock like a scan does, handles keep segments the compactor removes readable
+            let files: HashMap<FileIndex, Arc<dyn SegmentFile>> = state.files.iter()
+                .map(|(index, entry)| (*index, entry.file.clone()))
+                .collect();
+            (sampled, files)
+        };
+
+        let checked: Vec<_> = sampled.iter()
+            .map(|(key, entry, value_entry)| Kopper::verify_key(&files, key, entry, value_entry))
+            .collect();
+
+        let mut state = self.contention.lock(&self.state);
+        if state.closed {
+            return Err(KopperError::Closed);
+        }
+        let mut intact = Vec::new();
+        let mut corrupt = 0;
+        for ((key, entry, value_entry), checked) in sampled.iter().zip(checked) {
+            let segments = [Some(entry.file_index), value_entry.as_ref().ok().map(|entry| entry.file_index)];
+            let value = match checked {
+                // Expired, or in a segment quarantined before or meanwhile
+                _ if state.expired(key) || segments.iter().flatten().any(|segment| state.quarantined.contains(segment)) => continue,
+                Ok(value) => value,
+                Err((segment, problem)) => {
+                    tracing::error!(key_hash = key_hash(key), %segment, "Corrupt record: {problem}");
+                    corrupt += 1;
+                    // Compacted away meanwhile, nothing left to quarantine
+                    if state.files.contains_key(&segment) {
+                        self.quarantine_segment(&mut state, segment)?;
+                    }
+                    continue;
+                },
+            };
+            intact.push((key.clone(), state.hooks.read(key, value)?));
         }
 
-        // 2. Write to disk
-        let mut string_to_save = key.to_string();
-        string_to_save.push('\0');
-        string_to_save.push_str(&value);
-        string_to_save.push('\0');
-        
-        let string_to_save = string_to_save.as_bytes();
-        let file_index = state.current_file_index.clone();
-        state.files.get_mut(&file_index).unwrap().file.write_all(string_to_save)?;
+        state.verify_stats.checked += sampled.len() as u64;
+        state.verify_stats.corrupt += corrupt;
+        if let Some(metrics) = &state.metrics {
+            metrics.record(Stat::Verification { checked: sampled.len() as u128, corrupt: corrupt as u128, mismatched: 0 });
+        }
+        Ok(intact)
+    }
 
-        // Update current offset and total size
-        state.offset += string_to_save.len();
-        state.size += string_to_save.len();
+    /// Value of `key` read back from `entry` in `files`, or the segment with the
+    /// problem found and what it is
+    fn verify_key(files: &HashMap<FileIndex, Arc<dyn SegmentFile>>, key: &str, entry: &TableEntry, value_entry: &Result<TableEntry, String>)
+        -> Result<String, (FileIndex, String)> {
+        let value_entry = value_entry.clone().map_err(|problem| (entry.file_index, problem))?;
+        let read = |at: usize, len: usize, file_index: FileIndex| {
+            let mut bytes = vec![0; len];
+            files.get(&file_index).unwrap().read_at(&mut bytes, at as u64)
+                .map(|_| bytes)
+                .map_err(|err| (file_index, err.to_string()))
+        };
 
-        Ok(state.size)
+        let record = read(entry.offset - key.len() - 1, key.len() + entry.len + 2, entry.file_index)?;
+        verify::check_record(key, &record).map_err(|problem| (entry.file_index, problem))?;
+        // The blob a reference points to
+        let value = if (value_entry.file_index, value_entry.offset) != (entry.file_index, entry.offset) {
+            let blob = read(value_entry.offset, value_entry.len, value_entry.file_index)?;
+            verify::check_value(&blob).map_err(|problem| (value_entry.file_index, problem))?;
+            blob
+        } else {
+            record[key.len() + 1..key.len() + 1 + entry.len].to_vec()
+        };
+        decode_value(&**files.get(&value_entry.file_index).unwrap(), value)
+            .map_err(|err| (value_entry.file_index, err.to_string()))
     }
 
-    fn cut_off_segment(&self, state: &mut std::sync::MutexGuard<'_, SharedState>) {
-              
-        // Increment index - current_file_index is the biggest of all
-        state.current_file_index = FileIndex { base: state.current_file_index.base + 1, index: 0 };
-        let new_file_name = self.path.clone() + "/" + &state.current_file_index.to_string();
+    /// Keeps values from being read from `segment`, or compacted away, if
+    /// quarantining is on. The segment written to is cut off first.
+    fn quarantine_segment(&self, state: &mut MutexGuard<'_, SharedState>, segment: FileIndex) -> Result<(), KopperError> {
+        if !state.quarantine || state.quarantined.contains(&segment) {
+            return Ok(());
+        }
+        if segment == state.current_file_index {
+            // Another process writes to it, or nothing can
+            if state.read_only || state.following {
+                return Ok(());
+            }
+            self.cut_off_segment(state)?;
+        }
+        tracing::error!(%segment, "Segment quarantined");
+        state.quarantined.insert(segment);
+        Ok(())
+    }
 
-        // Create a new file
-        let file = OpenOptions::new()
-                        .read(true)
-                        .append(true)
-                        .create(true)
-                        .open(new_file_name)
-                        .expect("Failed to open file");
+    /// Compares the values [`Kopper::verify`] found `intact` with the ones
+    /// `replica` holds, which returns `None` for the keys it can't tell. Keys
+    /// that differ are logged and counted in [`Kopper::verify_stats`], unless
+    /// they changed here meanwhile. Returns how many differ.
+    pub fn cross_check(&self, intact: &[(String, String)], replica: impl Fn(&str) -> Option<Option<String>>) -> Result<usize, KopperError> {
+        let mut mismatched = 0;
+        for (key, value) in intact {
+            let Some(replicated) = replica(key) else {
+                continue;
+            };
+            if replicated.as_ref() == Some(value) {
+                continue;
+            }
+            let state = self.contention.lock(&self.state);
+            if state.closed {
+                return Err(KopperError::Closed);
+            }
+            let current = Kopper::lookup(&state, key).ok().map(|current| state.hooks.read(key, current)).transpose()?;
+            if current.as_ref() == Some(value) {
+                tracing::warn!(key_hash = key_hash(key), "Value differs on the replica");
+                mismatched += 1;
+            }
+        }
 
-        // Add new file to file table
-        let new_file_index = state.current_file_index;
-        state.files.insert(new_file_index, FileEntry { file: file, unused_count: 0 });
-        state.offset = 0;        
+        let mut state = self.contention.lock(&self.state);
+        state.verify_stats.mismatched += mismatched as u64;
+        if let Some(metrics) = &state.metrics {
+            metrics.record(Stat::Verification { checked: 0, corrupt: 0, mismatched: mismatched as u128 });
+        }
+        Ok(mismatched)
     }
 
-    fn run_compactor(&self, receiver: Receiver<()>) {
+    /// Calls [`Kopper::verify`] with `sample` every `interval` of the database's
+    /// clock, until the database is closed. The keys found intact are compared
+    /// with `replica` too if it's given, see [`Kopper::cross_check`].
+    pub fn start_verifier(&self, interval: Duration, sample: usize, replica: Option<verify::Replica>) {
+        let verifier = self.clone();
+        let clock = self.contention.lock(&self.state).clock.clone();
+        std::thread::spawn(move || loop {
+            clock.sleep(interval);
+            let verified = verifier.verify(sample).and_then(|intact| match &replica {
+                Some(replica) => verifier.cross_check(&intact, replica).map(|_| ()),
+                None => Ok(()),
+            });
+            match verified {
+                Ok(()) => {},
+                Err(KopperError::Closed) => break,
+                Err(err) => tracing::warn!("Can't verify {}: {err}", verifier.path()),
+            }
+        });
+    }
 
-        let state = self.state.clone();
-        let path = self.path.clone();
-        std::thread::spawn(move || {
+    /// Quarantines the segments [`Kopper::verify`] finds corrupt records in, or
+    /// stops to. Values aren't read from a quarantined segment - they fail with
+    /// [`KopperError::Quarantined`] until they're written again - and the compactor
+    /// leaves it alone, so it can be looked into. Lasts until the database is closed.
+    pub fn set_quarantine(&self, quarantine: bool) {
+        let mut state = self.contention.lock(&self.state);
+        state.quarantine = quarantine;
+        if !quarantine {
+            state.quarantined.clear();
+        }
+    }
 
-            fn compact(state_mutex: &Mutex<SharedState>, path: String) {
+    /// Found by [`Kopper::verify`] and [`Kopper::cross_check`] so far
+    pub fn verify_stats(&self) -> VerifyStats {
+        self.contention.lock(&self.state).verify_stats()
+    }
 
-                // Release the lock immidiately after taking a copy of current state
-                let state = state_mutex.lock().unwrap();
+    pub fn compression_stats(&self) -> CompressionStats {
+        self.contention.lock(&self.state).compression_stats
+    }
 
-                // Choose the best file to compact
-                let (mut file_index, mut file_entry) = state.files.first_key_value().unwrap();
+    /// How recovering a database opened with [`Kopper::create_lazily`] goes,
+    /// `None` once every segment is recovered
+    pub fn recovery_progress(&self) -> Option<RecoveryProgress> {
+        self.contention.lock(&self.state).recovering.as_ref().map(|recovering| recovering.progress)
+    }
+
+    /// Blocks until every segment is recovered, see [`Kopper::create_lazily`]
+    pub fn wait_for_recovery(&self) -> Result<(), KopperError> {
+        let mut state = self.contention.lock(&self.state);
+        loop {
+            if state.closed {
+                return Err(KopperError::Closed);
+            }
+            match &state.recovering {
+                None => return Ok(()),
+                Some(Recovering { failed: Some(reason), .. }) => return Err(KopperError::InternalError(anyhow::anyhow!("{reason}"))),
+                Some(_) => self.recovered.wait(&mut state),
+            }
+        }
+    }
+
+    /// Rejects writes and deletes with [`KopperError::ReadOnly`] while set.
+    /// [`Kopper::apply`] still works.
+    pub fn set_read_only(&self, read_only: bool) {
+        self.contention.lock(&self.state).read_only = read_only;
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.contention.lock(&self.state).read_only
+    }
+
+    /// Why writes fail with [`KopperError::Degraded`], if they do. A write that
+    /// ran out of disk space, or whose partial record couldn't be cut off again,
+    /// leaves the database readable but degraded until [`Kopper::resume`].
+    pub fn degraded(&self) -> Option<String> {
+        self.contention.lock(&self.state).degraded.clone()
+    }
+
+    /// Takes writes again after the database was degraded, e.g. once disk space
+    /// was freed. Whatever the failed write left in the current segment is cut off
+    /// first - if that fails, so does this, and the database stays degraded.
+    pub fn resume(&self) -> Result<(), KopperError> {
+        let mut state = self.contention.lock(&self.state);
+
+        if state.closed {
+            return Err(KopperError::Closed);
+        }
+        if state.degraded.is_none() {
+            return Ok(());
+        }
+
+        state.files[&state.current_file_index].file.truncate(state.offset as u64)?;
+        tracing::info!("Taking writes again");
+        state.degraded = None;
+        Ok(())
+    }
+
+    /// Identifies this instance's change log. Sequences start over with every
+    /// [`Kopper::create`], so a sequence only means something together with it.
+    pub fn log_id(&self) -> u64 {
+        self.contention.lock(&self.state).log_id
+    }
+
+    /// Sequence of the newest change, 0 before the first one
+    pub fn sequence(&self) -> u64 {
+        self.contention.lock(&self.state).sequence
+    }
+
+    /// Keeps the newest `capacity` changes for [`Kopper::changes_since`],
+    /// [`DEFAULT_CHANGE_LOG_CAPACITY`] by default. 0 turns the log off.
+    pub fn set_change_log_capacity(&self, capacity: usize) {
+        let mut state = self.contention.lock(&self.state);
+        state.change_log_capacity = capacity;
+        while state.change_log.len() > capacity {
+            state.change_log.pop_front();
+        }
+    }
+
+    /// Up to `limit` changes made after `sequence`, oldest first. Fails with
+    /// [`KopperError::ChangesUnavailable`] if some of them fell out of the change
+    /// log already, or `sequence` is newer than anything this instance made.
+    pub fn changes_since(&self, sequence: u64, limit: usize) -> Result<Vec<ChangeRecord>, KopperError> {
+        let state = self.contention.lock(&self.state);
+
+        if state.closed {
+            return Err(KopperError::Closed);
+        }
+
+        // Sequence of the newest change before the log
+        let before_log = state.sequence - state.change_log.len() as u64;
+        if sequence < before_log || sequence > state.sequence {
+            return Err(KopperError::ChangesUnavailable(sequence));
+        }
+
+        let skip = (sequence - before_log) as usize;
+        Ok(state.change_log.range(skip..).take(limit).cloned().collect())
+    }
+
+    /// Subscribes to reports of finished compactions. Like [`Kopper::watch`], the
+    /// subscription ends when the receiver is dropped or the database is closed.
+    pub fn compactions(&self) -> Receiver<CompactionReport> {
+        let (sender, receiver) = channel();
+        self.contention.lock(&self.state).compaction_listeners.push(sender);
+        receiver
+    }
+
+    /// Seals the active segment and starts a new one, returning its name - e.g.
+    /// before a backup, so it covers everything written so far in sealed segments,
+    /// or to line segments up with a maintenance window. An active segment with
+    /// nothing in it yet stays as it is.
+    pub fn roll_segment(&self) -> Result<String, KopperError> {
+        let mut state = self.writable()?;
+        if state.offset > 0 {
+            self.cut_off_segment(&mut state)?;
+        }
+        Ok(state.current_file_index.to_string())
+    }
+
+    /// Queues a compaction for every sealed segment, returning how many were queued.
+    /// Compactions run in the background - see [`Kopper::compactions`] for their
+    /// results, or [`Kopper::wait_for_compactions`] to wait for them.
+    pub fn compact(&self) -> Result<usize, KopperError> {
+        let state = self.contention.lock(&self.state);
+
+        if state.closed {
+            return Err(KopperError::Closed);
+        }
+        if state.following {
+            return Err(KopperError::ReadOnly);
+        }
+
+        let sealed = state.files.len() - 1;
+        for _ in 0..sealed {
+            // Ok to unwrap because sender always exists until receiver exists
+            self.send_to_compactor(CompactorRequest::Compact).unwrap();
+        }
+        Ok(sealed)
+    }
+
+    /// Blocks until every compaction queued so far has finished
+    pub fn wait_for_compactions(&self) -> Result<(), KopperError> {
+        let (done, finished) = channel();
+        {
+            let state = self.contention.lock(&self.state);
+            if state.closed {
+                return Err(KopperError::Closed);
+            }
+            if state.following {
+                return Ok(());
+            }
+            // Ok to unwrap because sender always exists until receiver exists
+            self.send_to_compactor(CompactorRequest::Barrier(done)).unwrap();
+        }
+
+        finished.recv().map_err(|_| KopperError::Closed)
+    }
+
+    /// Subscribes to changes of keys starting with `prefix` (empty prefix matches
+    /// every key). Events are delivered after the change is written to disk.
+    /// The subscription ends when the receiver is dropped or the database is closed.
+    pub fn watch(&self, prefix: &str) -> Receiver<ChangeEvent> {
+        let (sender, receiver) = channel();
+        self.add_watcher(prefix, Box::new(move |event| sender.send(event).is_ok()));
+        receiver
+    }
+
+    /// Like [`Kopper::watch`], handing events to `deliver` instead of a channel
+    /// until it returns `false` or the [`Subscription`] is dropped. `deliver` is
+    /// called with the database locked, so it mustn't block - e.g. it sends to
+    /// an async channel, so servers don't need a thread per subscriber.
+    pub fn watch_into(&self, prefix: &str, deliver: impl FnMut(ChangeEvent) -> bool + Send + 'static) -> Subscription {
+        let id = self.add_watcher(prefix, Box::new(deliver));
+        let state = Arc::downgrade(&self.state);
+        Subscription::new(move || {
+            if let Some(state) = state.upgrade() {
+                state.lock().watchers.retain(|watcher| watcher.id != id);
+            }
+        })
+    }
+
+    fn add_watcher(&self, prefix: &str, deliver: Box<dyn FnMut(ChangeEvent) -> bool + Send>) -> u64 {
+        let mut state = self.contention.lock(&self.state);
+        let id = state.next_watcher;
+        state.next_watcher += 1;
+        state.watchers.push(Watcher { id, prefix: prefix.to_owned(), deliver });
+        id
+    }
+
+    /// Sends `message` to the subscribers of `channel`, see [`Kopper::subscribe`],
+    /// returning how many got it. Messages aren't stored - not even in the change
+    /// log - so ones nobody is subscribed to are gone, and followers and replicas
+    /// don't get them.
+    pub fn publish(&self, channel: &str, message: &str) -> Result<usize, KopperError> {
+        if self.is_closed() {
+            return Err(KopperError::Closed);
+        }
+        Ok(self.channels.publish(channel, message))
+    }
+
+    /// Subscribes to messages published to `channel` from now on. The
+    /// subscription ends when the receiver is dropped or the database is closed.
+    pub fn subscribe(&self, channel: &str) -> Receiver<String> {
+        self.channels.subscribe(channel)
+    }
+
+    /// Like [`Kopper::subscribe`], handing messages to `deliver` instead of a
+    /// channel, see [`Kopper::watch_into`]
+    pub fn subscribe_into(&self, channel: &str, deliver: impl FnMut(String) -> bool + Send + 'static) -> Subscription {
+        let id = self.channels.subscribe_into(channel, Box::new(deliver));
+        let (channels, channel) = (Arc::downgrade(&self.channels), channel.to_owned());
+        Subscription::new(move || {
+            if let Some(channels) = channels.upgrade() {
+                channels.unsubscribe(&channel, id);
+            }
+        })
+    }
+
+    pub fn read(&self, key: &str) -> Result<String, KopperError> {
+        self.read_by(key, None)
+    }
+
+    /// Like [`Kopper::read`], failing with [`KopperError::TimedOut`] if the
+    /// database is still busy with other calls at `deadline`, or it's passed already
+    pub fn read_until(&self, key: &str, deadline: Instant) -> Result<String, KopperError> {
+        self.read_by(key, Some(deadline))
+    }
+
+    fn read_by(&self, key: &str, deadline: Option<Instant>) -> Result<String, KopperError> {
+        let _span = tracing::trace_span!("read", key_hash = key_hash(key)).entered();
+        check_key(key)?;
+        let mut state = self.lock_until(deadline)?;
+
+        if state.closed {
+            return Err(KopperError::Closed);
+        }
+
+        let value = state.hooks.read(key, Kopper::lookup(&state, key)?)?;
+        state.touch(key);
+        Ok(value)
+    }
+
+    /// Like [`Kopper::read`], along with the metadata the value was written with,
+    /// see [`Kopper::write_with_meta`]
+    pub fn read_with_meta(&self, key: &str) -> Result<(String, Meta), KopperError> {
+        let _span = tracing::trace_span!("read", key_hash = key_hash(key)).entered();
+        check_key(key)?;
+        let mut state = self.contention.lock(&self.state);
+
+        if state.closed {
+            return Err(KopperError::Closed);
+        }
+
+        let (value, meta) = Kopper::lookup_with_meta(&state, key)?;
+        let value = state.hooks.read(key, value)?;
+        state.touch(key);
+        Ok((value, meta))
+    }
+
+    /// Runs `hook` on every value written with [`Kopper::write`] and the like,
+    /// before it's stored - to check it, or to store another value instead, see
+    /// [`hooks`](crate::hooks). Values of lists, sets, hashes and counters aren't
+    /// hooked. Watchers and replicas are told about the values the hooks return.
+    pub fn on_write(&self, hook: impl Fn(&str, String) -> Result<String, KopperError> + Send + Sync + 'static) {
+        self.contention.lock(&self.state).hooks.write.push(Arc::new(hook));
+    }
+
+    /// Runs `hook` on every value [`Kopper::read`], [`Kopper::get_and_set`] and
+    /// [`Kopper::get_and_delete`] return, e.g. to undo what an [`Kopper::on_write`]
+    /// hook did. Scans and folds go over values as they're stored, the way
+    /// replicas get them.
+    pub fn on_read(&self, hook: impl Fn(&str, String) -> Result<String, KopperError> + Send + Sync + 'static) {
+        self.contention.lock(&self.state).hooks.read.push(Arc::new(hook));
+    }
+
+    /// Runs `hook` before every [`Kopper::delete`] and [`Kopper::get_and_delete`].
+    /// Keys expiring, evicted or renamed away aren't hooked.
+    pub fn on_delete(&self, hook: impl Fn(&str) -> Result<(), KopperError> + Send + Sync + 'static) {
+        self.contention.lock(&self.state).hooks.delete.push(Arc::new(hook));
+    }
+
+    /// Value of `key`, with the lock held
+    fn lookup(state: &SharedState, key: &str) -> Result<String, KopperError> {
+        Kopper::lookup_with_meta(state, key).map(|(value, _)| value)
+    }
+
+    /// Like [`Kopper::lookup`], along with the metadata of the value
+    fn lookup_with_meta(state: &SharedState, key: &str) -> Result<(String, Meta), KopperError> {
+        let table_entry = match state.table.get(key) {
+            Some(_) if state.expired(key) => return Err(KopperError::KeyDoesNotExist(key.to_owned())),
+            Some(table_entry) => state.value_entry(key, table_entry)?,
+            None => return Err(missing(state, key)),
+        };
+        if state.quarantined.contains(&table_entry.file_index) {
+            return Err(KopperError::Quarantined(table_entry.file_index.to_string()));
+        }
+
+        let file = 
+        &state.files
+            .get(&table_entry.file_index).unwrap() // Can't recover from this. Should panic.
+            .file;
+
+        tracing::trace!(segment = %table_entry.file_index, offset = table_entry.offset, len = table_entry.len, "reading value");
+        read_value_with_meta(&**file, &table_entry)
+    }
+
+    /// Copies a consistent snapshot of the database into `dir`, which can then be
+    /// opened like any other database. Segments are read through handles cloned 
+    /// upfront, so the compactor removing them meanwhile doesn't affect the copy,
+    /// and only the part of the current file written before this call is included.
+    pub fn backup_to(&self, dir: &str) -> Result<(), KopperError> {
+        let mut files = Vec::new();
+        {
+            let state = self.contention.lock(&self.state);
+
+            if state.closed {
+                return Err(KopperError::Closed);
+            }
+
+            for (index, entry) in state.files.iter() {
+                files.push((*index, entry.file.clone(), entry.file.len()?));
+            }
+        }
+
+        fs::create_dir_all(dir)?;
+        let target = self.store.in_dir(dir);
+        for (index, file, len) in files {
+            copy_prefix(&*file, len, &*target, &index.to_string())?;
+        }
+
+        Ok(())
+    }
+
+    /// Like [`Kopper::backup_to`], but sealed segments are hard-linked instead of
+    /// copied, which is cheap for big databases - they never change, and a link
+    /// keeps the data around even after the compactor removes the original.
+    /// Segments that can't be linked, e.g. across filesystems, are copied, and so
+    /// is the part of the active segment written before this call, and everything
+    /// when segments aren't kept in files. Compaction
+    /// happens under the same lock the links are made under, so the snapshot is
+    /// a single point in time. It's on disk once this returns, and `dir` can be
+    /// opened like any other database. Returns the sequence of the newest change
+    /// the snapshot includes.
+    pub fn snapshot_to(&self, dir: &str) -> Result<u64, KopperError> {
+        let mut copies = Vec::new();
+        let sequence;
+        {
+            let state = self.contention.lock(&self.state);
+
+            if state.closed {
+                return Err(KopperError::Closed);
+            }
+
+            // Links are made under the lock, so the compactor can't remove a segment in between
+            fs::create_dir_all(dir)?;
+            for (index, entry) in state.files.iter() {
+                let target = dir.to_owned() + "/" + &index.to_string();
+                let linked = *index != state.current_file_index && self.store.path()
+                    .is_some_and(|path| fs::hard_link(path.to_owned() + "/" + &index.to_string(), &target).is_ok());
+                if !linked {
+                    copies.push((index.to_string(), entry.file.clone(), entry.file.len()?));
+                }
+            }
+            sequence = state.sequence;
+        }
+
+        let target = self.store.in_dir(dir);
+        for (name, file, len) in copies {
+            copy_prefix(&*file, len, &*target, &name)?;
+        }
+
+        // Linked segments share their data with the database's, which may not be synced yet
+        self.wait_for_flush(sequence)?;
+        File::open(dir)?.sync_all()?;
+        Ok(sequence)
+    }
+
+    /// Backs the database up into `dir` like [`Kopper::snapshot_to`], along with
+    /// a manifest of its segments. Given a `parent` backup made this way, segments
+    /// it or its own parents hold already are left out - sealed segments never change,
+    /// so usually only the ones written or compacted since are kept. `dir` has to
+    /// be empty, and the parents have to stay where they are for [`Kopper::restore`]
+    /// to find them.
+    pub fn backup_incremental(&self, dir: &str, parent: Option<&str>) -> Result<BackupManifest, KopperError> {
+        let (parent, available) = match parent {
+            Some(parent) => (Some(fs::canonicalize(parent)?.to_string_lossy().into_owned()), BackupManifest::available(parent)?),
+            None => (None, Vec::new()),
+        };
+
+        if Path::new(dir).exists() && fs::read_dir(dir)?.next().is_some() {
+            return Err(KopperError::InvalidBackup(format!("{dir} isn't empty")));
+        }
+
+        // Everything is linked first, so the snapshot is consistent, then whatever
+        // the parents have is dropped again
+        let log = self.log_id();
+        let sequence = self.snapshot_to(dir)?;
+
+        let mut segments = Vec::new();
+        for index in segment_indexes(&LocalStore::new(dir))? {
+            let name = index.to_string();
+            let path = Path::new(dir).join(&name);
+            let mut segment = SegmentRecord::describe(&name, &path)?;
+            if available.iter().any(|available| available.same_contents(&segment)) {
+                fs::remove_file(path)?;
+                segment.included = false;
+            }
+            segments.push(segment);
+        }
+
+        let created_at = self.contention.lock(&self.state).clock.now_millis();
+        let manifest = BackupManifest { version: MANIFEST_VERSION, log, sequence, created_at, parent, segments };
+        manifest.save(dir)?;
+        Ok(manifest)
+    }
+
+    /// Copies the backup in `backup`, made with [`Kopper::backup_incremental`], into
+    /// `target` - layering it over its parents if it's an incremental one. Every
+    /// segment is checked against the manifest. The copy shares nothing with the
+    /// backup and is put together next to `target`, so `target` only appears once
+    /// it can be opened like any other database. `target` must not exist or be empty.
+    pub fn restore(backup: &str, target: &str) -> Result<BackupManifest, KopperError> {
+        let manifest = BackupManifest::load(backup)?;
+        let segments = manifest::locate(backup)?;
+
+        let staging = staging_dir(target)?;
+        let result = segments.into_iter().try_for_each(|(segment, source)| {
+            let copy = staging.join(&segment.name);
+            fs::copy(&source, &copy)?;
+            File::open(&copy)?.sync_all()?;
+
+            match SegmentRecord::describe(&segment.name, &copy)?.same_contents(&segment) {
+                true => Ok(()),
+                false => Err(KopperError::InvalidBackup(format!("Segment {} in {} is damaged", segment.name, source.display()))),
+            }
+        });
+
+        match result.and_then(|_| move_into_place(&staging, target)) {
+            Ok(()) => Ok(manifest),
+            Err(err) => {
+                let _ = fs::remove_dir_all(&staging);
+                Err(err)
+            }
+        }
+    }
+
+    /// Copies the database into `target` as a separate database, e.g. to seed a test
+    /// environment or stand up the other half of a blue/green deployment. Works like
+    /// a full [`Kopper::backup_incremental`] restored with [`Kopper::restore`], so the
+    /// copy is a single point in time, is checked against the segments it was made
+    /// from, and appears at `target` only once complete. Returns the manifest of the
+    /// intermediate backup, which is removed afterwards.
+    pub fn clone_to(&self, target: &str) -> Result<BackupManifest, KopperError> {
+        // Hidden - the database skips those when recovering. Inside the database's
+        // directory, so the intermediate backup only links sealed segments.
+        let backup = match self.store.path() {
+            Some(path) => format!("{path}/.clone-{}", new_log_id()),
+            None => format!("{}/.kopper-clone-{}", std::env::temp_dir().display(), new_log_id()),
+        };
+        let result = self.backup_incremental(&backup, None)
+            .and_then(|_| Kopper::restore(&backup, target));
+
+        let _ = fs::remove_dir_all(&backup);
+        result
+    }
+
+    /// Replaces the whole database with the segments in `dir`, e.g. a snapshot
+    /// shipped from another server. Segments are moved, so `dir` has to be on the
+    /// same filesystem. Watchers aren't told about the swap, and the change log
+    /// starts over under a new [`Kopper::log_id`] - whoever was following it has
+    /// to start over too. If it fails midway, the database is left with whatever
+    /// was installed so far.
+    pub fn install(&self, dir: &str) -> Result<(), KopperError> {
+        let Some(path) = self.store.path() else {
+            return Err(KopperError::Unsupported("installing segments"));
+        };
+        let incoming = segment_indexes(&LocalStore::new(dir))?;
+
+        let mut state = self.contention.lock(&self.state);
+
+        if state.closed {
+            return Err(KopperError::Closed);
+        }
+        if state.following {
+            return Err(KopperError::ReadOnly);
+        }
+
+        // Segments are found by listing them until all are in place
+        if let Some(segment_log) = &mut state.segment_log {
+            segment_log.reset()?;
+        }
+
+        // Old segments go first, names may clash
+        for index in state.files.keys() {
+            self.store.delete(&index.to_string())?;
+        }
+        state.files.clear();
+        state.table.clear();
+        state.blobs.clear();
+        state.deduped.clear();
+        state.expiries.clear();
+        state.expiry_queue.clear();
+        state.size = 0;
+        state.generation += 1;
+        // Whatever was left to recover is gone
+        state.recovering = None;
+        self.recovered.notify_all();
+        state.log_id = new_log_id();
+        state.change_log.clear();
+        // None of the incoming segments were synced here
+        state.synced_from = FileIndex { base: 0, index: 0 };
+
+        let mut found = Found::default();
+        for index in incoming {
+            fs::rename(dir.to_owned() + "/" + &index.to_string(), path.to_owned() + "/" + &index.to_string())?;
+
+            let file = packed::open(self.store.create(&index.to_string())?)?;
+            index_tail(&mut state.table, Some(&mut found), index, &*file, 0)?;
+            state.size += file.stored_len()? as usize;
+            state.files.insert(index, FileEntry { file, unused_count: 0, tier: Tier::Hot });
+        }
+
+        let keys: Vec<String> = state.table.keys().cloned().collect();
+        state.last_used.clear();
+        state.lru.clear();
+        state.seed_lru(keys.clone());
+        state.take_up(found, keys);
+        state.prune_blobs();
+        state.count_live_bytes();
+        state.refresh_dead_ratio();
+
+        // Nothing installed - start from an empty file, like a new database
+        if state.files.is_empty() {
+            let index = FileIndex { base: 0, index: 0 };
+            let file = self.store.create(&index.to_string())?;
+            state.files.insert(index, FileEntry { file, unused_count: 0, tier: Tier::Hot });
+        }
+
+        let live: Vec<String> = state.files.keys().map(FileIndex::to_string).collect();
+        if let Some(segment_log) = &mut state.segment_log {
+            segment_log.rewrite(&live)?;
+        }
+        self.store.sync_dir()?;
+        state.synced_dir_changes = state.dir_changes;
+
+        let (index, entry) = state.files.last_key_value().unwrap();
+        let (index, offset) = (*index, entry.file.len()? as usize);
+        state.current_file_index = index;
+        state.offset = offset;
+        state.rebuild_numeric_indexes()
+    }
+
+    /// Returns a consistent, point-in-time view of the whole database, iterating
+    /// over key-value pairs in key order. Only keys are copied upfront - values are
+    /// read lazily, so the view stays cheap for big databases. Writes and compactions 
+    /// done after this call don't affect it.
+    pub fn scan(&self) -> Result<Scan, KopperError> {
+        let state = self.contention.lock(&self.state);
+
+        if state.closed {
+            return Err(KopperError::Closed);
+        }
+        if state.recovering.is_some() {
+            return Err(KopperError::Recovering);
+        }
+
+        let mut entries: Vec<(String, TableEntry)> = state.table.iter()
+            .filter(|(key, _)| !keyspace::is_reserved(key) && !state.expired(key))
+            .map(|(key, entry)| (key.clone(), state.value_entry(key, entry).unwrap_or(*entry)))
+            .collect();
+        entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+
+        // Handles keep files readable even after the compactor removes them,
+        // and values in sealed files never move
+        let files = state.files.iter()
+            .map(|(index, entry)| (*index, entry.file.clone()))
+            .collect();
+
+        Ok(Scan { entries: entries.into_iter(), files, sequence: state.sequence })
+    }
+
+    /// Keys `pattern` matches in key order, only the ones after `after` if given
+    /// and `limit` of them at most. Passing the last one as `after` gets the next
+    /// page. Values aren't read.
+    pub fn scan_match(&self, pattern: &KeyPattern, after: Option<&str>, limit: usize) -> Result<Vec<String>, KopperError> {
+        let state = self.contention.lock(&self.state);
+        if state.closed {
+            return Err(KopperError::Closed);
+        }
+        if state.recovering.is_some() {
+            return Err(KopperError::Recovering);
+        }
+
+        let keys = state.table.keys()
+            .filter(|key| after.is_none_or(|after| key.as_str() > after) && !keyspace::is_reserved(key) && !state.expired(key) && pattern.matches(key));
+        Ok(first_keys(keys, limit).into_iter().cloned().collect())
+    }
+
+    /// Folds every key and its value into `init` with `f`, like [`Iterator::fold`]
+    /// over a [`Kopper::scan`] - but in no particular order, reading segments from
+    /// start to end, in big stretches, instead of every value on its own. For
+    /// passes over all the data, e.g. to sum something up.
+    pub fn fold<B>(&self, init: B, f: impl FnMut(B, &str, &str) -> B) -> Result<B, KopperError> {
+        self.fold_where(|_| true, init, f)
+    }
+
+    /// Keys `keys` match, with their values if `values` matches them as well, in
+    /// key order. `keys` are checked before any value is read - only the values of
+    /// the keys it matches are, in the order of [`Kopper::fold`].
+    pub fn scan_where(&self, keys: impl Fn(&str) -> bool, values: impl Fn(&str, &str) -> bool) -> Result<Vec<(String, String)>, KopperError> {
+        let mut found = self.fold_where(keys, Vec::new(), |mut found, key, value| {
+            if values(key, value) {
+                found.push((key.to_owned(), value.to_owned()));
+            }
+            found
+        })?;
+        found.sort_unstable();
+        Ok(found)
+    }
+
+    /// [`Kopper::fold`] over the keys `keys` match
+    fn fold_where<B>(&self, keys: impl Fn(&str) -> bool, init: B, f: impl FnMut(B, &str, &str) -> B) -> Result<B, KopperError> {
+        let (entries, files) = {
+            let state = self.contention.lock(&self.state);
+            if state.closed {
+                return Err(KopperError::Closed);
+            }
+            if state.recovering.is_some() {
+                return Err(KopperError::Recovering);
+            }
+
+            let entries: Vec<(String, TableEntry)> = state.table.iter()
+                .filter(|(key, _)| !keyspace::is_reserved(key) && !state.expired(key))
+                .map(|(key, entry)| Ok((key.clone(), state.value_entry(key, entry)?)))
+                .collect::<Result<_, KopperError>>()?;
+            // Handles keep files readable even after the compactor removes them,
+            // like for a scan
+            let files: BTreeMap<FileIndex, Arc<dyn SegmentFile>> = state.files.iter()
+                .map(|(index, entry)| (*index, entry.file.clone()))
+                .collect();
+            (entries, files)
+        };
+
+        read_in_order(entries.into_iter().filter(|(key, _)| keys(key)), &files, init, f)
+    }
+
+    /// Sets up the index `name` of numbers `extract` finds in values, replacing
+    /// the one there was, for [`Kopper::lookup_range`] - e.g. to find keys whose
+    /// values have a score over some number without reading every value. Every
+    /// value is read to build it, holding writes back meanwhile, and every change
+    /// after keeps it up to date. Indexes aren't stored - they're set up again
+    /// after opening the database.
+    pub fn create_numeric_index(&self, name: &str, extract: impl Fn(&str, &str) -> Option<f64> + Send + Sync + 'static) -> Result<(), KopperError> {
+        let mut state = self.contention.lock(&self.state);
+        if state.closed {
+            return Err(KopperError::Closed);
+        }
+        if state.recovering.is_some() {
+            return Err(KopperError::Recovering);
+        }
+
+        let extract: Extractor = Arc::new(extract);
+        let mut index = NumericIndex::new(extract);
+        state.fill_numeric_index(&mut index)?;
+        state.numeric_indexes.insert(name.to_owned(), index);
+        Ok(())
+    }
+
+    /// Drops the index `name`, returning whether there was one
+    pub fn drop_numeric_index(&self, name: &str) -> bool {
+        self.contention.lock(&self.state).numeric_indexes.remove(name).is_some()
+    }
+
+    /// Keys the index `name` found numbers in `range` in, in order of the numbers,
+    /// see [`Kopper::create_numeric_index`]
+    pub fn lookup_range(&self, index: &str, range: impl RangeBounds<f64>) -> Result<Vec<String>, KopperError> {
+        let state = self.contention.lock(&self.state);
+        if state.closed {
+            return Err(KopperError::Closed);
+        }
+
+        let Some(numeric_index) = state.numeric_indexes.get(index) else {
+            return Err(KopperError::KeyDoesNotExist(index.to_owned()));
+        };
+        Ok(numeric_index.range(range).filter(|key| !state.expired(key)).map(str::to_owned).collect())
+    }
+
+    /// Writes `value` under `key`, expiring after the default TTL if there's
+    /// one, see [`Kopper::set_default_ttl`]
+    pub fn write(&self, key: &str, value: &str) -> Result<Commit, KopperError> {
+        self.put(key, value, None, &Meta::default(), None)
+    }
+
+    /// Like [`Kopper::write`], failing with [`KopperError::TimedOut`] if the
+    /// database is still busy with other calls, or holding writes back, at
+    /// `deadline`. Nothing is written then.
+    pub fn write_until(&self, key: &str, value: &str, deadline: Instant) -> Result<Commit, KopperError> {
+        self.put(key, value, None, &Meta::default(), Some(deadline))
+    }
+
+    /// Like [`Kopper::write`], storing `meta` along with the value - read back
+    /// with [`Kopper::read_with_meta`]. Writing the key again replaces it, copies
+    /// and renames keep it. Watchers and replicas only get the value.
+    pub fn write_with_meta(&self, key: &str, value: &str, meta: &Meta) -> Result<Commit, KopperError> {
+        meta.check()?;
+        self.put(key, value, None, meta, None)
+    }
+
+    /// Like [`Kopper::write`], with the value expiring once `ttl` passes - on
+    /// the database's clock, see [`Kopper::set_clock`]. Expired keys read as
+    /// missing, and are deleted by [`Kopper::sweep_expired`]. Writing the key
+    /// again without a TTL keeps it, unless there's a default one. Values that expire aren't deduplicated,
+    /// and replicas and followers keep them until they're deleted here.
+    pub fn write_with_ttl(&self, key: &str, value: &str, ttl: Duration) -> Result<Commit, KopperError> {
+        let expires_at = self.contention.lock(&self.state).clock.now_millis().saturating_add(ttl.as_millis() as u64);
+        self.put(key, value, Some(expires_at), &Meta::default(), None)
+    }
+
+    /// Time left until the value of `key` expires, `None` if it never does - zero
+    /// for pinned keys past it
+    pub fn ttl(&self, key: &str) -> Result<Option<Duration>, KopperError> {
+        check_key(key)?;
+        let state = self.contention.lock(&self.state);
+        if state.closed {
+            return Err(KopperError::Closed);
+        }
+        if !state.table.contains_key(key) || state.expired(key) {
+            return Err(missing(&state, key));
+        }
+        Ok(state.expiries.get(key).map(|expires_at| Duration::from_millis(expires_at.saturating_sub(state.clock.now_millis()))))
+    }
+
+    fn put(&self, key: &str, value: &str, expires_at: Option<u64>, meta: &Meta, deadline: Option<Instant>) -> Result<Commit, KopperError> {
+        let _span = tracing::trace_span!("write", key_hash = key_hash(key)).entered();
+
+        check_key(key)?;
+        let mut state = self.unstalled_until(deadline)?;
+        let value = state.hooks.written(key, value)?;
+        self.put_locked(&mut state, key, &value, expires_at, meta)
+    }
+
+    /// Like [`Kopper::put`], holding the lock already
+    fn put_locked(&self, state: &mut MutexGuard<'_, SharedState>, key: &str, value: &str, expires_at: Option<u64>, meta: &Meta) -> Result<Commit, KopperError> {
+        // What the database keeps for itself expires only when it says so
+        let expires_at = expires_at.or_else(|| state.default_expiry().filter(|_| !keyspace::is_reserved(key)));
+        self.keep_version(state, key)?;
+
+        // 1. Write to disk
+        let encoded = Kopper::encode(state, value);
+        let stored = meta::wrap(meta, &encoded);
+        let (record, deduped) = match expires_at {
+            Some(expires_at) => (std::borrow::Cow::Owned(expiry::wrap(expires_at, &stored)), None),
+            None => Kopper::dedupe(state, &stored, &HashMap::new())?,
+        };
+        Kopper::check_quota(state, &[(key, &record)])?;
+        let entry = self.append(state, key, &record)?;
+        tracing::trace!(segment = %entry.file_index, offset = entry.offset, len = entry.len, "appended value");
+        Kopper::count_compressed(state, value, &encoded);
+
+        // 2. Save in in-memory map
+        Kopper::index(state, key, entry, deduped);
+        if let Some(expires_at) = expires_at {
+            state.expire(key, expires_at);
+        }
+
+        // 3. Notify watchers
+        publish(state, key, ChangeEvent::Write { key: key.to_owned(), value: value.to_owned() });
+        self.evict(state)?;
+
+        Ok(Commit { sequence: state.sequence, size: state.size })
+    }
+
+    /// Writes `value` under `key` like [`Kopper::write`], returning the value it
+    /// replaces, `None` if there was none. Nothing else changes the key in between.
+    pub fn get_and_set(&self, key: &str, value: &str) -> Result<Option<String>, KopperError> {
+        let _span = tracing::trace_span!("get_and_set", key_hash = key_hash(key)).entered();
+
+        check_key(key)?;
+        let mut state = self.unstalled()?;
+        let value = state.hooks.written(key, value)?;
+        let previous = match Kopper::lookup(&state, key) {
+            Ok(previous) => Some(state.hooks.read(key, previous)?),
+            Err(KopperError::KeyDoesNotExist(_)) => None,
+            Err(err) => return Err(err),
+        };
+        self.put_locked(&mut state, key, &value, None, &Meta::default())?;
+        Ok(previous)
+    }
+
+    /// Writes `value` under `key` like [`Kopper::write`] if the key still holds
+    /// `expected` - or is missing, for `None` - returning whether it did.
+    /// Nothing else changes the key in between.
+    pub fn compare_and_swap(&self, key: &str, expected: Option<&str>, value: &str) -> Result<bool, KopperError> {
+        let _span = tracing::trace_span!("compare_and_swap", key_hash = key_hash(key)).entered();
+
+        check_key(key)?;
+        let mut state = self.unstalled()?;
+        let current = match Kopper::lookup(&state, key) {
+            Ok(current) => Some(state.hooks.read(key, current)?),
+            Err(KopperError::KeyDoesNotExist(_)) => None,
+            Err(err) => return Err(err),
+        };
+        if current.as_deref() != expected {
+            return Ok(false);
+        }
+        let value = state.hooks.written(key, value)?;
+        self.put_locked(&mut state, key, &value, None, &Meta::default())?;
+        Ok(true)
+    }
+
+    /// Writes `value` under `key` like [`Kopper::write`] unless the key exists,
+    /// returning whether it did. Nothing else writes the key in between.
+    pub fn write_if_absent(&self, key: &str, value: &str) -> Result<bool, KopperError> {
+        self.write_if(key, value, false)
+    }
+
+    /// Writes `value` under `key` like [`Kopper::write`] only if the key exists,
+    /// returning whether it did. Nothing else deletes the key in between.
+    pub fn write_if_present(&self, key: &str, value: &str) -> Result<bool, KopperError> {
+        self.write_if(key, value, true)
+    }
+
+    fn write_if(&self, key: &str, value: &str, present: bool) -> Result<bool, KopperError> {
+        let _span = tracing::trace_span!("write_if", key_hash = key_hash(key), present).entered();
+
+        check_key(key)?;
+        let mut state = self.unstalled()?;
+        let exists = match Kopper::lookup(&state, key) {
+            Ok(_) => true,
+            Err(KopperError::KeyDoesNotExist(_)) => false,
+            Err(err) => return Err(err),
+        };
+        if exists != present {
+            return Ok(false);
+        }
+        let value = state.hooks.written(key, value)?;
+        self.put_locked(&mut state, key, &value, None, &Meta::default())?;
+        Ok(true)
+    }
+
+    /// Writes all `entries` under a single lock acquisition, appending them to disk
+    /// with as few writes as segment boundaries allow. Later entries for the same key win.
+    pub fn write_batch(&self, entries: &[(&str, &str)]) -> Result<Commit, KopperError> {
+
+        for (key, _) in entries {
+            check_key(key)?;
+        }
+        let mut state = self.unstalled()?;
+        let expires_at = state.default_expiry();
+        let values = entries.iter().map(|(key, value)| state.hooks.written(key, value)).collect::<Result<Vec<_>, _>>()?;
+        let entries: Vec<(&str, &str)> = entries.iter().zip(&values).map(|((key, _), value)| (*key, value.as_str())).collect();
+        self.put_batch(&mut state, &entries, true, expires_at)?;
+        self.evict(&mut state)?;
+        Ok(Commit { sequence: state.sequence, size: state.size })
+    }
+
+    /// Writes `entries`, all of them expiring at `expires_at` if it's given
+    fn put_batch(&self, state: &mut MutexGuard<'_, SharedState>, entries: &[(&str, &str)], check_quota: bool, expires_at: Option<u64>) -> Result<(), KopperError> {
+        for (key, _) in entries {
+            self.keep_version(state, key)?;
+        }
+        let stored: Vec<_> = entries.iter().map(|(_, value)| Kopper::encode(state, value)).collect();
+
+        // Values repeated within the batch refer to the first one
+        let mut batch = HashMap::new();
+        let mut deduped = Vec::with_capacity(stored.len());
+        for stored in &stored {
+            if let Some(expires_at) = expires_at {
+                deduped.push((std::borrow::Cow::Owned(expiry::wrap(expires_at, stored)), None));
+                continue;
+            }
+            let (record, dedupe) = Kopper::dedupe(state, stored, &batch)?;
+            if let Some(Record::Blob(hash)) = dedupe {
+                batch.insert(hash, &stored[..]);
+            }
+            deduped.push((record, dedupe));
+        }
+        let records: Vec<(&str, &[u8])> = entries.iter().zip(&deduped)
+            .map(|((key, _), (record, _))| (*key, &record[..]))
+            .collect();
+        if check_quota {
+            Kopper::check_quota(state, &records)?;
+        }
+
+        let table_entries = self.append_batch(state, &records)?;
+
+        for (((key, value), entry), (stored, (_, dedupe))) in entries.iter().zip(table_entries).zip(stored.iter().zip(&deduped)) {
+            Kopper::count_compressed(state, value, stored);
+            Kopper::index(state, key, entry, *dedupe);
+            if let Some(expires_at) = expires_at {
+                state.expire(key, expires_at);
+            }
+            publish(state, key, ChangeEvent::Write { key: key.to_string(), value: value.to_string() });
+        }
+        Ok(())
+    }
+
+    /// Bytes to store for `value`, compressed if the database is set to
+    fn encode<'a>(state: &SharedState, value: &'a str) -> std::borrow::Cow<'a, [u8]> {
+        match &state.compression {
+            Some(compression) => compression.encode(value.as_bytes()),
+            None => std::borrow::Cow::Borrowed(value.as_bytes()),
+        }
+    }
+
+    fn count_compressed(state: &mut SharedState, value: &str, stored: &[u8]) {
+        if stored.len() == value.len() {
+            return;
+        }
+        state.compression_stats.values += 1;
+        state.compression_stats.plain_bytes += value.len() as u64;
+        state.compression_stats.stored_bytes += stored.len() as u64;
+        if let Some(metrics) = &state.metrics {
+            metrics.record(Stat::Compression { plain: value.len() as u128, stored: stored.len() as u128 });
+        }
+    }
+
+    /// Refuses `records` if writing them would take the database over its quota,
+    /// or further over it
+    fn check_quota(state: &SharedState, records: &[(&str, &[u8])]) -> Result<(), KopperError> {
+        let Quota { max_bytes, max_keys } = state.quota;
+        if max_bytes.is_none() && max_keys.is_none() {
+            return Ok(());
+        }
+
+        // Later records of a key replace earlier ones
+        let mut written: HashMap<&str, usize> = HashMap::new();
+        for (key, record) in records {
+            written.insert(key, record.len());
+        }
+        let (mut keys, mut bytes) = (state.user_keys(), state.live_bytes);
+        let (mut more_keys, mut more_bytes) = (false, false);
+        for (key, len) in written.into_iter().filter(|(key, _)| !keyspace::is_reserved(key)) {
+            match state.table.get(key) {
+                Some(entry) => {
+                    bytes = bytes - entry.len + len;
+                    more_bytes |= len > entry.len;
+                },
+                None => {
+                    keys += 1;
+                    bytes += key.len() + len;
+                    (more_keys, more_bytes) = (true, true);
+                },
+            }
+        }
+
+        match (max_keys, max_bytes) {
+            (Some(max), _) if more_keys && keys > max => Err(KopperError::QuotaExceeded(QuotaLimit::Keys(max))),
+            (_, Some(max)) if more_bytes && bytes > max => Err(KopperError::QuotaExceeded(QuotaLimit::Bytes(max))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Record to store for `stored`: a reference if the same value is stored already,
+    /// a blob if the database dedupes values that long, or `stored` as it is.
+    /// `batch` holds the blobs of the records to be written along with it.
+    fn dedupe<'a>(state: &SharedState, stored: &'a [u8], batch: &HashMap<u64, &[u8]>) -> Result<(std::borrow::Cow<'a, [u8]>, Option<Record>), KopperError> {
+        let Some(_) = state.dedupe.filter(|min_len| stored.len() >= *min_len && stored.len() > dedupe::HEADER_LEN) else {
+            return Ok((std::borrow::Cow::Borrowed(stored), None));
+        };
+
+        // A hash is only trusted once the values turn out the same
+        let hash = dedupe::hash(stored);
+        let same = match (batch.get(&hash), state.blobs.get(&hash)) {
+            (Some(blob), _) => Some(*blob == stored),
+            (None, Some(Blob { entry: Some(entry), .. })) => {
+                let mut blob = vec![0; entry.len];
+                state.files[&entry.file_index].file.read_at(&mut blob, entry.offset as u64)?;
+                Some(&blob[dedupe::HEADER_LEN..] == stored)
+            },
+            // Still being recovered
+            (None, Some(_)) => Some(false),
+            (None, None) => None,
+        };
+
+        Ok(match same {
+            Some(true) => (std::borrow::Cow::Owned(dedupe::reference(hash)), Some(Record::Reference(hash))),
+            // Different values with the same hash, only one of them is deduplicated
+            Some(false) => (std::borrow::Cow::Borrowed(stored), None),
+            None => (std::borrow::Cow::Owned(dedupe::blob(hash, stored)), Some(Record::Blob(hash))),
+        })
+    }
+
+    /// Points `key` at its newest value, marking the old one as garbage. A blob
+    /// or a reference written for it, if any, is one more key referring to its hash.
+    fn index(state: &mut SharedState, key: &str, entry: TableEntry, deduped: Option<Record>) {
+        let hash = match deduped {
+            Some(Record::Blob(hash)) => {
+                state.blobs.insert(hash, Blob { entry: Some(entry), refs: 0 });
+                Some(hash)
+            },
+            Some(Record::Reference(hash)) => Some(hash),
+            None => None,
+        };
+
+        // Referred to first, the old record may have been the last one referring to the same blob
+        if let Some(blob) = hash.and_then(|hash| state.blobs.get_mut(&hash)) {
+            blob.refs += 1;
+        }
+        let old_hash = match hash {
+            Some(hash) => state.deduped.insert(key.to_owned(), hash),
+            None => state.deduped.remove(key),
+        };
+        if let Some(old_hash) = old_hash {
+            state.release(old_hash);
+        }
+        state.persist(key);
+        state.touch(key);
+
+        let reserved = keyspace::is_reserved(key);
+        if !reserved {
+            state.live_bytes += entry.len;
+        }
+        match state.table.insert(key.to_string(), entry) {
+            Some(old_entry) => {
+                if !reserved {
+                    state.live_bytes -= old_entry.len;
+                }
+                state.files.get_mut(&old_entry.file_index).unwrap().unused_count += 1;
+            },
+            None if reserved => state.reserved_keys += 1,
+            None => state.live_bytes += key.len(),
+        }
+    }
+
+    /// Deletes the least recently used keys while the database holds more than
+    /// its [`Eviction`] limits allow, keeping the one used last
+    fn evict(&self, state: &mut MutexGuard<'_, SharedState>) -> Result<(), KopperError> {
+        let Some(Eviction { max_bytes, max_keys }) = state.eviction else {
+            return Ok(());
+        };
+        // Keys not recovered yet can't be told apart from ones never used
+        if state.recovering.is_some() {
+            return Ok(());
+        }
+        let over = |state: &SharedState| max_keys.is_some_and(|max| state.user_keys() > max)
+            || max_bytes.is_some_and(|max| state.live_bytes > max);
+
+        let mut evicted = 0;
+        while over(state) {
+            let last = state.lru.last().map(|(_, key)| key);
+            let Some((_, key)) = state.lru.iter().find(|(_, key)| Some(key) != last && !state.pinned.contains(key)).cloned() else {
+                break;
+            };
+            self.remove(state, &key)?;
+            evicted += 1;
+        }
+        if evicted > 0 {
+            tracing::debug!(keys = evicted, "evicted least recently used keys");
+            // Ok to unwrap because sender always exists until receiver exists
+            self.send_to_compactor(CompactorRequest::Compact).unwrap();
+        }
+        Ok(())
+    }
+
+    /// Deletes up to `limit` keys whose values expired, soonest expired first,
+    /// returning how many. Deleting appends a tombstone, like [`Kopper::delete`] -
+    /// the expired value may shadow an older one, which would be back if the
+    /// compactor just dropped it. Nothing is deleted while the database is
+    /// read-only, or still being recovered.
+    pub fn sweep_expired(&self, limit: usize) -> Result<usize, KopperError> {
+        let mut state = self.contention.lock(&self.state);
+        if state.closed {
+            return Err(KopperError::Closed);
+        }
+        if state.read_only || state.following || state.degraded.is_some() || state.recovering.is_some() {
+            return Ok(0);
+        }
+
+        let now = state.clock.now_millis();
+        let expired: Vec<String> = state.expiry_queue.iter()
+            .take_while(|(expires_at, _)| *expires_at <= now)
+            .filter(|(_, key)| !state.pinned.contains(key))
+            .take(limit)
+            .map(|(_, key)| key.clone())
+            .collect();
+        for key in &expired {
+            self.remove(&mut state, key)?;
+        }
+        if !expired.is_empty() {
+            tracing::debug!(keys = expired.len(), "swept expired keys");
+        }
+        Ok(expired.len())
+    }
+
+    /// Calls [`Kopper::sweep_expired`] with `limit` every `interval` of the
+    /// database's clock, until the database is closed
+    pub fn start_sweeper(&self, interval: Duration, limit: usize) {
+        let sweeper = self.clone();
+        let clock = self.contention.lock(&self.state).clock.clone();
+        std::thread::spawn(move || loop {
+            clock.sleep(interval);
+            match sweeper.sweep_expired(limit) {
+                Ok(_) => {},
+                Err(KopperError::Closed) => break,
+                Err(err) => tracing::warn!("Can't sweep expired keys of {}: {err}", sweeper.path()),
+            }
+        });
+    }
+
+    /// Removes `key` by appending a tombstone record.
+    pub fn delete(&self, key: &str) -> Result<Commit, KopperError> {
+        let _span = tracing::trace_span!("delete", key_hash = key_hash(key)).entered();
+
+        check_key(key)?;
+        let mut state = self.writable()?;
+
+        if !state.table.contains_key(key) {
+            return Err(missing(&state, key));
+        }
+
+        state.hooks.deleted(key)?;
+        self.trash(&mut state, key)?;
+        self.remove(&mut state, key)?;
+        Ok(Commit { sequence: state.sequence, size: state.size })
+    }
+
+    /// Deletes `key` like [`Kopper::delete`], returning the value it had. Nothing
+    /// else changes the key in between, so only one caller gets the value.
+    pub fn get_and_delete(&self, key: &str) -> Result<String, KopperError> {
+        let _span = tracing::trace_span!("get_and_delete", key_hash = key_hash(key)).entered();
+
+        check_key(key)?;
+        let mut state = self.writable()?;
+        let value = state.hooks.read(key, Kopper::lookup(&state, key)?)?;
+        state.hooks.deleted(key)?;
+        self.trash(&mut state, key)?;
+        self.remove(&mut state, key)?;
+        Ok(value)
+    }
+
+    /// Restores the value `key` had when it was deleted, within the undelete
+    /// window, see [`Kopper::set_undelete_window`]. It comes back like it was
+    /// written again, without the TTL it had. Rejected while the key holds a
+    /// value, [`KopperError::KeyDoesNotExist`] if there's none to restore.
+    pub fn undelete(&self, key: &str) -> Result<Commit, KopperError> {
+        let _span = tracing::trace_span!("undelete", key_hash = key_hash(key)).entered();
+
+        check_key(key)?;
+        let mut state = self.unstalled()?;
+        match Kopper::lookup(&state, key) {
+            Ok(_) => return Err(KopperError::Rejected(format!("{key} wasn't deleted"))),
+            Err(KopperError::KeyDoesNotExist(_)) => {},
+            Err(err) => return Err(err),
+        }
+        let trash_key = trash::trash_key(key);
+        let (value, meta) = Kopper::lookup_with_meta(&state, &trash_key)
+            .map_err(|err| match err {
+                KopperError::KeyDoesNotExist(_) => KopperError::KeyDoesNotExist(key.to_owned()),
+                err => err,
+            })?;
+        let commit = self.put_locked(&mut state, key, &value, None, &meta)?;
+        self.remove(&mut state, &trash_key)?;
+        Ok(commit)
+    }
+
+    /// Keeps the value of `key` for the undelete window before it's removed
+    fn trash(&self, state: &mut MutexGuard<'_, SharedState>, key: &str) -> Result<(), KopperError> {
+        let Some(window) = state.undelete_window else {
+            return Ok(());
+        };
+        let (value, meta) = match Kopper::lookup_with_meta(state, key) {
+            Ok(_) if Kopper::chained_kind(state, key)?.is_some() => return Ok(()),
+            Ok(found) => found,
+            Err(KopperError::KeyDoesNotExist(_)) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let expires_at = state.clock.now_millis().saturating_add(window.as_millis() as u64);
+        self.put_locked(state, &trash::trash_key(key), &value, Some(expires_at), &meta)?;
+        Ok(())
+    }
+
+    fn remove(&self, state: &mut MutexGuard<'_, SharedState>, key: &str) -> Result<(), KopperError> {
+        self.keep_version(state, key)?;
+        let tombstone = self.append(state, key, TOMBSTONE)?;
+        Kopper::unindex(state, key, tombstone);
+        Ok(())
+    }
+
+    /// Drops `key` from the in-memory map once its `tombstone` is written
+    fn unindex(state: &mut SharedState, key: &str, tombstone: TableEntry) {
+        // Both the old value and the tombstone itself are garbage for the compactor
+        if let Some(entry) = state.table.remove(key) {
+            match keyspace::is_reserved(key) {
+                true => state.reserved_keys -= 1,
+                false => state.live_bytes -= key.len() + entry.len,
+            }
+            state.files.get_mut(&entry.file_index).unwrap().unused_count += 1;
+        }
+        if let Some(hash) = state.deduped.remove(key) {
+            state.release(hash);
+        }
+        state.persist(key);
+        state.forget(key);
+        state.files.get_mut(&tombstone.file_index).unwrap().unused_count += 1;
+        if let Some(recovering) = &mut state.recovering {
+            recovering.shadowed.insert(key.to_owned());
+        }
+
+        publish(state, key, ChangeEvent::Delete { key: key.to_owned() });
+    }
+
+    /// Moves the value of `from` to `to`, replacing whatever `to` held - like a
+    /// write of `to` and a delete of `from`, appended together so nothing comes
+    /// in between. The value keeps its TTL, if it has one.
+    pub fn rename(&self, from: &str, to: &str) -> Result<Commit, KopperError> {
+        let _span = tracing::trace_span!("rename", key_hash = key_hash(from)).entered();
+        self.copy_value(from, to, true)
+    }
+
+    /// Writes the value of `from` under `to` as well, replacing whatever `to` held,
+    /// without anything changing `from` in between. The copy keeps the TTL of the
+    /// value, if it has one, and refers to the same value if it's deduplicated.
+    pub fn copy(&self, from: &str, to: &str) -> Result<Commit, KopperError> {
+        let _span = tracing::trace_span!("copy", key_hash = key_hash(from)).entered();
+        self.copy_value(from, to, false)
+    }
+
+    /// Copies the value of `from` to `to`, deleting `from` in the same append if `rename`
+    fn copy_value(&self, from: &str, to: &str, rename: bool) -> Result<Commit, KopperError> {
+        check_key(from)?;
+        check_key(to)?;
+        let mut state = self.unstalled()?;
+        let (value, meta) = Kopper::lookup_with_meta(&state, from)?;
+        if from == to {
+            return Ok(Commit { sequence: state.sequence, size: state.size });
+        }
+
+        // A set, a hash, a counter or a list stays one, written whole
+        let whole = match Kopper::chained_kind(&state, from)? {
+            Some(_) => Kopper::chained(&state, from)?.map(|chained| Kopper::whole(&state, chained)).transpose()?,
+            None => None,
+        };
+        let expires_at = state.expiries.get(from).copied().filter(|_| whole.is_none());
+        let encoded = Kopper::encode(&state, &value);
+        let stored = meta::wrap(&meta, &encoded);
+        let (record, deduped) = match (&whole, expires_at) {
+            (Some(whole), _) => (std::borrow::Cow::Borrowed(&whole[..]), None),
+            (None, Some(expires_at)) => (std::borrow::Cow::Owned(expiry::wrap(expires_at, &stored)), None),
+            (None, None) => Kopper::dedupe(&state, &stored, &HashMap::new())?,
+        };
+        let mut records = vec![(to, &record[..])];
+        // A rename doesn't add a key, and adds no more than the keys' lengths differ
+        if rename {
+            records.push((from, TOMBSTONE));
+        } else {
+            Kopper::check_quota(&state, &records)?;
+        }
+
+        let entries = self.append_batch(&mut state, &records)?;
+        if whole.is_none() {
+            Kopper::count_compressed(&mut state, &value, &encoded);
+        }
+        Kopper::index(&mut state, to, entries[0], deduped);
+        if let Some(expires_at) = expires_at {
+            state.expire(to, expires_at);
+        }
+        publish(&mut state, to, ChangeEvent::Write { key: to.to_owned(), value });
+        if rename {
+            Kopper::unindex(&mut state, from, entries[1]);
+        }
+        self.evict(&mut state)?;
+
+        Ok(Commit { sequence: state.sequence, size: state.size })
+    }
+
+    /// Pushes `values` onto the start of the list under `key`, see [`list`],
+    /// creating it if there's none. The last of them ends up first, like Redis'
+    /// LPUSH. Returns how long the list is afterwards. Only the values pushed are
+    /// written. Like sets, lists don't expire, and aren't deduplicated or compressed.
+    pub fn lpush(&self, key: &str, values: &[&str]) -> Result<usize, KopperError> {
+        let ops: Vec<_> = values.iter().map(|value| list::Op::Push(list::End::Left, value)).collect();
+        self.update_list(key, &ops).map(|(len, _)| len)
+    }
+
+    /// Like [`Kopper::lpush`], pushing `values` onto the end of the list in order
+    pub fn rpush(&self, key: &str, values: &[&str]) -> Result<usize, KopperError> {
+        let ops: Vec<_> = values.iter().map(|value| list::Op::Push(list::End::Right, value)).collect();
+        self.update_list(key, &ops).map(|(len, _)| len)
+    }
+
+    /// Takes the first element off the list under `key`, `None` if there's no
+    /// such list. A list left empty is deleted.
+    pub fn lpop(&self, key: &str) -> Result<Option<String>, KopperError> {
+        self.update_list(key, &[list::Op::Pop(list::End::Left)]).map(|(_, popped)| popped)
+    }
+
+    /// Like [`Kopper::lpop`], taking the last element
+    pub fn rpop(&self, key: &str) -> Result<Option<String>, KopperError> {
+        self.update_list(key, &[list::Op::Pop(list::End::Right)]).map(|(_, popped)| popped)
+    }
+
+    /// Elements `start` to `stop` of the list under `key`, as [`list::range`]
+    /// picks them. Empty if there's no such list.
+    pub fn lrange(&self, key: &str, start: i64, stop: i64) -> Result<Vec<String>, KopperError> {
+        check_key(key)?;
+        let mut state = self.contention.lock(&self.state);
+        if state.closed {
+            return Err(KopperError::Closed);
+        }
+
+        let list = match Kopper::chained_of(&state, key, chain::Kind::List)? {
+            Some(newest) => list::elements(newest.record, segment_reader(&*state.files[&newest.entry.file_index].file))?,
+            None => return Ok(Vec::new()),
+        };
+        state.touch(key);
+        Ok(list::range(&list, start, stop))
+    }
+
+    /// Makes `ops` on the list under `key`, returning how long it is afterwards
+    /// and the last element popped. Pops of an empty list change nothing, and a
+    /// list left empty is deleted.
+    fn update_list(&self, key: &str, ops: &[list::Op]) -> Result<(usize, Option<String>), KopperError> {
+        let _span = tracing::trace_span!("update_list", key_hash = key_hash(key)).entered();
+
+        check_key(key)?;
+        let mut state = self.unstalled()?;
+        let newest = Kopper::chained_of(&state, key, chain::Kind::List)?;
+        let mut after = match &newest {
+            Some(newest) => list::elements(newest.record.clone(), segment_reader(&*state.files[&newest.entry.file_index].file))?,
+            None => VecDeque::new(),
+        };
+
+        let mut changed = Vec::with_capacity(ops.len());
+        let mut popped = None;
+        for op in ops.iter().copied() {
+            if matches!(op, list::Op::Pop(_)) && after.is_empty() {
+                continue;
+            }
+            popped = list::apply(&mut after, op).or(popped);
+            changed.push(op);
+        }
+        if changed.is_empty() {
+            return Ok((after.len(), popped));
+        }
+        if after.is_empty() {
+            self.remove(&mut state, key)?;
+            return Ok((0, popped));
+        }
+
+        self.append_change(&mut state, key, newest.as_ref(),
+            |offset, len, previous| list::delta(offset, len, previous, &changed),
+            || list::full(&after),
+            list::to_json(&after))?;
+        Ok((after.len(), popped))
+    }
+
+    /// Appends `item` to the queue `queue`, see [`queue`], creating it if there's
+    /// none. Returns the offset of the item. Queues don't expire.
+    pub fn push(&self, queue: &str, item: &str) -> Result<u64, KopperError> {
+        let _span = tracing::trace_span!("push", key_hash = key_hash(queue)).entered();
+
+        check_key(queue)?;
+        let mut state = self.unstalled()?;
+        let mut offsets = Kopper::lookup_offsets(&state, queue)?;
+        let offset = offsets.pushed;
+        offsets.pushed += 1;
+
+        let (item_key, offsets_key) = (queue::item_key(queue, offset), queue::offsets_key(queue));
+        self.put_batch(&mut state, &[(&item_key, item), (&offsets_key, &queue::encode(&offsets))], true, None)?;
+        let pushed = ChangeEvent::Write { key: queue.to_owned(), value: item.to_owned() };
+        state.poppers.retain_mut(|popper| popper.prefix != queue || (popper.deliver)(pushed.clone()));
+        self.evict(&mut state)?;
+        Ok(offset)
+    }
+
+    /// Takes the item at the front of the queue `queue`, waiting up to `timeout`
+    /// for one to be pushed if it's empty. `None` if none was. Every item is
+    /// taken once, by one of the callers waiting for it. A queue left empty stays,
+    /// with its offsets. Waits on this thread - see [`Kopper::watch_pushes`] to
+    /// wait some other way.
+    pub fn pop(&self, queue: &str, timeout: Duration) -> Result<Option<String>, KopperError> {
+        let _span = tracing::trace_span!("pop", key_hash = key_hash(queue)).entered();
+
+        // Watching first, so a push right after looking isn't missed
+        let (pushed, pushes) = channel();
+        let _subscription = self.watch_pushes(queue, move |_| pushed.send(()).is_ok())?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(item) = self.try_pop(queue)? {
+                return Ok(Some(item));
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            match pushes.recv_timeout(left) {
+                Ok(_) => {},
+                Err(RecvTimeoutError::Timeout) => return Ok(None),
+                Err(RecvTimeoutError::Disconnected) => return Err(KopperError::Closed),
+            }
+        }
+    }
+
+    /// Takes the item at the front of the queue `queue`, `None` if it's empty
+    pub fn try_pop(&self, queue: &str) -> Result<Option<String>, KopperError> {
+        let _span = tracing::trace_span!("try_pop", key_hash = key_hash(queue)).entered();
+
+        check_key(queue)?;
+        let mut state = self.unstalled()?;
+        let mut offsets = Kopper::lookup_offsets(&state, queue)?;
+        if offsets.popped == offsets.pushed {
+            return Ok(None);
+        }
+        let item_key = queue::item_key(queue, offsets.popped);
+        let item = Kopper::lookup(&state, &item_key)?;
+        offsets.popped += 1;
+
+        // Both in one append, so an item is never popped twice nor lost
+        let (offsets_key, offsets) = (queue::offsets_key(queue), queue::encode(&offsets));
+        let encoded = Kopper::encode(&state, &offsets);
+        let entries = self.append_batch(&mut state, &[(&offsets_key, &encoded), (&item_key, TOMBSTONE)])?;
+        Kopper::count_compressed(&mut state, &offsets, &encoded);
+        Kopper::index(&mut state, &offsets_key, entries[0], None);
+        publish(&mut state, &offsets_key, ChangeEvent::Write { key: offsets_key.clone(), value: offsets });
+        Kopper::unindex(&mut state, &item_key, entries[1]);
+        Ok(Some(item))
+    }
+
+    /// Hands an event of every item pushed to the queue `queue` from now on to
+    /// `deliver`, until it returns `false` or the [`Subscription`] is dropped.
+    /// Like [`Kopper::watch_into`], `deliver` mustn't block - e.g. it wakes an
+    /// async task, which then tries [`Kopper::try_pop`].
+    pub fn watch_pushes(&self, queue: &str, deliver: impl FnMut(ChangeEvent) -> bool + Send + 'static) -> Result<Subscription, KopperError> {
+        check_key(queue)?;
+        let mut state = self.contention.lock(&self.state);
+        if state.closed {
+            return Err(KopperError::Closed);
+        }
+        let id = state.next_watcher;
+        state.next_watcher += 1;
+        state.poppers.push(Watcher { id, prefix: queue.to_owned(), deliver: Box::new(deliver) });
+
+        let state = Arc::downgrade(&self.state);
+        Ok(Subscription::new(move || {
+            if let Some(state) = state.upgrade() {
+                state.lock().poppers.retain(|popper| popper.id != id);
+            }
+        }))
+    }
+
+    /// Up to `max` items of the queue `queue` from the offset `consumer` read up
+    /// to, with their offsets, oldest first. They stay in the queue - the consumer
+    /// moves on with [`Kopper::commit_offset`]. Items popped meanwhile are skipped.
+    pub fn consume(&self, queue: &str, consumer: &str, max: usize) -> Result<Vec<(u64, String)>, KopperError> {
+        check_key(queue)?;
+        let state = self.contention.lock(&self.state);
+        if state.closed {
+            return Err(KopperError::Closed);
+        }
+
+        let offsets = Kopper::lookup_offsets(&state, queue)?;
+        let from = offsets.consumers.get(consumer).copied().unwrap_or(0).max(offsets.popped);
+        (from..offsets.pushed).take(max)
+            .map(|offset| Ok((offset, Kopper::lookup(&state, &queue::item_key(queue, offset))?)))
+            .collect()
+    }
+
+    /// Has `consumer` go on reading the queue `queue` from `offset`, e.g. past
+    /// the items it's done with. Kept on disk, so it picks up where it left off.
+    pub fn commit_offset(&self, queue: &str, consumer: &str, offset: u64) -> Result<(), KopperError> {
+        let _span = tracing::trace_span!("commit_offset", key_hash = key_hash(queue)).entered();
+
+        check_key(queue)?;
+        let mut state = self.unstalled()?;
+        let mut offsets = Kopper::lookup_offsets(&state, queue)?;
+        let end = offsets.pushed;
+        if offset > end {
+            return Err(KopperError::Rejected(format!("Offset {offset} is past the end of {queue}, at {end}")));
+        }
+        offsets.consumers.insert(consumer.to_owned(), offset);
+
+        let offsets_key = queue::offsets_key(queue);
+        let expires_at = state.expiries.get(&offsets_key).copied();
+        self.put_locked(&mut state, &offsets_key, &queue::encode(&offsets), expires_at, &Meta::default())?;
+        Ok(())
+    }
+
+    /// Offset `consumer` reads the queue `queue` from, see [`Kopper::commit_offset`]
+    pub fn offset(&self, queue: &str, consumer: &str) -> Result<u64, KopperError> {
+        check_key(queue)?;
+        let state = self.contention.lock(&self.state);
+        if state.closed {
+            return Err(KopperError::Closed);
+        }
+        let offsets = Kopper::lookup_offsets(&state, queue)?;
+        Ok(offsets.consumers.get(consumer).copied().unwrap_or(0).max(offsets.popped))
+    }
+
+    /// Offsets of the queue `queue`, none read or popped if there are none yet
+    fn lookup_offsets(state: &SharedState, queue: &str) -> Result<queue::Offsets, KopperError> {
+        let key = queue::offsets_key(queue);
+        match Kopper::lookup(state, &key) {
+            Ok(_) if Kopper::chained_kind(state, &key)?.is_some() => Err(KopperError::WrongType(key)),
+            Ok(value) => queue::decode(&key, &value),
+            Err(KopperError::KeyDoesNotExist(_)) => Ok(queue::Offsets::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Takes the lock `name` for `ttl` unless it's held, see [`lock`]. Returns the
+    /// fencing token of the lease, `None` if the lock is held. Every lease gets a
+    /// greater token than the ones before it, so whatever the holder writes to can
+    /// turn down writes with older tokens - e.g. of a holder that stalled past its
+    /// lease.
+    pub fn acquire_lock(&self, name: &str, ttl: Duration) -> Result<Option<u64>, KopperError> {
+        let _span = tracing::trace_span!("acquire_lock", key_hash = key_hash(name)).entered();
+
+        check_key(name)?;
+        let mut state = self.unstalled()?;
+        let key = lock::lock_key(name);
+        let last = Kopper::lookup_lease(&state, &key)?;
+        let now = state.clock.now_millis();
+        if last.held(now) {
+            return Ok(None);
+        }
+
+        let lease = lock::Lease { token: last.token + 1, expires_at: now.saturating_add(ttl.as_millis() as u64) };
+        self.put_locked(&mut state, &key, &lock::encode(&lease), None, &Meta::default())?;
+        Ok(Some(lease.token))
+    }
+
+    /// Gives up the lease on the lock `name` with the fencing `token`, returning
+    /// whether it was still held with it. A lease that expired, and maybe went to
+    /// someone else since, is left alone.
+    pub fn release_lock(&self, name: &str, token: u64) -> Result<bool, KopperError> {
+        let _span = tracing::trace_span!("release_lock", key_hash = key_hash(name)).entered();
+
+        check_key(name)?;
+        let mut state = self.writable()?;
+        let key = lock::lock_key(name);
+        let lease = Kopper::lookup_lease(&state, &key)?;
+        if lease.token != token || !lease.held(state.clock.now_millis()) {
+            return Ok(false);
+        }
+        // The token stays, for the next lease to outgrow
+        let released = lock::Lease { expires_at: 0, ..lease };
+        self.put_locked(&mut state, &key, &lock::encode(&released), None, &Meta::default())?;
+        Ok(true)
+    }
+
+    /// Last lease on the lock under `key`, a blank one if it was never taken
+    fn lookup_lease(state: &SharedState, key: &str) -> Result<lock::Lease, KopperError> {
+        match Kopper::lookup(state, key) {
+            Ok(value) => lock::decode(key, &value),
+            Err(KopperError::KeyDoesNotExist(_)) => Ok(lock::Lease::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Adds `members` to the set under `key`, see [`set`], creating it if there's
+    /// none. Returns how many weren't in it yet - only those are written. Sets
+    /// don't expire, and aren't deduplicated or compressed.
+    pub fn sadd(&self, key: &str, members: &[&str]) -> Result<usize, KopperError> {
+        self.update_set(key, members, true)
+    }
+
+    /// Removes `members` from the set under `key`, returning how many were in it.
+    /// A set left empty is deleted.
+    pub fn srem(&self, key: &str, members: &[&str]) -> Result<usize, KopperError> {
+        self.update_set(key, members, false)
+    }
+
+    /// Members of the set under `key` in order, none if there's no such set
+    pub fn smembers(&self, key: &str) -> Result<BTreeSet<String>, KopperError> {
+        check_key(key)?;
+        let mut state = self.contention.lock(&self.state);
+        if state.closed {
+            return Err(KopperError::Closed);
+        }
+
+        let members = match Kopper::chained_of(&state, key, chain::Kind::Set)? {
+            Some(newest) => set::members(newest.record, segment_reader(&*state.files[&newest.entry.file_index].file))?,
+            None => return Ok(BTreeSet::new()),
+        };
+        state.touch(key);
+        Ok(members)
+    }
+
+    /// Whether `member` is in the set under `key`
+    pub fn sismember(&self, key: &str, member: &str) -> Result<bool, KopperError> {
+        Ok(self.smembers(key)?.contains(member))
+    }
+
+    /// Adds `members` to the set under `key`, or removes them unless `add`, and
+    /// returns how many that changes
+    fn update_set(&self, key: &str, members: &[&str], add: bool) -> Result<usize, KopperError> {
+        let _span = tracing::trace_span!("update_set", key_hash = key_hash(key)).entered();
+
+        check_key(key)?;
+        let mut state = self.unstalled()?;
+        let newest = Kopper::chained_of(&state, key, chain::Kind::Set)?;
+        let mut after = match &newest {
+            Some(newest) => set::members(newest.record.clone(), segment_reader(&*state.files[&newest.entry.file_index].file))?,
+            None => BTreeSet::new(),
+        };
+
+        let mut changed: Vec<&str> = members.iter().copied().filter(|member| after.contains(*member) != add).collect();
+        changed.sort_unstable();
+        changed.dedup();
+        if changed.is_empty() {
+            return Ok(0);
+        }
+        for member in &changed {
+            match add {
+                true => after.insert((*member).to_owned()),
+                false => after.remove(*member),
+            };
+        }
+        if after.is_empty() {
+            self.remove(&mut state, key)?;
+            return Ok(changed.len());
+        }
+
+        let (added, removed): (&[&str], &[&str]) = match add {
+            true => (&changed, &[]),
+            false => (&[], &changed),
+        };
+        self.append_change(&mut state, key, newest.as_ref(),
+            |offset, len, previous| set::delta(offset, len, previous, added, removed),
+            || set::full(&after),
+            set::to_json(&after))?;
+        Ok(changed.len())
+    }
+
+    /// Sets `fields` of the hash under `key`, see [`hash`], creating it if there's
+    /// none. Returns how many of them are new. Only the fields whose value changes
+    /// are written. Like sets, hashes don't expire, and aren't deduplicated or compressed.
+    pub fn hset(&self, key: &str, fields: &[(&str, &str)]) -> Result<usize, KopperError> {
+        let _span = tracing::trace_span!("hset", key_hash = key_hash(key)).entered();
+
+        check_key(key)?;
+        let mut state = self.unstalled()?;
+        let newest = Kopper::chained_of(&state, key, chain::Kind::Hash)?;
+        let mut after = match &newest {
+            Some(newest) => hash::fields(newest.record.clone(), segment_reader(&*state.files[&newest.entry.file_index].file))?,
+            None => BTreeMap::new(),
+        };
+
+        // Later values of the same field win
+        let mut changed: Vec<(&str, &str)> = Vec::new();
+        let mut added = 0;
+        for (field, value) in fields.iter().copied() {
+            match after.insert(field.to_owned(), value.to_owned()) {
+                Some(previous) if previous == value => continue,
+                Some(_) => {},
+                None => added += 1,
+            }
+            changed.retain(|(changed, _)| *changed != field);
+            changed.push((field, value));
+        }
+        if changed.is_empty() {
+            return Ok(0);
+        }
+
+        self.append_change(&mut state, key, newest.as_ref(),
+            |offset, len, previous| hash::delta(offset, len, previous, &changed, &[]),
+            || hash::full(&after),
+            hash::to_json(&after))?;
+        Ok(added)
+    }
+
+    /// Value of `field` of the hash under `key`, `None` if there's no such field or hash
+    pub fn hget(&self, key: &str, field: &str) -> Result<Option<String>, KopperError> {
+        Ok(self.hgetall(key)?.remove(field))
+    }
+
+    /// Deletes `fields` of the hash under `key`, returning how many it had. A hash
+    /// left without fields is deleted.
+    pub fn hdel(&self, key: &str, fields: &[&str]) -> Result<usize, KopperError> {
+        let _span = tracing::trace_span!("hdel", key_hash = key_hash(key)).entered();
+
+        check_key(key)?;
+        let mut state = self.unstalled()?;
+        let Some(newest) = Kopper::chained_of(&state, key, chain::Kind::Hash)? else {
+            return Ok(0);
+        };
+        let mut after = hash::fields(newest.record.clone(), segment_reader(&*state.files[&newest.entry.file_index].file))?;
+
+        let mut deleted: Vec<&str> = fields.iter().copied().filter(|field| after.remove(*field).is_some()).collect();
+        deleted.sort_unstable();
+        deleted.dedup();
+        if deleted.is_empty() {
+            return Ok(0);
+        }
+        if after.is_empty() {
+            self.remove(&mut state, key)?;
+            return Ok(deleted.len());
+        }
+
+        self.append_change(&mut state, key, Some(&newest),
+            |offset, len, previous| hash::delta(offset, len, previous, &[], &deleted),
+            || hash::full(&after),
+            hash::to_json(&after))?;
+        Ok(deleted.len())
+    }
+
+    /// Fields of the hash under `key` in order, none if there's no such hash
+    pub fn hgetall(&self, key: &str) -> Result<BTreeMap<String, String>, KopperError> {
+        check_key(key)?;
+        let mut state = self.contention.lock(&self.state);
+        if state.closed {
+            return Err(KopperError::Closed);
+        }
+
+        let fields = match Kopper::chained_of(&state, key, chain::Kind::Hash)? {
+            Some(newest) => hash::fields(newest.record, segment_reader(&*state.files[&newest.entry.file_index].file))?,
+            None => return Ok(BTreeMap::new()),
+        };
+        state.touch(key);
+        Ok(fields)
+    }
+
+    /// Counts `by` under `key` now, by the database's clock, see [`counter`],
+    /// creating the counter if there's none. Returns its total. Only the increment
+    /// is written. Like sets, counters don't expire, and aren't deduplicated or compressed.
+    pub fn incr(&self, key: &str, by: i64) -> Result<i64, KopperError> {
+        let _span = tracing::trace_span!("incr", key_hash = key_hash(key)).entered();
+
+        check_key(key)?;
+        let mut state = self.unstalled()?;
+        let newest = Kopper::chained_of(&state, key, chain::Kind::Counter)?;
+        let mut after = match &newest {
+            Some(newest) => counter::counter(newest.record.clone(), segment_reader(&*state.files[&newest.entry.file_index].file))?,
+            None => counter::Counter::default(),
+        };
+        let now = state.clock.now_millis();
+        after.add(now, by);
+
+        let total = after.total();
+        self.append_change(&mut state, key, newest.as_ref(),
+            |offset, len, previous| counter::delta(offset, len, previous, now, by),
+            || {
+                after.collapse(now);
+                counter::full(&after)
+            },
+            total.to_string())?;
+        Ok(total)
+    }
+
+    /// What the counter under `key` counted over the last `window`, by the
+    /// database's clock - 0 if there's no such counter. Increments collapsed by
+    /// the compactor count for every bucket, see [`counter::BUCKET_MS`], the
+    /// window overlaps - an hourly one past [`counter::MINUTE_BUCKETS_KEPT_MS`].
+    pub fn counted(&self, key: &str, window: Duration) -> Result<i64, KopperError> {
+        check_key(key)?;
+        let mut state = self.contention.lock(&self.state);
+        if state.closed {
+            return Err(KopperError::Closed);
+        }
+
+        let Some(newest) = Kopper::chained_of(&state, key, chain::Kind::Counter)? else {
+            return Ok(0);
+        };
+        let counter = counter::counter(newest.record, segment_reader(&*state.files[&newest.entry.file_index].file))?;
+        let since = state.clock.now_millis().saturating_sub(window.as_millis() as u64);
+        state.touch(key);
+        Ok(counter.sum_since(since))
+    }
+
+    /// Appends a change to the value of `key` changed a part at a time - the
+    /// `delta` of its `newest` record if it can build on that, see [`chain`], or
+    /// else the `whole` value. Watchers are told the value reads `json` now.
+    #[allow(clippy::too_many_arguments)]
+    fn append_change(&self, state: &mut MutexGuard<'_, SharedState>, key: &str, newest: Option<&Chained>,
+        delta: impl FnOnce(u64, usize, chain::Header) -> Vec<u8>, whole: impl FnOnce() -> Vec<u8>, json: String) -> Result<(), KopperError> {
+        // A change can't build on a record in another segment, including the one
+        // this record would be cut off into
+        let record = newest
+            .filter(|newest| newest.entry.file_index == state.current_file_index && newest.header.depth() < chain::MAX_DELTAS)
+            .map(|newest| delta(newest.entry.offset as u64, newest.entry.len, newest.header))
+            .filter(|delta| key.len() + delta.len() + 2 + state.offset <= self.segment_size)
+            .unwrap_or_else(whole);
+
+        Kopper::check_quota(state, &[(key, &record)])?;
+        let entry = self.append(state, key, &record)?;
+        Kopper::index(state, key, entry, None);
+        publish(state, key, ChangeEvent::Write { key: key.to_owned(), value: json });
+        self.evict(state)
+    }
+
+    /// Type of the value of `key` if it's changed a part at a time, see [`chain`],
+    /// without reading the rest of it
+    fn chained_kind(state: &SharedState, key: &str) -> Result<Option<chain::Kind>, KopperError> {
+        let Some(entry) = state.table.get(key).filter(|entry| entry.len > 0) else {
+            return Ok(None);
+        };
+        let mut flag = [0];
+        state.files[&entry.file_index].file.read_at(&mut flag, entry.offset as u64)?;
+        Ok(chain::kind(flag[0]))
+    }
+
+    /// Newest record of `key`, `None` if its value isn't changed a part at a time
+    fn chained(state: &SharedState, key: &str) -> Result<Option<Chained>, KopperError> {
+        let entry = match state.table.get(key) {
+            Some(_) if state.expired(key) => return Err(KopperError::KeyDoesNotExist(key.to_owned())),
+            Some(entry) => *entry,
+            None => return Err(missing(state, key)),
+        };
+        let mut record = vec![0; entry.len];
+        state.files[&entry.file_index].file.read_at(&mut record, entry.offset as u64)?;
+        Ok(chain::header(&record).map(|(kind, header)| Chained { entry, kind, header, record }))
+    }
+
+    /// Like [`Kopper::chained`] for a value of `kind`, `None` if `key` is missing
+    /// and [`KopperError::WrongType`] if it holds another value
+    fn chained_of(state: &SharedState, key: &str, kind: chain::Kind) -> Result<Option<Chained>, KopperError> {
+        match Kopper::chained(state, key) {
+            Ok(Some(newest)) if newest.kind == kind => Ok(Some(newest)),
+            Ok(_) => Err(KopperError::WrongType(key.to_owned())),
+            Err(KopperError::KeyDoesNotExist(_)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// The value whose newest record is `newest` as one record
+    fn whole(state: &SharedState, newest: Chained) -> Result<Vec<u8>, KopperError> {
+        whole(newest.kind, newest.record, segment_reader(&*state.files[&newest.entry.file_index].file), state.clock.now_millis())
+    }
+
+    /// Applies changes made elsewhere, like on a primary being replicated, in
+    /// order and even if the database is read-only. Consecutive writes go to disk
+    /// together. Deleting a missing key isn't an error, so changes can be replayed.
+    /// Databases opened with [`Kopper::follow`] reject changes from anywhere.
+    pub fn apply(&self, events: &[ChangeEvent]) -> Result<Commit, KopperError> {
+
+        let mut state = self.contention.lock(&self.state);
+
+        if state.closed {
+            return Err(KopperError::Closed);
+        }
+        if state.following {
+            return Err(KopperError::ReadOnly);
+        }
+        if let Some(reason) = &state.degraded {
+            return Err(KopperError::Degraded(reason.clone()));
+        }
+
+        let mut writes = Vec::new();
+        for event in events {
+            match event {
+                ChangeEvent::Write { key, value } => writes.push((key.as_str(), value.as_str())),
+                ChangeEvent::Delete { key } => {
+                    self.put_batch(&mut state, &writes, false, None)?;
+                    writes.clear();
+                    // The key may be in a segment still being recovered
+                    if state.table.contains_key(key) || state.recovering.is_some() {
+                        self.remove(&mut state, key)?;
+                    }
+                },
+            }
+        }
+        self.put_batch(&mut state, &writes, false, None)?;
+
+        Ok(Commit { sequence: state.sequence, size: state.size })
+    }
+
+    /// Locks the state for a write requested by a user
+    fn writable(&self) -> Result<MutexGuard<'_, SharedState>, KopperError> {
+        self.writable_until(None)
+    }
+
+    /// Locks the state, failing with [`KopperError::TimedOut`] if it isn't free by `deadline`
+    fn lock_until(&self, deadline: Option<Instant>) -> Result<MutexGuard<'_, SharedState>, KopperError> {
+        match deadline {
+            None => Ok(self.contention.lock(&self.state)),
+            // Same as the default of KvEngine::read_until, a deadline gone already fails even if the lock is free
+            Some(deadline) if Instant::now() >= deadline => Err(KopperError::TimedOut),
+            Some(deadline) => self.contention.lock_until(&self.state, deadline).ok_or(KopperError::TimedOut),
+        }
+    }
+
+    /// Like [`Kopper::writable`], giving up at `deadline`, see [`Kopper::lock_until`]
+    fn writable_until(&self, deadline: Option<Instant>) -> Result<MutexGuard<'_, SharedState>, KopperError> {
+        let state = self.lock_until(deadline)?;
+
+        if state.closed {
+            return Err(KopperError::Closed);
+        }
+        if state.read_only || state.following {
+            return Err(KopperError::ReadOnly);
+        }
+        if let Some(reason) = &state.degraded {
+            return Err(KopperError::Degraded(reason.clone()));
+        }
+        Ok(state)
+    }
+
+    /// Like [`Kopper::writable`], holding the write back while compaction is
+    /// behind, see [`Kopper::set_backpressure`]
+    fn unstalled(&self) -> Result<MutexGuard<'_, SharedState>, KopperError> {
+        self.unstalled_until(None)
+    }
+
+    /// Like [`Kopper::unstalled`], giving up at `deadline`, see [`Kopper::lock_until`]
+    fn unstalled_until(&self, deadline: Option<Instant>) -> Result<MutexGuard<'_, SharedState>, KopperError> {
+        let mut state = self.writable_until(deadline)?;
+        let Some(backpressure) = state.backpressure else {
+            return Ok(state);
+        };
+        if state.dead_ratio_of != state.current_file_index {
+            state.refresh_dead_ratio();
+        }
+
+        if state.dead_ratio > backpressure.soft && state.dead_ratio <= backpressure.hard {
+            // Other writes wait behind this one if it sleeps holding the lock
+            let clock = state.clock.clone();
+            drop(state);
+            clock.sleep(backpressure.delay);
+            state = self.writable_until(deadline)?;
+        }
+        if state.dead_ratio <= backpressure.hard {
+            return Ok(state);
+        }
+
+        if !state.stalled {
+            tracing::warn!(dead_ratio = state.dead_ratio, "Compaction is behind, refusing writes until it catches up");
+            state.stalled = true;
+            for _ in 1..state.files.len() {
+                // Ok to unwrap because sender always exists until receiver exists
+                self.send_to_compactor(CompactorRequest::Compact).unwrap();
+            }
+        }
+        Err(KopperError::Backpressure)
+    }
+
+    /// Appends a `key\0value\0` record to the current file, cutting off a new
+    /// segment first if the record wouldn't fit. Returns where the value landed.
+    fn append(&self, state: &mut MutexGuard<'_, SharedState>, key: &str, value: &[u8]) -> Result<TableEntry, KopperError> {
+        Ok(self.append_batch(state, &[(key, value)])?.remove(0))
+    }
+
+    /// Like [`Kopper::append`] for many records. Records headed for the same
+    /// segment are written with a single write.
+    fn append_batch(&self, state: &mut MutexGuard<'_, SharedState>, records: &[(&str, &[u8])]) -> Result<Vec<TableEntry>, KopperError> {
+
+        let mut entries = Vec::with_capacity(records.len());
+        let mut buffer = Vec::new();
+
+        for (key, value) in records {
+
+            // Segment file if next entry would exceed max size
+            if key.len() + value.len() + 2 + state.offset + buffer.len() > self.segment_size {
+                Kopper::flush(state, &mut buffer)?;
+                self.cut_off_segment(state)?;
+
+                // Ok to unwrap because sender always exists until receiver exists
+                self.send_to_compactor(CompactorRequest::Compact).unwrap(); 
+            }
+
+            entries.push(TableEntry {
+                file_index: state.current_file_index,
+                offset: state.offset + buffer.len() + key.len() + 1,
+                len: value.len()
+            });
+
+            buffer.extend_from_slice(key.as_bytes());
+            buffer.push(b'\0');
+            buffer.extend_from_slice(value);
+            buffer.push(b'\0');
+        }
+
+        Kopper::flush(state, &mut buffer)?;
+        Ok(entries)
+    }
+
+    /// Writes `buffer` to the current file and empties it.
+    fn flush(state: &mut SharedState, buffer: &mut Vec<u8>) -> Result<(), KopperError> {
+        if buffer.is_empty() {
+            return Ok(());
+        }
+
+        let file_index = state.current_file_index;
+        let file = &state.files[&file_index].file;
+        if let Err(err) = file.append(buffer) {
+            // Part of the buffer may have made it, and the next write would land after it
+            if let Err(truncate_err) = file.truncate(state.offset as u64) {
+                tracing::error!("Can't undo a failed write to {file_index}, refusing writes from now on: {truncate_err}");
+                state.degraded = Some(format!("can't undo a failed write to {file_index}: {truncate_err}"));
+            }
+            return Err(Kopper::check_full(state, err));
+        }
+
+        // Update current offset and total size
+        state.offset += buffer.len();
+        state.size += buffer.len();
+        buffer.clear();
+        Ok(())
+    }
+
+    /// Turns running out of disk space into [`KopperError::DatabaseFull`], refusing
+    /// writes from then on - the next ones would most likely fail the same way.
+    fn check_full(state: &mut SharedState, err: KopperError) -> KopperError {
+        if !err.is_storage_full() {
+            return err;
+        }
+        tracing::error!("Out of disk space, refusing writes from now on: {err}");
+        state.degraded.get_or_insert_with(|| "out of disk space".to_owned());
+        KopperError::DatabaseFull
+    }
+
+    fn cut_off_segment(&self, state: &mut MutexGuard<'_, SharedState>) -> Result<(), KopperError> {
+              
+        // Increment index - current_file_index is the biggest of all
+        let new_file_index = FileIndex { base: state.current_file_index.base + 1, index: 0 };
+
+        // Create a new file. If it fails, writes carry on in the current one.
+        let file = self.store.create(&new_file_index.to_string())
+            .map_err(|err| Kopper::check_full(state, err))?;
+
+        // Synced with the next compaction, or on close. Recovery takes up a new segment
+        // the MANIFEST doesn't know of as it is, so a failed append doesn't stop writes.
+        if let Some(segment_log) = &mut state.segment_log {
+            if let Err(err) = segment_log.append(&[new_file_index.to_string()], &[]) {
+                tracing::warn!("Can't record {new_file_index} in the {}: {err}", segment_log::NAME);
+            }
+        }
+
+        // Found after a crash once the directory is synced, see SharedState::synced_dir_changes
+        state.dir_changes += 1;
+
+        // Add new file to file table
+        state.current_file_index = new_file_index;
+        state.files.insert(new_file_index, FileEntry { file, unused_count: 0, tier: Tier::Hot });
+        state.offset = 0;        
+        Ok(())
+    }
+
+    /// Recovers the `pending` segments [`Kopper::create_lazily`] left, oldest first,
+    /// into a table of their own. Records of keys written or deleted since, or in
+    /// the newer segments, are stale - the rest are merged in once all are read.
+    fn run_recovery(&self, pending: Vec<FileIndex>) -> JoinHandle<()> {
+
+        fn recover(state_mutex: &parking_lot::Mutex<SharedState>, pending: &[FileIndex]) -> Result<(), KopperError> {
+            let state = state_mutex.lock();
+            let generation = state.generation;
+            let files: Vec<_> = pending.iter().map(|index| (*index, state.files[index].file.clone())).collect();
+            drop(state);
+
+            // Closed, or the segments were swapped by an install
+            let abandoned = |state: &SharedState| state.closed || state.generation != generation;
+
+            let mut table = HashMap::new();
+            let mut unused = BTreeMap::new();
+            let mut found = Found::default();
+            for (file_index, file) in files {
+                if abandoned(&state_mutex.lock()) {
+                    return Ok(());
+                }
+                tracing::debug!(segment = %file_index, "recovering");
+
+                let file = packed::open(file)?;
+                let stored = file.stored_len()? as usize;
+                let len = SharedState::recover_file(&mut table, &mut unused, None, &mut found, file_index, &*file)?;
+                if (len as u64) < file.len()? {
+                    tracing::warn!("Dropping an incomplete record at the end of {file_index}");
+                    file.truncate(len as u64)?;
+                }
+
+                let mut state = state_mutex.lock();
+                if abandoned(&state) {
+                    return Ok(());
+                }
+                state.size = state.size - stored + file.stored_len()? as usize;
+                if let Some(entry) = state.files.get_mut(&file_index) {
+                    entry.file = file;
+                }
+                let entries = state.table.len() + table.len();
+                if let Some(recovering) = &mut state.recovering {
+                    recovering.progress.recovered += 1;
+                    recovering.progress.bytes_scanned += len as u64;
+                    recovering.progress.entries = entries;
+                }
+            }
+
+            let mut state = state_mutex.lock();
+            if abandoned(&state) {
+                return Ok(());
+            }
+            let Some(recovering) = state.recovering.take() else {
+                return Ok(());
+            };
+            let mut merged = Vec::new();
+            for (key, entry) in table {
+                match state.table.contains_key(&key) || recovering.shadowed.contains(&key) {
+                    true => *unused.entry(entry.file_index).or_default() += 1,
+                    false => {
+                        state.table.insert(key.clone(), entry);
+                        merged.push(key);
+                    },
+                }
+            }
+            state.seed_lru(merged.clone());
+            state.take_up(found, merged);
+            state.prune_blobs();
+            state.count_live_bytes();
+            state.refresh_dead_ratio();
+            for (file_index, unused_count) in unused {
+                if let Some(entry) = state.files.get_mut(&file_index) {
+                    entry.unused_count += unused_count;
+                }
+            }
+            Ok(())
+        }
+
+        let state = self.state.clone();
+        let recovered = self.recovered.clone();
+        std::thread::spawn(move || {
+            let _span = tracing::info_span!("recovery", background = true).entered();
+            let started = Instant::now();
+
+            let outcome = recover(&state, &pending);
+            let mut state = state.lock();
+            match outcome {
+                Ok(()) => tracing::info!(
+                    segments = pending.len(),
+                    keys = state.table.len(),
+                    duration_ms = started.elapsed().as_millis() as u64,
+                    "recovered in the background"
+                ),
+                Err(err) => {
+                    tracing::error!("Can't recover older segments, refusing writes from now on: {err}");
+                    state.degraded = Some(format!("Can't recover older segments: {err}"));
+                    if let Some(recovering) = &mut state.recovering {
+                        recovering.failed = Some(err.to_string());
+                    }
+                },
+            }
+            drop(state);
+            recovered.notify_all();
+        })
+    }
+
+    /// Sends `request` to the compactor, counted in [`Saturation::compactor_queue`]
+    /// until the compactor takes it up
+    fn send_to_compactor(&self, request: CompactorRequest) -> Result<(), SendError<CompactorRequest>> {
+        self.compactor_queue.fetch_add(1, Ordering::Relaxed);
+        self.compactor.send(request).inspect_err(|_| {
+            self.compactor_queue.fetch_sub(1, Ordering::Relaxed);
+        })
+    }
+
+    /// Whether the compactor thread runs, `None` for followers which don't start one
+    fn compactor_alive(&self, state: &SharedState) -> Option<bool> {
+        match state.following {
+            true => None,
+            false => Some(self.compactor_thread.lock().unwrap_or_else(PoisonError::into_inner).as_ref()
+                .is_some_and(|compactor| !compactor.is_finished())),
+        }
+    }
+
+    fn run_compactor(&self, receiver: Receiver<CompactorRequest>) -> JoinHandle<()> {
+
+        let state = self.state.clone();
+        let store = self.store.clone();
+        let queue = self.compactor_queue.clone();
+        std::thread::spawn(move || {
+
+            fn compact(state_mutex: &parking_lot::Mutex<SharedState>, store: &dyn SegmentStore) {
+                let span = tracing::debug_span!("compaction", segment = tracing::field::Empty);
+                let _entered = span.enter();
+
+                // Release the lock immidiately after taking a copy of current state
+                let state = state_mutex.lock();
+                let clock = state.clock.clone();
+                let started = clock.now();
+                let generation = state.generation;
+
+                // Stale records can't be told apart until every segment is recovered
+                if state.recovering.is_some() {
+                    return;
+                }
+
+                // Choose the best file to compact. The active file is still being written to - skip it.
+                let mut best: Option<(&FileIndex, &FileEntry)> = None;
                 for (index, entry) in state.files.iter() {
-                    if entry.unused_count > file_entry.unused_count {
-                        file_index = index;
-                        file_entry = entry;
+                    if *index == state.current_file_index || state.quarantined.contains(index) {
+                        continue;
+                    }
+                    match best {
+                        Some((_, best_entry)) if entry.unused_count <= best_entry.unused_count => {},
+                        _ => best = Some((index, entry))
                     }
                 }
+
+                let Some((file_index, file_entry)) = best else {
+                    return;
+                };
                 
                 // Make explicit copies
                 let file_index = *file_index;
-                let mut file: File = file_entry.file.try_clone().unwrap();
+                let file = file_entry.file.clone();
+                let tier = file_entry.tier;
+                span.record("segment", tracing::field::display(file_index));
                 drop(state);
                 
                 // Load file into memory
-                let mut buffer = Vec::new();
-                file.seek(io::SeekFrom::Start(0)).unwrap();
-                file.read_to_end(&mut buffer).unwrap();
+                let loaded = file.len().and_then(|len| {
+                    let mut buffer = vec![0; len as usize];
+                    file.read_at(&mut buffer, 0)?;
+                    Ok((buffer, file.stored_len()? as usize))
+                });
+                let (buffer, old_size) = match loaded {
+                    Ok(loaded) => loaded,
+                    Err(err) => {
+                        tracing::warn!("Can't compact {file_index}: {err}");
+                        return;
+                    }
+                };
                 
                 let mut new_file_contents = Vec::new();
                 let iter = KeyValueIterator::from(&buffer);
                 let compacted_file_index = file_index + 1;
 
                 // Locked hashmap access here
-                let mut lock = state_mutex.lock().unwrap();
+                let mut lock = state_mutex.lock();
+
+                // Segments were swapped meanwhile, this one may be gone or taken by another
+                if lock.generation != generation {
+                    return;
+                }
+
+                // Tombstones only matter while an older file may still hold a value they shadow
+                let is_oldest_file = lock.files.first_key_value().map(|(index, _)| *index) == Some(file_index);
+
+                // Keys move to the new file only once it's safely written, and so do blobs
+                let mut moved = Vec::new();
+                let mut moved_blobs = Vec::new();
+                // Of blobs kept for other keys although their own is stale
+                let mut kept_for_blobs = HashSet::new();
                 for (key, key_value, value_offset) in iter {
+                    let value = &key_value[key.len() + 1..key_value.len() - 1];
+                    let new_entry = TableEntry { 
+                        file_index: compacted_file_index, 
+                        offset: new_file_contents.len() + key.len() + 1, 
+                        len: value.len()
+                    };
+
+                    // A blob stays as long as keys refer to it, whatever its own key is now
+                    let blob = match dedupe::parse(value) {
+                        Some(Record::Blob(hash)) => lock.blobs.get(&hash)
+                            .and_then(|blob| blob.entry)
+                            .filter(|entry| entry.file_index == file_index && entry.offset == value_offset)
+                            .map(|_| hash),
+                        _ => None,
+                    };
+                    if let Some(hash) = blob {
+                        moved_blobs.push((hash, new_entry));
+                    }
                     
-                    // If the newest entry exists in the file that's being compacted, 
-                    // change it's file_index and offset to new file
-                    let entry = lock.table.get(key).unwrap();
-                    if entry.file_index == file_index && entry.offset == value_offset {
-                        lock.table.insert(key.to_owned(), TableEntry { 
-                            file_index: compacted_file_index, 
-                            offset: new_file_contents.len() + key.len() + 1, 
-                            len: key_value.len() - key.len() - 2
-                        });
-                        new_file_contents.extend_from_slice(key_value);
+                    match lock.table.get(key) {
+                        // If the newest entry exists in the file that's being compacted, 
+                        // change it's file_index and offset to new file
+                        // A set, a hash, a counter or a list is written whole, the records it builds on
+                        // are left out
+                        Some(entry) if entry.file_index == file_index && entry.offset == value_offset && chain::header(value).is_some() => {
+                            let read = |offset: u64, len: usize| buffer.get(offset as usize..offset as usize + len)
+                                .map(<[u8]>::to_vec)
+                                .ok_or_else(|| KopperError::InternalError(anyhow::anyhow!("Set record points past the end of the segment")));
+                            let record = match whole(chain::header(value).unwrap().0, value.to_vec(), read, clock.now_millis()) {
+                                Ok(record) => record,
+                                Err(err) => {
+                                    tracing::warn!("Can't compact {file_index}: {err}");
+                                    return;
+                                }
+                            };
+                            moved.push((key, TableEntry { len: record.len(), ..new_entry }));
+                            new_file_contents.extend_from_slice(key.as_bytes());
+                            new_file_contents.push(b'\0');
+                            new_file_contents.extend_from_slice(&record);
+                            new_file_contents.push(b'\0');
+                        },
+                        Some(entry) if entry.file_index == file_index && entry.offset == value_offset => {
+                            moved.push((key, new_entry));
+                            new_file_contents.extend_from_slice(key_value);
+                        },
+                        _ if blob.is_some() => {
+                            kept_for_blobs.insert(key);
+                            new_file_contents.extend_from_slice(key_value);
+                        },
+                        // Key is deleted - keep the tombstone. In the oldest file too, if
+                        // it shadows a blob kept above.
+                        None if (!is_oldest_file || kept_for_blobs.contains(key)) && value == TOMBSTONE => {
+                            new_file_contents.extend_from_slice(key_value);
+                        },
+                        _ => {}
                     }
                 }
 
+                // Cold segments are packed, if that makes them any smaller
+                let packed_contents = lock.packing
+                    .filter(|packing| lock.current_file_index.base.saturating_sub(file_index.base) >= packing.cold_after)
+                    .and_then(|packing| packed::pack(packing.codec, &new_file_contents))
+                    .filter(|packed_contents| packed_contents.len() < new_file_contents.len());
+                let stored_contents = packed_contents.as_deref().unwrap_or(&new_file_contents);
+
+                // Old segments move to the cold tier, ones already there stay
+                let tier = match lock.cold_tier_after {
+                    Some(after) if lock.current_file_index.base.saturating_sub(file_index.base) >= after => Tier::Cold,
+                    _ => tier,
+                };
+
                 // Save compacted file
                 if !new_file_contents.is_empty() {
-                    let mut compacted_file =
-                        OpenOptions::new()
-                            .append(true)
-                            .create(true)
-                            .open(path.clone() + "/" + &compacted_file_index.to_string())
-                            .expect("Can't open file in compactor");
-                    
-                    compacted_file.write_all(&new_file_contents).unwrap();
-                    
+                    let written = store.create_on(&compacted_file_index.to_string(), tier).and_then(|compacted_file| {
+                        compacted_file.append(stored_contents)?;
+
+                        // Values may have been synced in the file removed below, keep it that way
+                        compacted_file.sync()?;
+                        packed::open(compacted_file)
+                    });
+
+                    // Nothing points at the new file yet, the old one stays
+                    let compacted_file = match written {
+                        Ok(compacted_file) => compacted_file,
+                        Err(err) => {
+                            let _ = store.delete(&compacted_file_index.to_string());
+                            tracing::warn!("Can't compact {file_index}: {err}");
+                            return;
+                        }
+                    };
+
                     // When all is ready, insert the new file to master tree
-                    lock.files.insert(compacted_file_index, FileEntry { file: compacted_file, unused_count: 0 });
-                    lock.size += new_file_contents.len();
+                    lock.files.insert(compacted_file_index, FileEntry { file: compacted_file, unused_count: 0, tier });
+                    lock.size += stored_contents.len();
+                }
+
+                // Records left out were overwritten or deleted in newer segments, which may
+                // not be synced yet - after a crash the old file would be the only copy
+                let synced = lock.files.range(lock.synced_from..)
+                    .filter(|(index, _)| **index != compacted_file_index)
+                    .try_for_each(|(_, entry)| entry.file.sync());
+
+                // The MANIFEST names the compacted segment, and the ones cut off since it
+                // was last synced - they have to be there after a crash
+                let synced = synced.and_then(|_| store.sync_dir());
+
+                // Once swapped in the MANIFEST, recovery drops the old file even if it's left behind
+                let added: Vec<String> = lock.files.contains_key(&compacted_file_index)
+                    .then(|| compacted_file_index.to_string())
+                    .into_iter()
+                    .collect();
+                let committed = synced.and_then(|_| match &mut lock.segment_log {
+                    Some(segment_log) => segment_log.commit(&added, &[file_index.to_string()]),
+                    None => Ok(()),
+                });
+                if let Err(err) = committed {
+                    if lock.files.remove(&compacted_file_index).is_some() {
+                        lock.size -= stored_contents.len();
+                        let _ = store.delete(&compacted_file_index.to_string());
+                    }
+                    tracing::warn!("Can't compact {file_index}: {err}");
+                    return;
+                }
+                lock.synced_sequence = lock.sequence;
+                lock.synced_from = lock.current_file_index;
+                lock.synced_dir_changes = lock.dir_changes;
+
+                let moved_keys = moved.len();
+                for (key, entry) in moved {
+                    // Sets written whole may have shrunk or grown
+                    if let Some(old_entry) = lock.table.insert(key.to_owned(), entry).filter(|_| !keyspace::is_reserved(key)) {
+                        lock.live_bytes = lock.live_bytes - old_entry.len + entry.len;
+                    }
+                }
+                for (hash, entry) in moved_blobs {
+                    if let Some(blob) = lock.blobs.get_mut(&hash) {
+                        blob.entry = Some(entry);
+                    }
                 }
 
-                lock.size -= file.metadata().unwrap().len() as usize;
+                lock.size -= old_size;
                 lock.files.remove(&file_index);
-                fs::remove_file(path + "/" + &file_index.to_string()).unwrap();
-                println!("Removed {}", file_index);
+                lock.refresh_dead_ratio();
+                // Left behind, recovery drops it. Synced along with the next flush.
+                match store.delete(&file_index.to_string()) {
+                    Ok(()) => lock.dir_changes += 1,
+                    Err(err) => tracing::warn!("Can't remove compacted {file_index}: {err}"),
+                }
+
+                let report = CompactionReport {
+                    reclaimed_bytes: old_size.saturating_sub(stored_contents.len()),
+                    duration: clock.since(started),
+                    segments: lock.files.len()
+                };
+                tracing::debug!(
+                    compacted = %compacted_file_index,
+                    moved_keys,
+                    reclaimed_bytes = report.reclaimed_bytes,
+                    packed = packed_contents.is_some(),
+                    tier = ?tier,
+                    duration_ms = report.duration.as_millis() as u64,
+                    "compacted"
+                );
+                lock.compaction_listeners.retain(|listener| listener.send(report.clone()).is_ok());
+                if let Some(metrics) = &lock.metrics {
+                    metrics.record(Stat::Compaction(report));
+                }
             }
 
+            // Loop ends when database is closed or all senders are dropped
             loop {
-                match receiver.recv() {
-                    Ok(_) => compact(&*state, path.clone()),
-                    Err(_) => { break; }, // All senders are dropped
+                let request = receiver.recv();
+                if request.is_ok() {
+                    queue.fetch_sub(1, Ordering::Relaxed);
+                }
+                match request {
+                    Ok(CompactorRequest::Compact) => compact(&state, &*store),
+                    Ok(CompactorRequest::Barrier(done)) => {
+                        let _ = done.send(());
+                    },
+                    Ok(CompactorRequest::Stop) | Err(_) => break,
                 }
             }
             
-            println!("{}", state.lock().unwrap().offset);
-        });
+            tracing::debug!("Compactor stopped at offset {}", state.lock().offset);
+        })
+    }
+}
+
+impl KvEngine for Kopper {
+    fn name(&self) -> &'static str {
+        "kopper"
+    }
+
+    fn read(&self, key: &str) -> Result<String, KopperError> {
+        Kopper::read(self, key)
+    }
+
+    fn read_until(&self, key: &str, deadline: Instant) -> Result<String, KopperError> {
+        Kopper::read_until(self, key, deadline)
+    }
+
+    fn write(&self, key: &str, value: &str) -> Result<usize, KopperError> {
+        Kopper::write(self, key, value).map(|commit| commit.size)
+    }
+
+    fn write_until(&self, key: &str, value: &str, deadline: Instant) -> Result<usize, KopperError> {
+        Kopper::write_until(self, key, value, deadline).map(|commit| commit.size)
+    }
+
+    fn write_batch(&self, entries: &[(&str, &str)]) -> Result<usize, KopperError> {
+        Kopper::write_batch(self, entries).map(|commit| commit.size)
+    }
+
+    fn write_durable(&self, key: &str, value: &str, durability: Durability) -> Result<usize, KopperError> {
+        let commit = Kopper::write(self, key, value)?;
+        match durability {
+            Durability::Flush => self.wait_for_flush(commit.sequence)?,
+            Durability::Replica(timeout) => self.wait_for_replication(commit.sequence, timeout)?,
+        }
+        Ok(commit.size)
+    }
+
+    fn delete(&self, key: &str) -> Result<usize, KopperError> {
+        Kopper::delete(self, key).map(|commit| commit.size)
+    }
+
+    fn scan(&self) -> Result<EngineScan, KopperError> {
+        Ok(Box::new(Kopper::scan(self)?))
+    }
+
+    fn stats(&self) -> Result<EngineStats, KopperError> {
+        let state = self.contention.lock(&self.state);
+        if state.closed {
+            return Err(KopperError::Closed);
+        }
+        Ok(EngineStats { keys: state.user_keys(), size: state.size })
+    }
+
+    fn close(&self) -> Result<(), KopperError> {
+        Kopper::close(self)
     }
 }
 
@@ -282,72 +3768,457 @@ pub enum KopperError {
     InternalError(anyhow::Error),
 
     #[error("No such item: {0}")]
-    KeyDoesNotExist(String)
+    KeyDoesNotExist(String),
+
+    #[error("Database is closed")]
+    Closed,
+
+    #[error("Database is read-only")]
+    ReadOnly,
+
+    #[error("Changes after sequence {0} aren't available")]
+    ChangesUnavailable(u64),
+
+    #[error("No replica confirmed change {0} in time")]
+    NotReplicated(u64),
+
+    #[error("Invalid backup: {0}")]
+    InvalidBackup(String),
+
+    #[error("Not supported by this engine: {0}")]
+    Unsupported(&'static str),
+
+    #[error("Out of disk space")]
+    DatabaseFull,
+
+    #[error("Database is degraded, writes are refused: {0}")]
+    Degraded(String),
+
+    #[error("Database is still being recovered, try again later")]
+    Recovering,
+
+    #[error("Quota of {0} exceeded")]
+    QuotaExceeded(QuotaLimit),
+
+    /// See [`Kopper::set_backpressure`]
+    #[error("Writes are held back until compaction catches up, try again later")]
+    Backpressure,
+
+    /// See [`Kopper::lpush`], [`Kopper::sadd`], [`Kopper::hset`] and [`Kopper::incr`]
+    #[error("{0} holds another type of value")]
+    WrongType(String),
+
+    /// For hooks to refuse an operation with, see [`Kopper::on_write`]
+    #[error("Rejected: {0}")]
+    Rejected(String),
+
+    /// See [`Kopper::set_quarantine`]
+    #[error("Segment {0} is quarantined as corrupt")]
+    Quarantined(String),
+
+    /// See [`Kopper::read_until`] and [`Kopper::write_until`]
+    #[error("Gave up waiting for the database, try again later")]
+    TimedOut,
+}
+
+impl KopperError {
+    /// The disk or the user's quota ran out of space
+    fn is_storage_full(&self) -> bool {
+        let KopperError::InternalError(err) = self else {
+            return false;
+        };
+        err.downcast_ref::<std::io::Error>()
+            .is_some_and(|err| matches!(err.kind(), std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded))
+    }
 }
 
 from_error!(KopperError::InternalError, std::num::ParseIntError, std::io::Error, std::str::Utf8Error, std::string::FromUtf8Error);
 
 impl SharedState {
-    fn create(path: &str) -> Result<SharedState, KopperError> {
-        let mut state = SharedState {
+    fn empty() -> SharedState {
+        SharedState {
             table: HashMap::new(),
             files: BTreeMap::new(),
             offset: 0,
             current_file_index: FileIndex { base: 0, index: 0 },
             size: 0,
+            closed: false,
+            watchers: Vec::new(),
+            poppers: Vec::new(),
+            next_watcher: 0,
+            compaction_listeners: Vec::new(),
+            metrics: None,
+            clock: Arc::new(SystemClock),
+            compression: None,
+            compression_stats: CompressionStats::default(),
+            packing: None,
+            cold_tier_after: None,
+            dedupe: None,
+            blobs: HashMap::new(),
+            deduped: HashMap::new(),
+            quota: Quota::default(),
+            live_bytes: 0,
+            reserved_keys: 0,
+            expiries: HashMap::new(),
+            expiry_queue: BTreeSet::new(),
+            default_ttl: None,
+            undelete_window: None,
+            eviction: None,
+            backpressure: None,
+            dead_ratio: 0.0,
+            dead_ratio_of: FileIndex { base: 0, index: 0 },
+            stalled: false,
+            uses: 0,
+            last_used: HashMap::new(),
+            lru: BTreeSet::new(),
+            pinned: HashSet::new(),
+            versions: HashMap::new(),
+            read_only: false,
+            degraded: None,
+            verify_cursor: None,
+            verify_stats: VerifyStats::default(),
+            quarantine: false,
+            quarantined: BTreeSet::new(),
+            recovering: None,
+            segment_log: None,
+            following: false,
+            generation: 0,
+            log_id: new_log_id(),
+            sequence: 0,
+            synced_sequence: 0,
+            synced_from: FileIndex { base: 0, index: 0 },
+            dir_changes: 0,
+            synced_dir_changes: 0,
+            replicated_sequence: 0,
+            change_log: VecDeque::new(),
+            change_log_capacity: DEFAULT_CHANGE_LOG_CAPACITY,
+            numeric_indexes: BTreeMap::new(),
+            hooks: Hooks::default(),
+        }
+    }
+
+    /// Recovers the database in `store`. With `eager` set, only that many of the
+    /// newest segments are - the rest are opened, and returned to be recovered later.
+    fn create(store: &dyn SegmentStore, progress: &mut dyn FnMut(RecoveryProgress), eager: Option<usize>) -> Result<(SharedState, Vec<FileIndex>), KopperError> {
+        let _span = tracing::info_span!("recovery").entered();
+        let started = Instant::now();
+        let mut state = SharedState::empty();
+
+        let (mut segment_log, logged) = SegmentLog::open(store)?;
+        let mut file_indexes = match logged {
+            Some(logged) => logged_segments(store, &logged)?,
+            None => listed_segments(store)?,
         };
 
-        // Create dir if doesn't exist yet
-        match fs::create_dir_all(path) { _ => () };
+        let mut recovery = RecoveryProgress { segments: file_indexes.len(), ..RecoveryProgress::default() };
+        progress(recovery);
 
-        // Recover all files
-        for dir_entry in fs::read_dir(path)? {
+        let later = eager.map_or(0, |eager| file_indexes.len().saturating_sub(eager.max(1)));
+        let pending = file_indexes.drain(..later).collect::<Vec<_>>();
+        for file_index in &pending {
+            // Not even read yet, a packed one is told apart in the background
+            let file = store.create(&file_index.to_string())?;
+            state.size += file.stored_len()? as usize;
+            let tier = store.tier_of(&file_index.to_string());
+            state.files.insert(*file_index, FileEntry { file, unused_count: 0, tier });
+        }
+        let mut shadowed = HashSet::new();
 
-            let dir_entry = dir_entry?;
+        // Recover all files, oldest first - later records override earlier ones
+        let mut unused = BTreeMap::new();
+        let mut found = Found::default();
+        for file_index in file_indexes {
 
-            let mut file = 
-                OpenOptions::new()
-                    .read(true)
-                    .append(true)
-                    .create(true)
-                    .open(dir_entry.path())?;
-            
-            let file_index: FileIndex = 
-                dir_entry.path()
-                    .file_name().unwrap()
-                    .to_str().unwrap()
-                    .parse()?;
+            let file = packed::open(store.create(&file_index.to_string())?)?;
 
-            println!("Recovering file: {}", file_index);
+            tracing::debug!(segment = %file_index, "recovering");
+
+            let deleted = (!pending.is_empty()).then_some(&mut shadowed);
+            let len = SharedState::recover_file(&mut state.table, &mut unused, deleted, &mut found, file_index, &*file)?;
+            if (len as u64) < file.len()? {
+                // Torn by a crash mid-write. Records appended after it would be read as part of it.
+                tracing::warn!("Dropping an incomplete record at the end of {file_index}");
+                file.truncate(len as u64)?;
+            }
+            state.size += file.stored_len()? as usize;
+            let tier = store.tier_of(&file_index.to_string());
+            state.files.insert(file_index, FileEntry { file, unused_count: 0, tier });
+
+            recovery.recovered += 1;
+            recovery.bytes_scanned += len as u64;
+            recovery.entries = state.table.len();
+            progress(recovery);
+        }
 
-            state.size += SharedState::recover_file(&mut state.table, file_index, &mut file)?;
-            state.files.insert(file_index, FileEntry { file, unused_count: 0 });
+        if !found.records.is_empty() || !found.expiring.is_empty() {
+            let keys: Vec<String> = state.table.keys().cloned().collect();
+            state.take_up(found, keys);
         }
+        if pending.is_empty() {
+            state.prune_blobs();
+        }
+        state.count_live_bytes();
 
         // If starting a new database, create the first file
         if state.files.is_empty() {
-            let head_file = String::from(path) + "/" + &state.current_file_index.to_string();
-            let file = OpenOptions::new()
-                .read(true)
-                .append(true)
-                .create(true)
-                .open(head_file)?;
+            let file = store.create(&state.current_file_index.to_string())?;
 
-            state.files.insert(FileIndex { base: 0, index: 0 }, FileEntry { file, unused_count: 0 });
+            state.files.insert(FileIndex { base: 0, index: 0 }, FileEntry { file, unused_count: 0, tier: Tier::Hot });
         }
 
-        // TODO: update unused counters for all files
+        // Starts over from the segments kept, dropping the history of the last run
+        let live: Vec<String> = state.files.keys().map(FileIndex::to_string).collect();
+        segment_log.rewrite(&live)?;
+        // Including whichever were created or removed above, and the MANIFEST itself
+        store.sync_dir()?;
+        state.segment_log = Some(segment_log);
 
-        state.current_file_index = *state.files.first_key_value().unwrap().0;
-        state.offset = state.files.first_key_value().unwrap().1.file.metadata().unwrap().len() as usize;
-        Ok(state)
+        if !pending.is_empty() {
+            state.recovering = Some(Recovering { progress: recovery, shadowed, failed: None });
+        }
+
+        // Same as if the records were written now, so compaction picks the files with most garbage
+        for (file_index, unused_count) in unused {
+            if let Some(entry) = state.files.get_mut(&file_index) {
+                entry.unused_count = unused_count;
+            }
+        }
+
+        // Continue writing to the newest file
+        state.current_file_index = *state.files.last_key_value().unwrap().0;
+        state.offset = state.files.last_key_value().unwrap().1.file.len()? as usize;
+        tracing::info!(
+            segments = state.files.len(),
+            keys = state.table.len(),
+            bytes = state.size,
+            pending = pending.len(),
+            duration_ms = started.elapsed().as_millis() as u64,
+            "recovered"
+        );
+        Ok((state, pending))
+    }
+
+    /// Where the value of `key` is stored - `entry`, unless it's a reference to a blob
+    /// Indexes every key in `index` by its value, see [`Kopper::create_numeric_index`]
+    fn fill_numeric_index(&self, index: &mut NumericIndex) -> Result<(), KopperError> {
+        let entries: Vec<(String, TableEntry)> = self.table.iter()
+            .filter(|(key, _)| !keyspace::is_reserved(key))
+            .map(|(key, entry)| Ok((key.clone(), self.value_entry(key, entry)?)))
+            .collect::<Result<_, KopperError>>()?;
+        let files = self.files.iter().map(|(file_index, entry)| (*file_index, entry.file.clone())).collect();
+        read_in_order(entries.into_iter(), &files, (), |_, key, value| index.update(key, Some(value)))
+    }
+
+    /// Builds every index anew, once the table was swapped without telling them
+    /// about the changes
+    fn rebuild_numeric_indexes(&mut self) -> Result<(), KopperError> {
+        let mut indexes = std::mem::take(&mut self.numeric_indexes);
+        let filled = indexes.values_mut().try_for_each(|index| {
+            index.clear();
+            self.fill_numeric_index(index)
+        });
+        self.numeric_indexes = indexes;
+        filled
+    }
+
+    fn value_entry(&self, key: &str, entry: &TableEntry) -> Result<TableEntry, KopperError> {
+        let Some(hash) = self.deduped.get(key) else {
+            return Ok(*entry);
+        };
+        match self.blobs.get(hash).and_then(|blob| blob.entry) {
+            Some(blob) => Ok(blob),
+            None if self.recovering.is_some() => Err(KopperError::Recovering),
+            None => Err(KopperError::InternalError(anyhow::anyhow!("Value of {key} is a reference to a blob that isn't stored"))),
+        }
+    }
+
+    /// See [`Kopper::verify_stats`]
+    fn verify_stats(&self) -> VerifyStats {
+        VerifyStats {
+            quarantined: self.quarantined.iter().map(FileIndex::to_string).collect(),
+            ..self.verify_stats.clone()
+        }
+    }
+
+    /// One key less refers to the blob of `hash`, which is garbage once none do
+    fn release(&mut self, hash: u64) {
+        if let Some(blob) = self.blobs.get_mut(&hash) {
+            blob.refs -= 1;
+            if blob.refs == 0 {
+                self.blobs.remove(&hash);
+            }
+        }
+    }
+
+    /// Takes up the blobs recovery `found`, and the references of `keys` to them
+    /// or when they expire. Blobs known already are newer and stay. Ones no key
+    /// refers to are kept for now, keys still being recovered may.
+    fn take_up(&mut self, found: Found, keys: impl IntoIterator<Item = String>) {
+        for (hash, entry) in found.blobs {
+            self.blobs.entry(hash).or_insert(Blob { entry: None, refs: 0 }).entry.get_or_insert(entry);
+        }
+        for key in keys {
+            let Some(entry) = self.table.get(&key) else {
+                continue;
+            };
+            let at = (entry.file_index, entry.offset);
+            if let Some(expires_at) = found.expiring.get(&at) {
+                self.expire(&key, *expires_at);
+            }
+            if let Some(hash) = found.records.get(&at) {
+                self.blobs.entry(*hash).or_insert(Blob { entry: None, refs: 0 }).refs += 1;
+                self.deduped.insert(key, *hash);
+            }
+        }
+    }
+
+    /// Has the value of `key` expire at `expires_at`
+    fn expire(&mut self, key: &str, expires_at: u64) {
+        self.persist(key);
+        self.expiries.insert(key.to_owned(), expires_at);
+        self.expiry_queue.insert((expires_at, key.to_owned()));
+    }
+
+    /// When a value written now expires by the default TTL, `None` if it doesn't
+    fn default_expiry(&self) -> Option<u64> {
+        self.default_ttl.map(|ttl| self.clock.now_millis().saturating_add(ttl.as_millis() as u64))
+    }
+
+    /// The value of `key` won't expire, e.g. it was overwritten
+    fn persist(&mut self, key: &str) {
+        if let Some(expires_at) = self.expiries.remove(key) {
+            self.expiry_queue.remove(&(expires_at, key.to_owned()));
+        }
+    }
+
+    fn segment_stats(&self) -> Result<Vec<SegmentStats>, KopperError> {
+        // Record of a value is `key\0value\0`. One kept for a blob is counted
+        // without its key, which may be anything by now.
+        let mut live: HashMap<FileIndex, (usize, usize)> = HashMap::new();
+        let mut counted = HashSet::new();
+        for (key, entry) in &self.table {
+            let (bytes, entries) = live.entry(entry.file_index).or_default();
+            *bytes += key.len() + entry.len + 2;
+            *entries += 1;
+            counted.insert((entry.file_index, entry.offset));
+        }
+        for entry in self.blobs.values().filter_map(|blob| blob.entry) {
+            if counted.insert((entry.file_index, entry.offset)) {
+                live.entry(entry.file_index).or_default().0 += entry.len + 1;
+            }
+        }
+
+        let mut segments = Vec::with_capacity(self.files.len());
+        for (index, file) in &self.files {
+            let bytes = file.file.len()? as usize;
+            let (live_bytes, entries) = live.get(index).copied().unwrap_or_default();
+            segments.push(SegmentStats {
+                name: index.to_string(),
+                bytes,
+                stored_bytes: file.file.stored_len()? as usize,
+                live_bytes,
+                dead_bytes: bytes.saturating_sub(live_bytes),
+                entries,
+                dead_entries: file.unused_count,
+                age: self.current_file_index.base.saturating_sub(index.base),
+                tier: file.tier,
+            });
+        }
+        Ok(segments)
+    }
+
+    /// Recounts [`SharedState::dead_ratio`], if writes are held back by it
+    fn refresh_dead_ratio(&mut self) {
+        let Some(backpressure) = self.backpressure else {
+            return;
+        };
+        let segments = match self.segment_stats() {
+            Ok(segments) => segments,
+            Err(err) => {
+                tracing::warn!("Can't count dead bytes: {err}");
+                return;
+            }
+        };
+
+        let sealed = segments.iter().filter(|segment| segment.age > 0);
+        let (bytes, dead) = sealed.fold((0, 0), |(bytes, dead), segment| (bytes + segment.bytes, dead + segment.dead_bytes));
+        self.dead_ratio = match bytes {
+            0 => 0.0,
+            _ => dead as f64 / bytes as f64,
+        };
+        self.dead_ratio_of = self.current_file_index;
+        if self.stalled && self.dead_ratio <= backpressure.hard {
+            tracing::info!(dead_ratio = self.dead_ratio, "Compaction caught up, taking writes again");
+            self.stalled = false;
+        }
+    }
+
+    /// Marks `key` as the most recently used, if the database evicts keys
+    fn touch(&mut self, key: &str) {
+        if self.eviction.is_none() || keyspace::is_reserved(key) {
+            return;
+        }
+        self.uses += 1;
+        if let Some(used) = self.last_used.insert(key.to_owned(), self.uses) {
+            self.lru.remove(&(used, key.to_owned()));
+        }
+        self.lru.insert((self.uses, key.to_owned()));
+    }
+
+    fn forget(&mut self, key: &str) {
+        if let Some(used) = self.last_used.remove(key) {
+            self.lru.remove(&(used, key.to_owned()));
+        }
+    }
+
+    /// Counts `keys` as used before any used so far, e.g. recovered in the background
+    fn seed_lru(&mut self, keys: Vec<String>) {
+        if self.eviction.is_none() {
+            return;
+        }
+        for key in keys.into_iter().filter(|key| !keyspace::is_reserved(key)) {
+            if !self.last_used.contains_key(&key) {
+                self.last_used.insert(key.clone(), 0);
+                self.lru.insert((0, key));
+            }
+        }
+    }
+
+    /// Whether `key` has a value that expired, but wasn't swept yet
+    fn expired(&self, key: &str) -> bool {
+        self.expiries.get(key).is_some_and(|expires_at| *expires_at <= self.clock.now_millis()) && !self.pinned.contains(key)
+    }
+
+    /// Adds up [`Usage::bytes`] and the reserved keys again, after the table was
+    /// filled other than by writes
+    fn count_live_bytes(&mut self) {
+        let (reserved, user): (Vec<_>, Vec<_>) = self.table.iter().partition(|(key, _)| keyspace::is_reserved(key));
+        self.reserved_keys = reserved.len();
+        self.live_bytes = user.into_iter().map(|(key, entry)| key.len() + entry.len).sum();
+    }
+
+    /// Keys users wrote, leaving out the reserved ones
+    fn user_keys(&self) -> usize {
+        self.table.len() - self.reserved_keys
+    }
+
+    /// Drops the blobs no key refers to, once all keys are known
+    fn prune_blobs(&mut self) {
+        self.blobs.retain(|_, blob| blob.refs > 0);
     }
 
-    fn recover_file(table: &mut HashMap<String, TableEntry>, file_index: FileIndex, file: &mut File) -> Result<usize, KopperError> {
+    /// Indexes every record of `file`, returning where the last complete one ends.
+    /// Records it makes stale, and its tombstones, are counted in `unused` by file,
+    /// and the keys of its tombstones collected in `deleted`, if given. Blobs and
+    /// references are collected in `found`.
+    /// A key that isn't UTF-8 was never written by [`Kopper`] - the segment is
+    /// read up to that record only, like one torn by a crash.
+    fn recover_file(table: &mut HashMap<String, TableEntry>, unused: &mut BTreeMap<FileIndex, usize>, mut deleted: Option<&mut HashSet<String>>, found: &mut Found, file_index: FileIndex, file: &dyn SegmentFile) -> Result<usize, KopperError> {
 
         enum CurrentlyReading { Key, Value }
         let mut currently_reading = CurrentlyReading::Key;
-        let mut key = String::new();
+        // Bytes, a character may continue in the next chunk
+        let mut key = Vec::new();
 
         // With regards to current buffer
         let mut key_offset: usize;
@@ -357,12 +4228,23 @@ impl SharedState {
         let mut buffer_file_offset: usize = 0;
         
         let mut buffer = [0; 2048];
+        let file_len = file.len()? as usize;
 
-        loop {
-            let bytes_in_buffer = match file.read(&mut buffer)? {
+        // Anything after the last complete record was torn by a crash
+        let mut end_of_records = 0;
+
+        // Needed to recognize a one-byte tombstone value that ended the previous chunk
+        let mut last_byte_of_previous_chunk = 0;
+
+        // Values that may be blobs or references, their headers are read at the end
+        let mut flagged = Vec::new();
+
+        'chunks: loop {
+            let bytes_in_buffer = match buffer.len().min(file_len - buffer_file_offset) {
                 0 => break,
-                bytes_read => bytes_read,
+                bytes_to_read => bytes_to_read,
             };
+            file.read_at(&mut buffer[..bytes_in_buffer], buffer_file_offset as u64)?;
             
             key_offset = 0;
             
@@ -370,29 +4252,62 @@ impl SharedState {
                 
                 if buffer[byte_index] == b'\0' {
 
-                    // TODO: if this is first byte: ERROR
-                    
                     match currently_reading {
                         CurrentlyReading::Key => {
-                            key.push_str(std::str::from_utf8(&buffer[key_offset..byte_index]).unwrap());
+                            key.extend_from_slice(&buffer[key_offset..byte_index]);
                             
                             value_file_offset = buffer_file_offset + byte_index + 1;
                             currently_reading = CurrentlyReading::Value;
                         },
                         CurrentlyReading::Value => {
-                            // Swap pointers between key and empty string to avoid cloning
-                            let mut tmp_key = String::new();
-                            std::mem::swap(&mut tmp_key, &mut key);
-                            
-                            // Collected all needed parts: key, value's offset and length
-                            table.insert(tmp_key, 
-                                TableEntry {
+                            let Ok(tmp_key) = String::from_utf8(std::mem::take(&mut key)) else {
+                                break 'chunks;
+                            };
+
+                            let len = buffer_file_offset + byte_index - value_file_offset;
+                            let last_value_byte = match byte_index {
+                                0 => last_byte_of_previous_chunk,
+                                _ => buffer[byte_index - 1]
+                            };
+
+                            let replaced = if len == TOMBSTONE.len() && last_value_byte == TOMBSTONE[0] {
+                                *unused.entry(file_index).or_default() += 1;
+                                let replaced = table.remove(&tmp_key);
+                                if let Some(deleted) = deleted.as_deref_mut() {
+                                    deleted.insert(tmp_key);
+                                }
+                                replaced
+                            }
+                            else {
+                                let entry = TableEntry {
                                     file_index,
                                     offset: value_file_offset,
-                                    len: buffer_file_offset + byte_index - value_file_offset,
-                                });
+                                    len,
+                                };
+                                if len >= dedupe::HEADER_LEN.min(expiry::HEADER_LEN) {
+                                    let first_byte = match value_file_offset.checked_sub(buffer_file_offset) {
+                                        Some(at) => buffer[at],
+                                        // Started in an earlier chunk
+                                        None => {
+                                            let mut first_byte = [0];
+                                            file.read_at(&mut first_byte, value_file_offset as u64)?;
+                                            first_byte[0]
+                                        },
+                                    };
+                                    if dedupe::flagged(first_byte) || expiry::flagged(first_byte) {
+                                        flagged.push(entry);
+                                    }
+                                }
+
+                                // Collected all needed parts: key, value's offset and length
+                                table.insert(tmp_key, entry)
+                            };
+                            if let Some(replaced) = replaced {
+                                *unused.entry(replaced.file_index).or_default() += 1;
+                            }
                                 
                             key_offset = byte_index + 1;
+                            end_of_records = buffer_file_offset + key_offset;
                             currently_reading = CurrentlyReading::Key;
                         }
                     }
@@ -400,25 +4315,322 @@ impl SharedState {
             }
 
             // Being here, we're probably left with some incomplete key or value that continues in the next chunk
-            match currently_reading {
-                CurrentlyReading::Key => {
-                    key.push_str(std::str::from_utf8(&buffer[key_offset..bytes_in_buffer])?);
-                },
-                _ => ()
+            if let CurrentlyReading::Key = currently_reading {
+                key.extend_from_slice(&buffer[key_offset..bytes_in_buffer]);
             }
 
             buffer_file_offset += bytes_in_buffer;
+            last_byte_of_previous_chunk = buffer[bytes_in_buffer - 1];
+        }
+
+        for entry in flagged {
+            let mut header = vec![0; entry.len.min(dedupe::HEADER_LEN.max(expiry::HEADER_LEN))];
+            file.read_at(&mut header, entry.offset as u64)?;
+            found.header(entry, &header);
+        }
+
+        Ok(end_of_records)
+    }
+}
+
+/// Copies the first `len` bytes of `file` to the segment `name` of `store`, replacing it
+fn copy_prefix(file: &dyn SegmentFile, len: u64, store: &dyn SegmentStore, name: &str) -> Result<(), KopperError> {
+    let target = store.create(name)?;
+    target.truncate(0)?;
+
+    let mut buffer = vec![0; 64 * 1024];
+    let mut offset = 0;
+    while offset < len {
+        let chunk = buffer.len().min((len - offset) as usize);
+        file.read_at(&mut buffer[..chunk], offset)?;
+        target.append(&buffer[..chunk])?;
+        offset += chunk as u64;
+    }
+
+    target.sync()?;
+    Ok(())
+}
+
+/// Hidden directory next to `target`, on the same filesystem, to put a database
+/// together in before [`move_into_place`]. Fails if `target` holds anything already.
+fn staging_dir(target: &str) -> Result<std::path::PathBuf, KopperError> {
+    let target = Path::new(target);
+    if target.exists() && fs::read_dir(target)?.next().is_some() {
+        return Err(KopperError::InvalidBackup(format!("{} isn't empty", target.display())));
+    }
+
+    let name = target.file_name()
+        .ok_or_else(|| KopperError::InvalidBackup(format!("{} isn't a directory name", target.display())))?;
+    let staging = target.with_file_name(format!(".{}.partial-{}", name.to_string_lossy(), new_log_id()));
+    fs::create_dir_all(&staging)?;
+    Ok(staging)
+}
+
+fn move_into_place(staging: &Path, target: &str) -> Result<(), KopperError> {
+    File::open(staging)?.sync_all()?;
+    // Only an empty directory can be in the way, see staging_dir
+    if Path::new(target).exists() {
+        fs::remove_dir(target)?;
+    }
+    fs::rename(staging, target)?;
+    Ok(())
+}
+
+/// Segments in `store`, oldest first
+fn segment_indexes(store: &dyn SegmentStore) -> Result<Vec<FileIndex>, KopperError> {
+    let mut file_indexes = store.list()?.iter()
+        .filter(|name| *name != segment_log::NAME)
+        .map(|name| name.parse::<FileIndex>())
+        .collect::<Result<Vec<_>, _>>()?;
+    file_indexes.sort();
+    Ok(file_indexes)
+}
+
+/// Segments of `store` the MANIFEST names, oldest first. The rest were left behind
+/// by a crash and are removed - segments compacted already, or the unfinished
+/// output of a compaction. New segments are recorded without syncing, so one the
+/// MANIFEST doesn't know of at all is kept.
+fn logged_segments(store: &dyn SegmentStore, logged: &Segments) -> Result<Vec<FileIndex>, KopperError> {
+    let mut file_indexes = Vec::new();
+    for file_index in segment_indexes(store)? {
+        let name = file_index.to_string();
+        if logged.removed.contains(&name) {
+            tracing::warn!("Removing {file_index}, compacted before a crash");
+            store.delete(&name)?;
+        } else if logged.live.contains(&name) || file_index.index == 0 {
+            file_indexes.push(file_index);
+        } else {
+            tracing::warn!("Removing {file_index}, left behind by an interrupted compaction");
+            store.delete(&name)?;
+        }
+    }
+
+    // Segments cut off since the directory was last synced may be gone, even if
+    // the MANIFEST recording them made it - nothing flushed was written to them
+    let found: HashSet<String> = file_indexes.iter().map(FileIndex::to_string).collect();
+    let newest = file_indexes.last().map(|file_index| file_index.base);
+    for missing in logged.live.iter().filter(|name| !found.contains(*name)) {
+        match missing.parse::<FileIndex>() {
+            Ok(file_index) if file_index.index == 0 && newest.is_none_or(|newest| file_index.base > newest) =>
+                tracing::warn!("Dropping {file_index}, cut off before a crash that lost it"),
+            _ => return Err(KopperError::InternalError(anyhow::anyhow!("Segment {missing} is in the {} but missing", segment_log::NAME))),
+        }
+    }
+    Ok(file_indexes)
+}
+
+/// Segments of `store` without a MANIFEST to go by, e.g. one written before there
+/// was any. A crash between a compaction writing its segment and removing the one
+/// it compacted leaves both behind. The compacted one may be incomplete, while the
+/// other never changed - only the oldest segment of every base is kept.
+fn listed_segments(store: &dyn SegmentStore) -> Result<Vec<FileIndex>, KopperError> {
+    let mut file_indexes: Vec<FileIndex> = Vec::new();
+    for file_index in segment_indexes(store)? {
+        match file_indexes.last() {
+            Some(older) if older.base == file_index.base => {
+                tracing::warn!("Removing {file_index}, left behind by an interrupted compaction of {older}");
+                store.delete(&file_index.to_string())?;
+            },
+            _ => file_indexes.push(file_index),
+        }
+    }
+    Ok(file_indexes)
+}
+
+/// Indexes the complete records of `file` from `from` on, returning where the
+/// last one ends. A record still being written by another process is left for later.
+fn index_tail(table: &mut HashMap<String, TableEntry>, mut found: Option<&mut Found>, file_index: FileIndex, file: &dyn SegmentFile, from: usize) -> Result<usize, KopperError> {
+    let len = file.len()? as usize;
+    if len <= from {
+        return Ok(from);
+    }
+
+    let mut buffer = vec![0; len - from];
+    file.read_at(&mut buffer, from as u64)?;
+
+    let mut end = 0;
+    for (key, record, value_offset) in KeyValueIterator::from(&buffer) {
+        let value = &record[key.len() + 1..record.len() - 1];
+        let entry = TableEntry { file_index, offset: from + value_offset, len: value.len() };
+        if value == TOMBSTONE {
+            table.remove(key);
+        } else {
+            table.insert(key.to_owned(), entry);
+        }
+        if let Some(found) = found.as_deref_mut() {
+            found.header(entry, value);
+        }
+        end = value_offset + value.len() + 1;
+    }
+    Ok(from + end)
+}
+
+/// Live values of a segment as recovery indexes them, see [`index_segment`]
+#[derive(Default, Debug)]
+pub struct SegmentIndex {
+    /// Offset and length of the newest value of every key, unless it was deleted
+    pub values: HashMap<String, (usize, usize)>,
+    /// Where the last complete record ends - anything after it is dropped on recovery
+    pub end_of_records: usize,
+}
+
+/// Reads `file` the way [`Kopper`] recovers its segments on startup
+pub fn index_segment(file: &dyn SegmentFile) -> Result<SegmentIndex, KopperError> {
+    let mut table = HashMap::new();
+    let end_of_records = SharedState::recover_file(&mut table, &mut BTreeMap::new(), None, &mut Found::default(), FileIndex { base: 0, index: 0 }, file)?;
+    let values = table.into_iter().map(|(key, entry)| (key, (entry.offset, entry.len))).collect();
+    Ok(SegmentIndex { values, end_of_records })
+}
+
+/// The `count` smallest of `keys`, in order, without sorting all of them
+fn first_keys<'a>(keys: impl Iterator<Item = &'a String>, count: usize) -> Vec<&'a String> {
+    let mut first = std::collections::BinaryHeap::new();
+    for key in keys {
+        first.push(key);
+        if first.len() > count {
+            first.pop();
+        }
+    }
+    first.into_sorted_vec()
+}
+
+/// Reads value described by `entry`
+fn read_value(file: &dyn SegmentFile, entry: &TableEntry) -> Result<String, KopperError> {
+    let mut buffer = vec![0; entry.len];
+    file.read_at(&mut buffer, entry.offset as u64)?;
+    decode_value(file, buffer)
+}
+
+/// Reads value described by `entry`, and the metadata it was written with
+fn read_value_with_meta(file: &dyn SegmentFile, entry: &TableEntry) -> Result<(String, Meta), KopperError> {
+    let mut buffer = vec![0; entry.len];
+    file.read_at(&mut buffer, entry.offset as u64)?;
+    decode_record(file, buffer)
+}
+
+/// Value stored as `buffer` in `file`
+fn decode_value(file: &dyn SegmentFile, buffer: Vec<u8>) -> Result<String, KopperError> {
+    decode_record(file, buffer).map(|(value, _)| value)
+}
+
+/// Value stored as `buffer` in `file`, and the metadata it was written with
+fn decode_record(file: &dyn SegmentFile, buffer: Vec<u8>) -> Result<(String, Meta), KopperError> {
+    let value = match buffer.first().and_then(|byte| chain::kind(*byte)) {
+        Some(chain::Kind::Set) => set::to_json(&set::members(buffer, segment_reader(file))?),
+        Some(chain::Kind::Hash) => hash::to_json(&hash::fields(buffer, segment_reader(file))?),
+        Some(chain::Kind::Counter) => counter::counter(buffer, segment_reader(file))?.total().to_string(),
+        Some(chain::Kind::List) => list::to_json(&list::elements(buffer, segment_reader(file))?),
+        None => {
+            let (stored, meta) = meta::strip(dedupe::strip(expiry::strip(buffer))?);
+            return Ok((String::from_utf8(compression::decode(stored)?)?, meta));
+        },
+    };
+    Ok((value, Meta::default()))
+}
+
+/// Reads the values of `entries` from `files` segment by segment, in order of
+/// offsets and in big stretches, folding them into `init` with `f`, see [`Kopper::fold`]
+fn read_in_order<B>(entries: impl Iterator<Item = (String, TableEntry)>, files: &BTreeMap<FileIndex, Arc<dyn SegmentFile>>,
+    init: B, mut f: impl FnMut(B, &str, &str) -> B) -> Result<B, KopperError> {
+    let mut by_file: BTreeMap<FileIndex, Vec<(String, TableEntry)>> = BTreeMap::new();
+    for (key, entry) in entries {
+        by_file.entry(entry.file_index).or_default().push((key, entry));
+    }
+
+    let mut acc = init;
+    for (file_index, mut entries) in by_file {
+        entries.sort_unstable_by_key(|(_, entry)| entry.offset);
+        let file = &*files[&file_index];
+        let mut entries = &entries[..];
+        while let [(_, first), ..] = entries {
+            // A stretch of the segment with as many values as fit, at least one
+            let start = first.offset;
+            let count = entries.iter().take_while(|(_, entry)| entry.offset + entry.len <= start + FOLD_READ_LEN).count().max(1);
+            let (stretch, rest) = entries.split_at(count);
+            let end = stretch.iter().map(|(_, entry)| entry.offset + entry.len).max().unwrap();
+            let mut buffer = vec![0; end - start];
+            file.read_at(&mut buffer, start as u64)?;
+            for (key, entry) in stretch {
+                let value = decode_value(file, buffer[entry.offset - start..][..entry.len].to_vec())?;
+                acc = f(acc, key, &value);
+            }
+            entries = rest;
         }
+    }
+    Ok(acc)
+}
+
+/// Reads records of [`chain`]s in `file`, from the offset and as long as given
+fn segment_reader(file: &dyn SegmentFile) -> impl FnMut(u64, usize) -> Result<Vec<u8>, KopperError> + '_ {
+    move |offset, len| {
+        let mut buffer = vec![0; len];
+        file.read_at(&mut buffer, offset)?;
+        Ok(buffer)
+    }
+}
+
+/// The value of `kind` whose newest record is `record` as one record, see
+/// [`chain::records`]. Counters collapse increments in buckets over by `now`.
+fn whole(kind: chain::Kind, record: Vec<u8>, read: impl FnMut(u64, usize) -> Result<Vec<u8>, KopperError>, now: u64) -> Result<Vec<u8>, KopperError> {
+    Ok(match kind {
+        chain::Kind::Set => set::full(&set::members(record, read)?),
+        chain::Kind::Hash => hash::full(&hash::fields(record, read)?),
+        chain::Kind::List => list::full(&list::elements(record, read)?),
+        chain::Kind::Counter => {
+            let mut counter = counter::counter(record, read)?;
+            counter.collapse(now);
+            counter::full(&counter)
+        },
+    })
+}
+
+/// Point-in-time view of the database created by [`Kopper::scan`].
+pub struct Scan {
+    entries: std::vec::IntoIter<(String, TableEntry)>,
+    files: BTreeMap<FileIndex, Arc<dyn SegmentFile>>,
+    sequence: u64
+}
+
+impl Scan {
+    /// Sequence of the newest change the view includes, see [`Kopper::changes_since`]
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
 
-        Ok(buffer_file_offset)
+    /// Keys of the snapshot in order, without reading any values
+    pub fn into_keys(self) -> impl Iterator<Item = String> {
+        self.entries.map(|(key, _)| key)
+    }
+
+    /// Only the entries with keys `keep` is true for - the values of the others
+    /// are never read
+    pub fn filter_keys(self, keep: impl Fn(&str) -> bool) -> Scan {
+        let entries: Vec<_> = self.entries.filter(|(key, _)| keep(key)).collect();
+        Scan { entries: entries.into_iter(), ..self }
+    }
+}
+
+impl Iterator for Scan {
+    type Item = Result<(String, String), KopperError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, entry) = self.entries.next()?;
+        let file = self.files.get(&entry.file_index).unwrap(); // Every indexed file was cloned
+        Some(read_value(&**file, &entry).map(|value| (key, value)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
     }
 }
 
 /// [`KeyValueIterator`] is an iterator that given a &Vec<u8> of format 
 /// `['k','e','y','\0','v','a','l','u','e','\0']` iterates over key-value pairs.
 /// 
-/// Iterator returns a tuple containing `key` string, ref to slice with `value`, and `offset`
-/// related to the beginning of the vector.  
+/// Iterator returns a tuple containing `key` string, ref to slice with the whole record
+/// (key and value, both with their separators), and `offset` of the value related to the
+/// beginning of the vector. It stops at the first incomplete record, or at a key that
+/// isn't UTF-8 - everything after it can't be trusted.
 /// 
 /// ```
 /// use kopperdb::kopper::KeyValueIterator;
@@ -444,31 +4656,15 @@ impl<'a> Iterator for KeyValueIterator<'a> {
     type Item = (&'a str,&'a [u8],usize);
 
     fn next(&mut self) -> Option<Self::Item> {
-        let mut key = "";
-        let mut value: &[u8] = &[];
-        let mut key_found = false;
-        let mut offset = 0;
-
-        // Find key
-        for byte in self.pointer..self.buf.len() {
-            if self.buf[byte] == b'\0' {
-                if !key_found {
-                    key = std::str::from_utf8(&self.buf[self.pointer..byte]).unwrap();
-                    key_found = true;
-                    offset = byte + 1;
-                }
-                else {
-                    value = &self.buf[self.pointer..byte + 1];
-                    self.pointer = byte + 1;
-                    break;
-                }
-            }
-        }
-        
-        if key.is_empty() || value.is_empty() {
-            return None;
-        }
+        let rest = &self.buf[self.pointer..];
+
+        // Find key, then the end of its value
+        let key_end = rest.iter().position(|&byte| byte == b'\0')?;
+        let value_end = key_end + 1 + rest[key_end + 1..].iter().position(|&byte| byte == b'\0')?;
+        let key = std::str::from_utf8(&rest[..key_end]).ok()?;
 
-        Some((key, value, offset))
+        let offset = self.pointer + key_end + 1;
+        self.pointer += value_end + 1;
+        Some((key, &rest[..value_end + 1], offset))
     }
 }
\ No newline at end of file
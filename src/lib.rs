@@ -1,4 +1,54 @@
 pub mod kopper;
+pub mod keyspace;
+pub mod store;
+pub mod sharded;
 pub mod brass;
+pub mod engine;
+pub mod lsm;
+pub mod metrics;
+pub mod clock;
+pub mod protocol;
+pub mod archive;
+pub mod manifest;
+pub mod compression;
+pub mod packed;
+pub mod dedupe;
+pub mod expiry;
+pub mod meta;
+pub mod chain;
+pub mod list;
+pub mod queue;
+pub mod versions;
+pub mod trash;
+pub mod lock;
+pub mod set;
+pub mod hash;
+pub mod counter;
+pub mod pattern;
+pub mod numeric_index;
+pub mod hooks;
+pub mod schema;
+pub mod merge_patch;
+pub mod doctor;
+pub mod verify;
+pub mod saturation;
+pub mod testing;
 
-mod error_utils;
\ No newline at end of file
+#[cfg(feature = "server")]
+pub mod stats;
+
+#[cfg(feature = "server")]
+pub mod client;
+
+#[cfg(feature = "kopper-ffi")]
+pub mod ffi;
+
+#[cfg(feature = "s3-backup")]
+pub mod backup;
+
+#[cfg(feature = "encryption")]
+pub mod encryption;
+
+mod error_utils;
+mod pubsub;
+mod segment_log;
\ No newline at end of file
@@ -0,0 +1,142 @@
+//! Lists of values, see [`Kopper::lpush`](crate::kopper::Kopper::lpush). Changed
+//! an element at a time, see [`chain`] - a record lists `op | element` for every
+//! element pushed, `op` being `<` onto the start or `>` onto the end, and a lone
+//! `op` for every element popped, `(` off the start or `)` off the end. The whole
+//! list is pushed onto the end, in order. A list reads as a JSON array of its elements.
+
+use std::collections::VecDeque;
+
+use crate::chain::{self, Header, Kind};
+use crate::kopper::KopperError;
+
+const PUSHED_LEFT: u8 = b'<';
+const PUSHED_RIGHT: u8 = b'>';
+const POPPED_LEFT: u8 = b'(';
+const POPPED_RIGHT: u8 = b')';
+
+/// End of a list pushed to or popped from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum End {
+    Left,
+    Right,
+}
+
+/// Change to a list, in the order they're made
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op<'a> {
+    Push(End, &'a str),
+    Pop(End),
+}
+
+/// Makes `op` on `list`, returning the element popped
+pub fn apply(list: &mut VecDeque<String>, op: Op) -> Option<String> {
+    match op {
+        Op::Push(End::Left, element) => list.push_front(element.to_owned()),
+        Op::Push(End::Right, element) => list.push_back(element.to_owned()),
+        Op::Pop(End::Left) => return list.pop_front(),
+        Op::Pop(End::Right) => return list.pop_back(),
+    }
+    None
+}
+
+/// Record of the whole `list`
+pub fn full(list: &VecDeque<String>) -> Vec<u8> {
+    let mut record = chain::full(Kind::List);
+    for element in list {
+        record.push(PUSHED_RIGHT);
+        chain::push(&mut record, element);
+    }
+    record
+}
+
+/// Record of `ops`, changing the list recorded at `offset` of the same segment -
+/// `len` long, with a header `previous`
+pub fn delta(offset: u64, len: usize, previous: Header, ops: &[Op]) -> Vec<u8> {
+    let mut record = chain::delta(Kind::List, offset, len, previous);
+    for op in ops {
+        match op {
+            Op::Push(end, element) => {
+                record.push(if *end == End::Left { PUSHED_LEFT } else { PUSHED_RIGHT });
+                chain::push(&mut record, element);
+            },
+            Op::Pop(End::Left) => record.push(POPPED_LEFT),
+            Op::Pop(End::Right) => record.push(POPPED_RIGHT),
+        }
+    }
+    record
+}
+
+/// Elements of the list whose newest record is `record`, see [`chain::records`]
+pub fn elements(record: Vec<u8>, read: impl FnMut(u64, usize) -> Result<Vec<u8>, KopperError>) -> Result<VecDeque<String>, KopperError> {
+    let mut list = VecDeque::new();
+    for record in chain::records(Kind::List, record, read)? {
+        let mut ops = chain::body(&record);
+        while let [op, rest @ ..] = ops {
+            ops = rest;
+            let op = match *op {
+                PUSHED_LEFT => Op::Push(End::Left, chain::take(&mut ops)?),
+                PUSHED_RIGHT => Op::Push(End::Right, chain::take(&mut ops)?),
+                POPPED_LEFT => Op::Pop(End::Left),
+                POPPED_RIGHT => Op::Pop(End::Right),
+                _ => return Err(chain::damaged()),
+            };
+            apply(&mut list, op);
+        }
+    }
+    Ok(list)
+}
+
+/// How a list reads
+pub fn to_json(list: &VecDeque<String>) -> String {
+    // Serializing strings can't fail
+    serde_json::to_string(list).unwrap()
+}
+
+/// Elements `start` to `stop` of `list`, both included. Negative indices count
+/// from the end, -1 being the last element, like Redis' LRANGE.
+pub fn range(list: &VecDeque<String>, start: i64, stop: i64) -> Vec<String> {
+    let len = list.len() as i64;
+    let index = |index: i64| match index {
+        index if index < 0 => (len + index).max(0),
+        index => index,
+    };
+    let (start, stop) = (index(start), index(stop).min(len - 1));
+    if start > stop {
+        return Vec::new();
+    }
+    list.range(start as usize..=stop as usize).cloned().collect()
+}
+
+/// TESTS
+
+#[test]
+fn test_ranges_count_from_either_end() {
+    let list: VecDeque<String> = ["a", "b", "c", "d"].into_iter().map(str::to_owned).collect();
+    assert_eq!(range(&list, 0, -1), ["a", "b", "c", "d"]);
+    assert_eq!(range(&list, 1, 2), ["b", "c"]);
+    assert_eq!(range(&list, -2, 10), ["c", "d"]);
+    assert_eq!(range(&list, -10, 0), ["a"]);
+    assert!(range(&list, 3, 1).is_empty());
+    assert!(range(&list, 4, -1).is_empty());
+    assert!(range(&VecDeque::new(), 0, -1).is_empty());
+}
+
+#[test]
+fn test_pushes_and_pops_apply_over_the_whole_list() {
+    let whole: VecDeque<String> = ["b", "c"].into_iter().map(str::to_owned).collect();
+    let mut segment = vec![full(&whole)];
+    segment.push(delta(0, segment[0].len(), Header::Full, &[Op::Push(End::Left, "a"), Op::Push(End::Right, ""), Op::Pop(End::Right)]));
+    let previous = chain::header(&segment[1]).unwrap().1;
+    segment.push(delta(1, segment[1].len(), previous, &[Op::Pop(End::Left), Op::Push(End::Right, "d"), Op::Pop(End::Left)]));
+
+    // Offsets stand in for where the records are
+    let read = |offset: u64, len: usize| {
+        assert_eq!(segment[offset as usize].len(), len);
+        Ok(segment[offset as usize].clone())
+    };
+    let list = elements(segment[2].clone(), read).unwrap();
+    assert_eq!(list, ["c", "d"]);
+    assert_eq!(to_json(&list), r#"["c","d"]"#);
+    assert_eq!(elements(full(&list), read).unwrap(), list);
+    assert!(elements(b"\xF5D not a header".to_vec(), read).is_err());
+}
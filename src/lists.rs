@@ -0,0 +1,120 @@
+//! Routes for lists, see [`Kopper::lpush`]. Pushes and pops go to either end of
+//! the list, and a range of it can be read without taking anything off it.
+
+use rocket::State;
+use rocket::serde::json::Json;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use kopperdb::kopper::{Kopper, KopperError};
+
+#[derive(Serialize, ToSchema)]
+pub struct PushResponse {
+    /// Elements in the list after the push, 0 on failure
+    len: usize,
+    /// "OK" on success, description of the problem otherwise
+    error: String
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PopResponse {
+    /// Element taken off the list, empty if there was none
+    value: String,
+    /// "OK" on success, description of the problem otherwise
+    error: String
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RangeResponse {
+    values: Vec<String>,
+    /// "OK" on success, description of the problem otherwise
+    error: String
+}
+
+fn pushed(pushed: Result<usize, KopperError>) -> Json<PushResponse> {
+    Json(match pushed {
+        Ok(len) => PushResponse { len, error: "OK".to_string() },
+        Err(err) => PushResponse { len: 0, error: format!("Error while pushing! : {err}") },
+    })
+}
+
+fn popped(key: &str, popped: Result<Option<String>, KopperError>) -> Json<PopResponse> {
+    Json(match popped {
+        Ok(Some(value)) => PopResponse { value, error: "OK".to_string() },
+        Ok(None) => PopResponse { value: String::new(), error: format!("{key} does not exist!") },
+        Err(err) => PopResponse { value: String::new(), error: format!("Error while popping! : {err}") },
+    })
+}
+
+#[utoipa::path(
+    post,
+    path = "/lpush/{key}/{value}",
+    tag = "lists",
+    params(
+        ("key" = String, Path, description = "Key of the list, created if there's none"),
+        ("value" = String, Path, description = "Element to push onto the start of the list")
+    ),
+    responses((status = 200, description = "Result of the push", body = PushResponse))
+)]
+#[post("/lpush/<key>/<value>")]
+pub fn lpush(key: &str, value: &str, db: &State<Kopper>) -> Json<PushResponse> {
+    pushed(db.lpush(key, &[value]))
+}
+
+#[utoipa::path(
+    post,
+    path = "/rpush/{key}/{value}",
+    tag = "lists",
+    params(
+        ("key" = String, Path, description = "Key of the list, created if there's none"),
+        ("value" = String, Path, description = "Element to push onto the end of the list")
+    ),
+    responses((status = 200, description = "Result of the push", body = PushResponse))
+)]
+#[post("/rpush/<key>/<value>")]
+pub fn rpush(key: &str, value: &str, db: &State<Kopper>) -> Json<PushResponse> {
+    pushed(db.rpush(key, &[value]))
+}
+
+#[utoipa::path(
+    post,
+    path = "/lpop/{key}",
+    tag = "lists",
+    params(("key" = String, Path, description = "Key of the list, deleted once it's empty")),
+    responses((status = 200, description = "First element of the list", body = PopResponse))
+)]
+#[post("/lpop/<key>")]
+pub fn lpop(key: &str, db: &State<Kopper>) -> Json<PopResponse> {
+    popped(key, db.lpop(key))
+}
+
+#[utoipa::path(
+    post,
+    path = "/rpop/{key}",
+    tag = "lists",
+    params(("key" = String, Path, description = "Key of the list, deleted once it's empty")),
+    responses((status = 200, description = "Last element of the list", body = PopResponse))
+)]
+#[post("/rpop/<key>")]
+pub fn rpop(key: &str, db: &State<Kopper>) -> Json<PopResponse> {
+    popped(key, db.rpop(key))
+}
+
+#[utoipa::path(
+    get,
+    path = "/lrange/{key}",
+    tag = "lists",
+    params(
+        ("key" = String, Path, description = "Key of the list"),
+        ("start" = Option<i64>, Query, description = "Index of the first element, 0 by default. Negative ones count from the end."),
+        ("stop" = Option<i64>, Query, description = "Index of the last element, included, -1 - the last one - by default")
+    ),
+    responses((status = 200, description = "Elements of the list in the range, none if there's no such list", body = RangeResponse))
+)]
+#[get("/lrange/<key>?<start>&<stop>")]
+pub fn lrange(key: &str, start: Option<i64>, stop: Option<i64>, db: &State<Kopper>) -> Json<RangeResponse> {
+    Json(match db.lrange(key, start.unwrap_or(0), stop.unwrap_or(-1)) {
+        Ok(values) => RangeResponse { values, error: "OK".to_string() },
+        Err(err) => RangeResponse { values: Vec::new(), error: format!("Error while reading! : {err}") },
+    })
+}
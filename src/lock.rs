@@ -0,0 +1,41 @@
+//! Locks, see [`Kopper::acquire_lock`](crate::kopper::Kopper::acquire_lock). A
+//! lock is a JSON object under the [reserved](crate::keyspace) [`lock_key`] of its
+//! name, holding the fencing token of its last lease and when that runs out. It
+//! stays once the lease is over or released, without a TTL and out of reach of
+//! writes, deletes and eviction, so the next lease gets a greater token even
+//! once the database was reopened.
+
+use serde::{Deserialize, Serialize};
+
+use crate::keyspace;
+use crate::kopper::KopperError;
+
+/// Key of the lock `name`
+pub fn lock_key(name: &str) -> String {
+    keyspace::reserved_key("lock", name)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lease {
+    /// Fencing token of the last lease, 0 if there was none
+    pub token: u64,
+    /// When the lease runs out, in milliseconds since the Unix epoch - 0 once released
+    pub expires_at: u64,
+}
+
+impl Lease {
+    /// Whether the lease still holds the lock at `now`
+    pub fn held(&self, now: u64) -> bool {
+        now < self.expires_at
+    }
+}
+
+/// Lease stored as `value` under `key`
+pub fn decode(key: &str, value: &str) -> Result<Lease, KopperError> {
+    serde_json::from_str(value).map_err(|_| KopperError::WrongType(key.to_owned()))
+}
+
+pub fn encode(lease: &Lease) -> String {
+    // Serializing numbers can't fail
+    serde_json::to_string(lease).unwrap()
+}
@@ -0,0 +1,65 @@
+//! Routes for locks, see [`Kopper::acquire_lock`]. A lease is taken for a while
+//! and comes with a fencing token, which releases it and can be checked by
+//! whatever the holder writes to, to turn down holders whose lease ran out.
+
+use std::time::Duration;
+
+use rocket::State;
+use rocket::serde::json::Json;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use kopperdb::kopper::Kopper;
+
+#[derive(Serialize, ToSchema)]
+pub struct LockResponse {
+    /// Fencing token of the lease, 0 if it wasn't taken
+    token: u64,
+    /// "OK" on success, description of the problem otherwise
+    error: String
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ReleaseResponse {
+    /// Whether the lease was still held with the token
+    released: bool,
+    /// "OK" on success, description of the problem otherwise
+    error: String
+}
+
+#[utoipa::path(
+    post,
+    path = "/lock/{name}/acquire",
+    tag = "locks",
+    params(
+        ("name" = String, Path, description = "Name of the lock"),
+        ("ttl_ms" = u64, Query, description = "How long the lease lasts unless released")
+    ),
+    responses((status = 200, description = "Fencing token of the lease", body = LockResponse))
+)]
+#[post("/lock/<name>/acquire?<ttl_ms>")]
+pub fn acquire(name: &str, ttl_ms: u64, db: &State<Kopper>) -> Json<LockResponse> {
+    Json(match db.acquire_lock(name, Duration::from_millis(ttl_ms)) {
+        Ok(Some(token)) => LockResponse { token, error: "OK".to_string() },
+        Ok(None) => LockResponse { token: 0, error: format!("{name} is held!") },
+        Err(err) => LockResponse { token: 0, error: format!("Error while locking! : {err}") },
+    })
+}
+
+#[utoipa::path(
+    post,
+    path = "/lock/{name}/release/{token}",
+    tag = "locks",
+    params(
+        ("name" = String, Path, description = "Name of the lock"),
+        ("token" = u64, Path, description = "Fencing token of the lease")
+    ),
+    responses((status = 200, description = "Result of the release", body = ReleaseResponse))
+)]
+#[post("/lock/<name>/release/<token>")]
+pub fn release(name: &str, token: u64, db: &State<Kopper>) -> Json<ReleaseResponse> {
+    Json(match db.release_lock(name, token) {
+        Ok(released) => ReleaseResponse { released, error: "OK".to_string() },
+        Err(err) => ReleaseResponse { released: false, error: format!("Error while unlocking! : {err}") },
+    })
+}
@@ -0,0 +1,104 @@
+use std::{sync::atomic::{AtomicU64, Ordering}, time::Instant, fmt::Display};
+
+use rocket::{
+    Request, Response, Data,
+    fairing::{Fairing, Info, Kind},
+    request::{FromRequest, Outcome},
+    http::Header
+};
+
+use kopperdb::stats::{Stat, Label};
+
+use crate::api::Metrics;
+
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Identifier of a single HTTP request. Taken from the `X-Request-Id` header
+/// if the client sent one, generated otherwise. It's echoed back in the response
+/// and attached to every log line and span produced while handling the request.
+#[derive(Clone)]
+pub struct RequestId(String);
+
+impl RequestId {
+    pub fn of<'r>(req: &'r Request<'_>) -> &'r RequestId {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        req.local_cache(|| match req.headers().get_one(REQUEST_ID_HEADER) {
+            Some(id) => RequestId(id.to_owned()),
+            None => RequestId(format!("{:016x}", NEXT_ID.fetch_add(1, Ordering::Relaxed))),
+        })
+    }
+
+    /// Span to enter while calling into the engine, so engine-level events
+    /// can be correlated with the request that caused them.
+    pub fn span(&self, key: &str) -> tracing::Span {
+        tracing::info_span!("request", request_id = %self, key)
+    }
+}
+
+impl Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestId {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(RequestId::of(req).clone())
+    }
+}
+
+struct RequestStart(Instant);
+
+/// Fairing logging one line per request: method, path, key, status, latency and request ID.
+/// The latency is also recorded in [`Metrics`], labelled with the route and status.
+pub struct RequestLogger;
+
+#[rocket::async_trait]
+impl Fairing for RequestLogger {
+    fn info(&self) -> Info {
+        Info { name: "Request logger", kind: Kind::Request | Kind::Response }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        req.local_cache(|| RequestStart(Instant::now()));
+        RequestId::of(req);
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let latency = req.local_cache(|| RequestStart(Instant::now())).0.elapsed();
+        let id = RequestId::of(req);
+
+        tracing::info!(
+            request_id = %id,
+            method = %req.method(),
+            path = %req.uri().path(),
+            key = route_param(req, "<key>"),
+            status = res.status().code,
+            latency_us = latency.as_micros() as u64,
+            "request handled"
+        );
+
+        if let Some(metrics) = req.rocket().state::<Metrics>() {
+            let route = req.route().and_then(|route| route.name.as_deref()).unwrap_or("unmatched");
+            let label = Label { route: route.to_owned(), status: res.status().code };
+            metrics.record(Stat::Request(label, latency.as_nanos()));
+        }
+
+        res.set_header(Header::new(REQUEST_ID_HEADER, id.0.clone()));
+    }
+}
+
+/// Value of the `param` segment of the matched route, e.g. `<key>`, if the route has one.
+pub fn route_param<'r>(req: &'r Request<'_>, param: &str) -> Option<&'r str> {
+    let route = req.route()?;
+    let index = route.uri.path()
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .position(|segment| segment == param)?;
+
+    req.uri().path().segments().get(index)
+}
@@ -0,0 +1,600 @@
+//! Log-structured merge tree engine. Writes go to an in-memory memtable, backed by
+//! a write-ahead log, which is flushed to an immutable sorted run once it outgrows
+//! `memtable_size`. Runs are merged into one once there are more than [`MAX_RUNS`].
+//!
+//! ```text
+//! wal        writes since the last flush, replayed on startup
+//! 1-3.run    runs 1 to 3, merged
+//! 4-4.run    flushed after them, wins over them
+//! ```
+//!
+//! Records are `key\0value\0` like in Kopper's segments, deletes are tombstones.
+//! Every run keeps a sparse index in memory - the first key of each block of
+//! [`INDEX_INTERVAL`] bytes - so a lookup reads one block per run at most, and keys
+//! don't have to fit in memory like Kopper's keydir. Runs are sorted, so ranges of
+//! keys are read without touching the rest.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Read, Write};
+use std::iter::Peekable;
+use std::ops::Bound;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::engine::{Durability, EngineScan, EngineStats, KvEngine};
+use crate::kopper::KopperError;
+
+/// Memtable size, in bytes of keys and values, the server flushes at
+pub const DEFAULT_MEMTABLE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Runs kept before they're merged into one
+pub const MAX_RUNS: usize = 4;
+
+/// Bytes of a run between two keys of its sparse index
+pub const INDEX_INTERVAL: u64 = 4096;
+
+/// Value of a record marking its key as deleted, never valid UTF-8
+const TOMBSTONE: &[u8] = &[0xFF];
+
+const WAL_FILE: &str = "wal";
+
+/// Bytes of a run read at once while iterating over it
+const READ_CHUNK: usize = 64 * 1024;
+
+/// Newest value of a key, `None` for deleted keys
+type Versioned = (String, Option<String>);
+
+type Records = Box<dyn Iterator<Item = Result<Versioned, KopperError>> + Send>;
+
+type Memtable = BTreeMap<String, Option<String>>;
+
+#[derive(Clone)]
+pub struct Lsm {
+    state: Arc<Mutex<SharedState>>,
+    path: String,
+    memtable_size: usize,
+}
+
+struct SharedState {
+    memtable: Memtable,
+    /// Bytes of keys and values in the memtable
+    memtable_bytes: usize,
+    wal: File,
+    wal_bytes: u64,
+    /// Oldest first
+    runs: Vec<Run>,
+    /// Id of the next flushed run
+    next_run: u64,
+    /// Number of keys that aren't deleted
+    keys: usize,
+}
+
+/// Sorted, immutable file holding the runs `first` to `last`
+struct Run {
+    first: u64,
+    last: u64,
+    file: Arc<File>,
+    size: u64,
+    /// First key of every block with its offset
+    index: Vec<(String, u64)>,
+}
+
+impl Run {
+    fn name(first: u64, last: u64) -> String {
+        format!("{first}-{last}.run")
+    }
+
+    /// Parses run names, `None` for other files
+    fn parse_name(name: &str) -> Option<(u64, u64)> {
+        let (first, last) = name.strip_suffix(".run")?.split_once('-')?;
+        Some((first.parse().ok()?, last.parse().ok()?))
+    }
+
+    /// Opens the run in `path`, reading it through once to build its index
+    fn open(path: &Path, first: u64, last: u64) -> Result<Self, KopperError> {
+        let file = Arc::new(File::open(path)?);
+        let size = file.metadata()?.len();
+        let mut run = Run { first, last, file, size, index: Vec::new() };
+
+        let mut records = run.iter(0);
+        let mut next_block = 0;
+        while let Some(offset) = records.position() {
+            let Some(record) = records.next() else { break };
+            let (key, _) = record?;
+            if offset >= next_block {
+                run.index.push((key, offset));
+                next_block = offset + INDEX_INTERVAL;
+            }
+        }
+        Ok(run)
+    }
+
+    /// Writes `records` as the run `first` to `last` of the database in `dir`.
+    /// The run only shows up under its name once it's complete and on disk.
+    fn write(dir: &str, first: u64, last: u64, records: impl Iterator<Item = Result<Versioned, KopperError>>) -> Result<Self, KopperError> {
+        let temporary = format!("{dir}/.{}.tmp", Run::name(first, last));
+        let mut writer = BufWriter::new(File::create(&temporary)?);
+        let mut index = Vec::new();
+        let mut size = 0;
+        let mut next_block = 0;
+
+        for record in records {
+            let (key, value) = record?;
+            if size >= next_block {
+                index.push((key.clone(), size));
+                next_block = size + INDEX_INTERVAL;
+            }
+            size += encode(&mut writer, &key, value.as_deref())? as u64;
+        }
+
+        writer.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+        let path = format!("{dir}/{}", Run::name(first, last));
+        fs::rename(temporary, &path)?;
+        File::open(dir)?.sync_all()?;
+        Ok(Run { first, last, file: Arc::new(File::open(path)?), size, index })
+    }
+
+    /// Newest version of `key` in the run, `None` if the run doesn't have it
+    fn get(&self, key: &str) -> Result<Option<Option<String>>, KopperError> {
+        let block = self.index.partition_point(|(first, _)| first.as_str() <= key);
+        if block == 0 {
+            return Ok(None);
+        }
+
+        let start = self.index[block - 1].1;
+        let end = self.index.get(block).map_or(self.size, |(_, offset)| *offset);
+        let mut buffer = vec![0; (end - start) as usize];
+        self.file.read_exact_at(&mut buffer, start)?;
+
+        let mut fields = buffer.split(|byte| *byte == b'\0');
+        while let (Some(k), Some(value)) = (fields.next(), fields.next()) {
+            if k == key.as_bytes() {
+                return Ok(Some(decode_value(value)?));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Records from the one at `offset` on
+    fn iter(&self, offset: u64) -> RunIter {
+        RunIter { file: self.file.clone(), size: self.size, base: offset, buffer: Vec::new(), parsed: 0 }
+    }
+
+    /// Records with keys from `from` on, skipping the blocks before it
+    fn iter_from(&self, from: &str) -> impl Iterator<Item = Result<Versioned, KopperError>> + Send + use<> {
+        let block = self.index.partition_point(|(first, _)| first.as_str() <= from);
+        let offset = block.checked_sub(1).map_or(0, |block| self.index[block].1);
+        let from = from.to_owned();
+        self.iter(offset).skip_while(move |record| record.as_ref().is_ok_and(|(key, _)| *key < from))
+    }
+}
+
+/// Records of a run, read in chunks of [`READ_CHUNK`] without moving the file's cursor
+struct RunIter {
+    file: Arc<File>,
+    size: u64,
+    /// Offset of `buffer` in the file
+    base: u64,
+    buffer: Vec<u8>,
+    /// Bytes of `buffer` already returned
+    parsed: usize,
+}
+
+impl RunIter {
+    /// Offset of the next record, `None` at the end of the run
+    fn position(&self) -> Option<u64> {
+        let position = self.base + self.parsed as u64;
+        (position < self.size).then_some(position)
+    }
+
+    /// Next `\0`-terminated field, reading more of the file as needed
+    fn field(&mut self, start: usize) -> Result<Option<usize>, KopperError> {
+        loop {
+            if let Some(end) = self.buffer[start..].iter().position(|byte| *byte == b'\0') {
+                return Ok(Some(start + end));
+            }
+
+            let offset = self.base + self.buffer.len() as u64;
+            if offset >= self.size {
+                return Ok(None);
+            }
+            let mut chunk = vec![0; READ_CHUNK.min((self.size - offset) as usize)];
+            self.file.read_exact_at(&mut chunk, offset)?;
+            self.buffer.extend_from_slice(&chunk);
+        }
+    }
+
+    fn read_record(&mut self) -> Result<Option<Versioned>, KopperError> {
+        // Drop what's been returned before reading more
+        if self.parsed > READ_CHUNK {
+            self.buffer.drain(..self.parsed);
+            self.base += self.parsed as u64;
+            self.parsed = 0;
+        }
+
+        let Some(key_end) = self.field(self.parsed)? else {
+            return match self.parsed == self.buffer.len() {
+                true => Ok(None),
+                false => Err(KopperError::InternalError(anyhow::anyhow!("Run ends in the middle of a record"))),
+            };
+        };
+        let value_end = self.field(key_end + 1)?
+            .ok_or_else(|| KopperError::InternalError(anyhow::anyhow!("Run ends in the middle of a record")))?;
+
+        let key = String::from_utf8(self.buffer[self.parsed..key_end].to_vec())?;
+        let value = decode_value(&self.buffer[key_end + 1..value_end])?;
+        self.parsed = value_end + 1;
+        Ok(Some((key, value)))
+    }
+}
+
+impl Iterator for RunIter {
+    type Item = Result<Versioned, KopperError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+/// Appends the record of `key` to `writer`, returning its size
+fn encode(writer: &mut impl Write, key: &str, value: Option<&str>) -> Result<usize, KopperError> {
+    let value = value.map_or(TOMBSTONE, str::as_bytes);
+    writer.write_all(key.as_bytes())?;
+    writer.write_all(b"\0")?;
+    writer.write_all(value)?;
+    writer.write_all(b"\0")?;
+    Ok(key.len() + value.len() + 2)
+}
+
+fn decode_value(value: &[u8]) -> Result<Option<String>, KopperError> {
+    match value {
+        TOMBSTONE => Ok(None),
+        value => Ok(Some(String::from_utf8(value.to_vec())?)),
+    }
+}
+
+/// Records of several sources merged in key order. Sources come newest first,
+/// the first one to have a key wins.
+struct Merge {
+    sources: Vec<Peekable<Records>>,
+}
+
+impl Iterator for Merge {
+    type Item = Result<Versioned, KopperError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Errors come first, then the smallest key - of the newest source on ties
+        let mut next: Option<(usize, Option<&str>)> = None;
+        for (source, records) in self.sources.iter_mut().enumerate() {
+            let key = match records.peek() {
+                None => continue,
+                Some(Err(_)) => None,
+                Some(Ok((key, _))) => Some(key.as_str()),
+            };
+            let better = match (next, key) {
+                (None, _) => true,
+                (Some((_, None)), _) => false,
+                (Some(_), None) => true,
+                (Some((_, Some(best))), Some(key)) => key < best,
+            };
+            if better {
+                next = Some((source, key));
+            }
+        }
+
+        let (source, key) = next?;
+        let key = key.map(str::to_owned);
+        let record = self.sources[source].next()?;
+
+        // Older versions of the key are shadowed by this one
+        if let Some(key) = key {
+            for records in &mut self.sources[source + 1..] {
+                while records.next_if(|record| record.as_ref().is_ok_and(|(k, _)| *k == key)).is_some() {}
+            }
+        }
+        Some(record)
+    }
+}
+
+impl Lsm {
+    /// Opens the database in `path`, creating it if needed, and replays writes
+    /// that weren't flushed to a run
+    pub fn create(path: &str, memtable_size: usize) -> Result<Self, KopperError> {
+        fs::create_dir_all(path)?;
+
+        let mut ranges = Vec::new();
+        for file in fs::read_dir(path)? {
+            let file = file?;
+            let name = file.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') && name.ends_with(".tmp") {
+                // A run that wasn't finished
+                fs::remove_file(file.path())?;
+            } else if let Some(range) = Run::parse_name(&name) {
+                ranges.push(range);
+            }
+        }
+
+        // A crash while merging can leave behind the runs the merged one replaces
+        let mut runs = Vec::new();
+        for &(first, last) in &ranges {
+            let run_path = Path::new(path).join(Run::name(first, last));
+            if ranges.iter().any(|&(f, l)| (f, l) != (first, last) && f <= first && last <= l) {
+                fs::remove_file(run_path)?;
+            } else {
+                runs.push(Run::open(&run_path, first, last)?);
+            }
+        }
+        runs.sort_by_key(|run| run.first);
+
+        let (memtable, memtable_bytes, wal_bytes) = replay(&format!("{path}/{WAL_FILE}"))?;
+        let wal = OpenOptions::new().append(true).create(true).open(format!("{path}/{WAL_FILE}"))?;
+        let next_run = runs.last().map_or(1, |run| run.last + 1);
+
+        let mut state = SharedState { memtable, memtable_bytes, wal, wal_bytes, runs, next_run, keys: 0 };
+        state.keys = merged(&state, "", None).try_fold(0, |keys, record| record.map(|(_, value)| keys + value.is_some() as usize))?;
+
+        Ok(Lsm { state: Arc::new(Mutex::new(state)), path: path.to_owned(), memtable_size })
+    }
+
+    /// Newest version of `key`, `None` if it was never written
+    fn lookup(state: &SharedState, key: &str) -> Result<Option<Option<String>>, KopperError> {
+        if let Some(value) = state.memtable.get(key) {
+            return Ok(Some(value.clone()));
+        }
+        for run in state.runs.iter().rev() {
+            if let Some(value) = run.get(key)? {
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn read(&self, key: &str) -> Result<String, KopperError> {
+        let state = self.state.lock().unwrap();
+        Lsm::lookup(&state, key)?.flatten().ok_or_else(|| KopperError::KeyDoesNotExist(key.to_owned()))
+    }
+
+    /// Returns the size of the record
+    pub fn write(&self, key: &str, value: &str) -> Result<usize, KopperError> {
+        if key.contains('\0') || value.contains('\0') {
+            return Err(KopperError::InternalError(anyhow::anyhow!("Keys and values can't contain NUL")));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if Lsm::lookup(&state, key)?.flatten().is_none() {
+            state.keys += 1;
+        }
+        self.append(&mut state, key, Some(value))
+    }
+
+    pub fn delete(&self, key: &str) -> Result<usize, KopperError> {
+        let mut state = self.state.lock().unwrap();
+        if Lsm::lookup(&state, key)?.flatten().is_none() {
+            return Err(KopperError::KeyDoesNotExist(key.to_owned()));
+        }
+        state.keys -= 1;
+        self.append(&mut state, key, None)
+    }
+
+    /// Logs the new version of `key`, then puts it in the memtable, flushing it if it's full
+    fn append(&self, state: &mut SharedState, key: &str, value: Option<&str>) -> Result<usize, KopperError> {
+        let mut record = Vec::new();
+        let size = encode(&mut record, key, value)?;
+        state.wal.write_all(&record)?;
+        state.wal_bytes += size as u64;
+
+        state.memtable_bytes += key.len() + value.map_or(0, str::len);
+        state.memtable.insert(key.to_owned(), value.map(str::to_owned));
+        if state.memtable_bytes >= self.memtable_size {
+            self.flush(state)?;
+        }
+        Ok(size)
+    }
+
+    /// Writes the memtable out as a new run, merging all runs if there are too many
+    fn flush(&self, state: &mut SharedState) -> Result<(), KopperError> {
+        let id = state.next_run;
+        let records = state.memtable.iter().map(|(key, value)| Ok((key.clone(), value.clone())));
+        let run = Run::write(&self.path, id, id, records)?;
+        state.runs.push(run);
+        state.next_run += 1;
+
+        // Everything in the log is in the run now
+        state.memtable.clear();
+        state.memtable_bytes = 0;
+        state.wal.set_len(0)?;
+        state.wal.sync_all()?;
+        state.wal_bytes = 0;
+
+        if state.runs.len() > MAX_RUNS {
+            self.merge(state)?;
+        }
+        Ok(())
+    }
+
+    /// Merges every run into one. Deleted keys are dropped for good - no older run is left to shadow.
+    fn merge(&self, state: &mut SharedState) -> Result<(), KopperError> {
+        let (Some(first), Some(last)) = (state.runs.first().map(|run| run.first), state.runs.last().map(|run| run.last)) else {
+            return Ok(());
+        };
+
+        let sources = state.runs.iter().rev().map(|run| run_source(run.iter(0))).collect();
+        let records = Merge { sources }.filter(|record| !matches!(record, Ok((_, None))));
+        let merged = Run::write(&self.path, first, last, records)?;
+
+        // Merged run is on disk, the ones it replaces are only removed after. If that's
+        // cut short, they're removed on startup.
+        for run in std::mem::replace(&mut state.runs, vec![merged]) {
+            fs::remove_file(Path::new(&self.path).join(Run::name(run.first, run.last)))?;
+        }
+        File::open(&self.path)?.sync_all()?;
+        Ok(())
+    }
+
+    /// Flushes the memtable to a run and merges all runs into one
+    pub fn compact(&self) -> Result<(), KopperError> {
+        let mut state = self.state.lock().unwrap();
+        if !state.memtable.is_empty() {
+            self.flush(&mut state)?;
+        }
+        self.merge(&mut state)
+    }
+
+    /// Entries with keys from `from` up to, but not including, `to`, in key order,
+    /// as of the call. Only the blocks of runs holding them are read.
+    pub fn range(&self, from: &str, to: Option<&str>) -> Result<LsmScan, KopperError> {
+        let state = self.state.lock().unwrap();
+        Ok(LsmScan { records: Box::new(merged(&state, from, to)) })
+    }
+
+    /// Every entry in key order, as of the call
+    pub fn scan(&self) -> Result<LsmScan, KopperError> {
+        self.range("", None)
+    }
+
+    /// Number of keys currently stored
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().keys
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Size of the runs and the log on disk
+    pub fn size(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        (state.runs.iter().map(|run| run.size).sum::<u64>() + state.wal_bytes) as usize
+    }
+
+    /// Number of runs on disk
+    pub fn runs(&self) -> usize {
+        self.state.lock().unwrap().runs.len()
+    }
+
+    /// Flushes the log to disk
+    pub fn sync(&self) -> Result<(), KopperError> {
+        self.state.lock().unwrap().wal.sync_data()?;
+        Ok(())
+    }
+}
+
+fn run_source(records: impl Iterator<Item = Result<Versioned, KopperError>> + Send + 'static) -> Peekable<Records> {
+    let records: Records = Box::new(records);
+    records.peekable()
+}
+
+/// Newest versions of keys from `from` up to `to`, deleted ones included. The memtable
+/// is copied and runs are immutable, so it isn't affected by later writes.
+fn merged(state: &SharedState, from: &str, to: Option<&str>) -> impl Iterator<Item = Result<Versioned, KopperError>> + Send + use<> {
+    let upper = to.map_or(Bound::Unbounded, |to| Bound::Excluded(to.to_owned()));
+    let memtable: Vec<_> = state.memtable.range((Bound::Included(from.to_owned()), upper))
+        .map(|(key, value)| Ok((key.clone(), value.clone())))
+        .collect();
+
+    let mut sources = vec![run_source(memtable.into_iter())];
+    sources.extend(state.runs.iter().rev().map(|run| run_source(run.iter_from(from))));
+
+    let to = to.map(str::to_owned);
+    Merge { sources }.take_while(move |record| match (record, &to) {
+        (Ok((key, _)), Some(to)) => key < to,
+        _ => true,
+    })
+}
+
+/// Reads the write-ahead log at `path` into a memtable, with its size in bytes of keys
+/// and values and the size of the log. A record cut short by a crash is dropped.
+fn replay(path: &str) -> Result<(Memtable, usize, u64), KopperError> {
+    let mut log = Vec::new();
+    match File::open(path) {
+        Ok(mut file) => { file.read_to_end(&mut log)?; },
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {},
+        Err(err) => return Err(err.into()),
+    }
+
+    let mut memtable = BTreeMap::new();
+    let mut bytes = 0;
+    let mut complete = 0;
+    let mut fields = log.split(|byte| *byte == b'\0');
+    while let (Some(key), Some(value)) = (fields.next(), fields.next()) {
+        let end = complete + key.len() + value.len() + 2;
+        if end > log.len() {
+            break;
+        }
+        let key = String::from_utf8(key.to_vec())?;
+        let value = decode_value(value)?;
+        bytes += key.len() + value.as_ref().map_or(0, String::len);
+        memtable.insert(key, value);
+        complete = end;
+    }
+
+    if complete < log.len() {
+        tracing::warn!("Dropping {} bytes of an incomplete record at the end of {path}", log.len() - complete);
+        OpenOptions::new().write(true).open(path)?.set_len(complete as u64)?;
+    }
+    Ok((memtable, bytes, complete as u64))
+}
+
+/// Entries of the database in key order, created by [`Lsm::range`] and [`Lsm::scan`]
+pub struct LsmScan {
+    records: Records,
+}
+
+impl Iterator for LsmScan {
+    type Item = Result<(String, String), KopperError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.records.next()? {
+                Ok((key, Some(value))) => return Some(Ok((key, value))),
+                Ok((_, None)) => continue,
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+impl KvEngine for Lsm {
+    fn name(&self) -> &'static str {
+        "lsm"
+    }
+
+    fn read(&self, key: &str) -> Result<String, KopperError> {
+        Lsm::read(self, key)
+    }
+
+    fn write(&self, key: &str, value: &str) -> Result<usize, KopperError> {
+        Lsm::write(self, key, value)?;
+        Ok(self.size())
+    }
+
+    fn write_durable(&self, key: &str, value: &str, durability: Durability) -> Result<usize, KopperError> {
+        // Checked up front - a write that can't be as durable as asked isn't made at all
+        if let Durability::Replica(_) = durability {
+            return Err(KopperError::Unsupported("replication"));
+        }
+        Lsm::write(self, key, value)?;
+        self.sync()?;
+        Ok(self.size())
+    }
+
+    fn delete(&self, key: &str) -> Result<usize, KopperError> {
+        Lsm::delete(self, key)?;
+        Ok(self.size())
+    }
+
+    fn scan(&self) -> Result<EngineScan, KopperError> {
+        Ok(Box::new(Lsm::scan(self)?))
+    }
+
+    fn stats(&self) -> Result<EngineStats, KopperError> {
+        Ok(EngineStats { keys: self.len(), size: self.size() })
+    }
+
+    /// The memtable is kept in the log, which is replayed on the next start
+    fn close(&self) -> Result<(), KopperError> {
+        self.sync()
+    }
+}
@@ -0,0 +1,43 @@
+#[macro_use] extern crate rocket;
+
+mod admin;
+mod api;
+mod audit;
+mod binary;
+mod blobs;
+mod bulk;
+mod channels;
+mod counters;
+mod grpc;
+mod hashes;
+mod json;
+mod lists;
+mod locks;
+mod logging;
+mod memcached;
+mod queues;
+mod registry;
+mod reload;
+mod replication;
+mod resp;
+mod sets;
+mod verifier;
+mod version;
+mod ws;
+
+#[rocket::main]
+async fn main() -> Result<(), Box<rocket::Error>> {
+    // Log level is controlled with RUST_LOG, e.g. RUST_LOG=kopperdb=debug, and
+    // KOPPER_LOG_FORMAT=json logs a JSON object per line
+    let logs = tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env());
+    match std::env::var("KOPPER_LOG_FORMAT").as_deref() {
+        Ok("json") => logs.json().with_current_span(true).init(),
+        _ => logs.init(),
+    }
+
+    // Returns after a shutdown signal, once in-flight requests are drained
+    let rocket = api::rocket().launch().await.map_err(Box::new)?;
+    api::close_databases(&rocket);
+    Ok(())
+}
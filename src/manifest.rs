@@ -0,0 +1,156 @@
+//! Manifests of backups made with [`Kopper::backup_incremental`]. Sealed segments
+//! never change, so a backup only has to hold the segments its parent doesn't -
+//! its manifest lists every segment of the database at the time, and says which
+//! of them are in its own directory. The rest are found by walking the parents.
+//!
+//! ```text
+//! full/          .manifest  0_0  1_0  2_0
+//! monday/        .manifest  2_0  3_0          parent: full
+//! tuesday/       .manifest  1_1  3_0  4_0     parent: monday
+//! ```
+//!
+//! [`Kopper::backup_incremental`]: crate::kopper::Kopper::backup_incremental
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::kopper::KopperError;
+
+/// Hidden, so a full backup can still be opened as a database
+pub const MANIFEST_FILE: &str = ".manifest";
+
+/// Version written by [`BackupManifest::save`]. Newer ones are rejected.
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// Parents followed before a chain is considered broken, e.g. by a cycle
+const MAX_CHAIN_LENGTH: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BackupManifest {
+    pub version: u32,
+    /// Change log the backup was taken from, see [`Kopper::log_id`](crate::kopper::Kopper::log_id)
+    pub log: u64,
+    /// Newest change the backup includes
+    pub sequence: u64,
+    /// Unix ms
+    pub created_at: u64,
+    /// Absolute path of the backup this one builds on, `None` for full backups
+    pub parent: Option<String>,
+    /// Every segment of the database at the time of the backup
+    pub segments: Vec<SegmentRecord>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SegmentRecord {
+    pub name: String,
+    pub size: u64,
+    pub crc32: u32,
+    /// Whether the file is in this backup's directory rather than in a parent's
+    pub included: bool,
+}
+
+impl SegmentRecord {
+    /// Size and checksum of the segment file at `path`
+    pub fn describe(name: &str, path: &Path) -> Result<Self, KopperError> {
+        let mut file = File::open(path)?;
+        let mut hasher = crc32fast::Hasher::new();
+        let mut buffer = vec![0; 64 * 1024];
+        let mut size = 0;
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            size += read as u64;
+        }
+        Ok(SegmentRecord { name: name.to_owned(), size, crc32: hasher.finalize(), included: true })
+    }
+
+    /// Same file, as far as name, size and checksum tell
+    pub fn same_contents(&self, other: &SegmentRecord) -> bool {
+        (&self.name, self.size, self.crc32) == (&other.name, other.size, other.crc32)
+    }
+}
+
+fn invalid(message: String) -> KopperError {
+    KopperError::InvalidBackup(message)
+}
+
+impl BackupManifest {
+    pub fn load(dir: &str) -> Result<Self, KopperError> {
+        let manifest = match fs::read(Path::new(dir).join(MANIFEST_FILE)) {
+            Ok(manifest) => manifest,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Err(invalid(format!("{dir} has no manifest"))),
+            Err(err) => return Err(err.into()),
+        };
+
+        let manifest: BackupManifest = serde_json::from_slice(&manifest)
+            .map_err(|err| invalid(format!("Manifest of {dir} can't be read: {err}")))?;
+        if manifest.version > MANIFEST_VERSION {
+            return Err(invalid(format!("Manifest of {dir} has version {}, newer than supported {MANIFEST_VERSION}", manifest.version)));
+        }
+
+        // Names end up as paths - anything but a segment name could point anywhere
+        let mut names = HashSet::new();
+        for segment in &manifest.segments {
+            let is_segment_name = segment.name.split_once('_')
+                .is_some_and(|(base, index)| [base, index].iter().all(|part| !part.is_empty() && part.bytes().all(|byte| byte.is_ascii_digit())));
+            if !is_segment_name {
+                return Err(invalid(format!("Manifest of {dir} lists {:?}, which isn't a segment", segment.name)));
+            }
+            if !names.insert(&segment.name) {
+                return Err(invalid(format!("Manifest of {dir} lists segment {} twice", segment.name)));
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// Written last, so a backup without a manifest is known to be incomplete
+    pub fn save(&self, dir: &str) -> Result<(), KopperError> {
+        let temporary = Path::new(dir).join(format!("{MANIFEST_FILE}.tmp"));
+        let mut contents = serde_json::to_vec_pretty(self).map_err(|err| KopperError::InternalError(err.into()))?;
+        contents.push(b'\n');
+        fs::write(&temporary, contents)?;
+        File::open(&temporary)?.sync_all()?;
+        fs::rename(temporary, Path::new(dir).join(MANIFEST_FILE))?;
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+
+    /// Segments a backup building on the one in `dir` doesn't have to copy again
+    pub fn available(dir: &str) -> Result<Vec<SegmentRecord>, KopperError> {
+        Ok(locate(dir)?.into_iter().map(|(record, _)| record).collect())
+    }
+}
+
+/// Every segment of the backup in `dir`, with the file it's kept in - in `dir` or
+/// one of its parents. Fails if any of them is missing, but doesn't read them.
+pub fn locate(dir: &str) -> Result<Vec<(SegmentRecord, PathBuf)>, KopperError> {
+    let manifest = BackupManifest::load(dir)?;
+
+    // Parents of the backup, starting with itself
+    let mut chain = vec![(PathBuf::from(dir), manifest.clone())];
+    let mut visited = HashSet::new();
+    while let Some(parent) = chain.last().and_then(|(_, manifest)| manifest.parent.clone()) {
+        if !visited.insert(parent.clone()) || chain.len() > MAX_CHAIN_LENGTH {
+            return Err(invalid(format!("Parents of {dir} form a cycle")));
+        }
+        let parent_manifest = BackupManifest::load(&parent)?;
+        chain.push((PathBuf::from(parent), parent_manifest));
+    }
+
+    manifest.segments.iter()
+        .map(|segment| {
+            chain.iter()
+                .find_map(|(dir, manifest)| manifest.segments.iter()
+                    .find(|record| record.included && record.same_contents(segment))
+                    .map(|_| (segment.clone(), dir.join(&segment.name))))
+                .ok_or_else(|| invalid(format!("Segment {} of {dir} isn't in it or any of its parents", segment.name)))
+        })
+        .collect()
+}
@@ -0,0 +1,246 @@
+use rocket::{Rocket, Orbit, Shutdown};
+use rocket::fairing::AdHoc;
+use rocket::tokio::{self, io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader}, net::{TcpListener, TcpStream}};
+
+use kopperdb::kopper::{Kopper, KopperError};
+
+use crate::api;
+
+/// Longest key memcached clients are allowed to send
+const MAX_KEY_LEN: usize = 250;
+
+/// Largest value accepted by `set`, same as memcached's default item size limit
+const MAX_VALUE_LEN: usize = 1024 * 1024;
+
+/// Fairing starting a memcached text protocol listener at `memcached_address` from
+/// Rocket's config, if there's one. Supports `get`, `set`, `add`, `replace`, `delete`,
+/// `incr` and `decr`, so Kopper can sit behind existing memcached clients.
+pub fn listener() -> AdHoc {
+    AdHoc::on_liftoff("memcached listener", |rocket| Box::pin(async move {
+        let Ok(address) = rocket.figment().extract_inner::<String>("memcached_address") else {
+            return;
+        };
+
+        match TcpListener::bind(&address).await {
+            Ok(listener) => {
+                tracing::info!("memcached listener on {address}");
+                tokio::spawn(accept(listener, db(rocket), rocket.shutdown()));
+            },
+            Err(err) => tracing::error!("Can't start memcached listener on {address}: {err}"),
+        }
+    }))
+}
+
+fn db(rocket: &Rocket<Orbit>) -> Kopper {
+    rocket.state::<Kopper>().expect("Kopper is managed").clone()
+}
+
+async fn accept(listener: TcpListener, db: Kopper, mut shutdown: Shutdown) {
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    tracing::error!("memcached accept failed: {err}");
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+
+        let db = db.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            if let Err(err) = serve(stream, db, shutdown).await {
+                tracing::debug!("memcached connection closed: {err}");
+            }
+        });
+    }
+}
+
+async fn serve(stream: TcpStream, db: Kopper, mut shutdown: Shutdown) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    loop {
+        let mut line = String::new();
+        let read = tokio::select! {
+            read = reader.read_line(&mut line) => read?,
+            _ = &mut shutdown => return Ok(()),
+        };
+        if read == 0 {
+            return Ok(()); // Client hung up
+        }
+
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let (reply, noreply) = match args.as_slice() {
+            [] => continue,
+            ["quit"] => return Ok(()),
+            [cmd @ ("set" | "add" | "replace"), rest @ ..] => store(&db, &mut reader, cmd, rest).await?,
+            _ => {
+                let noreply = args.last() == Some(&"noreply");
+                let (db, args) = (db.clone(), args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>());
+                let reply = api::blocking(move || execute(&db, &args.iter().map(String::as_str).collect::<Vec<_>>())).await;
+                (reply, noreply)
+            },
+        };
+
+        if !noreply {
+            writer.write_all(reply.as_bytes()).await?;
+        }
+    }
+}
+
+fn valid_key(key: &str) -> bool {
+    !key.is_empty() && key.len() <= MAX_KEY_LEN && !key.contains(|c: char| c.is_control())
+}
+
+fn client_error(message: &str) -> String {
+    format!("CLIENT_ERROR {message}\r\n")
+}
+
+fn server_error(err: KopperError) -> String {
+    tracing::error!("{err}");
+    "SERVER_ERROR internal error\r\n".to_string()
+}
+
+/// `<cmd> <key> <flags> <exptime> <bytes> [noreply]` followed by the data block.
+/// Flags are accepted but not stored, items never expire.
+async fn store(
+    db: &Kopper,
+    reader: &mut (impl AsyncBufRead + Unpin),
+    cmd: &str,
+    args: &[&str],
+) -> std::io::Result<(String, bool)> {
+    let (key, exptime, len, noreply) = match args {
+        [key, _flags, exptime, len] => (*key, *exptime, *len, false),
+        [key, _flags, exptime, len, "noreply"] => (*key, *exptime, *len, true),
+        _ => return Ok(("ERROR\r\n".to_string(), false)),
+    };
+    let Some(len) = len.parse::<usize>().ok().filter(|len| *len <= MAX_VALUE_LEN) else {
+        // Without a sane length the data block can't be skipped - drop the connection
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Bad data chunk length"));
+    };
+
+    // Data block is followed by CRLF
+    let mut data = vec![0; len + 2];
+    reader.read_exact(&mut data).await?;
+    if !data.ends_with(b"\r\n") {
+        return Ok((client_error("bad data chunk"), noreply));
+    }
+    data.truncate(len);
+
+    if !valid_key(key) {
+        return Ok((client_error("bad key"), noreply));
+    }
+    if exptime != "0" {
+        return Ok((client_error("expiration is not supported"), noreply));
+    }
+    let Ok(value) = String::from_utf8(data) else {
+        return Ok((client_error("values must be valid UTF-8"), noreply));
+    };
+    if value.contains('\0') {
+        return Ok((client_error("values can't contain NUL"), noreply));
+    }
+
+    let (db, key, cmd) = (db.clone(), key.to_owned(), cmd.to_owned());
+    let stored = api::blocking(move || match cmd.as_str() {
+        "add" => db.write_if_absent(&key, &value),
+        "replace" => db.write_if_present(&key, &value),
+        _ => db.write(&key, &value).map(|_| true),
+    }).await;
+    let reply = match stored {
+        Ok(true) => "STORED\r\n".to_string(),
+        Ok(false) => "NOT_STORED\r\n".to_string(),
+        Err(err) => server_error(err),
+    };
+    Ok((reply, noreply))
+}
+
+fn execute(db: &Kopper, args: &[&str]) -> String {
+    let args = match args.split_last() {
+        Some((&"noreply", args)) => args,
+        _ => args,
+    };
+
+    match args {
+        ["version"] => format!("VERSION kopperdb {}\r\n", env!("CARGO_PKG_VERSION")),
+        ["get" | "gets", keys @ ..] if !keys.is_empty() => {
+            let mut reply = String::new();
+            for key in keys {
+                match db.read(key) {
+                    Ok(value) => reply += &format!("VALUE {key} 0 {}\r\n{value}\r\n", value.len()),
+                    Err(KopperError::KeyDoesNotExist(_)) => {},
+                    Err(err) => return server_error(err),
+                }
+            }
+            reply + "END\r\n"
+        },
+        ["delete", key] => match db.delete(key) {
+            Ok(_) => "DELETED\r\n".to_string(),
+            Err(KopperError::KeyDoesNotExist(_)) => "NOT_FOUND\r\n".to_string(),
+            Err(err) => server_error(err),
+        },
+        [cmd @ ("incr" | "decr"), key, delta] => {
+            let Ok(delta) = delta.parse::<u64>() else {
+                return client_error("invalid numeric delta argument");
+            };
+
+            // Written only if nobody changed the value since it was read - over
+            // any protocol - or else read again
+            loop {
+                let current = match db.read(key) {
+                    Ok(current) => current,
+                    Err(KopperError::KeyDoesNotExist(_)) => return "NOT_FOUND\r\n".to_string(),
+                    Err(err) => return server_error(err),
+                };
+                let Ok(value) = current.trim().parse::<u64>() else {
+                    return client_error("cannot increment or decrement non-numeric value");
+                };
+
+                // Same as memcached: incr wraps around at 64 bits, decr stops at 0
+                let value = if *cmd == "incr" { value.wrapping_add(delta) } else { value.saturating_sub(delta) };
+                match db.compare_and_swap(key, Some(&current), &value.to_string()) {
+                    Ok(true) => return format!("{value}\r\n"),
+                    Ok(false) => continue,
+                    Err(err) => return server_error(err),
+                }
+            }
+        },
+        _ => "ERROR\r\n".to_string(),
+    }
+}
+
+/// TESTS
+
+#[test]
+fn test_increments_are_not_lost_to_concurrent_writes() {
+    let db = kopperdb::testing::TempDb::new();
+    let kopper = db.kopper(4096).unwrap();
+    kopper.write("hits", "0").unwrap();
+
+    // Half of them over memcached, half like any other client changing the value
+    let threads: Vec<_> = (0..4).map(|thread| {
+        let kopper = kopper.clone();
+        std::thread::spawn(move || for _ in 0..50 {
+            if thread % 2 == 0 {
+                assert!(!execute(&kopper, &["incr", "hits", "1"]).contains("ERROR"));
+                continue;
+            }
+            loop {
+                let current = kopper.read("hits").unwrap();
+                let next = (current.parse::<u64>().unwrap() + 1).to_string();
+                if kopper.compare_and_swap("hits", Some(&current), &next).unwrap() {
+                    break;
+                }
+            }
+        })
+    }).collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(kopper.read("hits").unwrap(), "200");
+    assert_eq!(execute(&kopper, &["decr", "hits", "500"]), "0\r\n");
+    assert_eq!(execute(&kopper, &["incr", "missing", "1"]), "NOT_FOUND\r\n");
+    kopper.close().unwrap();
+}
@@ -0,0 +1,25 @@
+//! JSON merge patches, RFC 7386. A patch object sets the members it names, `null`
+//! ones removing them, merging objects member by member. Anything else replaces
+//! the document as a whole.
+
+use serde_json::{Map, Value};
+
+/// Applies `patch` to `target` in place
+pub fn apply(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let Value::Object(members) = target else { unreachable!() };
+    for (name, value) in patch {
+        match value {
+            Value::Null => {
+                members.remove(name);
+            },
+            value => apply(members.entry(name.clone()).or_insert(Value::Null), value),
+        }
+    }
+}
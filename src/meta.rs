@@ -0,0 +1,97 @@
+//! Metadata of keys, see [`Kopper::write_with_meta`](crate::kopper::Kopper::write_with_meta).
+//! Records of values written with some start with a header - a flag byte, the
+//! flags and how long the content type is, in hex, and the content type -
+//! followed by the value as it's stored otherwise. Values that expire have it
+//! behind their expiry header, and blobs of deduplicated ones hold it, so keys
+//! with other metadata don't share a blob. Like the other flags, it never starts
+//! valid UTF-8.
+
+use std::borrow::Cow;
+
+use crate::kopper::KopperError;
+
+const META: u8 = 0xF6;
+
+/// Flag, flags and length of the content type, in hex so a record never holds a NUL
+pub const MIN_HEADER_LEN: usize = 1 + 2 + 2;
+
+/// Longest content type a key can have
+pub const MAX_CONTENT_TYPE_LEN: usize = u8::MAX as usize;
+
+/// Set along with a value, and replaced by the next write of the key
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Meta {
+    /// Media type of the value, e.g. `image/svg+xml`, served with it over HTTP
+    pub content_type: Option<String>,
+    /// Up to the user, e.g. a tier or whether the key is pinned
+    pub flags: u8,
+}
+
+impl Meta {
+    pub fn is_empty(&self) -> bool {
+        self.content_type.is_none() && self.flags == 0
+    }
+
+    /// Fails for content types that are too long or aren't printable ASCII
+    pub fn check(&self) -> Result<(), KopperError> {
+        match &self.content_type {
+            Some(content_type) if content_type.len() > MAX_CONTENT_TYPE_LEN =>
+                Err(KopperError::Rejected(format!("Content type longer than {MAX_CONTENT_TYPE_LEN} bytes"))),
+            Some(content_type) if !content_type.bytes().all(|byte| byte.is_ascii_graphic() || byte == b' ') =>
+                Err(KopperError::Rejected(format!("Content type {content_type:?} isn't printable ASCII"))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// `stored` behind the header of `meta`, as it is if there's no metadata
+pub fn wrap<'a>(meta: &Meta, stored: &'a [u8]) -> Cow<'a, [u8]> {
+    if meta.is_empty() {
+        return Cow::Borrowed(stored);
+    }
+    let content_type = meta.content_type.as_deref().unwrap_or_default();
+    let mut record = Vec::with_capacity(MIN_HEADER_LEN + content_type.len() + stored.len());
+    record.push(META);
+    record.extend_from_slice(format!("{:02x}{:02x}", meta.flags, content_type.len()).as_bytes());
+    record.extend_from_slice(content_type.as_bytes());
+    record.extend_from_slice(stored);
+    Cow::Owned(record)
+}
+
+/// Metadata at the start of `stored` and how long its header is, `None` if there's none
+pub fn parse(stored: &[u8]) -> Option<(Meta, usize)> {
+    if stored.first() != Some(&META) {
+        return None;
+    }
+    let field = |at: usize| std::str::from_utf8(stored.get(at..at + 2)?).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok());
+    let (flags, len) = (field(1)?, field(3)? as usize);
+    let content_type = std::str::from_utf8(stored.get(MIN_HEADER_LEN..MIN_HEADER_LEN + len)?).ok()?;
+    let meta = Meta { content_type: (!content_type.is_empty()).then(|| content_type.to_owned()), flags };
+    Some((meta, MIN_HEADER_LEN + len))
+}
+
+/// Value stored as `stored` without the header, and the metadata it held
+pub fn strip(mut stored: Vec<u8>) -> (Vec<u8>, Meta) {
+    match parse(&stored) {
+        Some((meta, header_len)) => {
+            stored.drain(..header_len);
+            (stored, meta)
+        },
+        None => (stored, Meta::default()),
+    }
+}
+
+/// TESTS
+
+#[test]
+fn test_meta_parses_back() {
+    let meta = Meta { content_type: Some("image/svg+xml".to_owned()), flags: 0x81 };
+    let record = wrap(&meta, b"<svg/>");
+    assert!(!record.contains(&0));
+    assert_eq!(strip(record.into_owned()), (b"<svg/>".to_vec(), meta));
+
+    let flags_only = Meta { content_type: None, flags: 3 };
+    assert_eq!(strip(wrap(&flags_only, b"value").into_owned()), (b"value".to_vec(), flags_only));
+    assert_eq!(wrap(&Meta::default(), b"value"), &b"value"[..]);
+    assert_eq!(strip(b"\xF6value".to_vec()), (b"\xF6value".to_vec(), Meta::default()));
+}
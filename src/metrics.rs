@@ -0,0 +1,154 @@
+use std::{net::UdpSocket, sync::Arc, io};
+
+use crate::kopper::CompactionReport;
+
+/// Destination of everything the server and the engine measure. Implement it
+/// to route metrics into an existing monitoring stack.
+///
+/// Called on hot paths, so implementations should hand the work off quickly.
+pub trait MetricsSink: Send + Sync {
+    fn record(&self, stat: Stat);
+}
+
+/// Something measured, by the engine or by the server around it
+#[derive(Clone, Debug)]
+pub enum Stat {
+    ReadTime(u128),
+    WriteTime(u128),
+    Size(u128),
+    Request(Label, u128),
+    /// Operation finished, `true` if it failed
+    Completed(Operation, bool),
+    Compaction(CompactionReport),
+    /// Number of keys stored in the database
+    Keys(u128),
+    /// Write mirrored by [`Shadowed`](crate::engine::Shadowed), with how long it took each engine
+    ShadowWrite { primary: u128, shadow: u128 },
+    /// Value written compressed, with its size before and after
+    Compression { plain: u128, stored: u128 },
+    /// Keys verified, with how many were corrupt or differed on the replica,
+    /// see [`Kopper::verify`](crate::kopper::Kopper::verify)
+    Verification { checked: u128, corrupt: u128, mismatched: u128 },
+    /// Sampled [`Saturation`](crate::saturation::Saturation), with the lock waits
+    /// since the previous sample. Waited time is in nanoseconds.
+    Saturation { open_segments: u128, compactor_queue: u128, compactor_alive: bool, lock_waits: u128, lock_wait: u128 },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Operation {
+    Read,
+    Write,
+}
+
+/// Request latencies are kept in a separate series per route and response status,
+/// so slow endpoints don't hide the latency of fast ones
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Label {
+    /// Name of the handler, `unmatched` if no route matched
+    pub route: String,
+    pub status: u16,
+}
+
+/// Drops everything
+pub struct NoopSink;
+
+impl MetricsSink for NoopSink {
+    fn record(&self, _: Stat) {}
+}
+
+/// Sends every stat to all of the sinks
+pub struct Fanout(pub Vec<Arc<dyn MetricsSink>>);
+
+impl MetricsSink for Fanout {
+    fn record(&self, stat: Stat) {
+        for sink in &self.0 {
+            sink.record(stat.clone());
+        }
+    }
+}
+
+/// Exports stats to a statsd daemon over UDP, all names starting with `prefix`.
+/// Delivery is best effort - packets that can't be sent are dropped.
+pub struct StatsdSink {
+    socket: UdpSocket,
+    prefix: String,
+}
+
+impl StatsdSink {
+    pub fn new(address: &str, prefix: &str) -> io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(address)?;
+        socket.set_nonblocking(true)?;
+        Ok(StatsdSink { socket, prefix: prefix.to_owned() })
+    }
+}
+
+impl MetricsSink for StatsdSink {
+    fn record(&self, stat: Stat) {
+        for line in statsd_lines(&self.prefix, &stat) {
+            let _ = self.socket.send(line.as_bytes());
+        }
+    }
+}
+
+/// Nanoseconds as fractional milliseconds - the unit of statsd timers
+fn millis(nanos: u128) -> String {
+    format!("{:.3}", nanos as f64 / 1_000_000.0)
+}
+
+fn statsd_lines(prefix: &str, stat: &Stat) -> Vec<String> {
+    match stat {
+        Stat::ReadTime(time) => vec![format!("{prefix}.read.latency:{}|ms", millis(*time))],
+        Stat::WriteTime(time) => vec![format!("{prefix}.write.latency:{}|ms", millis(*time))],
+        Stat::Size(size) => vec![format!("{prefix}.size:{size}|g")],
+        Stat::Request(label, time) => vec![format!("{prefix}.request.{}.{}.latency:{}|ms", label.route, label.status, millis(*time))],
+        Stat::Completed(op, failed) => {
+            let op = match op {
+                Operation::Read => "read",
+                Operation::Write => "write",
+            };
+            let mut lines = vec![format!("{prefix}.{op}.ops:1|c")];
+            if *failed {
+                lines.push(format!("{prefix}.{op}.errors:1|c"));
+            }
+            lines
+        },
+        Stat::Compaction(report) => vec![
+            format!("{prefix}.compaction.reclaimed:{}|c", report.reclaimed_bytes),
+            format!("{prefix}.compaction.duration:{}|ms", millis(report.duration.as_nanos())),
+            format!("{prefix}.segments:{}|g", report.segments),
+        ],
+        Stat::Keys(keys) => vec![format!("{prefix}.keys:{keys}|g")],
+        Stat::ShadowWrite { primary, shadow } => vec![
+            format!("{prefix}.shadow.primary.latency:{}|ms", millis(*primary)),
+            format!("{prefix}.shadow.secondary.latency:{}|ms", millis(*shadow)),
+        ],
+        Stat::Compression { plain, stored } => vec![
+            format!("{prefix}.compression.plain:{plain}|c"),
+            format!("{prefix}.compression.stored:{stored}|c"),
+        ],
+        Stat::Verification { checked, corrupt, mismatched } => vec![
+            format!("{prefix}.verify.checked:{checked}|c"),
+            format!("{prefix}.verify.corrupt:{corrupt}|c"),
+            format!("{prefix}.verify.mismatched:{mismatched}|c"),
+        ],
+        Stat::Saturation { open_segments, compactor_queue, compactor_alive, lock_waits, lock_wait } => vec![
+            format!("{prefix}.segments.open:{open_segments}|g"),
+            format!("{prefix}.compactor.queue:{compactor_queue}|g"),
+            format!("{prefix}.compactor.alive:{}|g", *compactor_alive as u8),
+            format!("{prefix}.lock.waits:{lock_waits}|c"),
+            format!("{prefix}.lock.wait:{}|c", millis(*lock_wait)),
+        ],
+    }
+}
+
+/// TESTS
+
+#[test]
+fn test_statsd_lines() {
+    assert_eq!(statsd_lines("kopper", &Stat::ReadTime(1_500_000)), vec!["kopper.read.latency:1.500|ms"]);
+    assert_eq!(statsd_lines("kopper", &Stat::Completed(Operation::Write, true)), vec!["kopper.write.ops:1|c", "kopper.write.errors:1|c"]);
+    assert_eq!(statsd_lines("kopper", &Stat::Compression { plain: 100, stored: 20 }), vec!["kopper.compression.plain:100|c", "kopper.compression.stored:20|c"]);
+    assert_eq!(statsd_lines("kopper", &Stat::Saturation { open_segments: 3, compactor_queue: 1, compactor_alive: true, lock_waits: 2, lock_wait: 250_000 }),
+        vec!["kopper.segments.open:3|g", "kopper.compactor.queue:1|g", "kopper.compactor.alive:1|g", "kopper.lock.waits:2|c", "kopper.lock.wait:0.250|c"]);
+}
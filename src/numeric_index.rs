@@ -0,0 +1,104 @@
+//! Secondary indexes of numbers found in values, see
+//! [`Kopper::create_numeric_index`](crate::kopper::Kopper::create_numeric_index).
+//! An index only lives in memory - it's built from the values when it's created,
+//! and kept up to date with every change after that.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+
+/// Finds the number a key is indexed by in its value, `None` to leave it out
+pub type Extractor = Arc<dyn Fn(&str, &str) -> Option<f64> + Send + Sync>;
+
+/// Ordered the way [`f64::total_cmp`] orders numbers. NaNs are never indexed.
+#[derive(Clone, Copy, Debug)]
+struct Number(f64);
+
+impl PartialEq for Number {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Number {}
+
+impl PartialOrd for Number {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Number {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+pub struct NumericIndex {
+    extract: Extractor,
+    keys: BTreeSet<(Number, String)>,
+    numbers: HashMap<String, Number>,
+}
+
+impl NumericIndex {
+    pub fn new(extract: Extractor) -> Self {
+        NumericIndex { extract, keys: BTreeSet::new(), numbers: HashMap::new() }
+    }
+
+    /// Indexes `key` by the number in its `value`, or leaves it out if it holds
+    /// none or was deleted
+    pub fn update(&mut self, key: &str, value: Option<&str>) {
+        if let Some(number) = self.numbers.remove(key) {
+            self.keys.remove(&(number, key.to_owned()));
+        }
+        let Some(number) = value.and_then(|value| (self.extract)(key, value)).filter(|number| !number.is_nan()) else {
+            return;
+        };
+        self.numbers.insert(key.to_owned(), Number(number));
+        self.keys.insert((Number(number), key.to_owned()));
+    }
+
+    pub fn clear(&mut self) {
+        self.keys.clear();
+        self.numbers.clear();
+    }
+
+    /// Keys indexed by numbers in `range`, in order of the numbers and then of keys
+    pub fn range(&self, range: impl RangeBounds<f64>) -> impl Iterator<Item = &str> {
+        let (start, end) = (range.start_bound().cloned(), range.end_bound().cloned());
+        let from = match start {
+            Bound::Included(start) | Bound::Excluded(start) => Bound::Included((Number(start), String::new())),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        self.keys.range((from, Bound::Unbounded))
+            .skip_while(move |(number, _)| matches!(start, Bound::Excluded(start) if *number == Number(start)))
+            .take_while(move |(number, _)| match end {
+                Bound::Included(end) => *number <= Number(end),
+                Bound::Excluded(end) => *number < Number(end),
+                Bound::Unbounded => true,
+            })
+            .map(|(_, key)| key.as_str())
+    }
+}
+
+/// TESTS
+
+#[test]
+fn test_keys_are_found_by_range_of_numbers() {
+    let mut index = NumericIndex::new(Arc::new(|_, value: &str| value.parse().ok()));
+    index.update("a", Some("10"));
+    index.update("b", Some("50"));
+    index.update("c", Some("100"));
+    index.update("d", Some("not a number"));
+    index.update("e", Some("50"));
+
+    assert_eq!(index.range(10.0..100.0).collect::<Vec<_>>(), ["a", "b", "e"]);
+    assert_eq!(index.range((Bound::Excluded(10.0), Bound::Included(100.0))).collect::<Vec<_>>(), ["b", "e", "c"]);
+    assert_eq!(index.range(..).count(), 4);
+
+    index.update("b", Some("-1"));
+    index.update("e", None);
+    assert_eq!(index.range(..50.0).collect::<Vec<_>>(), ["b", "a"]);
+    assert_eq!(index.range(50.0..).collect::<Vec<_>>(), ["c"]);
+}
@@ -0,0 +1,206 @@
+//! Cold segments, packed by the compactor. A packed segment is its records cut
+//! into blocks, each compressed on its own, behind an index of where the blocks
+//! are - reading a value only decompresses the block it's in. Packed segments
+//! are sealed, so they're written in one go and never appended to.
+//!
+//! Layout: `magic | codec | block size: u32 | length: u64 | blocks: u32`, then
+//! `offset: u64 | length: u32` for every block, then the blocks. The magic starts
+//! with a byte no UTF-8 key can, so plain segments are never mistaken for one.
+
+use std::sync::{Arc, Mutex};
+
+use crate::compression::{self, Codec};
+use crate::kopper::KopperError;
+use crate::store::SegmentFile;
+
+const MAGIC: &[u8; 8] = b"\xFFKPACK1\0";
+const HEADER_LEN: usize = MAGIC.len() + 1 + 4 + 8 + 4;
+const INDEX_ENTRY_LEN: usize = 8 + 4;
+
+/// Plain bytes per block
+const BLOCK_SIZE: usize = 16 * 1024;
+
+/// Which segments the compactor packs, see
+/// [`Kopper::set_packing`](crate::kopper::Kopper::set_packing)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Packing {
+    pub codec: Codec,
+    /// Segments this many segments behind the active one, or more, are cold
+    pub cold_after: u32,
+}
+
+/// Default [`Packing::cold_after`]
+pub const DEFAULT_COLD_AFTER: u32 = 4;
+
+impl Packing {
+    pub fn new(codec: Codec) -> Self {
+        Packing { codec, cold_after: DEFAULT_COLD_AFTER }
+    }
+}
+
+fn codec_byte(codec: Codec) -> u8 {
+    match codec {
+        Codec::Lz4 => 1,
+        Codec::Zstd => 2,
+    }
+}
+
+/// Contents of a packed segment holding `data`, `None` if it can't be packed -
+/// built without codecs, or a block failed to compress
+pub fn pack(codec: Codec, data: &[u8]) -> Option<Vec<u8>> {
+    let blocks: Vec<Vec<u8>> = data.chunks(BLOCK_SIZE)
+        .map(|block| compression::compress(codec, block))
+        .collect::<Option<_>>()?;
+
+    let mut packed = Vec::with_capacity(HEADER_LEN + blocks.len() * INDEX_ENTRY_LEN + blocks.iter().map(Vec::len).sum::<usize>());
+    packed.extend_from_slice(MAGIC);
+    packed.push(codec_byte(codec));
+    packed.extend_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+    packed.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    packed.extend_from_slice(&(blocks.len() as u32).to_le_bytes());
+
+    let mut offset = (HEADER_LEN + blocks.len() * INDEX_ENTRY_LEN) as u64;
+    for block in &blocks {
+        packed.extend_from_slice(&offset.to_le_bytes());
+        packed.extend_from_slice(&(block.len() as u32).to_le_bytes());
+        offset += block.len() as u64;
+    }
+    for block in &blocks {
+        packed.extend_from_slice(block);
+    }
+    Some(packed)
+}
+
+/// Reads `file` through its block index if it's a packed segment, as it is otherwise
+pub fn open(file: Arc<dyn SegmentFile>) -> Result<Arc<dyn SegmentFile>, KopperError> {
+    if file.len()? < HEADER_LEN as u64 {
+        return Ok(file);
+    }
+    let mut header = [0; HEADER_LEN];
+    file.read_at(&mut header, 0)?;
+    if &header[..MAGIC.len()] != MAGIC {
+        return Ok(file);
+    }
+
+    let damaged = || KopperError::InternalError(anyhow::anyhow!("Packed segment is damaged"));
+    let codec = match header[MAGIC.len()] {
+        1 => Codec::Lz4,
+        2 => Codec::Zstd,
+        _ => return Err(damaged()),
+    };
+    let field = |at: usize, len: usize| {
+        let mut bytes = [0; 8];
+        bytes[..len].copy_from_slice(&header[at..at + len]);
+        u64::from_le_bytes(bytes)
+    };
+    let block_size = field(MAGIC.len() + 1, 4) as usize;
+    let len = field(MAGIC.len() + 5, 8);
+    let count = field(MAGIC.len() + 13, 4) as usize;
+    if block_size == 0 || len.div_ceil(block_size as u64) != count as u64 {
+        return Err(damaged());
+    }
+
+    let mut index = vec![0; count * INDEX_ENTRY_LEN];
+    file.read_at(&mut index, HEADER_LEN as u64)?;
+    let blocks = index.chunks(INDEX_ENTRY_LEN)
+        .map(|entry| (u64::from_le_bytes(entry[..8].try_into().unwrap()), u32::from_le_bytes(entry[8..].try_into().unwrap())))
+        .collect();
+
+    Ok(Arc::new(PackedSegment { inner: file, codec, block_size, len, blocks, cached: Mutex::new(None) }))
+}
+
+struct PackedSegment {
+    inner: Arc<dyn SegmentFile>,
+    codec: Codec,
+    block_size: usize,
+    /// Of the plain segment
+    len: u64,
+    /// Offset and length of every compressed block
+    blocks: Vec<(u64, u32)>,
+    /// Last block decompressed, by its number
+    cached: Mutex<Option<(usize, Vec<u8>)>>,
+}
+
+impl SegmentFile for PackedSegment {
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> Result<(), KopperError> {
+        if offset + buffer.len() as u64 > self.len {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+
+        let mut cached = self.cached.lock().unwrap();
+        let mut filled = 0;
+        while filled < buffer.len() {
+            let at = offset as usize + filled;
+            let (number, from) = (at / self.block_size, at % self.block_size);
+            if cached.as_ref().is_none_or(|(cached, _)| *cached != number) {
+                let (block_offset, block_len) = self.blocks[number];
+                let mut compressed = vec![0; block_len as usize];
+                self.inner.read_at(&mut compressed, block_offset)?;
+                *cached = Some((number, compression::decompress(self.codec, &compressed)?));
+            }
+
+            let block = &cached.as_ref().unwrap().1;
+            let part = block.len().saturating_sub(from).min(buffer.len() - filled);
+            if part == 0 {
+                return Err(KopperError::InternalError(anyhow::anyhow!("Packed segment is damaged")));
+            }
+            buffer[filled..filled + part].copy_from_slice(&block[from..from + part]);
+            filled += part;
+        }
+        Ok(())
+    }
+
+    fn append(&self, _: &[u8]) -> Result<(), KopperError> {
+        Err(KopperError::Unsupported("appending to a packed segment"))
+    }
+
+    fn len(&self) -> Result<u64, KopperError> {
+        Ok(self.len)
+    }
+
+    fn stored_len(&self) -> Result<u64, KopperError> {
+        self.inner.stored_len()
+    }
+
+    fn truncate(&self, _: u64) -> Result<(), KopperError> {
+        Err(KopperError::Unsupported("truncating a packed segment"))
+    }
+
+    fn sync(&self) -> Result<(), KopperError> {
+        self.inner.sync()
+    }
+}
+
+/// TESTS
+
+#[test]
+fn test_plain_segments_are_read_as_they_are() {
+    use crate::store::{MemoryStore, SegmentStore};
+
+    let store = MemoryStore::new();
+    let plain = store.create("1_0").unwrap();
+    plain.append(b"key\0value\0").unwrap();
+    assert_eq!(open(plain).unwrap().len().unwrap(), 10);
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn test_packed_segment_reads_like_the_plain_one() {
+    use crate::store::{MemoryStore, SegmentStore};
+
+    let data: Vec<u8> = (0..3 * BLOCK_SIZE + 100).map(|i| b"key\0value\0"[i % 10]).collect();
+    let store = MemoryStore::new();
+    let file = store.create("0_1").unwrap();
+    file.append(&pack(Codec::Lz4, &data).unwrap()).unwrap();
+
+    let packed = open(file).unwrap();
+    assert_eq!(packed.len().unwrap(), data.len() as u64);
+    assert!(packed.stored_len().unwrap() < data.len() as u64 / 4);
+    for (offset, len) in [(0, 10), (BLOCK_SIZE - 3, 10), (BLOCK_SIZE, 2 * BLOCK_SIZE + 100), (data.len() - 1, 1)] {
+        let mut buffer = vec![0; len];
+        packed.read_at(&mut buffer, offset as u64).unwrap();
+        assert_eq!(buffer, &data[offset..offset + len]);
+    }
+    assert!(packed.read_at(&mut [0; 2], data.len() as u64 - 1).is_err());
+    assert!(packed.append(b"more").is_err());
+}
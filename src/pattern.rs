@@ -0,0 +1,50 @@
+//! Patterns keys are searched by, see [`Kopper::scan_match`](crate::kopper::Kopper::scan_match).
+
+/// Matches keys with a glob or a regular expression
+#[derive(Clone, Debug)]
+pub enum KeyPattern {
+    /// Redis-style glob - `*` matches any run of bytes, `?` any one byte
+    Glob(String),
+    /// Matches keys it finds a match in, anchor it with `^` and `$` to match whole keys
+    Regex(regex::Regex),
+}
+
+impl KeyPattern {
+    pub fn regex(pattern: &str) -> Result<Self, regex::Error> {
+        Ok(KeyPattern::Regex(regex::Regex::new(pattern)?))
+    }
+
+    pub fn matches(&self, key: &str) -> bool {
+        match self {
+            KeyPattern::Glob(glob) => glob_match(glob.as_bytes(), key.as_bytes()),
+            KeyPattern::Regex(regex) => regex.is_match(key),
+        }
+    }
+}
+
+/// Whether the [`KeyPattern::Glob`] `pattern` matches `text`
+pub fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match (pattern.split_first(), text.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) => glob_match(rest, text) || (!text.is_empty() && glob_match(pattern, &text[1..])),
+        (Some((b'?', rest)), Some((_, text_rest))) => glob_match(rest, text_rest),
+        (Some((p, rest)), Some((t, text_rest))) if p == t => glob_match(rest, text_rest),
+        _ => false,
+    }
+}
+
+/// TESTS
+
+#[test]
+fn test_globs_and_regexes_match_keys() {
+    let glob = KeyPattern::Glob("user:*:settings".to_owned());
+    assert!(glob.matches("user:1:settings"));
+    assert!(glob.matches("user::settings"));
+    assert!(!glob.matches("user:1:settings:old"));
+    assert!(KeyPattern::Glob("a?c".to_owned()).matches("abc"));
+
+    let regex = KeyPattern::regex(r"^user:\d+$").unwrap();
+    assert!(regex.matches("user:12"));
+    assert!(!regex.matches("user:ab"));
+    assert!(KeyPattern::regex("(").is_err());
+}
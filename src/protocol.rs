@@ -0,0 +1,252 @@
+//! Kopper's binary wire protocol - the cheapest way to talk to the server.
+//!
+//! Every message, in both directions, is a single frame:
+//!
+//! ```text
+//! +------+-------------+---------------+-----+-------+
+//! | code | key length  | value length  | key | value |
+//! | u8   | u32, BE     | u32, BE       |     |       |
+//! +------+-------------+---------------+-----+-------+
+//! ```
+//!
+//! Requests carry an [`Opcode`] as their code, responses a [`Reply`] code with
+//! an empty key. Responses come back in request order, so clients may pipeline.
+//!
+//! ```
+//! use kopperdb::protocol::{Request, Response};
+//!
+//! let mut wire = Vec::new();
+//! Request::Put { key: "a".into(), value: "b".into() }.write_to(&mut wire).unwrap();
+//!
+//! let request = Request::read_from(&mut wire.as_slice()).unwrap();
+//! assert_eq!(request, Request::Put { key: "a".into(), value: "b".into() });
+//! ```
+
+use std::io::{self, Read, Write};
+
+/// Size of the fixed part of every frame
+pub const HEADER_LEN: usize = 9;
+
+/// Largest key plus value a frame may carry, bigger frames are rejected
+/// before anything is allocated for them
+pub const MAX_BODY_LEN: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum Opcode {
+    Get = 1,
+    Put = 2,
+    Delete = 3,
+    Ping = 4,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum Reply {
+    /// Value holds the read value for gets, nothing otherwise
+    Ok = 0,
+    NotFound = 1,
+    /// Value holds a description of what was wrong with the request
+    Invalid = 2,
+    /// Value holds a description of the server's problem
+    Error = 3,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProtocolError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("Unknown code {0}")]
+    UnknownCode(u8),
+
+    #[error("Frame body of {0} bytes is too large")]
+    TooLarge(usize),
+
+    #[error("Keys and values must be valid UTF-8")]
+    NotUtf8,
+}
+
+/// Fixed size start of a frame, telling how much more to read
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Header {
+    pub code: u8,
+    pub key_len: usize,
+    pub value_len: usize,
+}
+
+impl Header {
+    pub fn parse(bytes: &[u8; HEADER_LEN]) -> Result<Self, ProtocolError> {
+        let key_len = u32::from_be_bytes(bytes[1..5].try_into().unwrap()) as usize;
+        let value_len = u32::from_be_bytes(bytes[5..9].try_into().unwrap()) as usize;
+
+        let header = Header { code: bytes[0], key_len, value_len };
+        match header.body_len() {
+            len if len > MAX_BODY_LEN => Err(ProtocolError::TooLarge(len)),
+            _ => Ok(header),
+        }
+    }
+
+    /// Bytes following the header
+    pub fn body_len(&self) -> usize {
+        self.key_len + self.value_len
+    }
+}
+
+/// A whole frame, before its code is interpreted
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub code: u8,
+    pub key: String,
+    pub value: String,
+}
+
+impl Frame {
+    /// Builds a frame from a parsed header and the `body` read after it
+    pub fn from_parts(header: Header, mut body: Vec<u8>) -> Result<Self, ProtocolError> {
+        let value = body.split_off(header.key_len);
+        Ok(Frame {
+            code: header.code,
+            key: String::from_utf8(body).map_err(|_| ProtocolError::NotUtf8)?,
+            value: String::from_utf8(value).map_err(|_| ProtocolError::NotUtf8)?,
+        })
+    }
+
+    pub fn read_from(reader: &mut impl Read) -> Result<Self, ProtocolError> {
+        let mut header = [0; HEADER_LEN];
+        reader.read_exact(&mut header)?;
+        let header = Header::parse(&header)?;
+
+        let mut body = vec![0; header.body_len()];
+        reader.read_exact(&mut body)?;
+        Frame::from_parts(header, body)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.key.len() + self.value.len());
+        bytes.push(self.code);
+        bytes.extend_from_slice(&(self.key.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&(self.value.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(self.key.as_bytes());
+        bytes.extend_from_slice(self.value.as_bytes());
+        bytes
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    Get { key: String },
+    Put { key: String, value: String },
+    Delete { key: String },
+    Ping,
+}
+
+impl Request {
+    pub fn read_from(reader: &mut impl Read) -> Result<Self, ProtocolError> {
+        Request::try_from(Frame::read_from(reader)?)
+    }
+
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(&Frame::from(self.clone()).encode())
+    }
+}
+
+impl TryFrom<Frame> for Request {
+    type Error = ProtocolError;
+
+    fn try_from(frame: Frame) -> Result<Self, Self::Error> {
+        let Frame { code, key, value } = frame;
+        match code {
+            code if code == Opcode::Get as u8 => Ok(Request::Get { key }),
+            code if code == Opcode::Put as u8 => Ok(Request::Put { key, value }),
+            code if code == Opcode::Delete as u8 => Ok(Request::Delete { key }),
+            code if code == Opcode::Ping as u8 => Ok(Request::Ping),
+            code => Err(ProtocolError::UnknownCode(code)),
+        }
+    }
+}
+
+impl From<Request> for Frame {
+    fn from(request: Request) -> Self {
+        let (opcode, key, value) = match request {
+            Request::Get { key } => (Opcode::Get, key, String::new()),
+            Request::Put { key, value } => (Opcode::Put, key, value),
+            Request::Delete { key } => (Opcode::Delete, key, String::new()),
+            Request::Ping => (Opcode::Ping, String::new(), String::new()),
+        };
+        Frame { code: opcode as u8, key, value }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub reply: Reply,
+    pub value: String,
+}
+
+impl Response {
+    pub fn ok(value: String) -> Self {
+        Response { reply: Reply::Ok, value }
+    }
+
+    pub fn read_from(reader: &mut impl Read) -> Result<Self, ProtocolError> {
+        Response::try_from(Frame::read_from(reader)?)
+    }
+
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(&Frame::from(self.clone()).encode())
+    }
+}
+
+impl TryFrom<Frame> for Response {
+    type Error = ProtocolError;
+
+    fn try_from(frame: Frame) -> Result<Self, Self::Error> {
+        let reply = [Reply::Ok, Reply::NotFound, Reply::Invalid, Reply::Error]
+            .into_iter()
+            .find(|reply| *reply as u8 == frame.code)
+            .ok_or(ProtocolError::UnknownCode(frame.code))?;
+        Ok(Response { reply, value: frame.value })
+    }
+}
+
+impl From<Response> for Frame {
+    fn from(response: Response) -> Self {
+        Frame { code: response.reply as u8, key: String::new(), value: response.value }
+    }
+}
+
+/// TESTS
+
+#[test]
+fn test_frames_roundtrip() {
+    let requests = [
+        Request::Get { key: "key".into() },
+        Request::Put { key: "key".into(), value: "zażółć".into() },
+        Request::Delete { key: "key".into() },
+        Request::Ping,
+    ];
+
+    let mut wire = Vec::new();
+    for request in &requests {
+        request.write_to(&mut wire).unwrap();
+    }
+    Response { reply: Reply::NotFound, value: String::new() }.write_to(&mut wire).unwrap();
+
+    let mut reader = wire.as_slice();
+    for request in requests {
+        assert_eq!(Request::read_from(&mut reader).unwrap(), request);
+    }
+    assert_eq!(Response::read_from(&mut reader).unwrap().reply, Reply::NotFound);
+    assert!(reader.is_empty());
+}
+
+#[test]
+fn test_bad_frames() {
+    let unknown = Frame { code: 42, key: String::new(), value: String::new() };
+    assert!(matches!(Request::try_from(unknown), Err(ProtocolError::UnknownCode(42))));
+
+    let mut huge = [Opcode::Put as u8, 0, 0, 0, 1, 0, 0, 0, 0];
+    huge[5..9].copy_from_slice(&u32::MAX.to_be_bytes());
+    assert!(matches!(Header::parse(&huge), Err(ProtocolError::TooLarge(_))));
+}
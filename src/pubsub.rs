@@ -0,0 +1,81 @@
+//! Channels messages are published to, see [`Kopper::publish`](crate::kopper::Kopper::publish).
+//! Unlike changes of keys, messages aren't written anywhere - they go to whoever
+//! is subscribed to the channel at the time, and nobody gets them otherwise.
+//! Channels have their own lock, so publishing doesn't wait for writes.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver};
+
+/// Hands a message over, `false` once the subscriber is gone
+type Deliver = Box<dyn FnMut(String) -> bool + Send>;
+
+#[derive(Default)]
+pub struct Channels {
+    subscribers: Mutex<Subscribers>,
+}
+
+#[derive(Default)]
+struct Subscribers {
+    by_channel: HashMap<String, Vec<(u64, Deliver)>>,
+    /// Given to the next subscriber
+    next_id: u64,
+    closed: bool,
+}
+
+impl Channels {
+    /// Messages published to `channel` from now on, until the receiver is dropped
+    /// or the channels are closed
+    pub fn subscribe(&self, channel: &str) -> Receiver<String> {
+        let (sender, receiver) = mpsc::channel();
+        // Dropping the sender right away disconnects the receiver
+        self.subscribe_into(channel, Box::new(move |message| sender.send(message).is_ok()));
+        receiver
+    }
+
+    /// Hands messages published to `channel` from now on to `deliver`, until it
+    /// returns `false`, [`Channels::unsubscribe`] is called with the ID returned
+    /// or the channels are closed. `deliver` is called with the channels locked.
+    pub fn subscribe_into(&self, channel: &str, deliver: Deliver) -> u64 {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let id = subscribers.next_id;
+        subscribers.next_id += 1;
+        if !subscribers.closed {
+            subscribers.by_channel.entry(channel.to_owned()).or_default().push((id, deliver));
+        }
+        id
+    }
+
+    /// Ends the subscription to `channel` [`Channels::subscribe_into`] returned `id` for
+    pub fn unsubscribe(&self, channel: &str, id: u64) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if let Some(delivers) = subscribers.by_channel.get_mut(channel) {
+            delivers.retain(|(subscriber, _)| *subscriber != id);
+            if delivers.is_empty() {
+                subscribers.by_channel.remove(channel);
+            }
+        }
+    }
+
+    /// Sends `message` to the subscribers of `channel`, forgetting the ones that
+    /// hung up. Returns how many got it.
+    pub fn publish(&self, channel: &str, message: &str) -> usize {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let Some(delivers) = subscribers.by_channel.get_mut(channel) else {
+            return 0;
+        };
+        delivers.retain_mut(|(_, deliver)| deliver(message.to_owned()));
+        let sent = delivers.len();
+        if sent == 0 {
+            subscribers.by_channel.remove(channel);
+        }
+        sent
+    }
+
+    /// Disconnects every subscriber, now and later
+    pub fn close(&self) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.closed = true;
+        subscribers.by_channel.clear();
+    }
+}
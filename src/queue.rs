@@ -0,0 +1,48 @@
+//! Queues, see [`Kopper::push`](crate::kopper::Kopper::push). Every item of a
+//! queue is a record of its own, under the [reserved](crate::keyspace)
+//! [`item_key`] of its offset. Next to them, under [`offsets_key`], a JSON object
+//! holds how many items were pushed and popped so far and the offset every
+//! consumer read up to. An item's offset counts from the first item ever pushed,
+//! so it stays the same as the ones before it are popped.
+//!
+//! A push appends the item and the offsets, a pop the offsets and a tombstone of
+//! the item - each in one append, and neither rewrites the other items. Popped
+//! items are dropped by compaction like any other deleted value.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::keyspace;
+use crate::kopper::KopperError;
+
+/// Key of the offsets of `queue`
+pub fn offsets_key(queue: &str) -> String {
+    keyspace::reserved_key("offsets", queue)
+}
+
+/// Key of the item of `queue` at `offset`
+pub fn item_key(queue: &str, offset: u64) -> String {
+    // The offset is as wide for every item, so no two queues share a key
+    keyspace::reserved_key("item", &format!("{queue}:{offset:020}"))
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Offsets {
+    /// Items pushed so far, the offset of the next one
+    pub pushed: u64,
+    /// Items popped so far, the offset of the one at the front
+    pub popped: u64,
+    /// Offset of the next item every consumer reads, by name
+    pub consumers: BTreeMap<String, u64>,
+}
+
+/// Offsets stored as `value` under `key`
+pub fn decode(key: &str, value: &str) -> Result<Offsets, KopperError> {
+    serde_json::from_str(value).map_err(|_| KopperError::WrongType(key.to_owned()))
+}
+
+pub fn encode(offsets: &Offsets) -> String {
+    // Serializing strings and numbers can't fail
+    serde_json::to_string(offsets).unwrap()
+}
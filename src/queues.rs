@@ -0,0 +1,191 @@
+//! Routes for queues, see [`Kopper::push`]. Items are either popped, each by
+//! one of the clients waiting for it, or read by consumers at their own offsets,
+//! which they commit once they're done with the items.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use rocket::State;
+use rocket::serde::json::Json;
+use rocket::tokio::sync::Notify;
+use rocket::tokio::time;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use kopperdb::kopper::{Kopper, KopperError};
+
+use crate::api;
+
+/// Longest a pop waits for an item, whatever it asks for
+const MAX_POP_TIMEOUT_MS: u64 = 60_000;
+
+/// Items read at once unless asked for another number
+const DEFAULT_CONSUME_MAX: usize = 100;
+
+#[derive(Serialize, ToSchema)]
+pub struct QueuedResponse {
+    /// Offset of the item pushed, 0 on failure
+    offset: u64,
+    /// "OK" on success, description of the problem otherwise
+    error: String
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct QueuePopResponse {
+    /// Item taken off the queue, empty if none came in time
+    item: String,
+    /// "OK" on success, description of the problem otherwise
+    error: String
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct QueuedItem {
+    offset: u64,
+    item: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ConsumeResponse {
+    /// Oldest first, commit the offset after the last one handled
+    items: Vec<QueuedItem>,
+    /// "OK" on success, description of the problem otherwise
+    error: String
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct OffsetResponse {
+    /// Of the next item the consumer reads
+    offset: u64,
+    /// "OK" on success, description of the problem otherwise
+    error: String
+}
+
+#[utoipa::path(
+    post,
+    path = "/queue/{queue}/push/{item}",
+    tag = "queues",
+    params(
+        ("queue" = String, Path, description = "Key of the queue, created if there's none"),
+        ("item" = String, Path, description = "Item to append")
+    ),
+    responses((status = 200, description = "Result of the push", body = QueuedResponse))
+)]
+#[post("/queue/<queue>/push/<item>")]
+pub fn push(queue: &str, item: &str, db: &State<Kopper>) -> Json<QueuedResponse> {
+    Json(match db.push(queue, item) {
+        Ok(offset) => QueuedResponse { offset, error: "OK".to_string() },
+        Err(err) => QueuedResponse { offset: 0, error: format!("Error while pushing! : {err}") },
+    })
+}
+
+#[utoipa::path(
+    post,
+    path = "/queue/{queue}/pop",
+    tag = "queues",
+    params(
+        ("queue" = String, Path, description = "Key of the queue"),
+        ("timeout_ms" = Option<u64>, Query, description = "How long to wait for an item if the queue is empty, up to a minute. Doesn't wait by default")
+    ),
+    responses((status = 200, description = "Item taken off the queue", body = QueuePopResponse))
+)]
+#[post("/queue/<queue>/pop?<timeout_ms>")]
+pub async fn pop(queue: &str, timeout_ms: Option<u64>, db: &State<Kopper>) -> Json<QueuePopResponse> {
+    let timeout = Duration::from_millis(timeout_ms.unwrap_or(0).min(MAX_POP_TIMEOUT_MS));
+    Json(match wait_for_item(db.inner().clone(), queue.to_owned(), timeout).await {
+        Ok(Some(item)) => QueuePopResponse { item, error: "OK".to_string() },
+        Ok(None) => QueuePopResponse { item: String::new(), error: format!("{queue} is empty!") },
+        Err(err) => QueuePopResponse { item: String::new(), error: format!("Error while popping! : {err}") },
+    })
+}
+
+/// Like [`Kopper::pop`], waiting for a push without taking up a thread
+async fn wait_for_item(db: Kopper, queue: String, timeout: Duration) -> Result<Option<String>, KopperError> {
+    // Watching first, so a push right after looking isn't missed - one while
+    // nothing waits yet leaves a permit for the next wait
+    let pushed = Arc::new(Notify::new());
+    let _subscription = db.watch_pushes(&queue, {
+        let pushed = pushed.clone();
+        move |_| {
+            pushed.notify_one();
+            true
+        }
+    })?;
+    let deadline = time::Instant::now() + timeout;
+    loop {
+        let (db, queue) = (db.clone(), queue.clone());
+        if let Some(item) = api::blocking(move || db.try_pop(&queue)).await? {
+            return Ok(Some(item));
+        }
+        if time::timeout_at(deadline, pushed.notified()).await.is_err() {
+            return Ok(None);
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/queue/{queue}/consume/{consumer}",
+    tag = "queues",
+    params(
+        ("queue" = String, Path, description = "Key of the queue"),
+        ("consumer" = String, Path, description = "Name of the consumer, reading from the offset it committed"),
+        ("max" = Option<usize>, Query, description = "Most items to return, 100 by default")
+    ),
+    responses((status = 200, description = "Items from the consumer's offset on, left in the queue", body = ConsumeResponse))
+)]
+#[get("/queue/<queue>/consume/<consumer>?<max>")]
+pub fn consume(queue: &str, consumer: &str, max: Option<usize>, db: &State<Kopper>) -> Json<ConsumeResponse> {
+    Json(match db.consume(queue, consumer, max.unwrap_or(DEFAULT_CONSUME_MAX)) {
+        Ok(items) => ConsumeResponse {
+            items: items.into_iter().map(|(offset, item)| QueuedItem { offset, item }).collect(),
+            error: "OK".to_string(),
+        },
+        Err(err) => ConsumeResponse { items: Vec::new(), error: format!("Error while reading! : {err}") },
+    })
+}
+
+#[utoipa::path(
+    post,
+    path = "/queue/{queue}/commit/{consumer}/{offset}",
+    tag = "queues",
+    params(
+        ("queue" = String, Path, description = "Key of the queue"),
+        ("consumer" = String, Path, description = "Name of the consumer"),
+        ("offset" = u64, Path, description = "Offset to read from next, past the items handled")
+    ),
+    responses((status = 200, description = "Offset the consumer reads from", body = OffsetResponse))
+)]
+#[post("/queue/<queue>/commit/<consumer>/<offset>")]
+pub fn commit(queue: &str, consumer: &str, offset: u64, db: &State<Kopper>) -> Json<OffsetResponse> {
+    Json(match db.commit_offset(queue, consumer, offset) {
+        Ok(()) => OffsetResponse { offset, error: "OK".to_string() },
+        Err(err) => OffsetResponse { offset: 0, error: format!("Error while writing! : {err}") },
+    })
+}
+
+/// TESTS
+
+#[rocket::async_test]
+async fn test_pops_wait_for_pushes() {
+    let db = kopperdb::testing::TempDb::new();
+    let kopper = db.kopper(4096).unwrap();
+
+    kopper.push("jobs", "a").unwrap();
+    assert_eq!(wait_for_item(kopper.clone(), "jobs".to_owned(), Duration::ZERO).await.unwrap().as_deref(), Some("a"));
+    assert_eq!(wait_for_item(kopper.clone(), "jobs".to_owned(), Duration::from_millis(10)).await.unwrap(), None);
+
+    // Many wait on one worker, each taking an item once it's pushed
+    let waiting: Vec<_> = (0..3).map(|_| rocket::tokio::spawn(wait_for_item(kopper.clone(), "jobs".to_owned(), Duration::from_secs(10)))).collect();
+    time::sleep(Duration::from_millis(50)).await;
+    for item in ["b", "c", "d"] {
+        kopper.push("other", item).unwrap();
+        kopper.push("jobs", item).unwrap();
+    }
+    let mut popped = Vec::new();
+    for waiting in waiting {
+        popped.push(waiting.await.unwrap().unwrap().unwrap());
+    }
+    popped.sort();
+    assert_eq!(popped, ["b", "c", "d"]);
+    assert_eq!(kopper.try_pop("other").unwrap().as_deref(), Some("b"));
+}
@@ -0,0 +1,292 @@
+use std::{collections::{HashMap, BTreeMap}, sync::{Arc, Mutex, RwLock}, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use kopperdb::encryption::EncryptionKey;
+use kopperdb::engine::Durability;
+use kopperdb::kopper::{Backpressure, Kopper, KopperError, Quota, RecoveryProgress, DEFAULT_WRITE_STALL};
+use kopperdb::schema::Schemas;
+
+use crate::api::{create_kopper, enforce_schemas, log_recovery, DataDirs};
+
+fn default_segment_size() -> usize {
+    4096
+}
+
+/// Configuration of a single named database, read from the `databases` table
+/// of Rocket's config:
+///
+/// ```toml
+/// [default.databases.users]
+/// path = "users_database"
+/// segment_size = 8192
+/// # Optional, encrypts the database at rest
+/// key_file = "users.key"
+/// # Optional, keeps the segments private to the owner
+/// file_mode = 0o600
+/// # Optional, writes past these are refused
+/// max_bytes = 1048576
+/// max_keys = 10000
+/// # Optional, every write waits for an fsync
+/// sync = "flush"
+/// # Optional, in place of the top-level ones
+/// write_stall_soft = 0.5
+/// write_stall_hard = 0.8
+/// write_stall_delay_ms = 10
+/// ```
+#[derive(Deserialize, Clone, PartialEq)]
+pub struct DatabaseConfig {
+    pub path: String,
+
+    #[serde(default = "default_segment_size")]
+    pub segment_size: usize,
+
+    /// Holds the key the database is encrypted with, see [`EncryptionKey::from_file`]
+    #[serde(default)]
+    pub key_file: Option<String>,
+
+    /// Of the segment files, see [`LocalStore::with_mode`](kopperdb::store::LocalStore::with_mode)
+    #[serde(default)]
+    pub file_mode: Option<u32>,
+
+    /// See [`Quota`]
+    #[serde(default)]
+    pub max_bytes: Option<usize>,
+
+    #[serde(default)]
+    pub max_keys: Option<usize>,
+
+    #[serde(default)]
+    pub sync: SyncPolicy,
+
+    /// Hold writes back while compaction falls behind, see [`Backpressure`].
+    /// Without a hard limit of its own, the database goes by the top-level one.
+    #[serde(default)]
+    pub write_stall_soft: Option<f64>,
+
+    #[serde(default)]
+    pub write_stall_hard: Option<f64>,
+
+    #[serde(default)]
+    pub write_stall_delay_ms: Option<u64>,
+
+    /// Files of JSON schemas every value written has to follow, by key prefix -
+    /// `""` for all keys. See [`Schemas`].
+    #[serde(default)]
+    pub schemas: BTreeMap<String, String>,
+}
+
+/// When writes to a named database are acknowledged
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncPolicy {
+    /// Once in the OS page cache - segments are synced as they fill up, or on close
+    #[default]
+    Lazy,
+    /// Once fsynced, like `?wait=flush` for the main database
+    Flush,
+}
+
+impl DatabaseConfig {
+    /// This one with the settings of `config` that can change while the
+    /// database is open - where it is and how it's stored can't
+    fn reloaded(&self, config: &DatabaseConfig) -> DatabaseConfig {
+        DatabaseConfig {
+            max_bytes: config.max_bytes,
+            max_keys: config.max_keys,
+            sync: config.sync,
+            write_stall_soft: config.write_stall_soft,
+            write_stall_hard: config.write_stall_hard,
+            write_stall_delay_ms: config.write_stall_delay_ms,
+            ..self.clone()
+        }
+    }
+
+    /// Thresholds of its own, or else `defaults`
+    fn backpressure(&self, defaults: Option<Backpressure>) -> Option<Backpressure> {
+        let Some(hard) = self.write_stall_hard else {
+            return defaults;
+        };
+        Some(Backpressure {
+            soft: self.write_stall_soft.unwrap_or(hard),
+            hard,
+            delay: self.write_stall_delay_ms.map_or(DEFAULT_WRITE_STALL, Duration::from_millis),
+        })
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct DatabaseUsage {
+    keys: usize,
+    /// Of the keys and their values as stored
+    bytes: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_keys: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_bytes: Option<usize>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct DatabaseInfo {
+    name: String,
+    path: String,
+    /// Databases are opened on first use
+    open: bool,
+    /// Only known once the database is open
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<DatabaseUsage>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RecoveringDatabase {
+    name: String,
+    /// Segments found to recover
+    segments: usize,
+    /// Of those, recovered so far
+    recovered: usize,
+    bytes_scanned: u64,
+    /// Keys indexed so far
+    entries: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Readiness {
+    /// False while any database is being recovered
+    pub ready: bool,
+    recovering: Vec<RecoveringDatabase>,
+}
+
+/// Set of named [`Kopper`] databases served by one process.
+/// Each database is only opened (and recovered) on its first request.
+pub struct Registry {
+    /// Changed by [`Registry::reload`]
+    configs: RwLock<BTreeMap<String, DatabaseConfig>>,
+    /// Of databases without thresholds of their own
+    backpressure: RwLock<Option<Backpressure>>,
+    open: Mutex<HashMap<String, Kopper>>,
+    /// Progress of the databases being opened, kept apart from `open` - that
+    /// one is held for as long as recovery takes
+    recovering: Mutex<BTreeMap<String, RecoveryProgress>>,
+}
+
+impl Registry {
+    pub fn new(configs: BTreeMap<String, DatabaseConfig>, backpressure: Option<Backpressure>) -> Self {
+        Registry { configs: RwLock::new(configs), backpressure: RwLock::new(backpressure), open: Mutex::default(), recovering: Mutex::default() }
+    }
+
+    /// What writes to the database with given name wait for, `None` if only
+    /// for landing in the page cache or there's no such database
+    pub fn durability(&self, name: &str) -> Option<Durability> {
+        let configs = self.configs.read().unwrap();
+        let config = configs.get(name)?;
+        (config.sync == SyncPolicy::Flush).then_some(Durability::Flush)
+    }
+
+    /// Returns the database with given name, opening it if needed.
+    /// `None` if there is no such database in the configuration.
+    pub fn get(&self, name: &str) -> Option<Result<Kopper, KopperError>> {
+        let config = self.configs.read().unwrap().get(name)?.clone();
+
+        // Holding the lock while recovering makes sure the database is opened only once
+        let mut open = self.open.lock().unwrap();
+        if let Some(kopper) = open.get(name) {
+            return Some(Ok(kopper.clone()));
+        }
+
+        let schemas = match Schemas::load(&config.schemas) {
+            Ok(schemas) => Arc::new(schemas),
+            Err(err) => return Some(Err(err)),
+        };
+        let mut log = log_recovery(name);
+        let progress = |progress: RecoveryProgress| {
+            log(progress);
+            self.recovering.lock().unwrap().insert(name.to_owned(), progress);
+        };
+        let opened = match &config.key_file {
+            Some(key_file) => EncryptionKey::from_file(key_file)
+                .and_then(|key| create_kopper(&config.path, config.segment_size, Some(&key), config.file_mode, &DataDirs::default(), None, progress)),
+            None => create_kopper(&config.path, config.segment_size, None, config.file_mode, &DataDirs::default(), None, progress),
+        };
+        self.recovering.lock().unwrap().remove(name);
+        Some(opened.inspect(|kopper| {
+            kopper.set_quota(Quota { max_bytes: config.max_bytes, max_keys: config.max_keys });
+            kopper.set_backpressure(config.backpressure(*self.backpressure.read().unwrap()));
+            enforce_schemas(kopper, schemas);
+            open.insert(name.to_owned(), kopper.clone());
+        }))
+    }
+
+    /// Whether every database opened so far is serving, the ones being recovered if
+    /// not - including `kopper`, the one served outside the registry, under "kopper"
+    pub fn readiness(&self, kopper: &Kopper) -> Readiness {
+        let main = kopper.recovery_progress().map(|progress| ("kopper".to_owned(), progress));
+        let named = self.recovering.lock().unwrap().clone();
+        let recovering: Vec<_> = main.into_iter().chain(named)
+            .map(|(name, progress)| RecoveringDatabase {
+                name: name.clone(),
+                segments: progress.segments,
+                recovered: progress.recovered,
+                bytes_scanned: progress.bytes_scanned,
+                entries: progress.entries,
+            })
+            .collect();
+        Readiness { ready: recovering.is_empty(), recovering }
+    }
+
+    pub fn list(&self) -> Vec<DatabaseInfo> {
+        let open = self.open.lock().unwrap();
+        self.configs.read().unwrap().iter()
+            .map(|(name, config)| DatabaseInfo {
+                name: name.clone(),
+                path: config.path.clone(),
+                open: open.contains_key(name),
+                usage: open.get(name).map(|kopper| {
+                    let usage = kopper.usage();
+                    DatabaseUsage { keys: usage.keys, bytes: usage.bytes, max_keys: config.max_keys, max_bytes: config.max_bytes }
+                }),
+            })
+            .collect()
+    }
+
+    /// Takes the quotas, sync policies and write stalls of the databases from
+    /// `configs`, and `backpressure` for the ones without thresholds of their
+    /// own, applying them to the open ones. Databases new in `configs` are
+    /// opened on first use, ones no longer there are still served. Returns the
+    /// names of the databases whose settings changed.
+    pub fn reload(&self, configs: BTreeMap<String, DatabaseConfig>, backpressure: Option<Backpressure>) -> Vec<String> {
+        let open = self.open.lock().unwrap();
+        let mut current = self.configs.write().unwrap();
+        let stalls_changed = *self.backpressure.read().unwrap() != backpressure;
+        *self.backpressure.write().unwrap() = backpressure;
+
+        let mut changed = Vec::new();
+        for (name, config) in configs {
+            let reloaded = match current.get(&name) {
+                Some(old) => old.reloaded(&config),
+                None => config,
+            };
+            let same = current.get(&name) == Some(&reloaded);
+            if same && !(stalls_changed && reloaded.write_stall_hard.is_none()) {
+                continue;
+            }
+            if let Some(kopper) = open.get(&name) {
+                kopper.set_quota(Quota { max_bytes: reloaded.max_bytes, max_keys: reloaded.max_keys });
+                kopper.set_backpressure(reloaded.backpressure(backpressure));
+            }
+            current.insert(name.clone(), reloaded);
+            changed.push(name);
+        }
+        changed
+    }
+
+    /// Closes every database that has been opened so far.
+    pub fn close_all(&self) {
+        for (name, kopper) in self.open.lock().unwrap().iter() {
+            match kopper.close() {
+                Ok(()) => tracing::info!("Database {name} closed"),
+                Err(err) => tracing::error!("Failed to close database {name}: {err}"),
+            }
+        }
+    }
+}
@@ -0,0 +1,261 @@
+//! Settings changed without restarting. `POST /admin/reload` and SIGHUP read
+//! the config again and apply the [`Tunables`] that differ from the ones in
+//! effect, along with the quotas, sync policies and write stalls of named
+//! databases, see [`Registry::reload`]. Everything else - where databases are,
+//! how they're encrypted, which protocols are served - still takes a restart,
+//! and a reload changing any of [`RESTART_ONLY`] is refused.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rocket::State;
+use rocket::fairing::AdHoc;
+use rocket::figment::Figment;
+use rocket::figment::value::Value;
+use rocket::serde::json::Json;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use kopperdb::compression::Compression;
+use kopperdb::kopper::{Backpressure, Eviction, Kopper, DEFAULT_COLD_TIER_AFTER};
+use kopperdb::packed::Packing;
+
+use crate::api;
+use crate::registry::Registry;
+
+/// Settings read only at startup
+pub const RESTART_ONLY: &[&str] = &[
+    "engine", "shadow", "follow", "replicate_from", "lazy_recovery", "key_file", "file_mode",
+    "cold_dir", "data_dirs", "data_dir_placement", "schemas", "enforce_schemas", "audit_log", "backup_dir",
+    "resp_address", "memcached_address", "grpc_address", "binary_address", "binary_socket",
+];
+
+/// Settings of the main database that can change while it's served
+#[derive(Clone, Default, PartialEq)]
+pub struct Tunables {
+    default_ttl: Option<Duration>,
+    undelete_window: Option<Duration>,
+    eviction: Option<Eviction>,
+    backpressure: Option<Backpressure>,
+    compression: Option<Compression>,
+    packing: Option<Packing>,
+    dedupe: Option<usize>,
+    cold_tier_after: Option<u32>,
+    /// See [`Kopper::pin`]
+    pinned: BTreeSet<String>,
+    /// See [`Kopper::keep_versions`]
+    versions: BTreeMap<String, usize>,
+}
+
+impl Tunables {
+    /// Tunables in `figment`. Followers don't write, so they don't expire,
+    /// evict, stall or keep deleted anything, and only servers with a `cold_dir` move segments to it.
+    pub fn from_config(figment: &Figment, follow: bool, cold_tier: bool) -> Self {
+        let writes = !follow;
+        Tunables {
+            default_ttl: writes.then(|| figment.extract_inner("default_ttl_ms").ok().map(Duration::from_millis)).flatten(),
+            undelete_window: writes.then(|| figment.extract_inner("undelete_window_ms").ok().map(Duration::from_millis)).flatten(),
+            eviction: writes.then(|| api::eviction(figment)).flatten(),
+            backpressure: writes.then(|| api::backpressure(figment)).flatten(),
+            compression: api::compression(figment),
+            packing: api::packing(figment),
+            dedupe: api::dedupe(figment),
+            cold_tier_after: cold_tier.then(|| figment.extract_inner("cold_tier_after").unwrap_or(DEFAULT_COLD_TIER_AFTER)),
+            pinned: figment.extract_inner("pinned").unwrap_or_default(),
+            versions: figment.extract_inner("versions").unwrap_or_default(),
+        }
+    }
+
+    /// Sets the ones different from `applied` on `kopper`, returning their names.
+    /// Eviction starts over with every key counted as equally old, so it's only
+    /// set when it changes.
+    pub fn apply(&self, kopper: &Kopper, applied: &Tunables) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.default_ttl != applied.default_ttl {
+            kopper.set_default_ttl(self.default_ttl);
+            changed.push("default_ttl");
+        }
+        if self.undelete_window != applied.undelete_window {
+            kopper.set_undelete_window(self.undelete_window);
+            changed.push("undelete_window");
+        }
+        if self.eviction != applied.eviction {
+            kopper.set_eviction(self.eviction);
+            changed.push("eviction");
+        }
+        if self.backpressure != applied.backpressure {
+            kopper.set_backpressure(self.backpressure);
+            changed.push("write_stall");
+        }
+        if self.compression != applied.compression {
+            kopper.set_compression(self.compression);
+            changed.push("compression");
+        }
+        if self.packing != applied.packing {
+            kopper.set_packing(self.packing);
+            changed.push("pack_segments");
+        }
+        if self.dedupe != applied.dedupe {
+            kopper.set_dedupe(self.dedupe);
+            changed.push("dedupe");
+        }
+        if self.cold_tier_after != applied.cold_tier_after {
+            kopper.set_cold_tier_after(self.cold_tier_after);
+            changed.push("cold_tier_after");
+        }
+        if self.pinned != applied.pinned {
+            for key in applied.pinned.difference(&self.pinned) {
+                kopper.unpin(key);
+            }
+            for key in self.pinned.difference(&applied.pinned) {
+                kopper.pin(key);
+            }
+            changed.push("pinned");
+        }
+        if self.versions != applied.versions {
+            for key in applied.versions.keys().filter(|key| !self.versions.contains_key(*key)) {
+                kopper.keep_versions(key, 0);
+            }
+            for (key, versions) in &self.versions {
+                kopper.keep_versions(key, *versions);
+            }
+            changed.push("versions");
+        }
+        changed
+    }
+}
+
+/// Reloads the config into the databases served, see [`Reloader::reload`]
+pub struct Reloader {
+    kopper: Kopper,
+    registry: Arc<Registry>,
+    follow: bool,
+    cold_tier: bool,
+    applied: Mutex<Tunables>,
+    /// [`RESTART_ONLY`] settings as they were at startup
+    started: Vec<Option<Value>>,
+}
+
+impl Reloader {
+    /// For `kopper` with `applied` in effect, and the named databases of `registry`,
+    /// started with `figment`
+    pub fn new(kopper: Kopper, registry: Arc<Registry>, follow: bool, cold_tier: bool, applied: Tunables, figment: &Figment) -> Self {
+        let started = restart_only(figment);
+        Reloader { kopper, registry, follow, cold_tier, applied: Mutex::new(applied), started }
+    }
+
+    /// Reads the config again the way Rocket does at startup, see [`Reloader::reload_from`]
+    pub fn reload(&self) -> Result<Vec<String>, String> {
+        self.reload_from(&rocket::Config::figment())
+    }
+
+    /// Applies what changed in `figment`. Returns the names of the settings
+    /// changed, named databases as `databases.<name>` - or, applying nothing,
+    /// the [`RESTART_ONLY`] ones that changed. Panics on invalid settings, like startup does.
+    pub fn reload_from(&self, figment: &Figment) -> Result<Vec<String>, String> {
+        let restart: Vec<&str> = RESTART_ONLY.iter().zip(restart_only(figment).iter().zip(&self.started))
+            .filter(|(_, (now, started))| now != started)
+            .map(|(name, _)| *name)
+            .collect();
+        if !restart.is_empty() {
+            return Err(format!("Only changed by a restart: {}", restart.join(", ")));
+        }
+        let tunables = Tunables::from_config(figment, self.follow, self.cold_tier);
+        let databases = figment.extract_inner("databases").unwrap_or_default();
+
+        let mut applied = self.applied.lock().unwrap();
+        let mut changed: Vec<String> = tunables.apply(&self.kopper, &applied).into_iter().map(str::to_owned).collect();
+        *applied = tunables;
+        changed.extend(self.registry.reload(databases, api::backpressure(figment)).into_iter().map(|name| format!("databases.{name}")));
+        tracing::info!(?changed, "Config reloaded");
+        Ok(changed)
+    }
+}
+
+/// Values of the [`RESTART_ONLY`] settings in `figment`, in that order
+fn restart_only(figment: &Figment) -> Vec<Option<Value>> {
+    RESTART_ONLY.iter().map(|name| figment.find_value(name).ok()).collect()
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ReloadResponse {
+    /// Settings that changed, named databases as `databases.<name>`
+    changed: Vec<String>,
+    /// "OK" on success, description of the problem otherwise
+    error: String
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/reload",
+    tag = "admin",
+    responses((status = 200, description = "Result of the reload", body = ReloadResponse))
+)]
+#[post("/admin/reload")]
+pub async fn reload(reloader: &State<Arc<Reloader>>) -> Json<ReloadResponse> {
+    let reloader = reloader.inner().clone();
+    // Invalid settings panic, which leaves everything as it was
+    Json(match rocket::tokio::task::spawn_blocking(move || reloader.reload()).await {
+        Ok(Ok(changed)) => ReloadResponse { changed, error: "OK".to_string() },
+        Ok(Err(err)) => ReloadResponse { changed: Vec::new(), error: format!("{err}, nothing reloaded") },
+        Err(_) => ReloadResponse { changed: Vec::new(), error: "Invalid config, nothing reloaded".to_string() },
+    })
+}
+
+/// Reloads the config on every SIGHUP
+pub fn on_sighup() -> AdHoc {
+    AdHoc::on_liftoff("reload on SIGHUP", |rocket| Box::pin(async move {
+        use rocket::tokio::signal::unix::{signal, SignalKind};
+
+        let reloader = rocket.state::<Arc<Reloader>>().expect("Reloader is managed").clone();
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(err) => return tracing::error!("Can't handle SIGHUP: {err}"),
+        };
+        rocket::tokio::spawn(async move {
+            while hangups.recv().await.is_some() {
+                let reloader = reloader.clone();
+                match rocket::tokio::task::spawn_blocking(move || reloader.reload()).await {
+                    Ok(Ok(_)) => {},
+                    Ok(Err(err)) => tracing::error!("{err}, nothing reloaded"),
+                    Err(_) => tracing::error!("Invalid config, nothing reloaded"),
+                }
+            }
+        });
+    }))
+}
+
+/// TESTS
+
+#[test]
+fn test_reloads_apply_tunables_and_refuse_restart_only_settings() {
+    let db = kopperdb::testing::TempDb::new();
+    let kopper = db.kopper(100).unwrap();
+    let started = Figment::new().merge(("grpc_address", "127.0.0.1:50051"));
+    let tunables = Tunables::from_config(&started, false, false);
+    tunables.apply(&kopper, &Tunables::default());
+    let reloader = Reloader::new(kopper.clone(), Arc::new(Registry::new(BTreeMap::new(), None)), false, false, tunables, &started);
+    assert_eq!(reloader.reload_from(&started), Ok(Vec::new()));
+
+    let reloaded = started.clone()
+        .merge(("default_ttl_ms", 60_000))
+        .merge(("versions", BTreeMap::from([("config", 2)])));
+    assert_eq!(reloader.reload_from(&reloaded), Ok(vec!["default_ttl".to_owned(), "versions".to_owned()]));
+    kopper.write("session", "1").unwrap();
+    assert!(kopper.ttl("session").unwrap().is_some_and(|ttl| ttl <= Duration::from_secs(60)));
+    for value in ["1", "2", "3", "4"] {
+        kopper.write("config", value).unwrap();
+    }
+    assert_eq!(kopper.read_versions("config").unwrap(), ["4", "3", "2"]);
+    assert_eq!(reloader.reload_from(&reloaded), Ok(Vec::new()));
+
+    // Nothing's applied along with a restart-only change
+    let moved = Figment::new().merge(("grpc_address", "127.0.0.1:50052")).merge(("engine", "brass"));
+    assert_eq!(reloader.reload_from(&moved), Err("Only changed by a restart: engine, grpc_address".to_owned()));
+    kopper.write("session", "2").unwrap();
+    assert!(kopper.ttl("session").unwrap().is_some());
+    assert_eq!(reloader.reload_from(&started), Ok(vec!["default_ttl".to_owned(), "versions".to_owned()]));
+    kopper.write("session", "3").unwrap();
+    assert_eq!(kopper.ttl("session").unwrap(), None);
+}
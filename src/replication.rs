@@ -0,0 +1,407 @@
+//! Primary to replica replication by log shipping. Every server serves its change
+//! log under `/replication`, and one with `replicate_from` in its config becomes a
+//! read-only replica of the server at that URL: it tails the primary's changes,
+//! applying them as they come, and installs the segments of a snapshot of the
+//! primary when the changes it needs are gone - on the first start, after the primary restarts
+//! or after falling further behind than the primary's `replication_log_size`.
+
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use rocket::{State, Request, Response};
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::response::{self, Responder};
+use rocket::serde::json::Json;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use kopperdb::client::KopperClient;
+use kopperdb::kopper::{ChangeEvent, Kopper, KopperError, Scan};
+
+use crate::admin::Tarball;
+
+/// Changes sent in a single response unless the replica asks for fewer
+const MAX_CHANGES: usize = 1000;
+
+/// How long a replica waits before polling a primary with nothing new
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a replica waits before retrying after an error
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, ToSchema)]
+pub struct ReplicatedChange {
+    sequence: u64,
+    key: String,
+    /// Missing for deletes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ChangesResponse {
+    /// Change log the sequences belong to - it's a new one every time the server starts
+    log: u64,
+    /// Sequence of the newest change on the server
+    sequence: u64,
+    /// Oldest first
+    changes: Vec<ReplicatedChange>
+}
+
+#[utoipa::path(
+    get,
+    path = "/replication/changes",
+    tag = "replication",
+    params(
+        ("since" = u64, Query, description = "Sequence of the last change the caller has"),
+        ("log" = Option<u64>, Query, description = "Change log `since` belongs to"),
+        ("limit" = Option<usize>, Query, description = "Most changes to return, 1000 at most")
+    ),
+    responses(
+        (status = 200, description = "Changes made after `since`", body = ChangesResponse),
+        (status = 410, description = "Changes after `since` aren't available anymore, or `log` isn't the current log. \
+            Copy a fresh /replication/snapshot instead")
+    )
+)]
+#[get("/replication/changes?<since>&<log>&<limit>")]
+pub fn changes(since: u64, log: Option<u64>, limit: Option<usize>, db: &State<Kopper>) -> Result<Json<ChangesResponse>, Status> {
+    if log.is_some_and(|log| log != db.log_id()) {
+        return Err(Status::Gone);
+    }
+    // Replicas ask for what comes after the changes they applied
+    if log.is_some() {
+        db.confirm_replication(since);
+    }
+
+    let records = match db.changes_since(since, limit.unwrap_or(MAX_CHANGES).min(MAX_CHANGES)) {
+        Ok(records) => records,
+        Err(KopperError::ChangesUnavailable(_)) => return Err(Status::Gone),
+        Err(err) => {
+            tracing::error!("Can't read changes: {err}");
+            return Err(Status::InternalServerError);
+        }
+    };
+
+    let changes = records.into_iter()
+        .map(|record| match record.event {
+            ChangeEvent::Write { key, value } => ReplicatedChange { sequence: record.sequence, key, value: Some(value) },
+            ChangeEvent::Delete { key } => ReplicatedChange { sequence: record.sequence, key, value: None },
+        })
+        .collect();
+
+    Ok(Json(ChangesResponse { log: db.log_id(), sequence: db.sequence(), changes }))
+}
+
+/// Entries of a [`Scan`] as NDJSON, with the position in the change log they're
+/// as of in the `Kopper-Log` and `Kopper-Sequence` headers
+pub struct Snapshot {
+    log: u64,
+    scan: Scan
+}
+
+impl<'r> Responder<'r, 'r> for Snapshot {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'r> {
+        let sequence = self.scan.sequence();
+        Response::build_from(crate::bulk::ndjson(self.scan).respond_to(req)?)
+            .raw_header("Kopper-Log", self.log.to_string())
+            .raw_header("Kopper-Sequence", sequence.to_string())
+            .ok()
+    }
+}
+
+/// Segment files of a snapshot as a tarball, with the position in the change log
+/// they're as of in the `Kopper-Log` and `Kopper-Sequence` headers
+pub struct Segments {
+    tarball: Tarball,
+    log: u64,
+    sequence: u64
+}
+
+impl<'r> Responder<'r, 'r> for Segments {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'r> {
+        Response::build_from(self.tarball.respond_to(req)?)
+            .raw_header("Kopper-Log", self.log.to_string())
+            .raw_header("Kopper-Sequence", self.sequence.to_string())
+            .ok()
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/replication/segments",
+    tag = "replication",
+    responses((status = 200, description = "Tarball with the segment files of a snapshot, as of the change in the `Kopper-Log` \
+        and `Kopper-Sequence` headers. Continue with /replication/changes from there", content_type = "application/x-tar"))
+)]
+#[get("/replication/segments")]
+pub async fn segments(db: &State<Kopper>) -> Result<Segments, Status> {
+    // Inside the database's directory, so sealed segments can be hard-linked.
+    // Hidden - the database skips those when recovering.
+    let name = format!("snapshot-{}", SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos());
+    let dir = PathBuf::from(db.path()).join(".snapshots").join(&name);
+
+    let kopper = db.inner().clone();
+    let target = dir.clone();
+    let snapshot = rocket::tokio::task::spawn_blocking(move || {
+        // Log first - if it changes meanwhile, the sequence is from the new one
+        // and the replica is just sent back here
+        let log = kopper.log_id();
+        kopper.snapshot_to(&target.to_string_lossy()).map(|sequence| (log, sequence))
+    }).await;
+
+    let (log, sequence) = match snapshot.map_err(|err| KopperError::InternalError(err.into())).and_then(|result| result) {
+        Ok(position) => position,
+        Err(err) => {
+            tracing::error!("Can't take a snapshot: {err}");
+            let _ = std::fs::remove_dir_all(&dir);
+            return Err(Status::InternalServerError);
+        },
+    };
+
+    let cleanup = dir.clone();
+    let tarball = Tarball::of_dir(&name, dir, move || {
+        if let Err(err) = std::fs::remove_dir_all(&cleanup) {
+            tracing::error!("Can't remove {}: {err}", cleanup.display());
+        }
+    });
+    Ok(Segments { tarball, log, sequence })
+}
+
+#[utoipa::path(
+    get,
+    path = "/replication/snapshot",
+    tag = "replication",
+    responses((status = 200, description = "Every entry in the format of /export, as of the change in the `Kopper-Log` \
+        and `Kopper-Sequence` headers. Continue with /replication/changes from there", content_type = "application/x-ndjson"))
+)]
+#[get("/replication/snapshot")]
+pub fn snapshot(db: &State<Kopper>) -> Result<Snapshot, Status> {
+    match db.scan() {
+        Ok(scan) => Ok(Snapshot { log: db.log_id(), scan }),
+        Err(err) => {
+            tracing::error!("Can't start snapshot: {err}");
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+/// Where a replica is, shared between the replication thread and [`status`]
+#[derive(Default)]
+pub struct ReplicaState {
+    primary: String,
+    /// Primary's change log and the sequence of the last change applied from it
+    position: Option<(u64, u64)>,
+    /// Sequence of the primary's newest change, as of the last poll
+    primary_sequence: u64,
+    last_contact: Option<Instant>,
+    resyncs: u64,
+    error: Option<String>
+}
+
+impl ReplicaState {
+    pub fn primary(&self) -> &str {
+        &self.primary
+    }
+
+    /// Whether every change of the primary, as of the last poll, is applied
+    pub fn caught_up(&self) -> bool {
+        self.error.is_none() && self.position.is_some_and(|(_, applied)| applied >= self.primary_sequence)
+    }
+}
+
+/// Whether the server replicates another one, managed by [`replica`]
+pub enum Role {
+    Primary,
+    Replica(Arc<Mutex<ReplicaState>>)
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ReplicationStatus {
+    /// "primary" or "replica"
+    role: &'static str,
+    /// This server's own change log and its newest sequence
+    log: u64,
+    sequence: u64,
+    /// Replicas only, URL of the primary
+    #[serde(skip_serializing_if = "Option::is_none")]
+    primary: Option<String>,
+    /// Replicas only, sequence of the primary's last change applied here.
+    /// Missing until the first snapshot is copied.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    applied: Option<u64>,
+    /// Replicas only, changes made on the primary that aren't applied here yet
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lag: Option<u64>,
+    /// Replicas only, milliseconds since the primary last answered
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_contact_ms: Option<u128>,
+    /// Replicas only, snapshots copied since the server started
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resyncs: Option<u64>,
+    /// Replicas only, why replication isn't making progress
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>
+}
+
+#[utoipa::path(
+    get,
+    path = "/replication/status",
+    tag = "replication",
+    responses((status = 200, description = "Role of the server and, for replicas, how far behind they are", body = ReplicationStatus))
+)]
+#[get("/replication/status")]
+pub fn status(db: &State<Kopper>, role: &State<Role>) -> Json<ReplicationStatus> {
+    let mut status = ReplicationStatus {
+        role: "primary",
+        log: db.log_id(),
+        sequence: db.sequence(),
+        primary: None,
+        applied: None,
+        lag: None,
+        last_contact_ms: None,
+        resyncs: None,
+        error: None
+    };
+
+    if let Role::Replica(replica) = role.inner() {
+        let replica = replica.lock().unwrap();
+        let applied = replica.position.map(|(_, sequence)| sequence);
+
+        status.role = "replica";
+        status.primary = Some(replica.primary.clone());
+        status.applied = applied;
+        status.lag = applied.map(|applied| replica.primary_sequence.saturating_sub(applied));
+        status.last_contact_ms = replica.last_contact.map(|contact| contact.elapsed().as_millis());
+        status.resyncs = Some(replica.resyncs);
+        status.error = replica.error.clone();
+    }
+    Json(status)
+}
+
+/// Fairing making the server a read-only replica of the server at `replicate_from`
+/// from Rocket's config, if it's set. Replication runs on its own thread until
+/// the database is closed.
+pub fn replica() -> AdHoc {
+    AdHoc::on_ignite("replica", |rocket| async move {
+        let Ok(primary) = rocket.figment().extract_inner::<String>("replicate_from") else {
+            return rocket.manage(Role::Primary);
+        };
+
+        let db = rocket.state::<Kopper>().expect("Kopper is managed").clone();
+        db.set_read_only(true);
+
+        let state = Arc::new(Mutex::new(ReplicaState { primary: primary.clone(), ..Default::default() }));
+        let thread_state = state.clone();
+        rocket
+            .manage(Role::Replica(state))
+            .attach(AdHoc::on_liftoff("replication", move |_| Box::pin(async move {
+                tracing::info!("Replicating {primary}");
+                std::thread::spawn(move || replicate(db, primary, thread_state));
+            })))
+    })
+}
+
+/// Where the position in the primary's change log is kept between restarts.
+/// Hidden file - the database skips those when recovering.
+fn position_file(db: &Kopper) -> PathBuf {
+    PathBuf::from(db.path()).join(".replication")
+}
+
+fn load_position(db: &Kopper) -> Option<(u64, u64)> {
+    let position = std::fs::read_to_string(position_file(db)).ok()?;
+    let (log, sequence) = position.trim().split_once(' ')?;
+    Some((log.parse().ok()?, sequence.parse().ok()?))
+}
+
+/// Changes are applied before the position is saved, so after a crash some of
+/// them are applied again. That's harmless - they're replayed in order.
+fn save_position(db: &Kopper, (log, sequence): (u64, u64)) -> std::io::Result<()> {
+    std::fs::write(position_file(db), format!("{log} {sequence}\n"))
+}
+
+fn replicate(db: Kopper, primary: String, state: Arc<Mutex<ReplicaState>>) {
+    let mut position = load_position(&db);
+    state.lock().unwrap().position = position;
+    let mut client = None;
+
+    while !db.is_closed() {
+        let result = client.get_or_insert_with(|| KopperClient::connect(&primary)).as_ref()
+            .map_err(|err| err.to_string())
+            .and_then(|client| step(&db, client, &mut position, &state).map_err(|err| err.to_string()));
+
+        match result {
+            Ok(caught_up) => {
+                state.lock().unwrap().error = None;
+                if caught_up {
+                    std::thread::sleep(POLL_INTERVAL);
+                }
+            },
+            Err(err) if db.is_closed() => tracing::debug!("Replication stopped: {err}"),
+            Err(err) => {
+                tracing::error!("Replicating {primary} failed: {err}");
+                state.lock().unwrap().error = Some(err);
+                // Connect again, the primary may have moved
+                client = None;
+                std::thread::sleep(RETRY_INTERVAL);
+            }
+        }
+    }
+}
+
+/// Applies one batch of changes, or copies a snapshot if they're gone.
+/// Returns whether the replica caught up with the primary.
+fn step(db: &Kopper, client: &KopperClient, position: &mut Option<(u64, u64)>, state: &Mutex<ReplicaState>) -> Result<bool, Box<dyn Error>> {
+    let Some((log, since)) = *position else {
+        let resynced = resync(db, client)?;
+        *position = Some(resynced);
+        save_position(db, resynced)?;
+
+        let mut state = state.lock().unwrap();
+        state.position = *position;
+        state.primary_sequence = resynced.1;
+        state.last_contact = Some(Instant::now());
+        state.resyncs += 1;
+        return Ok(false);
+    };
+
+    let Some(changes) = client.changes(log, since, MAX_CHANGES)? else {
+        tracing::warn!("Changes after {since} aren't available on the primary anymore, copying a snapshot");
+        *position = None;
+        return Ok(false);
+    };
+
+    let caught_up = changes.changes.last().is_none_or(|change| change.sequence >= changes.sequence);
+    if let Some(last) = changes.changes.last().map(|change| change.sequence) {
+        let events: Vec<ChangeEvent> = changes.changes.into_iter().map(ChangeEvent::from).collect();
+        db.apply(&events)?;
+        *position = Some((log, last));
+        save_position(db, (log, last))?;
+    }
+
+    let mut state = state.lock().unwrap();
+    state.position = *position;
+    state.primary_sequence = changes.sequence;
+    state.last_contact = Some(Instant::now());
+    Ok(caught_up)
+}
+
+/// Makes the replica a copy of a snapshot of the primary, returning the position
+/// in the primary's change log the snapshot was taken at. Segments are shipped
+/// as they are, so big databases aren't replayed entry by entry.
+fn resync(db: &Kopper, client: &KopperClient) -> Result<(u64, u64), Box<dyn Error>> {
+    // Hidden - the database skips those when recovering. Staged inside the
+    // database's directory, so installing only has to rename the files.
+    let staging = PathBuf::from(db.path()).join(".bootstrap");
+    let _ = std::fs::remove_dir_all(&staging);
+    std::fs::create_dir_all(&staging)?;
+
+    let position = client.segments(&staging)?;
+    tracing::info!("Installing a snapshot of the primary as of change {}", position.1);
+    db.install(&staging.to_string_lossy())?;
+
+    std::fs::remove_dir_all(&staging)?;
+    Ok(position)
+}
@@ -0,0 +1,401 @@
+use rocket::{Rocket, Orbit, Shutdown};
+use rocket::fairing::AdHoc;
+use rocket::tokio::{self, io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader}, net::{TcpListener, TcpStream}};
+
+use kopperdb::kopper::{Kopper, KopperError};
+use kopperdb::pattern::KeyPattern;
+
+use crate::api;
+
+/// Keys returned by a single SCAN call unless the client asks for a different COUNT
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// Longest bulk string accepted from a client
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+/// Fairing starting a Redis protocol (RESP) listener at `resp_address` from Rocket's
+/// config, if there's one. Supports a subset of commands - GET, SET, GETSET, GETDEL,
+/// RENAME, DEL, EXISTS, TTL and SCAN, plus PING and QUIT - so existing Redis clients can
+/// talk to Kopper.
+pub fn listener() -> AdHoc {
+    AdHoc::on_liftoff("RESP listener", |rocket| Box::pin(async move {
+        let Ok(address) = rocket.figment().extract_inner::<String>("resp_address") else {
+            return;
+        };
+
+        match TcpListener::bind(&address).await {
+            Ok(listener) => {
+                tracing::info!("RESP listener on {address}");
+                tokio::spawn(accept(listener, db(rocket), rocket.shutdown()));
+            },
+            Err(err) => tracing::error!("Can't start RESP listener on {address}: {err}"),
+        }
+    }))
+}
+
+fn db(rocket: &Rocket<Orbit>) -> Kopper {
+    rocket.state::<Kopper>().expect("Kopper is managed").clone()
+}
+
+async fn accept(listener: TcpListener, db: Kopper, mut shutdown: Shutdown) {
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    tracing::error!("RESP accept failed: {err}");
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+
+        let db = db.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            if let Err(err) = serve(stream, db, shutdown).await {
+                tracing::debug!("RESP connection closed: {err}");
+            }
+        });
+    }
+}
+
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Simple(text) => out.extend_from_slice(format!("+{text}\r\n").as_bytes()),
+            Reply::Error(text) => out.extend_from_slice(format!("-{text}\r\n").as_bytes()),
+            Reply::Integer(value) => out.extend_from_slice(format!(":{value}\r\n").as_bytes()),
+            Reply::Bulk(None) => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Bulk(Some(value)) => {
+                out.extend_from_slice(format!("${}\r\n", value.len()).as_bytes());
+                out.extend_from_slice(value.as_bytes());
+                out.extend_from_slice(b"\r\n");
+            },
+            Reply::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(out);
+                }
+            },
+        }
+    }
+
+    fn internal(err: KopperError) -> Reply {
+        tracing::error!("{err}");
+        Reply::Error("ERR internal error".to_string())
+    }
+}
+
+async fn serve(stream: TcpStream, db: Kopper, mut shutdown: Shutdown) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    loop {
+        let command = tokio::select! {
+            command = read_command(&mut reader) => command?,
+            _ = &mut shutdown => return Ok(()),
+        };
+        let Some(command) = command else {
+            return Ok(()); // Client hung up
+        };
+
+        let quit = command.first().is_some_and(|name| name.eq_ignore_ascii_case(b"QUIT"));
+        let reply = match command.into_iter().map(String::from_utf8).collect::<Result<Vec<_>, _>>() {
+            Ok(args) => {
+                let db = db.clone();
+                api::blocking(move || execute(&db, args)).await
+            },
+            Err(_) => Reply::Error("ERR keys and values must be valid UTF-8".to_string()),
+        };
+
+        let mut out = Vec::new();
+        reply.encode(&mut out);
+        writer.write_all(&out).await?;
+
+        if quit {
+            return Ok(());
+        }
+    }
+}
+
+/// Reads one command - an array of bulk strings, or an inline command as typed
+/// into telnet. `None` once the client disconnects.
+async fn read_command(reader: &mut (impl AsyncBufRead + Unpin)) -> std::io::Result<Option<Vec<Vec<u8>>>> {
+    let invalid = |message: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string());
+
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        return Ok(None);
+    }
+
+    let Some(count) = line.strip_prefix('*') else {
+        return Ok(Some(line.split_whitespace().map(|arg| arg.as_bytes().to_vec()).collect()));
+    };
+    let count: usize = count.trim_end().parse().map_err(|_| invalid("Malformed array header"))?;
+
+    let mut args = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        line.clear();
+        reader.read_line(&mut line).await?;
+        let len: usize = line.strip_prefix('$')
+            .and_then(|len| len.trim_end().parse().ok())
+            .filter(|len| *len <= MAX_BULK_LEN)
+            .ok_or_else(|| invalid("Expected a bulk string"))?;
+
+        // Bulk string is followed by CRLF
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg).await?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(invalid("Bulk string longer than its length"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+fn wrong_args(name: &str) -> Reply {
+    Reply::Error(format!("ERR wrong number of arguments for '{}' command", name.to_lowercase()))
+}
+
+fn execute(db: &Kopper, args: Vec<String>) -> Reply {
+    let Some((name, args)) = args.split_first() else {
+        return Reply::Error("ERR empty command".to_string());
+    };
+
+    match (name.to_uppercase().as_str(), args) {
+        ("PING", []) => Reply::Simple("PONG"),
+        ("PING", [message]) => Reply::Bulk(Some(message.clone())),
+        ("QUIT", _) => Reply::Simple("OK"),
+        // Sent by redis-cli when connecting - no command docs to offer
+        ("COMMAND", _) => Reply::Array(Vec::new()),
+
+        ("GET", [key]) => match db.read(key) {
+            Ok(value) => Reply::Bulk(Some(value)),
+            Err(KopperError::KeyDoesNotExist(_)) => Reply::Bulk(None),
+            Err(err) => Reply::internal(err),
+        },
+        ("SET", [key, value]) => {
+            if key.is_empty() || key.contains('\0') || value.contains('\0') {
+                return Reply::Error("ERR keys can't be empty, keys and values can't contain NUL".to_string());
+            }
+            match db.write(key, value) {
+                Ok(_) => Reply::Simple("OK"),
+                Err(err) => Reply::internal(err),
+            }
+        },
+        ("SET", [_, _, ..]) => Reply::Error("ERR SET options are not supported".to_string()),
+        ("GETSET", [key, value]) => {
+            if key.is_empty() || key.contains('\0') || value.contains('\0') {
+                return Reply::Error("ERR keys can't be empty, keys and values can't contain NUL".to_string());
+            }
+            match db.get_and_set(key, value) {
+                Ok(previous) => Reply::Bulk(previous),
+                Err(err) => Reply::internal(err),
+            }
+        },
+        ("GETDEL", [key]) => match db.get_and_delete(key) {
+            Ok(value) => Reply::Bulk(Some(value)),
+            Err(KopperError::KeyDoesNotExist(_)) => Reply::Bulk(None),
+            Err(err) => Reply::internal(err),
+        },
+        ("RENAME", [from, to]) => {
+            if to.is_empty() || to.contains('\0') {
+                return Reply::Error("ERR keys can't be empty, keys and values can't contain NUL".to_string());
+            }
+            match db.rename(from, to) {
+                Ok(_) => Reply::Simple("OK"),
+                Err(KopperError::KeyDoesNotExist(_)) => Reply::Error("ERR no such key".to_string()),
+                Err(err) => Reply::internal(err),
+            }
+        },
+        ("DEL", [_, ..]) => {
+            let mut deleted = 0;
+            for key in args {
+                match db.delete(key) {
+                    Ok(_) => deleted += 1,
+                    Err(KopperError::KeyDoesNotExist(_)) => {},
+                    Err(err) => return Reply::internal(err),
+                }
+            }
+            Reply::Integer(deleted)
+        },
+        ("EXISTS", [_, ..]) => {
+            let mut existing = 0;
+            for key in args {
+                match db.read(key) {
+                    Ok(_) => existing += 1,
+                    Err(KopperError::KeyDoesNotExist(_)) => {},
+                    Err(err) => return Reply::internal(err),
+                }
+            }
+            Reply::Integer(existing)
+        },
+        // Seconds left, rounded like Redis does: -1 for keys that never expire, -2 for missing ones
+        ("TTL", [key]) => match db.ttl(key) {
+            Ok(Some(ttl)) => Reply::Integer(((ttl.as_millis() + 500) / 1000) as i64),
+            Ok(None) => Reply::Integer(-1),
+            Err(KopperError::KeyDoesNotExist(_)) => Reply::Integer(-2),
+            Err(err) => Reply::internal(err),
+        },
+        ("SCAN", [cursor, options @ ..]) => scan(db, cursor, options),
+
+        ("GET" | "SET" | "GETSET" | "GETDEL" | "RENAME" | "DEL" | "EXISTS" | "TTL" | "SCAN", _) => wrong_args(name),
+        _ => Reply::Error(format!("ERR unknown command '{name}'")),
+    }
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count]`. The cursor is the last key
+/// returned, and each call goes on with the keys after it in key order, so keys
+/// present for the whole iteration are returned exactly once. COUNT is the
+/// number of matching keys returned.
+fn scan(db: &Kopper, cursor: &str, options: &[String]) -> Reply {
+    let Some(after) = decode_cursor(cursor) else {
+        return Reply::Error("ERR invalid cursor".to_string());
+    };
+
+    let mut pattern = KeyPattern::Glob("*".to_string());
+    let mut count = DEFAULT_SCAN_COUNT;
+    for option in options.chunks(2) {
+        match option {
+            [name, value] if name.eq_ignore_ascii_case("MATCH") => pattern = KeyPattern::Glob(value.clone()),
+            [name, value] if name.eq_ignore_ascii_case("COUNT") => match value.parse() {
+                Ok(value) if value > 0 => count = value,
+                _ => return Reply::Error("ERR value is not an integer or out of range".to_string()),
+            },
+            _ => return Reply::Error("ERR syntax error".to_string()),
+        }
+    }
+
+    let mut keys = match db.scan_match(&pattern, after.as_deref(), count.saturating_add(1)) {
+        Ok(keys) => keys,
+        Err(err) => return Reply::internal(err),
+    };
+
+    // One extra key tells whether there's anything after this page
+    let next_cursor = match keys.len() > count {
+        true => {
+            keys.truncate(count);
+            encode_cursor(&keys[count - 1])
+        },
+        false => "0".to_string(),
+    };
+    let keys = keys.into_iter().map(|key| Reply::Bulk(Some(key))).collect();
+
+    Reply::Array(vec![Reply::Bulk(Some(next_cursor)), Reply::Array(keys)])
+}
+
+/// Cursor going on after `key`. Clients expect digits, so it's a 1 followed
+/// by every byte of the key as three digits - "0" stays the start and the end.
+fn encode_cursor(key: &str) -> String {
+    std::iter::once("1".to_string())
+        .chain(key.bytes().map(|byte| format!("{byte:03}")))
+        .collect()
+}
+
+/// Key a cursor from [`encode_cursor`] goes on after, `None` inside for "0"
+fn decode_cursor(cursor: &str) -> Option<Option<String>> {
+    if cursor == "0" {
+        return Some(None);
+    }
+    let digits = cursor.strip_prefix('1')?.as_bytes();
+    if digits.len() % 3 != 0 {
+        return None;
+    }
+    let bytes = digits.chunks(3)
+        .map(|digits| std::str::from_utf8(digits).ok()?.parse::<u8>().ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok().map(Some)
+}
+
+
+/// TESTS
+
+#[rocket::async_test]
+async fn test_commands_are_read_across_reads_and_one_after_another() {
+    // Split mid-frame, the way a client's writes may arrive
+    let first: &[u8] = b"*2\r\n$3\r\nGET\r\n$3\r\nke";
+    let rest: &[u8] = b"y\r\n*1\r\n$4\r\nPING\r\nPING hello\r\n";
+    let mut reader = BufReader::new(first.chain(rest));
+
+    assert_eq!(read_command(&mut reader).await.unwrap().unwrap(), [b"GET".to_vec(), b"key".to_vec()]);
+    assert_eq!(read_command(&mut reader).await.unwrap().unwrap(), [b"PING".to_vec()]);
+    assert_eq!(read_command(&mut reader).await.unwrap().unwrap(), [b"PING".to_vec(), b"hello".to_vec()]);
+    assert!(read_command(&mut reader).await.unwrap().is_none());
+
+    // Cut off by the client hanging up
+    let mut reader = BufReader::new(&b"*2\r\n$3\r\nGET\r\n$3\r\nk"[..]);
+    assert!(read_command(&mut reader).await.is_err());
+}
+
+#[rocket::async_test]
+async fn test_malformed_frames_are_refused() {
+    for frame in [&b"*two\r\n"[..], b"*1\r\n+GET\r\n", b"*1\r\n$-1\r\n", b"*1\r\n$2\r\nGET\r\n", b"*2\r\n$3\r\nGET\r\n"] {
+        let error = read_command(&mut BufReader::new(frame)).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData, "{}", String::from_utf8_lossy(frame));
+    }
+}
+
+#[test]
+fn test_commands_run_against_the_database() {
+    let db = kopperdb::testing::TempDb::new();
+    let kopper = db.kopper(4096).unwrap();
+
+    assert_eq!(reply(&kopper, &["set", "a", "1"]), "+OK\r\n");
+    assert_eq!(reply(&kopper, &["GET", "a"]), "$1\r\n1\r\n");
+    assert_eq!(reply(&kopper, &["GET", "b"]), "$-1\r\n");
+    assert_eq!(reply(&kopper, &["EXISTS", "a", "b"]), ":1\r\n");
+    assert_eq!(reply(&kopper, &["GET"]), "-ERR wrong number of arguments for 'get' command\r\n");
+    assert_eq!(reply(&kopper, &["FLUSHALL"]), "-ERR unknown command 'FLUSHALL'\r\n");
+    assert_eq!(reply(&kopper, &["DEL", "a", "b"]), ":1\r\n");
+    kopper.close().unwrap();
+}
+
+#[test]
+fn test_scan_cursors_go_on_after_the_last_key_returned() {
+    for key in ["a", "ключ", "\u{7f}\u{1F980}"] {
+        let cursor = encode_cursor(key);
+        assert!(cursor.bytes().all(|byte| byte.is_ascii_digit()));
+        assert_eq!(decode_cursor(&cursor), Some(Some(key.to_owned())));
+    }
+    assert_eq!(decode_cursor("0"), Some(None));
+    for cursor in ["", "2097", "19", "1999", "1208"] {
+        assert_eq!(decode_cursor(cursor), None, "{cursor}");
+    }
+
+    let db = kopperdb::testing::TempDb::new();
+    let kopper = db.kopper(4096).unwrap();
+    for key in ["ключ-1", "ключ-2", "ключ-3", "other"] {
+        kopper.write(key, "value").unwrap();
+    }
+    let mut cursor = "0".to_owned();
+    let mut pages = Vec::new();
+    loop {
+        let page = reply(&kopper, &["SCAN", &cursor, "MATCH", "ключ-*", "COUNT", "2"]);
+        cursor = page.split("\r\n").nth(2).unwrap().to_owned();
+        pages.push(page);
+        if cursor == "0" {
+            break;
+        }
+    }
+    assert_eq!(pages.len(), 2);
+    assert!(pages[0].ends_with("*2\r\n$10\r\nключ-1\r\n$10\r\nключ-2\r\n"));
+    assert!(pages[1].ends_with("*1\r\n$10\r\nключ-3\r\n"));
+    kopper.close().unwrap();
+}
+
+#[cfg(test)]
+fn reply(db: &Kopper, args: &[&str]) -> String {
+    let mut out = Vec::new();
+    execute(db, args.iter().map(|arg| arg.to_string()).collect()).encode(&mut out);
+    String::from_utf8(out).unwrap()
+}
@@ -0,0 +1,103 @@
+//! What the database is running out of, see [`Kopper::saturation`](crate::kopper::Kopper::saturation).
+//! Numbers to watch before they turn into an outage: file handles, compactions
+//! waiting to run, and time callers spend waiting on the lock of the database.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::{Mutex, MutexGuard};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Saturation {
+    /// Segments open, one file handle each
+    pub open_segments: usize,
+    /// Compaction requests not taken up by the compactor yet
+    pub compactor_queue: usize,
+    /// Whether the compactor thread runs, `None` if the database doesn't run one
+    pub compactor_alive: Option<bool>,
+    /// Times the lock was taken only after waiting for another thread, since the database was opened
+    pub lock_waits: u64,
+    /// Time spent waiting for the lock in all of them
+    pub lock_wait: Duration,
+}
+
+/// Counts the waits of [`Contention::lock`]
+#[derive(Default)]
+pub(crate) struct Contention {
+    waits: AtomicU64,
+    waited_nanos: AtomicU64,
+}
+
+impl Contention {
+    /// Locks `mutex`, counting the time spent waiting if another thread holds it
+    pub(crate) fn lock<'a, T>(&self, mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+        if let Some(guard) = mutex.try_lock() {
+            return guard;
+        }
+        // Measures the real wait, whatever clock the database runs on
+        let started = Instant::now();
+        let guard = mutex.lock();
+        self.count(started.elapsed());
+        guard
+    }
+
+    /// Like [`Contention::lock`], giving up with `None` once `deadline` passes
+    pub(crate) fn lock_until<'a, T>(&self, mutex: &'a Mutex<T>, deadline: Instant) -> Option<MutexGuard<'a, T>> {
+        if let Some(guard) = mutex.try_lock() {
+            return Some(guard);
+        }
+        let started = Instant::now();
+        let guard = mutex.try_lock_until(deadline);
+        self.count(started.elapsed());
+        guard
+    }
+
+    fn count(&self, waited: Duration) {
+        self.waits.fetch_add(1, Ordering::Relaxed);
+        self.waited_nanos.fetch_add(waited.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Waits so far and the time spent in them
+    pub(crate) fn waited(&self) -> (u64, Duration) {
+        (self.waits.load(Ordering::Relaxed), Duration::from_nanos(self.waited_nanos.load(Ordering::Relaxed)))
+    }
+}
+
+/// TESTS
+
+#[test]
+fn test_contention_counts_only_waits() {
+    use std::sync::Arc;
+
+    let contention = Arc::new(Contention::default());
+    let mutex = Arc::new(Mutex::new(()));
+    drop(contention.lock(&mutex));
+    assert_eq!(contention.waited(), (0, Duration::ZERO));
+
+    let held = mutex.lock();
+    let waiting = {
+        let (contention, mutex) = (contention.clone(), mutex.clone());
+        std::thread::spawn(move || drop(contention.lock(&mutex)))
+    };
+    std::thread::sleep(Duration::from_millis(50));
+    drop(held);
+    waiting.join().unwrap();
+
+    let (waits, waited) = contention.waited();
+    assert_eq!(waits, 1);
+    assert!(waited >= Duration::from_millis(10));
+
+    // Gives up while the mutex is held, gets it as soon as it's released
+    let held = mutex.lock();
+    assert!(contention.lock_until(&mutex, Instant::now() + Duration::from_millis(5)).is_none());
+    let waiting = {
+        let (contention, mutex) = (contention.clone(), mutex.clone());
+        std::thread::spawn(move || contention.lock_until(&mutex, Instant::now() + Duration::from_secs(60)).is_some())
+    };
+    std::thread::sleep(Duration::from_millis(20));
+    let released = Instant::now();
+    drop(held);
+    assert!(waiting.join().unwrap());
+    assert!(released.elapsed() < Duration::from_secs(10));
+    assert_eq!(contention.waited().0, 3);
+}
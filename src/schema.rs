@@ -0,0 +1,294 @@
+//! JSON schemas values under a key prefix have to follow, see [`Schemas`]. Only
+//! the common part of JSON Schema is understood - `type`, `enum`, `const`,
+//! `properties`, `required`, `additionalProperties`, `items`, `minimum`,
+//! `maximum`, `exclusiveMinimum`, `exclusiveMaximum`, `minLength`, `maxLength`,
+//! `pattern`, `minItems` and `maxItems`. Other keywords are ignored, like
+//! validators ignore keywords they don't know.
+
+use std::collections::BTreeMap;
+
+use serde_json::Value;
+
+use crate::kopper::KopperError;
+
+pub enum Schema {
+    /// `true`, or `{}`
+    Any,
+    /// `false`
+    Never,
+    Rules(Box<Rules>),
+}
+
+#[derive(Default)]
+pub struct Rules {
+    types: Vec<String>,
+    allowed: Option<Vec<Value>>,
+    properties: BTreeMap<String, Schema>,
+    required: Vec<String>,
+    additional_properties: Option<Schema>,
+    items: Option<Schema>,
+    minimum: Option<f64>,
+    maximum: Option<f64>,
+    exclusive_minimum: Option<f64>,
+    exclusive_maximum: Option<f64>,
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    pattern: Option<regex::Regex>,
+    min_items: Option<usize>,
+    max_items: Option<usize>,
+}
+
+impl Schema {
+    /// Reads the schema `schema`, failing on keywords it understands but that don't hold what they should
+    pub fn new(schema: &Value) -> Result<Self, String> {
+        let rules = match schema {
+            Value::Bool(true) => return Ok(Schema::Any),
+            Value::Bool(false) => return Ok(Schema::Never),
+            Value::Object(rules) => rules,
+            _ => return Err("a schema is an object or a boolean".to_owned()),
+        };
+
+        let number = |name: &str| rules.get(name).map(|value| value.as_f64().ok_or(format!("{name} isn't a number"))).transpose();
+        let count = |name: &str| rules.get(name).map(|value| value.as_u64().map(|count| count as usize).ok_or(format!("{name} isn't a count"))).transpose();
+        let strings = |name: &str| match rules.get(name) {
+            None => Ok(Vec::new()),
+            Some(Value::String(string)) if name == "type" => Ok(vec![string.clone()]),
+            Some(Value::Array(strings)) => strings.iter()
+                .map(|string| string.as_str().map(str::to_owned).ok_or(format!("{name} holds something else than strings")))
+                .collect(),
+            Some(_) => Err(format!("{name} isn't a list of strings")),
+        };
+
+        let mut properties = BTreeMap::new();
+        if let Some(schemas) = rules.get("properties") {
+            for (name, schema) in schemas.as_object().ok_or("properties isn't an object")? {
+                properties.insert(name.clone(), Schema::new(schema).map_err(|err| format!("properties.{name}: {err}"))?);
+            }
+        }
+        let nested = |name: &str| rules.get(name).map(|schema| Schema::new(schema).map_err(|err| format!("{name}: {err}"))).transpose();
+        let pattern = match rules.get("pattern") {
+            Some(pattern) => {
+                let pattern = pattern.as_str().ok_or("pattern isn't a string")?;
+                Some(regex::Regex::new(pattern).map_err(|err| format!("pattern: {err}"))?)
+            },
+            None => None,
+        };
+
+        let allowed = match (rules.get("enum"), rules.get("const")) {
+            (Some(Value::Array(allowed)), _) => Some(allowed.clone()),
+            (Some(_), _) => return Err("enum isn't an array".to_owned()),
+            (None, Some(constant)) => Some(vec![constant.clone()]),
+            (None, None) => None,
+        };
+        Ok(Schema::Rules(Box::new(Rules {
+            types: strings("type")?,
+            allowed,
+            properties,
+            required: strings("required")?,
+            additional_properties: nested("additionalProperties")?,
+            items: nested("items")?,
+            minimum: number("minimum")?,
+            maximum: number("maximum")?,
+            exclusive_minimum: number("exclusiveMinimum")?,
+            exclusive_maximum: number("exclusiveMaximum")?,
+            min_length: count("minLength")?,
+            max_length: count("maxLength")?,
+            pattern,
+            min_items: count("minItems")?,
+            max_items: count("maxItems")?,
+        })))
+    }
+
+    /// Everything wrong with `value`, each problem behind the JSON pointer to where it is
+    pub fn validate(&self, value: &Value) -> Vec<String> {
+        let mut problems = Vec::new();
+        self.check(value, "", &mut problems);
+        problems
+    }
+
+    fn check(&self, value: &Value, at: &str, problems: &mut Vec<String>) {
+        let location = if at.is_empty() { "/" } else { at };
+        let rules = match self {
+            Schema::Any => return,
+            Schema::Never => return problems.push(format!("{location}: nothing is allowed here")),
+            Schema::Rules(rules) => rules,
+        };
+        let mut problem = |problem: String| problems.push(format!("{location}: {problem}"));
+
+        if !rules.types.is_empty() && !rules.types.iter().any(|name| is_type(value, name)) {
+            problem(format!("expected {}, found {}", rules.types.join(" or "), type_of(value)));
+            return;
+        }
+        if rules.allowed.as_ref().is_some_and(|allowed| !allowed.contains(value)) {
+            problem(format!("{value} isn't one of the allowed values"));
+        }
+
+        match value {
+            Value::Number(number) => {
+                let number = number.as_f64().unwrap_or(f64::NAN);
+                if rules.minimum.is_some_and(|minimum| number < minimum) {
+                    problem(format!("{number} is less than {}", rules.minimum.unwrap()));
+                }
+                if rules.maximum.is_some_and(|maximum| number > maximum) {
+                    problem(format!("{number} is more than {}", rules.maximum.unwrap()));
+                }
+                if rules.exclusive_minimum.is_some_and(|minimum| number <= minimum) {
+                    problem(format!("{number} isn't more than {}", rules.exclusive_minimum.unwrap()));
+                }
+                if rules.exclusive_maximum.is_some_and(|maximum| number >= maximum) {
+                    problem(format!("{number} isn't less than {}", rules.exclusive_maximum.unwrap()));
+                }
+            },
+            Value::String(string) => {
+                let len = string.chars().count();
+                if rules.min_length.is_some_and(|min| len < min) {
+                    problem(format!("shorter than {} characters", rules.min_length.unwrap()));
+                }
+                if rules.max_length.is_some_and(|max| len > max) {
+                    problem(format!("longer than {} characters", rules.max_length.unwrap()));
+                }
+                if rules.pattern.as_ref().is_some_and(|pattern| !pattern.is_match(string)) {
+                    problem(format!("doesn't match {}", rules.pattern.as_ref().unwrap()));
+                }
+            },
+            Value::Array(items) => {
+                if rules.min_items.is_some_and(|min| items.len() < min) {
+                    problem(format!("fewer than {} items", rules.min_items.unwrap()));
+                }
+                if rules.max_items.is_some_and(|max| items.len() > max) {
+                    problem(format!("more than {} items", rules.max_items.unwrap()));
+                }
+                if let Some(schema) = &rules.items {
+                    for (index, item) in items.iter().enumerate() {
+                        schema.check(item, &format!("{at}/{index}"), problems);
+                    }
+                }
+            },
+            Value::Object(fields) => {
+                for name in rules.required.iter().filter(|name| !fields.contains_key(*name)) {
+                    problem(format!("{name} is required"));
+                }
+                for (name, field) in fields {
+                    let at = format!("{at}/{}", name.replace('~', "~0").replace('/', "~1"));
+                    match (rules.properties.get(name), &rules.additional_properties) {
+                        (Some(schema), _) | (None, Some(schema)) => schema.check(field, &at, problems),
+                        (None, None) => {},
+                    }
+                }
+            },
+            Value::Null | Value::Bool(_) => {},
+        }
+    }
+}
+
+fn is_type(value: &Value, name: &str) -> bool {
+    match name {
+        "integer" => value.as_f64().is_some_and(|number| number.fract() == 0.0),
+        name => type_of(value) == name,
+    }
+}
+
+fn type_of(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Schemas by the key prefix they're for. A value follows the schema of the
+/// longest prefix of its key, keys without one can hold anything. Hook one into
+/// the engine to have every write follow it:
+///
+/// ```
+/// # use std::sync::Arc;
+/// # use kopperdb::{schema::Schemas, testing::TempDb};
+/// # let db = TempDb::new();
+/// # let kopper = db.kopper(4096).unwrap();
+/// let mut schemas = Schemas::default();
+/// schemas.add("user:", &serde_json::json!({"type": "object", "required": ["name"]})).unwrap();
+/// let schemas = Arc::new(schemas);
+/// kopper.on_write(move |key, value| schemas.check(key, &value).map(|_| value));
+///
+/// assert!(kopper.write("user:1", r#"{"name": "ann"}"#).is_ok());
+/// assert!(kopper.write("user:2", "{}").is_err());
+/// ```
+#[derive(Default)]
+pub struct Schemas {
+    by_prefix: BTreeMap<String, Schema>,
+}
+
+impl Schemas {
+    /// Reads a schema for every prefix from the file given for it
+    pub fn load(files: &BTreeMap<String, String>) -> Result<Self, KopperError> {
+        let mut schemas = Schemas::default();
+        for (prefix, file) in files {
+            let schema: Value = serde_json::from_str(&std::fs::read_to_string(file)?)
+                .map_err(|err| KopperError::InternalError(anyhow::anyhow!("Schema {file} isn't JSON: {err}")))?;
+            schemas.add(prefix, &schema).map_err(|err| KopperError::InternalError(anyhow::anyhow!("Invalid schema {file}: {err}")))?;
+        }
+        Ok(schemas)
+    }
+
+    /// Values under `prefix` have to follow `schema` from now on
+    pub fn add(&mut self, prefix: &str, schema: &Value) -> Result<(), String> {
+        self.by_prefix.insert(prefix.to_owned(), Schema::new(schema)?);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_prefix.is_empty()
+    }
+
+    /// Refuses `value` with [`KopperError::Rejected`], listing what's wrong with
+    /// it, if `key` has a schema the value doesn't follow
+    pub fn check(&self, key: &str, value: &str) -> Result<(), KopperError> {
+        // Prefixes of the key sort before it, the longest one last
+        let Some((prefix, schema)) = self.by_prefix.range(..=key.to_owned()).rev().find(|(prefix, _)| key.starts_with(prefix.as_str())) else {
+            return Ok(());
+        };
+        let problems = match serde_json::from_str(value) {
+            Ok(value) => schema.validate(&value),
+            Err(err) => vec![format!("not JSON: {err}")],
+        };
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(KopperError::Rejected(format!("{key} doesn't follow the schema of {prefix:?}: {}", problems.join("; ")))),
+        }
+    }
+}
+
+/// TESTS
+
+#[test]
+fn test_values_are_checked_against_the_schema_of_their_prefix() {
+    let mut schemas = Schemas::default();
+    schemas.add("user:", &serde_json::json!({
+        "type": "object",
+        "required": ["name"],
+        "properties": {
+            "name": {"type": "string", "minLength": 1},
+            "age": {"type": "integer", "minimum": 0},
+            "tags": {"type": "array", "items": {"enum": ["admin", "guest"]}, "maxItems": 2}
+        },
+        "additionalProperties": false
+    })).unwrap();
+    schemas.add("user:bot:", &serde_json::json!(true)).unwrap();
+    assert!(schemas.add("broken:", &serde_json::json!({"pattern": "("})).is_err());
+
+    assert!(schemas.check("user:1", r#"{"name": "ann", "age": 30, "tags": ["admin"]}"#).is_ok());
+    assert!(schemas.check("user:bot:1", "42").is_ok());
+    assert!(schemas.check("other", "not even JSON").is_ok());
+
+    let Err(KopperError::Rejected(problems)) = schemas.check("user:2", r#"{"age": -1.5, "tags": ["root"], "extra": 1}"#) else {
+        panic!("Value should be rejected");
+    };
+    assert!(problems.contains("/: name is required"), "{problems}");
+    assert!(problems.contains("/age: expected integer, found number"), "{problems}");
+    assert!(problems.contains("/tags/0: \"root\" isn't one of the allowed values"), "{problems}");
+    assert!(problems.contains("/extra: nothing is allowed here"), "{problems}");
+    assert!(matches!(schemas.check("user:3", "{"), Err(KopperError::Rejected(_))));
+}
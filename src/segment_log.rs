@@ -0,0 +1,254 @@
+//! The MANIFEST - which segments make up the database, as an append-only log of
+//! segments added and removed. A crash can leave a compacted segment next to the
+//! one it replaces, or a half-written one the compaction never finished, and the
+//! directory alone can't tell those apart from segments holding data. Compaction
+//! commits the swap here before removing anything, so recovery knows.
+//!
+//! Layout: entries of `length: u32 | crc32: u32 | records`, where every record is
+//! `op | name length: u8 | name`. An entry is appended in one go - one cut short
+//! by a crash is the last one, and is dropped.
+
+use std::collections::{BTreeSet, HashSet};
+use std::sync::Arc;
+
+use crate::kopper::KopperError;
+use crate::store::{SegmentFile, SegmentStore};
+
+/// Kept in the store like a segment, but never parsed as one
+pub const NAME: &str = "MANIFEST";
+
+const ENTRY_HEADER_LEN: usize = 4 + 4;
+const ADDED: u8 = 1;
+const REMOVED: u8 = 2;
+
+/// Segments a MANIFEST names, as of its last complete entry
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Segments {
+    pub live: BTreeSet<String>,
+    /// Removed at some point, e.g. by compaction - if one is still there, it's
+    /// left over from a crash
+    pub removed: HashSet<String>,
+}
+
+pub struct SegmentLog {
+    file: Arc<dyn SegmentFile>,
+    /// End of the last complete entry
+    len: u64,
+    /// A failed append couldn't be undone, the file may end in part of it
+    torn: bool,
+}
+
+impl SegmentLog {
+    /// Opens the MANIFEST of `store`, creating it if there's none. Also returns
+    /// the segments it names, `None` if it's empty or damaged - the directory is
+    /// all there is to go by then, until [`SegmentLog::rewrite`].
+    pub fn open(store: &dyn SegmentStore) -> Result<(SegmentLog, Option<Segments>), KopperError> {
+        let file = store.create(NAME)?;
+        let mut contents = vec![0; file.len()? as usize];
+        file.read_at(&mut contents, 0)?;
+
+        let (segments, at) = parse(&contents);
+        let Some(segments) = segments else {
+            tracing::warn!("{NAME} is damaged at byte {at}, going by the segments found instead");
+            return Ok((SegmentLog { file, len: contents.len() as u64, torn: false }, None));
+        };
+
+        if at < contents.len() {
+            tracing::warn!("Dropping an incomplete entry at the end of {NAME}");
+            file.truncate(at as u64)?;
+        }
+        let log = SegmentLog { file, len: at as u64, torn: false };
+        Ok((log, (at > 0).then_some(segments)))
+    }
+
+    /// Appends an entry recording segments `added` and `removed`, not synced yet.
+    /// One that fails is cut off again, so the next one doesn't land after it.
+    pub fn append(&mut self, added: &[String], removed: &[String]) -> Result<(), KopperError> {
+        if self.torn {
+            self.file.truncate(self.len)?;
+            self.torn = false;
+        }
+
+        let mut records = Vec::new();
+        for (op, names) in [(ADDED, added), (REMOVED, removed)] {
+            for name in names {
+                records.push(op);
+                records.push(name.len() as u8);
+                records.extend_from_slice(name.as_bytes());
+            }
+        }
+        let mut entry = Vec::with_capacity(ENTRY_HEADER_LEN + records.len());
+        entry.extend_from_slice(&(records.len() as u32).to_le_bytes());
+        entry.extend_from_slice(&crc32fast::hash(&records).to_le_bytes());
+        entry.extend_from_slice(&records);
+
+        if let Err(err) = self.file.append(&entry) {
+            self.undo();
+            return Err(err);
+        }
+        self.len += entry.len() as u64;
+        Ok(())
+    }
+
+    /// Like [`SegmentLog::append`], returning once the entry and all before it
+    /// survive a crash. If they can't be synced, the entry is cut off again.
+    pub fn commit(&mut self, added: &[String], removed: &[String]) -> Result<(), KopperError> {
+        let before = self.len;
+        self.append(added, removed)?;
+        if let Err(err) = self.file.sync() {
+            self.len = before;
+            self.undo();
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Segments the MANIFEST names now, read back from the file. `None` like
+    /// for [`SegmentLog::open`].
+    pub fn segments(&self) -> Result<Option<Segments>, KopperError> {
+        let mut contents = vec![0; self.len as usize];
+        self.file.read_at(&mut contents, 0)?;
+        Ok(match parse(&contents) {
+            (Some(segments), at) if at > 0 => Some(segments),
+            _ => None,
+        })
+    }
+
+    pub fn sync(&self) -> Result<(), KopperError> {
+        self.file.sync()
+    }
+
+    /// Starts over with a single entry naming the `live` segments, synced. A
+    /// crash in between leaves the MANIFEST empty, as if there was none.
+    pub fn rewrite(&mut self, live: &[String]) -> Result<(), KopperError> {
+        self.reset()?;
+        self.commit(live, &[])
+    }
+
+    /// Empties the MANIFEST, so segments are found by listing them until the
+    /// next [`SegmentLog::rewrite`]
+    pub fn reset(&mut self) -> Result<(), KopperError> {
+        self.file.truncate(0)?;
+        self.file.sync()?;
+        self.len = 0;
+        self.torn = false;
+        Ok(())
+    }
+
+    fn undo(&mut self) {
+        if let Err(err) = self.file.truncate(self.len) {
+            tracing::warn!("Can't undo a failed append to {NAME}: {err}");
+            self.torn = true;
+        }
+    }
+}
+
+/// Segments named by the complete entries of `contents`, and where they end.
+/// `None` if an entry before the last is damaged, with where it starts.
+fn parse(contents: &[u8]) -> (Option<Segments>, usize) {
+    let mut segments = Segments::default();
+    let mut at = 0;
+    while at < contents.len() {
+        let Some(header) = contents.get(at..at + ENTRY_HEADER_LEN) else {
+            break;
+        };
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let checksum = u32::from_le_bytes(header[4..].try_into().unwrap());
+        let Some(records) = contents.get(at + ENTRY_HEADER_LEN..at + ENTRY_HEADER_LEN + len) else {
+            break;
+        };
+
+        let end = at + ENTRY_HEADER_LEN + len;
+        if crc32fast::hash(records) != checksum || !replay(records, &mut segments) {
+            // Only the last entry can be torn, a bad one before others was damaged
+            if end < contents.len() {
+                return (None, at);
+            }
+            break;
+        }
+        at = end;
+    }
+    (Some(segments), at)
+}
+
+/// Applies the records of an entry to `segments`, false if they don't parse
+fn replay(mut records: &[u8], segments: &mut Segments) -> bool {
+    while let [op, len, rest @ ..] = records {
+        let Some(name) = rest.get(..*len as usize).and_then(|name| std::str::from_utf8(name).ok()) else {
+            return false;
+        };
+        match *op {
+            ADDED => {
+                segments.removed.remove(name);
+                segments.live.insert(name.to_owned());
+            },
+            REMOVED => {
+                segments.live.remove(name);
+                segments.removed.insert(name.to_owned());
+            },
+            _ => return false,
+        }
+        records = &rest[*len as usize..];
+    }
+    records.is_empty()
+}
+
+/// TESTS
+
+#[test]
+fn test_segments_are_replayed_in_order() {
+    use crate::store::MemoryStore;
+
+    let store = MemoryStore::new();
+    let (mut log, segments) = SegmentLog::open(&store).unwrap();
+    assert!(segments.is_none());
+    log.rewrite(&["0_0".to_owned()]).unwrap();
+    log.append(&["1_0".to_owned()], &[]).unwrap();
+    log.commit(&["0_1".to_owned()], &["0_0".to_owned()]).unwrap();
+
+    let (_, segments) = SegmentLog::open(&store).unwrap();
+    let segments = segments.unwrap();
+    assert_eq!(segments.live, BTreeSet::from(["0_1".to_owned(), "1_0".to_owned()]));
+    assert_eq!(segments.removed, HashSet::from(["0_0".to_owned()]));
+}
+
+#[test]
+fn test_torn_last_entry_is_dropped() {
+    use crate::store::MemoryStore;
+
+    let store = MemoryStore::new();
+    let (mut log, _) = SegmentLog::open(&store).unwrap();
+    log.rewrite(&["0_0".to_owned()]).unwrap();
+    let len = log.len;
+    log.append(&["1_0".to_owned()], &[]).unwrap();
+    let file = store.create(NAME).unwrap();
+    file.truncate(log.len - 2).unwrap();
+
+    let (mut log, segments) = SegmentLog::open(&store).unwrap();
+    assert_eq!(segments.unwrap().live, BTreeSet::from(["0_0".to_owned()]));
+    assert_eq!(file.len().unwrap(), len);
+
+    // Later entries follow the complete ones
+    log.append(&["1_0".to_owned()], &[]).unwrap();
+    assert_eq!(SegmentLog::open(&store).unwrap().1.unwrap().live.len(), 2);
+}
+
+#[test]
+fn test_damaged_log_is_not_trusted() {
+    use crate::store::MemoryStore;
+
+    let store = MemoryStore::new();
+    let (mut log, _) = SegmentLog::open(&store).unwrap();
+    log.rewrite(&["0_0".to_owned()]).unwrap();
+    log.append(&["1_0".to_owned()], &[]).unwrap();
+
+    // Flips a byte of the first entry's records
+    let file = store.create(NAME).unwrap();
+    let mut contents = vec![0; file.len().unwrap() as usize];
+    file.read_at(&mut contents, 0).unwrap();
+    contents[ENTRY_HEADER_LEN + 2] ^= 1;
+    file.truncate(0).unwrap();
+    file.append(&contents).unwrap();
+
+    assert!(SegmentLog::open(&store).unwrap().1.is_none());
+}
@@ -0,0 +1,79 @@
+//! Sets of values, see [`Kopper::sadd`](crate::kopper::Kopper::sadd). Changed a
+//! member at a time, see [`chain`] - a record lists `op | member` for every
+//! member added or removed, `op` being `+` or `-`, or added for the whole set.
+//! A set reads as a JSON array of its members, in order.
+
+use std::collections::BTreeSet;
+
+use crate::chain::{self, Header, Kind};
+use crate::kopper::KopperError;
+
+const ADDED: u8 = b'+';
+const REMOVED: u8 = b'-';
+
+/// Record of the whole set of `members`
+pub fn full<'a>(members: impl IntoIterator<Item = &'a String>) -> Vec<u8> {
+    let mut record = chain::full(Kind::Set);
+    for member in members {
+        record.push(ADDED);
+        chain::push(&mut record, member);
+    }
+    record
+}
+
+/// Record of `added` and `removed` members, changing the set recorded at
+/// `offset` of the same segment - `len` long, with a header `previous`
+pub fn delta(offset: u64, len: usize, previous: Header, added: &[&str], removed: &[&str]) -> Vec<u8> {
+    let mut record = chain::delta(Kind::Set, offset, len, previous);
+    for (op, members) in [(ADDED, added), (REMOVED, removed)] {
+        for member in members {
+            record.push(op);
+            chain::push(&mut record, member);
+        }
+    }
+    record
+}
+
+/// Members of the set whose newest record is `record`, see [`chain::records`]
+pub fn members(record: Vec<u8>, read: impl FnMut(u64, usize) -> Result<Vec<u8>, KopperError>) -> Result<BTreeSet<String>, KopperError> {
+    let mut members = BTreeSet::new();
+    for record in chain::records(Kind::Set, record, read)? {
+        let mut ops = chain::body(&record);
+        while let [op, rest @ ..] = ops {
+            ops = rest;
+            let member = chain::take(&mut ops)?;
+            match *op {
+                ADDED => members.insert(member.to_owned()),
+                REMOVED => members.remove(member),
+                _ => return Err(chain::damaged()),
+            };
+        }
+    }
+    Ok(members)
+}
+
+/// How a set reads
+pub fn to_json(members: &BTreeSet<String>) -> String {
+    // Serializing strings can't fail
+    serde_json::to_string(members).unwrap()
+}
+
+/// TESTS
+
+#[test]
+fn test_changes_apply_over_the_whole_set() {
+    let mut segment = vec![full(&BTreeSet::from(["a".to_owned(), "b".to_owned()]))];
+    segment.push(delta(0, segment[0].len(), Header::Full, &["c", "d"], &["a"]));
+    let previous = chain::header(&segment[1]).unwrap().1;
+    segment.push(delta(1, segment[1].len(), previous, &["a"], &["d", "missing"]));
+
+    // Offsets stand in for where the records are
+    let read = |offset: u64, len: usize| {
+        assert_eq!(segment[offset as usize].len(), len);
+        Ok(segment[offset as usize].clone())
+    };
+    let set = members(segment[2].clone(), read).unwrap();
+    assert_eq!(set, BTreeSet::from(["a".to_owned(), "b".to_owned(), "c".to_owned()]));
+    assert_eq!(to_json(&set), r#"["a","b","c"]"#);
+    assert!(members(b"\xF9D not a header".to_vec(), read).is_err());
+}
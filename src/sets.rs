@@ -0,0 +1,102 @@
+//! Routes for sets, see [`Kopper::sadd`]. Members are added and removed one at
+//! a time, and read back all at once or checked one by one.
+
+use rocket::State;
+use rocket::serde::json::Json;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use kopperdb::kopper::{Kopper, KopperError};
+
+#[derive(Serialize, ToSchema)]
+pub struct ChangeCountResponse {
+    /// Members added or removed, 0 if the set had or lacked the member already, or on failure
+    changed: usize,
+    /// "OK" on success, description of the problem otherwise
+    error: String
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct MembersResponse {
+    /// In order, none if there's no such set
+    members: Vec<String>,
+    /// "OK" on success, description of the problem otherwise
+    error: String
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct IsMemberResponse {
+    member: bool,
+    /// "OK" on success, description of the problem otherwise
+    error: String
+}
+
+fn changed(changed: Result<usize, KopperError>) -> Json<ChangeCountResponse> {
+    Json(match changed {
+        Ok(changed) => ChangeCountResponse { changed, error: "OK".to_string() },
+        Err(err) => ChangeCountResponse { changed: 0, error: format!("Error while writing! : {err}") },
+    })
+}
+
+#[utoipa::path(
+    post,
+    path = "/sadd/{key}/{member}",
+    tag = "sets",
+    params(
+        ("key" = String, Path, description = "Key of the set, created if there's none"),
+        ("member" = String, Path, description = "Member to add")
+    ),
+    responses((status = 200, description = "Result of the addition", body = ChangeCountResponse))
+)]
+#[post("/sadd/<key>/<member>")]
+pub fn sadd(key: &str, member: &str, db: &State<Kopper>) -> Json<ChangeCountResponse> {
+    changed(db.sadd(key, &[member]))
+}
+
+#[utoipa::path(
+    post,
+    path = "/srem/{key}/{member}",
+    tag = "sets",
+    params(
+        ("key" = String, Path, description = "Key of the set, deleted once it's empty"),
+        ("member" = String, Path, description = "Member to remove")
+    ),
+    responses((status = 200, description = "Result of the removal", body = ChangeCountResponse))
+)]
+#[post("/srem/<key>/<member>")]
+pub fn srem(key: &str, member: &str, db: &State<Kopper>) -> Json<ChangeCountResponse> {
+    changed(db.srem(key, &[member]))
+}
+
+#[utoipa::path(
+    get,
+    path = "/smembers/{key}",
+    tag = "sets",
+    params(("key" = String, Path, description = "Key of the set")),
+    responses((status = 200, description = "Members of the set", body = MembersResponse))
+)]
+#[get("/smembers/<key>")]
+pub fn smembers(key: &str, db: &State<Kopper>) -> Json<MembersResponse> {
+    Json(match db.smembers(key) {
+        Ok(members) => MembersResponse { members: members.into_iter().collect(), error: "OK".to_string() },
+        Err(err) => MembersResponse { members: Vec::new(), error: format!("Error while reading! : {err}") },
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/sismember/{key}/{member}",
+    tag = "sets",
+    params(
+        ("key" = String, Path, description = "Key of the set"),
+        ("member" = String, Path, description = "Member to look for")
+    ),
+    responses((status = 200, description = "Whether the member is in the set", body = IsMemberResponse))
+)]
+#[get("/sismember/<key>/<member>")]
+pub fn sismember(key: &str, member: &str, db: &State<Kopper>) -> Json<IsMemberResponse> {
+    Json(match db.sismember(key, member) {
+        Ok(member) => IsMemberResponse { member, error: "OK".to_string() },
+        Err(err) => IsMemberResponse { member: false, error: format!("Error while reading! : {err}") },
+    })
+}
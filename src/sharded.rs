@@ -0,0 +1,167 @@
+//! Keyspace split by key hash across independent [`Kopper`] instances, each with
+//! its own directory, lock, active segment and compactor, so writes to different
+//! shards don't wait for each other.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::iter::Peekable;
+
+use crate::kopper::{Kopper, KopperError, Scan};
+
+/// Holds the number of shards, which can't change without moving keys around
+const SHARDS_FILE: &str = ".shards";
+
+#[derive(Clone)]
+pub struct ShardedKopper {
+    shards: Vec<Kopper>,
+    path: String
+}
+
+/// Shard `key` belongs to. CRC32 is stable across builds and platforms,
+/// unlike the standard library's hashers.
+fn shard_of(key: &str, shards: usize) -> usize {
+    crc32fast::hash(key.as_bytes()) as usize % shards
+}
+
+impl ShardedKopper {
+    /// Opens `shards` databases in subdirectories of `path`, creating them if needed.
+    /// A directory is always opened with the number of shards it was created with.
+    pub fn create(path: &str, shards: usize, segment_size: usize) -> Result<Self, KopperError> {
+        if shards == 0 {
+            return Err(KopperError::InternalError(anyhow::anyhow!("A database needs at least one shard")));
+        }
+
+        fs::create_dir_all(path)?;
+        let shards_file = format!("{path}/{SHARDS_FILE}");
+        match fs::read_to_string(&shards_file) {
+            Ok(existing) if existing.trim().parse::<usize>()? != shards => {
+                return Err(KopperError::InternalError(anyhow::anyhow!(
+                    "{path} has {} shards, can't open it with {shards}", existing.trim()
+                )));
+            },
+            Ok(_) => {},
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => fs::write(&shards_file, format!("{shards}\n"))?,
+            Err(err) => return Err(err.into()),
+        }
+
+        let shards = (0..shards)
+            .map(|shard| Kopper::create(&format!("{path}/{shard}"), segment_size))
+            .collect::<Result<_, _>>()?;
+        Ok(ShardedKopper { shards, path: path.to_owned() })
+    }
+
+    fn shard(&self, key: &str) -> &Kopper {
+        &self.shards[shard_of(key, self.shards.len())]
+    }
+
+    pub fn path(&self) -> String {
+        self.path.clone()
+    }
+
+    /// The databases behind the shards, in shard order
+    pub fn shards(&self) -> &[Kopper] {
+        &self.shards
+    }
+
+    pub fn read(&self, key: &str) -> Result<String, KopperError> {
+        self.shard(key).read(key)
+    }
+
+    /// Writes to the shard of `key`, returning the total size of all shards
+    pub fn write(&self, key: &str, value: &str) -> Result<usize, KopperError> {
+        self.shard(key).write(key, value)?;
+        Ok(self.size())
+    }
+
+    /// Writes every entry to its shard, one batch per shard. Batches of different
+    /// shards are independent - if one fails, the ones before it are still written.
+    pub fn write_batch(&self, entries: &[(&str, &str)]) -> Result<usize, KopperError> {
+        let mut batches: BTreeMap<usize, Vec<(&str, &str)>> = BTreeMap::new();
+        for (key, value) in entries {
+            batches.entry(shard_of(key, self.shards.len())).or_default().push((key, value));
+        }
+
+        for (shard, batch) in batches {
+            self.shards[shard].write_batch(&batch)?;
+        }
+        Ok(self.size())
+    }
+
+    pub fn delete(&self, key: &str) -> Result<usize, KopperError> {
+        self.shard(key).delete(key)?;
+        Ok(self.size())
+    }
+
+    /// Number of keys in all shards
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(Kopper::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Size of all shards on disk
+    pub fn size(&self) -> usize {
+        self.shards.iter().map(Kopper::size).sum()
+    }
+
+    /// Every entry in key order. Each shard's part is consistent on its own,
+    /// but the shards are scanned one after another, not at a single point in time.
+    pub fn scan(&self) -> Result<ShardedScan, KopperError> {
+        let scans = self.shards.iter()
+            .map(|shard| shard.scan().map(Iterator::peekable))
+            .collect::<Result<_, _>>()?;
+        Ok(ShardedScan { scans })
+    }
+
+    /// Queues compaction of every sealed segment of every shard, returning how many were queued
+    pub fn compact(&self) -> Result<usize, KopperError> {
+        self.shards.iter().map(Kopper::compact).sum()
+    }
+
+    pub fn wait_for_compactions(&self) -> Result<(), KopperError> {
+        self.shards.iter().try_for_each(Kopper::wait_for_compactions)
+    }
+
+    pub fn sync(&self) -> Result<(), KopperError> {
+        self.shards.iter().try_for_each(Kopper::sync)
+    }
+
+    /// Closes every shard, even if closing one of them fails. Returns the first error.
+    pub fn close(&self) -> Result<(), KopperError> {
+        let mut result = Ok(());
+        for shard in &self.shards {
+            result = result.and(shard.close());
+        }
+        result
+    }
+}
+
+/// Entries of all shards merged in key order, created by [`ShardedKopper::scan`]
+pub struct ShardedScan {
+    scans: Vec<Peekable<Scan>>
+}
+
+impl Iterator for ShardedScan {
+    type Item = Result<(String, String), KopperError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Keys live in exactly one shard, so there are no ties. Errors come first.
+        let next = self.scans.iter_mut()
+            .enumerate()
+            .filter_map(|(shard, scan)| scan.peek().map(|entry| (shard, entry.as_ref().ok().map(|(key, _)| key))))
+            .min_by(|(_, a), (_, b)| match (a, b) {
+                (None, _) => std::cmp::Ordering::Less,
+                (_, None) => std::cmp::Ordering::Greater,
+                (Some(a), Some(b)) => a.cmp(b),
+            })
+            .map(|(shard, _)| shard)?;
+        self.scans[next].next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.scans.iter().map(|scan| scan.size_hint().0).sum();
+        (len, Some(len))
+    }
+}
@@ -1,62 +1,497 @@
+use hdrhistogram::Histogram;
+use crate::clock::{Clock, SystemClock};
+use crate::metrics::MetricsSink;
 use plotters::prelude::*;
-use std::{error::Error, sync::{self, Mutex, mpsc::channel, Arc}};
+use std::{error::Error, collections::{BTreeMap, VecDeque}, sync::{self, Mutex, mpsc::channel, Arc}};
+use std::time::Duration;
+use std::{fs, io, path::Path};
+use serde::{Deserialize, Serialize};
+
+pub use crate::metrics::{Label, Operation, Stat};
 
 pub struct StatsAggregator {
-    receiver: sync::mpsc::Receiver<Stat>,
+    receiver: sync::mpsc::Receiver<(u64, Stat)>,
     counters: Arc<Counters>
 }
 
+/// Number of samples kept per series if not configured otherwise
+pub const DEFAULT_RETENTION: usize = 100_000;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Sample {
+    /// When the sample was taken, in milliseconds since the Unix epoch
+    pub time: u64,
+    pub value: u128,
+}
+
+/// Fixed-size buffer of the most recent samples of a series. Once full,
+/// every new sample replaces the oldest one, so memory use stays bounded.
+#[derive(Clone)]
+pub struct Samples {
+    buffer: VecDeque<Sample>,
+    capacity: usize,
+}
+
+impl Samples {
+    pub fn new(capacity: usize) -> Self {
+        Samples { buffer: VecDeque::new(), capacity: capacity.max(1) }
+    }
+
+    pub fn push(&mut self, sample: Sample) {
+        if self.buffer.len() == self.capacity {
+            self.buffer.pop_front();
+        }
+        self.buffer.push_back(sample);
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Samples from the oldest to the newest
+    pub fn iter(&self) -> impl Iterator<Item = &Sample> {
+        self.buffer.iter()
+    }
+
+    pub fn to_vec(&self) -> Vec<Sample> {
+        self.buffer.iter().copied().collect()
+    }
+
+    /// Samples taken at or after `time`, from the oldest to the newest
+    pub fn since(&self, time: u64) -> Vec<Sample> {
+        // Samples are in time order, so the window is a suffix of the buffer
+        let start = self.buffer.partition_point(|sample| sample.time < time);
+        self.buffer.range(start..).copied().collect()
+    }
+}
+
+/// Distribution summary of a series
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Percentiles {
+    pub count: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub p999: u64,
+}
+
+/// A single metric: HDR histogram of every sample ever recorded, for accurate
+/// percentiles in constant memory, plus the most recent samples for charts.
+#[derive(Clone)]
+pub struct Series {
+    samples: Samples,
+    histogram: Histogram<u64>,
+}
+
+impl Series {
+    pub fn new(retention: usize) -> Self {
+        Series {
+            samples: Samples::new(retention),
+            // 3 significant digits, auto-resizing - can't fail
+            histogram: Histogram::new(3).unwrap(),
+        }
+    }
+
+    pub fn record(&mut self, time: u64, value: u128) {
+        self.samples.push(Sample { time, value });
+        // Histogram grows to fit the value, recording can only fail for u64::MAX itself
+        let _ = self.histogram.record(value.min(u64::MAX as u128 - 1) as u64);
+    }
+
+    /// Most recent samples, see [`Samples`]
+    pub fn samples(&self) -> &Samples {
+        &self.samples
+    }
+
+    pub fn percentiles(&self) -> Percentiles {
+        let histogram = &self.histogram;
+        Percentiles {
+            count: histogram.len(),
+            p50: histogram.value_at_quantile(0.5),
+            p90: histogram.value_at_quantile(0.9),
+            p99: histogram.value_at_quantile(0.99),
+            p999: histogram.value_at_quantile(0.999),
+        }
+    }
+}
+
+/// Seconds of operation counts kept by [`Rates`]
+pub const RATE_RETENTION_SECS: u64 = 60 * 60;
+
+/// Operations completed within one second
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Bucket {
+    /// Seconds since the Unix epoch
+    pub second: u64,
+    pub ops: u64,
+    pub errors: u64,
+}
+
+/// Operation and error counts per second over the last [`RATE_RETENTION_SECS`]
+#[derive(Clone, Default)]
+pub struct Rates {
+    buckets: VecDeque<Bucket>,
+}
+
+impl Rates {
+    pub fn record(&mut self, time: u64, failed: bool) {
+        let second = time / 1000;
+        match self.buckets.back_mut() {
+            Some(bucket) if bucket.second >= second => {
+                bucket.ops += 1;
+                bucket.errors += failed as u64;
+            },
+            _ => self.buckets.push_back(Bucket { second, ops: 1, errors: failed as u64 }),
+        }
+
+        while self.buckets.front().is_some_and(|bucket| bucket.second + RATE_RETENTION_SECS <= second) {
+            self.buckets.pop_front();
+        }
+    }
+
+    /// One bucket for every second from `start` up to `end`, both in milliseconds since
+    /// the epoch. Seconds nothing happened in are included as empty buckets.
+    pub fn between(&self, start: u64, end: u64) -> Vec<Bucket> {
+        let mut recorded = self.buckets.iter().skip_while(|bucket| bucket.second < start / 1000).peekable();
+
+        (start / 1000..=end / 1000)
+            .map(|second| match recorded.next_if(|bucket| bucket.second == second) {
+                Some(bucket) => *bucket,
+                None => Bucket { second, ..Default::default() },
+            })
+            .collect()
+    }
+
+    /// Counts of both rates added together
+    pub fn merged(&self, other: &Rates) -> Rates {
+        let mut buckets: BTreeMap<u64, Bucket> = BTreeMap::new();
+        for bucket in self.buckets.iter().chain(other.buckets.iter()) {
+            let merged = buckets.entry(bucket.second).or_insert(Bucket { second: bucket.second, ..Default::default() });
+            merged.ops += bucket.ops;
+            merged.errors += bucket.errors;
+        }
+        Rates { buckets: buckets.into_values().collect() }
+    }
+
+    /// Operations per second, one sample per second up to `now`. Displayed in [`Unit::OPS`].
+    pub fn throughput(&self, now: u64) -> Series {
+        let mut series = Series::new(RATE_RETENTION_SECS as usize);
+        if let Some(start) = self.buckets.front() {
+            for bucket in self.between(start.second * 1000, now) {
+                series.record(bucket.second * 1000, bucket.ops as u128);
+            }
+        }
+        series
+    }
+
+    /// Failed operations in basis points of all operations, one sample per second
+    /// anything happened in. Displayed in [`Unit::PERCENT`].
+    pub fn error_rate(&self) -> Series {
+        let mut series = Series::new(RATE_RETENTION_SECS as usize);
+        for bucket in &self.buckets {
+            series.record(bucket.second * 1000, (bucket.errors * 10_000 / bucket.ops) as u128);
+        }
+        series
+    }
+}
+
+/// How values of a series are displayed: divided by `divisor`, followed by `name`
+#[derive(Clone, Copy)]
+pub struct Unit {
+    pub name: &'static str,
+    pub divisor: u128,
+}
+
+impl Unit {
+    /// Latencies are recorded in nanoseconds
+    pub const MICROS: Unit = Unit { name: "us", divisor: 1000 };
+    /// Sizes are recorded in bytes
+    pub const KB: Unit = Unit { name: "KB", divisor: 1000 };
+    /// Durations of longer tasks, recorded in nanoseconds
+    pub const MILLIS: Unit = Unit { name: "ms", divisor: 1_000_000 };
+    pub const OPS: Unit = Unit { name: "ops/s", divisor: 1 };
+    pub const COUNT: Unit = Unit { name: "", divisor: 1 };
+    /// Rates are recorded in basis points
+    pub const PERCENT: Unit = Unit { name: "%", divisor: 100 };
+}
+
 pub struct Counters {
-    pub read_counter: Mutex<Vec<u128>>,
-    pub write_counter: Mutex<Vec<u128>>,
-    pub size: Mutex<Vec<u128>>,
+    pub read_counter: Mutex<Series>,
+    pub write_counter: Mutex<Series>,
+    pub size: Mutex<Series>,
+    pub requests: Mutex<BTreeMap<Label, Series>>,
+    pub read_rates: Mutex<Rates>,
+    pub write_rates: Mutex<Rates>,
+    pub compaction_reclaimed: Mutex<Series>,
+    pub compaction_duration: Mutex<Series>,
+    pub segments: Mutex<Series>,
+    pub keys: Mutex<Series>,
+    pub shadow_primary: Mutex<Series>,
+    pub shadow_secondary: Mutex<Series>,
+    /// Stored size of every compressed value, in basis points of its plain size
+    pub compression: Mutex<Series>,
+    /// Corrupt or mismatched keys found by every verification
+    pub discrepancies: Mutex<Series>,
+    pub open_segments: Mutex<Series>,
+    pub compactor_queue: Mutex<Series>,
+    /// Time spent waiting for the lock of the database between samples
+    pub lock_wait: Mutex<Series>,
+
+    /// Capacity of every series, including the ones created per label
+    retention: usize,
 }
 
 impl Counters {
-    pub fn new() -> Self {
+    fn new(retention: usize) -> Self {
         Counters {
-            read_counter: Mutex::default(),
-            write_counter: Mutex::default(),
-            size: Mutex::default()
+            read_counter: Mutex::new(Series::new(retention)),
+            write_counter: Mutex::new(Series::new(retention)),
+            size: Mutex::new(Series::new(retention)),
+            requests: Mutex::default(),
+            read_rates: Mutex::default(),
+            write_rates: Mutex::default(),
+            compaction_reclaimed: Mutex::new(Series::new(retention)),
+            compaction_duration: Mutex::new(Series::new(retention)),
+            segments: Mutex::new(Series::new(retention)),
+            keys: Mutex::new(Series::new(retention)),
+            shadow_primary: Mutex::new(Series::new(retention)),
+            shadow_secondary: Mutex::new(Series::new(retention)),
+            compression: Mutex::new(Series::new(retention)),
+            discrepancies: Mutex::new(Series::new(retention)),
+            open_segments: Mutex::new(Series::new(retention)),
+            compactor_queue: Mutex::new(Series::new(retention)),
+            lock_wait: Mutex::new(Series::new(retention)),
+            retention,
         }
     }
+
+    /// Request latencies of all series matching `route` and `status`, `None` matching anything,
+    /// merged into one series
+    pub fn requests_matching(&self, route: Option<&str>, status: Option<u16>) -> Series {
+        let mut merged = Series::new(self.retention);
+        let mut samples = Vec::new();
+
+        for (_, series) in self.requests.lock().unwrap().iter()
+            .filter(|(label, _)| route.is_none_or(|route| label.route == route))
+            .filter(|(label, _)| status.is_none_or(|status| label.status == status)) {
+
+            // Auto-resizing histograms accept any value - can't fail
+            merged.histogram.add(&series.histogram).unwrap();
+            samples.extend(series.samples.iter().copied());
+        }
+
+        // Keep the merged samples in time order, newest ones if they don't all fit
+        samples.sort_by_key(|sample| sample.time);
+        for sample in samples {
+            merged.samples.push(sample);
+        }
+        merged
+    }
 }
 
-impl StatsAggregator {
-    pub fn run(&mut self) {
-        loop {
-            match self.receiver.recv() {
-                Ok(stat) => match stat {
-                    Stat::ReadTime(time) => self.counters.read_counter.lock().unwrap().push(time),
-                    Stat::WriteTime(time) => self.counters.write_counter.lock().unwrap().push(time),
-                    Stat::Size(size) => self.counters.size.lock().unwrap().push(size),
-                }
-
-                // Sender disconnected - stop the thread
-                Err(_) => break
+/// Persisted form of a [`Series`]
+#[derive(Serialize, Deserialize, Default)]
+struct SeriesSnapshot {
+    /// Recorded histogram values with their counts
+    histogram: Vec<(u64, u64)>,
+    /// Most recent samples as `(time, value)`
+    samples: Vec<(u64, u128)>,
+}
+
+/// Only this many of the most recent samples of each series are persisted, to keep the file small
+const PERSISTED_SAMPLES: usize = 10_000;
+
+impl Series {
+    fn snapshot(&self) -> SeriesSnapshot {
+        let skip = self.samples.len().saturating_sub(PERSISTED_SAMPLES);
+        SeriesSnapshot {
+            histogram: self.histogram.iter_recorded().map(|value| (value.value_iterated_to(), value.count_at_value())).collect(),
+            samples: self.samples.iter().skip(skip).map(|sample| (sample.time, sample.value)).collect(),
+        }
+    }
+
+    /// Adds `snapshot` in front of what's already recorded
+    fn restore(&mut self, snapshot: &SeriesSnapshot) {
+        for (value, count) in &snapshot.histogram {
+            let _ = self.histogram.record_n(*value, *count);
+        }
+
+        let capacity = self.samples.capacity;
+        let current = std::mem::replace(&mut self.samples, Samples::new(capacity));
+        for (time, value) in &snapshot.samples {
+            self.samples.push(Sample { time: *time, value: *value });
+        }
+        for sample in current.iter() {
+            self.samples.push(*sample);
+        }
+    }
+}
+
+/// Everything [`Counters`] aggregated, as stored by [`Counters::save`]
+#[derive(Serialize, Deserialize, Default)]
+struct Snapshot {
+    series: BTreeMap<String, SeriesSnapshot>,
+    /// Per-second buckets as `(second, ops, errors)`
+    rates: BTreeMap<String, Vec<(u64, u64, u64)>>,
+}
+
+impl Counters {
+    /// Series kept under a fixed name, without the per-label ones
+    fn named_series(&self) -> [(&'static str, &Mutex<Series>); 14] {
+        [
+            ("read", &self.read_counter),
+            ("write", &self.write_counter),
+            ("size", &self.size),
+            ("compaction_reclaimed", &self.compaction_reclaimed),
+            ("compaction_duration", &self.compaction_duration),
+            ("segments", &self.segments),
+            ("keys", &self.keys),
+            ("shadow_primary", &self.shadow_primary),
+            ("shadow_secondary", &self.shadow_secondary),
+            ("compression", &self.compression),
+            ("discrepancies", &self.discrepancies),
+            ("open_segments", &self.open_segments),
+            ("compactor_queue", &self.compactor_queue),
+            ("lock_wait", &self.lock_wait),
+        ]
+    }
+
+    fn named_rates(&self) -> [(&'static str, &Mutex<Rates>); 2] {
+        [("read", &self.read_rates), ("write", &self.write_rates)]
+    }
+
+    /// Writes everything aggregated so far to `path`. The file is replaced
+    /// atomically, so a crash mid-save leaves the previous snapshot intact.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut snapshot = Snapshot::default();
+        for (name, series) in self.named_series() {
+            snapshot.series.insert(name.to_owned(), series.lock().unwrap().snapshot());
+        }
+        for (label, series) in self.requests.lock().unwrap().iter() {
+            snapshot.series.insert(format!("request:{}:{}", label.status, label.route), series.snapshot());
+        }
+        for (name, rates) in self.named_rates() {
+            let buckets = rates.lock().unwrap().buckets.iter().map(|bucket| (bucket.second, bucket.ops, bucket.errors)).collect();
+            snapshot.rates.insert(name.to_owned(), buckets);
+        }
+
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, serde_json::to_vec(&snapshot)?)?;
+        fs::rename(temporary, path)
+    }
+
+    /// Restores a snapshot written by [`Counters::save`]. Restored samples go before
+    /// the ones recorded since startup.
+    pub fn load(&self, path: &Path) -> io::Result<()> {
+        let snapshot: Snapshot = serde_json::from_slice(&fs::read(path)?)?;
+
+        for (name, series) in self.named_series() {
+            if let Some(saved) = snapshot.series.get(name) {
+                series.lock().unwrap().restore(saved);
             }
         }
+
+        let mut requests = self.requests.lock().unwrap();
+        for (name, saved) in &snapshot.series {
+            let Some((status, route)) = name.strip_prefix("request:").and_then(|label| label.split_once(':')) else {
+                continue;
+            };
+            let Ok(status) = status.parse() else {
+                continue;
+            };
+            requests.entry(Label { route: route.to_owned(), status })
+                .or_insert_with(|| Series::new(self.retention))
+                .restore(saved);
+        }
+
+        for (name, rates) in self.named_rates() {
+            let Some(saved) = snapshot.rates.get(name) else {
+                continue;
+            };
+            let saved = Rates { buckets: saved.iter().map(|(second, ops, errors)| Bucket { second: *second, ops: *ops, errors: *errors }).collect() };
+            let mut rates = rates.lock().unwrap();
+            *rates = saved.merged(&rates);
+        }
+        Ok(())
     }
 }
 
-pub enum Stat {
-    ReadTime(u128),
-    WriteTime(u128),
-    Size(u128)
+impl StatsAggregator {
+    pub fn run(&mut self) {
+        // Sender disconnected - stop the thread
+        while let Ok((now, stat)) = self.receiver.recv() {
+            match stat {
+                Stat::ReadTime(time) => self.counters.read_counter.lock().unwrap().record(now, time),
+                Stat::WriteTime(time) => self.counters.write_counter.lock().unwrap().record(now, time),
+                Stat::Size(size) => self.counters.size.lock().unwrap().record(now, size),
+                Stat::Request(label, time) => self.counters.requests.lock().unwrap()
+                    .entry(label)
+                    .or_insert_with(|| Series::new(self.counters.retention))
+                    .record(now, time),
+                Stat::Completed(Operation::Read, failed) => self.counters.read_rates.lock().unwrap().record(now, failed),
+                Stat::Completed(Operation::Write, failed) => self.counters.write_rates.lock().unwrap().record(now, failed),
+                Stat::Compaction(report) => {
+                    self.counters.compaction_reclaimed.lock().unwrap().record(now, report.reclaimed_bytes as u128);
+                    self.counters.compaction_duration.lock().unwrap().record(now, report.duration.as_nanos());
+                    self.counters.segments.lock().unwrap().record(now, report.segments as u128);
+                },
+                Stat::Keys(keys) => self.counters.keys.lock().unwrap().record(now, keys),
+                Stat::ShadowWrite { primary, shadow } => {
+                    self.counters.shadow_primary.lock().unwrap().record(now, primary);
+                    self.counters.shadow_secondary.lock().unwrap().record(now, shadow);
+                },
+                Stat::Compression { plain, stored } => {
+                    if let Some(ratio) = (stored * 10_000).checked_div(plain) {
+                        self.counters.compression.lock().unwrap().record(now, ratio);
+                    }
+                },
+                Stat::Verification { corrupt, mismatched, .. } => self.counters.discrepancies.lock().unwrap().record(now, corrupt + mismatched),
+                Stat::Saturation { open_segments, compactor_queue, lock_wait, .. } => {
+                    self.counters.open_segments.lock().unwrap().record(now, open_segments);
+                    self.counters.compactor_queue.lock().unwrap().record(now, compactor_queue);
+                    self.counters.lock_wait.lock().unwrap().record(now, lock_wait);
+                },
+            }
+        }
+    }
 }
 
+#[derive(Clone)]
 pub struct Stats {
-    sender: sync::mpsc::Sender<Stat>,
+    sender: sync::mpsc::Sender<(u64, Stat)>,
     pub counters: Arc<Counters>,
+    clock: Arc<dyn Clock>,
+}
+
+/// In-process aggregation behind the `/stats` endpoints
+impl MetricsSink for Stats {
+    fn record(&self, stat: Stat) {
+        self.send(stat);
+    }
 }
 
 impl Stats {
     pub fn create() -> (Stats, StatsAggregator) {
+        Stats::with_retention(DEFAULT_RETENTION)
+    }
+
+    /// Like [`Stats::create`], keeping only the last `retention` samples of every series
+    pub fn with_retention(retention: usize) -> (Stats, StatsAggregator) {
+        Stats::with_clock(retention, Arc::new(SystemClock))
+    }
+
+    /// Like [`Stats::with_retention`], timestamping samples with `clock`
+    pub fn with_clock(retention: usize, clock: Arc<dyn Clock>) -> (Stats, StatsAggregator) {
         let (tx, rx) = channel();
-        let counters = Arc::new(Counters::new());
+        let counters = Arc::new(Counters::new(retention));
         (Stats {
             sender: tx,
-            counters: counters.clone()
+            counters: counters.clone(),
+            clock,
         },
         StatsAggregator {
             receiver: rx,
@@ -64,72 +499,184 @@ impl Stats {
         })
     }
 
+    /// Records `stat`, timestamped with the current time
     pub fn send(&self, stat: Stat) {
-        self.sender.send(stat).unwrap();
+        self.sender.send((self.clock.now_millis(), stat)).unwrap();
+    }
+
+    /// Current time of the clock samples are timestamped with, in milliseconds since the Unix epoch
+    pub fn now_millis(&self) -> u64 {
+        self.clock.now_millis()
     }
 }
 
-const OUT_FILE_NAME: &'static str = "stats.png";
-const RESOLUTION_QUALITY: usize = 4;
+const CHART_SIZE: (u32, u32) = (1024, 640);
 
-pub fn draw(data: &Vec<u128>, label: &str, unit: &str) -> Result<(), Box<dyn Error>> {
+/// Image formats charts can be rendered to
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ChartFormat {
+    Png,
+    Svg,
+}
 
-    // Find the biggest datapoint to use as height of graph
-    let max = match data.iter().max() {
-        Some(max) => *max,
-        None => 1,
+/// Formats an X axis position, in seconds relative to now
+fn format_ago(seconds: f64) -> String {
+    let ago = -seconds.round() as i64;
+    match ago {
+        _ if seconds > -0.05 => "now".to_string(),
+        ..=9 => format!("{seconds:.1}s"),
+        10..=119 => format!("-{ago}s"),
+        120..=7199 => format!("-{}m", ago / 60),
+        _ => format!("-{}h", ago / 3600),
+    }
+}
+
+/// Renders a chart of `series` over time into an image in memory. The X axis
+/// covers the last `window` up to `now`, or everything still retained if there's no window.
+pub fn render(series: &Series, label: &str, unit: Unit, window: Option<Duration>, now: u64, format: ChartFormat) -> Result<Vec<u8>, Box<dyn Error>> {
+    match format {
+        ChartFormat::Png => {
+            let (width, height) = CHART_SIZE;
+            let mut pixels = vec![0; width as usize * height as usize * 3];
+            draw(BitMapBackend::with_buffer(&mut pixels, CHART_SIZE).into_drawing_area(), series, label, unit, window, now)?;
+
+            let image = image::RgbImage::from_raw(width, height, pixels).ok_or("Bitmap size mismatch")?;
+            let mut png = Vec::new();
+            image.write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)?;
+            Ok(png)
+        },
+        ChartFormat::Svg => {
+            let mut svg = String::new();
+            draw(SVGBackend::with_string(&mut svg, CHART_SIZE).into_drawing_area(), series, label, unit, window, now)?;
+            Ok(svg.into_bytes())
+        },
+    }
+}
+
+fn draw<DB: DrawingBackend>(root: DrawingArea<DB, plotters::coord::Shift>, series: &Series, label: &str, unit: Unit, window: Option<Duration>, now: u64) -> Result<(), Box<dyn Error>>
+where DB::ErrorType: 'static {
+    let start = match window {
+        Some(window) => now.saturating_sub(window.as_millis() as u64),
+        None => series.samples().iter().next().map_or(now, |sample| sample.time),
     };
-    
-    // Setup bitmap
-    let root = BitMapBackend::new(
-        OUT_FILE_NAME, 
-        (640.max(RESOLUTION_QUALITY * data.len()) as u32,
-         640 as u32)).into_drawing_area();
+    let data = series.samples().since(start);
+
+    // Find the biggest datapoint to use as height of graph
+    let max = data.iter().map(|sample| sample.value).max().unwrap_or(1);
+
+    // Seconds relative to now, so the chart reads "x minutes ago"
+    let x_of = |time: u64| (time as f64 - now as f64) / 1000.0;
+    let x_start = x_of(start).min(-1.0);
 
     // Background
     root.fill(&WHITE)?;
 
-    // Calculate p50, p95, p99
-    let mut sorted = data.clone();
-    sorted.sort();
-    let p50: u128 = sorted[sorted.len() * 50 / 100] / 1000;
-    let p95 = sorted[sorted.len() * 95 / 100] / 1000;
-    let p99 = sorted[sorted.len() * 99 / 100] / 1000;
+    // Percentiles cover all samples, not just the charted ones
+    let Percentiles { p50, p90, p99, p999, .. } = series.percentiles();
+    let divisor = unit.divisor as u64;
+    let (p50, p90, p99, p999) = (p50 / divisor, p90 / divisor, p99 / divisor, p999 / divisor);
+    let unit_name = unit.name;
 
     // Create chart
     let mut chart = ChartBuilder::on(&root)
-        .set_label_area_size(LabelAreaPosition::Left, 40)
+        .set_label_area_size(LabelAreaPosition::Left, 60)
         .set_label_area_size(LabelAreaPosition::Bottom, 40)
-        .set_label_area_size(LabelAreaPosition::Right, 40)
-        .caption(format!("{label}, p50: {p50}{unit}, p95: {p95}{unit}, p99: {p99}{unit}"), ("sans-serif", 20))
-        .build_cartesian_2d(
-            (0usize..data.len()).into_segmented(), 
-            0u128..(max + 10))?;
+        .set_label_area_size(LabelAreaPosition::Right, 60)
+        .caption(format!("{label}, p50: {p50}{unit_name}, p90: {p90}{unit_name}, p99: {p99}{unit_name}, p999: {p999}{unit_name}"), ("sans-serif", 20))
+        .build_cartesian_2d(x_start..0.0, 0u128..(max + 10))?;
     
     chart
         .configure_mesh()
-        .y_label_formatter(&|x| format!("{}{}", x / 1000, unit))
+        .x_label_formatter(&|x| format_ago(*x))
+        .y_label_formatter(&|y| format!("{}{}", y / unit.divisor, unit.name))
         .draw()?;
 
-    chart.draw_series(
-        (0usize..) // range [0 to data.len() - 1]
-            .zip(data.iter())
-            .map(|(x, y)| {
+    // One point per sample, idle periods stay empty
+    chart.draw_series(data.iter().map(|sample| {
+        Circle::new((x_of(sample.time), sample.value), 2, RED.mix(0.5).filled())
+    }))?;
 
-        let x0 = SegmentValue::Exact(x);
-        let x1 = SegmentValue::Exact(x + 1);
+    // Backends only finish the image here - don't let a failure go silently
+    root.present()?;
+    Ok(())
+}
 
-        // Points: [bottom_left: (x, y), top_right: (x, y)]
-        let mut bar = Rectangle::new(
-            [(x0, 0), (x1, *y)], 
-            RED.mix(0.5).filled()
-        );
+/// TESTS
 
-        bar.set_margin(0, 0, 1, 1);
-        bar
-    }))?;
+#[test]
+fn test_samples_drop_oldest() {
+    let mut samples = Samples::new(3);
+    for time in 1..=5 {
+        samples.push(Sample { time, value: time as u128 * 10 });
+    }
 
-    // To avoid the IO failure being ignored silently, we manually call the present function
-    root.present().expect("Unable to write result to file");
-    Ok(())
-}
\ No newline at end of file
+    assert_eq!(samples.len(), 3);
+    let values: Vec<u128> = samples.iter().map(|sample| sample.value).collect();
+    assert_eq!(values, vec![30, 40, 50]);
+    assert_eq!(samples.since(4), vec![Sample { time: 4, value: 40 }, Sample { time: 5, value: 50 }]);
+}
+
+#[test]
+fn test_series_percentiles() {
+    let mut series = Series::new(10);
+    for sample in 1..=1000 {
+        series.record(sample as u64, sample);
+    }
+
+    let percentiles = series.percentiles();
+    assert_eq!(percentiles.count, 1000);
+    assert_eq!(percentiles.p50, 500);
+    assert_eq!(percentiles.p99, 990);
+    assert_eq!(series.samples().len(), 10);
+}
+
+#[test]
+fn test_rates_fill_idle_seconds() {
+    let mut rates = Rates::default();
+    rates.record(1_000, false);
+    rates.record(1_500, true);
+    rates.record(3_200, false);
+
+    assert_eq!(rates.between(1_000, 4_000), vec![
+        Bucket { second: 1, ops: 2, errors: 1 },
+        Bucket { second: 2, ops: 0, errors: 0 },
+        Bucket { second: 3, ops: 1, errors: 0 },
+        Bucket { second: 4, ops: 0, errors: 0 },
+    ]);
+}
+
+#[test]
+fn test_series_snapshot_roundtrip() {
+    let mut series = Series::new(10);
+    series.record(1, 100);
+    series.record(2, 200);
+
+    let mut restored = Series::new(10);
+    restored.record(3, 300);
+    restored.restore(&series.snapshot());
+
+    assert_eq!(restored.percentiles().count, 3);
+    let times: Vec<u64> = restored.samples().iter().map(|sample| sample.time).collect();
+    assert_eq!(times, vec![1, 2, 3]);
+}
+
+#[test]
+fn test_stats_are_timestamped_with_their_clock() {
+    use crate::clock::MockClock;
+    use std::time::UNIX_EPOCH;
+
+    let clock = Arc::new(MockClock::new(UNIX_EPOCH + Duration::from_secs(10)));
+    let (stats, mut aggregator) = Stats::with_clock(10, clock.clone());
+    stats.send(Stat::Completed(Operation::Read, false));
+    clock.advance(Duration::from_millis(2_500));
+    stats.send(Stat::Completed(Operation::Read, false));
+    stats.send(Stat::ReadTime(7));
+    let now = stats.now_millis();
+    drop(stats);
+    aggregator.run();
+
+    let times: Vec<u64> = aggregator.counters.read_counter.lock().unwrap().samples().iter().map(|sample| sample.time).collect();
+    assert_eq!(times, vec![12_500]);
+    let throughput: Vec<u128> = aggregator.counters.read_rates.lock().unwrap().throughput(now).samples().iter().map(|sample| sample.value).collect();
+    assert_eq!(throughput, vec![1, 0, 1]);
+}
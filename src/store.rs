@@ -0,0 +1,470 @@
+//! Where [`Kopper`](crate::kopper::Kopper) keeps its segments. The engine only
+//! ever creates segments, appends to the newest one, reads them at offsets and
+//! deletes them once compacted, so that's all a [`SegmentStore`] has to offer -
+//! files in a directory with [`LocalStore`], plain memory with [`MemoryStore`],
+//! two of those split into tiers with [`TieredStore`], several spread across
+//! disks with [`SpreadStore`], or anything wrapping them, e.g. to fail on
+//! purpose in tests.
+
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::kopper::KopperError;
+use crate::segment_log;
+
+/// Open segment. Handles stay readable after the segment is deleted from its
+/// store, so whoever holds one - a scan, a backup - isn't affected by compaction.
+pub trait SegmentFile: Send + Sync {
+    /// Fills `buffer` from `offset` on, failing if the segment ends before
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> Result<(), KopperError>;
+
+    fn append(&self, data: &[u8]) -> Result<(), KopperError>;
+
+    fn len(&self) -> Result<u64, KopperError>;
+
+    /// Bytes it takes up in its store, where that's not what it reads as - e.g.
+    /// a segment [packed](crate::packed) by the compactor
+    fn stored_len(&self) -> Result<u64, KopperError> {
+        self.len()
+    }
+
+    /// Drops everything from `len` on, e.g. what's left of a failed append
+    fn truncate(&self, len: u64) -> Result<(), KopperError>;
+
+    fn is_empty(&self) -> Result<bool, KopperError> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Returns once everything appended so far survives a crash
+    fn sync(&self) -> Result<(), KopperError>;
+}
+
+pub trait SegmentStore: Send + Sync {
+    /// Opens the segment `name` for appending, creating it empty if it doesn't exist
+    fn create(&self, name: &str) -> Result<Arc<dyn SegmentFile>, KopperError>;
+
+    /// Opens the existing segment `name`, only to read it
+    fn open(&self, name: &str) -> Result<Arc<dyn SegmentFile>, KopperError>;
+
+    /// Names of every segment, in no particular order
+    fn list(&self) -> Result<Vec<String>, KopperError>;
+
+    fn delete(&self, name: &str) -> Result<(), KopperError>;
+
+    /// Returns once the segments created and deleted so far stay that way after
+    /// a crash, like [`SegmentFile::sync`] for what's in them - on a filesystem,
+    /// by syncing the directory. Stores without one have nothing to do.
+    fn sync_dir(&self) -> Result<(), KopperError> {
+        Ok(())
+    }
+
+    /// Directory the segments are files in, if they are - needed for what only
+    /// works on a filesystem, like hard-linking or moving segments in
+    fn path(&self) -> Option<&str> {
+        None
+    }
+
+    /// Store of the same kind with its segments in the directory `dir`, e.g. to
+    /// copy segments into for a snapshot - an encrypted store encrypts them too
+    fn in_dir(&self, dir: &str) -> Arc<dyn SegmentStore> {
+        Arc::new(LocalStore::new(dir))
+    }
+
+    /// Like [`SegmentStore::create`], on `tier` if the segment doesn't exist yet.
+    /// Stores without tiers only have the one.
+    fn create_on(&self, name: &str, _tier: Tier) -> Result<Arc<dyn SegmentFile>, KopperError> {
+        self.create(name)
+    }
+
+    /// Tier the existing segment `name` is kept on
+    fn tier_of(&self, _name: &str) -> Tier {
+        Tier::Hot
+    }
+}
+
+/// Storage a segment is kept on, see [`TieredStore`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tier {
+    /// Fast, where segments are created
+    Hot,
+    /// Slower and cheaper, for old segments the compactor moved there
+    Cold,
+}
+
+/// Search permission for the directory wherever `mode` lets its files be read
+fn dir_mode(mode: u32) -> u32 {
+    mode | (mode & 0o444) >> 2
+}
+
+/// Segments as files in a directory, named after the segment. Hidden files belong
+/// to whoever embeds the database (e.g. persisted stats), they're not segments.
+pub struct LocalStore {
+    path: String,
+    /// Of new segments, see [`LocalStore::with_mode`]
+    mode: Option<u32>,
+}
+
+impl LocalStore {
+    /// `path` has to exist. Segments are created with the process' default permissions.
+    pub fn new(path: &str) -> Self {
+        LocalStore { path: path.to_owned(), mode: None }
+    }
+
+    /// Like [`LocalStore::new`], with segments only as accessible as `mode` lets
+    /// them be, e.g. `0o600` for the owner only. Fails unless `path` is owned by
+    /// this process' user. The directory is given the same mode, plus searching
+    /// wherever it can be read, and segments already there are set to `mode`.
+    pub fn with_mode(path: &str, mode: u32) -> Result<Self, KopperError> {
+        let owner = fs::metadata(path)?.uid();
+        // Safe, only returns the user ID
+        let user = unsafe { libc::geteuid() };
+        if owner != user {
+            return Err(KopperError::InternalError(anyhow::anyhow!("{path} is owned by user {owner}, not by {user} the database runs as")));
+        }
+        fs::set_permissions(path, fs::Permissions::from_mode(dir_mode(mode)))?;
+
+        let store = LocalStore { path: path.to_owned(), mode: Some(mode) };
+        for name in store.list()? {
+            let file = store.file(&name);
+            let current = fs::metadata(&file)?.permissions().mode() & 0o777;
+            if current != mode {
+                tracing::warn!("Segment {file} had mode {current:o}, setting it to {mode:o}");
+                fs::set_permissions(&file, fs::Permissions::from_mode(mode))?;
+            }
+        }
+        Ok(store)
+    }
+
+    fn file(&self, name: &str) -> String {
+        self.path.clone() + "/" + name
+    }
+}
+
+struct LocalFile(File);
+
+impl SegmentFile for LocalFile {
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> Result<(), KopperError> {
+        // Positional read - the cursor is shared with other handles
+        Ok(self.0.read_exact_at(buffer, offset)?)
+    }
+
+    fn append(&self, data: &[u8]) -> Result<(), KopperError> {
+        Ok((&self.0).write_all(data)?)
+    }
+
+    fn len(&self) -> Result<u64, KopperError> {
+        Ok(self.0.metadata()?.len())
+    }
+
+    fn truncate(&self, len: u64) -> Result<(), KopperError> {
+        Ok(self.0.set_len(len)?)
+    }
+
+    fn sync(&self) -> Result<(), KopperError> {
+        Ok(self.0.sync_data()?)
+    }
+}
+
+impl SegmentStore for LocalStore {
+    fn create(&self, name: &str) -> Result<Arc<dyn SegmentFile>, KopperError> {
+        let mut options = OpenOptions::new();
+        options.read(true).append(true).create(true);
+        if let Some(mode) = self.mode {
+            options.mode(mode);
+        }
+        Ok(Arc::new(LocalFile(options.open(self.file(name))?)))
+    }
+
+    fn open(&self, name: &str) -> Result<Arc<dyn SegmentFile>, KopperError> {
+        Ok(Arc::new(LocalFile(File::open(self.file(name))?)))
+    }
+
+    fn list(&self) -> Result<Vec<String>, KopperError> {
+        let mut names = Vec::new();
+        for dir_entry in fs::read_dir(&self.path)? {
+            let file_name = dir_entry?.file_name();
+            let file_name = file_name.to_str().unwrap();
+            if !file_name.starts_with('.') {
+                names.push(file_name.to_owned());
+            }
+        }
+        Ok(names)
+    }
+
+    fn delete(&self, name: &str) -> Result<(), KopperError> {
+        Ok(fs::remove_file(self.file(name))?)
+    }
+
+    fn sync_dir(&self) -> Result<(), KopperError> {
+        Ok(File::open(&self.path)?.sync_all()?)
+    }
+
+    fn path(&self) -> Option<&str> {
+        Some(&self.path)
+    }
+
+    /// Snapshots and backups are kept as private as the database
+    fn in_dir(&self, dir: &str) -> Arc<dyn SegmentStore> {
+        Arc::new(LocalStore { path: dir.to_owned(), mode: self.mode })
+    }
+}
+
+/// Segments split between a `hot` store, e.g. a directory on an SSD, and a `cold`
+/// one on cheaper storage. New segments are hot - the compactor writes old ones
+/// to the cold store, see [`Kopper::set_cold_tier_after`](crate::kopper::Kopper::set_cold_tier_after).
+/// Names are unique across both, so a segment is looked up wherever it is.
+pub struct TieredStore {
+    hot: Arc<dyn SegmentStore>,
+    cold: Arc<dyn SegmentStore>,
+}
+
+impl TieredStore {
+    pub fn new(hot: Arc<dyn SegmentStore>, cold: Arc<dyn SegmentStore>) -> Self {
+        TieredStore { hot, cold }
+    }
+
+    fn tier(&self, tier: Tier) -> &dyn SegmentStore {
+        match tier {
+            Tier::Hot => &*self.hot,
+            Tier::Cold => &*self.cold,
+        }
+    }
+}
+
+impl SegmentStore for TieredStore {
+    fn create(&self, name: &str) -> Result<Arc<dyn SegmentFile>, KopperError> {
+        self.create_on(name, Tier::Hot)
+    }
+
+    fn open(&self, name: &str) -> Result<Arc<dyn SegmentFile>, KopperError> {
+        self.tier(self.tier_of(name)).open(name)
+    }
+
+    fn list(&self) -> Result<Vec<String>, KopperError> {
+        let mut names = self.hot.list()?;
+        names.extend(self.cold.list()?);
+        Ok(names)
+    }
+
+    fn delete(&self, name: &str) -> Result<(), KopperError> {
+        self.tier(self.tier_of(name)).delete(name)
+    }
+
+    fn sync_dir(&self) -> Result<(), KopperError> {
+        self.hot.sync_dir()?;
+        self.cold.sync_dir()
+    }
+
+    /// Of the hot store, where segments are moved in and snapshots linked from.
+    /// Cold segments are copied instead.
+    fn path(&self) -> Option<&str> {
+        self.hot.path()
+    }
+
+    fn in_dir(&self, dir: &str) -> Arc<dyn SegmentStore> {
+        self.hot.in_dir(dir)
+    }
+
+    fn create_on(&self, name: &str, tier: Tier) -> Result<Arc<dyn SegmentFile>, KopperError> {
+        // One that exists stays where it is
+        let exists = self.hot.open(name).is_ok() || self.cold.open(name).is_ok();
+        self.tier(if exists { self.tier_of(name) } else { tier }).create(name)
+    }
+
+    fn tier_of(&self, name: &str) -> Tier {
+        match self.cold.open(name) {
+            Ok(_) => Tier::Cold,
+            Err(_) => Tier::Hot,
+        }
+    }
+}
+
+/// How a [`SpreadStore`] picks the store a new segment goes to
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Placement {
+    /// Each in turn
+    #[default]
+    RoundRobin,
+    /// The one whose filesystem has the most space left. Stores that aren't
+    /// directories count as full.
+    FreeSpace,
+}
+
+impl FromStr for Placement {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "round-robin" => Ok(Placement::RoundRobin),
+            "free-space" => Ok(Placement::FreeSpace),
+            _ => Err(format!("Unknown placement {name}, expected round-robin or free-space")),
+        }
+    }
+}
+
+/// Bytes an unprivileged user can still write to the filesystem holding `path`
+fn free_space(path: &str) -> u64 {
+    let Ok(path) = CString::new(path) else {
+        return 0;
+    };
+    // Safe, statvfs is plain numbers and only written to
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    match unsafe { libc::statvfs(path.as_ptr(), &mut stats) } {
+        0 => stats.f_bavail as u64 * stats.f_frsize as u64,
+        _ => 0,
+    }
+}
+
+/// Segments spread across several stores, e.g. directories on different disks,
+/// so a database can outgrow the capacity and bandwidth of one. New segments go
+/// to one of them by the [`Placement`], the rest are looked up wherever they
+/// are. Names are unique across all of them, and recovery lists them all. The
+/// first store is the main one, holding the MANIFEST and anything moved in.
+pub struct SpreadStore {
+    stores: Vec<Arc<dyn SegmentStore>>,
+    placement: Placement,
+    /// Of the store the next segment goes to, for [`Placement::RoundRobin`]
+    next: AtomicUsize,
+}
+
+impl SpreadStore {
+    /// Panics without any `stores`
+    pub fn new(stores: Vec<Arc<dyn SegmentStore>>, placement: Placement) -> Self {
+        assert!(!stores.is_empty(), "Segments have to be kept somewhere");
+        SpreadStore { stores, placement, next: AtomicUsize::new(0) }
+    }
+
+    fn holding(&self, name: &str) -> Option<&dyn SegmentStore> {
+        self.stores.iter().find(|store| store.open(name).is_ok()).map(|store| &**store)
+    }
+
+    fn place(&self) -> &dyn SegmentStore {
+        let at = match self.placement {
+            Placement::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed) % self.stores.len(),
+            Placement::FreeSpace => (0..self.stores.len())
+                .max_by_key(|&at| (self.stores[at].path().map_or(0, free_space), std::cmp::Reverse(at)))
+                .unwrap(),
+        };
+        &*self.stores[at]
+    }
+}
+
+impl SegmentStore for SpreadStore {
+    fn create(&self, name: &str) -> Result<Arc<dyn SegmentFile>, KopperError> {
+        match self.holding(name) {
+            Some(store) => store.create(name),
+            None if name == segment_log::NAME => self.stores[0].create(name),
+            None => self.place().create(name),
+        }
+    }
+
+    fn open(&self, name: &str) -> Result<Arc<dyn SegmentFile>, KopperError> {
+        match self.holding(name) {
+            Some(store) => store.open(name),
+            None => Err(io::Error::from(io::ErrorKind::NotFound).into()),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<String>, KopperError> {
+        let mut names = Vec::new();
+        for store in &self.stores {
+            names.extend(store.list()?);
+        }
+        Ok(names)
+    }
+
+    fn delete(&self, name: &str) -> Result<(), KopperError> {
+        match self.holding(name) {
+            Some(store) => store.delete(name),
+            None => Err(io::Error::from(io::ErrorKind::NotFound).into()),
+        }
+    }
+
+    fn sync_dir(&self) -> Result<(), KopperError> {
+        self.stores.iter().try_for_each(|store| store.sync_dir())
+    }
+
+    /// Of the main store. Segments elsewhere are copied instead of linked.
+    fn path(&self) -> Option<&str> {
+        self.stores[0].path()
+    }
+
+    fn in_dir(&self, dir: &str) -> Arc<dyn SegmentStore> {
+        self.stores[0].in_dir(dir)
+    }
+}
+
+/// Segments kept in memory, gone with the store - for tests and throwaway databases.
+/// Syncing does nothing.
+#[derive(Default)]
+pub struct MemoryStore {
+    segments: Mutex<BTreeMap<String, Arc<MemoryFile>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[derive(Default)]
+struct MemoryFile(Mutex<Vec<u8>>);
+
+impl SegmentFile for MemoryFile {
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> Result<(), KopperError> {
+        let data = self.0.lock().unwrap();
+        let from = offset as usize;
+        let part = data.get(from..from + buffer.len())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        buffer.copy_from_slice(part);
+        Ok(())
+    }
+
+    fn append(&self, data: &[u8]) -> Result<(), KopperError> {
+        self.0.lock().unwrap().extend_from_slice(data);
+        Ok(())
+    }
+
+    fn len(&self) -> Result<u64, KopperError> {
+        Ok(self.0.lock().unwrap().len() as u64)
+    }
+
+    fn truncate(&self, len: u64) -> Result<(), KopperError> {
+        self.0.lock().unwrap().truncate(len as usize);
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<(), KopperError> {
+        Ok(())
+    }
+}
+
+impl SegmentStore for MemoryStore {
+    fn create(&self, name: &str) -> Result<Arc<dyn SegmentFile>, KopperError> {
+        Ok(self.segments.lock().unwrap().entry(name.to_owned()).or_default().clone())
+    }
+
+    fn open(&self, name: &str) -> Result<Arc<dyn SegmentFile>, KopperError> {
+        match self.segments.lock().unwrap().get(name) {
+            Some(segment) => Ok(segment.clone()),
+            None => Err(io::Error::from(io::ErrorKind::NotFound).into()),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<String>, KopperError> {
+        Ok(self.segments.lock().unwrap().keys().cloned().collect())
+    }
+
+    fn delete(&self, name: &str) -> Result<(), KopperError> {
+        match self.segments.lock().unwrap().remove(name) {
+            Some(_) => Ok(()),
+            None => Err(io::Error::from(io::ErrorKind::NotFound).into()),
+        }
+    }
+}
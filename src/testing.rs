@@ -0,0 +1,99 @@
+//! Throwaway databases for tests. A [`TempDb`] is a fresh directory in the
+//! system's temporary directory, removed with everything in it once dropped.
+//!
+//! ```
+//! use kopperdb::testing::TempDb;
+//!
+//! let db = TempDb::new();
+//! let kopper = db.kopper(1024).unwrap();
+//! kopper.write("key", "value").unwrap();
+//! kopper.close().unwrap();
+//!
+//! // Opened again from the same directory
+//! assert_eq!(db.kopper(1024).unwrap().read("key").unwrap(), "value");
+//! ```
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::brass::Brass;
+use crate::kopper::{Kopper, KopperError};
+use crate::lsm::Lsm;
+
+static CREATED: AtomicUsize = AtomicUsize::new(0);
+
+/// Unique directory, only this guard and what it hands out use it. Every
+/// engine gets its own subdirectory named after it, `kopper`, `brass` or `lsm` -
+/// opening one again reopens its database.
+/// Drop the guard after the databases, their background threads may still be
+/// writing until then.
+pub struct TempDb {
+    path: String,
+}
+
+impl TempDb {
+    /// Panics if the directory can't be created
+    pub fn new() -> Self {
+        loop {
+            let name = format!("kopperdb-{}-{}", std::process::id(), CREATED.fetch_add(1, Ordering::Relaxed));
+            let path: PathBuf = std::env::temp_dir().join(name);
+            match fs::create_dir(&path) {
+                Ok(()) => return TempDb { path: path.to_string_lossy().into_owned() },
+                // Left behind by an earlier process with the same id
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(err) => panic!("Can't create a temporary directory in {}: {err}", path.display()),
+            }
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Path of `name` in the directory, e.g. to back up to. Nothing is created.
+    pub fn join(&self, name: &str) -> String {
+        format!("{}/{name}", self.path)
+    }
+
+    pub fn kopper(&self, segment_size: usize) -> Result<Kopper, KopperError> {
+        Kopper::create(&self.join("kopper"), segment_size)
+    }
+
+    pub fn brass(&self, segment_size: usize) -> Result<Brass, KopperError> {
+        Brass::create(&self.join("brass"), segment_size)
+    }
+
+    pub fn lsm(&self, memtable_size: usize) -> Result<Lsm, KopperError> {
+        Lsm::create(&self.join("lsm"), memtable_size)
+    }
+}
+
+impl Default for TempDb {
+    fn default() -> Self {
+        TempDb::new()
+    }
+}
+
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir_all(&self.path) {
+            tracing::warn!("Can't remove {}: {err}", self.path);
+        }
+    }
+}
+
+/// TESTS
+
+#[test]
+fn test_temp_db_is_removed_on_drop() {
+    let db = TempDb::new();
+    let other = TempDb::new();
+    assert_ne!(db.path(), other.path());
+
+    db.kopper(100).unwrap().write("ab", "cd").unwrap();
+    db.brass(4096).unwrap().write("ab", "cd").unwrap();
+    let path = db.path().to_owned();
+    drop(db);
+    assert!(!std::path::Path::new(&path).exists());
+}
@@ -0,0 +1,13 @@
+//! Deleted values kept for a while, see [`Kopper::undelete`](crate::kopper::Kopper::undelete).
+//! With an undelete window set, a delete writes the tombstone of a key along with
+//! the value it had under [`trash_key`], expiring once the window is over. Until
+//! then the value can be restored from there - afterwards it's swept and
+//! compacted away like any other expired value. The key is
+//! [reserved](crate::keyspace), so no user key is ever mistaken for it.
+
+use crate::keyspace;
+
+/// Key the deleted value of `key` is kept under
+pub fn trash_key(key: &str) -> String {
+    keyspace::reserved_key("deleted", key)
+}
@@ -0,0 +1,56 @@
+//! Background verification of the main database, see [`Kopper::verify`]. With
+//! `verify_interval_ms` in the config, `verify_sample` keys are read back from
+//! disk that often. A replica compares them with its primary too, whenever it's
+//! caught up - values changed on the primary since the last poll would differ.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rocket::fairing::AdHoc;
+
+use kopperdb::client::KopperClient;
+use kopperdb::kopper::Kopper;
+use kopperdb::verify::Replica;
+
+use crate::replication::{ReplicaState, Role};
+
+/// Keys verified at a time unless `verify_sample` says otherwise
+const DEFAULT_VERIFY_SAMPLE: usize = 100;
+
+/// Starts the verifier once the server is up, if it's configured
+pub fn verifier() -> AdHoc {
+    AdHoc::on_liftoff("verifier", |rocket| Box::pin(async move {
+        let Ok(interval) = rocket.figment().extract_inner::<u64>("verify_interval_ms") else {
+            return;
+        };
+        let sample = rocket.figment().extract_inner("verify_sample").unwrap_or(DEFAULT_VERIFY_SAMPLE);
+        let kopper = rocket.state::<Kopper>().expect("Kopper is managed");
+        kopper.set_quarantine(rocket.figment().extract_inner("quarantine_corrupt").unwrap_or(false));
+
+        let replica = match rocket.state::<Role>() {
+            Some(Role::Replica(state)) => Some(primary_reader(state.clone())),
+            _ => None,
+        };
+        tracing::info!(interval_ms = interval, sample, cross_checked = replica.is_some(), "Verifying records");
+        kopper.start_verifier(Duration::from_millis(interval), sample, replica);
+    }))
+}
+
+/// Reads keys from the primary of the replica in `state` while it's caught up
+fn primary_reader(state: Arc<Mutex<ReplicaState>>) -> Replica {
+    let client: Mutex<Option<KopperClient>> = Mutex::new(None);
+    Box::new(move |key| {
+        let primary = {
+            let state = state.lock().unwrap();
+            if !state.caught_up() {
+                return None;
+            }
+            state.primary().to_owned()
+        };
+        let mut client = client.lock().unwrap();
+        if client.is_none() {
+            *client = KopperClient::connect(&primary).ok();
+        }
+        client.as_ref()?.get(key).ok()
+    })
+}
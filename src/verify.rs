@@ -0,0 +1,67 @@
+//! Verification of the records behind keys, see [`Kopper::verify`](crate::kopper::Kopper::verify).
+//! Records don't carry checksums of their own. A record read back has to hold its
+//! key between the NULs around it, and a deduplicated blob has to hash to the hash
+//! it's stored under - decoding the value catches the rest, e.g. broken
+//! compression or bytes that aren't UTF-8.
+
+use serde::{Deserialize, Serialize};
+
+use crate::dedupe::{self, Record};
+
+/// Reads a key from a replica for [`Kopper::start_verifier`](crate::kopper::Kopper::start_verifier),
+/// `None` if it can't tell
+pub type Replica = Box<dyn Fn(&str) -> Option<Option<String>> + Send>;
+
+/// Found by [`Kopper::verify`](crate::kopper::Kopper::verify) and
+/// [`Kopper::cross_check`](crate::kopper::Kopper::cross_check) since the
+/// database was opened
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VerifyStats {
+    /// Keys verified
+    pub checked: u64,
+    /// Of them, ones whose record was corrupt
+    pub corrupt: u64,
+    /// Ones whose value differed on the replica
+    pub mismatched: u64,
+    /// Segments quarantined, see [`Kopper::set_quarantine`](crate::kopper::Kopper::set_quarantine)
+    pub quarantined: Vec<String>,
+}
+
+/// What's wrong with `record` - the key, a NUL, the value and a NUL - read back
+/// from where the value of `key` should be
+pub fn check_record(key: &str, record: &[u8]) -> Result<(), String> {
+    let value = record.strip_prefix(key.as_bytes())
+        .and_then(|rest| rest.strip_prefix(b"\0"))
+        .and_then(|rest| rest.strip_suffix(b"\0"))
+        .ok_or_else(|| "the record doesn't hold the key".to_owned())?;
+    if value.contains(&0) {
+        return Err("the value runs into another record".to_owned());
+    }
+    check_value(value)
+}
+
+/// What's wrong with `value` as stored, e.g. a blob that doesn't match its hash
+pub fn check_value(value: &[u8]) -> Result<(), String> {
+    match dedupe::parse(value) {
+        Some(Record::Blob(hash)) if dedupe::hash(&value[dedupe::HEADER_LEN..]) != hash => Err(format!("the blob doesn't match its hash {hash:016x}")),
+        _ => Ok(()),
+    }
+}
+
+/// TESTS
+
+#[test]
+fn test_check_record_finds_broken_records() {
+    let blob = dedupe::blob(dedupe::hash(b"value"), b"value");
+    let record = |key: &str, value: &[u8]| [key.as_bytes(), b"\0", value, b"\0"].concat();
+
+    assert_eq!(check_record("key", &record("key", b"value")), Ok(()));
+    assert_eq!(check_record("key", &record("key", &blob)), Ok(()));
+    assert!(check_record("key", &record("kez", b"value")).is_err());
+    assert!(check_record("key", &record("key", b"va\0ue")).is_err());
+    assert!(check_record("key", &record("key", b"value")[..9]).is_err());
+
+    let mut flipped = blob.clone();
+    *flipped.last_mut().unwrap() ^= 1;
+    assert!(check_record("key", &record("key", &flipped)).is_err());
+}
@@ -0,0 +1,117 @@
+use rocket::{
+    Request, Response, Data,
+    fairing::{Fairing, Info, Kind},
+    http::{Header, uri::Origin}
+};
+
+const VERSION_HEADER: &str = "X-Api-Version";
+const SUPPORTED_HEADER: &str = "X-Api-Supported-Versions";
+
+/// Versions of the HTTP API this server speaks. Routes of version `n` are
+/// mounted under `/v<n>`, anything that changes a response shape goes into a new version.
+pub const SUPPORTED: &[u32] = &[1];
+
+/// Version served to clients that don't ask for one. Stays at 1 so that clients
+/// written before versioning existed keep getting the responses they expect.
+pub const DEFAULT: u32 = 1;
+
+/// Base path routes of `version` are mounted at
+pub fn base(version: u32) -> String {
+    format!("/v{version}")
+}
+
+/// Version from a `/v<n>/...` path, `None` for unversioned paths
+fn version_of(path: &str) -> Option<u32> {
+    let segment = path.strip_prefix("/v")?.split('/').next()?;
+    segment.parse().ok()
+}
+
+struct Negotiated(u32);
+
+/// Fairing handling version negotiation:
+/// - `/v<n>/...` paths are served by version `n`, as is
+/// - unversioned paths are rewritten to the version from the `X-Api-Version`
+///   header, or [`DEFAULT`] if there's none
+///
+/// Every response says which version served it in `X-Api-Version`, and lists
+/// the [`SUPPORTED`] versions, so clients can tell why an unknown version 404'd.
+pub struct ApiVersion;
+
+#[rocket::async_trait]
+impl Fairing for ApiVersion {
+    fn info(&self) -> Info {
+        Info { name: "API version negotiation", kind: Kind::Request | Kind::Response }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        if let Some(version) = version_of(req.uri().path().as_str()) {
+            req.local_cache(|| Negotiated(version));
+            return;
+        }
+
+        let version = match req.headers().get_one(VERSION_HEADER) {
+            // Garbage maps to 0, which nothing is mounted at - the request 404s
+            // instead of being served by a guessed version
+            Some(header) => header.trim().trim_start_matches('v').parse().unwrap_or_default(),
+            None => DEFAULT,
+        };
+        req.local_cache(|| Negotiated(version));
+
+        let uri = match req.uri().query() {
+            Some(query) => format!("{}{}?{}", base(version), req.uri().path(), query),
+            None => format!("{}{}", base(version), req.uri().path()),
+        };
+        match Origin::parse_owned(uri) {
+            Ok(uri) => req.set_uri(uri),
+            Err(err) => tracing::error!("Can't add version to {}: {err}", req.uri()),
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let Negotiated(version) = req.local_cache(|| Negotiated(DEFAULT));
+        if SUPPORTED.contains(version) {
+            res.set_header(Header::new(VERSION_HEADER, version.to_string()));
+        }
+
+        let supported: Vec<String> = SUPPORTED.iter().map(u32::to_string).collect();
+        res.set_header(Header::new(SUPPORTED_HEADER, supported.join(", ")));
+    }
+}
+
+/// TESTS
+
+#[test]
+fn test_versions_are_negotiated_by_path_and_header() {
+    use rocket::http::Status;
+    use rocket::local::blocking::Client;
+
+    let client = Client::tracked(rocket::build().attach(ApiVersion).mount(base(1), routes![echo])).unwrap();
+    let get = |path: &str, version: Option<&str>| {
+        let mut request = client.get(path.to_owned());
+        if let Some(version) = version {
+            request.add_header(Header::new(VERSION_HEADER, version.to_owned()));
+        }
+        let response = request.dispatch();
+        assert_eq!(response.headers().get_one(SUPPORTED_HEADER), Some("1"));
+        (response.status(), response.headers().get_one(VERSION_HEADER).map(str::to_owned), response.into_string())
+    };
+
+    // Unversioned paths without a header get the default version, with the query
+    assert_eq!(get("/echo?word=hi", None), (Status::Ok, Some("1".to_owned()), Some("hi".to_owned())));
+    assert_eq!(get("/echo?word=hi", Some("v1")), (Status::Ok, Some("1".to_owned()), Some("hi".to_owned())));
+    // The path wins over the header
+    assert_eq!(get("/v1/echo?word=hi", Some("2")), (Status::Ok, Some("1".to_owned()), Some("hi".to_owned())));
+
+    // Versions not served 404, saying which ones are
+    for version in ["2", "garbage"] {
+        let (status, served, _) = get("/echo?word=hi", Some(version));
+        assert_eq!((status, served), (Status::NotFound, None));
+    }
+    assert_eq!(get("/v2/echo?word=hi", None).0, Status::NotFound);
+}
+
+#[cfg(test)]
+#[get("/echo?<word>")]
+fn echo(word: &str) -> String {
+    word.to_owned()
+}
@@ -0,0 +1,26 @@
+//! Versions kept of a key, see [`Kopper::keep_versions`](crate::kopper::Kopper::keep_versions).
+//! Before a write replaces the value of such a key - or a delete removes it - the
+//! value is pushed to the front of a JSON array under [`history_key`], which is
+//! cut to the number of versions kept. The history is rewritten whole,
+//! so compaction drops the versions cut off like any other overwritten value.
+//! Its key is [reserved](crate::keyspace), out of reach and sight of users.
+
+use std::collections::VecDeque;
+
+use crate::keyspace;
+use crate::kopper::KopperError;
+
+/// Key of the previous versions of `key`
+pub fn history_key(key: &str) -> String {
+    keyspace::reserved_key("versions", key)
+}
+
+/// Previous versions stored as `value` under `key`, newest first
+pub fn decode(key: &str, value: &str) -> Result<VecDeque<String>, KopperError> {
+    serde_json::from_str(value).map_err(|_| KopperError::WrongType(key.to_owned()))
+}
+
+pub fn encode(history: &VecDeque<String>) -> String {
+    // Serializing strings can't fail
+    serde_json::to_string(history).unwrap()
+}
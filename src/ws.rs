@@ -0,0 +1,192 @@
+use rocket::{State, Shutdown};
+use rocket::futures::{SinkExt, StreamExt};
+use rocket::serde::json::serde_json;
+use rocket::tokio::sync::mpsc::{self, UnboundedSender};
+use rocket_ws::{WebSocket, Channel, Message};
+use serde::{Deserialize, Serialize};
+
+use kopperdb::kopper::{Kopper, KopperError, ChangeEvent, Subscription};
+
+/// Message sent by the client. Every request is answered with a [`WsResponse`]
+/// carrying the same `op`:
+///
+/// ```json
+/// {"op": "get", "key": "a"}
+/// {"op": "set", "key": "a", "value": "b"}
+/// {"op": "del", "key": "a"}
+/// {"op": "subscribe", "prefix": "user:"}
+/// {"op": "publish", "channel": "chat", "message": "hi"}
+/// {"op": "listen", "channel": "chat"}
+/// ```
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum WsRequest {
+    Get { key: String },
+    Set { key: String, value: String },
+    Del { key: String },
+    Subscribe {
+        #[serde(default)]
+        prefix: String
+    },
+    Publish { channel: String, message: String },
+    Listen { channel: String },
+}
+
+/// Message sent by the server - either a reply to a [`WsRequest`], a change
+/// notification with `op` set to `write`/`delete` for subscribed connections, or
+/// a `message` published to a channel the connection listens to.
+#[derive(Serialize, Default)]
+struct WsResponse {
+    op: &'static str,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    channel: Option<String>,
+
+    /// Listeners a published message went to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    receivers: Option<usize>,
+
+    /// "OK" on success, description of the problem otherwise
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl WsResponse {
+    fn reply(op: &'static str, key: Option<String>, result: Result<Option<String>, KopperError>) -> Self {
+        let (value, error) = match result {
+            Ok(value) => (value, "OK".to_string()),
+            Err(KopperError::KeyDoesNotExist(key)) => (None, format!("{key} does not exist!")),
+            Err(err) => {
+                tracing::error!("{err}");
+                (None, "Internal Error".to_string())
+            }
+        };
+        WsResponse { op, key, value, error: Some(error), ..Default::default() }
+    }
+
+    fn message(channel: &str, message: String) -> Self {
+        WsResponse { op: "message", channel: Some(channel.to_owned()), value: Some(message), ..Default::default() }
+    }
+}
+
+impl From<ChangeEvent> for WsResponse {
+    fn from(event: ChangeEvent) -> Self {
+        match event {
+            ChangeEvent::Write { key, value } => WsResponse { op: "write", key: Some(key), value: Some(value), ..Default::default() },
+            ChangeEvent::Delete { key } => WsResponse { op: "delete", key: Some(key), ..Default::default() },
+        }
+    }
+}
+
+/// Subscriptions the connection makes end up in `subscriptions`, and end with it
+fn handle(db: &Kopper, text: &str, events: &UnboundedSender<WsResponse>, subscriptions: &mut Vec<Subscription>) -> WsResponse {
+    let request = match serde_json::from_str::<WsRequest>(text) {
+        Ok(request) => request,
+        Err(err) => return WsResponse { op: "error", error: Some(format!("Malformed request: {err}")), ..Default::default() },
+    };
+
+    match request {
+        WsRequest::Get { key } => {
+            let result = db.read(&key).map(Some);
+            WsResponse::reply("get", Some(key), result)
+        },
+        WsRequest::Set { key, value } => {
+            let result = db.write(&key, &value).map(|_| None);
+            WsResponse::reply("set", Some(key), result)
+        },
+        WsRequest::Del { key } => {
+            let result = db.delete(&key).map(|_| None);
+            WsResponse::reply("del", Some(key), result)
+        },
+        WsRequest::Subscribe { prefix } => {
+            let events = events.clone();
+            subscriptions.push(db.watch_into(&prefix, move |event| events.send(WsResponse::from(event)).is_ok()));
+            WsResponse::reply("subscribe", None, Ok(None))
+        },
+        WsRequest::Publish { channel, message } => match db.publish(&channel, &message) {
+            Ok(receivers) => WsResponse { receivers: Some(receivers), channel: Some(channel), ..WsResponse::reply("publish", None, Ok(None)) },
+            Err(err) => WsResponse { channel: Some(channel), ..WsResponse::reply("publish", None, Err(err)) },
+        },
+        WsRequest::Listen { channel } => {
+            let events = events.clone();
+            let listened = channel.clone();
+            subscriptions.push(db.subscribe_into(&channel, move |message| events.send(WsResponse::message(&listened, message)).is_ok()));
+            WsResponse { channel: Some(channel), ..WsResponse::reply("listen", None, Ok(None)) }
+        },
+    }
+}
+
+fn to_message(response: &WsResponse) -> Message {
+    // Serializing a struct of strings can't fail
+    Message::Text(serde_json::to_string(response).unwrap())
+}
+
+#[get("/ws")]
+pub fn ws(ws: WebSocket, db: &State<Kopper>, mut shutdown: Shutdown) -> Channel<'static> {
+    let db = db.inner().clone();
+
+    ws.channel(move |mut stream| Box::pin(async move {
+        let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+        let mut subscriptions = Vec::new();
+
+        loop {
+            rocket::tokio::select! {
+                message = stream.next() => match message {
+                    Some(Ok(Message::Text(text))) => {
+                        let response = handle(&db, &text, &events_tx, &mut subscriptions);
+                        stream.send(to_message(&response)).await?;
+                    },
+                    Some(Ok(Message::Close(_))) | None => break,
+                    Some(Ok(_)) => continue,
+                    Some(Err(err)) => return Err(err),
+                },
+                Some(event) = events_rx.recv() => {
+                    stream.send(to_message(&event)).await?;
+                },
+                _ = &mut shutdown => break,
+            }
+        }
+
+        Ok(())
+    }))
+}
+
+/// TESTS
+
+#[test]
+fn test_requests_are_answered_and_subscriptions_pushed() {
+    let db = kopperdb::testing::TempDb::new();
+    let kopper = db.kopper(4096).unwrap();
+    let (events, mut pushed) = mpsc::unbounded_channel();
+    let mut subscriptions = Vec::new();
+    let mut send = |text: &str| serde_json::to_string(&handle(&kopper, text, &events, &mut subscriptions)).unwrap();
+
+    assert_eq!(send(r#"{"op": "subscribe", "prefix": "user:"}"#), r#"{"op":"subscribe","error":"OK"}"#);
+    assert_eq!(send(r#"{"op": "set", "key": "user:1", "value": "ann"}"#), r#"{"op":"set","key":"user:1","error":"OK"}"#);
+    assert_eq!(send(r#"{"op": "get", "key": "user:1"}"#), r#"{"op":"get","key":"user:1","value":"ann","error":"OK"}"#);
+    assert_eq!(send(r#"{"op": "set", "key": "other", "value": "x"}"#), r#"{"op":"set","key":"other","error":"OK"}"#);
+    assert_eq!(send(r#"{"op": "del", "key": "user:1"}"#), r#"{"op":"del","key":"user:1","error":"OK"}"#);
+    assert_eq!(send(r#"{"op": "get", "key": "user:1"}"#), r#"{"op":"get","key":"user:1","error":"user:1 does not exist!"}"#);
+    assert!(send(r#"{"op": "drop"}"#).starts_with(r#"{"op":"error","error":"Malformed request: "#));
+
+    assert_eq!(send(r#"{"op": "listen", "channel": "chat"}"#), r#"{"op":"listen","channel":"chat","error":"OK"}"#);
+    assert_eq!(send(r#"{"op": "publish", "channel": "chat", "message": "hi"}"#), r#"{"op":"publish","channel":"chat","receivers":1,"error":"OK"}"#);
+
+    // Only changes under the prefix, then the message
+    let pushed: Vec<_> = std::iter::from_fn(|| pushed.try_recv().ok()).map(|event| serde_json::to_string(&event).unwrap()).collect();
+    assert_eq!(pushed, [
+        r#"{"op":"write","key":"user:1","value":"ann"}"#,
+        r#"{"op":"delete","key":"user:1"}"#,
+        r#"{"op":"message","value":"hi","channel":"chat"}"#,
+    ]);
+
+    // Subscriptions end with the connection
+    drop(subscriptions);
+    assert_eq!(kopper.publish("chat", "gone").unwrap(), 0);
+}
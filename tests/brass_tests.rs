@@ -1,23 +1,180 @@
-mod common;
-use crate::common::*;
-
-use kopperdb::brass::*;
-
-fn get_new_path() -> String {
-    DB_PATH.to_owned() + "/kopper/" + &random_key_value_with_size(20).0
-}
-
-#[test]
-fn test_write_read() {
-    let brass = Brass::create(&get_new_path(), SEGMENT_SIZE).unwrap();
-
-    // Write
-    let (key, value) = random_key_value();
-    brass.write(&key, &value).unwrap();
-
-    // Read
-    let read_response = brass.read(&key).unwrap();
-
-    assert_eq!(read_response, value);
-}
-
+mod common;
+use crate::common::*;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use kopperdb::engine::{KvEngine, Shadowed};
+use kopperdb::kopper::KopperError;
+use kopperdb::metrics::{MetricsSink, NoopSink, Stat};
+use kopperdb::testing::TempDb;
+
+#[test]
+fn test_write_read() {
+    let db = TempDb::new();
+    let brass = db.brass(SEGMENT_SIZE).unwrap();
+
+    // Write
+    let (key, value) = random_key_value();
+    brass.write(&key, &value).unwrap();
+
+    // Read
+    let read_response = brass.read(&key).unwrap();
+
+    assert_eq!(read_response, value);
+}
+
+#[test]
+fn engines_behave_alike() {
+    let db = TempDb::new();
+    let engines: Vec<Box<dyn KvEngine>> = vec![
+        Box::new(db.brass(SEGMENT_SIZE).unwrap()),
+        Box::new(db.kopper(SEGMENT_SIZE).unwrap()),
+        Box::new(db.lsm(SEGMENT_SIZE).unwrap()),
+    ];
+
+    for engine in engines {
+        for (key, value) in [("b", "2"), ("a", "1"), ("c", "3")] {
+            engine.write(key, value).unwrap();
+        }
+        engine.delete("b").unwrap();
+
+        assert_eq!(engine.read("a").unwrap(), "1", "{}", engine.name());
+        assert!(matches!(engine.read("b"), Err(KopperError::KeyDoesNotExist(_))), "{}", engine.name());
+        assert!(matches!(engine.delete("b"), Err(KopperError::KeyDoesNotExist(_))), "{}", engine.name());
+
+        let entries: Vec<_> = engine.scan().unwrap().collect::<Result<_, _>>().unwrap();
+        assert_eq!(entries, [("a".to_string(), "1".to_string()), ("c".to_string(), "3".to_string())], "{}", engine.name());
+        assert_eq!(engine.stats().unwrap().keys, 2, "{}", engine.name());
+    }
+}
+
+#[test]
+fn shadow_mirrors_writes() {
+    struct Collect(Mutex<Vec<Stat>>);
+    impl MetricsSink for Collect {
+        fn record(&self, stat: Stat) {
+            self.0.lock().unwrap().push(stat);
+        }
+    }
+
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    let brass = Arc::new(db.brass(SEGMENT_SIZE).unwrap());
+    let metrics = Arc::new(Collect(Mutex::new(Vec::new())));
+    let shadowed = Shadowed::new(Arc::new(kopper.clone()), brass.clone(), metrics.clone());
+
+    shadowed.write("a", "1").unwrap();
+    shadowed.write("b", "2").unwrap();
+    shadowed.delete("a").unwrap();
+    // Failed on the primary, so never mirrored
+    assert!(shadowed.delete("missing").is_err());
+
+    // Mirroring happens in the background
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while metrics.0.lock().unwrap().len() < 3 {
+        assert!(Instant::now() < deadline, "Writes weren't mirrored in time");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    assert_eq!(shadowed.read("b").unwrap(), "2");
+    assert_eq!(brass.read("b").unwrap(), "2");
+    assert!(matches!(brass.read("a"), Err(KopperError::KeyDoesNotExist(_))));
+    assert!(metrics.0.lock().unwrap().iter().all(|stat| matches!(stat, Stat::ShadowWrite { .. })));
+}
+
+#[test]
+fn closing_a_shadowed_engine_closes_both() {
+    let (db, shadow_db) = (TempDb::new(), TempDb::new());
+    let primary = db.kopper(SEGMENT_SIZE).unwrap();
+    let shadow = shadow_db.kopper(SEGMENT_SIZE).unwrap();
+    let shadowed = Shadowed::new(Arc::new(primary.clone()), Arc::new(shadow.clone()), Arc::new(NoopSink));
+
+    shadowed.write("a", "1").unwrap();
+    shadowed.close().unwrap();
+
+    assert!(primary.is_closed());
+    assert!(shadow.is_closed());
+    assert!(matches!(shadowed.stats(), Err(KopperError::Closed)));
+}
+
+#[test]
+fn segments_split_and_survive_reopening() {
+    let db = TempDb::new();
+    let brass = db.brass(SEGMENT_SIZE).unwrap();
+
+    let mut expected = BTreeMap::new();
+    for _ in 0..200 {
+        let (key, value) = random_key_value();
+        brass.write(&key, &value).unwrap();
+        expected.insert(key, value);
+    }
+    // Overwrites stay in place
+    let (key, _) = expected.iter().next().map(|(k, v)| (k.clone(), v.clone())).unwrap();
+    brass.write(&key, "new").unwrap();
+    expected.insert(key, "new".to_string());
+
+    // Far more than one segment holds
+    assert!(brass.size() > 10 * SEGMENT_SIZE);
+
+    drop(brass);
+    let brass = db.brass(SEGMENT_SIZE).unwrap();
+    for (key, value) in &expected {
+        assert_eq!(&brass.read(key).unwrap(), value);
+    }
+    assert_eq!(brass.scan().unwrap(), expected.into_iter().collect::<Vec<_>>());
+
+    // Entries have to fit half a segment
+    assert!(brass.write("key", &"v".repeat(SEGMENT_SIZE / 2)).is_err());
+}
+
+#[test]
+fn compaction_reclaims_deleted_space() {
+    let db = TempDb::new();
+    let brass = db.brass(SEGMENT_SIZE).unwrap();
+
+    let entries: Vec<_> = (0..100).map(|_| random_key_value()).collect();
+    for (key, value) in &entries {
+        brass.write(key, value).unwrap();
+    }
+    for (key, _) in &entries[10..] {
+        brass.delete(key).unwrap();
+    }
+    assert!(matches!(brass.delete(&entries[10].0), Err(KopperError::KeyDoesNotExist(_))));
+
+    let before = brass.size();
+    let freed = brass.compact().unwrap();
+    assert!(freed > 0);
+    assert_eq!(brass.size(), before - freed * SEGMENT_SIZE);
+
+    let mut expected: Vec<_> = entries[..10].to_vec();
+    expected.sort();
+    assert_eq!(brass.scan().unwrap(), expected);
+    assert_eq!(brass.compact().unwrap(), 0);
+}
+
+#[test]
+fn reopening_removes_what_a_crash_left_behind() {
+    let db = TempDb::new();
+    let brass = db.brass(SEGMENT_SIZE).unwrap();
+    for _ in 0..20 {
+        let (key, value) = random_key_value();
+        brass.write(&key, &value).unwrap();
+    }
+    let entries = brass.scan().unwrap();
+    let size = brass.size();
+    drop(brass);
+    let path = db.join("brass");
+
+    // A segment written for a split the parent never got to, and an unfinished write
+    fs::write(format!("{path}/999"), vec![0; SEGMENT_SIZE]).unwrap();
+    fs::write(format!("{path}/.3.tmp"), b"partial").unwrap();
+
+    let brass = db.brass(SEGMENT_SIZE).unwrap();
+    assert!(!std::path::Path::new(&format!("{path}/999")).exists());
+    assert!(!std::path::Path::new(&format!("{path}/.3.tmp")).exists());
+    assert_eq!(brass.scan().unwrap(), entries);
+    assert_eq!(brass.size(), size);
+}
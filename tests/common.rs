@@ -1,6 +1,5 @@
 use rand::{Rng, distributions::Alphanumeric};
 
-pub const DB_PATH: &str = "testfiles";
 pub const SEGMENT_SIZE: usize = 100;
 
 pub fn random_key_value_with_size(size: usize) -> (String, String) {
@@ -0,0 +1,411 @@
+mod faults;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use kopperdb::kopper::{ChangeEvent, Kopper, KopperError};
+
+use crate::faults::FaultyStore;
+
+/// Small enough for every few writes to start a new segment
+const SEGMENT_SIZE: usize = 40;
+
+fn open(store: &Arc<FaultyStore>) -> Kopper {
+    Kopper::with_store(store.clone(), SEGMENT_SIZE).unwrap()
+}
+
+/// Every acknowledged write is there after recovering. A write that failed
+/// may have made it anyway, but only whole.
+fn assert_recovered(kopper: &Kopper, acknowledged: &HashMap<String, String>, failed: Option<&(String, String)>) {
+    for (key, value) in acknowledged {
+        let recovered = kopper.read(key).unwrap();
+        match failed {
+            Some((failed_key, failed_value)) if failed_key == key && &recovered == failed_value => {},
+            _ => assert_eq!(&recovered, value, "{key} recovered wrong"),
+        }
+    }
+    if let Some((key, value)) = failed {
+        if !acknowledged.contains_key(key) {
+            match kopper.read(key) {
+                Ok(recovered) => assert_eq!(&recovered, value),
+                Err(err) => assert!(matches!(err, KopperError::KeyDoesNotExist(_))),
+            }
+        }
+    }
+    assert!(kopper.len() <= acknowledged.len() + failed.is_some() as usize);
+}
+
+/// Overwrites a few keys with flushed writes until the first one fails, which is returned
+fn write_until_failure(kopper: &Kopper, acknowledged: &mut HashMap<String, String>, writes: usize) -> Option<(String, String)> {
+    for i in 0..writes {
+        let (key, value) = (format!("key{}", i % 5), format!("value{i}"));
+        match kopper.write(&key, &value).and_then(|commit| kopper.wait_for_flush(commit.sequence)) {
+            Ok(()) => acknowledged.insert(key, value),
+            Err(_) => return Some((key, value)),
+        };
+    }
+    None
+}
+
+#[test]
+fn failed_writes_are_not_acknowledged() {
+    let store = FaultyStore::new();
+    let kopper = open(&store);
+    kopper.write("kept", "value").unwrap();
+
+    store.fail_write(1);
+    assert!(kopper.write("lost", "value").is_err());
+    store.fail_write(1);
+    assert!(kopper.delete("kept").is_err());
+
+    assert!(matches!(kopper.read("lost"), Err(KopperError::KeyDoesNotExist(_))));
+    assert_eq!(kopper.read("kept").unwrap(), "value");
+    kopper.write("lost", "found").unwrap();
+    assert_eq!(kopper.read("lost").unwrap(), "found");
+
+    let kopper = open(&store.restart());
+    assert_eq!(kopper.read("kept").unwrap(), "value");
+    assert_eq!(kopper.read("lost").unwrap(), "found");
+}
+
+#[test]
+fn short_writes_are_undone() {
+    let store = FaultyStore::new();
+    let kopper = open(&store);
+    kopper.write("a", "1").unwrap();
+
+    store.short_write(1, 3);
+    assert!(kopper.write("torn", "value").is_err());
+
+    // Would land after the torn part if it was still there
+    kopper.write("b", "2").unwrap();
+    assert_eq!(kopper.read("b").unwrap(), "2");
+
+    let kopper = open(&store.restart());
+    assert_eq!(kopper.read("a").unwrap(), "1");
+    assert_eq!(kopper.read("b").unwrap(), "2");
+    assert!(matches!(kopper.read("torn"), Err(KopperError::KeyDoesNotExist(_))));
+}
+
+#[test]
+fn writes_stop_when_a_torn_write_cant_be_undone() {
+    let store = FaultyStore::new();
+    let kopper = open(&store);
+    kopper.write("a", "1").unwrap();
+
+    store.fail_truncates(true);
+    store.short_write(1, 3);
+    assert!(kopper.write("torn", "value").is_err());
+    assert!(matches!(kopper.write("b", "2"), Err(KopperError::Degraded(_))));
+    assert_eq!(kopper.read("a").unwrap(), "1");
+
+    // Still can't be cut off
+    assert!(kopper.resume().is_err());
+    assert!(kopper.degraded().is_some());
+
+    // Recovery drops the torn part instead
+    store.fail_truncates(false);
+    let kopper = open(&store.restart());
+    kopper.write("b", "2").unwrap();
+    let kopper = open(&store.restart());
+    assert_eq!(kopper.read("a").unwrap(), "1");
+    assert_eq!(kopper.read("b").unwrap(), "2");
+    assert_eq!(kopper.len(), 2);
+}
+
+#[test]
+fn full_disk_degrades_the_database() {
+    let store = FaultyStore::new();
+    let kopper = open(&store);
+    kopper.write("a", "1").unwrap();
+
+    // Room for a part of the next record only
+    store.set_capacity(Some(store.used() + 4));
+    assert!(matches!(kopper.write("b", "a longer value"), Err(KopperError::DatabaseFull)));
+    assert!(matches!(kopper.read("b"), Err(KopperError::KeyDoesNotExist(_))));
+    assert!(kopper.degraded().is_some());
+
+    // Readable, but nothing is written until resumed
+    assert!(matches!(kopper.write("c", "3"), Err(KopperError::Degraded(_))));
+    assert!(matches!(kopper.delete("a"), Err(KopperError::Degraded(_))));
+    assert!(matches!(kopper.apply(&[ChangeEvent::Delete { key: "a".to_owned() }]), Err(KopperError::Degraded(_))));
+    assert_eq!(kopper.read("a").unwrap(), "1");
+
+    store.set_capacity(None);
+    kopper.resume().unwrap();
+    assert!(kopper.degraded().is_none());
+    kopper.write("b", "2").unwrap();
+
+    let kopper = open(&store.restart());
+    assert_eq!(kopper.read("a").unwrap(), "1");
+    assert_eq!(kopper.read("b").unwrap(), "2");
+    assert_eq!(kopper.len(), 2);
+}
+
+#[test]
+fn deletes_and_new_segments_on_a_full_disk_are_undone() {
+    let store = FaultyStore::new();
+    let kopper = open(&store);
+    kopper.write("a", "1").unwrap();
+
+    store.set_capacity(Some(store.used() + 1));
+    assert!(matches!(kopper.delete("a"), Err(KopperError::DatabaseFull)));
+    assert_eq!(kopper.read("a").unwrap(), "1");
+
+    // Fills the segment up to the last byte, the next record needs a new one
+    store.set_capacity(None);
+    kopper.resume().unwrap();
+    kopper.write("b", &"x".repeat(SEGMENT_SIZE - 4 - 6)).unwrap();
+    store.set_capacity(Some(store.used() + 3));
+    assert!(matches!(kopper.write("c", "3"), Err(KopperError::DatabaseFull)));
+
+    store.set_capacity(None);
+    kopper.resume().unwrap();
+    kopper.write("c", "3").unwrap();
+    // Not racing the compaction of the sealed segment
+    kopper.wait_for_compactions().unwrap();
+
+    let kopper = open(&store.restart());
+    assert_eq!(kopper.read("a").unwrap(), "1");
+    assert_eq!(kopper.read("c").unwrap(), "3");
+    assert_eq!(kopper.len(), 3);
+}
+
+#[test]
+fn compaction_on_a_full_disk_keeps_the_segment() {
+    let store = FaultyStore::new();
+    let kopper = open(&store);
+    for i in 0..4 {
+        kopper.write("key", &format!("value{i}")).unwrap();
+    }
+    kopper.write("other", &"x".repeat(SEGMENT_SIZE - 8)).unwrap();
+    kopper.wait_for_compactions().unwrap();
+
+    store.set_capacity(Some(store.used()));
+    kopper.compact().unwrap();
+    kopper.wait_for_compactions().unwrap();
+    assert_eq!(kopper.read("key").unwrap(), "value3");
+
+    // Only writes run out of space, so the database isn't degraded
+    assert!(kopper.degraded().is_none());
+    store.set_capacity(None);
+    let kopper = open(&store.restart());
+    assert_eq!(kopper.read("key").unwrap(), "value3");
+    assert_eq!(kopper.read("other").unwrap(), "x".repeat(SEGMENT_SIZE - 8));
+}
+
+#[test]
+fn flushed_writes_survive_power_loss() {
+    for torn in [0, 1, 4, 1000] {
+        let store = FaultyStore::new();
+        let kopper = open(&store);
+
+        let mut flushed = HashMap::new();
+        let mut unflushed = HashMap::new();
+        for i in 0..20 {
+            let (key, value) = (format!("key{i}"), format!("value{i}"));
+            let commit = kopper.write(&key, &value).unwrap();
+            if i % 3 == 0 {
+                kopper.wait_for_flush(commit.sequence).unwrap();
+                flushed.insert(key, value);
+            } else {
+                unflushed.insert(key, value);
+            }
+        }
+
+        store.power_loss(torn);
+        assert!(kopper.write("after", "power loss").is_err());
+
+        let kopper = open(&store.restart());
+        for (key, value) in &flushed {
+            assert_eq!(&kopper.read(key).unwrap(), value);
+        }
+        for (key, value) in &unflushed {
+            match kopper.read(key) {
+                Ok(recovered) => assert_eq!(&recovered, value),
+                Err(err) => assert!(matches!(err, KopperError::KeyDoesNotExist(_))),
+            }
+        }
+
+        // Torn tail is gone, new writes don't get mixed up with it
+        kopper.write("new", "write").unwrap();
+        kopper.close().unwrap();
+        let kopper = open(&store.restart());
+        assert_eq!(kopper.read("new").unwrap(), "write");
+        for (key, value) in &flushed {
+            assert_eq!(&kopper.read(key).unwrap(), value);
+        }
+    }
+}
+
+#[test]
+fn segments_never_synced_in_the_directory_are_dropped() {
+    let store = FaultyStore::new();
+    let kopper = open(&store);
+    let commit = kopper.write("key0", "flushed").unwrap();
+    kopper.wait_for_flush(commit.sequence).unwrap();
+
+    // Cuts off new segments, recorded in the MANIFEST - torn bytes keep the entries
+    for i in 1..10 {
+        kopper.write(&format!("key{i}"), "unflushed").unwrap();
+    }
+    store.power_loss(1000);
+
+    let kopper = open(&store.restart());
+    assert_eq!(kopper.read("key0").unwrap(), "flushed");
+    kopper.write("new", "write").unwrap();
+    kopper.close().unwrap();
+    assert_eq!(open(&store.restart()).read("new").unwrap(), "write");
+}
+
+#[test]
+fn compaction_keeps_overwritten_values_until_the_overwrite_is_synced() {
+    let store = FaultyStore::new();
+    let kopper = open(&store);
+    for (key, value) in [("key0", "value0"), ("key1", "value1"), ("key2", "value2")] {
+        let commit = kopper.write(key, value).unwrap();
+        kopper.wait_for_flush(commit.sequence).unwrap();
+    }
+
+    // Starts a new segment and compacts the full one, where key0 is out of date now
+    kopper.write("key0", "value3").unwrap();
+    kopper.wait_for_compactions().unwrap();
+    store.power_loss(0);
+
+    let kopper = open(&store.restart());
+    assert_eq!(kopper.read("key0").unwrap(), "value3");
+    assert_eq!(kopper.read("key2").unwrap(), "value2");
+}
+
+#[test]
+fn compacted_segments_left_behind_stay_compacted() {
+    use kopperdb::store::SegmentStore;
+
+    let store = FaultyStore::new();
+    let kopper = open(&store);
+    for i in 0..10 {
+        kopper.write(&format!("key{i}"), "old").unwrap();
+    }
+    kopper.sync().unwrap();
+    let segments: Vec<(String, Vec<u8>)> = store.list().unwrap().into_iter().map(|name| {
+        let segment = store.open(&name).unwrap();
+        let mut contents = vec![0; segment.len().unwrap() as usize];
+        segment.read_at(&mut contents, 0).unwrap();
+        (name, contents)
+    }).collect();
+
+    // Compaction drops the old values, then every tombstone shadowing them
+    for i in 0..10 {
+        kopper.delete(&format!("key{i}")).unwrap();
+    }
+    kopper.write("kept", &"x".repeat(SEGMENT_SIZE - 8)).unwrap();
+    for _ in 0..5 {
+        kopper.compact().unwrap();
+        kopper.wait_for_compactions().unwrap();
+    }
+    kopper.close().unwrap();
+
+    // As if removing the compacted segments never reached the disk
+    let store = store.restart();
+    let left = store.list().unwrap();
+    for (name, contents) in segments.iter().filter(|(name, _)| !left.contains(name)) {
+        store.create(name).unwrap().append(contents).unwrap();
+    }
+
+    let kopper = open(&store);
+    for i in 0..10 {
+        assert!(matches!(kopper.read(&format!("key{i}")), Err(KopperError::KeyDoesNotExist(_))));
+    }
+    assert_eq!(kopper.len(), 1);
+    assert_eq!(store.list().unwrap(), left);
+}
+
+#[test]
+fn power_loss_at_any_write_while_segments_roll_and_compact() {
+    // Appends of the writes, of new segments and of the compactor alike
+    let writes = {
+        let store = FaultyStore::new();
+        let kopper = open(&store);
+        assert!(write_until_failure(&kopper, &mut HashMap::new(), 50).is_none());
+        kopper.wait_for_compactions().unwrap();
+        store.writes()
+    };
+
+    for n in 1..=writes {
+        let store = FaultyStore::new();
+        let kopper = open(&store);
+        store.power_loss_at_write(n, n % 7);
+
+        let mut acknowledged = HashMap::new();
+        let failed = write_until_failure(&kopper, &mut acknowledged, 50);
+        // The compactor may run into it first
+        let _ = kopper.wait_for_compactions();
+
+        let kopper = open(&store.restart());
+        assert_recovered(&kopper, &acknowledged, failed.as_ref());
+
+        // Recovered database carries on
+        let mut after = acknowledged.clone();
+        let failed_again = write_until_failure(&kopper, &mut after, 10);
+        assert!(failed_again.is_none());
+        kopper.close().unwrap();
+        let kopper = open(&store.restart());
+        assert_recovered(&kopper, &after, None);
+    }
+}
+
+#[test]
+fn power_loss_during_slow_compactions() {
+    for wait in [1, 3, 10, 30] {
+        let store = FaultyStore::new();
+        store.set_delay(Duration::from_micros(200));
+        let kopper = open(&store);
+
+        let mut acknowledged = HashMap::new();
+        let writer = {
+            let kopper = kopper.clone();
+            std::thread::spawn(move || {
+                let failed = write_until_failure(&kopper, &mut acknowledged, usize::MAX);
+                (acknowledged, failed)
+            })
+        };
+
+        // Compactions triggered by the writes are usually still going on
+        std::thread::sleep(Duration::from_millis(wait));
+        store.power_loss(wait as usize % 5);
+        let (acknowledged, failed) = writer.join().unwrap();
+        let _ = kopper.wait_for_compactions();
+
+        let kopper = open(&store.restart());
+        assert_recovered(&kopper, &acknowledged, failed.as_ref());
+    }
+}
+
+#[test]
+fn power_loss_during_background_recovery() {
+    use kopperdb::store::SegmentStore;
+
+    let store = FaultyStore::new();
+    let kopper = open(&store);
+    let mut acknowledged = HashMap::new();
+    assert!(write_until_failure(&kopper, &mut acknowledged, 30).is_none());
+    kopper.close().unwrap();
+
+    // Recovery of the older segments can't get past the oldest one
+    let store = store.restart();
+    let oldest = store.list().unwrap().into_iter()
+        .filter(|name| name != "MANIFEST")
+        .min_by_key(|name| name.split('_').next().unwrap().parse::<u32>().unwrap())
+        .unwrap();
+    store.stall_reads(Some(&oldest));
+    let kopper = Kopper::with_store_lazily(store.clone(), SEGMENT_SIZE, 1).unwrap();
+    assert!(write_until_failure(&kopper, &mut acknowledged, 12).is_none());
+    assert!(kopper.recovery_progress().is_some());
+
+    store.power_loss(3);
+    assert!(kopper.wait_for_recovery().is_err());
+    let _ = kopper.close();
+
+    let kopper = open(&store.restart());
+    assert_recovered(&kopper, &acknowledged, None);
+}
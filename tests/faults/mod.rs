@@ -0,0 +1,289 @@
+//! [`SegmentStore`] kept in memory that fails on command, to see what the engine
+//! makes of failed and torn writes, slow disks and power loss.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use kopperdb::kopper::KopperError;
+use kopperdb::store::{SegmentFile, SegmentStore};
+
+#[derive(Clone, Copy)]
+enum Fault {
+    /// Nothing is written
+    Fail,
+    /// Only that many bytes are written
+    Short(usize),
+    /// Power goes out instead, see [`FaultyStore::power_loss`]
+    PowerLoss(usize),
+}
+
+#[derive(Default)]
+struct Segment {
+    data: Vec<u8>,
+    /// Bytes that survive a power loss
+    synced: usize,
+}
+
+#[derive(Default)]
+struct Disk {
+    segments: BTreeMap<String, Arc<Mutex<Segment>>>,
+    /// Segments as of the last [`SegmentStore::sync_dir`], what a power loss
+    /// leaves - with the ones deleted since, but none created
+    linked: BTreeMap<String, Arc<Mutex<Segment>>>,
+    /// Appends so far
+    writes: usize,
+    /// By the number of the append they hit
+    faults: BTreeMap<usize, Fault>,
+    fail_truncates: bool,
+    /// Bytes all segments together can hold
+    capacity: Option<usize>,
+    delay: Duration,
+    /// Segment reads of which block, see [`FaultyStore::stall_reads`]
+    stalled: Option<String>,
+    /// Bumped by every power loss, handles opened before stop working
+    power_cycles: u64,
+}
+
+impl Disk {
+    fn power_loss(&mut self, torn: usize) {
+        self.segments = self.linked.clone();
+        for segment in self.segments.values() {
+            let mut segment = segment.lock().unwrap();
+            let kept = segment.data.len().min(segment.synced + torn);
+            segment.data.truncate(kept);
+            segment.synced = kept;
+        }
+        self.power_cycles += 1;
+    }
+
+    /// Bytes that can still be appended
+    fn space_left(&self) -> usize {
+        let used: usize = self.segments.values().map(|segment| segment.lock().unwrap().data.len()).sum();
+        self.capacity.map_or(usize::MAX, |capacity| capacity.saturating_sub(used))
+    }
+}
+
+/// Everything fails after a power loss - see [`FaultyStore::restart`] for a
+/// store to open the database again with.
+#[derive(Default)]
+pub struct FaultyStore {
+    disk: Arc<Mutex<Disk>>,
+    power_cycle: u64,
+}
+
+impl FaultyStore {
+    pub fn new() -> Arc<Self> {
+        Arc::default()
+    }
+
+    /// Same disk, powered up again. Faults planned and not hit yet are dropped,
+    /// and so are stalled reads.
+    pub fn restart(&self) -> Arc<Self> {
+        let mut disk = self.disk.lock().unwrap();
+        disk.faults.clear();
+        disk.stalled = None;
+        Arc::new(FaultyStore { disk: self.disk.clone(), power_cycle: disk.power_cycles })
+    }
+
+    /// The `n`th append from now on, starting at 1, fails without writing anything
+    pub fn fail_write(&self, n: usize) {
+        self.plan(n, Fault::Fail);
+    }
+
+    /// The `n`th append from now on writes only `len` bytes, then fails
+    pub fn short_write(&self, n: usize, len: usize) {
+        self.plan(n, Fault::Short(len));
+    }
+
+    /// Power goes out at the `n`th append from now on, which doesn't happen
+    pub fn power_loss_at_write(&self, n: usize, torn: usize) {
+        self.plan(n, Fault::PowerLoss(torn));
+    }
+
+    fn plan(&self, n: usize, fault: Fault) {
+        let mut disk = self.disk.lock().unwrap();
+        let at = disk.writes + n;
+        disk.faults.insert(at, fault);
+    }
+
+    /// Undoing a failed append fails too
+    pub fn fail_truncates(&self, fail: bool) {
+        self.disk.lock().unwrap().fail_truncates = fail;
+    }
+
+    /// Appends past `bytes` in all segments together write only what still fits,
+    /// then fail like on a full disk. `None` for no limit.
+    pub fn set_capacity(&self, bytes: Option<usize>) {
+        self.disk.lock().unwrap().capacity = bytes;
+    }
+
+    /// Bytes in all segments together
+    pub fn used(&self) -> usize {
+        let disk = self.disk.lock().unwrap();
+        disk.segments.values().map(|segment| segment.lock().unwrap().data.len()).sum()
+    }
+
+    /// Every append and sync takes at least `delay`
+    pub fn set_delay(&self, delay: Duration) {
+        self.disk.lock().unwrap().delay = delay;
+    }
+
+    /// Reads of the segment `name` block until called again with `None`
+    pub fn stall_reads(&self, name: Option<&str>) {
+        self.disk.lock().unwrap().stalled = name.map(str::to_owned);
+    }
+
+    /// Loses everything that wasn't synced, except for the first `torn` bytes
+    /// appended to every segment since - a write cut short. Segments created or
+    /// deleted since the directory was synced weren't either.
+    pub fn power_loss(&self, torn: usize) {
+        self.disk.lock().unwrap().power_loss(torn);
+    }
+
+    /// Appends so far
+    pub fn writes(&self) -> usize {
+        self.disk.lock().unwrap().writes
+    }
+
+    fn powered(&self) -> Result<std::sync::MutexGuard<'_, Disk>, KopperError> {
+        powered(&self.disk, self.power_cycle)
+    }
+
+    fn handle(&self, name: &str, segment: Arc<Mutex<Segment>>) -> Arc<dyn SegmentFile> {
+        Arc::new(FaultyFile { name: name.to_owned(), segment, disk: self.disk.clone(), power_cycle: self.power_cycle })
+    }
+}
+
+fn powered(disk: &Mutex<Disk>, power_cycle: u64) -> Result<std::sync::MutexGuard<'_, Disk>, KopperError> {
+    let disk = disk.lock().unwrap();
+    match disk.power_cycles == power_cycle {
+        true => Ok(disk),
+        false => Err(failure("lost with the power")),
+    }
+}
+
+struct FaultyFile {
+    name: String,
+    segment: Arc<Mutex<Segment>>,
+    disk: Arc<Mutex<Disk>>,
+    power_cycle: u64,
+}
+
+fn failure(message: &str) -> KopperError {
+    io::Error::other(message.to_owned()).into()
+}
+
+impl FaultyFile {
+    fn powered(&self) -> Result<std::sync::MutexGuard<'_, Disk>, KopperError> {
+        powered(&self.disk, self.power_cycle)
+            .map_err(|_| failure(&format!("{} was lost with the power", self.name)))
+    }
+
+    fn wait(&self) -> Result<(), KopperError> {
+        let delay = self.powered()?.delay;
+        std::thread::sleep(delay);
+        Ok(())
+    }
+}
+
+impl SegmentFile for FaultyFile {
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> Result<(), KopperError> {
+        while self.powered()?.stalled.as_ref() == Some(&self.name) {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let _disk = self.powered()?;
+        let segment = self.segment.lock().unwrap();
+        let from = offset as usize;
+        let part = segment.data.get(from..from + buffer.len())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        buffer.copy_from_slice(part);
+        Ok(())
+    }
+
+    fn append(&self, data: &[u8]) -> Result<(), KopperError> {
+        self.wait()?;
+        let mut disk = self.powered()?;
+        disk.writes += 1;
+        let write = disk.writes;
+        let fault = disk.faults.remove(&write);
+        let space_left = disk.space_left();
+
+        let mut segment = self.segment.lock().unwrap();
+        match fault {
+            None if data.len() > space_left => {
+                segment.data.extend_from_slice(&data[..space_left]);
+                Err(io::Error::from(io::ErrorKind::StorageFull).into())
+            },
+            None => {
+                segment.data.extend_from_slice(data);
+                Ok(())
+            },
+            Some(Fault::Fail) => Err(failure("write failed")),
+            Some(Fault::Short(len)) => {
+                segment.data.extend_from_slice(&data[..len.min(data.len())]);
+                Err(failure("short write"))
+            },
+            Some(Fault::PowerLoss(torn)) => {
+                drop(segment);
+                disk.power_loss(torn);
+                Err(failure("power loss"))
+            },
+        }
+    }
+
+    fn len(&self) -> Result<u64, KopperError> {
+        let _disk = self.powered()?;
+        Ok(self.segment.lock().unwrap().data.len() as u64)
+    }
+
+    fn truncate(&self, len: u64) -> Result<(), KopperError> {
+        let disk = self.powered()?;
+        if disk.fail_truncates {
+            return Err(failure("truncate failed"));
+        }
+        let mut segment = self.segment.lock().unwrap();
+        segment.data.truncate(len as usize);
+        segment.synced = segment.synced.min(segment.data.len());
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<(), KopperError> {
+        self.wait()?;
+        let _disk = self.powered()?;
+        let mut segment = self.segment.lock().unwrap();
+        segment.synced = segment.data.len();
+        Ok(())
+    }
+}
+
+impl SegmentStore for FaultyStore {
+    fn create(&self, name: &str) -> Result<Arc<dyn SegmentFile>, KopperError> {
+        let segment = self.powered()?.segments.entry(name.to_owned()).or_default().clone();
+        Ok(self.handle(name, segment))
+    }
+
+    fn open(&self, name: &str) -> Result<Arc<dyn SegmentFile>, KopperError> {
+        let segment = self.powered()?.segments.get(name).cloned()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        Ok(self.handle(name, segment))
+    }
+
+    fn list(&self) -> Result<Vec<String>, KopperError> {
+        Ok(self.powered()?.segments.keys().cloned().collect())
+    }
+
+    fn delete(&self, name: &str) -> Result<(), KopperError> {
+        match self.powered()?.segments.remove(name) {
+            Some(_) => Ok(()),
+            None => Err(io::Error::from(io::ErrorKind::NotFound).into()),
+        }
+    }
+
+    fn sync_dir(&self) -> Result<(), KopperError> {
+        let mut disk = self.powered()?;
+        disk.linked = disk.segments.clone();
+        Ok(())
+    }
+}
@@ -1,18 +1,30 @@
 mod common;
+// Only stalled reads are needed
+#[allow(dead_code)]
+mod faults;
 use core::time;
+use std::fs;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
 
-use kopperdb::kopper::Kopper;
+use kopperdb::clock::{Clock, MockClock};
+use kopperdb::kopper::{index_segment, ChangeEvent, KeyValueIterator, Kopper, KopperError};
+use kopperdb::lock;
+use kopperdb::pattern::KeyPattern;
+use kopperdb::queue;
+use kopperdb::sharded::ShardedKopper;
+use kopperdb::store::{MemoryStore, SegmentStore};
+use kopperdb::testing::TempDb;
+use kopperdb::trash;
+use kopperdb::versions;
 
 use crate::common::*;
 
-fn get_new_path() -> String {
-    DB_PATH.to_owned() + "/kopper/" + &random_key_value_with_size(20).0
-}
-
 #[test]
 fn test_write_read() {
 
-    let kopper = Kopper::create(&get_new_path(), SEGMENT_SIZE).unwrap();
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
 
     // Write
     let (key, value) = random_key_value();
@@ -28,7 +40,8 @@ fn test_write_read() {
 #[test]
 fn database_recovers_after_dying() {
 
-    let kopper = Kopper::create(&get_new_path(), SEGMENT_SIZE).unwrap();
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
 
     let mut key_values = Vec::new();
     for i in 0..5 {
@@ -37,7 +50,7 @@ fn database_recovers_after_dying() {
     }
 
     // All in-memory structure is dropped
-    let kopper = Kopper::create(&kopper.path(), SEGMENT_SIZE).unwrap();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
     
     for i in key_values {
         let read_response = kopper.read(&i.0).unwrap();
@@ -48,7 +61,8 @@ fn database_recovers_after_dying() {
 #[test]
 fn recover_all_files_from_folder() {
     // Create small segments
-    let kopper = Kopper::create(&get_new_path(), SEGMENT_SIZE).unwrap();
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
     
     // Fill first file quickly
     for _ in 0..3 {
@@ -65,14 +79,15 @@ fn recover_all_files_from_folder() {
 
 #[test]
 fn database_does_not_grow_forever() {
-    let kopper = Kopper::create(&get_new_path(), 14).unwrap();
+    let db = TempDb::new();
+    let kopper = db.kopper(14).unwrap();
 
     // Send 10 identical requests
     let (key, value) = random_key_value_with_size(2);
     for _ in 0..10 {
         kopper.write(&key, &value).unwrap();
-        std::thread::sleep(time::Duration::from_millis(10));
     }
+    kopper.wait_for_compactions().unwrap();
 
     // Verify that database is smaller than 10 x (key + value + 2)
     let all_entries_together_size = 10 * (2 + 2 + 2) / 2;
@@ -83,17 +98,2008 @@ fn database_does_not_grow_forever() {
 
 #[test]
 fn file_offset_is_set_correctly_after_recovery() {
-    let kopper = Kopper::create(&get_new_path(), SEGMENT_SIZE).unwrap();
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
 
     // Write to a file - offset is len(key + value) + 2
     kopper.write("some_key", "222222").unwrap();
 
     // Recreate memory part of database from files
-    let kopper = Kopper::create(&kopper.path(), SEGMENT_SIZE).unwrap();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
     
     // Write to a file again - offset should be recovered too, and correctly saved in in-memory table
     kopper.write("some_key", "333333").unwrap();
 
     let read_response = kopper.read("some_key").unwrap();
     assert_eq!(read_response, "333333");
-}
\ No newline at end of file
+}
+#[test]
+fn closed_database_rejects_operations() {
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    kopper.write("key", "value").unwrap();
+
+    // Every clone shares the closed state
+    let clone = kopper.clone();
+    kopper.close().unwrap();
+
+    assert!(matches!(clone.read("key"), Err(KopperError::Closed)));
+    assert!(matches!(clone.write("key", "other"), Err(KopperError::Closed)));
+}
+
+#[test]
+fn data_survives_close() {
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+
+    // Cut off a few segments so the compactor has work queued during close
+    for _ in 0..10 {
+        let (key, value) = random_key_value_with_size(19);
+        kopper.write(&key, &value).unwrap();
+    }
+    kopper.write("meaningful", "thing").unwrap();
+    kopper.close().unwrap();
+
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    assert_eq!(kopper.read("meaningful").unwrap(), "thing");
+}
+
+#[test]
+fn watch_delivers_matching_writes() {
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    let events = kopper.watch("user:");
+
+    kopper.write("user:1", "a").unwrap();
+    kopper.write("order:1", "b").unwrap();
+    kopper.write("user:2", "c").unwrap();
+
+    assert_eq!(events.try_recv().unwrap(), ChangeEvent::Write { key: "user:1".to_owned(), value: "a".to_owned() });
+    assert_eq!(events.try_recv().unwrap(), ChangeEvent::Write { key: "user:2".to_owned(), value: "c".to_owned() });
+    assert!(events.try_recv().is_err());
+
+    // Closing the database ends the subscription
+    kopper.close().unwrap();
+    assert!(matches!(events.recv(), Err(std::sync::mpsc::RecvError)));
+}
+
+#[test]
+fn watch_into_stops_delivering_once_the_subscription_is_dropped() {
+    use std::sync::{Arc, Mutex};
+
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    let delivered = Arc::new(Mutex::new(Vec::new()));
+    let subscription = {
+        let delivered = delivered.clone();
+        kopper.watch_into("user:", move |event| {
+            delivered.lock().unwrap().push(event);
+            true
+        })
+    };
+
+    kopper.write("user:1", "a").unwrap();
+    kopper.write("order:1", "b").unwrap();
+    drop(subscription);
+    kopper.write("user:2", "c").unwrap();
+    assert_eq!(*delivered.lock().unwrap(), [ChangeEvent::Write { key: "user:1".to_owned(), value: "a".to_owned() }]);
+
+    // The watcher is gone, not just ignored - nothing holds on to the callback
+    assert_eq!(Arc::strong_count(&delivered), 1);
+}
+
+#[test]
+fn published_messages_reach_current_subscribers_only() {
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+
+    assert_eq!(kopper.publish("chat", "nobody hears this").unwrap(), 0);
+    let first = kopper.subscribe("chat");
+    let second = kopper.subscribe("chat");
+    let other = kopper.subscribe("news");
+    assert_eq!(kopper.publish("chat", "hi").unwrap(), 2);
+    assert_eq!(first.try_recv().unwrap(), "hi");
+    assert_eq!(second.try_recv().unwrap(), "hi");
+    assert!(other.try_recv().is_err());
+
+    // Nothing is stored
+    assert!(kopper.is_empty());
+    drop(second);
+    assert_eq!(kopper.publish("chat", "bye").unwrap(), 1);
+
+    // Subscriptions handing messages over end when dropped
+    let subscription = kopper.subscribe_into("chat", |_| true);
+    assert_eq!(kopper.publish("chat", "bye").unwrap(), 2);
+    drop(subscription);
+    assert_eq!(first.try_recv().unwrap(), "bye");
+
+    kopper.close().unwrap();
+    assert_eq!(first.try_recv().unwrap(), "bye");
+    assert!(matches!(first.recv(), Err(std::sync::mpsc::RecvError)));
+    assert!(matches!(kopper.publish("chat", "late"), Err(KopperError::Closed)));
+}
+
+#[test]
+fn deleted_values_can_be_restored_within_the_undelete_window() {
+    let db = TempDb::new();
+    let clock = Arc::new(MockClock::new(UNIX_EPOCH + time::Duration::from_secs(1_000)));
+    let open = || {
+        let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+        kopper.set_clock(clock.clone());
+        kopper.set_undelete_window(Some(time::Duration::from_secs(60)));
+        kopper
+    };
+
+    let kopper = open();
+    kopper.write("kept", "value").unwrap();
+    kopper.write("gone", "value").unwrap();
+    kopper.delete("kept").unwrap();
+    kopper.delete("gone").unwrap();
+    assert!(matches!(kopper.read("kept"), Err(KopperError::KeyDoesNotExist(_))));
+
+    // Kept apart from the keys users write, and out of their sight
+    kopper.write("kept:deleted", "mine").unwrap();
+    assert_eq!(kopper.len(), 1);
+    assert_eq!(kopper.scan().unwrap().map(Result::unwrap).collect::<Vec<_>>(), [("kept:deleted".to_owned(), "mine".to_owned())]);
+    assert!(matches!(kopper.write(&trash::trash_key("kept"), "forged"), Err(KopperError::Rejected(_))));
+    assert!(matches!(kopper.delete(&trash::trash_key("kept")), Err(KopperError::Rejected(_))));
+    kopper.close().unwrap();
+
+    let kopper = open();
+    kopper.undelete("kept").unwrap();
+    assert_eq!(kopper.read("kept").unwrap(), "value");
+    assert_eq!(kopper.read("kept:deleted").unwrap(), "mine");
+    assert!(matches!(kopper.undelete("kept"), Err(KopperError::Rejected(_))));
+
+    // Once the window is over, the value is gone for good
+    clock.advance(time::Duration::from_secs(61));
+    assert!(matches!(kopper.undelete("gone"), Err(KopperError::KeyDoesNotExist(_))));
+
+    // Without a window, nothing is kept
+    kopper.set_undelete_window(None);
+    kopper.delete("kept").unwrap();
+    assert!(matches!(kopper.undelete("kept"), Err(KopperError::KeyDoesNotExist(_))));
+}
+
+#[test]
+fn verify_finds_corrupt_records_and_quarantines_their_segments() {
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    kopper.write("intact", "value").unwrap();
+    kopper.write("corrupt", "value").unwrap();
+    kopper.roll_segment().unwrap();
+    kopper.write("later", "value").unwrap();
+
+    // Goes on after the last key checked in key order, starting over past the last one
+    let keys = |sample| kopper.verify(sample).unwrap().into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+    assert_eq!(keys(2), ["corrupt", "intact"]);
+    assert_eq!(keys(2), ["later", "corrupt"]);
+    assert_eq!(keys(2), ["intact", "later"]);
+
+    // The disk flips a bit of a key under the open database
+    let (segment, mut bytes, at) = fs::read_dir(db.join("kopper")).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find_map(|path| {
+            let bytes = fs::read(&path).ok()?;
+            let at = bytes.windows(7).position(|window| window == b"corrupt")?;
+            Some((path, bytes, at))
+        })
+        .unwrap();
+    bytes[at] ^= 1;
+    fs::write(&segment, bytes).unwrap();
+
+    kopper.set_quarantine(true);
+    // Keys sampled after the segment is quarantined are skipped
+    assert_eq!(kopper.verify(10).unwrap(), [("later".to_owned(), "value".to_owned())]);
+    let stats = kopper.verify_stats();
+    assert_eq!((stats.checked, stats.corrupt, stats.quarantined.len()), (9, 1, 1));
+
+    // Nothing is read from the segment until it's written again
+    assert!(matches!(kopper.read("intact"), Err(KopperError::Quarantined(_))));
+    kopper.write("intact", "again").unwrap();
+    assert_eq!(kopper.read("intact").unwrap(), "again");
+    assert_eq!(kopper.read("later").unwrap(), "value");
+
+    // Values that differ elsewhere are counted too
+    let intact = kopper.verify(10).unwrap();
+    assert_eq!(kopper.cross_check(&intact, |key| Some((key == "later").then(|| "other".to_owned()))).unwrap(), 2);
+    assert_eq!(kopper.verify_stats().mismatched, 2);
+}
+
+#[test]
+fn deleted_key_stays_deleted_after_recovery() {
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+
+    kopper.write("gone", "value").unwrap();
+    kopper.write("kept", "value").unwrap();
+    kopper.delete("gone").unwrap();
+
+    assert!(matches!(kopper.read("gone"), Err(KopperError::KeyDoesNotExist(_))));
+    assert!(matches!(kopper.delete("gone"), Err(KopperError::KeyDoesNotExist(_))));
+
+    // Push the tombstone through a few compactions
+    for _ in 0..10 {
+        let (key, value) = random_key_value_with_size(19);
+        kopper.write(&key, &value).unwrap();
+    }
+    kopper.close().unwrap();
+
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    assert!(matches!(kopper.read("gone"), Err(KopperError::KeyDoesNotExist(_))));
+    assert_eq!(kopper.read("kept").unwrap(), "value");
+}
+
+#[test]
+fn metadata_is_kept_with_the_value_it_was_written_with() {
+    use kopperdb::meta::Meta;
+
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    kopper.set_dedupe(Some(8));
+
+    let svg = Meta { content_type: Some("image/svg+xml".to_owned()), flags: 2 };
+    kopper.write_with_meta("logo", "<svg>logo</svg>", &svg).unwrap();
+    kopper.write_with_meta("plain", "<svg>logo</svg>", &Meta::default()).unwrap();
+    kopper.copy("logo", "copy").unwrap();
+    assert_eq!(kopper.read("logo").unwrap(), "<svg>logo</svg>");
+    assert_eq!(kopper.read_with_meta("plain").unwrap().1, Meta::default());
+    assert!(matches!(kopper.write_with_meta("bad", "x", &Meta { content_type: Some("a\nb".to_owned()), flags: 0 }), Err(KopperError::Rejected(_))));
+    kopper.close().unwrap();
+
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    assert_eq!(kopper.read_with_meta("logo").unwrap(), ("<svg>logo</svg>".to_owned(), svg.clone()));
+    assert_eq!(kopper.read_with_meta("copy").unwrap().1, svg);
+
+    // Replaced by the next write
+    kopper.write("logo", "text").unwrap();
+    assert_eq!(kopper.read_with_meta("logo").unwrap().1, Meta::default());
+    kopper.close().unwrap();
+}
+
+#[test]
+fn compare_and_swap_only_replaces_the_expected_value() {
+    use kopperdb::merge_patch;
+    use serde_json::json;
+
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+
+    assert!(kopper.compare_and_swap("doc", None, r#"{"a":1}"#).unwrap());
+    assert!(!kopper.compare_and_swap("doc", None, r#"{"a":2}"#).unwrap());
+    assert!(!kopper.compare_and_swap("doc", Some(r#"{"a":3}"#), r#"{"a":4}"#).unwrap());
+    assert_eq!(kopper.read("doc").unwrap(), r#"{"a":1}"#);
+
+    // Patched by members, null removing them
+    let mut document: serde_json::Value = serde_json::from_str(&kopper.read("doc").unwrap()).unwrap();
+    merge_patch::apply(&mut document, &json!({ "a": null, "b": { "c": [1] } }));
+    assert!(kopper.compare_and_swap("doc", Some(r#"{"a":1}"#), &document.to_string()).unwrap());
+    assert_eq!(serde_json::from_str::<serde_json::Value>(&kopper.read("doc").unwrap()).unwrap(), json!({ "b": { "c": [1] } }));
+    kopper.close().unwrap();
+}
+
+#[test]
+fn conditional_writes_check_whether_the_key_exists() {
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+
+    assert!(!kopper.write_if_present("key", "replaced").unwrap());
+    assert!(kopper.write_if_absent("key", "added").unwrap());
+    assert!(!kopper.write_if_absent("key", "added again").unwrap());
+    assert_eq!(kopper.read("key").unwrap(), "added");
+
+    assert!(kopper.write_if_present("key", "replaced").unwrap());
+    assert_eq!(kopper.read("key").unwrap(), "replaced");
+
+    kopper.delete("key").unwrap();
+    assert!(!kopper.write_if_present("key", "replaced again").unwrap());
+    assert!(kopper.write_if_absent("key", "added back").unwrap());
+    kopper.close().unwrap();
+}
+
+#[test]
+fn get_and_set_or_delete_hand_out_the_old_value() {
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+
+    assert_eq!(kopper.get_and_set("counter", "1").unwrap(), None);
+    assert_eq!(kopper.get_and_set("counter", "2").unwrap().as_deref(), Some("1"));
+    assert_eq!(kopper.read("counter").unwrap(), "2");
+
+    // Taken off a queue only once
+    kopper.write("job", "payload").unwrap();
+    assert_eq!(kopper.get_and_delete("job").unwrap(), "payload");
+    assert!(matches!(kopper.get_and_delete("job"), Err(KopperError::KeyDoesNotExist(_))));
+    kopper.close().unwrap();
+
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    assert_eq!(kopper.read("counter").unwrap(), "2");
+    assert!(matches!(kopper.read("job"), Err(KopperError::KeyDoesNotExist(_))));
+}
+
+#[test]
+fn keys_are_renamed_and_copied() {
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+
+    kopper.write("draft", "text").unwrap();
+    kopper.write("published", "old").unwrap();
+    kopper.rename("draft", "published").unwrap();
+    assert_eq!(kopper.read("published").unwrap(), "text");
+    assert!(matches!(kopper.read("draft"), Err(KopperError::KeyDoesNotExist(_))));
+    assert!(matches!(kopper.rename("draft", "other"), Err(KopperError::KeyDoesNotExist(_))));
+
+    kopper.copy("published", "backup").unwrap();
+    assert_eq!(kopper.read("published").unwrap(), "text");
+    assert_eq!(kopper.read("backup").unwrap(), "text");
+
+    // Renaming a key to itself leaves it be
+    kopper.rename("backup", "backup").unwrap();
+    assert_eq!(kopper.read("backup").unwrap(), "text");
+
+    // The TTL goes along
+    kopper.write_with_ttl("session", "token", time::Duration::from_secs(60)).unwrap();
+    kopper.rename("session", "renewed").unwrap();
+    assert!(kopper.ttl("renewed").unwrap().is_some());
+    kopper.close().unwrap();
+
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    assert_eq!(kopper.read("published").unwrap(), "text");
+    assert_eq!(kopper.read("backup").unwrap(), "text");
+    assert!(matches!(kopper.read("draft"), Err(KopperError::KeyDoesNotExist(_))));
+    assert!(matches!(kopper.read("session"), Err(KopperError::KeyDoesNotExist(_))));
+    assert!(kopper.ttl("renewed").unwrap().is_some());
+}
+
+#[test]
+fn lists_are_pushed_and_popped_at_either_end() {
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+
+    assert_eq!(kopper.rpush("queue", &["b", "c"]).unwrap(), 2);
+    assert_eq!(kopper.lpush("queue", &["a", "z"]).unwrap(), 4);
+    assert_eq!(kopper.lrange("queue", 0, -1).unwrap(), ["z", "a", "b", "c"]);
+    assert_eq!(kopper.lrange("queue", -2, -1).unwrap(), ["b", "c"]);
+    assert_eq!(kopper.lpop("queue").unwrap().as_deref(), Some("z"));
+    assert_eq!(kopper.rpop("queue").unwrap().as_deref(), Some("c"));
+
+    // Values that aren't lists are left alone
+    kopper.write("plain", "value").unwrap();
+    assert!(matches!(kopper.lpush("plain", &["a"]), Err(KopperError::WrongType(_))));
+    assert_eq!(kopper.read("plain").unwrap(), "value");
+
+    // Popping the last element deletes the list
+    kopper.rpush("single", &["only"]).unwrap();
+    assert_eq!(kopper.lpop("single").unwrap().as_deref(), Some("only"));
+    assert_eq!(kopper.lpop("single").unwrap(), None);
+    assert!(matches!(kopper.read("single"), Err(KopperError::KeyDoesNotExist(_))));
+    assert!(kopper.lrange("single", 0, -1).unwrap().is_empty());
+
+    // A list reads as an array, which written isn't a list
+    assert_eq!(kopper.read("queue").unwrap(), r#"["a","b"]"#);
+    kopper.write("array", "[]").unwrap();
+    assert!(matches!(kopper.lpush("array", &["a"]), Err(KopperError::WrongType(_))));
+    assert!(matches!(kopper.sadd("queue", &["a"]), Err(KopperError::WrongType(_))));
+    kopper.close().unwrap();
+
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    assert_eq!(kopper.lrange("queue", 0, -1).unwrap(), ["a", "b"]);
+}
+
+#[test]
+fn list_changes_are_folded_into_one_record() {
+    let db = TempDb::new();
+    let kopper = db.kopper(2048).unwrap();
+
+    // Changes across segments, each one starting over with the whole list
+    let mut expected = Vec::new();
+    for i in 0..100 {
+        let element = format!("e{i:02}");
+        kopper.rpush("list", &[&element]).unwrap();
+        expected.push(element);
+        if i % 3 == 0 {
+            assert_eq!(kopper.lpop("list").unwrap(), Some(expected.remove(0)));
+        }
+    }
+    assert_eq!(kopper.lrange("list", 0, -1).unwrap(), expected);
+
+    // Once the list is written whole in a segment, only the elements pushed are
+    kopper.roll_segment().unwrap();
+    kopper.lpush("list", &["new"]).unwrap();
+    let size = kopper.size();
+    assert_eq!(kopper.lpop("list").unwrap().as_deref(), Some("new"));
+    assert!(kopper.size() - size < 64);
+
+    kopper.roll_segment().unwrap();
+    kopper.compact().unwrap();
+    kopper.wait_for_compactions().unwrap();
+    assert_eq!(kopper.lrange("list", 0, -1).unwrap(), expected);
+    kopper.close().unwrap();
+
+    let kopper = db.kopper(2048).unwrap();
+    assert_eq!(kopper.lrange("list", 0, -1).unwrap(), expected);
+    assert_eq!(kopper.rpush("list", &["new"]).unwrap(), expected.len() + 1);
+}
+
+#[test]
+fn queues_hand_out_items_once_and_keep_consumer_offsets() {
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+
+    assert_eq!(kopper.push("jobs", "a").unwrap(), 0);
+    assert_eq!(kopper.push("jobs", "b").unwrap(), 1);
+    assert_eq!(kopper.pop("jobs", time::Duration::ZERO).unwrap().as_deref(), Some("a"));
+    assert_eq!(kopper.push("jobs", "c").unwrap(), 2);
+
+    // Offsets stay put as items are popped
+    assert_eq!(kopper.consume("jobs", "audit", 10).unwrap(), [(1, "b".to_owned()), (2, "c".to_owned())]);
+    kopper.commit_offset("jobs", "audit", 2).unwrap();
+    assert!(matches!(kopper.commit_offset("jobs", "audit", 4), Err(KopperError::Rejected(_))));
+
+    // Items and offsets are kept out of sight, apart from the keys users write
+    assert_eq!(kopper.len(), 0);
+    kopper.write("jobs:offsets", "{}").unwrap();
+    assert!(matches!(kopper.write(&queue::offsets_key("jobs"), "{}"), Err(KopperError::Rejected(_))));
+    kopper.close().unwrap();
+
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    assert_eq!(kopper.offset("jobs", "audit").unwrap(), 2);
+    assert_eq!(kopper.consume("jobs", "audit", 10).unwrap(), [(2, "c".to_owned())]);
+    assert_eq!(kopper.pop("jobs", time::Duration::ZERO).unwrap().as_deref(), Some("b"));
+    assert_eq!(kopper.pop("jobs", time::Duration::ZERO).unwrap().as_deref(), Some("c"));
+    assert_eq!(kopper.pop("jobs", time::Duration::from_millis(10)).unwrap(), None);
+    assert_eq!(kopper.offset("jobs", "new").unwrap(), 3);
+
+    // Waits for a push
+    let pusher = kopper.clone();
+    let pushed = std::thread::spawn(move || {
+        std::thread::sleep(time::Duration::from_millis(50));
+        pusher.write("jobs2", "not an item").unwrap();
+        pusher.push("jobs", "d").unwrap()
+    });
+    assert_eq!(kopper.pop("jobs", time::Duration::from_secs(10)).unwrap().as_deref(), Some("d"));
+    assert_eq!(pushed.join().unwrap(), 3);
+
+    // However long the queue, a push or a pop writes as much
+    for i in 0..200 {
+        kopper.push("long", &format!("item{i:03}")).unwrap();
+    }
+    let size = kopper.size();
+    kopper.push("long", "last").unwrap();
+    assert_eq!(kopper.pop("long", time::Duration::ZERO).unwrap().as_deref(), Some("item000"));
+    assert!(kopper.size() - size < 256);
+    assert_eq!(kopper.consume("long", "late", 1).unwrap(), [(1, "item001".to_owned())]);
+}
+
+#[test]
+fn locks_are_held_once_with_growing_fencing_tokens() {
+    let db = TempDb::new();
+    let clock = Arc::new(MockClock::new(UNIX_EPOCH + time::Duration::from_secs(1_000)));
+    let open = || {
+        let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+        kopper.set_clock(clock.clone());
+        kopper
+    };
+
+    let kopper = open();
+    let ttl = time::Duration::from_secs(10);
+    assert_eq!(kopper.acquire_lock("leader", ttl).unwrap(), Some(1));
+    assert_eq!(kopper.acquire_lock("leader", ttl).unwrap(), None);
+    assert!(!kopper.release_lock("leader", 2).unwrap());
+    assert!(kopper.release_lock("leader", 1).unwrap());
+    assert_eq!(kopper.acquire_lock("leader", ttl).unwrap(), Some(2));
+
+    // Keys users write don't touch the lock, nor are they touched by it
+    kopper.write("leader", "mine").unwrap();
+    kopper.write("leader:fence", "mine").unwrap();
+    kopper.delete("leader").unwrap();
+    assert_eq!(kopper.acquire_lock("leader", ttl).unwrap(), None);
+    assert!(matches!(kopper.delete(&lock::lock_key("leader")), Err(KopperError::Rejected(_))));
+    assert_eq!(kopper.len(), 1);
+
+    // An expired lease is free to take, and can't be released any more
+    clock.advance(time::Duration::from_secs(11));
+    assert_eq!(kopper.acquire_lock("leader", ttl).unwrap(), Some(3));
+    assert!(!kopper.release_lock("leader", 2).unwrap());
+    kopper.close().unwrap();
+
+    // Tokens keep growing once the lock is gone
+    let kopper = open();
+    clock.advance(time::Duration::from_secs(11));
+    assert_eq!(kopper.acquire_lock("leader", ttl).unwrap(), Some(4));
+}
+
+#[test]
+fn versioned_keys_keep_their_previous_values() {
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+
+    kopper.write("doc", "v1").unwrap();
+    kopper.keep_versions("doc", 2);
+    kopper.write("doc", "v2").unwrap();
+    kopper.write("doc", "v3").unwrap();
+    kopper.write("doc", "v4").unwrap();
+    assert_eq!(kopper.read_versions("doc").unwrap(), ["v4", "v3", "v2"]);
+
+    // The history is out of sight, and no user key is taken for it
+    assert_eq!(kopper.len(), 1);
+    kopper.write("doc:versions", "mine").unwrap();
+    assert_eq!(kopper.read_versions("doc").unwrap(), ["v4", "v3", "v2"]);
+    kopper.delete("doc:versions").unwrap();
+    assert!(matches!(kopper.write(&versions::history_key("doc"), "forged"), Err(KopperError::Rejected(_))));
+
+    // Deletes keep the value deleted
+    kopper.delete("doc").unwrap();
+    assert_eq!(kopper.read_versions("doc").unwrap(), ["v4", "v3"]);
+    kopper.write("doc", "v5").unwrap();
+
+    // Versions cut off are compacted away, the ones kept survive
+    for _ in 0..10 {
+        kopper.roll_segment().unwrap();
+        kopper.compact().unwrap();
+    }
+    kopper.close().unwrap();
+
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    assert_eq!(kopper.read_versions("doc").unwrap(), ["v5", "v4", "v3"]);
+    assert!(matches!(kopper.read_versions("missing"), Err(KopperError::KeyDoesNotExist(_))));
+}
+
+#[test]
+fn sets_keep_their_members_through_changes() {
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+
+    assert_eq!(kopper.sadd("tags", &["red", "green", "red"]).unwrap(), 2);
+    assert_eq!(kopper.sadd("tags", &["green", "blue"]).unwrap(), 1);
+    assert_eq!(kopper.srem("tags", &["red", "missing"]).unwrap(), 1);
+    assert!(kopper.sismember("tags", "blue").unwrap());
+    assert!(!kopper.sismember("tags", "red").unwrap());
+    assert_eq!(kopper.smembers("tags").unwrap().into_iter().collect::<Vec<_>>(), ["blue", "green"]);
+    assert_eq!(kopper.read("tags").unwrap(), r#"["blue","green"]"#);
+    assert_eq!(kopper.scan().unwrap().map(Result::unwrap).collect::<Vec<_>>(), [("tags".to_owned(), r#"["blue","green"]"#.to_owned())]);
+
+    // Values of other types are left alone, and so are sets
+    kopper.write("plain", "value").unwrap();
+    assert!(matches!(kopper.sadd("plain", &["a"]), Err(KopperError::WrongType(_))));
+    assert!(matches!(kopper.smembers("plain"), Err(KopperError::WrongType(_))));
+    assert!(matches!(kopper.lpush("tags", &["a"]), Err(KopperError::WrongType(_))));
+    assert!(kopper.smembers("missing").unwrap().is_empty());
+
+    // Removing the last member deletes the set
+    kopper.sadd("single", &["only"]).unwrap();
+    assert_eq!(kopper.srem("single", &["only"]).unwrap(), 1);
+    assert!(matches!(kopper.read("single"), Err(KopperError::KeyDoesNotExist(_))));
+    kopper.close().unwrap();
+
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    assert_eq!(kopper.smembers("tags").unwrap().len(), 2);
+    kopper.copy("tags", "copied").unwrap();
+    assert_eq!(kopper.sadd("copied", &["red"]).unwrap(), 1);
+    assert_eq!(kopper.smembers("copied").unwrap().len(), 3);
+}
+
+#[test]
+fn set_changes_are_folded_into_one_record() {
+    let db = TempDb::new();
+    let kopper = db.kopper(2048).unwrap();
+
+    // Changes across segments, each one starting over with the whole set
+    for i in 0..100 {
+        kopper.sadd("set", &[&format!("m{i:02}")]).unwrap();
+        if i % 3 == 0 {
+            kopper.srem("set", &[&format!("m{:02}", i / 2)]).unwrap();
+        }
+    }
+    let expected = kopper.smembers("set").unwrap();
+    assert!(expected.contains("m99") && !expected.contains("m00"));
+
+    // Once the set is written whole in a segment, only the members changed are
+    kopper.roll_segment().unwrap();
+    kopper.sadd("set", &["new"]).unwrap();
+    let size = kopper.size();
+    kopper.srem("set", &["new"]).unwrap();
+    assert!(kopper.size() - size < 64);
+
+    kopper.roll_segment().unwrap();
+    kopper.compact().unwrap();
+    kopper.wait_for_compactions().unwrap();
+    assert_eq!(kopper.smembers("set").unwrap(), expected);
+    kopper.close().unwrap();
+
+    let kopper = db.kopper(2048).unwrap();
+    assert_eq!(kopper.smembers("set").unwrap(), expected);
+    assert_eq!(kopper.sadd("set", &["new"]).unwrap(), 1);
+    assert_eq!(kopper.smembers("set").unwrap().len(), expected.len() + 1);
+}
+
+#[test]
+fn hashes_change_a_field_at_a_time() {
+    let db = TempDb::new();
+    let kopper = db.kopper(2048).unwrap();
+
+    assert_eq!(kopper.hset("user", &[("name", "ann"), ("age", "30")]).unwrap(), 2);
+    assert_eq!(kopper.hset("user", &[("age", "31"), ("city", "oslo")]).unwrap(), 1);
+    assert_eq!(kopper.hset("user", &[("age", "31")]).unwrap(), 0);
+    assert_eq!(kopper.hget("user", "age").unwrap().as_deref(), Some("31"));
+    assert_eq!(kopper.hget("user", "missing").unwrap(), None);
+    assert_eq!(kopper.hdel("user", &["city", "missing"]).unwrap(), 1);
+    assert_eq!(kopper.read("user").unwrap(), r#"{"age":"31","name":"ann"}"#);
+
+    // Setting one field of a big hash writes only that field
+    kopper.roll_segment().unwrap();
+    kopper.hset("user", &[("bio", &"x".repeat(500))]).unwrap();
+    let size = kopper.size();
+    kopper.hset("user", &[("age", "32")]).unwrap();
+    assert!(kopper.size() - size < 64);
+
+    kopper.write("plain", "value").unwrap();
+    assert!(matches!(kopper.hset("plain", &[("field", "value")]), Err(KopperError::WrongType(_))));
+    kopper.sadd("set", &["member"]).unwrap();
+    assert!(matches!(kopper.hgetall("set"), Err(KopperError::WrongType(_))));
+    assert!(matches!(kopper.smembers("user"), Err(KopperError::WrongType(_))));
+    assert!(kopper.hgetall("missing").unwrap().is_empty());
+
+    kopper.roll_segment().unwrap();
+    kopper.compact().unwrap();
+    kopper.wait_for_compactions().unwrap();
+    let expected = kopper.hgetall("user").unwrap();
+    assert_eq!(expected.len(), 3);
+    kopper.close().unwrap();
+
+    let kopper = db.kopper(2048).unwrap();
+    assert_eq!(kopper.hgetall("user").unwrap(), expected);
+    assert_eq!(kopper.hdel("user", &["name", "age", "bio"]).unwrap(), 3);
+    assert!(matches!(kopper.read("user"), Err(KopperError::KeyDoesNotExist(_))));
+}
+
+#[test]
+fn counters_sum_over_a_window() {
+    let db = TempDb::new();
+    let clock = Arc::new(MockClock::new(UNIX_EPOCH + time::Duration::from_secs(1_000)));
+    let open = || {
+        let kopper = db.kopper(2048).unwrap();
+        kopper.set_clock(clock.clone());
+        kopper
+    };
+
+    let kopper = open();
+    assert_eq!(kopper.incr("hits", 1).unwrap(), 1);
+    clock.advance(time::Duration::from_secs(30 * 60));
+    assert_eq!(kopper.incr("hits", 2).unwrap(), 3);
+    clock.advance(time::Duration::from_secs(45 * 60));
+    let size = kopper.size();
+    assert_eq!(kopper.incr("hits", 4).unwrap(), 7);
+    assert!(kopper.size() - size < 64);
+
+    let hour = time::Duration::from_secs(60 * 60);
+    assert_eq!(kopper.counted("hits", hour).unwrap(), 6);
+    assert_eq!(kopper.counted("hits", time::Duration::ZERO).unwrap(), 4);
+    assert_eq!(kopper.counted("missing", hour).unwrap(), 0);
+    assert_eq!(kopper.read("hits").unwrap(), "7");
+    assert!(matches!(kopper.sadd("hits", &["member"]), Err(KopperError::WrongType(_))));
+
+    // Compacted, the increments from before this minute are counted by the minute
+    kopper.roll_segment().unwrap();
+    kopper.compact().unwrap();
+    kopper.wait_for_compactions().unwrap();
+    assert_eq!(kopper.counted("hits", hour).unwrap(), 6);
+    kopper.close().unwrap();
+
+    let kopper = open();
+    assert_eq!(kopper.counted("hits", hour).unwrap(), 6);
+    assert_eq!(kopper.counted("hits", time::Duration::ZERO).unwrap(), 4);
+    assert_eq!(kopper.incr("hits", -7).unwrap(), 0);
+}
+
+#[test]
+fn fold_and_scan_where_read_segments_in_order() {
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+
+    for i in 0..20 {
+        kopper.write(&format!("user:{i:02}"), &i.to_string()).unwrap();
+    }
+    kopper.write("other", "100").unwrap();
+    kopper.delete("user:00").unwrap();
+    kopper.write("user:01", "50").unwrap();
+    kopper.sadd("user:set", &["a"]).unwrap();
+
+    let sum = kopper.fold(0, |sum, _, value| sum + value.parse::<i32>().unwrap_or(0)).unwrap();
+    assert_eq!(sum, (2..20).sum::<i32>() + 50 + 100);
+    assert_eq!(kopper.fold(0, |count, _, _| count + 1).unwrap(), 21);
+
+    let found = kopper.scan_where(|key| key.starts_with("user:"), |_, value| value.len() > 1).unwrap();
+    let keys: Vec<&str> = found.iter().map(|(key, _)| key.as_str()).collect();
+    assert_eq!(keys[..3], ["user:01", "user:10", "user:11"]);
+    assert_eq!(found.last().unwrap(), &("user:set".to_owned(), r#"["a"]"#.to_owned()));
+    assert_eq!(found.len(), 12);
+
+    let keys_only = kopper.scan_where(|key| key == "other", |_, _| true).unwrap();
+    assert_eq!(keys_only, [("other".to_owned(), "100".to_owned())]);
+}
+
+#[test]
+fn keys_are_searched_a_page_at_a_time() {
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+
+    for user in ["1", "2", "3", "10"] {
+        kopper.write(&format!("user:{user}:settings"), "{}").unwrap();
+        kopper.write(&format!("user:{user}:name"), "ann").unwrap();
+    }
+    kopper.delete("user:2:settings").unwrap();
+
+    let glob = KeyPattern::Glob("user:*:settings".to_owned());
+    let page = kopper.scan_match(&glob, None, 2).unwrap();
+    assert_eq!(page, ["user:10:settings", "user:1:settings"]);
+    let page = kopper.scan_match(&glob, Some(&page[1]), 2).unwrap();
+    assert_eq!(page, ["user:3:settings"]);
+
+    let regex = KeyPattern::regex(r"^user:\d{2}:").unwrap();
+    assert_eq!(kopper.scan_match(&regex, None, 10).unwrap(), ["user:10:name", "user:10:settings"]);
+}
+
+#[test]
+fn numeric_index_finds_keys_by_range() {
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    let score = |_: &str, value: &str| serde_json::from_str::<serde_json::Value>(value).ok()?["score"].as_f64();
+
+    kopper.write("ann", r#"{"score": 30}"#).unwrap();
+    kopper.write("bob", r#"{"score": 5}"#).unwrap();
+    kopper.write("cat", "no score").unwrap();
+    kopper.create_numeric_index("score", score).unwrap();
+
+    kopper.write("dan", r#"{"score": 99.5}"#).unwrap();
+    kopper.write("bob", r#"{"score": 50}"#).unwrap();
+    kopper.write("eve", r#"{"score": 100}"#).unwrap();
+    kopper.delete("ann").unwrap();
+    assert_eq!(kopper.lookup_range("score", 10.0..100.0).unwrap(), ["bob", "dan"]);
+    assert_eq!(kopper.lookup_range("score", 60.0..).unwrap(), ["dan", "eve"]);
+    assert!(matches!(kopper.lookup_range("missing", ..), Err(KopperError::KeyDoesNotExist(_))));
+
+    // Rebuilt once a follower catches up
+    let follower = Kopper::follow(&kopper.path(), time::Duration::from_secs(3600)).unwrap();
+    follower.create_numeric_index("score", score).unwrap();
+    kopper.write("fay", r#"{"score": 1}"#).unwrap();
+    kopper.write("eve", "no score").unwrap();
+    follower.refresh().unwrap();
+    assert_eq!(follower.lookup_range("score", ..).unwrap(), ["fay", "bob", "dan"]);
+
+    assert!(kopper.drop_numeric_index("score"));
+    assert!(kopper.lookup_range("score", ..).is_err());
+}
+
+#[test]
+fn hooks_check_and_change_values_in_order() {
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    let deleted = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+    kopper.on_write(|key, value| match value.is_empty() {
+        true => Err(KopperError::Rejected(format!("{key} can't be empty"))),
+        false => Ok(value),
+    });
+    // Values of secret keys are stored reversed
+    kopper.on_write(|key, value| Ok(match key.starts_with("secret:") {
+        true => value.chars().rev().collect(),
+        false => value,
+    }));
+    kopper.on_read(|key, value| Ok(match key.starts_with("secret:") {
+        true => value.chars().rev().collect(),
+        false => value,
+    }));
+    kopper.on_delete({
+        let deleted = deleted.clone();
+        move |key| {
+            if key == "kept" {
+                return Err(KopperError::Rejected("kept can't be deleted".to_owned()));
+            }
+            deleted.lock().unwrap().push(key.to_owned());
+            Ok(())
+        }
+    });
+
+    kopper.write("secret:a", "abc").unwrap();
+    kopper.write_batch(&[("secret:b", "de"), ("plain", "fg")]).unwrap();
+    assert!(matches!(kopper.write("empty", ""), Err(KopperError::Rejected(_))));
+    assert!(matches!(kopper.read("empty"), Err(KopperError::KeyDoesNotExist(_))));
+    assert_eq!(kopper.read("secret:a").unwrap(), "abc");
+    assert_eq!(kopper.get_and_set("secret:b", "hi").unwrap().as_deref(), Some("de"));
+    let stored: Vec<(String, String)> = kopper.scan().unwrap().map(Result::unwrap).collect();
+    assert_eq!(stored[1..], [("secret:a".to_owned(), "cba".to_owned()), ("secret:b".to_owned(), "ih".to_owned())]);
+
+    kopper.write("kept", "value").unwrap();
+    assert!(matches!(kopper.delete("kept"), Err(KopperError::Rejected(_))));
+    assert_eq!(kopper.get_and_delete("secret:b").unwrap(), "hi");
+    kopper.delete("plain").unwrap();
+    assert_eq!(*deleted.lock().unwrap(), ["secret:b", "plain"]);
+    assert_eq!(kopper.read("kept").unwrap(), "value");
+}
+
+#[test]
+fn scan_is_a_consistent_snapshot() {
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+
+    kopper.write("b", "2").unwrap();
+    kopper.write("a", "1").unwrap();
+    kopper.write("c", "3").unwrap();
+    kopper.delete("c").unwrap();
+
+    let scan = kopper.scan().unwrap();
+
+    // Changes after the scan started are not visible
+    kopper.write("a", "changed").unwrap();
+    kopper.write("d", "4").unwrap();
+
+    let entries: Vec<(String, String)> = scan.map(Result::unwrap).collect();
+    assert_eq!(entries, vec![("a".to_owned(), "1".to_owned()), ("b".to_owned(), "2".to_owned())]);
+}
+
+#[test]
+fn write_batch_spanning_segments() {
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+
+    // 20 entries of 42 bytes - more than 8 segments' worth
+    let key_values: Vec<(String, String)> = (0..20).map(|_| random_key_value_with_size(20)).collect();
+    let mut batch: Vec<(&str, &str)> = key_values.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+    batch.push((&key_values[0].0, "overwritten"));
+
+    kopper.write_batch(&batch).unwrap();
+
+    // Let queued compactions finish, so they don't race with recovery
+    kopper.close().unwrap();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    assert_eq!(kopper.read(&key_values[0].0).unwrap(), "overwritten");
+    for (key, value) in &key_values[1..] {
+        assert_eq!(&kopper.read(key).unwrap(), value);
+    }
+}
+
+#[test]
+fn backup_can_be_opened() {
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+
+    let key_values: Vec<(String, String)> = (0..10).map(|_| random_key_value()).collect();
+    for (key, value) in &key_values {
+        kopper.write(key, value).unwrap();
+    }
+
+    let backup_path = db.join("backup");
+    kopper.backup_to(&backup_path).unwrap();
+
+    // Not part of the backup
+    kopper.write("late", "write").unwrap();
+
+    let backup = Kopper::create(&backup_path, SEGMENT_SIZE).unwrap();
+    for (key, value) in &key_values {
+        assert_eq!(&backup.read(key).unwrap(), value);
+    }
+    assert!(matches!(backup.read("late"), Err(KopperError::KeyDoesNotExist(_))));
+}
+
+#[test]
+fn compaction_is_reported() {
+    let db = TempDb::new();
+    let kopper = db.kopper(14).unwrap();
+    let compactions = kopper.compactions();
+
+    // Overwriting a single key leaves only stale records behind
+    for _ in 0..5 {
+        kopper.write("ab", "cd").unwrap();
+    }
+
+    let report = compactions.recv_timeout(time::Duration::from_secs(5)).unwrap();
+    assert!(report.reclaimed_bytes > 0);
+    assert_eq!(kopper.len(), 1);
+}
+
+#[test]
+fn recovery_skips_hidden_files() {
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    let path = kopper.path();
+    kopper.write("meaningful", "thing").unwrap();
+    std::fs::write(path.clone() + "/.sidecar", "not a segment").unwrap();
+
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    assert_eq!(kopper.read("meaningful").unwrap(), "thing");
+}
+
+#[test]
+fn compact_on_demand() {
+    let db = TempDb::new();
+    let kopper = db.kopper(14).unwrap();
+    // Live, so compactions triggered by the writes can't get rid of every sealed segment
+    kopper.write("xy", "zz").unwrap();
+    for _ in 0..5 {
+        kopper.write("ab", "cd").unwrap();
+    }
+
+    let compactions = kopper.compactions();
+    assert!(kopper.compact().unwrap() > 0);
+    kopper.wait_for_compactions().unwrap();
+    assert!(compactions.try_recv().is_ok());
+
+    kopper.close().unwrap();
+    assert!(matches!(kopper.compact(), Err(KopperError::Closed)));
+
+    let kopper = db.kopper(14).unwrap();
+    assert_eq!(kopper.read("ab").unwrap(), "cd");
+}
+
+#[test]
+fn segment_rolls_on_demand() {
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    assert_eq!(kopper.roll_segment().unwrap(), "0_0");
+
+    kopper.write("ab", "cd").unwrap();
+    let rolled = kopper.roll_segment().unwrap();
+    assert_eq!(rolled, "1_0");
+    // Nothing written since, nothing to seal
+    assert_eq!(kopper.roll_segment().unwrap(), rolled);
+
+    kopper.write("ef", "gh").unwrap();
+    let segments = kopper.segment_stats().unwrap();
+    assert_eq!(segments.iter().map(|segment| segment.name.as_str()).collect::<Vec<_>>(), ["0_0", "1_0"]);
+    assert_eq!(segments[0].entries, 1);
+    assert_eq!(segments[1].entries, 1);
+
+    kopper.set_read_only(true);
+    assert!(matches!(kopper.roll_segment(), Err(KopperError::ReadOnly)));
+    kopper.close().unwrap();
+
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    assert_eq!(kopper.read("ab").unwrap(), "cd");
+    assert_eq!(kopper.read("ef").unwrap(), "gh");
+}
+
+#[test]
+fn changes_replay_on_read_only_replica() {
+    let db = TempDb::new();
+    let primary = db.kopper(SEGMENT_SIZE).unwrap();
+    primary.set_change_log_capacity(2);
+    primary.write("a", "1").unwrap();
+    primary.write("b", "2").unwrap();
+    primary.delete("a").unwrap();
+
+    // Only the newest two are kept
+    assert!(matches!(primary.changes_since(0, 10), Err(KopperError::ChangesUnavailable(0))));
+    let changes = primary.changes_since(1, 10).unwrap();
+    assert_eq!(changes.iter().map(|change| change.sequence).collect::<Vec<_>>(), vec![2, 3]);
+    assert!(primary.changes_since(3, 10).unwrap().is_empty());
+
+    let replica = Kopper::create(&db.join("replica"), SEGMENT_SIZE).unwrap();
+    replica.set_read_only(true);
+    assert!(matches!(replica.write("a", "1"), Err(KopperError::ReadOnly)));
+
+    // Deleting a key the replica doesn't have is fine
+    let events: Vec<ChangeEvent> = changes.into_iter().map(|change| change.event).collect();
+    replica.apply(&events).unwrap();
+    assert_eq!(replica.read("b").unwrap(), "2");
+    assert_eq!(replica.len(), 1);
+}
+
+#[test]
+fn writes_wait_for_flush_and_replication() {
+    let db = TempDb::new();
+    // Everything fits in one segment, so no compaction changes the size meanwhile
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    let first = kopper.write("ab", "cd").unwrap();
+    let second = kopper.write_batch(&[("ef", "gh"), ("ij", "kl")]).unwrap();
+    let third = kopper.delete("ab").unwrap();
+    assert_eq!((first.sequence, second.sequence, third.sequence), (1, 3, 4));
+    assert_eq!(third.size, kopper.size());
+
+    kopper.wait_for_flush(second.sequence).unwrap();
+    kopper.wait_for_flush(third.sequence).unwrap();
+
+    let timeout = time::Duration::from_millis(50);
+    assert!(matches!(kopper.wait_for_replication(third.sequence, timeout), Err(KopperError::NotReplicated(4))));
+
+    let replica = kopper.clone();
+    let confirm = std::thread::spawn(move || {
+        std::thread::sleep(time::Duration::from_millis(20));
+        replica.confirm_replication(3);
+        std::thread::sleep(time::Duration::from_millis(20));
+        replica.confirm_replication(4);
+    });
+    kopper.wait_for_replication(third.sequence, time::Duration::from_secs(10)).unwrap();
+    confirm.join().unwrap();
+    assert_eq!(kopper.replicated_sequence(), 4);
+}
+
+#[test]
+fn follower_catches_up_with_owner() {
+    let db = TempDb::new();
+    let owner = db.kopper(14).unwrap();
+    let path = owner.path();
+    owner.write("ab", "cd").unwrap();
+
+    // Refreshed by hand only
+    let follower = Kopper::follow(&path, time::Duration::from_secs(3600)).unwrap();
+    assert_eq!(follower.read("ab").unwrap(), "cd");
+    assert!(matches!(follower.write("ab", "ef"), Err(KopperError::ReadOnly)));
+
+    // Tail of the active segment, then new segments and compacted ones
+    owner.write("gh", "ij").unwrap();
+    follower.refresh().unwrap();
+    assert_eq!(follower.read("gh").unwrap(), "ij");
+
+    for _ in 0..5 {
+        owner.write("ab", "kl").unwrap();
+    }
+    owner.delete("gh").unwrap();
+    owner.compact().unwrap();
+    owner.wait_for_compactions().unwrap();
+
+    follower.refresh().unwrap();
+    assert_eq!(follower.read("ab").unwrap(), "kl");
+    assert!(matches!(follower.read("gh"), Err(KopperError::KeyDoesNotExist(_))));
+    assert_eq!(follower.len(), owner.len());
+}
+
+#[test]
+fn follower_refreshes_on_its_clock() {
+    let db = TempDb::new();
+    let owner = db.kopper(SEGMENT_SIZE).unwrap();
+    let path = owner.path();
+    owner.write("ab", "cd").unwrap();
+
+    let clock = Arc::new(MockClock::new(UNIX_EPOCH));
+    let follower = Kopper::follow_with_clock(&path, time::Duration::from_secs(60), clock.clone()).unwrap();
+    clock.wait_for_sleeps(1);
+
+    owner.write("gh", "ij").unwrap();
+    clock.advance(time::Duration::from_secs(59));
+    assert!(matches!(follower.read("gh"), Err(KopperError::KeyDoesNotExist(_))));
+
+    // Asleep again once it's refreshed
+    clock.advance(time::Duration::from_secs(1));
+    clock.wait_for_sleeps(2);
+    assert_eq!(follower.read("gh").unwrap(), "ij");
+    follower.close().unwrap();
+}
+
+#[test]
+fn backups_are_timestamped_with_the_database_clock() {
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    kopper.write("ab", "cd").unwrap();
+
+    let clock = Arc::new(MockClock::new(UNIX_EPOCH + time::Duration::from_secs(1_000)));
+    kopper.set_clock(clock.clone());
+    let manifest = kopper.backup_incremental(&db.join("backup"), None).unwrap();
+    assert_eq!(manifest.created_at, clock.now_millis());
+}
+
+#[test]
+fn values_expire_after_their_ttl() {
+    let db = TempDb::new();
+    let clock = Arc::new(MockClock::new(UNIX_EPOCH + time::Duration::from_secs(1_000)));
+    let open = || {
+        let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+        kopper.set_clock(clock.clone());
+        kopper
+    };
+
+    let kopper = open();
+    kopper.write("old", "forever").unwrap();
+    kopper.write_with_ttl("old", "for a while", time::Duration::from_secs(10)).unwrap();
+    kopper.write_with_ttl("kept", "for a while", time::Duration::from_secs(10)).unwrap();
+    kopper.write("kept", "forever").unwrap();
+    kopper.write_with_ttl("later", "for longer", time::Duration::from_secs(60)).unwrap();
+    assert_eq!(kopper.ttl("old").unwrap(), Some(time::Duration::from_secs(10)));
+    assert_eq!(kopper.ttl("kept").unwrap(), None);
+    kopper.close().unwrap();
+
+    // Recovered with the time they expire at
+    let kopper = open();
+    assert_eq!(kopper.read("old").unwrap(), "for a while");
+    clock.advance(time::Duration::from_secs(10));
+    assert!(matches!(kopper.read("old"), Err(KopperError::KeyDoesNotExist(_))));
+    assert!(kopper.ttl("old").is_err());
+    assert_eq!(kopper.read("kept").unwrap(), "forever");
+    assert_eq!(kopper.ttl("later").unwrap(), Some(time::Duration::from_secs(50)));
+    let scanned: Vec<_> = kopper.scan().unwrap().map(|entry| entry.unwrap().0).collect();
+    assert_eq!(scanned, ["kept", "later"]);
+
+    // Swept for good, the value written before doesn't come back
+    assert_eq!(kopper.len(), 3);
+    assert_eq!(kopper.sweep_expired(100).unwrap(), 1);
+    assert_eq!(kopper.len(), 2);
+    clock.advance(time::Duration::from_secs(50));
+    assert_eq!(kopper.sweep_expired(100).unwrap(), 1);
+    kopper.compact().unwrap();
+    kopper.wait_for_compactions().unwrap();
+    kopper.close().unwrap();
+
+    let kopper = open();
+    assert!(matches!(kopper.read("old"), Err(KopperError::KeyDoesNotExist(_))));
+    assert!(matches!(kopper.read("later"), Err(KopperError::KeyDoesNotExist(_))));
+    assert_eq!(kopper.len(), 1);
+}
+
+#[test]
+fn values_expire_after_the_default_ttl() {
+    let db = TempDb::new();
+    let clock = Arc::new(MockClock::new(UNIX_EPOCH + time::Duration::from_secs(1_000)));
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    kopper.set_clock(clock.clone());
+    kopper.write("before", "forever").unwrap();
+
+    kopper.set_default_ttl(Some(time::Duration::from_secs(10)));
+    kopper.write("session", "for a while").unwrap();
+    kopper.write_batch(&[("batched", "for a while")]).unwrap();
+    kopper.write_with_ttl("own", "for longer", time::Duration::from_secs(60)).unwrap();
+    kopper.apply(&[ChangeEvent::Write { key: "applied".to_owned(), value: "forever".to_owned() }]).unwrap();
+    assert_eq!(kopper.ttl("session").unwrap(), Some(time::Duration::from_secs(10)));
+    assert_eq!(kopper.ttl("batched").unwrap(), Some(time::Duration::from_secs(10)));
+    assert_eq!(kopper.ttl("own").unwrap(), Some(time::Duration::from_secs(60)));
+    assert_eq!(kopper.ttl("before").unwrap(), None);
+    assert_eq!(kopper.ttl("applied").unwrap(), None);
+
+    clock.advance(time::Duration::from_secs(10));
+    assert!(matches!(kopper.read("session"), Err(KopperError::KeyDoesNotExist(_))));
+    assert!(matches!(kopper.read("batched"), Err(KopperError::KeyDoesNotExist(_))));
+    assert_eq!(kopper.read("own").unwrap(), "for longer");
+
+    kopper.set_default_ttl(None);
+    kopper.write("session", "forever").unwrap();
+    assert_eq!(kopper.ttl("session").unwrap(), None);
+}
+
+#[test]
+fn snapshot_installs_into_another_database() {
+    let db = TempDb::new();
+    let primary = db.kopper(14).unwrap();
+    for _ in 0..5 {
+        primary.write("ab", "cd").unwrap();
+    }
+    primary.write("ef", "gh").unwrap();
+    primary.delete("ef").unwrap();
+    primary.write("ij", "kl").unwrap();
+
+    let snapshot = db.join("snapshot");
+    let sequence = primary.snapshot_to(&snapshot).unwrap();
+    assert_eq!(sequence, primary.sequence());
+
+    let path = db.join("replica");
+    let replica = Kopper::create(&path, 14).unwrap();
+    replica.write("mn", "op").unwrap();
+    let log = replica.log_id();
+
+    replica.install(&snapshot).unwrap();
+    assert_ne!(replica.log_id(), log);
+    assert_eq!(replica.read("ab").unwrap(), "cd");
+    assert_eq!(replica.read("ij").unwrap(), "kl");
+    assert!(matches!(replica.read("ef"), Err(KopperError::KeyDoesNotExist(_))));
+    assert!(matches!(replica.read("mn"), Err(KopperError::KeyDoesNotExist(_))));
+
+    // Installed segments are the database's own from now on
+    replica.write("qr", "st").unwrap();
+    replica.close().unwrap();
+    let reopened = Kopper::create(&path, 14).unwrap();
+    assert_eq!(reopened.len(), 3);
+    assert_eq!(reopened.read("qr").unwrap(), "st");
+}
+
+#[test]
+fn snapshot_is_a_point_in_time_copy() {
+    let db = TempDb::new();
+    let kopper = db.kopper(14).unwrap();
+    for i in 0..20 {
+        kopper.write(&format!("k{}", i % 4), &i.to_string()).unwrap();
+    }
+    kopper.delete("k3").unwrap();
+
+    // Compact and keep writing meanwhile - none of it may leak into the snapshot
+    let writer = kopper.clone();
+    let writes = std::thread::spawn(move || {
+        for i in 0..50 {
+            writer.write("late", &i.to_string()).unwrap();
+        }
+    });
+    kopper.compact().unwrap();
+    let snapshot = db.join("snapshot");
+    let sequence = kopper.snapshot_to(&snapshot).unwrap();
+    writes.join().unwrap();
+    kopper.wait_for_compactions().unwrap();
+
+    let copy = Kopper::create(&snapshot, 14).unwrap();
+    assert_eq!(copy.read("k0").unwrap(), "16");
+    assert_eq!(copy.read("k2").unwrap(), "18");
+    assert!(matches!(copy.read("k3"), Err(KopperError::KeyDoesNotExist(_))));
+
+    // Every write the snapshot includes, and none after it
+    let late = (sequence - 21) as usize;
+    match copy.read("late") {
+        Ok(value) => assert_eq!(value.parse::<usize>().unwrap(), late - 1),
+        Err(_) => assert_eq!(late, 0),
+    }
+    assert_eq!(kopper.read("late").unwrap(), "49");
+}
+
+#[test]
+fn incremental_backups_layer_over_a_full_one() {
+    let db = TempDb::new();
+    let kopper = db.kopper(14).unwrap();
+    for i in 0..10 {
+        kopper.write(&format!("k{}", i % 5), &i.to_string()).unwrap();
+    }
+
+    let full = db.join("full");
+    let manifest = kopper.backup_incremental(&full, None).unwrap();
+    assert!(manifest.segments.iter().all(|segment| segment.included));
+
+    // Sealed segments are the parent's, only the active one and the new ones are kept
+    kopper.write("k1", "changed").unwrap();
+    kopper.delete("k2").unwrap();
+    let first = db.join("first");
+    let first_manifest = kopper.backup_incremental(&first, Some(&full)).unwrap();
+    let included = first_manifest.segments.iter().filter(|segment| segment.included).count();
+    assert!(included < first_manifest.segments.len());
+    assert_eq!(fs::read_dir(&first).unwrap().count(), included + 1);
+
+    kopper.compact().unwrap();
+    kopper.wait_for_compactions().unwrap();
+    kopper.write("k5", "new").unwrap();
+    let second = db.join("second");
+    kopper.backup_incremental(&second, Some(&first)).unwrap();
+
+    let target = db.join("target");
+    let manifest = Kopper::restore(&second, &target).unwrap();
+    assert_eq!(manifest.sequence, kopper.sequence());
+    let restored = Kopper::create(&target, 14).unwrap();
+    assert_eq!(restored.read("k1").unwrap(), "changed");
+    assert_eq!(restored.read("k5").unwrap(), "new");
+    assert!(matches!(restored.read("k2"), Err(KopperError::KeyDoesNotExist(_))));
+    assert_eq!(restored.len(), kopper.len());
+
+    // A damaged segment in a parent is noticed
+    let inherited = first_manifest.segments.iter().find(|segment| !segment.included).unwrap();
+    let segment = format!("{full}/{}", inherited.name);
+    let mut contents = fs::read(&segment).unwrap();
+    contents[0] ^= 1;
+    fs::write(&segment, contents).unwrap();
+    let result = Kopper::restore(&first, &db.join("damaged"));
+    assert!(matches!(result, Err(KopperError::InvalidBackup(_))), "{:?}", result.err());
+}
+
+#[test]
+fn clone_is_a_separate_database() {
+    let db = TempDb::new();
+    let kopper = db.kopper(14).unwrap();
+    let path = kopper.path();
+    for i in 0..10 {
+        kopper.write(&format!("k{}", i % 3), &i.to_string()).unwrap();
+    }
+
+    let target = db.join("target");
+    let manifest = kopper.clone_to(&target).unwrap();
+    assert_eq!(manifest.sequence, 10);
+    // The intermediate backup is gone
+    assert!(fs::read_dir(&path).unwrap().all(|entry| !entry.unwrap().file_name().to_string_lossy().starts_with('.')));
+
+    let clone = Kopper::create(&target, 14).unwrap();
+    clone.write("k0", "clone").unwrap();
+    kopper.write("k1", "source").unwrap();
+    kopper.compact().unwrap();
+    kopper.wait_for_compactions().unwrap();
+    assert_eq!(kopper.read("k0").unwrap(), "9");
+    assert_eq!(clone.read("k1").unwrap(), "7");
+    assert_eq!(clone.read("k0").unwrap(), "clone");
+
+    // Only into somewhere empty
+    assert!(matches!(kopper.clone_to(&target), Err(KopperError::InvalidBackup(_))));
+}
+
+#[test]
+fn restore_rejects_invalid_manifests() {
+    let db = TempDb::new();
+    let kopper = db.kopper(14).unwrap();
+    kopper.write("ab", "cd").unwrap();
+    let backup = db.join("backup");
+    kopper.backup_incremental(&backup, None).unwrap();
+
+    let manifest = fs::read_to_string(format!("{backup}/.manifest")).unwrap();
+    fs::write(format!("{backup}/.manifest"), manifest.replace("\"0_0\"", "\"../0_0\"")).unwrap();
+
+    let target = db.join("target");
+    assert!(matches!(Kopper::restore(&backup, &target), Err(KopperError::InvalidBackup(_))));
+    assert!(!std::path::Path::new(&target).exists());
+}
+
+#[test]
+fn sharded_kopper_spreads_keys() {
+    let db = TempDb::new();
+    let path = db.join("sharded");
+    let sharded = ShardedKopper::create(&path, 4, SEGMENT_SIZE).unwrap();
+
+    let mut key_values: Vec<(String, String)> = (0..100).map(|_| random_key_value()).collect();
+    for (key, value) in &key_values[..50] {
+        sharded.write(key, value).unwrap();
+    }
+    let batch: Vec<(&str, &str)> = key_values[50..].iter().map(|(key, value)| (key.as_str(), value.as_str())).collect();
+    sharded.write_batch(&batch).unwrap();
+
+    assert!(sharded.shards().iter().all(|shard| !shard.is_empty()));
+    sharded.delete(&key_values[0].0).unwrap();
+    let (deleted, _) = key_values.remove(0);
+    assert!(matches!(sharded.read(&deleted), Err(KopperError::KeyDoesNotExist(_))));
+
+    key_values.sort();
+    key_values.dedup_by(|a, b| a.0 == b.0);
+    let scanned: Vec<(String, String)> = sharded.scan().unwrap().collect::<Result<_, _>>().unwrap();
+    assert_eq!(scanned.len(), sharded.len());
+    assert!(scanned.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+    // Keys would end up in the wrong shards
+    sharded.close().unwrap();
+    assert!(ShardedKopper::create(&path, 2, SEGMENT_SIZE).is_err());
+    let sharded = ShardedKopper::create(&path, 4, SEGMENT_SIZE).unwrap();
+    assert_eq!(sharded.read(&key_values[0].0).unwrap(), key_values[0].1);
+}
+
+#[test]
+fn database_lives_in_memory() {
+    let store = Arc::new(MemoryStore::new());
+    let kopper = Kopper::with_store(store.clone(), 14).unwrap();
+    for _ in 0..5 {
+        kopper.write("ab", "cd").unwrap();
+    }
+    kopper.write("ef", "gh").unwrap();
+    kopper.delete("ef").unwrap();
+
+    kopper.compact().unwrap();
+    kopper.wait_for_compactions().unwrap();
+    assert!(matches!(kopper.install("anywhere"), Err(KopperError::Unsupported(_))));
+    kopper.close().unwrap();
+
+    let kopper = Kopper::with_store(store, 14).unwrap();
+    assert_eq!(kopper.read("ab").unwrap(), "cd");
+    assert!(matches!(kopper.read("ef"), Err(KopperError::KeyDoesNotExist(_))));
+
+    // Handed out as files, everything is still there
+    let db = TempDb::new();
+    let backup = db.join("backup");
+    kopper.backup_to(&backup).unwrap();
+    assert_eq!(Kopper::create(&backup, 14).unwrap().read("ab").unwrap(), "cd");
+}
+
+#[test]
+fn keys_split_across_recovery_chunks_are_recovered() {
+    let store = Arc::new(MemoryStore::new());
+    let kopper = Kopper::with_store(store.clone(), 100_000).unwrap();
+
+    // Recovery reads 2048 bytes at a time - every key has a character cut in two somewhere
+    let keys: Vec<String> = (0..8).map(|shift| "a".repeat(2040 + shift) + "żółw").collect();
+    for key in &keys {
+        kopper.write(key, "v").unwrap();
+    }
+    kopper.write("", "empty key").unwrap();
+    kopper.close().unwrap();
+
+    let kopper = Kopper::with_store(store, 100_000).unwrap();
+    for key in &keys {
+        assert_eq!(kopper.read(key).unwrap(), "v");
+    }
+    assert_eq!(kopper.read("").unwrap(), "empty key");
+}
+
+#[test]
+fn segment_is_read_up_to_a_malformed_record() {
+    let store = MemoryStore::new();
+    let file = store.create("0_0").unwrap();
+    file.append(b"ab\0cd\0\xff\xfe\0ef\0gh\0ij\0").unwrap();
+
+    let index = index_segment(&*file).unwrap();
+    assert_eq!(index.end_of_records, 6);
+    assert_eq!(index.values.get("ab"), Some(&(3, 2)));
+    assert_eq!(index.values.len(), 1);
+
+    let records: Vec<_> = KeyValueIterator::from(b"ab\0cd\0\xff\xfe\0ef\0gh\0ij\0").collect();
+    assert_eq!(records, vec![("ab", &b"ab\0cd\0"[..], 3)]);
+}
+
+#[test]
+fn recovered_segments_with_stale_records_are_compacted_first() {
+    let store = Arc::new(MemoryStore::new());
+    store.create("0_0").unwrap().append(b"k1\0v1\0k2\0v2\0").unwrap();
+    store.create("1_0").unwrap().append(b"ab\0cd\0ab\0ce\0").unwrap();
+    store.create("2_0").unwrap();
+
+    let kopper = Kopper::with_store(store, 14).unwrap();
+    let compactions = kopper.compactions();
+    kopper.compact().unwrap();
+    kopper.wait_for_compactions().unwrap();
+
+    // Only the overwritten "ab" is stale - the live segment could free nothing
+    assert_eq!(compactions.try_recv().unwrap().reclaimed_bytes, 6);
+    assert_eq!(kopper.read("ab").unwrap(), "ce");
+    assert_eq!(kopper.read("k2").unwrap(), "v2");
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn encrypted_database_needs_its_key() {
+    use kopperdb::encryption::EncryptionKey;
+
+    let db = TempDb::new();
+    let path = db.join("kopper");
+    let key = EncryptionKey::generate();
+    let kopper = Kopper::create_encrypted(&path, SEGMENT_SIZE, &key).unwrap();
+
+    // Enough to roll and compact segments
+    let mut expected = std::collections::HashMap::new();
+    for i in 0..200 {
+        let (key, value) = (format!("key{}", i % 20), format!("secret{i}"));
+        kopper.write(&key, &value).unwrap();
+        expected.insert(key, value);
+    }
+    kopper.wait_for_compactions().unwrap();
+    let snapshot = db.join("snapshot");
+    kopper.snapshot_to(&snapshot).unwrap();
+    kopper.close().unwrap();
+
+    for entry in fs::read_dir(&path).unwrap() {
+        let contents = fs::read(entry.unwrap().path()).unwrap();
+        assert!(!contents.windows(6).any(|window| window == b"secret"));
+    }
+
+    for path in [&path, &snapshot] {
+        let kopper = Kopper::create_encrypted(path, SEGMENT_SIZE, &key).unwrap();
+        for (key, value) in &expected {
+            assert_eq!(&kopper.read(key).unwrap(), value);
+        }
+        kopper.close().unwrap();
+    }
+    assert!(Kopper::create_encrypted(&path, SEGMENT_SIZE, &EncryptionKey::generate()).is_err());
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn compressed_values_read_back() {
+    use kopperdb::compression::{Codec, Compression};
+
+    let db = TempDb::new();
+    let kopper = db.kopper(4096).unwrap();
+    let long = "compressible ".repeat(100);
+    kopper.write("before", &long).unwrap();
+
+    kopper.set_compression(Some(Compression::new(Codec::Zstd)));
+    kopper.write("short", "tiny").unwrap();
+    kopper.write_batch(&[("a", &long), ("b", &"b".repeat(1000))]).unwrap();
+    let stats = kopper.compression_stats();
+    assert_eq!(stats.values, 2);
+    assert!(stats.ratio().unwrap() < 0.1);
+    assert!(kopper.size() < 2 * long.len());
+
+    kopper.set_compression(Some(Compression::new(Codec::Lz4)));
+    kopper.write("c", &long).unwrap();
+    kopper.compact().unwrap();
+    kopper.wait_for_compactions().unwrap();
+    kopper.close().unwrap();
+
+    let kopper = db.kopper(4096).unwrap();
+    for (key, value) in [("before", long.clone()), ("short", "tiny".to_owned()), ("a", long.clone()), ("b", "b".repeat(1000)), ("c", long)] {
+        assert_eq!(kopper.read(key).unwrap(), value);
+    }
+    let scanned: Vec<_> = kopper.scan().unwrap().map(|entry| entry.unwrap().0).collect();
+    assert_eq!(scanned, ["a", "b", "before", "c", "short"]);
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn cold_segments_are_packed_by_compaction() {
+    use kopperdb::compression::Codec;
+    use kopperdb::packed::Packing;
+
+    let fill = |kopper: &Kopper| {
+        // Every segment ends up with stale records, so every one is compacted
+        for i in 0..40 {
+            for round in 0..2 {
+                kopper.write(&format!("key{i:02}"), &format!("round {round} of value {i} ").repeat(10)).unwrap();
+            }
+        }
+        kopper.compact().unwrap();
+        kopper.wait_for_compactions().unwrap();
+    };
+
+    let plain_db = TempDb::new();
+    let plain = plain_db.kopper(1024).unwrap();
+    fill(&plain);
+
+    let db = TempDb::new();
+    let kopper = db.kopper(1024).unwrap();
+    kopper.set_packing(Some(Packing { codec: Codec::Zstd, cold_after: 1 }));
+    fill(&kopper);
+    assert!(kopper.size() < plain.size() / 2, "{} packed, {} plain", kopper.size(), plain.size());
+
+    // Packed segments are compacted again, and read after reopening
+    kopper.delete("key00").unwrap();
+    fill(&kopper);
+    kopper.close().unwrap();
+    let kopper = db.kopper(1024).unwrap();
+    for i in 0..40 {
+        assert_eq!(kopper.read(&format!("key{i:02}")).unwrap(), plain.read(&format!("key{i:02}")).unwrap());
+    }
+    assert_eq!(kopper.scan().unwrap().count(), 40);
+}
+
+#[test]
+fn recovery_reports_progress() {
+    use kopperdb::kopper::RecoveryProgress;
+
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    for i in 0..20 {
+        kopper.write(&format!("key{i:02}"), "value").unwrap();
+    }
+    let size = kopper.size();
+    kopper.close().unwrap();
+
+    let mut reports = Vec::new();
+    let kopper = Kopper::create_with_progress(&db.join("kopper"), SEGMENT_SIZE, |progress| reports.push(progress)).unwrap();
+
+    let segments = reports[0].segments;
+    assert!(segments > 1);
+    assert_eq!(reports[0], RecoveryProgress { segments, ..RecoveryProgress::default() });
+    assert_eq!(reports.len(), segments + 1);
+    assert!(reports.windows(2).all(|pair| pair[0].recovered + 1 == pair[1].recovered && pair[0].bytes_scanned <= pair[1].bytes_scanned));
+    assert_eq!(*reports.last().unwrap(), RecoveryProgress { segments, recovered: segments, bytes_scanned: size as u64, entries: 20 });
+    assert_eq!(kopper.len(), 20);
+}
+
+#[test]
+fn lazily_opened_database_recovers_older_segments_in_the_background() {
+    use crate::faults::FaultyStore;
+
+    // The oldest segment holds a and gone, deleted in 2_0
+    let store = FaultyStore::new();
+    let kopper = Kopper::with_store(store.clone(), 12).unwrap();
+    for (key, value) in [("a", "1"), ("gone", "o"), ("b", "2"), ("c", "3")] {
+        kopper.write(key, value).unwrap();
+    }
+    kopper.delete("gone").unwrap();
+    kopper.write("d", "4").unwrap();
+    kopper.wait_for_compactions().unwrap();
+    kopper.close().unwrap();
+
+    // Compacted by now, under a name of its own
+    let store = store.restart();
+    let oldest = store.list().unwrap().into_iter().filter(|name| name.starts_with("0_")).min().unwrap();
+    store.stall_reads(Some(&oldest));
+    let kopper = Kopper::with_store_lazily(store.clone(), 12, 2).unwrap();
+    let progress = kopper.recovery_progress().unwrap();
+    assert_eq!((progress.segments, progress.recovered), (3, 2));
+
+    assert_eq!(kopper.read("d").unwrap(), "4");
+    assert!(matches!(kopper.read("gone"), Err(KopperError::KeyDoesNotExist(_))));
+    assert!(matches!(kopper.read("a"), Err(KopperError::Recovering)));
+    assert!(matches!(kopper.delete("a"), Err(KopperError::Recovering)));
+    assert!(matches!(kopper.scan(), Err(KopperError::Recovering)));
+    kopper.write("a", "5").unwrap();
+    assert_eq!(kopper.read("a").unwrap(), "5");
+
+    store.stall_reads(None);
+    kopper.wait_for_recovery().unwrap();
+    assert_eq!(kopper.recovery_progress(), None);
+    let expected = [("a", "5"), ("b", "2"), ("c", "3"), ("d", "4")];
+    let scanned: Vec<(String, String)> = kopper.scan().unwrap().collect::<Result<_, _>>().unwrap();
+    assert_eq!(scanned, expected.map(|(key, value)| (key.to_owned(), value.to_owned())));
+
+    // The overwritten and deleted records are garbage like any other
+    kopper.compact().unwrap();
+    kopper.wait_for_compactions().unwrap();
+    kopper.close().unwrap();
+    let kopper = Kopper::with_store(store.restart(), 12).unwrap();
+    for (key, value) in expected {
+        assert_eq!(kopper.read(key).unwrap(), value);
+    }
+    assert!(kopper.read("gone").is_err());
+}
+
+#[test]
+fn databases_without_a_manifest_are_opened_from_their_segments() {
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    let expected: Vec<(String, String)> = (0..30).map(|_| random_key_value()).collect();
+    for (key, value) in &expected {
+        kopper.write(key, value).unwrap();
+    }
+    kopper.wait_for_compactions().unwrap();
+    kopper.close().unwrap();
+
+    // Written before there was one
+    let manifest = db.join("kopper/MANIFEST");
+    fs::remove_file(&manifest).unwrap();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    for (key, value) in &expected {
+        assert_eq!(&kopper.read(key).unwrap(), value);
+    }
+    assert!(fs::metadata(&manifest).unwrap().len() > 0);
+}
+
+#[test]
+fn old_segments_are_moved_to_the_cold_tier() {
+    use kopperdb::store::{LocalStore, TieredStore};
+
+    let db = TempDb::new();
+    let (hot, cold) = (db.join("hot"), db.join("cold"));
+    fs::create_dir_all(&hot).unwrap();
+    fs::create_dir_all(&cold).unwrap();
+    let open = || Kopper::with_store(Arc::new(TieredStore::new(Arc::new(LocalStore::new(&hot)), Arc::new(LocalStore::new(&cold)))), SEGMENT_SIZE).unwrap();
+
+    let kopper = open();
+    kopper.set_cold_tier_after(Some(2));
+    let mut expected = std::collections::HashMap::new();
+    for i in 0..200 {
+        let (key, value) = (format!("key{}", i % 30), format!("value{i}"));
+        kopper.write(&key, &value).unwrap();
+        expected.insert(key, value);
+    }
+    kopper.compact().unwrap();
+    kopper.wait_for_compactions().unwrap();
+    kopper.close().unwrap();
+
+    // Only segments at least two behind the active one are cold
+    let segments = |dir: &str| -> Vec<(u32, String)> {
+        let mut segments: Vec<(u32, String)> = fs::read_dir(dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name != "MANIFEST")
+            .map(|name| (name.split('_').next().unwrap().parse().unwrap(), name))
+            .collect();
+        segments.sort();
+        segments
+    };
+    let (hot_segments, cold_segments) = (segments(&hot), segments(&cold));
+    let active = hot_segments.last().unwrap().0;
+    assert!(!cold_segments.is_empty());
+    assert!(cold_segments.iter().all(|(base, _)| active - base >= 2));
+
+    let kopper = open();
+    for (key, value) in &expected {
+        assert_eq!(&kopper.read(key).unwrap(), value);
+    }
+}
+
+#[test]
+fn segments_are_spread_across_stores() {
+    use kopperdb::store::{Placement, SpreadStore};
+
+    let (first, second) = (Arc::new(MemoryStore::new()), Arc::new(MemoryStore::new()));
+    let open = || Kopper::with_store(Arc::new(SpreadStore::new(vec![first.clone(), second.clone()], Placement::RoundRobin)), SEGMENT_SIZE).unwrap();
+
+    let kopper = open();
+    let mut expected = std::collections::HashMap::new();
+    for i in 0..100 {
+        let (key, value) = (format!("key{}", i % 30), format!("value{i}"));
+        kopper.write(&key, &value).unwrap();
+        expected.insert(key, value);
+    }
+
+    // The MANIFEST stays with the first one
+    let (first_segments, second_segments) = (first.list().unwrap(), second.list().unwrap());
+    assert!(first_segments.contains(&"MANIFEST".to_owned()));
+    assert!(first_segments.len() > 1);
+    assert!(!second_segments.is_empty());
+    assert!(first_segments.iter().all(|name| !second_segments.contains(name)));
+
+    kopper.compact().unwrap();
+    kopper.wait_for_compactions().unwrap();
+    kopper.close().unwrap();
+
+    let kopper = open();
+    for (key, value) in &expected {
+        assert_eq!(&kopper.read(key).unwrap(), value);
+    }
+}
+
+#[test]
+fn identical_values_are_stored_once() {
+    let db = TempDb::new();
+    let value = "shared value ".repeat(20);
+    let keys: Vec<String> = (0..50).map(|i| format!("key{i:02}")).collect();
+    let compact = |kopper: &Kopper| {
+        kopper.compact().unwrap();
+        kopper.wait_for_compactions().unwrap();
+    };
+
+    let kopper = db.kopper(1024).unwrap();
+    kopper.set_dedupe(Some(64));
+    kopper.write("short", "too short to bother").unwrap();
+    for key in &keys[..40] {
+        kopper.write(key, &value).unwrap();
+    }
+    let batch: Vec<(&str, &str)> = keys[40..].iter().map(|key| (key.as_str(), value.as_str())).collect();
+    kopper.write_batch(&batch).unwrap();
+    assert!(kopper.size() < keys.len() * value.len() / 5);
+    kopper.close().unwrap();
+
+    // Read back without dedupe, and after the key the value was first written under is overwritten
+    let kopper = db.kopper(1024).unwrap();
+    assert_eq!(kopper.read("short").unwrap(), "too short to bother");
+    assert!(keys.iter().all(|key| kopper.read(key).unwrap() == value));
+    kopper.write(&keys[0], "other").unwrap();
+    compact(&kopper);
+    kopper.close().unwrap();
+
+    let kopper = db.kopper(1024).unwrap();
+    assert_eq!(kopper.read(&keys[0]).unwrap(), "other");
+    assert!(keys[1..].iter().all(|key| kopper.read(key).unwrap() == value));
+    let scanned: Vec<_> = kopper.scan().unwrap().map(|entry| entry.unwrap().1).filter(|scanned| *scanned == value).collect();
+    assert_eq!(scanned.len(), keys.len() - 1);
+
+    // Once no key refers to it, compaction drops it
+    for key in &keys {
+        kopper.delete(key).unwrap();
+    }
+    compact(&kopper);
+    kopper.close().unwrap();
+    let stored = fs::read_dir(db.join("kopper")).unwrap()
+        .map(|entry| fs::read(entry.unwrap().path()).unwrap())
+        .any(|contents| contents.windows(value.len()).any(|window| window == value.as_bytes()));
+    assert!(!stored);
+}
+
+#[test]
+fn writes_past_the_quota_are_refused() {
+    use kopperdb::kopper::{Quota, QuotaLimit, Usage};
+
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    kopper.set_quota(Quota { max_bytes: Some(40), max_keys: Some(3) });
+    kopper.write("a", "1").unwrap();
+    kopper.write_batch(&[("b", "2"), ("c", "3")]).unwrap();
+    assert_eq!(kopper.usage(), Usage { keys: 3, bytes: 6 });
+    assert!(matches!(kopper.write("d", "4"), Err(KopperError::QuotaExceeded(QuotaLimit::Keys(3)))));
+    assert!(matches!(kopper.write_batch(&[("a", "5"), ("d", "4")]), Err(KopperError::QuotaExceeded(QuotaLimit::Keys(3)))));
+    assert_eq!(kopper.read("a").unwrap(), "1");
+
+    // Keys already there can be overwritten, as long as they fit
+    kopper.write("a", &"x".repeat(30)).unwrap();
+    assert!(matches!(kopper.write("b", &"x".repeat(10)), Err(KopperError::QuotaExceeded(QuotaLimit::Bytes(40)))));
+    kopper.write("a", "shorter").unwrap();
+
+    // Deleting makes room, and replicated changes aren't held back
+    kopper.delete("c").unwrap();
+    kopper.write("d", "4").unwrap();
+    kopper.apply(&[ChangeEvent::Write { key: "e".to_owned(), value: "5".to_owned() }]).unwrap();
+    assert_eq!(kopper.usage(), Usage { keys: 4, bytes: 14 });
+    kopper.close().unwrap();
+
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    assert_eq!(kopper.usage(), Usage { keys: 4, bytes: 14 });
+}
+
+#[test]
+fn least_recently_used_keys_are_evicted() {
+    use kopperdb::kopper::{Eviction, Usage};
+
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    kopper.write_batch(&[("a", "1"), ("b", "2")]).unwrap();
+    kopper.set_eviction(Some(Eviction { max_bytes: Some(20), max_keys: Some(3) }));
+
+    // Keys already there go first, then the least recently read or written
+    kopper.write("c", "3").unwrap();
+    kopper.read("a").unwrap();
+    kopper.write("d", "4").unwrap();
+    assert!(matches!(kopper.read("b"), Err(KopperError::KeyDoesNotExist(_))));
+    kopper.write("c", "33").unwrap();
+    kopper.write("e", "5").unwrap();
+    assert!(matches!(kopper.read("a"), Err(KopperError::KeyDoesNotExist(_))));
+    assert_eq!(kopper.usage(), Usage { keys: 3, bytes: 7 });
+
+    // Too big for the rest to stay, but the key written is kept
+    kopper.write("f", &"x".repeat(30)).unwrap();
+    assert_eq!(kopper.usage(), Usage { keys: 1, bytes: 31 });
+    assert_eq!(kopper.read("f").unwrap().len(), 30);
+
+    // Evicted keys are deleted for good
+    kopper.close().unwrap();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    assert_eq!(kopper.usage(), Usage { keys: 1, bytes: 31 });
+}
+
+#[test]
+fn pinned_keys_are_neither_evicted_nor_expired() {
+    use kopperdb::kopper::Eviction;
+
+    let db = TempDb::new();
+    let clock = Arc::new(MockClock::new(UNIX_EPOCH + time::Duration::from_secs(1_000)));
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    kopper.set_clock(clock.clone());
+    kopper.set_eviction(Some(Eviction { max_bytes: None, max_keys: Some(2) }));
+    kopper.pin("config");
+
+    kopper.write_with_ttl("config", "limits", time::Duration::from_secs(10)).unwrap();
+    kopper.write("a", "1").unwrap();
+    kopper.write("b", "2").unwrap();
+    assert_eq!(kopper.read("config").unwrap(), "limits");
+    assert!(matches!(kopper.read("a"), Err(KopperError::KeyDoesNotExist(_))));
+
+    clock.advance(time::Duration::from_secs(10));
+    assert_eq!(kopper.sweep_expired(100).unwrap(), 0);
+    assert_eq!(kopper.read("config").unwrap(), "limits");
+    assert_eq!(kopper.ttl("config").unwrap(), Some(time::Duration::ZERO));
+
+    // Past its TTL, it's gone as soon as it's unpinned
+    assert!(kopper.unpin("config"));
+    assert!(!kopper.unpin("config"));
+    assert!(matches!(kopper.read("config"), Err(KopperError::KeyDoesNotExist(_))));
+    assert_eq!(kopper.sweep_expired(100).unwrap(), 1);
+}
+
+#[test]
+fn segment_stats_split_live_and_dead_bytes() {
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    kopper.write("a", "old").unwrap();
+    kopper.write("b", "value").unwrap();
+    kopper.write("a", "new").unwrap();
+    kopper.delete("b").unwrap();
+
+    let segments = kopper.segment_stats().unwrap();
+    assert_eq!(segments.len(), 1);
+    let segment = &segments[0];
+    assert_eq!((segment.name.as_str(), segment.age), ("0_0", 0));
+    assert_eq!(segment.bytes, 6 + 8 + 6 + 4);
+    assert_eq!((segment.live_bytes, segment.dead_bytes), (6, 18));
+    assert_eq!((segment.entries, segment.dead_entries), (1, 3));
+
+    // Older segments age as new ones are cut off
+    kopper.write("c", &"x".repeat(SEGMENT_SIZE - 4)).unwrap();
+    let segments = kopper.segment_stats().unwrap();
+    assert_eq!(segments.iter().map(|segment| segment.age).collect::<Vec<_>>(), vec![1, 0]);
+    assert_eq!(segments[1].live_bytes, segments[1].bytes);
+}
+
+#[test]
+fn doctor_reports_what_needs_attention() {
+    use kopperdb::doctor::Health;
+
+    let db = TempDb::new();
+    let kopper = db.kopper(SEGMENT_SIZE).unwrap();
+    kopper.write("key", "value").unwrap();
+    let report = kopper.doctor().unwrap();
+    let names: Vec<&str> = report.checks.iter().map(|check| check.name.as_str()).collect();
+    assert_eq!(names, ["permissions", "lock", "manifest", "descriptors", "compactor", "fragmentation", "verification"]);
+    assert_eq!(report.health(), Health::Ok, "{report:?}");
+
+    kopper.set_read_only(true);
+    let report = kopper.doctor().unwrap();
+    let lock = report.checks.iter().find(|check| check.name == "lock").unwrap();
+    assert_eq!(lock.health, Health::Warning);
+    assert!(lock.remedy.is_some());
+    assert_eq!(report.health(), Health::Warning);
+
+    kopper.close().unwrap();
+    assert!(matches!(kopper.doctor(), Err(KopperError::Closed)));
+}
+
+#[test]
+fn writes_stall_while_compaction_is_behind() {
+    use kopperdb::kopper::Backpressure;
+    use crate::faults::FaultyStore;
+
+    // Compacting the first segment blocks until it's let through
+    let store = FaultyStore::new();
+    store.stall_reads(Some("0_0"));
+    let kopper = Kopper::with_store(store.clone(), SEGMENT_SIZE).unwrap();
+    kopper.set_backpressure(Some(Backpressure { soft: 0.2, hard: 0.6, delay: time::Duration::from_millis(1) }));
+
+    // Overwrites leave the sealed segment dead
+    let value = "x".repeat(40);
+    for _ in 0..3 {
+        kopper.write("a", &value).unwrap();
+    }
+    assert!(matches!(kopper.write("b", "1"), Err(KopperError::Backpressure)));
+    assert!(matches!(kopper.write_batch(&[("b", "1")]), Err(KopperError::Backpressure)));
+    kopper.delete("a").unwrap();
+
+    store.stall_reads(None);
+    kopper.wait_for_compactions().unwrap();
+    kopper.write("b", "1").unwrap();
+}
+
+#[test]
+fn saturation_shows_compactions_piling_up() {
+    use crate::faults::FaultyStore;
+
+    let store = FaultyStore::new();
+    store.stall_reads(Some("0_0"));
+    let kopper = Kopper::with_store(store.clone(), SEGMENT_SIZE).unwrap();
+    for _ in 0..3 {
+        kopper.write("a", &"x".repeat(40)).unwrap();
+    }
+    let saturation = kopper.saturation().unwrap();
+    assert_eq!(saturation.open_segments, kopper.segment_stats().unwrap().len());
+    assert_eq!(saturation.compactor_alive, Some(true));
+
+    // The first compaction is stuck, the ones after it wait
+    kopper.compact().unwrap();
+    assert!(kopper.saturation().unwrap().compactor_queue >= 1);
+
+    store.stall_reads(None);
+    kopper.wait_for_compactions().unwrap();
+    assert_eq!(kopper.saturation().unwrap().compactor_queue, 0);
+
+    kopper.close().unwrap();
+    assert!(matches!(kopper.saturation(), Err(KopperError::Closed)));
+}
+
+#[test]
+fn calls_give_up_waiting_for_a_busy_database_at_their_deadline() {
+    use std::time::Instant;
+    use crate::faults::FaultyStore;
+
+    let store = FaultyStore::new();
+    let kopper = Kopper::with_store(store.clone(), SEGMENT_SIZE).unwrap();
+    kopper.write("key", "value").unwrap();
+
+    // A slow disk keeps the database busy with this write
+    store.set_delay(time::Duration::from_millis(300));
+    let slow = {
+        let kopper = kopper.clone();
+        std::thread::spawn(move || kopper.write("slow", "value").unwrap())
+    };
+    std::thread::sleep(time::Duration::from_millis(50));
+
+    let soon = || Instant::now() + time::Duration::from_millis(10);
+    assert!(matches!(kopper.read_until("key", soon()), Err(KopperError::TimedOut)));
+    assert!(matches!(kopper.write_until("late", "value", soon()), Err(KopperError::TimedOut)));
+    assert!(kopper.saturation().unwrap().lock_waits >= 2);
+    slow.join().unwrap();
+
+    // Nothing is written by writes that gave up, a free database isn't waited for
+    store.set_delay(time::Duration::ZERO);
+    assert_eq!(kopper.read_until("key", soon()).unwrap(), "value");
+    assert!(matches!(kopper.read("late"), Err(KopperError::KeyDoesNotExist(_))));
+
+    // Same as any other engine, a deadline gone already fails even with the lock free
+    assert!(matches!(kopper.read_until("key", Instant::now()), Err(KopperError::TimedOut)));
+}
+
+#[test]
+fn segments_are_only_as_accessible_as_their_mode() {
+    use std::os::unix::fs::PermissionsExt;
+    use kopperdb::store::LocalStore;
+
+    let mode = |path: &str| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+    let db = TempDb::new();
+    let path = db.join("kopper");
+    fs::create_dir_all(&path).unwrap();
+
+    // Written before, readable by anyone
+    fs::write(path.clone() + "/0_0", "old\0value\0").unwrap();
+    fs::set_permissions(path.clone() + "/0_0", fs::Permissions::from_mode(0o644)).unwrap();
+
+    let store = LocalStore::with_mode(&path, 0o600).unwrap();
+    let kopper = Kopper::with_store(Arc::new(store), SEGMENT_SIZE).unwrap();
+    for _ in 0..10 {
+        let (key, value) = random_key_value();
+        kopper.write(&key, &value).unwrap();
+    }
+    assert_eq!(kopper.read("old").unwrap(), "value");
+
+    assert_eq!(mode(&path), 0o700);
+    let segments: Vec<_> = fs::read_dir(&path).unwrap().map(|entry| entry.unwrap().path()).collect();
+    assert!(segments.len() > 1);
+    for segment in segments {
+        assert_eq!(mode(segment.to_str().unwrap()), 0o600, "{segment:?}");
+    }
+
+    // Snapshots are as private
+    let snapshot = db.join("snapshot");
+    kopper.snapshot_to(&snapshot).unwrap();
+    for segment in fs::read_dir(&snapshot).unwrap() {
+        assert_eq!(mode(segment.unwrap().path().to_str().unwrap()), 0o600);
+    }
+}
@@ -0,0 +1,108 @@
+mod common;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+
+use kopperdb::kopper::KopperError;
+use kopperdb::lsm::MAX_RUNS;
+use kopperdb::testing::TempDb;
+
+use crate::common::*;
+
+#[test]
+fn writes_survive_flushes_merges_and_reopening() {
+    // Memtable of a segment's size flushes every few writes
+    let db = TempDb::new();
+    let lsm = db.lsm(SEGMENT_SIZE).unwrap();
+
+    let mut expected = BTreeMap::new();
+    for round in 0..300 {
+        let (key, value) = random_key_value();
+        lsm.write(&key, &value).unwrap();
+        expected.insert(key, value);
+
+        // Overwrites and deletes of keys that are already in runs
+        if round % 3 == 0 {
+            let key = expected.keys().nth(round % expected.len()).unwrap().clone();
+            lsm.write(&key, "new").unwrap();
+            expected.insert(key, "new".to_string());
+        }
+        if round % 5 == 0 {
+            let key = expected.keys().next().unwrap().clone();
+            lsm.delete(&key).unwrap();
+            expected.remove(&key);
+            assert!(matches!(lsm.read(&key), Err(KopperError::KeyDoesNotExist(_))));
+            assert!(matches!(lsm.delete(&key), Err(KopperError::KeyDoesNotExist(_))));
+        }
+    }
+    assert!(lsm.runs() <= MAX_RUNS);
+    assert_eq!(lsm.len(), expected.len());
+
+    // Last writes are only in the log
+    drop(lsm);
+    let lsm = db.lsm(SEGMENT_SIZE).unwrap();
+    for (key, value) in &expected {
+        assert_eq!(&lsm.read(key).unwrap(), value);
+    }
+    assert_eq!(lsm.len(), expected.len());
+    assert_eq!(lsm.scan().unwrap().collect::<Result<Vec<_>, _>>().unwrap(), expected.into_iter().collect::<Vec<_>>());
+}
+
+#[test]
+fn ranges_only_cover_their_keys() {
+    let db = TempDb::new();
+    let lsm = db.lsm(SEGMENT_SIZE).unwrap();
+    for key in ["d", "a", "c", "f", "b", "e"] {
+        lsm.write(key, &key.repeat(10)).unwrap();
+    }
+    lsm.delete("c").unwrap();
+
+    let keys = |from, to| lsm.range(from, to).unwrap().map(|entry| entry.unwrap().0).collect::<Vec<_>>();
+    assert_eq!(keys("b", Some("e")), ["b", "d"]);
+    assert_eq!(keys("bb", None), ["d", "e", "f"]);
+    assert_eq!(keys("", Some("a")), Vec::<String>::new());
+
+    // Merged into a single run, deletes are gone for good
+    lsm.compact().unwrap();
+    assert_eq!(lsm.runs(), 1);
+    assert_eq!(keys("", None), ["a", "b", "d", "e", "f"]);
+}
+
+#[test]
+fn reopening_cleans_up_after_a_crash() {
+    let db = TempDb::new();
+    let lsm = db.lsm(SEGMENT_SIZE).unwrap();
+    for _ in 0..20 {
+        let (key, value) = random_key_value();
+        lsm.write(&key, &value).unwrap();
+    }
+    lsm.compact().unwrap();
+    lsm.write("last", "value").unwrap();
+    let entries: Vec<_> = lsm.scan().unwrap().collect::<Result<_, _>>().unwrap();
+    drop(lsm);
+    let path = db.join("lsm");
+
+    let merged = fs::read_dir(&path).unwrap()
+        .map(|file| file.unwrap().file_name().into_string().unwrap())
+        .find(|name| name.ends_with(".run"))
+        .unwrap();
+    let (first, last) = merged.trim_end_matches(".run").split_once('-').unwrap();
+    assert!(first < last);
+
+    // A run the merge replaced but didn't get to remove, an unfinished run and a torn write
+    fs::write(format!("{path}/{first}-{first}.run"), b"ghost\0boo\0").unwrap();
+    fs::write(format!("{path}/.9-9.run.tmp"), b"partial").unwrap();
+    fs::OpenOptions::new().append(true).open(format!("{path}/wal")).unwrap().write_all(b"torn\0wri").unwrap();
+
+    let lsm = db.lsm(SEGMENT_SIZE).unwrap();
+    assert!(!std::path::Path::new(&format!("{path}/{first}-{first}.run")).exists());
+    assert!(!std::path::Path::new(&format!("{path}/.9-9.run.tmp")).exists());
+    assert!(matches!(lsm.read("ghost"), Err(KopperError::KeyDoesNotExist(_))));
+    assert!(matches!(lsm.read("torn"), Err(KopperError::KeyDoesNotExist(_))));
+    assert_eq!(lsm.scan().unwrap().collect::<Result<Vec<_>, _>>().unwrap(), entries);
+
+    // Writes after the torn one aren't lost
+    lsm.write("after", "crash").unwrap();
+    drop(lsm);
+    assert_eq!(db.lsm(SEGMENT_SIZE).unwrap().read("after").unwrap(), "crash");
+}
@@ -0,0 +1,157 @@
+// Only power loss is modelled
+#[allow(dead_code)]
+mod faults;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+use kopperdb::kopper::{Kopper, KopperError};
+use proptest::prelude::*;
+
+use crate::faults::FaultyStore;
+
+/// Small enough for a few writes to fill a segment
+const SEGMENT_SIZE: usize = 40;
+
+/// Few keys, so most writes overwrite or delete something that's already there
+const KEYS: &[&str] = &["a", "b", "c", "d", "e"];
+
+#[derive(Clone, Debug)]
+enum Op {
+    Write(usize, String),
+    Delete(usize),
+    Read(usize),
+    Flush,
+    Compact,
+    /// Clean restart, after [`Kopper::close`]
+    Restart,
+    /// Power loss keeping the first few unsynced bytes of every segment
+    Crash(usize),
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    let key = 0..KEYS.len();
+    prop_oneof![
+        6 => (key.clone(), "[a-z]{0,12}").prop_map(|(key, value)| Op::Write(key, value)),
+        2 => key.clone().prop_map(Op::Delete),
+        2 => key.prop_map(Op::Read),
+        1 => Just(Op::Flush),
+        1 => Just(Op::Compact),
+        1 => Just(Op::Restart),
+        1 => (0..20usize).prop_map(Op::Crash),
+    ]
+}
+
+/// What the database should hold. Values of a key written since the last flush
+/// may or may not survive a crash - the synced one, if any, or any of those.
+#[derive(Default)]
+struct Model {
+    current: BTreeMap<&'static str, String>,
+    synced: BTreeMap<&'static str, String>,
+    /// Every state a key was in since the last flush, `None` for deleted
+    unsynced: BTreeMap<&'static str, Vec<Option<String>>>,
+}
+
+impl Model {
+    fn set(&mut self, key: &'static str, value: Option<String>) {
+        match &value {
+            Some(value) => self.current.insert(key, value.clone()),
+            None => self.current.remove(key),
+        };
+        self.unsynced.entry(key).or_default().push(value);
+    }
+
+    fn flushed(&mut self) {
+        self.synced = self.current.clone();
+        self.unsynced.clear();
+    }
+
+    /// Takes over what survived the crash, after checking it could have
+    fn crashed(&mut self, kopper: &Kopper) {
+        for key in KEYS {
+            let recovered = match kopper.read(key) {
+                Ok(value) => Some(value),
+                Err(KopperError::KeyDoesNotExist(_)) => None,
+                Err(err) => panic!("Can't read {key}: {err}"),
+            };
+            let synced = self.synced.get(key).cloned();
+            let possible = self.unsynced.get(key).is_some_and(|states| states.contains(&recovered));
+            assert!(recovered == synced || possible, "{key} recovered as {recovered:?}, synced {synced:?}, written since {:?}", self.unsynced.get(key));
+            match recovered {
+                Some(value) => self.current.insert(key, value),
+                None => self.current.remove(key),
+            };
+        }
+        self.flushed();
+    }
+}
+
+fn open(store: &Arc<FaultyStore>) -> Kopper {
+    Kopper::with_store(store.clone(), SEGMENT_SIZE).unwrap()
+}
+
+fn assert_same(kopper: &Kopper, model: &Model) {
+    let scanned: Vec<(String, String)> = kopper.scan().unwrap().collect::<Result<_, _>>().unwrap();
+    let expected: Vec<(String, String)> = model.current.iter().map(|(key, value)| (key.to_string(), value.clone())).collect();
+    assert_eq!(scanned, expected);
+    let keys: BTreeSet<&str> = KEYS.iter().copied().filter(|key| kopper.read(key).is_ok()).collect();
+    assert_eq!(keys, model.current.keys().copied().collect());
+}
+
+fn run(ops: Vec<Op>) {
+    let mut store = FaultyStore::new();
+    let mut kopper = open(&store);
+    let mut model = Model::default();
+
+    for op in ops {
+        match op {
+            Op::Write(key, value) => {
+                kopper.write(KEYS[key], &value).unwrap();
+                model.set(KEYS[key], Some(value));
+            },
+            Op::Delete(key) => match kopper.delete(KEYS[key]) {
+                Ok(_) => model.set(KEYS[key], None),
+                Err(KopperError::KeyDoesNotExist(_)) => assert!(!model.current.contains_key(KEYS[key])),
+                Err(err) => panic!("Can't delete {}: {err}", KEYS[key]),
+            },
+            Op::Read(key) => match kopper.read(KEYS[key]) {
+                Ok(value) => assert_eq!(Some(&value), model.current.get(KEYS[key])),
+                Err(KopperError::KeyDoesNotExist(_)) => assert!(!model.current.contains_key(KEYS[key])),
+                Err(err) => panic!("Can't read {}: {err}", KEYS[key]),
+            },
+            Op::Flush => {
+                kopper.wait_for_flush(kopper.sequence()).unwrap();
+                model.flushed();
+            },
+            Op::Compact => {
+                kopper.compact().unwrap();
+                kopper.wait_for_compactions().unwrap();
+                assert_same(&kopper, &model);
+            },
+            Op::Restart => {
+                kopper.close().unwrap();
+                store = store.restart();
+                kopper = open(&store);
+                model.flushed();
+                assert_same(&kopper, &model);
+            },
+            Op::Crash(torn) => {
+                // Compactions of the last writes may still be going on
+                store.power_loss(torn);
+                let _ = kopper.close();
+                store = store.restart();
+                kopper = open(&store);
+                model.crashed(&kopper);
+                assert_same(&kopper, &model);
+            },
+        }
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(200))]
+
+    #[test]
+    fn kopper_behaves_like_a_map(ops in prop::collection::vec(op(), 1..80)) {
+        run(ops);
+    }
+}